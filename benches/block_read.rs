@@ -0,0 +1,49 @@
+//! Cost of reading one block of a cached file with a seek and a read through an exclusive
+//! handle (`read_file_block`), and with a positioned read through a shared one
+//! (`read_file_block_at`, `pread` on Unix).
+//!
+//! Run with `cargo bench --bench block_read`. The file is read once beforehand so every read is
+//! served from the page cache, leaving the cost of the calls themselves.
+
+use std::{fs::File, hint::black_box, io::Write};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sendfile::file::utils::{read_file_block, read_file_block_at};
+
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+const BLOCK_SIZES: [u32; 3] = [64 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+
+fn block_read(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("sendfile_bench_{}", std::process::id()));
+    let mut file = File::create(&path).unwrap();
+    file.write_all(&vec![0x5A; FILE_SIZE]).unwrap();
+    drop(file);
+    let mut file = File::open(&path).unwrap();
+    read_file_block(&mut file, 0, FILE_SIZE as u32).unwrap();
+
+    let mut group = c.benchmark_group("block_read");
+    for block_size in BLOCK_SIZES {
+        let blocks = (FILE_SIZE / block_size as usize) as u32;
+        group.throughput(Throughput::Bytes(block_size as u64));
+
+        let mut seq = 0;
+        group.bench_function(BenchmarkId::new("seek_read", block_size), |b| {
+            b.iter(|| {
+                seq = (seq + 1) % blocks;
+                read_file_block(&mut file, black_box(seq), block_size).unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("pread", block_size), |b| {
+            b.iter(|| {
+                seq = (seq + 1) % blocks;
+                read_file_block_at(&file, black_box(seq), block_size).unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, block_read);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+//! Cost of the per-block checksum with each implementation.
+//!
+//! Run with `cargo bench --bench checksum`. Measures every [ChecksumImpl] for common block
+//! sizes, so slowdowns show up before they cap transfers.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sendfile::stream::checksum::{hardware_target, ChecksumImpl};
+
+/// Block sizes measured, from the smallest allowed to the default and beyond.
+const BLOCK_SIZES: [usize; 3] = [64 * 1024, 1024 * 1024, 8 * 1024 * 1024];
+
+fn checksum(c: &mut Criterion) {
+    println!(
+        "hardware target: {}",
+        hardware_target().unwrap_or_else(|| String::from("none"))
+    );
+
+    let mut group = c.benchmark_group("checksum");
+    for block_size in BLOCK_SIZES {
+        let block: Vec<u8> = (0..block_size).map(|i| (i * 31 % 251) as u8).collect();
+        group.throughput(Throughput::Bytes(block_size as u64));
+
+        for implementation in [ChecksumImpl::Auto, ChecksumImpl::Software] {
+            group.bench_with_input(
+                BenchmarkId::new(implementation.to_string(), block_size),
+                &block,
+                |b, block| b.iter(|| implementation.checksum(black_box(block))),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, checksum);
+criterion_main!(benches);
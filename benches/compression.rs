@@ -0,0 +1,89 @@
+//! Cost of compressing and decompressing one block with gzip, as older peers do, and with zstd,
+//! which peers supporting it negotiate instead.
+//!
+//! Run with `cargo bench --bench compression`. Text-like blocks compress well, random blocks
+//! don't compress at all and show the cost of trying. The compressed size of each block is
+//! printed once, so ratios can be compared alongside the timings.
+
+use std::{
+    hint::black_box,
+    io::{Read, Write},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use sendfile::stream::compress::ZSTD_LEVEL;
+
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+fn text_block() -> Vec<u8> {
+    let line = b"2026-10-16T09:40:35Z INFO sendfile::stream::receive Block 42 verified\n";
+    line.iter().copied().cycle().take(BLOCK_SIZE).collect()
+}
+
+fn random_block() -> Vec<u8> {
+    // xorshift, so the block doesn't compress yet is the same on every run
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    (0..BLOCK_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn gzip(block: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(block).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn gunzip(compressed: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::with_capacity(BLOCK_SIZE);
+    GzDecoder::new(compressed)
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    decompressed
+}
+
+fn compression(c: &mut Criterion) {
+    let mut compress = c.benchmark_group("compress");
+    compress.throughput(Throughput::Bytes(BLOCK_SIZE as u64));
+    for (content, block) in [("text", text_block()), ("random", random_block())] {
+        let gzipped = gzip(&block);
+        let zstded = zstd::bulk::compress(&block, ZSTD_LEVEL).unwrap();
+        println!(
+            "{content}: gzip {} bytes, zstd {} bytes, of {BLOCK_SIZE}",
+            gzipped.len(),
+            zstded.len()
+        );
+
+        compress.bench_with_input(BenchmarkId::new("gzip", content), &block, |b, block| {
+            b.iter(|| gzip(black_box(block)))
+        });
+        compress.bench_with_input(BenchmarkId::new("zstd", content), &block, |b, block| {
+            b.iter(|| zstd::bulk::compress(black_box(block), ZSTD_LEVEL).unwrap())
+        });
+    }
+    compress.finish();
+
+    let mut decompress = c.benchmark_group("decompress");
+    decompress.throughput(Throughput::Bytes(BLOCK_SIZE as u64));
+    for (content, block) in [("text", text_block()), ("random", random_block())] {
+        let gzipped = gzip(&block);
+        let zstded = zstd::bulk::compress(&block, ZSTD_LEVEL).unwrap();
+
+        decompress.bench_with_input(BenchmarkId::new("gzip", content), &gzipped, |b, data| {
+            b.iter(|| gunzip(black_box(data)))
+        });
+        decompress.bench_with_input(BenchmarkId::new("zstd", content), &zstded, |b, data| {
+            b.iter(|| zstd::bulk::decompress(black_box(data), BLOCK_SIZE).unwrap())
+        });
+    }
+    decompress.finish();
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Cost of framing data blocks: serializing a `Data` message and attaching its headers on the
+//! sender, reading the frame back with `read_next_payload` on the receiver.
+//!
+//! Run with `cargo bench --bench frame`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sendfile::{
+    connection::read_next_payload,
+    transport::{attach_headers, DataV1, SenderMessageV1, MAX_MESSAGE_SIZE},
+};
+
+const BLOCK_SIZES: [usize; 3] = [64 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+
+fn data_message(block: &[u8]) -> SenderMessageV1<'_> {
+    SenderMessageV1::Data(DataV1 {
+        seq: 7,
+        checksum: 0xCBF4_3926,
+        file_hash: &[0xAA; 32],
+        compressed: false,
+        data: block,
+    })
+}
+
+fn frame(c: &mut Criterion) {
+    let mut encode = c.benchmark_group("frame_encode");
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    for block_size in BLOCK_SIZES {
+        let block = vec![0x5A; block_size];
+        encode.throughput(Throughput::Bytes(block_size as u64));
+        encode.bench_with_input(
+            BenchmarkId::from_parameter(block_size),
+            &block,
+            |b, block| {
+                b.iter(|| {
+                    let payload = data_message(black_box(block))
+                        .to_bytes(&mut buffer)
+                        .unwrap();
+                    attach_headers(payload)
+                })
+            },
+        );
+    }
+    encode.finish();
+
+    let mut decode = c.benchmark_group("frame_decode");
+    for block_size in BLOCK_SIZES {
+        let block = vec![0x5A; block_size];
+        let frame = attach_headers(data_message(&block).to_bytes(&mut buffer).unwrap());
+        decode.throughput(Throughput::Bytes(block_size as u64));
+        decode.bench_with_input(
+            BenchmarkId::from_parameter(block_size),
+            &frame,
+            |b, frame| {
+                b.iter(|| {
+                    let mut stream = &frame[..];
+                    let result =
+                        read_next_payload::<SenderMessageV1, _>(&mut stream, &mut buffer, 0)
+                            .unwrap();
+                    black_box(result.total_bytes_read)
+                })
+            },
+        );
+    }
+    decode.finish();
+}
+
+criterion_group!(benches, frame);
+criterion_main!(benches);
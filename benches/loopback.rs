@@ -0,0 +1,105 @@
+//! Throughput of whole transfers over the loopback interface, for several block sizes.
+//!
+//! Run with `cargo bench --bench loopback`. Each iteration sends a file from memory to a
+//! receiver in memory through the real protocol, handshake included, so the results cover the
+//! whole pipeline without disk I/O. The default handshake and data ports must be free.
+
+use std::{
+    io::Cursor,
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sendfile::{
+    cli::HANDSHAKE_PORT,
+    file::utils::get_bytes_blake3_hash,
+    stream::{
+        options::{ReceiveOptions, SendOptions},
+        receive::receive_to_memory,
+        send::send_from_reader,
+    },
+};
+
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+const BLOCK_SIZES: [u32; 3] = [256 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+const CONCURRENCY: u16 = 4;
+/// Time the receiver is given to start listening before the sender connects.
+const RECEIVER_STARTUP: Duration = Duration::from_millis(100);
+
+fn transfer(data: &[u8], hash: [u8; 32], block_size: u32) -> Duration {
+    let receive_options = ReceiveOptions {
+        concurrency: CONCURRENCY,
+        identity_path: None,
+        peers_path: None,
+        ..ReceiveOptions::default()
+    };
+    let send_options = SendOptions {
+        block_size,
+        concurrency: CONCURRENCY,
+        history_path: None,
+        identity_path: None,
+        peers_path: None,
+        ..SendOptions::default()
+    };
+
+    thread::scope(|scope| {
+        let receiver = scope.spawn(|| {
+            let mut buffer = Vec::with_capacity(FILE_SIZE);
+            receive_to_memory(("127.0.0.1", HANDSHAKE_PORT), &mut buffer, &receive_options)
+                .unwrap_or_else(|e| panic!("Receive failed: {e}"));
+        });
+        thread::sleep(RECEIVER_STARTUP);
+
+        let started_at = Instant::now();
+        send_from_reader(
+            ("127.0.0.1", HANDSHAKE_PORT),
+            Cursor::new(data),
+            "bench.bin",
+            data.len() as u64,
+            hash,
+            &send_options,
+        )
+        .unwrap_or_else(|e| panic!("Send failed: {e}"));
+        receiver.join().unwrap();
+        started_at.elapsed()
+    })
+}
+
+fn loopback(c: &mut Criterion) {
+    // Failed transfers are explained in the log, e.g. with RUST_LOG=warn
+    let _ = env_logger::try_init();
+
+    // Half compressible, half random, like a mix of logs and media
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let data: Vec<u8> = (0..FILE_SIZE)
+        .map(|i| {
+            if i < FILE_SIZE / 2 {
+                (i / 64) as u8
+            } else {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }
+        })
+        .collect();
+    let hash = get_bytes_blake3_hash(&data);
+
+    let mut group = c.benchmark_group("loopback");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    for block_size in BLOCK_SIZES {
+        group.bench_function(BenchmarkId::from_parameter(block_size), |b| {
+            b.iter_custom(|iterations| {
+                (0..iterations)
+                    .map(|_| transfer(&data, hash, block_size))
+                    .sum()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, loopback);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+//! Reads arbitrary bytes as a frame of either peer. Reading must fail cleanly or produce a
+//! message that survives being encoded and read again.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sendfile::{
+    connection::read_next_payload,
+    transport::{attach_headers, ReceiverMessageV1, SenderMessageV1},
+};
+
+fuzz_target!(|frame: &[u8]| {
+    let mut buffer = vec![0u8; frame.len() + 1];
+    if let Ok(result) = read_next_payload::<SenderMessageV1, _>(&mut &frame[..], &mut buffer, 0) {
+        let mut encoded = vec![0u8; frame.len() + 64];
+        let reencoded = attach_headers(result.message.to_bytes(&mut encoded).unwrap());
+        let mut buffer = vec![0u8; reencoded.len() + 1];
+        let reread = read_next_payload::<SenderMessageV1, _>(&mut &reencoded[..], &mut buffer, 0)
+            .expect("re-encoded sender frame does not read back");
+        assert_eq!(reread.message, result.message);
+    }
+
+    let mut buffer = vec![0u8; frame.len() + 1];
+    if let Ok(result) = read_next_payload::<ReceiverMessageV1, _>(&mut &frame[..], &mut buffer, 0) {
+        let mut encoded = vec![0u8; frame.len() + 64];
+        let reencoded = attach_headers(result.message.to_bytes(&mut encoded).unwrap());
+        let mut buffer = vec![0u8; reencoded.len() + 1];
+        let reread = read_next_payload::<ReceiverMessageV1, _>(&mut &reencoded[..], &mut buffer, 0)
+            .expect("re-encoded receiver frame does not read back");
+        assert_eq!(reread.message, result.message);
+    }
+});
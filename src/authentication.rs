@@ -0,0 +1,270 @@
+//! Authentication of the sender to the receiver.
+//!
+//! Right after its handshake, a sender with an [Identity] signs an [AuthenticationV1] for the
+//! offered file. The receiver checks the signature and looks the key up in its trusted peers
+//! (see [crate::peers]), so it knows which machine the file comes from.
+//!
+//! The sender also hands the receiver a random [SessionV1] on the handshake connection. Every data
+//! connection then opens with a [ConnHelloV1] keyed with its token, so the sender only serves
+//! connections of the receiver it offered the file to.
+//!
+//! An authentication sent along with a session covers it, and a receiver only accepts each
+//! authentication once (see [SeenAuthentications]), so one captured on a plaintext connection
+//! can't be replayed to pass as its sender.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use thiserror::Error;
+
+use crate::{
+    identity::Identity,
+    transport::{AuthenticationV1, ConnHelloV1, SessionV1},
+};
+
+/// Domain separation prefix of the signed payload, so an authentication signature cannot be
+/// replayed as a signature over anything else.
+const AUTHENTICATION_CONTEXT: &[u8] = b"sendfile-auth-v1";
+
+/// Domain separation prefix of the signed payload of an authentication bound to a session.
+const SESSION_AUTHENTICATION_CONTEXT: &[u8] = b"sendfile-auth-session-v1";
+
+/// Domain separation prefix of the MAC in a [ConnHelloV1].
+const CONN_HELLO_CONTEXT: &[u8] = b"sendfile-conn-hello-v1";
+
+/// Largest accepted difference between the sender's and the receiver's clocks, in seconds.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 600;
+
+/// Errors that can occur while checking an authentication.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AuthenticationError {
+    /// The authentication is for a different file than the one offered.
+    #[error("Authentication is for a different file")]
+    FileMismatch,
+    /// The authentication is too old or from the future, it may be a replay.
+    #[error("Authentication timestamp is {0} seconds off")]
+    Expired(u64),
+    /// The public key or signature is malformed, or the signature does not match.
+    #[error("Invalid authentication signature")]
+    InvalidSignature,
+    /// The authentication was already accepted once, it is a replay.
+    #[error("Authentication was already used for another transfer")]
+    Replayed,
+}
+
+/// Creates an authentication for the file with `file_hash`, signed by `identity` and
+/// timestamped now. It covers `session` if the sender hands the receiver one.
+pub fn sign_authentication(
+    identity: &Identity,
+    file_hash: [u8; 32],
+    session: Option<&SessionV1>,
+) -> AuthenticationV1 {
+    let mut authentication = AuthenticationV1 {
+        file_hash,
+        timestamp: now(),
+        sender_key: identity.public_key(),
+        signature: Vec::new(),
+    };
+    authentication.signature = identity
+        .sign(&signed_payload(&authentication, session))
+        .to_vec();
+    authentication
+}
+
+/// Checks that `authentication` covers the file with `file_hash` and `session`, if the sender
+/// handed one, is recent, and that its signature was made by the key it carries.
+///
+/// Whether the authentication was used before is checked by [SeenAuthentications::admit].
+pub fn verify_authentication(
+    authentication: &AuthenticationV1,
+    file_hash: &[u8; 32],
+    session: Option<&SessionV1>,
+) -> Result<(), AuthenticationError> {
+    if authentication.file_hash != *file_hash {
+        return Err(AuthenticationError::FileMismatch);
+    }
+    let skew = now().abs_diff(authentication.timestamp);
+    if skew > MAX_CLOCK_SKEW_SECS {
+        return Err(AuthenticationError::Expired(skew));
+    }
+
+    let key = VerifyingKey::from_bytes(&authentication.sender_key)
+        .map_err(|_| AuthenticationError::InvalidSignature)?;
+    let signature = Signature::from_slice(&authentication.signature)
+        .map_err(|_| AuthenticationError::InvalidSignature)?;
+    key.verify_strict(&signed_payload(authentication, session), &signature)
+        .map_err(|_| AuthenticationError::InvalidSignature)
+}
+
+/// Signatures of the authentications a receiver accepted, kept for as long as an
+/// authentication is recent enough to be accepted.
+#[derive(Debug, Default)]
+pub struct SeenAuthentications {
+    /// Time each signature was first seen, in seconds since the Unix epoch.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl SeenAuthentications {
+    /// Returns the authentications accepted by this process.
+    pub fn global() -> &'static SeenAuthentications {
+        static SEEN: OnceLock<SeenAuthentications> = OnceLock::new();
+        SEEN.get_or_init(SeenAuthentications::default)
+    }
+
+    /// Records `authentication`, failing with [AuthenticationError::Replayed] if it was seen
+    /// before.
+    pub fn admit(&self, authentication: &AuthenticationV1) -> Result<(), AuthenticationError> {
+        let now = now();
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        // Older authentications fail the clock check anyway
+        seen.retain(|_, first_seen| now.abs_diff(*first_seen) <= 2 * MAX_CLOCK_SKEW_SECS);
+        if seen.insert(authentication.signature.clone(), now).is_some() {
+            return Err(AuthenticationError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+/// Creates a session with a random id and token.
+pub fn new_session() -> Result<SessionV1, getrandom::Error> {
+    let mut session = SessionV1 {
+        session_id: [0; 16],
+        token: [0; 32],
+    };
+    getrandom::fill(&mut session.session_id)?;
+    getrandom::fill(&mut session.token)?;
+    Ok(session)
+}
+
+/// Returns the hello opening the data connections of `session`.
+pub fn conn_hello(session: &SessionV1) -> ConnHelloV1 {
+    ConnHelloV1 {
+        session_id: session.session_id,
+        auth: hello_mac(session).into(),
+    }
+}
+
+/// Checks that `hello` opens a data connection of `session`.
+pub fn verify_conn_hello(session: &SessionV1, hello: &ConnHelloV1) -> bool {
+    // Comparing hashes takes constant time
+    hello.session_id == session.session_id && blake3::Hash::from(hello.auth) == hello_mac(session)
+}
+
+fn hello_mac(session: &SessionV1) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(&session.token);
+    hasher.update(CONN_HELLO_CONTEXT);
+    hasher.update(&session.session_id);
+    hasher.finalize()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the bytes covered by the authentication signature.
+fn signed_payload(authentication: &AuthenticationV1, session: Option<&SessionV1>) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(SESSION_AUTHENTICATION_CONTEXT.len() + 32 + 8 + 32 + 48);
+    payload.extend_from_slice(match session {
+        Some(_) => SESSION_AUTHENTICATION_CONTEXT,
+        None => AUTHENTICATION_CONTEXT,
+    });
+    payload.extend_from_slice(&authentication.file_hash);
+    payload.extend_from_slice(&authentication.timestamp.to_le_bytes());
+    payload.extend_from_slice(&authentication.sender_key);
+    if let Some(session) = session {
+        payload.extend_from_slice(&session.session_id);
+        payload.extend_from_slice(&session.token);
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authentication_roundtrip_and_tampering() {
+        let identity = Identity::from_seed([9; 32]);
+        let authentication = sign_authentication(&identity, [0xAB; 32], None);
+        assert_eq!(
+            verify_authentication(&authentication, &[0xAB; 32], None),
+            Ok(())
+        );
+
+        assert_eq!(
+            verify_authentication(&authentication, &[0xCD; 32], None),
+            Err(AuthenticationError::FileMismatch)
+        );
+
+        let mut impostor = authentication.clone();
+        impostor.sender_key = Identity::from_seed([1; 32]).public_key();
+        assert_eq!(
+            verify_authentication(&impostor, &[0xAB; 32], None),
+            Err(AuthenticationError::InvalidSignature)
+        );
+
+        let mut replayed = authentication;
+        replayed.timestamp -= 2 * MAX_CLOCK_SKEW_SECS;
+        assert!(matches!(
+            verify_authentication(&replayed, &[0xAB; 32], None),
+            Err(AuthenticationError::Expired(_))
+        ));
+    }
+
+    #[test]
+    fn test_authentication_is_bound_to_its_session() {
+        let identity = Identity::from_seed([9; 32]);
+        let session = new_session().unwrap();
+        let authentication = sign_authentication(&identity, [0xAB; 32], Some(&session));
+        assert_eq!(
+            verify_authentication(&authentication, &[0xAB; 32], Some(&session)),
+            Ok(())
+        );
+
+        // Replayed with another session, or without one
+        let other = new_session().unwrap();
+        assert_eq!(
+            verify_authentication(&authentication, &[0xAB; 32], Some(&other)),
+            Err(AuthenticationError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_authentication(&authentication, &[0xAB; 32], None),
+            Err(AuthenticationError::InvalidSignature)
+        );
+
+        // Replayed with its own session
+        let seen = SeenAuthentications::default();
+        assert_eq!(seen.admit(&authentication), Ok(()));
+        assert_eq!(
+            seen.admit(&authentication),
+            Err(AuthenticationError::Replayed)
+        );
+        let again = sign_authentication(&identity, [0xAB; 32], Some(&other));
+        assert_eq!(seen.admit(&again), Ok(()));
+    }
+
+    #[test]
+    fn test_conn_hello_belongs_to_its_session() {
+        let session = new_session().unwrap();
+        let other = new_session().unwrap();
+        assert_ne!(session, other);
+
+        let hello = conn_hello(&session);
+        assert!(verify_conn_hello(&session, &hello));
+        assert!(!verify_conn_hello(&other, &hello));
+
+        // The id alone isn't enough, the token must be known
+        let forged = ConnHelloV1 {
+            session_id: session.session_id,
+            auth: [0; 32],
+        };
+        assert!(!verify_conn_hello(&session, &forged));
+    }
+}
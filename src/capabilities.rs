@@ -0,0 +1,877 @@
+//! Optional protocol features a peer supports, advertised during the handshake.
+
+use std::{
+    fmt::Display,
+    ops::{BitAnd, BitOr},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Version of this build of sendfile, sent to the peer in the handshake.
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Set of optional features supported by a peer, exchanged on the wire as a bitmask.
+///
+/// Bits are grouped by area: compression codecs (0-5), checksum algorithms (8-9), protocol
+/// features (6-7, 10-23, 27 and 29-31) and security (24-26 and 28). Features added once those 32
+/// bits were in use take bits 32-63. Unknown bits sent by newer peers are preserved, so a set can
+/// be safely intersected with the local one.
+///
+/// The mask is encoded as a varint, so sets without bits past 31 encode as they did when it was
+/// 32 bits wide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Gzip compression of data blocks, the codec of peers that don't negotiate one.
+    pub const GZIP: Self = Self(1 << 0);
+    /// Zstandard compression of data blocks. Receivers that pick it tell the sender on every
+    /// data connection (`Algorithms`), see [AlgorithmsV1](crate::transport::AlgorithmsV1).
+    pub const ZSTD: Self = Self(1 << 1);
+    /// Sender answers requests it expects to serve later (e.g. past its connection limit, or for a
+    /// block its disk can't read for now) with `RetryAfter` instead of failing them, see
+    /// [crate::stream::retry_after].
+    pub const RETRY_AFTER: Self = Self(1 << 6);
+    /// Sender follows the handshake with the time on its clock (`Clock`), which the receiver
+    /// checks its own against, see [crate::stream::clock]. The last bit of the codec range, taken
+    /// once every feature bit was in use.
+    pub const CLOCK: Self = Self(1 << 7);
+
+    /// CRC-32 (ISO-HDLC) block checksums, the checksum of peers that don't negotiate one.
+    pub const CRC32: Self = Self(1 << 8);
+    /// CRC-32C (Castagnoli) block checksums, negotiated like [Self::ZSTD].
+    pub const CRC32C: Self = Self(1 << 9);
+
+    /// Peers cancelling the transfer tell the other side on its connections (`Cancel`), which
+    /// then stops at once instead of retrying, see [crate::stream::cancel].
+    pub const CANCEL: Self = Self(1 << 10);
+    /// Receivers request runs of consecutive blocks with a single `RequestRange` instead of a
+    /// request per block, see [RequestRangeV1](crate::transport::RequestRangeV1).
+    pub const RANGE_REQUEST: Self = Self(1 << 11);
+    /// Receivers downloading a range ask the sender to stream its blocks (`PushRange`) and only
+    /// acknowledge them a window at a time (`PushAck`), naming those to send again, instead of
+    /// requesting every block, see [PushRangeV1](crate::transport::PushRangeV1).
+    pub const PUSH_STREAM: Self = Self(1 << 12);
+    /// The handshake connection stays open as a control channel: the receiver reports its
+    /// progress (`Progress`) and the end of the transfer (`TransferComplete`) on it, the sender
+    /// pushes directives (`Abort`/`Throttle`), see [crate::stream::control].
+    pub const CONTROL_CHANNEL: Self = Self(1 << 13);
+    /// Receivers resuming a transfer tell the sender which blocks of a range they already hold
+    /// (`HaveBlocks`), and the sender answers every other block without waiting to be asked, see
+    /// [HaveBlocksV1](crate::transport::HaveBlocksV1).
+    pub const BLOCK_BITMAP: Self = Self(1 << 14);
+    /// Peers waiting on an idle connection send heartbeats (`Ping`/`Pong`), see
+    /// [crate::stream::heartbeat]. Not advertised with `--no-heartbeat`.
+    pub const HEARTBEAT: Self = Self(1 << 15);
+
+    /// Verification of existing blocks when resuming (`VerifyBlock`/`VerifyResponse`).
+    pub const VERIFY_BLOCK: Self = Self(1 << 16);
+    /// Several control messages of the receiver coalesced in a single frame (`Batch`), see
+    /// [BatchV1](crate::transport::BatchV1). Receivers batch the checksums of blocks verified
+    /// when resuming.
+    pub const BATCHING: Self = Self(1 << 17);
+    /// Signed delivery receipt returned by the receiver once the file is verified.
+    pub const RECEIPT: Self = Self(1 << 18);
+    /// BLAKE3 hashes of individual blocks (`BlockHashesRequest`/`BlockHashes`), used for reuse
+    /// of blocks from a local block store.
+    pub const BLOCK_HASHES: Self = Self(1 << 19);
+    /// Receiver answers the offered file with an accept/reject decision (`OfferResponse`), or
+    /// from protocol version 5 on with the parameters it accepted it with (`HandshakeAck`) or
+    /// the reason it refused it (`HandshakeReject`).
+    pub const OFFER_RESPONSE: Self = Self(1 << 20);
+    /// Sender only probes the receiver (`send --dry-run`): the receiver answers the offer, listing
+    /// conflicts in the reason of an accepted offer, and no data connection follows. Senders only
+    /// advertise it for dry runs.
+    pub const DRY_RUN: Self = Self(1 << 21);
+    /// Sender follows the handshake with the first bytes of the file (`FileHeader`), so the
+    /// receiver can check their type before accepting it.
+    pub const FILE_HEADER: Self = Self(1 << 22);
+    /// Sender only measures the path to the receiver (`sendfile probe`): the receiver answers
+    /// timed `Probe` messages on the handshake connection and no file is offered. Senders only
+    /// advertise it for probes.
+    pub const PROBE: Self = Self(1 << 23);
+
+    /// Encrypted handshake and data connections.
+    pub const ENCRYPTION: Self = Self(1 << 24);
+    /// Sender signs the offer with its identity key (`Authentication`), the receiver signs its
+    /// receipt.
+    pub const AUTHENTICATION: Self = Self(1 << 25);
+    /// Sender hands out a session on the handshake connection (`Session`) and receivers open
+    /// every data connection with a hello proving it belongs to that session (`ConnHello`).
+    pub const CONN_HELLO: Self = Self(1 << 26);
+    /// Sender lists the protocol versions it supports on the handshake connection
+    /// (`ProtocolVersions`) and the receiver answers with the one it picked (`ProtocolVersion`).
+    /// The handshake itself is framed as protocol version 1, which every receiver reads.
+    pub const VERSION_NEGOTIATION: Self = Self(1 << 27);
+    /// Handshake and data connections encrypted with a Noise channel set up on the handshake
+    /// connection (`NoiseHandshake`), see [crate::noise]. Senders only advertise it with
+    /// `--noise`.
+    pub const NOISE: Self = Self(1 << 28);
+    /// Sender follows the handshake with key/value metadata of the file (`Metadata`), see
+    /// [crate::file::metadata]. Senders only advertise it with metadata to send.
+    pub const METADATA: Self = Self(1 << 29);
+    /// Blocks requested on a data connection (`UdpRequest`) are sent as UDP datagrams with
+    /// forward error correction, see [crate::stream::udp]. Senders only advertise it with
+    /// `--udp`, and never over encrypted connections.
+    pub const UDP_FEC: Self = Self(1 << 30);
+    /// Blocks requested with a `SparseRequest` that only hold zeros are answered with a
+    /// `ZeroBlock` instead of their data, so empty regions of disk images don't cross the wire.
+    pub const ZERO_BLOCKS: Self = Self(1 << 31);
+
+    /// Receivers end every data connection with a `Goodbye`, after their `TransferComplete` if
+    /// they report it there, and close it once the sender answered with its own, see
+    /// [GoodbyeV1](crate::transport::GoodbyeV1).
+    pub const GOODBYE: Self = Self(1 << 32);
+
+    /// Human readable names of every known capability, in bit order.
+    const NAMES: &[(Self, &'static str)] = &[
+        (Self::GZIP, "gzip"),
+        (Self::ZSTD, "zstd"),
+        (Self::RETRY_AFTER, "retry hints"),
+        (Self::CLOCK, "clock check"),
+        (Self::CRC32, "crc32"),
+        (Self::CRC32C, "crc32c"),
+        (Self::CANCEL, "cancellation"),
+        (Self::RANGE_REQUEST, "range requests"),
+        (Self::PUSH_STREAM, "push streaming"),
+        (Self::CONTROL_CHANNEL, "control channel"),
+        (Self::BLOCK_BITMAP, "block bitmaps"),
+        (Self::HEARTBEAT, "heartbeats"),
+        (Self::VERIFY_BLOCK, "block verification"),
+        (Self::BATCHING, "batching"),
+        (Self::RECEIPT, "receipts"),
+        (Self::BLOCK_HASHES, "block hashes"),
+        (Self::OFFER_RESPONSE, "offer responses"),
+        (Self::DRY_RUN, "dry runs"),
+        (Self::FILE_HEADER, "file headers"),
+        (Self::PROBE, "probes"),
+        (Self::ENCRYPTION, "encryption"),
+        (Self::AUTHENTICATION, "authentication"),
+        (Self::CONN_HELLO, "connection hellos"),
+        (Self::VERSION_NEGOTIATION, "version negotiation"),
+        (Self::NOISE, "noise channel"),
+        (Self::METADATA, "metadata"),
+        (Self::UDP_FEC, "udp with fec"),
+        (Self::ZERO_BLOCKS, "zero blocks"),
+        (Self::GOODBYE, "goodbyes"),
+    ];
+
+    /// Returns an empty set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the capabilities supported by this build.
+    pub const fn local() -> Self {
+        Self(
+            Self::GZIP.0
+                | Self::ZSTD.0
+                | Self::RETRY_AFTER.0
+                | Self::CLOCK.0
+                | Self::CRC32.0
+                | Self::CRC32C.0
+                | Self::CANCEL.0
+                | Self::RANGE_REQUEST.0
+                | Self::PUSH_STREAM.0
+                | Self::CONTROL_CHANNEL.0
+                | Self::BLOCK_BITMAP.0
+                | Self::HEARTBEAT.0
+                | Self::VERIFY_BLOCK.0
+                | Self::BATCHING.0
+                | Self::RECEIPT.0
+                | Self::BLOCK_HASHES.0
+                | Self::OFFER_RESPONSE.0
+                | Self::DRY_RUN.0
+                | Self::FILE_HEADER.0
+                | Self::PROBE.0
+                | Self::AUTHENTICATION.0
+                | Self::CONN_HELLO.0
+                | Self::VERSION_NEGOTIATION.0
+                | Self::NOISE.0
+                | Self::METADATA.0
+                | Self::UDP_FEC.0
+                | Self::ZERO_BLOCKS.0
+                | Self::GOODBYE.0,
+        )
+    }
+
+    /// Returns the capabilities supported by this build over connections secured with TLS or
+    /// not: [Self::local], with [Self::ENCRYPTION] if they are `encrypted`, see [crate::tls].
+    pub const fn local_with_encryption(encrypted: bool) -> Self {
+        if encrypted {
+            Self(Self::local().0 | Self::ENCRYPTION.0)
+        } else {
+            Self::local()
+        }
+    }
+
+    /// Creates a set from its raw bitmask, keeping unknown bits.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw bitmask.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns `true` if every capability in `other` is also in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the capabilities of `self` that are not in `other`.
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Returns the names of the known capabilities in the set.
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// Returns the name of a single capability, or `None` for unknown or combined sets.
+    pub fn name(self) -> Option<&'static str> {
+        Self::NAMES
+            .iter()
+            .find(|(capability, _)| *capability == self)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.names();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Compression codec applied to data blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CompressionCodec {
+    /// Blocks are sent as-is.
+    None,
+    /// Gzip (deflate) compression.
+    Gzip,
+    /// Zstandard compression.
+    Zstd,
+}
+
+impl CompressionCodec {
+    /// Codecs in order of preference, best first.
+    pub const PREFERENCE: &[Self] = &[Self::Zstd, Self::Gzip];
+
+    /// Returns the capability advertising support for this codec.
+    pub const fn capability(self) -> Capabilities {
+        match self {
+            Self::None => Capabilities::empty(),
+            Self::Gzip => Capabilities::GZIP,
+            Self::Zstd => Capabilities::ZSTD,
+        }
+    }
+}
+
+impl Display for CompressionCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Checksum algorithm used to verify data blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    /// CRC-32 (ISO-HDLC), the original block checksum.
+    Crc32,
+    /// CRC-32C (Castagnoli), hardware accelerated on most CPUs.
+    Crc32c,
+}
+
+impl ChecksumAlgorithm {
+    /// Algorithms in order of preference, best first.
+    pub const PREFERENCE: &[Self] = &[Self::Crc32c, Self::Crc32];
+
+    /// Returns the capability advertising support for this algorithm.
+    pub const fn capability(self) -> Capabilities {
+        match self {
+            Self::Crc32 => Capabilities::CRC32,
+            Self::Crc32c => Capabilities::CRC32C,
+        }
+    }
+}
+
+impl Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Crc32 => write!(f, "crc32"),
+            Self::Crc32c => write!(f, "crc32c"),
+        }
+    }
+}
+
+/// Features chosen for a transfer after comparing the capabilities of both peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureSet {
+    /// Best compression codec supported by both sides, or [CompressionCodec::None].
+    pub compression: CompressionCodec,
+    /// Best checksum algorithm supported by both sides.
+    pub checksum: ChecksumAlgorithm,
+    /// Whether control messages may be batched, otherwise requests are sent one at a time.
+    pub batching: bool,
+    /// Whether existing blocks can be verified on resume, otherwise they are downloaded again.
+    pub verify_blocks: bool,
+    /// Whether the receiver returns a signed receipt once the file is verified.
+    pub receipt: bool,
+    /// Whether block hashes can be requested, so blocks already in a local store are reused.
+    pub block_hashes: bool,
+    /// Whether the receiver tells the sender if it accepted or rejected the file.
+    pub offer_response: bool,
+    /// Whether the sender only probes the receiver, see [Capabilities::DRY_RUN].
+    pub dry_run: bool,
+    /// Whether the sender sends the first bytes of the file, see [Capabilities::FILE_HEADER].
+    pub file_header: bool,
+    /// Whether the sender only measures the path, see [Capabilities::PROBE].
+    pub probe: bool,
+    /// Whether the connections are encrypted.
+    pub encryption: bool,
+    /// Whether the sender proved its identity, see [crate::authentication].
+    pub authentication: bool,
+    /// Whether data connections open with a hello naming their session, see
+    /// [Capabilities::CONN_HELLO].
+    pub conn_hello: bool,
+    /// Whether the protocol version is negotiated, otherwise the version the handshake is
+    /// framed with is used, see [Capabilities::VERSION_NEGOTIATION].
+    pub version_negotiation: bool,
+    /// Whether the connections are encrypted with a Noise channel, see [Capabilities::NOISE].
+    pub noise: bool,
+    /// Whether the sender sends metadata of the file, see [Capabilities::METADATA].
+    pub metadata: bool,
+    /// Whether blocks are sent as UDP datagrams, see [Capabilities::UDP_FEC].
+    pub udp_fec: bool,
+    /// Whether blocks of zeros are only named by the sender, see [Capabilities::ZERO_BLOCKS].
+    pub zero_blocks: bool,
+    /// Whether idle connections carry heartbeats, see [Capabilities::HEARTBEAT].
+    pub heartbeat: bool,
+    /// Whether resuming receivers name the blocks they hold instead of verifying them, see
+    /// [Capabilities::BLOCK_BITMAP].
+    pub block_bitmap: bool,
+    /// Whether the handshake connection carries progress and directives, see
+    /// [Capabilities::CONTROL_CHANNEL].
+    pub control_channel: bool,
+    /// Whether the blocks of a range are streamed by the sender rather than requested one by
+    /// one, see [Capabilities::PUSH_STREAM].
+    pub push_stream: bool,
+    /// Whether runs of consecutive blocks are requested with a single message, see
+    /// [Capabilities::RANGE_REQUEST].
+    pub range_request: bool,
+    /// Whether a peer cancelling the transfer tells the other side, see [Capabilities::CANCEL].
+    pub cancel: bool,
+    /// Whether the sender tells the receiver the time on its clock, see [Capabilities::CLOCK].
+    pub clock: bool,
+    /// Whether the sender asks the receiver to retry later rather than failing requests, see
+    /// [Capabilities::RETRY_AFTER].
+    pub retry_after: bool,
+    /// Whether data connections end with goodbyes, see [Capabilities::GOODBYE].
+    pub goodbye: bool,
+}
+
+/// A feature that was downgraded because the peer lacks it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Downgrade {
+    /// Preferred capability the peer is missing.
+    pub wanted: Capabilities,
+    /// What is used instead.
+    pub fallback: String,
+}
+
+impl Display for Downgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer lacks {}, using {}", self.wanted, self.fallback)
+    }
+}
+
+impl FeatureSet {
+    /// Picks the best features supported by both `local` and `peer`.
+    ///
+    /// Preferred features the peer lacks (but `local` supports) are returned as [Downgrade]s so
+    /// they can be logged. Returns `None` if the peers have no checksum algorithm in common, in
+    /// which case blocks cannot be verified and the transfer must not proceed.
+    pub fn negotiate(local: Capabilities, peer: Capabilities) -> Option<(Self, Vec<Downgrade>)> {
+        let common = local & peer;
+        let mut downgrades = Vec::new();
+        let mut note_downgrade = |wanted: Capabilities, fallback: String| {
+            if local.contains(wanted) && !peer.contains(wanted) {
+                downgrades.push(Downgrade { wanted, fallback });
+            }
+        };
+
+        let compression = pick(CompressionCodec::PREFERENCE, common, |c| c.capability())
+            .unwrap_or(CompressionCodec::None);
+        for codec in CompressionCodec::PREFERENCE
+            .iter()
+            .take_while(|codec| **codec != compression)
+        {
+            note_downgrade(codec.capability(), compression.to_string());
+        }
+
+        let checksum = pick(ChecksumAlgorithm::PREFERENCE, common, |c| c.capability())?;
+        for algorithm in ChecksumAlgorithm::PREFERENCE
+            .iter()
+            .take_while(|algorithm| **algorithm != checksum)
+        {
+            note_downgrade(algorithm.capability(), checksum.to_string());
+        }
+
+        let batching = common.contains(Capabilities::BATCHING);
+        note_downgrade(Capabilities::BATCHING, String::from("single requests"));
+
+        let verify_blocks = common.contains(Capabilities::VERIFY_BLOCK);
+        note_downgrade(
+            Capabilities::VERIFY_BLOCK,
+            String::from("full re-download on resume"),
+        );
+
+        let receipt = common.contains(Capabilities::RECEIPT);
+        note_downgrade(Capabilities::RECEIPT, String::from("no delivery receipt"));
+
+        let block_hashes = common.contains(Capabilities::BLOCK_HASHES);
+        note_downgrade(
+            Capabilities::BLOCK_HASHES,
+            String::from("no local block reuse"),
+        );
+
+        let offer_response = common.contains(Capabilities::OFFER_RESPONSE);
+        note_downgrade(
+            Capabilities::OFFER_RESPONSE,
+            String::from("rejections surface as timeouts"),
+        );
+
+        // Not a downgrade, senders only advertise it for dry runs
+        let dry_run = common.contains(Capabilities::DRY_RUN);
+
+        let file_header = common.contains(Capabilities::FILE_HEADER);
+        note_downgrade(
+            Capabilities::FILE_HEADER,
+            String::from("file types told by extension only"),
+        );
+
+        // Not a downgrade either, senders only advertise it for probes
+        let probe = common.contains(Capabilities::PROBE);
+
+        let encryption = common.contains(Capabilities::ENCRYPTION);
+        note_downgrade(Capabilities::ENCRYPTION, String::from("plaintext"));
+
+        let authentication = common.contains(Capabilities::AUTHENTICATION);
+        note_downgrade(
+            Capabilities::AUTHENTICATION,
+            String::from("unauthenticated peers"),
+        );
+
+        let conn_hello = common.contains(Capabilities::CONN_HELLO);
+        note_downgrade(
+            Capabilities::CONN_HELLO,
+            String::from("data connections not tied to the session"),
+        );
+
+        let version_negotiation = common.contains(Capabilities::VERSION_NEGOTIATION);
+        note_downgrade(
+            Capabilities::VERSION_NEGOTIATION,
+            String::from("protocol version of the handshake"),
+        );
+
+        // Not a downgrade, senders only advertise it with --noise
+        let noise = common.contains(Capabilities::NOISE);
+
+        // Not a downgrade, senders only advertise it with metadata
+        let metadata = common.contains(Capabilities::METADATA);
+
+        // Not a downgrade, senders only advertise it with --udp. Datagrams are plaintext, so
+        // encrypted transfers keep every block on their connections
+        let udp_fec = common.contains(Capabilities::UDP_FEC) && !encryption && !noise;
+
+        let zero_blocks = common.contains(Capabilities::ZERO_BLOCKS);
+        note_downgrade(
+            Capabilities::ZERO_BLOCKS,
+            String::from("blocks of zeros sent in full"),
+        );
+
+        let heartbeat = common.contains(Capabilities::HEARTBEAT);
+        note_downgrade(
+            Capabilities::HEARTBEAT,
+            String::from("no heartbeats on idle connections"),
+        );
+
+        let block_bitmap = common.contains(Capabilities::BLOCK_BITMAP);
+        note_downgrade(
+            Capabilities::BLOCK_BITMAP,
+            String::from("per-block verification of resumed files"),
+        );
+
+        let control_channel = common.contains(Capabilities::CONTROL_CHANNEL);
+        note_downgrade(
+            Capabilities::CONTROL_CHANNEL,
+            String::from("progress counted by the sender"),
+        );
+
+        let push_stream = common.contains(Capabilities::PUSH_STREAM);
+        note_downgrade(
+            Capabilities::PUSH_STREAM,
+            String::from("a request per block"),
+        );
+
+        let range_request = common.contains(Capabilities::RANGE_REQUEST);
+        note_downgrade(
+            Capabilities::RANGE_REQUEST,
+            String::from("a request per block"),
+        );
+
+        let cancel = common.contains(Capabilities::CANCEL);
+        note_downgrade(
+            Capabilities::CANCEL,
+            String::from("connections closed without notice"),
+        );
+
+        let clock = common.contains(Capabilities::CLOCK);
+        note_downgrade(
+            Capabilities::CLOCK,
+            String::from("clocks assumed to be in sync"),
+        );
+
+        let retry_after = common.contains(Capabilities::RETRY_AFTER);
+        note_downgrade(
+            Capabilities::RETRY_AFTER,
+            String::from("blind backoff on busy senders"),
+        );
+
+        let goodbye = common.contains(Capabilities::GOODBYE);
+        note_downgrade(
+            Capabilities::GOODBYE,
+            String::from("data connections closed without goodbyes"),
+        );
+
+        Some((
+            Self {
+                compression,
+                checksum,
+                batching,
+                verify_blocks,
+                receipt,
+                block_hashes,
+                offer_response,
+                dry_run,
+                file_header,
+                probe,
+                encryption,
+                authentication,
+                conn_hello,
+                version_negotiation,
+                noise,
+                metadata,
+                udp_fec,
+                zero_blocks,
+                heartbeat,
+                block_bitmap,
+                control_channel,
+                push_stream,
+                range_request,
+                cancel,
+                clock,
+                retry_after,
+                goodbye,
+            },
+            downgrades,
+        ))
+    }
+
+    /// Returns the capabilities the chosen features stand for, which the peers share: the codec,
+    /// the checksum algorithm and every feature in use.
+    pub fn capabilities(&self) -> Capabilities {
+        [
+            (self.goodbye, Capabilities::GOODBYE),
+            (self.retry_after, Capabilities::RETRY_AFTER),
+            (self.clock, Capabilities::CLOCK),
+            (self.cancel, Capabilities::CANCEL),
+            (self.range_request, Capabilities::RANGE_REQUEST),
+            (self.push_stream, Capabilities::PUSH_STREAM),
+            (self.control_channel, Capabilities::CONTROL_CHANNEL),
+            (self.block_bitmap, Capabilities::BLOCK_BITMAP),
+            (self.heartbeat, Capabilities::HEARTBEAT),
+            (self.verify_blocks, Capabilities::VERIFY_BLOCK),
+            (self.batching, Capabilities::BATCHING),
+            (self.receipt, Capabilities::RECEIPT),
+            (self.block_hashes, Capabilities::BLOCK_HASHES),
+            (self.offer_response, Capabilities::OFFER_RESPONSE),
+            (self.dry_run, Capabilities::DRY_RUN),
+            (self.file_header, Capabilities::FILE_HEADER),
+            (self.probe, Capabilities::PROBE),
+            (self.encryption, Capabilities::ENCRYPTION),
+            (self.authentication, Capabilities::AUTHENTICATION),
+            (self.conn_hello, Capabilities::CONN_HELLO),
+            (self.version_negotiation, Capabilities::VERSION_NEGOTIATION),
+            (self.noise, Capabilities::NOISE),
+            (self.metadata, Capabilities::METADATA),
+            (self.udp_fec, Capabilities::UDP_FEC),
+            (self.zero_blocks, Capabilities::ZERO_BLOCKS),
+        ]
+        .into_iter()
+        .filter(|(used, _)| *used)
+        .fold(
+            self.compression.capability() | self.checksum.capability(),
+            |capabilities, (_, capability)| capabilities | capability,
+        )
+    }
+}
+
+impl Display for FeatureSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "compression={}, checksum={}, batching={}, verify_blocks={}, receipt={}, block_hashes={}, offer_response={}, dry_run={}, file_header={}, probe={}, encryption={}, authentication={}, conn_hello={}, version_negotiation={}, noise={}, metadata={}, udp_fec={}, zero_blocks={}, heartbeat={}, block_bitmap={}, control_channel={}, push_stream={}, range_request={}, cancel={}, clock={}, retry_after={}, goodbye={}",
+            self.compression,
+            self.checksum,
+            self.batching,
+            self.verify_blocks,
+            self.receipt,
+            self.block_hashes,
+            self.offer_response,
+            self.dry_run,
+            self.file_header,
+            self.probe,
+            self.encryption,
+            self.authentication,
+            self.conn_hello,
+            self.version_negotiation,
+            self.noise,
+            self.metadata,
+            self.udp_fec,
+            self.zero_blocks,
+            self.heartbeat,
+            self.block_bitmap,
+            self.control_channel,
+            self.push_stream,
+            self.range_request,
+            self.cancel,
+            self.clock,
+            self.retry_after,
+            self.goodbye
+        )
+    }
+}
+
+/// Requirements enforced in strict mode, for transfers that must never fall back to an insecure
+/// or older protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrictPolicy {
+    /// Lowest protocol version that may be used.
+    pub min_protocol_version: u8,
+}
+
+impl StrictPolicy {
+    /// Checks that `capabilities` (the local set, or the set shared with the peer) include
+    /// encryption, with TLS or a Noise channel, and authentication and that `protocol_version`
+    /// is recent enough.
+    ///
+    /// Returns a description of every unmet requirement on failure.
+    pub fn check(&self, capabilities: Capabilities, protocol_version: u8) -> Result<(), String> {
+        let mut violations = Vec::new();
+
+        if !capabilities.contains(Capabilities::ENCRYPTION)
+            && !capabilities.contains(Capabilities::NOISE)
+        {
+            violations.push(format!("{} is not available", Capabilities::ENCRYPTION));
+        }
+        if !capabilities.contains(Capabilities::AUTHENTICATION) {
+            violations.push(format!("{} is not available", Capabilities::AUTHENTICATION));
+        }
+
+        if protocol_version < self.min_protocol_version {
+            violations.push(format!(
+                "protocol version {} is below the required minimum {}",
+                protocol_version, self.min_protocol_version
+            ));
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations.join(", "))
+        }
+    }
+}
+
+/// Returns the first entry of `preference` whose capability is in `common`.
+fn pick<T: Copy>(
+    preference: &[T],
+    common: Capabilities,
+    capability: impl Fn(T) -> Capabilities,
+) -> Option<T> {
+    preference
+        .iter()
+        .copied()
+        .find(|item| common.contains(capability(*item)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_picks_best_common_features() {
+        let local = Capabilities::ZSTD
+            | Capabilities::GZIP
+            | Capabilities::CRC32C
+            | Capabilities::CRC32
+            | Capabilities::BATCHING;
+        let (features, downgrades) = FeatureSet::negotiate(local, local).unwrap();
+
+        assert_eq!(features.compression, CompressionCodec::Zstd);
+        assert_eq!(features.checksum, ChecksumAlgorithm::Crc32c);
+        assert!(features.batching);
+        assert!(downgrades.is_empty());
+        assert_eq!(
+            features.capabilities(),
+            Capabilities::ZSTD | Capabilities::CRC32C | Capabilities::BATCHING
+        );
+
+        let local = Capabilities::local();
+        let (features, _) = FeatureSet::negotiate(local, local).unwrap();
+        assert!(local.contains(features.capabilities()));
+    }
+
+    #[test]
+    fn test_negotiate_degrades_to_peer_features() {
+        let local = Capabilities::ZSTD
+            | Capabilities::GZIP
+            | Capabilities::CRC32C
+            | Capabilities::CRC32
+            | Capabilities::BATCHING;
+        let peer = Capabilities::GZIP | Capabilities::CRC32;
+        let (features, downgrades) = FeatureSet::negotiate(local, peer).unwrap();
+
+        assert_eq!(features.compression, CompressionCodec::Gzip);
+        assert_eq!(features.checksum, ChecksumAlgorithm::Crc32);
+        assert!(!features.batching);
+        assert_eq!(
+            downgrades.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+            vec![
+                "peer lacks zstd, using gzip",
+                "peer lacks crc32c, using crc32",
+                "peer lacks batching, using single requests",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_udp_is_never_negotiated_over_encrypted_connections() {
+        let local = Capabilities::CRC32 | Capabilities::UDP_FEC;
+        let (features, _) = FeatureSet::negotiate(local, local).unwrap();
+        assert!(features.udp_fec);
+
+        for encryption in [Capabilities::ENCRYPTION, Capabilities::NOISE] {
+            let (features, _) =
+                FeatureSet::negotiate(local | encryption, local | encryption).unwrap();
+            assert!(!features.udp_fec);
+        }
+    }
+
+    #[test]
+    fn test_strict_policy() {
+        let policy = StrictPolicy {
+            min_protocol_version: 2,
+        };
+        let secure = Capabilities::ENCRYPTION | Capabilities::AUTHENTICATION;
+
+        assert!(policy.check(secure, 2).is_ok());
+        assert!(policy
+            .check(Capabilities::NOISE | Capabilities::AUTHENTICATION, 2)
+            .is_ok());
+        assert_eq!(
+            policy.check(Capabilities::ENCRYPTION, 1).unwrap_err(),
+            "authentication is not available, protocol version 1 is below the required minimum 2"
+        );
+    }
+
+    #[test]
+    fn test_negotiate_without_common_checksum_fails() {
+        let local = Capabilities::GZIP | Capabilities::CRC32;
+        let peer = Capabilities::GZIP | Capabilities::CRC32C;
+        assert!(FeatureSet::negotiate(local, peer).is_none());
+    }
+
+    #[test]
+    fn test_negotiate_without_common_codec_disables_compression() {
+        let local = Capabilities::GZIP | Capabilities::CRC32;
+        let peer = Capabilities::CRC32;
+        let (features, _) = FeatureSet::negotiate(local, peer).unwrap();
+        assert_eq!(features.compression, CompressionCodec::None);
+    }
+
+    #[test]
+    fn test_contains_and_intersection() {
+        let peer = Capabilities::GZIP | Capabilities::CRC32;
+        assert!(peer.contains(Capabilities::GZIP));
+        assert!(!peer.contains(Capabilities::ZSTD));
+        assert!(!peer.contains(Capabilities::GZIP | Capabilities::ZSTD));
+        assert_eq!(peer.without(Capabilities::GZIP), Capabilities::CRC32);
+
+        let common = peer & (Capabilities::GZIP | Capabilities::ZSTD);
+        assert_eq!(common, Capabilities::GZIP);
+    }
+
+    #[test]
+    fn test_unknown_bits_are_preserved() {
+        let peer = Capabilities::from_bits(Capabilities::GZIP.bits() | 1 << 5);
+        assert_eq!(peer.bits() >> 5, 1);
+        assert_eq!(peer.names(), vec!["gzip"]);
+    }
+
+    #[test]
+    fn test_masks_without_extension_bits_encode_as_before() {
+        let mut wide = [0u8; 16];
+        let mut narrow = [0u8; 16];
+        let local = Capabilities::local().without(Capabilities::GOODBYE);
+        let wide = postcard::to_slice(&local, &mut wide).unwrap();
+        let narrow = postcard::to_slice(&(local.bits() as u32), &mut narrow).unwrap();
+        assert_eq!(wide, narrow);
+
+        let peer: Capabilities = postcard::from_bytes(narrow).unwrap();
+        assert_eq!(peer, local);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Capabilities::empty().to_string(), "none");
+        assert_eq!(
+            (Capabilities::ZSTD | Capabilities::GZIP).to_string(),
+            "gzip, zstd"
+        );
+        assert_eq!(Capabilities::ZSTD.name(), Some("zstd"));
+    }
+}
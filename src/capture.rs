@@ -0,0 +1,569 @@
+//! Wire-level captures of the frames a peer exchanges, for debugging (`--capture`).
+//!
+//! With the hidden global `--capture PATH`, every connection of a send, receive or probe records
+//! the bytes it writes and reads, as the protocol sees them (after TLS and Noise decryption),
+//! with the time they crossed the connection. `sendfile replay PATH` splits them back into frames
+//! and prints every message with its direction, framing and session, so protocol bugs between
+//! versions can be diagnosed without Wireshark dissectors.
+//!
+//! A capture is a JSON Lines file: a [CaptureHeader] naming the peer that recorded it, then a
+//! [CaptureRecord] per read or write. UDP datagrams are not captured, nor is anything exchanged
+//! before the handshake (relay rendezvous, WebSocket upgrade).
+
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    capabilities::SOFTWARE_VERSION,
+    connection::{read_next_payload, StreamReadError},
+    history::{from_hex, to_hex},
+    transport::{DataV1, ReceiverMessageV1, SenderMessageV1, MAX_MESSAGE_SIZE, SESSION_ID_SIZE},
+};
+
+/// Version of the capture format, in the [CaptureHeader].
+pub const CAPTURE_FORMAT_VERSION: u32 = 1;
+
+/// Capture every connection of this process records into, see [Capture::start].
+static GLOBAL: OnceLock<Arc<Capture>> = OnceLock::new();
+
+/// Errors of reading a capture.
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("Failed to read the capture: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Line {line} of the capture is invalid: {details}")]
+    InvalidRecord { line: usize, details: String },
+
+    #[error("Unsupported capture format {0}, this build reads format {CAPTURE_FORMAT_VERSION}")]
+    UnsupportedFormat(u32),
+}
+
+/// Peer that recorded a capture, telling which messages its frames carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sender => write!(f, "sender"),
+            Self::Receiver => write!(f, "receiver"),
+        }
+    }
+}
+
+/// Way bytes crossed a connection, seen from the peer that recorded them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Sent => "sent",
+            Self::Received => "received",
+        })
+    }
+}
+
+/// First line of a capture.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureHeader {
+    /// Version of the capture format, [CAPTURE_FORMAT_VERSION].
+    pub format: u32,
+    /// Peer that recorded the capture.
+    pub role: Role,
+    /// Version of sendfile that recorded the capture.
+    pub software_version: String,
+    /// Time the capture started, in milliseconds since the Unix epoch.
+    pub started_at_ms: u64,
+}
+
+/// Bytes written or read on a connection, every line of a capture after its header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    /// Time since the capture started, in microseconds.
+    pub at_us: u64,
+    /// Connection the bytes crossed, numbered in the order connections were opened.
+    pub connection: u32,
+    pub direction: Direction,
+    /// The bytes, hex encoded.
+    #[serde(default)]
+    pub data: String,
+    /// Number of bytes recorded last in [Self::direction] that were not frames after all: bytes
+    /// read ahead of a Noise handshake, recorded again once the channel decrypted them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retracted: Option<usize>,
+}
+
+/// File connections record the bytes they exchange into.
+pub struct Capture {
+    file: Mutex<File>,
+    started: Instant,
+    next_connection: AtomicU32,
+    /// Whether a record failed to be written, warned about once.
+    failed: AtomicBool,
+}
+
+impl Capture {
+    /// Creates the capture file at `path`, recorded by `role`.
+    pub fn create(path: &Path, role: Role) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let header = CaptureHeader {
+            format: CAPTURE_FORMAT_VERSION,
+            role,
+            software_version: SOFTWARE_VERSION.to_string(),
+            started_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        };
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            started: Instant::now(),
+            next_connection: AtomicU32::new(0),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Creates the capture file at `path` and makes every connection opened from now on record
+    /// into it, see [Self::global].
+    ///
+    /// # Returns
+    ///
+    /// An error if the file can't be created, or if a capture was already started.
+    pub fn start(path: &Path, role: Role) -> io::Result<()> {
+        let capture = Arc::new(Self::create(path, role)?);
+        GLOBAL
+            .set(capture)
+            .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "Capture already started"))
+    }
+
+    /// Returns the capture started with [Self::start], if any.
+    pub fn global() -> Option<&'static Arc<Capture>> {
+        GLOBAL.get()
+    }
+
+    /// Returns a tap recording the bytes of a new connection.
+    pub fn tap(self: &Arc<Self>) -> CaptureTap {
+        CaptureTap {
+            capture: self.clone(),
+            connection: self.next_connection.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn write(&self, record: &CaptureRecord) {
+        let result = serde_json::to_string(record)
+            .map_err(io::Error::from)
+            .and_then(|line| {
+                let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+                writeln!(file, "{}", line)
+            });
+        // A capture never fails the transfer it records
+        if let Err(e) = result
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            warn!("Failed to record the capture, it is incomplete: {}", e);
+        }
+    }
+}
+
+/// Records the bytes of one connection into a [Capture].
+pub struct CaptureTap {
+    capture: Arc<Capture>,
+    connection: u32,
+}
+
+impl CaptureTap {
+    /// Returns a tap of the global capture, `None` unless one was started.
+    pub fn global() -> Option<Self> {
+        Capture::global().map(Capture::tap)
+    }
+
+    /// Records `bytes` crossing the connection in `direction`.
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.capture.write(&CaptureRecord {
+            at_us: self.elapsed_us(),
+            connection: self.connection,
+            direction,
+            data: to_hex(bytes),
+            retracted: None,
+        });
+    }
+
+    /// Takes back the last `len` bytes recorded in `direction`, which were not frames.
+    pub fn retract(&self, direction: Direction, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.capture.write(&CaptureRecord {
+            at_us: self.elapsed_us(),
+            connection: self.connection,
+            direction,
+            data: String::new(),
+            retracted: Some(len),
+        });
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        self.capture.started.elapsed().as_micros() as u64
+    }
+}
+
+/// A frame of a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    /// Time since the capture started at which the last byte of the frame crossed the
+    /// connection.
+    pub at: Duration,
+    pub connection: u32,
+    pub direction: Direction,
+    /// Protocol version the frame is framed with.
+    pub protocol_version: u8,
+    /// Session id the frame is tagged with.
+    pub session_id: Option<[u8; SESSION_ID_SIZE]>,
+    /// Length of the frame, headers included.
+    pub len: usize,
+    /// The message, with the data of blocks summed up by their length.
+    pub message: String,
+}
+
+/// Bytes of a capture that are not a frame, at the end of what a connection exchanged in one
+/// direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnparsedBytes {
+    pub connection: u32,
+    pub direction: Direction,
+    /// Offset of the bytes in what the connection exchanged in that direction.
+    pub offset: usize,
+    pub len: usize,
+    /// Why they are not a frame.
+    pub reason: String,
+}
+
+/// Frames of a capture, see [read_capture].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    pub header: CaptureHeader,
+    /// Every frame, in the order their last byte crossed their connection.
+    pub frames: Vec<CapturedFrame>,
+    /// Bytes following the last frame of a connection in a direction, e.g. a frame cut short by
+    /// a dropped connection.
+    pub unparsed: Vec<UnparsedBytes>,
+}
+
+impl Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Capture recorded by a sendfile {} {}, {} frames",
+            self.header.software_version,
+            self.header.role,
+            self.frames.len()
+        )?;
+        for frame in &self.frames {
+            let session = match &frame.session_id {
+                Some(id) => to_hex(&id[..4]),
+                None => String::from("-"),
+            };
+            writeln!(
+                f,
+                "{:>12.6}s  #{:<3} {:<8}  v{}  {:<8}  {:>8} B  {}",
+                frame.at.as_secs_f64(),
+                frame.connection,
+                frame.direction,
+                frame.protocol_version,
+                session,
+                frame.len,
+                frame.message
+            )?;
+        }
+        for bytes in &self.unparsed {
+            writeln!(
+                f,
+                "#{} {}: {} bytes from offset {} are not a frame: {}",
+                bytes.connection, bytes.direction, bytes.len, bytes.offset, bytes.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What a connection exchanged in one direction.
+struct Flow {
+    connection: u32,
+    direction: Direction,
+    bytes: Vec<u8>,
+    /// Offset in `bytes` each record ends at, with its time.
+    ends: Vec<(usize, Duration)>,
+}
+
+/// Reads the capture at `path` and splits what each connection exchanged into frames.
+///
+/// # Returns
+///
+/// The frames, or an error if the file can't be read or is not a capture.
+pub fn read_capture(path: &Path) -> Result<Replay, CaptureError> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header: CaptureHeader = match lines.next() {
+        Some(line) => parse_line(&line?, 1)?,
+        None => {
+            return Err(CaptureError::InvalidRecord {
+                line: 1,
+                details: String::from("Empty file"),
+            })
+        }
+    };
+    if header.format != CAPTURE_FORMAT_VERSION {
+        return Err(CaptureError::UnsupportedFormat(header.format));
+    }
+
+    let mut flows: Vec<Flow> = Vec::new();
+    for (index, line) in lines.enumerate() {
+        let line_number = index + 2;
+        let record: CaptureRecord = parse_line(&line?, line_number)?;
+        let flow = match flows.iter().position(|flow| {
+            flow.connection == record.connection && flow.direction == record.direction
+        }) {
+            Some(position) => &mut flows[position],
+            None => {
+                flows.push(Flow {
+                    connection: record.connection,
+                    direction: record.direction,
+                    bytes: Vec::new(),
+                    ends: Vec::new(),
+                });
+                flows.last_mut().unwrap()
+            }
+        };
+        let at = Duration::from_micros(record.at_us);
+        if let Some(len) = record.retracted {
+            let len = flow.bytes.len().saturating_sub(len);
+            flow.bytes.truncate(len);
+            flow.ends.retain(|(end, _)| *end <= len);
+            continue;
+        }
+        let data = from_hex(&record.data).ok_or_else(|| CaptureError::InvalidRecord {
+            line: line_number,
+            details: String::from("Data is not hex encoded"),
+        })?;
+        flow.bytes.extend_from_slice(&data);
+        flow.ends.push((flow.bytes.len(), at));
+    }
+
+    let mut frames = Vec::new();
+    let mut unparsed = Vec::new();
+    for flow in &flows {
+        let sender = (header.role == Role::Sender) == (flow.direction == Direction::Sent);
+        split_frames(flow, sender, &mut frames, &mut unparsed);
+    }
+    frames.sort_by_key(|frame| frame.at);
+    Ok(Replay {
+        header,
+        frames,
+        unparsed,
+    })
+}
+
+fn parse_line<T: for<'de> Deserialize<'de>>(
+    line: &str,
+    line_number: usize,
+) -> Result<T, CaptureError> {
+    serde_json::from_str(line).map_err(|e| CaptureError::InvalidRecord {
+        line: line_number,
+        details: e.to_string(),
+    })
+}
+
+/// Splits the bytes of `flow` into frames of the sender's messages if `sender` is set, of the
+/// receiver's otherwise.
+fn split_frames(
+    flow: &Flow,
+    sender: bool,
+    frames: &mut Vec<CapturedFrame>,
+    unparsed: &mut Vec<UnparsedBytes>,
+) {
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let mut offset = 0;
+    while offset < flow.bytes.len() {
+        let mut stream = Cursor::new(&flow.bytes[offset..]);
+        let parsed = if sender {
+            read_next_payload::<SenderMessageV1, _>(&mut stream, &mut buffer, 0).map(|result| {
+                let len = result.next_payload_index.unwrap_or(result.total_bytes_read);
+                let message = describe_sender(&result.message);
+                (message, result.protocol_version, result.session_id, len)
+            })
+        } else {
+            read_next_payload::<ReceiverMessageV1, _>(&mut stream, &mut buffer, 0).map(|result| {
+                let len = result.next_payload_index.unwrap_or(result.total_bytes_read);
+                let message = format!("{:?}", result.message);
+                (message, result.protocol_version, result.session_id, len)
+            })
+        };
+        match parsed {
+            Ok((message, protocol_version, session_id, len)) if len > 0 => {
+                let end = offset + len;
+                let at = flow
+                    .ends
+                    .iter()
+                    .find(|(record_end, _)| *record_end >= end)
+                    .map_or(Duration::ZERO, |(_, at)| *at);
+                frames.push(CapturedFrame {
+                    at,
+                    connection: flow.connection,
+                    direction: flow.direction,
+                    protocol_version,
+                    session_id,
+                    len,
+                    message,
+                });
+                offset = end;
+            }
+            result => {
+                let reason = match result {
+                    Err(StreamReadError::UnexpectedEof) => String::from("truncated frame"),
+                    Err(e) => e.to_string(),
+                    Ok(_) => String::from("empty frame"),
+                };
+                unparsed.push(UnparsedBytes {
+                    connection: flow.connection,
+                    direction: flow.direction,
+                    offset,
+                    len: flow.bytes.len() - offset,
+                    reason,
+                });
+                return;
+            }
+        }
+    }
+}
+
+/// Describes a message of the sender, summing up the data it carries by its length.
+fn describe_sender(message: &SenderMessageV1) -> String {
+    match message {
+        SenderMessageV1::Data(data) => format!("Data({})", describe_data(data)),
+        SenderMessageV1::FileData(file_data) => format!(
+            "FileData {{ file_index: {}, {} }}",
+            file_data.file_index,
+            describe_data(&file_data.data)
+        ),
+        SenderMessageV1::FileHeader(header) => format!(
+            "FileHeader {{ file_hash: {}, bytes: {} bytes }}",
+            to_hex(&header.file_hash),
+            header.bytes.len()
+        ),
+        SenderMessageV1::Probe(probe) => format!(
+            "Probe {{ seq: {}, echo: {}, payload: {} bytes }}",
+            probe.seq,
+            probe.echo,
+            probe.payload.len()
+        ),
+        message => format!("{:?}", message),
+    }
+}
+
+fn describe_data(data: &DataV1) -> String {
+    format!(
+        "seq: {}, checksum: {:#010x}, compressed: {}, data: {} bytes",
+        data.seq,
+        data.checksum,
+        data.compressed,
+        data.data.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{attach_headers_for, RequestV1, CURRENT_PROTOCOL_VERSION};
+
+    const SESSION: [u8; SESSION_ID_SIZE] = [9; SESSION_ID_SIZE];
+
+    #[test]
+    fn test_replays_recorded_frames() {
+        let path = std::env::temp_dir().join(format!("sendfile_capture_{}", std::process::id()));
+        let capture = Arc::new(Capture::create(&path, Role::Receiver).unwrap());
+        let tap = capture.tap();
+
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+        let request = ReceiverMessageV1::Request(RequestV1 {
+            file_hash: [1; 32],
+            seq: 4,
+        });
+        let payload = request.to_bytes(&mut buffer).unwrap();
+        tap.record(
+            Direction::Sent,
+            &attach_headers_for(CURRENT_PROTOCOL_VERSION, Some(&SESSION), payload),
+        );
+
+        let block = [7u8; 300];
+        let data = SenderMessageV1::Data(DataV1 {
+            seq: 4,
+            checksum: 0xABCD,
+            file_hash: &[1; 32],
+            compressed: false,
+            data: &block,
+        });
+        let payload = data.to_bytes(&mut buffer).unwrap();
+        let frame = attach_headers_for(CURRENT_PROTOCOL_VERSION, Some(&SESSION), payload);
+        // Bytes read ahead of a channel are taken back, and frames span reads
+        tap.record(Direction::Received, b"not a frame");
+        tap.retract(Direction::Received, 11);
+        tap.record(Direction::Received, &frame[..10]);
+        tap.record(Direction::Received, &frame[10..]);
+        tap.record(Direction::Received, &frame[..5]);
+        drop(tap);
+        drop(capture);
+
+        let replay = read_capture(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replay.header.role, Role::Receiver);
+        assert_eq!(replay.frames.len(), 2);
+        let [sent, received] = &replay.frames[..] else {
+            unreachable!()
+        };
+        assert_eq!(sent.direction, Direction::Sent);
+        assert_eq!(sent.session_id, Some(SESSION));
+        assert_eq!(sent.message, format!("{:?}", request));
+        assert_eq!(received.direction, Direction::Received);
+        assert_eq!(received.len, frame.len());
+        assert_eq!(
+            received.message,
+            "Data(seq: 4, checksum: 0x0000abcd, compressed: false, data: 300 bytes)"
+        );
+        assert_eq!(
+            replay.unparsed,
+            vec![UnparsedBytes {
+                connection: 0,
+                direction: Direction::Received,
+                offset: frame.len(),
+                len: 5,
+                reason: String::from("truncated frame"),
+            }]
+        );
+        assert!(replay.to_string().contains("Data(seq: 4"));
+    }
+}
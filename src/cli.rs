@@ -1,9 +1,46 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use clap::{Args, Parser, Subcommand};
+use clap_complete::ArgValueCandidates;
 
-pub const HANDSHAKE_PORT: u16 = 7878;
-pub const TRANSFER_PORT: u16 = 7879;
+use crate::{
+    capabilities::StrictPolicy,
+    completions::{complete_hosts, CompletionShell},
+    file::{
+        content_type::TypePattern,
+        duplicate::DuplicateMode,
+        metadata::{parse_metadata_entry, MetadataStore},
+    },
+    pairing::parse_code,
+    stream::{
+        bundle::FileOrder,
+        checksum::ChecksumImpl,
+        concurrency::DEFAULT_MAX_CONCURRENCY,
+        error::ErrorFormat,
+        estimate::DEFAULT_ENTROPY_THRESHOLD,
+        heartbeat::{Heartbeat, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_HEARTBEAT_TIMEOUT},
+        keepalive::Keepalive,
+        options::{
+            DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_LISTEN_BACKLOG, DEFAULT_MAX_CLOCK_SKEW,
+            DEFAULT_READ_RETRIES, MAX_PIPELINE_DEPTH, MAX_READ_RETRIES,
+        },
+        probe::DEFAULT_PROBE_DURATION,
+        profile::{ReceiveProfile, SendProfile},
+        scan::DEFAULT_SCAN_TIMEOUT,
+        schedule::RateSchedule,
+        udp::DEFAULT_UDP_OVERHEAD,
+    },
+    threads::{parse_cpu_list, WorkerOptions},
+    tls::{default_tls_path, TlsConfig, TlsError},
+    transport::{
+        relay::{DEFAULT_RELAY_PORT, DEFAULT_RELAY_WAIT},
+        ws::TransportKind,
+        BINARY_FRAMING_PROTOCOL_VERSION,
+    },
+    units::UnitSystem,
+};
+
+pub use crate::limits::{HANDSHAKE_PORT, TRANSFER_PORT};
 
 #[derive(Parser)]
 #[command(name = "sendfile")]
@@ -11,6 +48,34 @@ pub const TRANSFER_PORT: u16 = 7879;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Prefixes sizes are shown with: si (1 kB = 1000 bytes) or iec (1 KiB = 1024 bytes)
+    #[arg(long, global = true, value_enum, default_value_t = UnitSystem::Iec)]
+    pub units: UnitSystem,
+
+    /// Implementation computing block checksums: auto (fastest available), hardware (fail
+    /// without CPU support) or software
+    #[arg(long, global = true, value_enum, default_value_t = ChecksumImpl::Auto)]
+    pub checksum_impl: ChecksumImpl,
+
+    /// Directory keeping the history, the index of received files and quarantined files
+    /// [default: $SENDFILE_DATA_DIR, else <data dir>/sendfile]
+    #[arg(long, global = true, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// How failed sends and receives are reported: human (a log line) or json (one line of
+    /// JSON on stderr with the error code, message, operation, peer, file and block)
+    #[arg(long, global = true, value_enum, default_value_t = ErrorFormat::Human)]
+    pub error_format: ErrorFormat,
+
+    /// Lock keys, pairing codes and other secrets in memory (mlock) so they are never written
+    /// to swap, best effort within the locked memory limit (ulimit -l)
+    #[arg(long, global = true)]
+    pub lock_secrets: bool,
+
+    /// Record every frame sent and received to this file, for `sendfile replay`
+    #[arg(long, global = true, hide = true, value_name = "PATH")]
+    pub capture: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -19,28 +84,242 @@ pub enum Commands {
     Send(SendArgs),
     /// Receive a file and write it to a path
     Receive(ReceiveArgs),
+    /// Inspect and prune the local block store used by --dedup
+    Cache(CacheArgs),
+    /// Hash existing files into the index of received files used by --reuse-duplicates
+    Index(IndexArgs),
+    /// Inspect, compact or clear the stores keeping the history and the index of received files
+    State(StateArgs),
+    /// List the receivers announced on the local network with `receive --advertise`
+    Discover(DiscoverArgs),
+    /// Run a relay pairing senders and receivers by rendezvous code and piping their transfers,
+    /// for peers that are both behind NAT
+    Relay(RelayArgs),
+    /// Re-verify a received file against the hash recorded by `receive --xattrs`
+    Check(CheckArgs),
+    /// List the transfers running in other sendfile processes
+    Status(StatusArgs),
+    /// Serve a web page showing running and past transfers
+    Dashboard(DashboardArgs),
+    /// Exchange trust bundles with other machines and manage trusted peers
+    Peer(PeerArgs),
+    /// Pair with another machine through a one-time code, so receivers accept its files:
+    /// `sendfile pair` prints the code, `sendfile pair HOST CODE` on the other machine uses it
+    Pair(PairArgs),
+    /// Review, release or delete files held back by `receive --scan-cmd`
+    Quarantine(QuarantineArgs),
+    /// Measure the round trip time, path MTU and throughput to a receiver and recommend
+    /// transfer options
+    Probe(ProbeArgs),
+    /// Print the script registering shell completions, e.g. `source <(sendfile completions bash)`
+    #[command(hide = true)]
+    Completions(CompletionsArgs),
+    /// Print the frames of a capture recorded with --capture
+    #[command(hide = true)]
+    Replay(ReplayArgs),
+    /// Print a Wireshark dissector of the protocol, generated from the message definitions
+    #[command(hide = true)]
+    Dissector,
 }
 
 #[derive(Args)]
 pub struct SendArgs {
-    /// Path to the file to send
+    /// Path to the file to send, or a directory to send with every file in it
     #[arg(name = "FILE")]
     pub file: PathBuf,
 
-    /// Receiver host or IP
-    #[arg(name = "HOST")]
-    pub host: String,
+    /// Receiver host or IP, the alias of a trusted peer, or the name of a receiver announced on
+    /// the local network with `receive --advertise`
+    #[arg(
+        name = "HOST",
+        required_unless_present_any = ["uds", "relay"],
+        add = ArgValueCandidates::new(complete_hosts)
+    )]
+    pub host: Option<String>,
 
     /// Block size in bytes
     #[arg(short, long)]
     pub block_size: Option<u32>,
 
-    /// Number of concurrent connections [default: capped to min(os_threads, 16)]
+    /// Number of concurrent connections [default: min(os_threads, --max-concurrency)]
     #[arg(short, long)]
     pub concurrency: Option<u16>,
 
+    /// Upper bound on the number of concurrent connections
+    #[arg(long, default_value_t = DEFAULT_MAX_CONCURRENCY)]
+    pub max_concurrency: u16,
+
+    /// Preset of transfer options. `lan-10g` sends uncompressed 4 MiB blocks over 8 connections
+    /// with large socket buffers, for fast local networks. Options given explicitly take
+    /// precedence
+    #[arg(long, value_enum, default_value_t = SendProfile::Standard)]
+    pub profile: SendProfile,
+
     #[arg(long)]
     pub no_compress: bool,
+
+    /// Send blocks whose sampled byte entropy is above this many bits per byte without trying
+    /// to compress them, e.g. encrypted or already compressed data. 8 tries every block
+    #[arg(long, value_name = "BITS", value_parser = parse_entropy_threshold, default_value_t = DEFAULT_ENTROPY_THRESHOLD)]
+    pub compress_entropy_threshold: f64,
+
+    /// Do not take a shared advisory lock on the source file (e.g. on NFS where flock misbehaves)
+    #[arg(long)]
+    pub no_lock: bool,
+
+    /// Tune reads for a source on a network filesystem (NFS/SMB): sequential hashing and
+    /// maximum block size unless --block-size is given
+    #[arg(long)]
+    pub network_fs: bool,
+
+    /// Order the files of a directory are transferred in: by name, size-asc for the smallest
+    /// first, size-desc for the largest first, or mtime for the most recently modified first
+    #[arg(long, value_enum, default_value_t = FileOrder::Name)]
+    pub order: FileOrder,
+
+    /// Do not record the transfer and its receipt in the local history
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Identity key the sender authenticates with [default: <config dir>/sendfile/identity.key]
+    #[arg(long, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+
+    /// Send the file without authenticating to the receiver
+    #[arg(long, conflicts_with = "identity")]
+    pub no_auth: bool,
+
+    /// Drop a connection once the receiver accepted no data for this many seconds
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub write_timeout: u64,
+
+    /// Data connections the transfer listener queues before they are accepted
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LISTEN_BACKLOG)]
+    pub listen_backlog: u32,
+
+    /// Drop a data connection that sent no request this many seconds after it was accepted
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_HANDSHAKE_TIMEOUT.as_secs())]
+    pub handshake_timeout: u64,
+
+    /// Abort the transfer, telling the receiver, once it has run this many seconds, hashing
+    /// included, and exit with status 3. The receiver keeps the blocks received to resume from
+    #[arg(long, value_name = "SECS")]
+    pub max_duration: Option<u64>,
+
+    /// Send at most this many bytes per second (e.g. 10M), telling receivers that support it to
+    /// slow down as well
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+
+    /// Limit the rate by local time of day, e.g. `08:00=2M,18:00=unlimited`. Each limit holds
+    /// until the next one starts, the last one until the first one the next day
+    #[arg(long, value_name = "SCHEDULE", value_parser = parse_rate_schedule, conflicts_with = "limit_rate")]
+    pub rate_schedule: Option<RateSchedule>,
+
+    /// Times a failed block read is retried before the block is reported unreadable
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_READ_RETRIES, value_parser = clap::value_parser!(u32).range(..=MAX_READ_RETRIES as i64))]
+    pub read_retries: u32,
+
+    /// Assumed speed of the link to the receiver in Mbit/s, used to estimate the transfer time
+    /// and whether compression pays off
+    #[arg(long, value_name = "MBIT", default_value_t = 1000)]
+    pub link_speed: u64,
+
+    /// Hash the file and offer it to the receiver, report what would be transferred and the
+    /// receiver's answer, then exit without sending any data
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Emit transfer events on the D-Bus session bus (requires the `dbus` feature)
+    #[arg(long)]
+    pub dbus: bool,
+
+    /// Export OpenTelemetry traces of the transfer over OTLP (requires the `otel` feature)
+    #[arg(long)]
+    pub otlp: bool,
+
+    #[command(flatten)]
+    pub workers: WorkerArgs,
+
+    #[command(flatten)]
+    pub strict: StrictArgs,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    /// Encrypt the handshake and data connections with a Noise channel keyed by the identity
+    /// keys, set up in the handshake. The receiver must support it
+    #[arg(long)]
+    pub noise: bool,
+
+    /// Pair with the receiver using the one-time code it printed (`receive --pair`) before
+    /// anything about the file is sent. Implies an encrypted channel like --noise
+    #[arg(long, value_name = "CODE", value_parser = parse_code)]
+    pub code: Option<String>,
+
+    /// Attach KEY=VALUE metadata to the file (e.g. ticket=1234), handed to the receiver's scan
+    /// command and stored next to the file with `receive --metadata-store`. Repeatable
+    #[arg(long = "meta", value_name = "KEY=VALUE", value_parser = parse_metadata_entry)]
+    pub metadata: Vec<(String, String)>,
+
+    /// Send blocks as UDP datagrams with forward error correction, for high-bandwidth,
+    /// high-latency links. Requests stay on TCP, and blocks losing too many datagrams are sent
+    /// again over TCP. Datagrams are not encrypted, so it can't be combined with encryption
+    #[arg(long, conflicts_with_all = ["tls", "noise", "code"])]
+    pub udp: bool,
+
+    /// Repair datagrams sent on top of the datagrams of each block with --udp, in percent. Raised
+    /// while blocks fail to be rebuilt
+    #[arg(long, value_name = "PERCENT", requires = "udp", default_value_t = DEFAULT_UDP_OVERHEAD)]
+    pub udp_overhead: u32,
+
+    /// Connections to send over: tcp, or ws to tunnel the transfer through a single WebSocket
+    /// (wss:// with --tls) for networks only letting HTTP(S) through. HOST may then be a ws:// or
+    /// wss:// URL, e.g. of a reverse proxy in front of the receiver
+    #[arg(long, value_enum, default_value_t = TransportKind::Tcp)]
+    pub transport: TransportKind,
+
+    /// Forward proxy to tunnel the WebSocket of --transport ws through with HTTP CONNECT,
+    /// `http://[user:password@]host[:port]`, authenticating with basic authentication if it has
+    /// credentials. Defaults to HTTPS_PROXY for wss:// URLs and HTTP_PROXY for ws:// URLs
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Send to a receiver on this host listening on a Unix domain socket (`receive --uds`)
+    /// instead of over TCP, for fast local transfers, e.g. between containers sharing a volume
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["HOST", "transport", "tls", "noise", "code", "udp", "dry_run"]
+    )]
+    pub uds: Option<PathBuf>,
+
+    /// Open every connection to the receiver's handshake port, the handshake connection carrying
+    /// blocks too, instead of accepting data connections on port 7879, for senders behind a
+    /// firewall or NAT. The receiver must use `receive --single-port`
+    #[arg(long, conflicts_with_all = ["uds", "udp"])]
+    pub single_port: bool,
+
+    /// Send through a relay (`sendfile relay`) at ADDR, port 7880 unless given, instead of
+    /// connecting to the receiver, for when both sides are behind NAT. Both sides connect out to
+    /// the relay, which pairs them by the code the receiver printed (--relay-code)
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "relay_code",
+        conflicts_with_all = ["HOST", "uds", "transport", "tls", "noise", "code", "udp", "dry_run", "single_port"]
+    )]
+    pub relay: Option<String>,
+
+    /// Rendezvous code the receiver printed, pairing this sender with it at the relay
+    #[arg(long, value_name = "CODE", requires = "relay")]
+    pub relay_code: Option<String>,
+
+    #[command(flatten)]
+    pub keepalive: KeepaliveArgs,
+
+    #[command(flatten)]
+    pub heartbeat: HeartbeatArgs,
 }
 
 #[derive(Args)]
@@ -50,7 +329,837 @@ pub struct ReceiveArgs {
     #[arg(name = "PATH")]
     pub file: PathBuf,
 
-    /// Number of concurrent connections [default: capped to min(os_threads, 16)]
+    /// Number of concurrent connections [default: min(os_threads, --max-concurrency)]
     #[arg(short, long)]
     pub concurrency: Option<u16>,
+
+    /// Upper bound on the number of concurrent connections
+    #[arg(long, default_value_t = DEFAULT_MAX_CONCURRENCY)]
+    pub max_concurrency: u16,
+
+    /// Block requests each connection keeps outstanding, so high-latency links don't wait a
+    /// round trip per block. 1 requests blocks one at a time [default: 8, 16 with --profile
+    /// lan-10g]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=MAX_PIPELINE_DEPTH as i64))]
+    pub pipeline_depth: Option<u32>,
+
+    /// Resource preset. `low-memory` receives over a single connection with buffers sized to
+    /// blocks of at most 64 KiB, for routers and single-board computers. `lan-10g` receives over
+    /// 8 connections with large socket buffers, for fast local networks
+    #[arg(long, value_enum, default_value_t = ReceiveProfile::Standard)]
+    pub profile: ReceiveProfile,
+
+    /// Do not take an exclusive advisory lock on the output file (e.g. on NFS where flock misbehaves)
+    #[arg(long)]
+    pub no_lock: bool,
+
+    /// Tune writes for a destination on a network filesystem (NFS/SMB): serialize block writes
+    /// through a single file handle
+    #[arg(long)]
+    pub network_fs: bool,
+
+    /// Do not pre-allocate the output file to its full size before receiving
+    #[arg(long)]
+    pub no_preallocate: bool,
+
+    /// Write each block out to disk and drop it from the page cache, so receiving a huge file
+    /// doesn't evict the rest of the cache. Slower, since every write waits for the disk
+    #[arg(long)]
+    pub no_cache_pollution: bool,
+
+    /// Write at most this many bytes per second to the output file (e.g. 50M), leaving disk
+    /// bandwidth to other workloads on the destination disk
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub disk_limit_rate: Option<u64>,
+
+    /// Receive the files of a directory next to it, and only move them into it once every file
+    /// is verified. A failed transfer leaves the directory as it was
+    #[arg(long)]
+    pub atomic_batch: bool,
+
+    /// Write over PATH if it is a block device (e.g. /dev/sdb when imaging a disk). It must be
+    /// at least as large as the file, the rest of it is left as it was
+    #[arg(long, conflicts_with = "scan_cmd")]
+    pub yes_i_mean_a_device: bool,
+
+    /// Senders the listener queues before they are accepted
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LISTEN_BACKLOG)]
+    pub listen_backlog: u32,
+
+    /// Drop a sender that did not complete its handshake this many seconds after it was
+    /// accepted, and accept the next one
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_HANDSHAKE_TIMEOUT.as_secs())]
+    pub handshake_timeout: u64,
+
+    /// Abort the receive, telling the sender, once it has run this many seconds, waiting for the
+    /// sender included, and exit with status 3. The blocks received are kept to resume from
+    #[arg(long, value_name = "SECS")]
+    pub max_duration: Option<u64>,
+
+    /// Warn when the sender's clock is further than this many seconds from this side's
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_MAX_CLOCK_SKEW.as_secs())]
+    pub max_clock_skew: u64,
+
+    /// Identity key used to sign delivery receipts [default: <config dir>/sendfile/identity.key]
+    #[arg(long, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+
+    /// Do not send a signed delivery receipt to the sender
+    #[arg(long, conflicts_with = "identity")]
+    pub no_receipt: bool,
+
+    /// Reuse blocks of previously received files from the local block store
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Directory of the block store, implies --dedup [default: <cache dir>/sendfile/blocks]
+    #[arg(long, value_name = "DIR")]
+    pub block_store: Option<PathBuf>,
+
+    /// Size cap of the block store, least recently used blocks are evicted as blocks are added
+    /// (e.g. 512M, 10G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "10G")]
+    pub block_store_max_size: u64,
+
+    /// Record the file hash and receive time in the output file's extended attributes
+    #[arg(long)]
+    pub xattrs: bool,
+
+    /// When a file identical to the offered one was received before under PATH, hard-link
+    /// (link) or copy (copy) it to the destination instead of downloading it
+    #[arg(long, value_enum, value_name = "MODE", conflicts_with = "no_history")]
+    pub reuse_duplicates: Option<DuplicateMode>,
+
+    /// Do not record received files in the local index --reuse-duplicates looks files up in
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Store the metadata senders attach with `send --meta` next to the file: as
+    /// user.sendfile.meta.* extended attributes (xattrs) or in a <FILE>.meta.json sidecar
+    #[arg(long, value_enum, value_name = "STORE")]
+    pub metadata_store: Option<MetadataStore>,
+
+    /// When every connection to the sender is lost, keep reconnecting (or accept the sender's
+    /// new handshake for the same file) and resume instead of failing
+    #[arg(long)]
+    pub auto_retry: bool,
+
+    /// Total time --auto-retry keeps trying, in seconds
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "auto_retry",
+        default_value_t = 300
+    )]
+    pub retry_budget: u64,
+
+    /// Zero-fill blocks the sender can't read instead of aborting, listing them in a damage report
+    /// next to the file
+    #[arg(long)]
+    pub best_effort: bool,
+
+    /// Only accept files of these types: kinds (executable, image, video, audio, archive,
+    /// document, media) or extensions (e.g. .iso), judged by extension and sniffed content
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    pub accept_types: Vec<TypePattern>,
+
+    /// Reject files of these types, even if accepted by --accept-types
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    pub reject_types: Vec<TypePattern>,
+
+    /// Scan each received file with this shell command, the file path appended, before accepting
+    /// it. Files it exits non-zero on are moved to the quarantine directory
+    #[arg(long, value_name = "CMD")]
+    pub scan_cmd: Option<String>,
+
+    /// Where files failing --scan-cmd are held [default: <data dir>/quarantine]
+    #[arg(long, value_name = "DIR", requires = "scan_cmd")]
+    pub quarantine_dir: Option<PathBuf>,
+
+    /// Kill --scan-cmd and fail the file once it has run this many seconds
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_SCAN_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..), requires = "scan_cmd")]
+    pub scan_timeout: u64,
+
+    /// Wait for a sender, report where its file would be written and what is in the way, then
+    /// reject it without receiving any data
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Announce this receiver on the local network over mDNS, so senders can use its name
+    /// instead of its address
+    #[arg(long, conflicts_with = "uds")]
+    pub advertise: bool,
+
+    /// Name the receiver is announced with [default: host name]
+    #[arg(long, value_name = "NAME", requires = "advertise")]
+    pub name: Option<String>,
+
+    /// Emit transfer events on the D-Bus session bus (requires the `dbus` feature)
+    #[arg(long)]
+    pub dbus: bool,
+
+    /// Export OpenTelemetry traces of the transfer over OTLP (requires the `otel` feature)
+    #[arg(long)]
+    pub otlp: bool,
+
+    #[command(flatten)]
+    pub workers: WorkerArgs,
+
+    #[command(flatten)]
+    pub strict: StrictArgs,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    /// Print a one-time code and only accept a sender that pairs with it (`send --code`). A
+    /// failed pairing ends the receive, so the code can't be guessed
+    #[arg(long)]
+    pub pair: bool,
+
+    /// Accept senders that aren't trusted peers. Otherwise only senders authenticating as a peer
+    /// paired with `sendfile pair` or imported with `peer import` are accepted, besides those
+    /// pairing with --pair, meeting at a --relay or on a --uds socket
+    #[arg(long)]
+    pub open: bool,
+
+    /// Connections to receive over: tcp, or ws to accept the transfer as a single WebSocket
+    /// (wss:// with --tls) on the handshake port, from senders using `--transport ws`
+    #[arg(long, value_enum, default_value_t = TransportKind::Tcp)]
+    pub transport: TransportKind,
+
+    /// Listen on a Unix domain socket created at PATH instead of the TCP ports, for a sender on
+    /// this host using `send --uds`. The socket is removed once the transfer ends
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["transport", "tls", "pair", "auto_retry", "dry_run"]
+    )]
+    pub uds: Option<PathBuf>,
+
+    /// Accept the data connections of a `send --single-port` sender on the handshake port, the
+    /// handshake connection carrying blocks too, instead of connecting to port 7879 of the sender
+    #[arg(long, conflicts_with_all = ["uds", "auto_retry"])]
+    pub single_port: bool,
+
+    /// Receive through a relay (`sendfile relay`) at ADDR, port 7880 unless given, instead of
+    /// listening, for when both sides are behind NAT. Prints the rendezvous code the sender
+    /// passes with `send --relay-code`
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = ["uds", "transport", "tls", "pair", "auto_retry", "dry_run", "single_port", "advertise"]
+    )]
+    pub relay: Option<String>,
+
+    /// Rendezvous code to wait under at the relay [default: a new one-time code]
+    #[arg(long, value_name = "CODE", requires = "relay")]
+    pub relay_code: Option<String>,
+
+    #[command(flatten)]
+    pub keepalive: KeepaliveArgs,
+
+    #[command(flatten)]
+    pub heartbeat: HeartbeatArgs,
+}
+
+#[derive(Args)]
+pub struct ProbeArgs {
+    /// Receiver host or IP, or the alias of a trusted peer
+    #[arg(name = "HOST", add = ArgValueCandidates::new(complete_hosts))]
+    pub host: String,
+
+    /// Seconds spent measuring the throughput
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_PROBE_DURATION.as_secs())]
+    pub duration: u64,
+
+    /// Store the recommended block size and concurrency in the trusted peer's options, used by
+    /// later sends to it
+    #[arg(long)]
+    pub save: bool,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    /// Probe over a Noise channel, as `send --noise` does
+    #[arg(long)]
+    pub noise: bool,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    /// Previously received file to verify
+    #[arg(name = "PATH")]
+    pub file: PathBuf,
+}
+
+#[derive(Args)]
+pub struct CacheArgs {
+    /// Directory of the block store [default: <cache dir>/sendfile/blocks]
+    #[arg(long, value_name = "DIR", global = true)]
+    pub block_store: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub action: CacheAction,
+}
+
+#[derive(Args)]
+pub struct StateArgs {
+    #[command(subcommand)]
+    pub action: StateAction,
+}
+
+#[derive(Args)]
+pub struct IndexArgs {
+    /// Index of received files [default: <data dir>/state/received.jsonl]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub index: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub workers: WorkerArgs,
+
+    #[command(subcommand)]
+    pub action: IndexAction,
+}
+
+#[derive(Args)]
+pub struct DiscoverArgs {
+    /// Seconds to wait for receivers to answer
+    #[arg(long, value_name = "SECS", default_value_t = 3)]
+    pub timeout: u64,
+
+    /// Print the receivers as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct RelayArgs {
+    /// Port to accept senders and receivers on
+    #[arg(long, default_value_t = DEFAULT_RELAY_PORT)]
+    pub port: u16,
+
+    /// Seconds a sender or receiver waits for its counterpart before being disconnected
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_RELAY_WAIT.as_secs())]
+    pub wait_timeout: u64,
+}
+
+#[derive(Args)]
+pub struct CompletionsArgs {
+    /// Shell to register completions with
+    #[arg(value_enum)]
+    pub shell: CompletionShell,
+}
+
+#[derive(Args)]
+pub struct ReplayArgs {
+    /// Capture to print
+    #[arg(name = "PATH")]
+    pub path: PathBuf,
+}
+
+#[derive(Args)]
+pub struct StatusArgs {
+    /// Print the status as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct DashboardArgs {
+    /// Address to serve the dashboard on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+    pub listen: String,
+}
+
+#[derive(Args)]
+pub struct PeerArgs {
+    /// Trusted peers file [default: <config dir>/sendfile/peers.json]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub peers: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub action: PeerAction,
+}
+
+#[derive(Subcommand)]
+pub enum PeerAction {
+    /// Write a bundle describing this machine, to import on the machines it exchanges files with
+    Export {
+        /// Alias suggested to the importing machine
+        #[arg(long)]
+        name: String,
+        /// Host name or IP this machine is reachable at, may be repeated. Senders try them in
+        /// order, so list LAN addresses before VPN or WAN ones
+        #[arg(long = "address", value_name = "ADDR")]
+        addresses: Vec<String>,
+        /// Port this machine receives on, if the peer must use another one (e.g. port forwarding)
+        #[arg(long)]
+        port: Option<u16>,
+        /// Preferred block size in bytes
+        #[arg(long)]
+        block_size: Option<u32>,
+        /// Preferred number of concurrent connections
+        #[arg(long)]
+        concurrency: Option<u16>,
+        /// Prefer uncompressed blocks
+        #[arg(long)]
+        no_compress: bool,
+        /// Identity key to export [default: <config dir>/sendfile/identity.key]
+        #[arg(long, value_name = "PATH")]
+        identity: Option<PathBuf>,
+        /// Write the bundle to this file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Trust the machine described by a bundle
+    Import {
+        /// Bundle written by `peer export`
+        #[arg(name = "FILE")]
+        file: PathBuf,
+        /// Alias to store the peer under instead of the one in the bundle
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List trusted peers
+    List,
+    /// Stop trusting a peer
+    Remove {
+        /// Alias of the peer
+        #[arg(name = "NAME")]
+        name: String,
+    },
+}
+
+#[derive(Args)]
+pub struct PairArgs {
+    /// Machine running `sendfile pair`, to pair with using the code it printed. Without it, this
+    /// machine prints a code and waits for the other one on the handshake port until it pairs
+    /// or Ctrl-C
+    #[arg(name = "HOST", requires = "CODE")]
+    pub host: Option<String>,
+
+    /// Code printed by `sendfile pair` on HOST
+    #[arg(name = "CODE", value_parser = parse_code)]
+    pub code: Option<String>,
+
+    /// Alias to store the other machine under [default: the name it introduces itself with]
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Name this machine introduces itself with [default: its host name]
+    #[arg(long, value_name = "NAME")]
+    pub introduce_as: Option<String>,
+
+    /// Identity key to introduce this machine with [default: <config dir>/sendfile/identity.key]
+    #[arg(long, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+
+    /// Trusted peers file [default: <config dir>/sendfile/peers.json]
+    #[arg(long, value_name = "PATH")]
+    pub peers: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct QuarantineArgs {
+    /// Quarantine directory [default: <data dir>/quarantine]
+    #[arg(long, value_name = "DIR", global = true)]
+    pub quarantine_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub action: QuarantineAction,
+}
+
+#[derive(Subcommand)]
+pub enum QuarantineAction {
+    /// List held files with their sender and why they were held
+    List {
+        /// Print the records as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move a held file to where it was received for, or elsewhere
+    Release {
+        /// Id of the held file, as shown by `quarantine list`
+        #[arg(name = "ID")]
+        id: String,
+        /// Move the file to this path or directory instead
+        #[arg(long, value_name = "PATH")]
+        to: Option<PathBuf>,
+        /// Replace an existing file at the destination
+        #[arg(long)]
+        force: bool,
+    },
+    /// Delete held files
+    Purge {
+        /// Ids of the held files, as shown by `quarantine list`
+        #[arg(name = "ID", required_unless_present = "all")]
+        ids: Vec<String>,
+        /// Delete every held file
+        #[arg(long, conflicts_with = "ID")]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheAction {
+    /// Evict least recently used blocks until the store fits in the size cap
+    Gc {
+        /// Size cap of the store (e.g. 512M, 10G)
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "10G")]
+        max_size: u64,
+    },
+    /// Show the number of blocks in the store and their total size
+    Stats,
+    /// Remove every block from the store
+    Clear,
+}
+
+#[derive(Subcommand)]
+pub enum IndexAction {
+    /// Hash every file under DIR into the index, replacing its entries for them
+    Build {
+        /// Directory whose files are indexed, e.g. the output path of `receive`
+        dir: PathBuf,
+    },
+    /// Hash the files under DIR added or modified since they were indexed, and drop the entries
+    /// of files that are gone
+    Update {
+        /// Directory whose files are indexed, e.g. the output path of `receive`
+        dir: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StateAction {
+    /// List the stores with their number of records and size
+    List {
+        /// Print the stores as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print every record of a store as a JSON line
+    Show {
+        /// Name of the store, as shown by `state list`
+        #[arg(name = "STORE")]
+        name: String,
+    },
+    /// Rewrite stores without their replaced and removed records
+    Compact {
+        /// Name of the store, every store if not given
+        #[arg(name = "STORE")]
+        name: Option<String>,
+    },
+    /// Remove every record of a store
+    Clear {
+        /// Name of the store, as shown by `state list`
+        #[arg(name = "STORE")]
+        name: String,
+    },
+}
+
+#[derive(Args)]
+pub struct WorkerArgs {
+    /// Number of hashing worker threads [default: available parallelism]
+    #[arg(long)]
+    pub threads: Option<usize>,
+
+    /// Pin hashing and (de)compression workers to these CPUs, e.g. "0-3,6"
+    #[arg(long, value_parser = parse_cpu_list)]
+    pub cpus: Option<CpuList>,
+}
+
+impl SendArgs {
+    /// Returns the flags given that can't apply to a directory, which is sent as one bundle of
+    /// files (see [crate::stream::bundle]) over TCP only, and can't be offered in a dry run.
+    pub fn directory_conflicts(&self) -> Vec<&'static str> {
+        let mut conflicts = Vec::new();
+        if self.udp {
+            conflicts.push("--udp");
+        }
+        if self.dry_run {
+            conflicts.push("--dry-run");
+        }
+        conflicts
+    }
+}
+
+#[derive(Args)]
+pub struct StrictArgs {
+    /// Refuse to transfer unless encryption, authentication and the minimum protocol version
+    /// can be negotiated
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Lowest protocol version accepted in strict mode. Dry runs never negotiate version 3, which
+    /// tags frames with the transfer's session
+    #[arg(long, requires = "strict", default_value_t = BINARY_FRAMING_PROTOCOL_VERSION)]
+    pub min_protocol_version: u8,
+}
+
+impl StrictArgs {
+    pub fn to_policy(&self) -> Option<StrictPolicy> {
+        self.strict.then_some(StrictPolicy {
+            min_protocol_version: self.min_protocol_version,
+        })
+    }
+}
+
+#[derive(Args)]
+pub struct TlsArgs {
+    /// Secure the handshake and data connections with TLS, the peer must use --tls too
+    #[arg(long)]
+    pub tls: bool,
+
+    /// PEM certificate (chain) presented to the peer [default: <config dir>/sendfile/tls/cert.pem]
+    #[arg(long, value_name = "PATH", requires = "tls")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate [default: <config dir>/sendfile/tls/key.pem]
+    #[arg(long, value_name = "PATH", requires = "tls")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM certificate authority the peer's certificate must be signed by
+    /// [default: <config dir>/sendfile/tls/ca.pem]
+    #[arg(long, value_name = "PATH", requires = "tls")]
+    pub tls_ca: Option<PathBuf>,
+}
+
+impl TlsArgs {
+    /// Loads the certificate, key and authority with `--tls`, `None` without.
+    pub fn to_config(&self) -> Result<Option<Arc<TlsConfig>>, TlsError> {
+        if !self.tls {
+            return Ok(None);
+        }
+        let path = |arg: &Option<PathBuf>, name: &str| {
+            arg.clone()
+                .or_else(|| default_tls_path(name))
+                .unwrap_or_else(|| PathBuf::from(name))
+        };
+        let config = TlsConfig::load(
+            &path(&self.tls_cert, "cert.pem"),
+            &path(&self.tls_key, "key.pem"),
+            &path(&self.tls_ca, "ca.pem"),
+        )?;
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+#[derive(Args)]
+pub struct KeepaliveArgs {
+    /// Seconds a connection may be idle before TCP keepalive probes start
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub keepalive_idle: u64,
+
+    /// Seconds between two unanswered keepalive probes
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub keepalive_interval: u64,
+
+    /// Unanswered keepalive probes after which the peer is considered gone
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub keepalive_count: u32,
+
+    /// Leave TCP keepalive to the system default
+    #[arg(long, conflicts_with_all = ["keepalive_idle", "keepalive_interval", "keepalive_count"])]
+    pub no_keepalive: bool,
+}
+
+impl KeepaliveArgs {
+    pub fn to_options(&self) -> Option<Keepalive> {
+        (!self.no_keepalive).then_some(Keepalive {
+            idle: Duration::from_secs(self.keepalive_idle),
+            interval: Duration::from_secs(self.keepalive_interval),
+            count: self.keepalive_count,
+        })
+    }
+}
+
+#[derive(Args)]
+pub struct HeartbeatArgs {
+    /// Seconds a connection may wait on the peer before it is pinged
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_HEARTBEAT_INTERVAL.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    pub heartbeat_interval: u64,
+
+    /// Seconds without anything from the peer after which it is considered gone
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_HEARTBEAT_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    pub heartbeat_timeout: u64,
+
+    /// Neither send nor answer heartbeats on idle connections
+    #[arg(long, conflicts_with_all = ["heartbeat_interval", "heartbeat_timeout"])]
+    pub no_heartbeat: bool,
+}
+
+impl HeartbeatArgs {
+    pub fn to_options(&self) -> Option<Heartbeat> {
+        (!self.no_heartbeat).then_some(Heartbeat {
+            interval: Duration::from_secs(self.heartbeat_interval),
+            timeout: Duration::from_secs(self.heartbeat_timeout),
+        })
+    }
+}
+
+/// Alias so clap treats the parsed CPU list as a single value rather than a repeated argument.
+type CpuList = Vec<usize>;
+
+impl WorkerArgs {
+    pub fn to_options(&self) -> WorkerOptions {
+        WorkerOptions {
+            threads: self.threads,
+            cpus: self.cpus.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Parses an entropy in bits per byte, between 0 and 8.
+pub fn parse_entropy_threshold(bits: &str) -> Result<f64, String> {
+    let bits: f64 = bits
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid entropy {bits:?}: {e}"))?;
+    if !(0.0..=8.0).contains(&bits) {
+        return Err(format!(
+            "entropy must be between 0 and 8 bits per byte, got {bits}"
+        ));
+    }
+    Ok(bits)
+}
+
+/// Parses a size in bytes with an optional binary suffix, e.g. `4096`, `512K`, `10G`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let upper = size.to_ascii_uppercase();
+    let digits = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, shift) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 10),
+        Some('M') => (&digits[..digits.len() - 1], 20),
+        Some('G') => (&digits[..digits.len() - 1], 30),
+        Some('T') => (&digits[..digits.len() - 1], 40),
+        _ => (digits, 0),
+    };
+
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid size {size:?}: {e}"))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {size:?} is too large"))
+}
+
+/// Parses a rate in bytes per second, as a size with an optional `/s` suffix (e.g. `50M/s`).
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    match parse_size(rate.strip_suffix("/s").unwrap_or(rate))? {
+        0 => Err(format!("rate {rate:?} must be positive")),
+        bytes_per_second => Ok(bytes_per_second),
+    }
+}
+
+/// Parses a rate schedule of comma-separated `HH:MM=RATE` limits, where RATE is parsed like
+/// [parse_rate] or is `unlimited`.
+pub fn parse_rate_schedule(schedule: &str) -> Result<RateSchedule, String> {
+    let entries = schedule
+        .split(',')
+        .map(|entry| {
+            let (time, rate) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("limit {entry:?} is not HH:MM=RATE"))?;
+            let minute = parse_time_of_day(time.trim())
+                .ok_or_else(|| format!("time {time:?} is not HH:MM"))?;
+            let rate = match rate.trim() {
+                "unlimited" => None,
+                rate => Some(parse_rate(rate)?),
+            };
+            Ok((minute, rate))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    RateSchedule::new(entries)
+}
+
+/// Parses a `HH:MM` time of day into minutes since midnight.
+fn parse_time_of_day(time: &str) -> Option<u16> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours = hours.parse::<u16>().ok().filter(|hours| *hours < 24)?;
+    let minutes = Some(minutes)
+        .filter(|minutes| minutes.len() == 2)?
+        .parse::<u16>()
+        .ok()
+        .filter(|minutes| *minutes < 60)?;
+    Some(hours * 60 + minutes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("10G").unwrap(), 10 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("2mib").unwrap(), 2 * 1024 * 1024);
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("50M").unwrap(), 50 * 1024 * 1024);
+        assert_eq!(parse_rate("512K/s").unwrap(), 512 * 1024);
+        assert!(parse_rate("0").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate_schedule() {
+        let schedule = parse_rate_schedule("08:00=2M, 18:30=unlimited").unwrap();
+        assert_eq!(
+            schedule,
+            RateSchedule::new([(8 * 60, Some(2 * 1024 * 1024)), (18 * 60 + 30, None)]).unwrap()
+        );
+        assert!(parse_rate_schedule("8=2M").is_err());
+        assert!(parse_rate_schedule("24:00=2M").is_err());
+        assert!(parse_rate_schedule("08:5=2M").is_err());
+        assert!(parse_rate_schedule("08:00=fast").is_err());
+        assert!(parse_rate_schedule("08:00=1M,08:00=2M").is_err());
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["sendfile", "quarantine", "purge", "--all"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Quarantine(QuarantineArgs {
+                action: QuarantineAction::Purge { all: true, .. },
+                ..
+            })
+        ));
+        assert!(Cli::try_parse_from(["sendfile", "quarantine", "purge"]).is_err());
+        assert!(Cli::try_parse_from(["sendfile", "quarantine", "purge", "x", "--all"]).is_err());
+    }
+
+    #[test]
+    fn test_directory_conflicts() {
+        let send_args = |args: &[&str]| {
+            let cli =
+                Cli::try_parse_from(["sendfile", "send", "photos", "nas.lan"].iter().chain(args))
+                    .unwrap();
+            match cli.command {
+                Commands::Send(args) => args,
+                _ => unreachable!(),
+            }
+        };
+        assert!(
+            send_args(&["--concurrency", "8"])
+                .directory_conflicts()
+                .is_empty()
+        );
+        assert_eq!(send_args(&["--udp"]).directory_conflicts(), ["--udp"]);
+        assert_eq!(
+            send_args(&["--dry-run", "--udp"]).directory_conflicts(),
+            ["--udp", "--dry-run"]
+        );
+    }
 }
@@ -0,0 +1,175 @@
+//! Shell completions, generated at completion time by the binary itself.
+//!
+//! `sendfile completions <SHELL>` prints a small script that registers `sendfile` with the shell.
+//! On every completion the shell then runs `COMPLETE=<SHELL> sendfile -- <words>`, answered by
+//! [clap_complete::CompleteEnv] before the command line is parsed. This keeps completions in sync
+//! with the installed binary, and lets the `HOST` of `send` complete to trusted peer aliases and
+//! hosts recently sent to, see [known_hosts].
+
+use std::{io, path::Path};
+
+use clap::ValueEnum;
+use clap_complete::{
+    env::{Bash, EnvCompleter, Fish, Zsh},
+    CompletionCandidate,
+};
+
+use crate::{
+    history::{default_history_path, load_entries},
+    peers::{default_peers_path, PeerRegistry},
+};
+
+/// Environment variable the registered scripts set to request completions.
+pub const COMPLETE_VAR: &str = "COMPLETE";
+
+/// Largest number of recent hosts offered as completions.
+pub const RECENT_HOST_LIMIT: usize = 20;
+
+/// Shells completions can be generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl CompletionShell {
+    fn completer(self) -> &'static dyn EnvCompleter {
+        match self {
+            Self::Bash => &Bash,
+            Self::Zsh => &Zsh,
+            Self::Fish => &Fish,
+        }
+    }
+}
+
+/// Writes the script registering completions of `bin` with `shell` to `out`.
+///
+/// # Arguments
+///
+/// * `shell` - Shell the script is for.
+/// * `bin` - Name the binary is invoked by.
+/// * `completer` - Path of the binary answering completions, usually `bin` itself.
+/// * `out` - Where the script is written.
+pub fn write_registration(
+    shell: CompletionShell,
+    bin: &str,
+    completer: &str,
+    out: &mut dyn io::Write,
+) -> io::Result<()> {
+    shell
+        .completer()
+        .write_registration(COMPLETE_VAR, bin, bin, completer, out)
+}
+
+/// Returns the hosts `send` can be pointed at: the aliases of trusted peers, then the hosts of the
+/// most recent transfers in the history, each with a short description.
+///
+/// Unreadable files contribute no hosts, completions must never fail.
+pub fn known_hosts(
+    peers_path: Option<&Path>,
+    history_path: Option<&Path>,
+) -> Vec<(String, String)> {
+    let mut hosts: Vec<(String, String)> = Vec::new();
+
+    if let Some(Ok(registry)) = peers_path.map(PeerRegistry::load) {
+        for peer in registry.peers {
+            let description = format!("trusted peer ({})", peer.addresses.join(", "));
+            hosts.push((peer.name, description));
+        }
+    }
+
+    let peer_count = hosts.len();
+    if let Some(Ok(entries)) = history_path.map(load_entries) {
+        for entry in entries.iter().rev() {
+            if hosts.len() - peer_count >= RECENT_HOST_LIMIT {
+                break;
+            }
+            // Peers are recorded as `host:port`
+            let host = entry
+                .peer
+                .rsplit_once(':')
+                .map_or(entry.peer.as_str(), |(host, _)| host);
+            if !hosts.iter().any(|(known, _)| known == host) {
+                hosts.push((host.to_string(), format!("sent {}", entry.file_name)));
+            }
+        }
+    }
+
+    hosts
+}
+
+/// Completes the `HOST` of `send` from the default peers and history files.
+pub fn complete_hosts() -> Vec<CompletionCandidate> {
+    known_hosts(
+        default_peers_path().as_deref(),
+        default_history_path().as_deref(),
+    )
+    .into_iter()
+    .map(|(host, description)| CompletionCandidate::new(host).help(Some(description.into())))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::{append_entry, HistoryEntry},
+        peers::{Peer, PeerOptions},
+    };
+
+    #[test]
+    fn test_known_hosts_lists_peers_then_recent_hosts() {
+        let dir = std::env::temp_dir().join(format!("sendfile-completions-{}", std::process::id()));
+        let peers_path = dir.join("peers.json");
+        let history_path = dir.join("history.jsonl");
+
+        let mut registry = PeerRegistry::default();
+        registry.insert(Peer {
+            name: String::from("nas"),
+            public_key: String::new(),
+            addresses: vec![String::from("192.168.1.20")],
+            port: None,
+            options: PeerOptions::default(),
+        });
+        registry.save(&peers_path).unwrap();
+
+        for (peer, file_name) in [
+            ("10.0.0.5:7878", "old.iso"),
+            ("nas:7878", "backup.tar"),
+            ("10.0.0.7:7878", "photos.zip"),
+            ("10.0.0.5:7878", "new.iso"),
+        ] {
+            let entry = HistoryEntry {
+                timestamp: 0,
+                peer: String::from(peer),
+                file_name: String::from(file_name),
+                file_hash: String::new(),
+                bytes: 0,
+                receipt: None,
+                bundle: None,
+            };
+            append_entry(&history_path, &entry).unwrap();
+        }
+
+        let hosts = known_hosts(Some(&peers_path), Some(&history_path));
+        assert_eq!(
+            hosts,
+            vec![
+                (
+                    String::from("nas"),
+                    String::from("trusted peer (192.168.1.20)")
+                ),
+                (String::from("10.0.0.5"), String::from("sent new.iso")),
+                (String::from("10.0.0.7"), String::from("sent photos.zip")),
+            ]
+        );
+        assert!(known_hosts(Some(&dir.join("missing.json")), None).is_empty());
+
+        let mut script = Vec::new();
+        write_registration(CompletionShell::Bash, "sendfile", "sendfile", &mut script).unwrap();
+        assert!(String::from_utf8(script).unwrap().contains("COMPLETE"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
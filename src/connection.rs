@@ -5,8 +5,10 @@ use std::{
 };
 
 use crate::transport::{
-    CURRENT_PROTOCOL_VERSION, LENGTH_HEADER_PREFIX, MAX_HEADER_SIZE, MAX_MESSAGE_SIZE,
-    MESSAGE_DELIMITER, VERSION_HEADER_PRIFIX,
+    FrameHeader, FrameHeaderError, BINARY_FRAMING_PROTOCOL_VERSION, FRAME_HEADER_SIZE, FRAME_MAGIC,
+    LENGTH_HEADER_PREFIX, MAX_HEADER_SIZE, MAX_MESSAGE_SIZE, MESSAGE_DELIMITER,
+    SESSION_FRAMING_PROTOCOL_VERSION, SESSION_ID_SIZE, TEXT_FRAMING_PROTOCOL_VERSION,
+    VERSION_HEADER_PRIFIX,
 };
 use serde::Deserialize;
 
@@ -33,14 +35,23 @@ pub enum StreamReadError {
     #[error("Unsupported protocol version: found {found}, expected {expected}")]
     UnsupportedProtocolVersion { found: u8, expected: u8 },
 
+    /// The binary frame header is corrupted or uses unknown flags.
+    #[error("Invalid frame header: {0}")]
+    InvalidFrameHeader(#[from] FrameHeaderError),
+
     /// Failed to deserialize the message payload.
     #[error("Failed to parse message payload: {0}")]
     PayloadParseError(#[from] postcard::Error),
+
+    /// The message is not tagged with the session of the transfer, see
+    /// [ReadPayloadResult::check_session].
+    #[error("Message does not belong to the session of the transfer")]
+    WrongSession,
 }
 
 /// Result of reading a message from the stream
 ///
-/// Includes the parsed message, the index of the next payload in the buffer, and the total number of
+/// Includes the parsed message, the protocol version it was framed with, the session id it was tagged with, the index of the next payload in the buffer, and the total number of
 /// bytes read from the stream.
 ///
 /// The `next_payload_index` and `total_bytes_reac` fields are used to ensure that any extra data
@@ -50,10 +61,34 @@ pub enum StreamReadError {
 #[derive(Debug)]
 pub struct ReadPayloadResult<T> {
     pub message: T,
+    pub protocol_version: u8,
+    pub session_id: Option<[u8; SESSION_ID_SIZE]>,
     pub total_bytes_read: usize,
     pub next_payload_index: Option<usize>,
 }
 
+impl<T> ReadPayloadResult<T> {
+    /// Checks that the message belongs to the session `session_id` of a transfer running
+    /// `protocol_version`: tagged with it from [SESSION_FRAMING_PROTOCOL_VERSION] on, untagged
+    /// before.
+    ///
+    /// # Returns
+    ///
+    /// [StreamReadError::WrongSession] if the message is tagged with another session, is tagged
+    /// when the transfer doesn't tag messages, or isn't when it does.
+    pub fn check_session(
+        &self,
+        session_id: Option<&[u8; SESSION_ID_SIZE]>,
+        protocol_version: u8,
+    ) -> Result<(), StreamReadError> {
+        let expected = session_id.filter(|_| protocol_version >= SESSION_FRAMING_PROTOCOL_VERSION);
+        if self.session_id.as_ref() != expected {
+            return Err(StreamReadError::WrongSession);
+        }
+        Ok(())
+    }
+}
+
 /// Reads a message from the stream, ensuring it starts with the expected headers and version.
 ///
 /// - `stream`: The input stream to read from.
@@ -62,18 +97,25 @@ pub struct ReadPayloadResult<T> {
 ///
 /// Returns a [ReadPayloadResult] containing the parsed message and metadata about the read operation, or a [StreamReadError] on failure.
 ///
+/// ## Framing:
+/// Messages framed with the binary [FrameHeader] of [BINARY_FRAMING_PROTOCOL_VERSION] up to
+/// [SESSION_FRAMING_PROTOCOL_VERSION], which later versions are framed as, and with the text headers of [TEXT_FRAMING_PROTOCOL_VERSION] are all
+/// read, told apart by [FRAME_MAGIC]. The session id frames of [SESSION_FRAMING_PROTOCOL_VERSION]
+/// are tagged with is returned, and checked by the caller, see [ReadPayloadResult::check_session].
+///
 /// ## Guarantees:
-/// The function will block until a complete message is read. Uses the `Len: ` header to determine
-/// the expected message length and ensures that the entire message is read before returning.
+/// The function will block until a complete message is read. Uses the payload length of the header
+/// to determine the expected message length and ensures that the entire message is read before returning.
 ///
 /// ## Expectations:
 /// 1. The caller must provide a buffer that is large enough to hold the entire message
 /// 2. The caller must ensure that any previous message data in the buffer is properly accounted for
-/// using the `filled_len` parameter
+///    using the `filled_len` parameter
 ///
 /// ## Errors:
-/// - cStreamReadError::BufferSmallerThanExpectedc: If the provided buffer is smaller than the expected message length
+/// - [StreamReadError::BufferSmallerThanExpected]: If the provided buffer is smaller than the expected message length
 /// - [StreamReadError::InvalidMessageFormat]: If the message does not start with the expected headers or version information
+/// - [StreamReadError::InvalidFrameHeader]: If a binary frame header is corrupted
 /// - [StreamReadError::Io]: For any I/O errors that occur during reading from the stream
 pub fn read_next_payload<'a, T, S: io::Read>(
     stream: &mut S,
@@ -83,69 +125,76 @@ pub fn read_next_payload<'a, T, S: io::Read>(
 where
     T: Deserialize<'a>,
 {
+    read_next_payload_with(stream, buffer, filled_len, postcard::from_bytes)
+}
+
+/// Reads a message from the stream as [read_next_payload] does, decoding its payload with
+/// `decode` instead of deserializing it as `T`.
+pub fn read_next_payload_with<'a, T, S: io::Read>(
+    stream: &mut S,
+    buffer: &'a mut [u8],
+    filled_len: usize,
+    decode: impl FnOnce(&'a [u8]) -> Result<T, postcard::Error>,
+) -> Result<ReadPayloadResult<T>, StreamReadError> {
     let mut total_bytes_read = filled_len; // Total bytes read from stream
 
-    // Extract header bytes
-    let header = loop {
-        if total_bytes_read == buffer.len() {
-            return Err(StreamReadError::BufferSmallerThanExpected {
-                min_expected: MAX_MESSAGE_SIZE,
+    // Enough bytes to tell the framings apart
+    fill_buffer(stream, buffer, &mut total_bytes_read, FRAME_MAGIC.len())?;
+    let mut session_id = None;
+    let (version, payload_start_index, length) = if buffer[..FRAME_MAGIC.len()] == FRAME_MAGIC {
+        fill_buffer(stream, buffer, &mut total_bytes_read, FRAME_HEADER_SIZE)?;
+        let header = FrameHeader::decode(buffer[..FRAME_HEADER_SIZE].try_into().unwrap())?;
+        // Later protocol versions are framed as the last one that changed the framing
+        if !(BINARY_FRAMING_PROTOCOL_VERSION..=SESSION_FRAMING_PROTOCOL_VERSION)
+            .contains(&header.version)
+        {
+            return Err(StreamReadError::UnsupportedProtocolVersion {
+                found: header.version,
+                expected: SESSION_FRAMING_PROTOCOL_VERSION,
             });
         }
-
-        if total_bytes_read > MAX_HEADER_SIZE {
+        if header.has_session() != (header.version >= SESSION_FRAMING_PROTOCOL_VERSION) {
             return Err(StreamReadError::InvalidMessageFormat {
                 details: format!(
-                    "Header exceeds maximum allowed size of {} bytes",
-                    MAX_HEADER_SIZE
+                    "Session id flag {} on a frame of protocol version {}",
+                    if header.has_session() {
+                        "set"
+                    } else {
+                        "missing"
+                    },
+                    header.version
                 ),
             });
         }
-
-        let curr_bytes_read = stream.read(&mut buffer[total_bytes_read..])?;
-        if curr_bytes_read == 0 {
-            return Err(StreamReadError::UnexpectedEof);
-        }
-        let previous_total = total_bytes_read;
-        total_bytes_read = previous_total + curr_bytes_read;
-
-        // Check if the header delimiter is present in the newly read bytes
-        let test_crlf_from_idx = previous_total.saturating_sub(2 * MESSAGE_DELIMITER.len() - 1);
-        let header_end_index_opt = buffer[test_crlf_from_idx..total_bytes_read]
-            .windows(2 * MESSAGE_DELIMITER.len())
-            .position(|window| window == [MESSAGE_DELIMITER, MESSAGE_DELIMITER].concat())
-            .map(|index| index + test_crlf_from_idx); // Adjust index to account for the offset
-
-        if let Some(header_end) = header_end_index_opt {
-            break &buffer[..header_end]; // We have the full header, break with the header slice
+        let mut payload_start_index = FRAME_HEADER_SIZE;
+        if header.has_session() {
+            payload_start_index += SESSION_ID_SIZE;
+            fill_buffer(stream, buffer, &mut total_bytes_read, payload_start_index)?;
+            session_id = Some(
+                buffer[FRAME_HEADER_SIZE..payload_start_index]
+                    .try_into()
+                    .unwrap(),
+            );
         }
+        (
+            header.version,
+            payload_start_index,
+            header.payload_length as usize,
+        )
+    } else {
+        read_text_headers(stream, buffer, &mut total_bytes_read)?
     };
 
-    let (version, length) = parse_all_headers(header)?;
-
-    if version != CURRENT_PROTOCOL_VERSION {
-        return Err(StreamReadError::UnsupportedProtocolVersion {
-            found: version,
-            expected: CURRENT_PROTOCOL_VERSION,
-        });
-    }
-
-    let payload_start_index = header.len() + 2 * MESSAGE_DELIMITER.len();
-    let expected_total_length = payload_start_index + length;
-
+    let expected_total_length = payload_start_index.saturating_add(length);
     if expected_total_length > buffer.len() {
         return Err(StreamReadError::BufferSmallerThanExpected {
             min_expected: expected_total_length,
         });
     }
-
-    while total_bytes_read < expected_total_length {
-        let bytes_read = stream.read(&mut buffer[total_bytes_read..])?;
-        total_bytes_read += bytes_read;
-    }
+    fill_buffer(stream, buffer, &mut total_bytes_read, expected_total_length)?;
 
     let payload_bytes = &buffer[payload_start_index..expected_total_length];
-    let message: T = postcard::from_bytes(payload_bytes)?;
+    let message = decode(payload_bytes)?;
     let next_payload_index = if total_bytes_read > expected_total_length {
         Some(expected_total_length)
     } else {
@@ -154,11 +203,98 @@ where
 
     Ok(ReadPayloadResult {
         message,
+        protocol_version: version,
+        session_id,
         total_bytes_read,
         next_payload_index,
     })
 }
 
+/// Reads from `stream` into `buffer` until it holds at least `target_len` bytes, of which
+/// `total_bytes_read` are already filled.
+fn fill_buffer<S: io::Read>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    total_bytes_read: &mut usize,
+    target_len: usize,
+) -> Result<(), StreamReadError> {
+    if target_len > buffer.len() {
+        return Err(StreamReadError::BufferSmallerThanExpected {
+            min_expected: target_len,
+        });
+    }
+    while *total_bytes_read < target_len {
+        let bytes_read = stream.read(&mut buffer[*total_bytes_read..])?;
+        if bytes_read == 0 {
+            return Err(StreamReadError::UnexpectedEof);
+        }
+        *total_bytes_read += bytes_read;
+    }
+    Ok(())
+}
+
+/// Fails with [StreamReadError::UnsupportedProtocolVersion] unless `found` is `expected`.
+fn check_version(found: u8, expected: u8) -> Result<(), StreamReadError> {
+    if found != expected {
+        return Err(StreamReadError::UnsupportedProtocolVersion { found, expected });
+    }
+    Ok(())
+}
+
+/// Reads the text headers of a [TEXT_FRAMING_PROTOCOL_VERSION] message into `buffer`, up to the
+/// empty line ending them.
+///
+/// Returns the tuple `(version, payload_start_index, length)` on success.
+fn read_text_headers<S: io::Read>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    total_bytes_read: &mut usize,
+) -> Result<(u8, usize, usize), StreamReadError> {
+    let mut searched_len: usize = 0; // Bytes already searched for the header delimiter
+
+    // Extract header bytes
+    let header = loop {
+        // Check if the header delimiter is present in the bytes not searched yet, which may
+        // include a whole message left over from the previous read
+        let test_crlf_from_idx = searched_len.saturating_sub(2 * MESSAGE_DELIMITER.len() - 1);
+        let header_end_index_opt = buffer[test_crlf_from_idx..*total_bytes_read]
+            .windows(2 * MESSAGE_DELIMITER.len())
+            .position(|window| window == [MESSAGE_DELIMITER, MESSAGE_DELIMITER].concat())
+            .map(|index| index + test_crlf_from_idx); // Adjust index to account for the offset
+
+        if let Some(header_end) = header_end_index_opt {
+            break &buffer[..header_end]; // We have the full header, break with the header slice
+        }
+        searched_len = *total_bytes_read;
+
+        if *total_bytes_read == buffer.len() {
+            return Err(StreamReadError::BufferSmallerThanExpected {
+                min_expected: MAX_MESSAGE_SIZE,
+            });
+        }
+
+        if *total_bytes_read > MAX_HEADER_SIZE {
+            return Err(StreamReadError::InvalidMessageFormat {
+                details: format!(
+                    "Header exceeds maximum allowed size of {} bytes",
+                    MAX_HEADER_SIZE
+                ),
+            });
+        }
+
+        let curr_bytes_read = stream.read(&mut buffer[*total_bytes_read..])?;
+        if curr_bytes_read == 0 {
+            return Err(StreamReadError::UnexpectedEof);
+        }
+        *total_bytes_read += curr_bytes_read;
+    };
+
+    let (version, length) = parse_all_headers(header)?;
+    check_version(version, TEXT_FRAMING_PROTOCOL_VERSION)?;
+
+    Ok((version, header.len() + 2 * MESSAGE_DELIMITER.len(), length))
+}
+
 /// Parses the headers from the provided header buffer and extracts the protocol
 /// version and payload length.
 ///
@@ -194,7 +330,7 @@ fn parse_all_headers(header_buffer: &[u8]) -> Result<(u8, usize), StreamReadErro
 /// ## Arguments
 /// - `line`: The header line to parse, as a byte slice.
 /// - `prefix_len`: The length of the expected prefix (including the ": " separator). This is used to
-/// split the header line and extract the value portion.
+///   split the header line and extract the value portion.
 pub fn parse_header_line<ParsedValue: FromStr<Err = impl Display>>(
     line: &[u8],
     prefix_len: usize,
@@ -223,6 +359,9 @@ mod tests {
     use std::io::{PipeReader, Write};
 
     use super::*;
+    use crate::transport::{
+        attach_headers, attach_session_headers, CURRENT_PROTOCOL_VERSION, FRAME_FLAG_SESSION,
+    };
     use serde::Serialize;
 
     /// Create a test struct to reduce the complexity of sending
@@ -241,26 +380,12 @@ mod tests {
             }
         }
 
-        fn get_message_bytes<'a>(&self) -> Vec<u8> {
+        fn get_message_bytes(&self) -> Vec<u8> {
             let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
             let payload_bytes =
                 postcard::to_slice(self, &mut buffer).expect("Failed to serialize MockMessage");
 
-            let version_header = format!("Ver: {}\r\n", CURRENT_PROTOCOL_VERSION);
-            let length_header = format!("Len: {}\r\n", payload_bytes.len());
-            let full_message = [
-                version_header.as_bytes(),
-                length_header.as_bytes(),
-                b"\r\n",
-                payload_bytes,
-            ]
-            .concat();
-            let full_message_chars = full_message
-                .iter()
-                .map(|byte| *byte as char)
-                .collect::<Vec<_>>();
-            println!("Full message bytes: {:?}", full_message_chars);
-            full_message.to_owned()
+            attach_headers(payload_bytes).into_vec()
         }
     }
 
@@ -305,7 +430,7 @@ mod tests {
             postcard::to_slice(&message, &mut buffer).expect("Failed to serialize test message");
 
         let full_message = [
-            format!("Ver: {}\r\n", CURRENT_PROTOCOL_VERSION).as_bytes(),
+            format!("Ver: {}\r\n", TEXT_FRAMING_PROTOCOL_VERSION).as_bytes(),
             format!("Len: {}\r\n", payload_bytes.len()).as_bytes(),
             b"\r\n",
             payload_bytes,
@@ -332,7 +457,7 @@ mod tests {
             move || {
                 // Write 5 bytes at a time with a delay to simulate slowness
                 for chunk in payload_bytes.chunks(5) {
-                    writer.write(chunk).expect("Failed to write chunk");
+                    writer.write_all(chunk).expect("Failed to write chunk");
                     writer.flush().expect("Failed to flush writer");
                     std::thread::sleep(std::time::Duration::from_millis(100)); // 100ms delay between chunks
                 }
@@ -344,4 +469,114 @@ mod tests {
             .expect("Failed to read payload from slow writer");
         assert_eq!(result.message, message);
     }
+
+    #[test]
+    fn test_read_next_payload_from_leftover() {
+        // Two messages read at once: the second is parsed from the buffer without touching the
+        // stream, which has nothing more to read
+        let message = MockMessage::new_dummy_message();
+        let bytes = message.get_message_bytes();
+        let mut buffer = vec![0u8; 1024];
+        buffer[..bytes.len()].copy_from_slice(&bytes);
+        buffer[bytes.len()..2 * bytes.len()].copy_from_slice(&bytes);
+
+        let mut empty = io::empty();
+        let result = read_next_payload::<MockMessage, _>(&mut empty, &mut buffer, 2 * bytes.len())
+            .expect("Failed to read first message");
+        assert_eq!(result.message, message);
+        assert_eq!(result.next_payload_index, Some(bytes.len()));
+
+        buffer.copy_within(bytes.len()..2 * bytes.len(), 0);
+        let result = read_next_payload::<MockMessage, _>(&mut empty, &mut buffer, bytes.len())
+            .expect("Failed to read leftover message");
+        assert_eq!(result.message, message);
+        assert_eq!(result.next_payload_index, None);
+    }
+
+    #[test]
+    fn test_read_next_payload_framings() {
+        let message = MockMessage::new_dummy_message();
+        let mut payload = vec![0u8; 64];
+        let payload = postcard::to_slice(&message, &mut payload).unwrap();
+        let mut buffer = vec![0u8; 1024];
+
+        let binary = attach_headers(payload);
+        let result = read_next_payload::<MockMessage, _>(&mut &binary[..], &mut buffer, 0)
+            .expect("Failed to read binary frame");
+        assert_eq!(result.message, message);
+        assert_eq!(result.protocol_version, BINARY_FRAMING_PROTOCOL_VERSION);
+        assert_eq!(result.session_id, None);
+
+        let text = crate::transport::attach_text_headers(payload);
+        let result = read_next_payload::<MockMessage, _>(&mut &text[..], &mut buffer, 0)
+            .expect("Failed to read text frame");
+        assert_eq!(result.message, message);
+        assert_eq!(result.protocol_version, TEXT_FRAMING_PROTOCOL_VERSION);
+
+        // Text headers are only used by version 1
+        let text = b"Ver: 2\r\nLen: 0\r\n\r\n";
+        let err = read_next_payload::<MockMessage, _>(&mut &text[..], &mut buffer, 0).unwrap_err();
+        assert!(matches!(
+            err,
+            StreamReadError::UnsupportedProtocolVersion {
+                found: 2,
+                expected: 1
+            }
+        ));
+
+        let mut corrupted = binary.into_vec();
+        corrupted[6] ^= 0x40;
+        let err =
+            read_next_payload::<MockMessage, _>(&mut &corrupted[..], &mut buffer, 0).unwrap_err();
+        assert!(matches!(
+            err,
+            StreamReadError::InvalidFrameHeader(FrameHeaderError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_next_payload_session_frames() {
+        let message = MockMessage::new_dummy_message();
+        let mut payload = vec![0u8; 64];
+        let payload = postcard::to_slice(&message, &mut payload).unwrap();
+        let mut buffer = vec![0u8; 1024];
+
+        let tagged = attach_session_headers(&[7; SESSION_ID_SIZE], payload);
+        let result = read_next_payload::<MockMessage, _>(&mut &tagged[..], &mut buffer, 0)
+            .expect("Failed to read session frame");
+        assert_eq!(result.message, message);
+        assert_eq!(result.protocol_version, SESSION_FRAMING_PROTOCOL_VERSION);
+        assert_eq!(result.session_id, Some([7; SESSION_ID_SIZE]));
+        assert!(result
+            .check_session(Some(&[7; SESSION_ID_SIZE]), CURRENT_PROTOCOL_VERSION)
+            .is_ok());
+        assert!(matches!(
+            result.check_session(Some(&[8; SESSION_ID_SIZE]), CURRENT_PROTOCOL_VERSION),
+            Err(StreamReadError::WrongSession)
+        ));
+        assert!(result
+            .check_session(Some(&[7; SESSION_ID_SIZE]), BINARY_FRAMING_PROTOCOL_VERSION)
+            .is_err());
+
+        // Frames of a session-tagging transfer must be tagged
+        let untagged = attach_headers(payload);
+        let result = read_next_payload::<MockMessage, _>(&mut &untagged[..], &mut buffer, 0)
+            .expect("Failed to read binary frame");
+        assert!(result
+            .check_session(Some(&[7; SESSION_ID_SIZE]), CURRENT_PROTOCOL_VERSION)
+            .is_err());
+        assert!(result
+            .check_session(Some(&[7; SESSION_ID_SIZE]), BINARY_FRAMING_PROTOCOL_VERSION)
+            .is_ok());
+
+        // The flag and the version go together
+        let mut header = FrameHeader::new(payload.len() as u32);
+        header.flags = FRAME_FLAG_SESSION;
+        let mut mismatched = header.encode().to_vec();
+        mismatched.extend_from_slice(&[7; SESSION_ID_SIZE]);
+        mismatched.extend_from_slice(payload);
+        let err =
+            read_next_payload::<MockMessage, _>(&mut &mismatched[..], &mut buffer, 0).unwrap_err();
+        assert!(matches!(err, StreamReadError::InvalidMessageFormat { .. }));
+    }
 }
@@ -0,0 +1,326 @@
+//! Status dashboard served over HTTP.
+//!
+//! `sendfile dashboard` serves a single page, embedded in the binary, that shows the transfers
+//! running in local sendfile processes (see [crate::status]) with live progress, along with the
+//! transfer history. The page polls two JSON endpoints:
+//!
+//! - `GET /api/transfers`: active transfers, grouped by process.
+//! - `GET /api/history`: the most recent history entries, newest first.
+//!
+//! Only requests naming the dashboard's own address or `localhost` in their `Host` header are
+//! answered, so a page on another site can't read them through a rebound DNS name.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+use crate::{history::load_entries, status::query_status, threads::thread_name};
+
+/// The dashboard page.
+const INDEX_HTML: &str = include_str!("dashboard/index.html");
+
+/// Number of history entries returned by `/api/history`.
+const HISTORY_LIMIT: usize = 100;
+
+/// Time allowed to a client to send its request, and to read the response.
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+/// Longest request or header line read, in bytes.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// Most header lines read from a request.
+const MAX_HEADERS: usize = 64;
+
+/// Most requests answered at once, connections past it are closed unanswered.
+const MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// Where the dashboard reads its data from.
+#[derive(Debug, Clone)]
+pub struct DashboardSources {
+    /// Directory of the status sockets of running processes.
+    pub status_dir: PathBuf,
+    /// History file of completed sends, `None` shows an empty history.
+    pub history_path: Option<PathBuf>,
+}
+
+/// An HTTP response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(body: Vec<u8>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn error(status: &'static str, message: String) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: message.into_bytes(),
+        }
+    }
+}
+
+/// Decrements the number of requests being answered when the request is done.
+struct ActiveRequest(Arc<AtomicUsize>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves the dashboard on `address` until the process exits. Each request is answered on its
+/// own thread, up to [MAX_CONCURRENT_REQUESTS] at once.
+pub fn serve_dashboard(
+    address: impl ToSocketAddrs,
+    sources: DashboardSources,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let local_addr = listener.local_addr()?;
+    info!("Dashboard listening on http://{}", local_addr);
+    let active = Arc::new(AtomicUsize::new(0));
+
+    for (index, stream) in listener.incoming().enumerate() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept dashboard connection: {}", e);
+                continue;
+            }
+        };
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_REQUESTS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            debug!("Too many dashboard requests, closing connection");
+            continue;
+        }
+        let request = ActiveRequest(active.clone());
+
+        let sources = sources.clone();
+        let spawn_result = thread::Builder::new()
+            .name(thread_name("http", index))
+            .spawn(move || {
+                let _request = request;
+                if let Err(e) = handle_request(stream, local_addr, &sources) {
+                    debug!("Failed to answer dashboard request: {}", e);
+                }
+            });
+        if let Err(e) = spawn_result {
+            warn!("Failed to spawn dashboard thread: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(
+    mut stream: TcpStream,
+    local_addr: SocketAddr,
+    sources: &DashboardSources,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+    let response = match read_request(&mut BufReader::new(&stream))? {
+        Ok(request) if !is_allowed_host(request.host.as_deref(), local_addr) => {
+            Response::error("403 Forbidden", String::from("Host not allowed"))
+        }
+        Ok(request) if request.method == "GET" => route(&request.target, sources),
+        Ok(request) => Response::error(
+            "405 Method Not Allowed",
+            format!("{} is not supported", request.method),
+        ),
+        Err(response) => response,
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+/// The parts of an HTTP request the dashboard looks at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Request {
+    method: String,
+    target: String,
+    host: Option<String>,
+}
+
+/// Reads the request line and headers of a request, up to the empty line ending them.
+///
+/// # Returns
+///
+/// The request, or the error response to answer a request with overlong lines or too many
+/// headers with.
+fn read_request(reader: &mut impl BufRead) -> std::io::Result<Result<Request, Response>> {
+    let Some(request_line) = read_line(reader)? else {
+        return Ok(Err(Response::error(
+            "414 URI Too Long",
+            String::from("Request line too long"),
+        )));
+    };
+    let mut parts = request_line.split_whitespace();
+    let mut request = Request {
+        method: parts.next().unwrap_or_default().to_string(),
+        target: parts.next().unwrap_or_default().to_string(),
+        host: None,
+    };
+
+    for _ in 0..MAX_HEADERS {
+        let Some(line) = read_line(reader)? else {
+            break;
+        };
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(Ok(request));
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("host")
+        {
+            request.host = Some(value.trim().to_string());
+        }
+    }
+    Ok(Err(Response::error(
+        "431 Request Header Fields Too Large",
+        String::from("Request headers too large"),
+    )))
+}
+
+/// Reads a line of at most [MAX_LINE_LEN] bytes, `None` if it is longer.
+fn read_line(reader: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_LEN).read_line(&mut line)?;
+    Ok((line.ends_with('\n') || (line.len() as u64) < MAX_LINE_LEN).then_some(line))
+}
+
+/// Returns whether a request with the `Host` header `host` is meant for the dashboard listening
+/// on `local_addr`: it names its address or `localhost`, with its port if any.
+fn is_allowed_host(host: Option<&str>, local_addr: SocketAddr) -> bool {
+    let Some(host) = host else {
+        return false;
+    };
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name, Some(port)),
+        _ => (host, None),
+    };
+    if port.is_some_and(|port| port.parse() != Ok(local_addr.port())) {
+        return false;
+    }
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip == local_addr.ip() || ip.is_loopback())
+}
+
+fn route(target: &str, sources: &DashboardSources) -> Response {
+    let path = target.split('?').next().unwrap_or_default();
+    match path {
+        "/" | "/index.html" => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: INDEX_HTML.as_bytes().to_vec(),
+        },
+        "/api/transfers" => match query_status(&sources.status_dir) {
+            Ok(processes) => Response::json(serde_json::to_vec(&processes).unwrap_or_default()),
+            Err(e) => Response::error("500 Internal Server Error", e.to_string()),
+        },
+        "/api/history" => match recent_history(sources.history_path.as_deref()) {
+            Ok(body) => Response::json(body),
+            Err(e) => Response::error("500 Internal Server Error", e.to_string()),
+        },
+        _ => Response::error("404 Not Found", format!("{path} not found")),
+    }
+}
+
+fn recent_history(path: Option<&Path>) -> Result<Vec<u8>, crate::history::HistoryError> {
+    let mut entries = match path {
+        Some(path) => load_entries(path)?,
+        None => Vec::new(),
+    };
+    entries.reverse();
+    entries.truncate(HISTORY_LIMIT);
+    Ok(serde_json::to_vec(&entries)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let dir = std::env::temp_dir().join(format!("sendfile_dashboard_{}", std::process::id()));
+        let sources = DashboardSources {
+            status_dir: dir.join("status"),
+            history_path: Some(dir.join("history.jsonl")),
+        };
+
+        assert_eq!(
+            route("/", &sources).content_type,
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(route("/api/transfers?t=1", &sources).body, b"[]");
+        assert_eq!(route("/api/history", &sources).body, b"[]");
+        assert_eq!(route("/favicon.ico", &sources).status, "404 Not Found");
+    }
+
+    #[test]
+    fn test_read_request_checks_host() {
+        let local_addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let request =
+            read_request(&mut &b"GET /api/history HTTP/1.1\r\nHost: localhost:8080\r\n\r\n"[..])
+                .unwrap()
+                .unwrap();
+        assert_eq!(request.target, "/api/history");
+        assert!(is_allowed_host(request.host.as_deref(), local_addr));
+
+        assert!(is_allowed_host(Some("127.0.0.1:8080"), local_addr));
+        assert!(is_allowed_host(Some("[::1]:8080"), local_addr));
+        assert!(!is_allowed_host(Some("evil.example:8080"), local_addr));
+        assert!(!is_allowed_host(Some("localhost:9090"), local_addr));
+        assert!(!is_allowed_host(None, local_addr));
+
+        let long_line = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_LINE_LEN as usize)
+        );
+        assert_eq!(
+            read_request(&mut long_line.as_bytes())
+                .unwrap()
+                .unwrap_err()
+                .status,
+            "414 URI Too Long"
+        );
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS));
+        assert_eq!(
+            read_request(&mut many_headers.as_bytes())
+                .unwrap()
+                .unwrap_err()
+                .status,
+            "431 Request Header Fields Too Large"
+        );
+    }
+}
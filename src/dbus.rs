@@ -0,0 +1,203 @@
+//! Transfer events on the D-Bus session bus, for desktop integration.
+//!
+//! With `--dbus`, the process emits signals on the `org.sendfile.Transfer` interface at
+//! `/org/sendfile/Transfer` so desktop environments and scripts can react to transfers (show
+//! notifications, track progress, ...):
+//!
+//! - `Offer(id: t, peer: s, file_name: s, total_bytes: t)`: a sender offered a file.
+//! - `Started(id: t, peer: s, file_name: s, total_bytes: t)`: a file started being sent.
+//! - `Progress(id: t, bytes_transferred: t, total_bytes: t)`: every second while running.
+//! - `Completed(id: t, file_name: s)` and `Failed(id: t, file_name: s)`: the transfer ended.
+//!
+//! `total_bytes` is 0 while unknown. Requires the `dbus` cargo feature.
+
+use std::{
+    sync::mpsc::RecvTimeoutError,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use zbus::blocking::Connection;
+
+use crate::{
+    stream::registry::{RegistryEvent, TransferDirection, TransferRegistry, TransferStatus},
+    threads::thread_name,
+};
+
+/// Object path the signals are emitted from.
+pub const OBJECT_PATH: &str = "/org/sendfile/Transfer";
+/// Interface of the signals.
+pub const INTERFACE: &str = "org.sendfile.Transfer";
+
+/// Interval between two `Progress` signals of a transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connects to the session bus and emits signals for the transfers of `registry` from a
+/// background thread, until the process exits.
+pub fn spawn_dbus_events(registry: &'static TransferRegistry) -> zbus::Result<()> {
+    let connection = Connection::session()?;
+    let events = registry.subscribe();
+
+    thread::Builder::new()
+        .name(thread_name("dbus", 0))
+        .spawn(move || {
+            let mut next_progress = Instant::now() + PROGRESS_INTERVAL;
+            loop {
+                let timeout = next_progress.saturating_duration_since(Instant::now());
+                let result = match events.recv_timeout(timeout) {
+                    Ok(event) => emit(&connection, &Signal::of_event(event)),
+                    Err(RecvTimeoutError::Timeout) => {
+                        next_progress = Instant::now() + PROGRESS_INTERVAL;
+                        registry
+                            .snapshot()
+                            .iter()
+                            .try_for_each(|status| emit(&connection, &Signal::progress(status)))
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if let Err(e) = result {
+                    warn!("Failed to emit D-Bus signal: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// A signal of [INTERFACE], with its arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Signal {
+    Offer(u64, String, String, u64),
+    Started(u64, String, String, u64),
+    Progress(u64, u64, u64),
+    Completed(u64, String),
+    Failed(u64, String),
+}
+
+impl Signal {
+    /// Returns the signal announcing `event`.
+    fn of_event(event: RegistryEvent) -> Self {
+        match event {
+            RegistryEvent::Started(status) => {
+                let total_bytes = status.total_bytes.unwrap_or(0);
+                match status.direction {
+                    TransferDirection::Receive => {
+                        Self::Offer(status.id, status.peer, status.file_name, total_bytes)
+                    }
+                    TransferDirection::Send => {
+                        Self::Started(status.id, status.peer, status.file_name, total_bytes)
+                    }
+                }
+            }
+            RegistryEvent::Finished {
+                id,
+                file_name,
+                succeeded: true,
+            } => Self::Completed(id, file_name),
+            RegistryEvent::Finished { id, file_name, .. } => Self::Failed(id, file_name),
+        }
+    }
+
+    /// Returns the signal reporting the progress of the transfer in `status`.
+    fn progress(status: &TransferStatus) -> Self {
+        Self::Progress(
+            status.id,
+            status.bytes_transferred,
+            status.total_bytes.unwrap_or(0),
+        )
+    }
+
+    /// Returns the name of the signal.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Offer(..) => "Offer",
+            Self::Started(..) => "Started",
+            Self::Progress(..) => "Progress",
+            Self::Completed(..) => "Completed",
+            Self::Failed(..) => "Failed",
+        }
+    }
+}
+
+fn emit(connection: &Connection, signal: &Signal) -> zbus::Result<()> {
+    let name = signal.name();
+    match signal {
+        Signal::Offer(id, peer, file_name, total_bytes)
+        | Signal::Started(id, peer, file_name, total_bytes) => emit_body(
+            connection,
+            name,
+            &(*id, peer.as_str(), file_name.as_str(), *total_bytes),
+        ),
+        Signal::Progress(id, bytes_transferred, total_bytes) => {
+            emit_body(connection, name, &(*id, *bytes_transferred, *total_bytes))
+        }
+        Signal::Completed(id, file_name) | Signal::Failed(id, file_name) => {
+            emit_body(connection, name, &(*id, file_name.as_str()))
+        }
+    }
+}
+
+fn emit_body<B>(connection: &Connection, signal: &str, body: &B) -> zbus::Result<()>
+where
+    B: serde::Serialize + zbus::zvariant::DynamicType,
+{
+    connection.emit_signal(None::<()>, OBJECT_PATH, INTERFACE, signal, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(direction: TransferDirection) -> TransferStatus {
+        TransferStatus {
+            id: 3,
+            direction,
+            peer: String::from("192.0.2.7:7878"),
+            file_name: String::from("report.pdf"),
+            bytes_transferred: 40,
+            total_bytes: None,
+            paused: false,
+            started_at: 1_700_000_000,
+            bottleneck: None,
+            files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_events_map_to_signals() {
+        let offer = Signal::of_event(RegistryEvent::Started(status(TransferDirection::Receive)));
+        assert_eq!(
+            offer,
+            Signal::Offer(
+                3,
+                String::from("192.0.2.7:7878"),
+                String::from("report.pdf"),
+                0
+            )
+        );
+        assert_eq!(offer.name(), "Offer");
+        let started = Signal::of_event(RegistryEvent::Started(status(TransferDirection::Send)));
+        assert_eq!(started.name(), "Started");
+
+        let finished = |succeeded| {
+            Signal::of_event(RegistryEvent::Finished {
+                id: 3,
+                file_name: String::from("report.pdf"),
+                succeeded,
+            })
+        };
+        assert_eq!(
+            finished(true),
+            Signal::Completed(3, String::from("report.pdf"))
+        );
+        assert_eq!(
+            finished(false),
+            Signal::Failed(3, String::from("report.pdf"))
+        );
+
+        let mut running = status(TransferDirection::Send);
+        assert_eq!(Signal::progress(&running), Signal::Progress(3, 40, 0));
+        running.total_bytes = Some(100);
+        assert_eq!(Signal::progress(&running), Signal::Progress(3, 40, 100));
+    }
+}
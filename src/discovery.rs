@@ -0,0 +1,268 @@
+//! Receiver discovery on the local network over mDNS/DNS-SD.
+//!
+//! A receiver started with `receive --advertise` announces itself as a `_sendfile._tcp` service
+//! named after the host (or `--name`), with its handshake port and the fingerprint of its
+//! identity key in the TXT record. `sendfile discover` lists the receivers announced on the LAN,
+//! and `send` accepts one of their names instead of an address when no trusted peer or DNS name
+//! matches it. The fingerprint lets users tell receivers of the same name apart; it is not
+//! checked during the transfer, pair or trust the receiver for that.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::capabilities::SOFTWARE_VERSION;
+
+/// DNS-SD service type receivers are announced as.
+pub const SERVICE_TYPE: &str = "_sendfile._tcp.local.";
+
+/// Time `send` waits for a receiver named on the command line to answer.
+pub const DEFAULT_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// TXT record key of the fingerprint of the receiver's identity key.
+const FINGERPRINT_KEY: &str = "fp";
+
+/// TXT record key of the receiver's software version.
+const VERSION_KEY: &str = "version";
+
+/// Errors that can occur while announcing or discovering receivers.
+#[derive(Error, Debug)]
+pub enum DiscoveryError {
+    /// The mDNS responder could not be started or used.
+    #[error("mDNS error: {0}")]
+    Mdns(#[from] mdns_sd::Error),
+    /// No receiver of that name answered in time.
+    #[error("No receiver named {0:?} found on the local network")]
+    NotFound(String),
+}
+
+/// A receiver announced on the local network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiscoveredReceiver {
+    /// Name the receiver is announced with.
+    pub name: String,
+    /// Addresses of the receiver, IPv4 first.
+    pub addresses: Vec<IpAddr>,
+    /// Handshake port of the receiver.
+    pub port: u16,
+    /// Hex encoded Ed25519 public key of the receiver, if it has an identity.
+    pub fingerprint: Option<String>,
+    /// Software version of the receiver.
+    pub version: Option<String>,
+}
+
+impl fmt::Display for DiscoveredReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addresses: Vec<String> = self.addresses.iter().map(IpAddr::to_string).collect();
+        write!(
+            f,
+            "{}  {} port {}",
+            self.name,
+            addresses.join(", "),
+            self.port
+        )?;
+        if let Some(fingerprint) = &self.fingerprint {
+            write!(f, "  key {}", fingerprint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Announcement of a receiver, withdrawn once dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            debug!("Failed to withdraw {:?}: {}", self.fullname, e);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Announces a receiver named `name` listening on `port`, with the hex encoded identity key
+/// `fingerprint`, on every network interface.
+pub fn advertise(
+    name: &str,
+    port: u16,
+    fingerprint: Option<&str>,
+) -> Result<Advertisement, DiscoveryError> {
+    let daemon = ServiceDaemon::new()?;
+    let mut properties = vec![(VERSION_KEY, SOFTWARE_VERSION)];
+    if let Some(fingerprint) = fingerprint {
+        properties.push((FINGERPRINT_KEY, fingerprint));
+    }
+    let host_name = format!("{}.local.", name.replace(' ', "-"));
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        name,
+        &host_name,
+        "",
+        port,
+        properties.as_slice(),
+    )?
+    .enable_addr_auto();
+    let fullname = info.get_fullname().to_string();
+    daemon.register(info)?;
+    Ok(Advertisement { daemon, fullname })
+}
+
+/// Lists the receivers answering within `timeout`, by name.
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredReceiver>, DiscoveryError> {
+    let mut receivers = HashMap::new();
+    browse(timeout, |receiver| {
+        receivers.insert(receiver.name.clone(), receiver);
+        false
+    })?;
+    let mut receivers: Vec<_> = receivers.into_values().collect();
+    receivers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(receivers)
+}
+
+/// Looks up the receiver announced as `name`, ignoring case, waiting `timeout` at most.
+pub fn resolve(name: &str, timeout: Duration) -> Result<DiscoveredReceiver, DiscoveryError> {
+    let mut found = None;
+    browse(timeout, |receiver| {
+        if receiver.name.eq_ignore_ascii_case(name) {
+            found = Some(receiver);
+        }
+        found.is_some()
+    })?;
+    found.ok_or_else(|| DiscoveryError::NotFound(name.to_string()))
+}
+
+/// Hands every receiver resolved within `timeout` to `on_receiver`, until it returns `true`.
+fn browse(
+    timeout: Duration,
+    mut on_receiver: impl FnMut(DiscoveredReceiver) -> bool,
+) -> Result<(), DiscoveryError> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let Ok(event) = events.recv_timeout(remaining) else {
+            break;
+        };
+        if let ServiceEvent::ServiceResolved(info) = event
+            && let Some(receiver) = receiver_from(&info)
+            && on_receiver(receiver)
+        {
+            break;
+        }
+    }
+    if let Err(e) = daemon.shutdown() {
+        warn!("Failed to stop the mDNS responder: {}", e);
+    }
+    Ok(())
+}
+
+/// Reads the receiver announced by `info`, `None` if it is not a sendfile receiver or none of
+/// its addresses can be connected to.
+fn receiver_from(info: &ServiceInfo) -> Option<DiscoveredReceiver> {
+    let name = info
+        .get_fullname()
+        .strip_suffix(SERVICE_TYPE)?
+        .strip_suffix('.')?;
+    // Link-local IPv6 addresses can't be connected to without the interface they are on
+    let mut addresses: Vec<IpAddr> = info
+        .get_addresses()
+        .iter()
+        .copied()
+        .filter(|address| !matches!(address, IpAddr::V6(v6) if v6.is_unicast_link_local()))
+        .collect();
+    if addresses.is_empty() {
+        return None;
+    }
+    addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+    Some(DiscoveredReceiver {
+        name: name.to_string(),
+        addresses,
+        port: info.get_port(),
+        fingerprint: info.get_property_val_str(FINGERPRINT_KEY).map(String::from),
+        version: info.get_property_val_str(VERSION_KEY).map(String::from),
+    })
+}
+
+/// Returns the name of this host, the default name receivers are announced with.
+pub fn host_name() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = [0u8; 256];
+        // SAFETY: the buffer outlives the call, and its last byte is left zero so the name is
+        // NUL terminated even if truncated
+        let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len() - 1) };
+        if result == 0 {
+            let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+            let name = String::from_utf8_lossy(&buffer[..len]);
+            // Only the first label of a fully qualified name
+            if let Some(name) = name.split('.').next().filter(|name| !name.is_empty()) {
+                return name.to_string();
+            }
+        }
+    }
+    String::from("sendfile")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receiver_from_service_info() {
+        let properties = [(FINGERPRINT_KEY, "ab01"), (VERSION_KEY, "0.1.0")];
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "nas",
+            "nas.local.",
+            "fe80::1,fd00::2,192.168.1.20",
+            7878,
+            &properties[..],
+        )
+        .unwrap();
+
+        assert_eq!(
+            receiver_from(&info),
+            Some(DiscoveredReceiver {
+                name: String::from("nas"),
+                addresses: vec!["192.168.1.20".parse().unwrap(), "fd00::2".parse().unwrap()],
+                port: 7878,
+                fingerprint: Some(String::from("ab01")),
+                version: Some(String::from("0.1.0")),
+            })
+        );
+
+        let other = ServiceInfo::new(
+            "_http._tcp.local.",
+            "nas",
+            "nas.local.",
+            "192.168.1.20",
+            80,
+            None::<HashMap<String, String>>,
+        )
+        .unwrap();
+        assert_eq!(receiver_from(&other), None);
+
+        let unreachable = ServiceInfo::new(
+            SERVICE_TYPE,
+            "nas",
+            "nas.local.",
+            "fe80::1",
+            7878,
+            &properties[..],
+        )
+        .unwrap();
+        assert_eq!(receiver_from(&unreachable), None);
+        assert!(!host_name().is_empty());
+    }
+}
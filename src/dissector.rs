@@ -0,0 +1,1252 @@
+//! Wireshark dissector of the protocol, generated from the message definitions
+//! (`sendfile dissector`).
+//!
+//! The layout of every message is traced from its `Deserialize` implementation: [Layouts::protocol]
+//! deserializes [SenderMessageV1] and [ReceiverMessageV1] from a tracing deserializer that answers
+//! every request with an empty value and records what was asked for, field names and variants
+//! included, until each variant of each enum has been seen. [Layouts::to_lua] then writes a Lua
+//! dissector reading both framings and walking the postcard encoding of those layouts, so it
+//! follows the protocol as messages are added instead of being kept in sync by hand.
+//!
+//! Only what crosses the wire in the clear can be dissected: frames of TLS and Noise connections
+//! are encrypted, and compressed blocks are shown as they were sent.
+
+use std::{
+    any::type_name,
+    collections::BTreeMap,
+    fmt::{self, Display, Write},
+    marker::PhantomData,
+};
+
+use serde::de::{
+    self, value::U32Deserializer, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    capabilities::SOFTWARE_VERSION,
+    limits::{HANDSHAKE_PORT, TRANSFER_PORT},
+    transport::{
+        ReceiverMessageV1, SenderMessageV1, FRAME_FLAG_SESSION, FRAME_HEADER_SIZE, FRAME_MAGIC,
+        LENGTH_HEADER_PREFIX, MAX_HEADER_SIZE, MESSAGE_DELIMITER, SESSION_ID_SIZE,
+        VERSION_HEADER_PRIFIX,
+    },
+};
+
+/// Traces of a root type after which an enum with a variant still unseen is an error, well above
+/// what the nesting of the protocol's messages takes.
+const MAX_TRACES: usize = 10_000;
+
+/// Errors tracing the layout of a message.
+#[derive(Error, Debug)]
+pub enum TraceError {
+    #[error("{0} has no postcard layout the dissector can walk")]
+    Unsupported(&'static str),
+
+    #[error("Variants of {0} are still untraced after {MAX_TRACES} traces")]
+    Incomplete(&'static str),
+
+    #[error("{0}")]
+    Deserialize(String),
+}
+
+impl de::Error for TraceError {
+    fn custom<T: Display>(message: T) -> Self {
+        Self::Deserialize(message.to_string())
+    }
+}
+
+/// Postcard encoding of a traced value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Layout {
+    /// Single byte, 0 or 1.
+    Bool,
+    /// Single byte.
+    U8,
+    /// Single byte, two's complement.
+    I8,
+    /// LEB128 varint of an unsigned integer.
+    Varint,
+    /// LEB128 varint of a zigzag-encoded signed integer.
+    Signed,
+    /// Varint length, then UTF-8 bytes.
+    Str,
+    /// Varint length, then bytes.
+    Bytes,
+    /// Bytes of a fixed-size array, without length.
+    FixedBytes(usize),
+    /// Elements of a tuple or array, without length.
+    Tuple(Vec<Layout>),
+    /// Varint count, then elements.
+    Seq(Box<Layout>),
+    /// Varint count, then keys and values.
+    Map(Box<Layout>, Box<Layout>),
+    /// Byte 0 for none, 1 followed by the value for some.
+    Option(Box<Layout>),
+    /// Nothing.
+    Unit,
+    /// Fields of a struct, in declaration order.
+    Struct(String),
+    /// Varint index of the variant, then its content.
+    Enum(String),
+}
+
+impl Layout {
+    /// Returns the Lua table describing the layout, see [LUA_DISSECTOR].
+    fn to_lua(&self) -> String {
+        match self {
+            Self::Bool => String::from("{ \"bool\" }"),
+            Self::U8 => String::from("{ \"u8\" }"),
+            Self::I8 => String::from("{ \"i8\" }"),
+            Self::Varint => String::from("{ \"varint\" }"),
+            Self::Signed => String::from("{ \"signed\" }"),
+            Self::Str => String::from("{ \"str\" }"),
+            Self::Bytes => String::from("{ \"bytes\" }"),
+            Self::Unit => String::from("{ \"unit\" }"),
+            Self::FixedBytes(len) => format!("{{ \"fixed\", {len} }}"),
+            Self::Tuple(elements) => {
+                let elements: Vec<String> = elements.iter().map(Self::to_lua).collect();
+                format!("{{ \"tuple\", {{ {} }} }}", elements.join(", "))
+            }
+            Self::Seq(element) => format!("{{ \"seq\", {} }}", element.to_lua()),
+            Self::Map(key, value) => format!("{{ \"map\", {}, {} }}", key.to_lua(), value.to_lua()),
+            Self::Option(value) => format!("{{ \"option\", {} }}", value.to_lua()),
+            Self::Struct(name) => format!("{{ \"struct\", {} }}", lua_string(name.as_bytes())),
+            Self::Enum(name) => format!("{{ \"enum\", {} }}", lua_string(name.as_bytes())),
+        }
+    }
+}
+
+/// Variants of an enum, while it is traced.
+struct EnumLayout {
+    /// Name and layout of the content of each variant, none until the variant is traced.
+    variants: Vec<(&'static str, Option<Layout>)>,
+    /// Index of the variant the next trace goes through.
+    next: usize,
+}
+
+impl EnumLayout {
+    fn is_complete(&self) -> bool {
+        self.variants.iter().all(|(_, layout)| layout.is_some())
+    }
+}
+
+/// Layouts of the structs and enums met while tracing.
+#[derive(Default)]
+struct Registry {
+    structs: BTreeMap<String, Vec<(&'static str, Layout)>>,
+    enums: BTreeMap<String, EnumLayout>,
+}
+
+impl Registry {
+    /// Traces `T` until every variant of every enum it holds has been seen.
+    ///
+    /// Each trace goes through the next variant of each enum it meets, in turn, so variants
+    /// nested in other variants are reached once their parents come round again.
+    fn trace<T: Deserialize<'static>>(&mut self) -> Result<(), TraceError> {
+        for _ in 0..MAX_TRACES {
+            trace_seed(self, PhantomData::<T>)?;
+            if self.enums.values().all(EnumLayout::is_complete) {
+                return Ok(());
+            }
+        }
+        Err(TraceError::Incomplete(type_name::<T>()))
+    }
+
+    /// Returns the traced layouts, once every variant has been seen.
+    fn into_layouts(self) -> Layouts {
+        let enums = self
+            .enums
+            .into_iter()
+            .map(|(name, layout)| {
+                let variants = layout
+                    .variants
+                    .into_iter()
+                    .map(|(variant, layout)| (variant, layout.unwrap_or(Layout::Unit)))
+                    .collect();
+                (name, variants)
+            })
+            .collect();
+        Layouts {
+            structs: self.structs,
+            enums,
+        }
+    }
+}
+
+/// Deserializes a value of `seed` from a [Tracer].
+///
+/// # Returns
+///
+/// The value, and the layout the tracer recorded for it.
+fn trace_seed<T: DeserializeSeed<'static>>(
+    registry: &mut Registry,
+    seed: T,
+) -> Result<(T::Value, Layout), TraceError> {
+    let mut layout = None;
+    let value = seed.deserialize(Tracer {
+        registry,
+        layout: &mut layout,
+    })?;
+    let layout = layout.ok_or(TraceError::Unsupported(type_name::<T::Value>()))?;
+    Ok((value, layout))
+}
+
+/// Deserializer answering every request with an empty value, recording the layout asked for.
+struct Tracer<'a> {
+    registry: &'a mut Registry,
+    /// Layout of the value deserialized, set once it is known.
+    layout: &'a mut Option<Layout>,
+}
+
+impl Tracer<'_> {
+    /// Traces the fields of the struct `name`, which may be a struct variant.
+    fn trace_struct<V: Visitor<'static>>(
+        self,
+        name: String,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let Tracer { registry, layout } = self;
+        let mut seq = SeqTracer::new(registry, fields.len());
+        let value = visitor.visit_seq(&mut seq)?;
+        let fields = fields.iter().copied().zip(seq.layouts).collect();
+        registry.structs.insert(name.clone(), fields);
+        *layout = Some(Layout::Struct(name));
+        Ok(value)
+    }
+}
+
+/// Implements deserialization of unsigned integers, all encoded as varints.
+macro_rules! trace_varint {
+    ($($deserialize:ident => $visit:ident),*) => {
+        $(
+            fn $deserialize<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+                *self.layout = Some(Layout::Varint);
+                visitor.$visit(0)
+            }
+        )*
+    };
+}
+
+/// Implements deserialization of signed integers, all encoded as zigzag varints.
+macro_rules! trace_signed {
+    ($($deserialize:ident => $visit:ident),*) => {
+        $(
+            fn $deserialize<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+                *self.layout = Some(Layout::Signed);
+                visitor.$visit(0)
+            }
+        )*
+    };
+}
+
+impl<'a> de::Deserializer<'static> for Tracer<'a> {
+    type Error = TraceError;
+
+    trace_varint!(
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128
+    );
+    trace_signed!(
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128
+    );
+
+    fn deserialize_any<V: Visitor<'static>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::Unsupported("A self-describing value"))
+    }
+
+    fn deserialize_bool<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::Bool);
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_u8<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::U8);
+        visitor.visit_u8(0)
+    }
+
+    fn deserialize_i8<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::I8);
+        visitor.visit_i8(0)
+    }
+
+    fn deserialize_f32<V: Visitor<'static>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::Unsupported("f32"))
+    }
+
+    fn deserialize_f64<V: Visitor<'static>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::Unsupported("f64"))
+    }
+
+    fn deserialize_char<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::Str);
+        visitor.visit_char(' ')
+    }
+
+    fn deserialize_str<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::Str);
+        visitor.visit_borrowed_str("")
+    }
+
+    fn deserialize_string<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::Str);
+        visitor.visit_string(String::new())
+    }
+
+    fn deserialize_bytes<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::Bytes);
+        visitor.visit_borrowed_bytes(&[])
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::Bytes);
+        visitor.visit_byte_buf(Vec::new())
+    }
+
+    fn deserialize_option<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut inner = None;
+        let value = visitor.visit_some(Tracer {
+            registry: self.registry,
+            layout: &mut inner,
+        })?;
+        let inner = inner.ok_or(TraceError::Unsupported("An option"))?;
+        *self.layout = Some(Layout::Option(Box::new(inner)));
+        Ok(value)
+    }
+
+    fn deserialize_unit<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Some(Layout::Unit);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'static>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'static>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        // Newtypes are encoded as what they wrap
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut seq = SeqTracer::new(self.registry, 1);
+        let value = visitor.visit_seq(&mut seq)?;
+        let element = seq.layouts.pop().unwrap_or(Layout::Unit);
+        *self.layout = Some(match element {
+            Layout::U8 => Layout::Bytes,
+            element => Layout::Seq(Box::new(element)),
+        });
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'static>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let mut seq = SeqTracer::new(self.registry, len);
+        let value = visitor.visit_seq(&mut seq)?;
+        let elements = seq.layouts;
+        *self.layout = Some(
+            if !elements.is_empty() && elements.iter().all(|e| *e == Layout::U8) {
+                Layout::FixedBytes(len)
+            } else {
+                Layout::Tuple(elements)
+            },
+        );
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'static>>(
+        self,
+        _: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut map = MapTracer {
+            registry: self.registry,
+            remaining: 1,
+            key: None,
+            value: None,
+        };
+        let value = visitor.visit_map(&mut map)?;
+        *self.layout = Some(Layout::Map(
+            Box::new(map.key.unwrap_or(Layout::Unit)),
+            Box::new(map.value.unwrap_or(Layout::Unit)),
+        ));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'static>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.trace_struct(name.to_string(), fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'static>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        if variants.is_empty() {
+            return Err(TraceError::Unsupported("An enum without variants"));
+        }
+        let traced = self
+            .registry
+            .enums
+            .entry(name.to_string())
+            .or_insert_with(|| EnumLayout {
+                variants: variants.iter().map(|variant| (*variant, None)).collect(),
+                next: 0,
+            });
+        let index = traced.next;
+        traced.next = (index + 1) % variants.len();
+
+        let mut variant = None;
+        let value = visitor.visit_enum(EnumTracer {
+            registry: &mut *self.registry,
+            name: format!("{name}::{}", variants[index]),
+            index,
+            layout: &mut variant,
+        })?;
+        if let Some(traced) = self.registry.enums.get_mut(name) {
+            traced.variants[index].1 = variant;
+        }
+        *self.layout = Some(Layout::Enum(name.to_string()));
+        Ok(value)
+    }
+
+    fn deserialize_identifier<V: Visitor<'static>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::Unsupported("An identifier"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'static>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::Unsupported("An ignored value"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Elements of a sequence, tuple or struct, traced in turn.
+struct SeqTracer<'a> {
+    registry: &'a mut Registry,
+    remaining: usize,
+    layouts: Vec<Layout>,
+}
+
+impl<'a> SeqTracer<'a> {
+    fn new(registry: &'a mut Registry, len: usize) -> Self {
+        Self {
+            registry,
+            remaining: len,
+            layouts: Vec::with_capacity(len),
+        }
+    }
+}
+
+impl SeqAccess<'static> for SeqTracer<'_> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'static>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let (value, layout) = trace_seed(self.registry, seed)?;
+        self.layouts.push(layout);
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// A single entry of a map, traced.
+struct MapTracer<'a> {
+    registry: &'a mut Registry,
+    remaining: usize,
+    key: Option<Layout>,
+    value: Option<Layout>,
+}
+
+impl MapAccess<'static> for MapTracer<'_> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'static>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let (key, layout) = trace_seed(self.registry, seed)?;
+        self.key = Some(layout);
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'static>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, TraceError> {
+        let (value, layout) = trace_seed(self.registry, seed)?;
+        self.value = Some(layout);
+        Ok(value)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// The variant of an enum chosen for this trace.
+struct EnumTracer<'a> {
+    registry: &'a mut Registry,
+    /// Name struct variants are registered under.
+    name: String,
+    index: usize,
+    /// Layout of the content of the variant, set once it is known.
+    layout: &'a mut Option<Layout>,
+}
+
+impl<'a> EnumAccess<'static> for EnumTracer<'a> {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'static>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), TraceError> {
+        let index = u32::try_from(self.index)
+            .map_err(|_| TraceError::Unsupported("An enum with more than u32::MAX variants"))?;
+        let variant = seed.deserialize(U32Deserializer::<TraceError>::new(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a> VariantAccess<'static> for EnumTracer<'a> {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        *self.layout = Some(Layout::Unit);
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'static>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, TraceError> {
+        seed.deserialize(Tracer {
+            registry: self.registry,
+            layout: self.layout,
+        })
+    }
+
+    fn tuple_variant<V: Visitor<'static>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        de::Deserializer::deserialize_tuple(
+            Tracer {
+                registry: self.registry,
+                layout: self.layout,
+            },
+            len,
+            visitor,
+        )
+    }
+
+    fn struct_variant<V: Visitor<'static>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let tracer = Tracer {
+            registry: self.registry,
+            layout: self.layout,
+        };
+        tracer.trace_struct(self.name, fields, visitor)
+    }
+}
+
+/// Layouts of the structs and enums of the protocol, traced from their definitions.
+pub struct Layouts {
+    /// Fields of each struct, in declaration order. Struct variants are named `Enum::Variant`.
+    structs: BTreeMap<String, Vec<(&'static str, Layout)>>,
+    /// Variants of each enum, in index order, with the layout of their content.
+    enums: BTreeMap<String, Vec<(&'static str, Layout)>>,
+}
+
+impl Layouts {
+    /// Traces the layouts of [SenderMessageV1] and [ReceiverMessageV1], and of everything they
+    /// hold.
+    ///
+    /// # Returns
+    ///
+    /// An error if a message holds a value postcard doesn't encode with a fixed layout, such as a
+    /// float.
+    pub fn protocol() -> Result<Self, TraceError> {
+        let mut registry = Registry::default();
+        registry.trace::<SenderMessageV1<'static>>()?;
+        registry.trace::<ReceiverMessageV1>()?;
+        Ok(registry.into_layouts())
+    }
+
+    /// Returns the Wireshark dissector of messages with these layouts, as a Lua plugin.
+    pub fn to_lua(&self) -> String {
+        let mut lua = String::new();
+        // Writing to a String can't fail
+        let _ = self.write_lua(&mut lua);
+        lua
+    }
+
+    fn write_lua(&self, lua: &mut String) -> fmt::Result {
+        writeln!(
+            lua,
+            "-- Wireshark dissector of the sendfile protocol, generated by `sendfile dissector` \
+             from the\n-- message definitions of sendfile {SOFTWARE_VERSION}. Regenerate it \
+             instead of editing it.\n--\n-- Copy it to the personal Lua plugins folder shown \
+             in Help > About Wireshark > Folders.\n"
+        )?;
+
+        let delimiter = MESSAGE_DELIMITER;
+        let headers_end = [delimiter, delimiter].concat();
+        let constants: [(&str, String); 12] = [
+            ("FRAME_MAGIC", lua_string(&FRAME_MAGIC)),
+            ("FRAME_HEADER_SIZE", FRAME_HEADER_SIZE.to_string()),
+            ("FRAME_FLAG_SESSION", FRAME_FLAG_SESSION.to_string()),
+            ("SESSION_ID_SIZE", SESSION_ID_SIZE.to_string()),
+            ("VERSION_HEADER_PREFIX", lua_string(VERSION_HEADER_PRIFIX)),
+            ("LENGTH_HEADER_PREFIX", lua_string(LENGTH_HEADER_PREFIX)),
+            ("HEADERS_END", lua_string(&headers_end)),
+            ("MAX_HEADER_SIZE", MAX_HEADER_SIZE.to_string()),
+            ("HANDSHAKE_PORT", HANDSHAKE_PORT.to_string()),
+            ("TRANSFER_PORT", TRANSFER_PORT.to_string()),
+            ("SENDER_MESSAGE", lua_string(b"SenderMessageV1")),
+            ("RECEIVER_MESSAGE", lua_string(b"ReceiverMessageV1")),
+        ];
+        for (name, value) in constants {
+            writeln!(lua, "local {name} = {value}")?;
+        }
+
+        writeln!(
+            lua,
+            "\n-- Fields of each struct, in the order they are encoded"
+        )?;
+        writeln!(lua, "local structs = {{")?;
+        for (name, fields) in &self.structs {
+            writeln!(lua, "  [{}] = {{", lua_string(name.as_bytes()))?;
+            for (field, layout) in fields {
+                let field = lua_string(field.as_bytes());
+                writeln!(lua, "    {{ {field}, {} }},", layout.to_lua())?;
+            }
+            writeln!(lua, "  }},")?;
+        }
+        writeln!(lua, "}}")?;
+
+        writeln!(
+            lua,
+            "\n-- Variants of each enum, in the order of their index"
+        )?;
+        writeln!(lua, "local enums = {{")?;
+        for (name, variants) in &self.enums {
+            writeln!(lua, "  [{}] = {{", lua_string(name.as_bytes()))?;
+            for (variant, layout) in variants {
+                let variant = lua_string(variant.as_bytes());
+                writeln!(lua, "    {{ {variant}, {} }},", layout.to_lua())?;
+            }
+            writeln!(lua, "  }},")?;
+        }
+        writeln!(lua, "}}")?;
+
+        lua.push_str(LUA_DISSECTOR);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Layouts {
+    /// Walks `bytes` as the Lua dissector does, as a value of the enum `name`.
+    ///
+    /// # Returns
+    ///
+    /// The name of the variant, if `bytes` hold exactly one value of the enum.
+    pub(crate) fn decode(&self, name: &str, bytes: &[u8]) -> Option<&'static str> {
+        let mut offset = 0;
+        let variant = self.skip_enum(name, bytes, &mut offset)?;
+        (offset == bytes.len()).then_some(variant)
+    }
+
+    fn skip_enum(&self, name: &str, bytes: &[u8], offset: &mut usize) -> Option<&'static str> {
+        let index = usize::try_from(read_varint(bytes, offset)?).ok()?;
+        let (variant, layout) = self.enums.get(name)?.get(index)?;
+        self.skip(layout, bytes, offset)?;
+        Some(variant)
+    }
+
+    fn skip(&self, layout: &Layout, bytes: &[u8], offset: &mut usize) -> Option<()> {
+        match layout {
+            Layout::Unit => Some(()),
+            Layout::Bool | Layout::U8 | Layout::I8 => advance(bytes, offset, 1),
+            Layout::FixedBytes(len) => advance(bytes, offset, *len),
+            Layout::Varint | Layout::Signed => read_varint(bytes, offset).map(|_| ()),
+            Layout::Str | Layout::Bytes => {
+                let len = usize::try_from(read_varint(bytes, offset)?).ok()?;
+                advance(bytes, offset, len)
+            }
+            Layout::Option(value) => match bytes.get(*offset)? {
+                0 => advance(bytes, offset, 1),
+                _ => {
+                    *offset += 1;
+                    self.skip(value, bytes, offset)
+                }
+            },
+            Layout::Tuple(elements) => elements
+                .iter()
+                .try_for_each(|element| self.skip(element, bytes, offset)),
+            Layout::Seq(element) => {
+                (0..read_varint(bytes, offset)?).try_for_each(|_| self.skip(element, bytes, offset))
+            }
+            Layout::Map(key, value) => (0..read_varint(bytes, offset)?).try_for_each(|_| {
+                self.skip(key, bytes, offset)?;
+                self.skip(value, bytes, offset)
+            }),
+            Layout::Struct(name) => self
+                .structs
+                .get(name)?
+                .iter()
+                .try_for_each(|(_, field)| self.skip(field, bytes, offset)),
+            Layout::Enum(name) => self.skip_enum(name, bytes, offset).map(|_| ()),
+        }
+    }
+}
+
+/// Moves `offset` past `len` bytes, if `bytes` hold them.
+#[cfg(test)]
+fn advance(bytes: &[u8], offset: &mut usize, len: usize) -> Option<()> {
+    *offset = offset.checked_add(len).filter(|end| *end <= bytes.len())?;
+    Some(())
+}
+
+/// Reads the postcard varint at `offset`, moving it past the varint.
+#[cfg(test)]
+fn read_varint(bytes: &[u8], offset: &mut usize) -> Option<u128> {
+    let mut value = 0u128;
+    for shift in (0..128).step_by(7) {
+        let byte = *bytes.get(*offset)?;
+        *offset += 1;
+        value |= u128::from(byte & 0x7F) << shift;
+        if byte < 0x80 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Returns the Wireshark dissector of the protocol, as a Lua plugin.
+///
+/// # Returns
+///
+/// An error if the layout of a message can't be traced, see [Layouts::protocol].
+pub fn lua_dissector() -> Result<String, TraceError> {
+    Ok(Layouts::protocol()?.to_lua())
+}
+
+/// Returns `bytes` as a Lua string literal, escaping what isn't printable ASCII.
+fn lua_string(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                literal.push('\\');
+                literal.push(byte as char);
+            }
+            b'\r' => literal.push_str("\\r"),
+            b'\n' => literal.push_str("\\n"),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\x{byte:02X}")),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Dissector walking the `structs` and `enums` tables written before it.
+///
+/// Layouts are tables naming their encoding first: `{ "varint" }`, `{ "fixed", 32 }`,
+/// `{ "seq", element }`, `{ "struct", name }` and so on, see [Layout]. Varints are decoded into
+/// Lua numbers, exact up to 2^53. Written for Lua 5.2 and later, without bitwise operators.
+const LUA_DISSECTOR: &str = r#"
+local sendfile = Proto("sendfile", "sendfile Protocol")
+
+local fields = {
+  version = ProtoField.uint8("sendfile.version", "Protocol version"),
+  flags = ProtoField.uint8("sendfile.flags", "Flags", base.HEX),
+  length = ProtoField.uint32("sendfile.length", "Payload length"),
+  header_crc = ProtoField.uint32("sendfile.header_crc", "Header CRC-32", base.HEX),
+  session_id = ProtoField.bytes("sendfile.session_id", "Session id"),
+  message = ProtoField.string("sendfile.message", "Message"),
+}
+sendfile.fields = {
+  fields.version, fields.flags, fields.length, fields.header_crc, fields.session_id,
+  fields.message,
+}
+
+local malformed = ProtoExpert.new("sendfile.malformed", "Payload does not match its message",
+  expert.group.MALFORMED, expert.severity.ERROR)
+sendfile.experts = { malformed }
+
+-- Bytes shown in hex, longer values are summed up by their length
+local MAX_HEX_BYTES = 64
+
+-- Adds a text item covering len bytes at offset, or none when dry running without a tree.
+local function add_item(tree, tvb, offset, len, text)
+  if tree == nil then
+    return nil
+  end
+  if len > 0 then
+    return tree:add(tvb(offset, len), text)
+  end
+  return tree:add(sendfile, text)
+end
+
+-- Shrinks an item added over the rest of the payload to the bytes its value took.
+local function fit_item(item, offset, stop)
+  if item ~= nil and stop ~= nil and stop > offset then
+    item:set_len(stop - offset)
+  end
+end
+
+local function format_number(value)
+  return string.format("%.0f", value)
+end
+
+local function format_bytes(tvb, offset, len)
+  if len == 0 then
+    return "(empty)"
+  elseif len > MAX_HEX_BYTES then
+    return format_number(len) .. " bytes"
+  end
+  return tvb(offset, len):bytes():tohex()
+end
+
+-- Reads the postcard varint at offset, returns its value and length, or nil past limit.
+local function read_varint(tvb, offset, limit)
+  local value, scale = 0, 1.0
+  for i = 0, 18 do
+    if offset + i >= limit then
+      return nil
+    end
+    local byte = tvb(offset + i, 1):uint()
+    value = value + (byte % 128) * scale
+    if byte < 128 then
+      return value, i + 1
+    end
+    scale = scale * 128
+  end
+  return nil
+end
+
+local dissect_layout
+
+-- Adds the fields of a struct to tree, returns the offset past them, or nil if the payload
+-- ends first.
+local function dissect_fields(tvb, offset, limit, name, tree)
+  for _, field in ipairs(structs[name]) do
+    offset = dissect_layout(tvb, offset, limit, field[2], tree, field[1])
+    if offset == nil then
+      return nil
+    end
+  end
+  return offset
+end
+
+-- Adds the variant of an enum to tree, returns the offset past it and the variant name, or nil
+-- if the payload ends first or names no variant.
+local function dissect_enum(tvb, offset, limit, name, tree, label)
+  local index, len = read_varint(tvb, offset, limit)
+  local variant = index and enums[name][index + 1]
+  if variant == nil then
+    return nil
+  end
+  local item = add_item(tree, tvb, offset, limit - offset, label .. ": " .. variant[1])
+  local content = variant[2]
+  local stop
+  if content[1] == "struct" then
+    stop = dissect_fields(tvb, offset + len, limit, content[2], item)
+  else
+    stop = dissect_layout(tvb, offset + len, limit, content, item, "value")
+  end
+  fit_item(item, offset, stop)
+  return stop, variant[1]
+end
+
+-- Adds the value of layout at offset to tree, returns the offset past it, or nil if the
+-- payload ends first.
+dissect_layout = function(tvb, offset, limit, layout, tree, label)
+  local kind = layout[1]
+  if kind == "unit" then
+    return offset
+  elseif kind == "bool" or kind == "u8" or kind == "i8" then
+    if offset >= limit then
+      return nil
+    end
+    local value = tvb(offset, 1):uint()
+    if kind == "bool" then
+      value = tostring(value ~= 0)
+    elseif kind == "i8" and value >= 128 then
+      value = value - 256
+    end
+    add_item(tree, tvb, offset, 1, label .. ": " .. value)
+    return offset + 1
+  elseif kind == "varint" or kind == "signed" then
+    local value, len = read_varint(tvb, offset, limit)
+    if value == nil then
+      return nil
+    end
+    if kind == "signed" then
+      if value % 2 == 0 then
+        value = value / 2
+      else
+        value = -(value + 1) / 2
+      end
+    end
+    add_item(tree, tvb, offset, len, label .. ": " .. format_number(value))
+    return offset + len
+  elseif kind == "str" or kind == "bytes" then
+    local len, prefix = read_varint(tvb, offset, limit)
+    if len == nil or offset + prefix + len > limit then
+      return nil
+    end
+    local value
+    if kind == "bytes" then
+      value = format_bytes(tvb, offset + prefix, len)
+    elseif len == 0 then
+      value = '""'
+    else
+      value = '"' .. tvb(offset + prefix, len):string(ENC_UTF_8) .. '"'
+    end
+    add_item(tree, tvb, offset, prefix + len, label .. ": " .. value)
+    return offset + prefix + len
+  elseif kind == "fixed" then
+    local len = layout[2]
+    if offset + len > limit then
+      return nil
+    end
+    add_item(tree, tvb, offset, len, label .. ": " .. format_bytes(tvb, offset, len))
+    return offset + len
+  elseif kind == "option" then
+    if offset >= limit then
+      return nil
+    elseif tvb(offset, 1):uint() == 0 then
+      add_item(tree, tvb, offset, 1, label .. ": None")
+      return offset + 1
+    end
+    return dissect_layout(tvb, offset + 1, limit, layout[2], tree, label)
+  elseif kind == "tuple" then
+    local item = add_item(tree, tvb, offset, limit - offset, label)
+    local stop = offset
+    for i, element in ipairs(layout[2]) do
+      stop = dissect_layout(tvb, stop, limit, element, item, "[" .. (i - 1) .. "]")
+      if stop == nil then
+        return nil
+      end
+    end
+    fit_item(item, offset, stop)
+    return stop
+  elseif kind == "seq" or kind == "map" then
+    local count, prefix = read_varint(tvb, offset, limit)
+    -- Every element takes at least a byte
+    if count == nil or count > limit - offset - prefix then
+      return nil
+    end
+    local item = add_item(tree, tvb, offset, limit - offset, label .. ": " .. format_number(count) .. " items")
+    local stop = offset + prefix
+    for i = 0, count - 1 do
+      if kind == "seq" then
+        stop = dissect_layout(tvb, stop, limit, layout[2], item, "[" .. format_number(i) .. "]")
+      else
+        local entry = add_item(item, tvb, stop, limit - stop, "[" .. format_number(i) .. "]")
+        local start = stop
+        stop = dissect_layout(tvb, stop, limit, layout[2], entry, "key")
+        stop = stop and dissect_layout(tvb, stop, limit, layout[3], entry, "value")
+        fit_item(entry, start, stop)
+      end
+      if stop == nil then
+        return nil
+      end
+    end
+    fit_item(item, offset, stop)
+    return stop
+  elseif kind == "struct" then
+    local item = add_item(tree, tvb, offset, limit - offset, label)
+    local stop = dissect_fields(tvb, offset, limit, layout[2], item)
+    fit_item(item, offset, stop)
+    return stop
+  elseif kind == "enum" then
+    return (dissect_enum(tvb, offset, limit, layout[2], tree, label))
+  end
+  return nil
+end
+
+-- Returns whether the bytes at offset start like prefix, and whether they hold all of it.
+local function starts_with(tvb, offset, prefix)
+  local available = math.min(tvb:len() - offset, #prefix)
+  return tvb(offset, available):raw() == prefix:sub(1, available), available == #prefix
+end
+
+-- Returns the length of the frame at offset and of its headers, nil if no frame starts there,
+-- or -1 while more bytes are needed to tell.
+local function frame_length(tvb, offset)
+  local available = tvb:len() - offset
+  local binary, complete = starts_with(tvb, offset, FRAME_MAGIC)
+  if binary then
+    if not complete or available < FRAME_HEADER_SIZE then
+      return -1
+    end
+    local header = FRAME_HEADER_SIZE
+    if math.floor(tvb(offset + 5, 1):uint() / FRAME_FLAG_SESSION) % 2 == 1 then
+      header = header + SESSION_ID_SIZE
+    end
+    return header + tvb(offset + 6, 4):uint(), header
+  end
+
+  local text, complete = starts_with(tvb, offset, VERSION_HEADER_PREFIX)
+  if not text then
+    return nil
+  elseif not complete then
+    return -1
+  end
+  local headers = tvb(offset, math.min(available, MAX_HEADER_SIZE)):raw()
+  local stop = headers:find(HEADERS_END, 1, true)
+  if stop == nil then
+    if available < MAX_HEADER_SIZE then
+      return -1
+    end
+    return nil
+  end
+  local length = tonumber(headers:match(LENGTH_HEADER_PREFIX .. "(%d+)"))
+  if length == nil then
+    return nil
+  end
+  local header = stop + #HEADERS_END - 1
+  return header + length, header
+end
+
+-- Returns the message enum expected from the direction of the packet: the sender writes to the
+-- receiver's handshake port, and the receiver to the sender's transfer port.
+local function expected_message(pinfo)
+  if pinfo.dst_port == HANDSHAKE_PORT or pinfo.src_port == TRANSFER_PORT then
+    return SENDER_MESSAGE, RECEIVER_MESSAGE
+  end
+  return RECEIVER_MESSAGE, SENDER_MESSAGE
+end
+
+-- Adds a whole frame to tree, returns the name of its message.
+local function dissect_frame(tvb, header, pinfo, tree)
+  local item = tree:add(sendfile, tvb())
+  if starts_with(tvb, 0, FRAME_MAGIC) then
+    item:add(tvb(0, #FRAME_MAGIC), "Magic: " .. tvb(0, #FRAME_MAGIC):bytes():tohex())
+    item:add(fields.version, tvb(4, 1))
+    item:add(fields.flags, tvb(5, 1))
+    item:add(fields.length, tvb(6, 4))
+    item:add(fields.header_crc, tvb(10, 4))
+    if header > FRAME_HEADER_SIZE then
+      item:add(fields.session_id, tvb(FRAME_HEADER_SIZE, SESSION_ID_SIZE))
+    end
+  else
+    local headers = tvb(0, header):raw()
+    local version = tonumber(headers:match(VERSION_HEADER_PREFIX .. "(%d+)")) or 0
+    item:add(fields.version, tvb(0, header), version)
+    item:add(fields.length, tvb(0, header), tvb:len() - header)
+  end
+
+  local limit = tvb:len()
+  local expected, other = expected_message(pinfo)
+  if dissect_enum(tvb, header, limit, expected, nil, "") ~= limit
+      and dissect_enum(tvb, header, limit, other, nil, "") == limit then
+    expected = other
+  end
+  local stop, variant = dissect_enum(tvb, header, limit, expected, item, expected)
+  if stop ~= limit then
+    item:add_proto_expert_info(malformed)
+  end
+  variant = variant or "Unknown"
+  if limit > header then
+    item:add(fields.message, tvb(header, limit - header), variant)
+  end
+  item:append_text(", " .. variant)
+  return variant
+end
+
+function sendfile.dissector(tvb, pinfo, tree)
+  local offset = 0
+  local messages = {}
+  while offset < tvb:len() do
+    local length, header = frame_length(tvb, offset)
+    if length == nil then
+      break
+    elseif length < 0 or offset + length > tvb:len() then
+      pinfo.desegment_offset = offset
+      if length < 0 then
+        pinfo.desegment_len = DESEGMENT_ONE_MORE_SEGMENT
+      else
+        pinfo.desegment_len = offset + length - tvb:len()
+      end
+      offset = tvb:len()
+      break
+    end
+    messages[#messages + 1] = dissect_frame(tvb(offset, length):tvb(), header, pinfo, tree)
+    offset = offset + length
+  end
+  if #messages > 0 then
+    pinfo.cols.protocol = "SENDFILE"
+    pinfo.cols.info = table.concat(messages, ", ")
+  end
+  return offset
+end
+
+local tcp_port = DissectorTable.get("tcp.port")
+tcp_port:add(HANDSHAKE_PORT, sendfile)
+tcp_port:add(TRANSFER_PORT, sendfile)
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize, Deserialize)]
+    struct Inner {
+        kind: Kind,
+        hash: [u8; 4],
+        kinds: Vec<Kind>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum Kind {
+        First,
+        Second,
+        Third,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum Sample {
+        Empty,
+        Pair(i32, Option<u16>),
+        Named {
+            name: String,
+            entries: BTreeMap<String, u64>,
+        },
+        Nested(Inner),
+    }
+
+    fn sample_layouts() -> Layouts {
+        let mut registry = Registry::default();
+        registry.trace::<Sample>().unwrap();
+        registry.into_layouts()
+    }
+
+    #[test]
+    fn test_traced_layouts_walk_encoded_values() {
+        let layouts = sample_layouts();
+        let nested = Inner {
+            kind: Kind::Third,
+            hash: [1, 2, 3, 4],
+            kinds: vec![Kind::Second, Kind::First],
+        };
+        let samples = [
+            (Sample::Empty, "Empty"),
+            (Sample::Pair(-300, Some(300)), "Pair"),
+            (Sample::Pair(5, None), "Pair"),
+            (
+                Sample::Named {
+                    name: String::from("name"),
+                    entries: BTreeMap::from([(String::from("key"), u64::MAX)]),
+                },
+                "Named",
+            ),
+            (Sample::Nested(nested), "Nested"),
+        ];
+        let mut buffer = [0u8; 64];
+        for (sample, variant) in samples {
+            let bytes = postcard::to_slice(&sample, &mut buffer).unwrap();
+            assert_eq!(layouts.decode("Sample", bytes), Some(variant));
+            assert_eq!(layouts.decode("Sample", &bytes[..bytes.len() - 1]), None);
+        }
+        assert_eq!(layouts.decode("Sample", &[4]), None);
+
+        assert_eq!(
+            layouts.structs["Inner"],
+            vec![
+                ("kind", Layout::Enum(String::from("Kind"))),
+                ("hash", Layout::FixedBytes(4)),
+                (
+                    "kinds",
+                    Layout::Seq(Box::new(Layout::Enum(String::from("Kind"))))
+                ),
+            ]
+        );
+        assert_eq!(
+            layouts.enums["Sample"][1].1,
+            Layout::Tuple(vec![
+                Layout::Signed,
+                Layout::Option(Box::new(Layout::Varint))
+            ])
+        );
+        assert!(layouts.structs.contains_key("Sample::Named"));
+        assert_eq!(layouts.enums["Kind"].len(), 3);
+    }
+
+    #[test]
+    fn test_lua_declares_every_message() {
+        let layouts = Layouts::protocol().unwrap();
+        let lua = layouts.to_lua();
+        for name in ["SenderMessageV1", "ReceiverMessageV1", "BatchedMessageV1"] {
+            assert!(
+                lua.contains(&format!("[\"{name}\"] = {{")),
+                "{name} missing"
+            );
+            for (variant, _) in &layouts.enums[name] {
+                assert!(
+                    lua.contains(&format!("{{ \"{variant}\", ")),
+                    "{variant} missing"
+                );
+            }
+        }
+        assert!(lua.contains(r#"local FRAME_MAGIC = "\xF5SFP""#));
+        assert!(lua.contains(r#"local HEADERS_END = "\r\n\r\n""#));
+        assert_eq!(lua_string(b"a\"b\\\x01"), r#""a\"b\\\x01""#);
+    }
+}
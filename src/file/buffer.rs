@@ -0,0 +1,130 @@
+//! Page aligned buffers for the hot path.
+//!
+//! Connection and hashing buffers are allocated on page boundaries (4 KiB), as required by
+//! `O_DIRECT` and letting the kernel move whole pages instead of partial ones. Buffers of at least
+//! [HUGE_PAGE_SIZE] are aligned to it and, on Linux, marked for transparent huge pages so the
+//! kernel can back them with larger pages and fewer, larger DMA segments.
+
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+/// Alignment of every [AlignedBuffer], the smallest page size of common platforms.
+pub const PAGE_SIZE: usize = 4096;
+/// Size of a transparent huge page on x86_64 and most ARMv8 kernels.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Zero initialized, fixed size buffer aligned to a page boundary.
+///
+/// Dereferences to a byte slice, so it can be used wherever a `&mut [u8]` buffer is expected.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer owns its allocation like a `Vec<u8>`
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocates a zeroed buffer of `len` bytes, aligned to [PAGE_SIZE], or to [HUGE_PAGE_SIZE]
+    /// if it is at least that large.
+    pub fn zeroed(len: usize) -> Self {
+        let align = if len >= HUGE_PAGE_SIZE {
+            HUGE_PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+        // Whole pages, and never a zero sized allocation
+        let size = len.max(1).next_multiple_of(align);
+        let layout = Layout::from_size_align(size, align).expect("buffer size overflows isize");
+
+        // SAFETY: the layout has a non-zero size
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+        if align == HUGE_PAGE_SIZE {
+            advise_huge_pages(ptr, size);
+        }
+
+        Self { ptr, len, layout }
+    }
+
+    /// Returns the alignment of the buffer in bytes.
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the allocation holds at least `len` initialized bytes and lives as long as self
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in `deref`, and `&mut self` guarantees exclusive access
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `zeroed` with this layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+impl fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("len", &self.len)
+            .field("alignment", &self.alignment())
+            .finish()
+    }
+}
+
+/// Asks the kernel to back the allocation with transparent huge pages. Failure (e.g. THP
+/// disabled) only costs the optimization, so it is ignored.
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(ptr: NonNull<u8>, size: usize) {
+    // SAFETY: the range is a live allocation aligned to a page boundary
+    unsafe {
+        libc::madvise(ptr.as_ptr().cast(), size, libc::MADV_HUGEPAGE);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_huge_pages(_ptr: NonNull<u8>, _size: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zeroed_buffer_alignment() {
+        for (len, align) in [
+            (0, PAGE_SIZE),
+            (100, PAGE_SIZE),
+            (HUGE_PAGE_SIZE + 128, HUGE_PAGE_SIZE),
+        ] {
+            let mut buffer = AlignedBuffer::zeroed(len);
+            assert_eq!(buffer.len(), len);
+            assert_eq!(buffer.alignment(), align);
+            assert_eq!(buffer.as_ptr() as usize % align, 0);
+            assert!(buffer.iter().all(|&byte| byte == 0));
+
+            buffer.fill(0xAB);
+            assert!(buffer.iter().all(|&byte| byte == 0xAB));
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Types of offered files, and the policy a receiver filters offers with.
+//!
+//! The type of a file is told from its extension and, when the sender supports
+//! [FILE_HEADER](crate::capabilities::Capabilities::FILE_HEADER), from the magic bytes among the
+//! first [SNIFF_LEN] bytes it sends along with the handshake. A [TypePolicy] lists the types a
+//! receiver accepts or rejects, so a drop box can refuse executables or only take media files
+//! before any block is downloaded.
+
+use std::{fmt::Display, path::Path, str::FromStr};
+
+/// Number of bytes at the start of a file sent for sniffing its type.
+pub const SNIFF_LEN: usize = 512;
+
+/// Prefix of the reason of offers rejected by a [TypePolicy], so senders can tell them apart.
+pub const TYPE_REJECTION_PREFIX: &str = "Content type refused: ";
+
+/// Broad kind of file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    /// Programs, libraries, installers and scripts.
+    Executable,
+    Image,
+    Video,
+    Audio,
+    /// Compressed files and archives.
+    Archive,
+    /// PDFs, office documents and text.
+    Document,
+}
+
+impl FileKind {
+    const ALL: &[Self] = &[
+        Self::Executable,
+        Self::Image,
+        Self::Video,
+        Self::Audio,
+        Self::Archive,
+        Self::Document,
+    ];
+
+    /// Returns the name the kind is given on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Executable => "executable",
+            Self::Image => "image",
+            Self::Video => "video",
+            Self::Audio => "audio",
+            Self::Archive => "archive",
+            Self::Document => "document",
+        }
+    }
+
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Executable => &[
+                "exe", "dll", "msi", "com", "scr", "bat", "cmd", "ps1", "vbs", "sh", "so", "dylib",
+                "apk", "jar",
+            ],
+            Self::Image => &[
+                "jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff", "heic", "svg",
+            ],
+            Self::Video => &["mp4", "m4v", "mkv", "webm", "avi", "mov", "wmv"],
+            Self::Audio => &["mp3", "flac", "ogg", "opus", "wav", "m4a", "aac"],
+            Self::Archive => &["zip", "gz", "tgz", "xz", "zst", "bz2", "7z", "rar", "tar"],
+            Self::Document => &["pdf", "txt", "md", "doc", "docx", "odt", "rtf"],
+        }
+    }
+
+    /// Returns the kind of files named with `extension`, compared case-insensitively.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let extension = extension.to_ascii_lowercase();
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.extensions().contains(&extension.as_str()))
+    }
+}
+
+impl Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Magic bytes of a format: every `(offset, bytes)` part must be found in the header.
+struct Signature {
+    parts: &'static [(usize, &'static [u8])],
+    kind: FileKind,
+    format: &'static str,
+}
+
+const SIGNATURES: &[Signature] = &[
+    signature(&[(0, b"\x7fELF")], FileKind::Executable, "ELF"),
+    signature(&[(0, b"MZ")], FileKind::Executable, "PE"),
+    signature(&[(0, b"\xcf\xfa\xed\xfe")], FileKind::Executable, "Mach-O"),
+    signature(&[(0, b"\xce\xfa\xed\xfe")], FileKind::Executable, "Mach-O"),
+    signature(&[(0, b"\xca\xfe\xba\xbe")], FileKind::Executable, "Mach-O"),
+    signature(&[(0, b"#!")], FileKind::Executable, "script"),
+    signature(&[(0, b"\x89PNG\r\n\x1a\n")], FileKind::Image, "PNG"),
+    signature(&[(0, b"\xff\xd8\xff")], FileKind::Image, "JPEG"),
+    signature(&[(0, b"GIF8")], FileKind::Image, "GIF"),
+    signature(&[(0, b"RIFF"), (8, b"WEBP")], FileKind::Image, "WebP"),
+    signature(&[(4, b"ftyp")], FileKind::Video, "MP4"),
+    signature(&[(0, b"\x1a\x45\xdf\xa3")], FileKind::Video, "Matroska"),
+    signature(&[(0, b"RIFF"), (8, b"AVI ")], FileKind::Video, "AVI"),
+    signature(&[(0, b"RIFF"), (8, b"WAVE")], FileKind::Audio, "WAV"),
+    signature(&[(0, b"ID3")], FileKind::Audio, "MP3"),
+    signature(&[(0, b"fLaC")], FileKind::Audio, "FLAC"),
+    signature(&[(0, b"OggS")], FileKind::Audio, "Ogg"),
+    signature(&[(0, b"PK\x03\x04")], FileKind::Archive, "ZIP"),
+    signature(&[(0, b"\x1f\x8b")], FileKind::Archive, "gzip"),
+    signature(&[(0, b"\xfd7zXZ\x00")], FileKind::Archive, "xz"),
+    signature(&[(0, b"\x28\xb5\x2f\xfd")], FileKind::Archive, "zstd"),
+    signature(&[(0, b"BZh")], FileKind::Archive, "bzip2"),
+    signature(&[(0, b"7z\xbc\xaf\x27\x1c")], FileKind::Archive, "7z"),
+    signature(&[(0, b"Rar!\x1a\x07")], FileKind::Archive, "RAR"),
+    signature(&[(257, b"ustar")], FileKind::Archive, "tar"),
+    signature(&[(0, b"%PDF-")], FileKind::Document, "PDF"),
+];
+
+const fn signature(
+    parts: &'static [(usize, &'static [u8])],
+    kind: FileKind,
+    format: &'static str,
+) -> Signature {
+    Signature {
+        parts,
+        kind,
+        format,
+    }
+}
+
+/// Returns the kind and format name of a file starting with `header`, `None` if no known magic
+/// bytes are found.
+pub fn sniff(header: &[u8]) -> Option<(FileKind, &'static str)> {
+    SIGNATURES
+        .iter()
+        .find(|signature| {
+            signature.parts.iter().all(|(offset, magic)| {
+                header
+                    .get(*offset..offset + magic.len())
+                    .is_some_and(|bytes| bytes == *magic)
+            })
+        })
+        .map(|signature| (signature.kind, signature.format))
+}
+
+/// What is known about the type of an offered file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileType {
+    /// Lowercase extension of the file name, without the dot.
+    pub extension: Option<String>,
+    /// Kind and format sniffed from the first bytes, `None` if they are unknown or unrecognized.
+    pub sniffed: Option<(FileKind, &'static str)>,
+}
+
+impl FileType {
+    /// Describes the file named `file_name` starting with `header`, `None` if the sender did not
+    /// send its first bytes.
+    pub fn detect(file_name: &str, header: Option<&[u8]>) -> Self {
+        Self {
+            extension: Path::new(file_name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .map(str::to_ascii_lowercase),
+            sniffed: header.and_then(sniff),
+        }
+    }
+
+    /// Returns the kinds the file was found to be, from its content and its extension.
+    fn kinds(&self) -> impl Iterator<Item = FileKind> + '_ {
+        let by_extension = self.extension.as_deref().and_then(FileKind::from_extension);
+        self.sniffed
+            .map(|(kind, _)| kind)
+            .into_iter()
+            .chain(by_extension)
+    }
+}
+
+impl Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.sniffed {
+            Some((kind, format)) => write!(f, "{} {}", format, kind),
+            None => write!(f, "unrecognized content"),
+        }
+    }
+}
+
+/// Type of files listed in a [TypePolicy].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypePattern {
+    /// Files of any of these kinds, e.g. `image`, or `media` for images, videos and audio.
+    Kinds(Vec<FileKind>),
+    /// Files named with this extension, lowercase and without the dot, e.g. `iso`.
+    Extension(String),
+}
+
+impl TypePattern {
+    /// Returns whether anything about the file says it is of this type.
+    fn matches_any(&self, file_type: &FileType) -> bool {
+        match self {
+            Self::Kinds(kinds) => file_type.kinds().any(|kind| kinds.contains(&kind)),
+            Self::Extension(extension) => file_type.extension.as_ref() == Some(extension),
+        }
+    }
+
+    /// Returns whether everything known about the file says it is of this type, so a renamed
+    /// executable doesn't pass for a video.
+    fn matches_all(&self, file_type: &FileType) -> bool {
+        match self {
+            Self::Kinds(kinds) => {
+                let mut found = file_type.kinds().peekable();
+                found.peek().is_some() && found.all(|kind| kinds.contains(&kind))
+            }
+            Self::Extension(_) => self.matches_any(file_type),
+        }
+    }
+}
+
+impl FromStr for TypePattern {
+    type Err = String;
+
+    /// Parses a kind name, `media`, or an extension with or without its leading dot.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        if s == "media" {
+            return Ok(Self::Kinds(vec![
+                FileKind::Image,
+                FileKind::Video,
+                FileKind::Audio,
+            ]));
+        }
+        if let Some(kind) = FileKind::ALL.iter().find(|kind| kind.name() == s) {
+            return Ok(Self::Kinds(vec![*kind]));
+        }
+
+        let extension = s.strip_prefix('.').unwrap_or(&s);
+        if extension.is_empty() || !extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("{s:?} is neither a file kind nor an extension"));
+        }
+        Ok(Self::Extension(extension.to_string()))
+    }
+}
+
+impl Display for TypePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Kinds(kinds) => {
+                let names: Vec<_> = kinds.iter().map(|kind| kind.name()).collect();
+                write!(f, "{}", names.join("|"))
+            }
+            Self::Extension(extension) => write!(f, ".{}", extension),
+        }
+    }
+}
+
+/// Types of files a receiver accepts. The default accepts every file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TypePolicy {
+    /// Only files of one of these types are accepted, unless empty.
+    pub accept: Vec<TypePattern>,
+    /// Files of any of these types are rejected, even if accepted by [TypePolicy::accept].
+    pub reject: Vec<TypePattern>,
+}
+
+impl TypePolicy {
+    /// Checks the type of an offered file.
+    ///
+    /// A file is rejected if its content or its extension matches a rejected type, and, when
+    /// types to accept are listed, unless both its content and its extension, where known, match
+    /// one of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_name` - Name of the file on the sender side.
+    /// * `header` - First bytes of the file, `None` if the sender did not send them.
+    ///
+    /// # Returns
+    ///
+    /// The reason the file is rejected, if it is.
+    pub fn check(&self, file_name: &str, header: Option<&[u8]>) -> Result<(), String> {
+        let file_type = FileType::detect(file_name, header);
+
+        if let Some(pattern) = self
+            .reject
+            .iter()
+            .find(|pattern| pattern.matches_any(&file_type))
+        {
+            return Err(format!(
+                "{} ({}) matches rejected type {}",
+                file_name, file_type, pattern
+            ));
+        }
+
+        if !self.accept.is_empty()
+            && !self
+                .accept
+                .iter()
+                .any(|pattern| pattern.matches_all(&file_type))
+        {
+            let accepted: Vec<_> = self.accept.iter().map(|p| p.to_string()).collect();
+            return Err(format!(
+                "{} ({}) is not one of the accepted types {}",
+                file_name,
+                file_type,
+                accepted.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELF: &[u8] = b"\x7fELF\x02\x01\x01\x00";
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+
+    fn policy(accept: &[&str], reject: &[&str]) -> TypePolicy {
+        TypePolicy {
+            accept: accept.iter().map(|p| p.parse().unwrap()).collect(),
+            reject: reject.iter().map(|p| p.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(ELF), Some((FileKind::Executable, "ELF")));
+        assert_eq!(sniff(PNG), Some((FileKind::Image, "PNG")));
+        assert_eq!(
+            sniff(b"RIFF\x00\x00\x00\x00WAVEfmt "),
+            Some((FileKind::Audio, "WAV"))
+        );
+
+        let mut tar = vec![0u8; SNIFF_LEN];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff(&tar), Some((FileKind::Archive, "tar")));
+        assert_eq!(sniff(&tar[..260]), None);
+        assert_eq!(sniff(b"hello"), None);
+    }
+
+    #[test]
+    fn test_type_policy() {
+        let drop_box = policy(&[], &["executable", ".iso"]);
+        assert!(drop_box.check("notes.txt", Some(b"hello")).is_ok());
+        assert!(drop_box.check("setup.exe", None).is_err());
+        assert!(drop_box.check("disk.ISO", None).is_err());
+        // Renamed executables are caught by their content
+        let reason = drop_box.check("cat.jpg", Some(ELF)).unwrap_err();
+        assert_eq!(
+            reason,
+            "cat.jpg (ELF executable) matches rejected type executable"
+        );
+
+        let media = policy(&["media"], &[]);
+        assert!(media.check("cat.png", Some(PNG)).is_ok());
+        assert!(media.check("cat.png", None).is_ok());
+        assert!(media.check("cat", Some(PNG)).is_ok());
+        assert!(media.check("cat.png", Some(ELF)).is_err());
+        assert!(media.check("cat.exe", Some(PNG)).is_err());
+        assert_eq!(
+            media.check("notes", Some(b"hello")).unwrap_err(),
+            "notes (unrecognized content) is not one of the accepted types image|video|audio"
+        );
+
+        assert!("".parse::<TypePattern>().is_err());
+        assert!("../x".parse::<TypePattern>().is_err());
+        assert_eq!(
+            ".TAR".parse::<TypePattern>(),
+            Ok(TypePattern::Extension(String::from("tar")))
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Block devices as transfer endpoints, e.g. `/dev/sdb` when imaging a disk.
+//!
+//! Devices have a fixed size and no length to set: a receiver writing to one checks that the file
+//! fits, writes its blocks in place and leaves whatever follows the file on the device untouched.
+//! Nothing is written next to a device, neither resume state nor sidecars, since it usually sits
+//! in `/dev`. A sender reads a device like a file of [its size](file_size), and blocks of zeros
+//! in it are only named on the wire, see
+//! [Capabilities::ZERO_BLOCKS](crate::capabilities::Capabilities::ZERO_BLOCKS).
+
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Returns whether `path` is a block device. Always `false` on platforms without them.
+pub fn is_block_device(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        std::fs::metadata(path)
+            .map(|metadata| metadata.file_type().is_block_device())
+            .unwrap_or(false)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Returns the size of the device (or file) opened as `file`, whose metadata reports a length
+/// of 0 for devices.
+///
+/// On Linux, devices are asked for their size with the `BLKGETSIZE64` ioctl. Elsewhere, and for
+/// anything the ioctl doesn't apply to, the size is found by seeking to the end.
+pub fn device_size(file: &File) -> io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    if let Some(size) = ioctl_device_size(file) {
+        return Ok(size);
+    }
+    seek_size(file)
+}
+
+/// Returns the size of the block device opened as `file`, `None` if it isn't one.
+#[cfg(target_os = "linux")]
+fn ioctl_device_size(file: &File) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+
+    /// `BLKGETSIZE64` of `<linux/fs.h>`, the size of the device in bytes.
+    const BLKGETSIZE64: libc::Ioctl = libc::_IOR::<libc::size_t>(0x12, 114);
+
+    let mut size: u64 = 0;
+    // SAFETY: the descriptor is owned by `file` for the duration of the call, and the ioctl writes
+    // a single u64 into `size`.
+    match unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size) } {
+        0 => Some(size),
+        _ => None,
+    }
+}
+
+/// Returns the size of `file` by seeking to its end, then back to its start.
+fn seek_size(file: &File) -> io::Result<u64> {
+    let mut file = file;
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(size)
+}
+
+/// Returns the size of the file or block device at `path`.
+pub fn file_size(path: &Path) -> io::Result<u64> {
+    if is_block_device(path) {
+        device_size(&File::open(path)?)
+    } else {
+        Ok(std::fs::metadata(path)?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regular_files_are_not_devices() {
+        let path = std::env::temp_dir().join(format!("sendfile_device_{}", std::process::id()));
+        std::fs::write(&path, [7u8; 1000]).unwrap();
+
+        assert!(!is_block_device(&path));
+        assert!(!is_block_device(Path::new("/nonexistent/sendfile")));
+        assert_eq!(device_size(&File::open(&path).unwrap()).unwrap(), 1000);
+        assert_eq!(file_size(&path).unwrap(), 1000);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_size_falls_back_to_seeking() {
+        let path = std::env::temp_dir().join(format!("sendfile_seek_size_{}", std::process::id()));
+        std::fs::write(&path, [7u8; 3000]).unwrap();
+        let mut file = File::open(&path).unwrap();
+
+        // The ioctl only applies to block devices
+        #[cfg(target_os = "linux")]
+        assert_eq!(ioctl_device_size(&file), None);
+        file.seek(SeekFrom::Start(1234)).unwrap();
+        assert_eq!(device_size(&file).unwrap(), 3000);
+        assert_eq!(file.stream_position().unwrap(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
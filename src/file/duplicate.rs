@@ -0,0 +1,162 @@
+//! Files a receiver already holds, reused instead of downloaded again.
+//!
+//! Every received file is recorded in the [index of received files](crate::history::ReceivedEntry).
+//! When a file is offered whose hash and size match a file recorded under the output directory,
+//! and that file still holds the same content, it is hard-linked or copied to the destination
+//! and the transfer is reported complete without downloading any block.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use log::debug;
+
+use crate::history::{load_received, to_hex, HistoryError};
+
+/// Suffix appended to the destination to name the link or copy until it replaces it.
+pub const PLACEMENT_SUFFIX: &str = ".sendfile-duplicate";
+
+/// How a file already held by the receiver is placed at the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateMode {
+    /// Hard link sharing the content of the existing file, so changing one changes the other.
+    /// Falls back to a copy across filesystems.
+    Link,
+    /// Independent copy of the existing file.
+    Copy,
+}
+
+/// Returns the files of the index of received files at `index_path` with `file_hash` and
+/// `size` that still exist under `root`, most recently received first.
+///
+/// Only their size is checked, their content may have changed since they were received.
+pub fn find_candidates(
+    index_path: &Path,
+    root: &Path,
+    file_hash: [u8; 32],
+    size: u64,
+) -> Result<Vec<PathBuf>, HistoryError> {
+    let root = root.canonicalize()?;
+    let file_hash = to_hex(&file_hash);
+
+    let mut candidates = Vec::new();
+    for entry in load_received(index_path)?.into_iter().rev() {
+        if entry.file_hash != file_hash || entry.bytes != size {
+            continue;
+        }
+        let Ok(path) = entry.path.canonicalize() else {
+            continue;
+        };
+        if path.starts_with(&root)
+            && !candidates.contains(&path)
+            && fs::metadata(&path)
+                .is_ok_and(|metadata| metadata.is_file() && metadata.len() == size)
+        {
+            candidates.push(path);
+        }
+    }
+    Ok(candidates)
+}
+
+/// Places the content of `source` at `destination` with `mode`, replacing whatever is there.
+///
+/// # Returns
+///
+/// The mode the file was placed with, [DuplicateMode::Copy] if a hard link could not be made.
+pub fn place_duplicate(
+    source: &Path,
+    destination: &Path,
+    mode: DuplicateMode,
+) -> io::Result<DuplicateMode> {
+    let mut temp = destination.as_os_str().to_owned();
+    temp.push(PLACEMENT_SUFFIX);
+    let temp = PathBuf::from(temp);
+    let _ = fs::remove_file(&temp);
+
+    let placed = match mode {
+        DuplicateMode::Link => match fs::hard_link(source, &temp) {
+            Ok(()) => DuplicateMode::Link,
+            Err(e) => {
+                debug!("Failed to hard-link {:?}, copying it: {}", source, e);
+                fs::copy(source, &temp)?;
+                DuplicateMode::Copy
+            }
+        },
+        DuplicateMode::Copy => {
+            fs::copy(source, &temp)?;
+            DuplicateMode::Copy
+        }
+    };
+    if let Err(e) = fs::rename(&temp, destination) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    // Renaming onto another link to the same file leaves both names in place
+    let _ = fs::remove_file(&temp);
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{record_received, ReceivedEntry};
+
+    #[test]
+    fn test_find_and_place_duplicates() {
+        let dir = std::env::temp_dir().join(format!("sendfile_duplicate_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let outside = dir.join("outside");
+        let root = dir.join("root");
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(root.join("old")).unwrap();
+        let index = dir.join("received.jsonl");
+
+        let original = root.join("old").join("report.pdf");
+        let elsewhere = outside.join("report.pdf");
+        for path in [&original, &elsewhere] {
+            fs::write(path, b"report").unwrap();
+            let entry = ReceivedEntry {
+                timestamp: 1_700_000_000,
+                file_hash: to_hex(&[0xAB; 32]),
+                bytes: 6,
+                path: path.clone(),
+            };
+            record_received(&index, &entry).unwrap();
+        }
+
+        let original = original.canonicalize().unwrap();
+        assert_eq!(
+            find_candidates(&index, &root, [0xAB; 32], 6).unwrap(),
+            vec![original.clone()]
+        );
+        assert!(find_candidates(&index, &root, [0xCD; 32], 6)
+            .unwrap()
+            .is_empty());
+        assert!(find_candidates(&index, &root, [0xAB; 32], 7)
+            .unwrap()
+            .is_empty());
+
+        let linked = root.join("linked.pdf");
+        let copied = root.join("copied.pdf");
+        fs::write(&copied, b"stale content").unwrap();
+        assert_eq!(
+            place_duplicate(&original, &linked, DuplicateMode::Link).unwrap(),
+            DuplicateMode::Link
+        );
+        assert_eq!(
+            place_duplicate(&original, &copied, DuplicateMode::Copy).unwrap(),
+            DuplicateMode::Copy
+        );
+        // Linking again over the same file
+        place_duplicate(&original, &linked, DuplicateMode::Link).unwrap();
+
+        assert_eq!(fs::read(&linked).unwrap(), b"report");
+        assert_eq!(fs::read(&copied).unwrap(), b"report");
+        assert!(!root.join("linked.pdf.sendfile-duplicate").exists());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
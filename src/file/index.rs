@@ -0,0 +1,263 @@
+//! Indexing files already on disk into the [index of received files](crate::history::ReceivedEntry),
+//! so [duplicates](crate::file::duplicate) of files that predate the receiver are found too.
+//!
+//! `sendfile index build DIR` hashes every file under `DIR`, `sendfile index update DIR` only
+//! those added or modified since they were indexed. Files are hashed in parallel, one file per
+//! worker thread.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    file::{
+        duplicate::PLACEMENT_SUFFIX,
+        resume::{resume_state_path, RESUME_STATE_SUFFIX},
+        utils::{get_file_blake3_hash_with, HashStrategy},
+    },
+    history::{
+        forget_received_in, load_received_from, record_received_in, to_hex, HistoryError,
+        ReceivedEntry,
+    },
+    state::{JsonFileStore, StateStore},
+    threads::{thread_name, WorkerOptions},
+};
+
+/// Outcome of indexing a directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    /// Files hashed and added to the index.
+    pub hashed: usize,
+    /// Bytes of the files hashed.
+    pub bytes: u64,
+    /// Files whose entry was still up to date.
+    pub unchanged: usize,
+    /// Entries dropped for files that are gone.
+    pub removed: usize,
+}
+
+/// Indexes the files under `dir` into the index of received files at `index_path`, with
+/// `workers` hashing them.
+///
+/// With `rebuild`, every file under `dir` is hashed again. Otherwise entries of files unchanged
+/// since they were indexed are kept as they are. Entries of files outside of `dir` are always
+/// kept, entries of files under `dir` that are gone are dropped. Files being received, which
+/// have a resume state next to them, are skipped.
+///
+/// # Returns
+///
+/// The number of files hashed, kept and dropped, or a `HistoryError` if `dir` can't be walked
+/// or the index can't be read or written. Files that can't be hashed are skipped with a warning.
+pub fn index_directory(
+    index_path: &Path,
+    dir: &Path,
+    rebuild: bool,
+    workers: &WorkerOptions,
+) -> Result<IndexStats, HistoryError> {
+    let dir = dir.canonicalize()?;
+    let mut files = HashSet::new();
+    collect_files(&dir, &mut files)?;
+
+    let store = JsonFileStore::open(index_path)?;
+    let mut stats = IndexStats::default();
+    for entry in load_received_from(&store)? {
+        if !entry.path.starts_with(&dir) {
+            continue;
+        }
+        if !rebuild && is_up_to_date(&entry) && files.remove(&entry.path) {
+            stats.unchanged += 1;
+        } else if !files.contains(&entry.path) {
+            forget_received_in(&store, &entry.path)?;
+            stats.removed += 1;
+        }
+    }
+
+    let files: Vec<PathBuf> = files.into_iter().collect();
+    let hashed = hash_files(&files, workers);
+    stats.hashed = hashed.len();
+    stats.bytes = hashed.iter().map(|entry| entry.bytes).sum();
+    for entry in &hashed {
+        record_received_in(&store, entry)?;
+    }
+
+    store.compact()?;
+    Ok(stats)
+}
+
+/// Returns whether the file of `entry` still has the size it was indexed with and was not
+/// modified since, to the second.
+fn is_up_to_date(entry: &ReceivedEntry) -> bool {
+    let Ok(metadata) = fs::metadata(&entry.path) else {
+        return false;
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    metadata.len() == entry.bytes && modified.is_some_and(|modified| modified <= entry.timestamp)
+}
+
+/// Adds the regular files under `dir` to `files`, without following symbolic links.
+fn collect_files(dir: &Path, files: &mut HashSet<PathBuf>) -> Result<(), HistoryError> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&path, files)?;
+        } else if file_type.is_file() && !is_transfer_file(&path) {
+            files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `path` is left by a transfer rather than a received file: a resume state, a
+/// duplicate being placed, or a file still being received.
+fn is_transfer_file(path: &Path) -> bool {
+    let name = path.as_os_str().to_string_lossy();
+    name.ends_with(RESUME_STATE_SUFFIX)
+        || name.ends_with(PLACEMENT_SUFFIX)
+        || resume_state_path(path).exists()
+}
+
+/// Hashes `files` on [WorkerOptions::thread_count] threads, returning an entry for each file
+/// that could be read.
+fn hash_files(files: &[PathBuf], workers: &WorkerOptions) -> Vec<ReceivedEntry> {
+    let next = AtomicUsize::new(0);
+    let entries = Mutex::new(Vec::with_capacity(files.len()));
+    let threads = workers.thread_count().min(files.len());
+
+    thread::scope(|scope| {
+        for index in 0..threads {
+            let (next, entries) = (&next, &entries);
+            let spawn_result = thread::Builder::new()
+                .name(thread_name("index", index))
+                .spawn_scoped(scope, move || {
+                    workers.pin_current_thread(index);
+                    while let Some(path) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if let Some(entry) = hash_file(path) {
+                            entries
+                                .lock()
+                                .unwrap_or_else(|e| e.into_inner())
+                                .push(entry);
+                        }
+                    }
+                });
+            if let Err(e) = spawn_result {
+                warn!("Failed to spawn index thread {}: {}", index, e);
+            }
+        }
+    });
+
+    let mut entries = entries.into_inner().unwrap_or_else(|e| e.into_inner());
+    // In path order, whatever order the workers finished in
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+}
+
+/// Hashes the file at `path`, one thread reading it front to back since every worker hashes a
+/// file of its own.
+fn hash_file(path: &Path) -> Option<ReceivedEntry> {
+    debug!("Hashing {:?}", path);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let bytes = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            warn!("Skipping {:?}: {}", path, e);
+            return None;
+        }
+    };
+    match get_file_blake3_hash_with(path, HashStrategy::Sequential, &WorkerOptions::default()) {
+        Ok(hash) => Some(ReceivedEntry {
+            timestamp,
+            file_hash: to_hex(&hash),
+            bytes,
+            path: path.to_path_buf(),
+        }),
+        Err(e) => {
+            warn!("Skipping {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        file::utils::get_file_blake3_hash,
+        history::{load_received, record_received},
+    };
+
+    #[test]
+    fn test_build_and_update_index() {
+        let dir = std::env::temp_dir().join(format!("sendfile_index_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("nested")).unwrap();
+        let index = dir.join("received.jsonl");
+        let outside = ReceivedEntry {
+            timestamp: 1,
+            file_hash: to_hex(&[0xAB; 32]),
+            bytes: 6,
+            path: dir.join("elsewhere.bin"),
+        };
+        record_received(&index, &outside).unwrap();
+
+        let first = root.join("first.bin");
+        let second = root.join("nested").join("second.bin");
+        let partial = root.join("partial.bin");
+        fs::write(&first, b"first").unwrap();
+        fs::write(&second, b"second file").unwrap();
+        fs::write(&partial, b"part").unwrap();
+        fs::write(resume_state_path(&partial), b"state").unwrap();
+
+        let workers = WorkerOptions {
+            threads: Some(2),
+            ..WorkerOptions::default()
+        };
+        let stats = index_directory(&index, &root, true, &workers).unwrap();
+        assert_eq!(stats.hashed, 2);
+        assert_eq!(stats.bytes, 16);
+
+        let entries = load_received(&index).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], outside);
+        let first = first.canonicalize().unwrap();
+        let entry = entries.iter().find(|entry| entry.path == first).unwrap();
+        assert_eq!(
+            entry.file_hash,
+            to_hex(&get_file_blake3_hash(&first).unwrap())
+        );
+
+        fs::remove_file(&second).unwrap();
+        let stats = index_directory(&index, &root, false, &workers).unwrap();
+        assert_eq!(
+            stats,
+            IndexStats {
+                hashed: 0,
+                bytes: 0,
+                unchanged: 1,
+                removed: 1,
+            }
+        );
+        assert_eq!(load_received(&index).unwrap().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
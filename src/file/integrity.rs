@@ -0,0 +1,154 @@
+//! Integrity metadata kept in a received file's extended attributes.
+//!
+//! After a verified transfer the receiver can record the file's BLAKE3 hash and the time it was
+//! received as `user.sendfile.*` extended attributes. `sendfile check` later re-hashes the file
+//! and compares it with the recorded hash to detect bit rot or tampering.
+
+use std::{io, path::Path};
+
+use crate::{
+    file::{error::FileHashError, utils::get_file_blake3_hash},
+    history::{from_hex, to_hex},
+};
+
+/// Extended attribute holding the hex encoded BLAKE3 hash of the file.
+pub const HASH_ATTRIBUTE: &str = "user.sendfile.blake3";
+/// Extended attribute holding the time the file was received, in seconds since the Unix epoch.
+pub const RECEIVED_AT_ATTRIBUTE: &str = "user.sendfile.received_at";
+
+/// Integrity metadata recorded for a received file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntegrityRecord {
+    /// BLAKE3 hash of the file, as computed by [get_file_blake3_hash].
+    pub hash: [u8; 32],
+    /// Time the file was received, in seconds since the Unix epoch.
+    pub received_at: u64,
+}
+
+/// Result of re-verifying a file against its recorded integrity metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckOutcome {
+    /// The file still matches the recorded hash.
+    Intact(IntegrityRecord),
+    /// The file content changed since it was received.
+    Modified {
+        record: IntegrityRecord,
+        actual: [u8; 32],
+    },
+    /// The file has no integrity metadata.
+    Unrecorded,
+}
+
+/// Records `record` in the extended attributes of the file at `path`.
+pub fn store_integrity(path: &Path, record: &IntegrityRecord) -> io::Result<()> {
+    set_attribute(path, HASH_ATTRIBUTE, to_hex(&record.hash).as_bytes())?;
+    set_attribute(
+        path,
+        RECEIVED_AT_ATTRIBUTE,
+        record.received_at.to_string().as_bytes(),
+    )
+}
+
+/// Reads the integrity metadata of the file at `path`, `None` if it has none.
+pub fn load_integrity(path: &Path) -> io::Result<Option<IntegrityRecord>> {
+    let Some(hash) = get_attribute(path, HASH_ATTRIBUTE)? else {
+        return Ok(None);
+    };
+    let invalid = |name: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Malformed extended attribute {name}"),
+        )
+    };
+
+    let hash = std::str::from_utf8(&hash)
+        .ok()
+        .and_then(from_hex)
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| invalid(HASH_ATTRIBUTE))?;
+    let received_at = match get_attribute(path, RECEIVED_AT_ATTRIBUTE)? {
+        Some(value) => std::str::from_utf8(&value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| invalid(RECEIVED_AT_ATTRIBUTE))?,
+        None => 0,
+    };
+
+    Ok(Some(IntegrityRecord { hash, received_at }))
+}
+
+/// Re-hashes the file at `path` and compares it with its recorded integrity metadata.
+pub fn check_integrity(path: &Path) -> Result<CheckOutcome, FileHashError> {
+    let Some(record) = load_integrity(path)? else {
+        return Ok(CheckOutcome::Unrecorded);
+    };
+
+    let actual = get_file_blake3_hash(path)?;
+    if actual == record.hash {
+        Ok(CheckOutcome::Intact(record))
+    } else {
+        Ok(CheckOutcome::Modified { record, actual })
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn set_attribute(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+#[cfg(unix)]
+fn get_attribute(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    xattr::get(path, name)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_attribute(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Extended attributes are only supported on Unix",
+    ))
+}
+
+#[cfg(not(unix))]
+fn get_attribute(_path: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_store_and_check_integrity() {
+        let path = std::env::temp_dir().join(format!("sendfile_xattr_{}", std::process::id()));
+        fs::write(&path, b"received content").unwrap();
+        assert_eq!(check_integrity(&path).unwrap(), CheckOutcome::Unrecorded);
+
+        let record = IntegrityRecord {
+            hash: get_file_blake3_hash(&path).unwrap(),
+            received_at: 1_700_000_000,
+        };
+        match store_integrity(&path, &record) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                // The temp filesystem has no user extended attributes
+                fs::remove_file(&path).unwrap();
+                return;
+            }
+            Err(e) => panic!("Failed to store integrity metadata: {e}"),
+        }
+        assert_eq!(
+            check_integrity(&path).unwrap(),
+            CheckOutcome::Intact(record)
+        );
+
+        fs::write(&path, b"tampered content").unwrap();
+        assert!(matches!(
+            check_integrity(&path).unwrap(),
+            CheckOutcome::Modified { .. }
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Free-form metadata attached to a sent file, such as `ticket=1234` or `source=build-42`.
+//!
+//! Senders pass key/value pairs with `send --meta`, sent to receivers supporting
+//! [METADATA](crate::capabilities::Capabilities::METADATA) after the handshake. Receivers hand
+//! them to the [offer handler](crate::stream::offer::OfferInfo) and the scan command, and store
+//! them next to the received file as `user.sendfile.meta.*` extended attributes or in a JSON
+//! sidecar ([MetadataStore]), so external tracking systems can tie the file to its origin.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use thiserror::Error;
+
+use crate::file::integrity::set_attribute;
+
+/// Key/value metadata of a file, in key order.
+pub type Metadata = BTreeMap<String, String>;
+
+/// Most entries a file's metadata may have.
+pub const MAX_METADATA_ENTRIES: usize = 32;
+
+/// Most bytes of keys and values a file's metadata may have in total, so it fits the message
+/// following the handshake.
+pub const MAX_METADATA_SIZE: usize = 1024;
+
+/// Longest key, so it fits the name of an extended attribute.
+pub const MAX_KEY_LEN: usize = 64;
+
+/// Prefix of the extended attributes metadata is stored in, followed by the key.
+pub const METADATA_ATTRIBUTE_PREFIX: &str = "user.sendfile.meta.";
+
+/// Suffix appended to the path of a received file to name its metadata sidecar.
+pub const METADATA_SIDECAR_SUFFIX: &str = ".meta.json";
+
+/// Prefix of the environment variables metadata is passed to the scan command in, followed by
+/// the key in upper case.
+pub const METADATA_ENV_PREFIX: &str = "SENDFILE_META_";
+
+/// Errors that can occur with invalid metadata.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MetadataError {
+    /// A key is empty, too long or holds other characters than letters, digits, `_`, `-`, `.`.
+    #[error("Invalid metadata key {0:?}, use up to 64 letters, digits, '_', '-' or '.'")]
+    InvalidKey(String),
+    /// A value holds control characters, which would garble logs and sidecars.
+    #[error("Metadata value of {0:?} holds control characters")]
+    InvalidValue(String),
+    /// The metadata has too many entries or bytes.
+    #[error(
+        "Metadata too large, at most {MAX_METADATA_ENTRIES} entries and {MAX_METADATA_SIZE} bytes"
+    )]
+    TooLarge,
+}
+
+/// Where a receiver stores the metadata of received files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MetadataStore {
+    /// `user.sendfile.meta.<key>` extended attributes of the file.
+    Xattrs,
+    /// JSON object in a sidecar file next to it, see [metadata_sidecar_path].
+    Sidecar,
+}
+
+/// Checks that `metadata` can be sent and stored.
+pub fn check_metadata(metadata: &Metadata) -> Result<(), MetadataError> {
+    let size: usize = metadata.iter().map(|(k, v)| k.len() + v.len()).sum();
+    if metadata.len() > MAX_METADATA_ENTRIES || size > MAX_METADATA_SIZE {
+        return Err(MetadataError::TooLarge);
+    }
+    for (key, value) in metadata {
+        let valid_key = !key.is_empty()
+            && key.len() <= MAX_KEY_LEN
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid_key {
+            return Err(MetadataError::InvalidKey(key.clone()));
+        }
+        if value.chars().any(char::is_control) {
+            return Err(MetadataError::InvalidValue(key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Parses a `KEY=VALUE` metadata entry given on the command line.
+pub fn parse_metadata_entry(entry: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = entry.split_once('=') else {
+        return Err(format!("expected KEY=VALUE, got {entry:?}"));
+    };
+    let metadata = Metadata::from([(key.trim().to_string(), value.to_string())]);
+    check_metadata(&metadata).map_err(|e| e.to_string())?;
+    Ok(metadata.into_iter().next().expect("one entry"))
+}
+
+/// Returns where the metadata sidecar of the file at `path` is written.
+pub fn metadata_sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(METADATA_SIDECAR_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Returns the environment variables `metadata` is passed in, `SENDFILE_META_` followed by the
+/// key in upper case, `-` and `.` replaced with `_`.
+pub fn metadata_env(metadata: &Metadata) -> impl Iterator<Item = (String, &str)> {
+    metadata.iter().map(|(key, value)| {
+        let name: String = key
+            .chars()
+            .map(|c| match c {
+                '-' | '.' => '_',
+                c => c.to_ascii_uppercase(),
+            })
+            .collect();
+        (format!("{METADATA_ENV_PREFIX}{name}"), value.as_str())
+    })
+}
+
+/// Stores `metadata` for the received file at `path` in `store`.
+pub fn store_metadata(path: &Path, metadata: &Metadata, store: MetadataStore) -> io::Result<()> {
+    match store {
+        MetadataStore::Xattrs => metadata.iter().try_for_each(|(key, value)| {
+            set_attribute(
+                path,
+                &format!("{METADATA_ATTRIBUTE_PREFIX}{key}"),
+                value.as_bytes(),
+            )
+        }),
+        MetadataStore::Sidecar => {
+            let json = serde_json::to_vec_pretty(metadata).map_err(io::Error::other)?;
+            fs::write(metadata_sidecar_path(path), json)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_entries_are_checked() {
+        assert_eq!(
+            parse_metadata_entry("ticket=1234"),
+            Ok((String::from("ticket"), String::from("1234")))
+        );
+        assert_eq!(
+            parse_metadata_entry("build.url=https://ci/42?a=b"),
+            Ok((String::from("build.url"), String::from("https://ci/42?a=b")))
+        );
+        assert!(parse_metadata_entry("ticket").is_err());
+        assert!(parse_metadata_entry("=1234").is_err());
+        assert!(parse_metadata_entry("a b=c").is_err());
+        assert!(parse_metadata_entry("note=two\nlines").is_err());
+
+        let large: Metadata = (0..MAX_METADATA_ENTRIES + 1)
+            .map(|i| (format!("k{i}"), String::new()))
+            .collect();
+        assert_eq!(check_metadata(&large), Err(MetadataError::TooLarge));
+        let long = Metadata::from([(String::from("k"), "v".repeat(MAX_METADATA_SIZE))]);
+        assert_eq!(check_metadata(&long), Err(MetadataError::TooLarge));
+    }
+
+    #[test]
+    fn test_metadata_env_names() {
+        let metadata = Metadata::from([
+            (String::from("build.id"), String::from("42")),
+            (String::from("ticket"), String::from("1234")),
+        ]);
+        assert_eq!(
+            metadata_env(&metadata).collect::<Vec<_>>(),
+            [
+                (String::from("SENDFILE_META_BUILD_ID"), "42"),
+                (String::from("SENDFILE_META_TICKET"), "1234"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata_sidecar() {
+        let path = std::env::temp_dir().join(format!("sendfile_meta_{}", std::process::id()));
+        let metadata = Metadata::from([(String::from("ticket"), String::from("1234"))]);
+        store_metadata(&path, &metadata, MetadataStore::Sidecar).unwrap();
+        let sidecar = metadata_sidecar_path(&path);
+        let stored: Metadata = serde_json::from_slice(&fs::read(&sidecar).unwrap()).unwrap();
+        assert_eq!(stored, metadata);
+        fs::remove_file(&sidecar).unwrap();
+    }
+}
@@ -1,8 +1,20 @@
 use log::debug;
 
-use crate::file::error::GetFileMetadataError;
-
+use crate::{
+    file::{error::GetFileMetadataError, utils::HashStrategy},
+    threads::WorkerOptions,
+};
+
+pub mod buffer;
+pub mod content_type;
+pub mod device;
+pub mod duplicate;
 pub mod error;
+pub mod index;
+pub mod integrity;
+pub mod metadata;
+pub mod resume;
+pub mod store;
 pub mod utils;
 
 #[derive(Debug)]
@@ -37,6 +49,16 @@ impl FileMetadata {
     ///
     /// A `Result` containing the `FileMetadata` or an `io::Error`.
     pub fn from_file(path: &std::path::Path) -> Result<Self, GetFileMetadataError> {
+        Self::from_file_with(path, HashStrategy::Parallel, &WorkerOptions::default())
+    }
+
+    /// Creates a `FileMetadata` instance from a file path, hashing the content with the given
+    /// [HashStrategy] and hashing workers.
+    pub fn from_file_with(
+        path: &std::path::Path,
+        strategy: HashStrategy,
+        workers: &WorkerOptions,
+    ) -> Result<Self, GetFileMetadataError> {
         let filename = path
             .file_name()
             .and_then(|name| name.to_str())
@@ -44,10 +66,10 @@ impl FileMetadata {
             .to_string();
         debug!("Calculating metadata for file: {:?}", path);
 
-        let filesize = std::fs::metadata(path)?.len();
+        let filesize = device::file_size(path)?;
         debug!("File size: {} bytes", filesize);
 
-        let filehash = utils::get_file_blake3_hash(path)?;
+        let filehash = utils::get_file_blake3_hash_with(path, strategy, workers)?;
         debug!("File hash (BLAKE3): {:x?}", filehash);
 
         Ok(Self {
@@ -0,0 +1,168 @@
+//! Progress of an interrupted receive, persisted next to the output file.
+//!
+//! When a transfer ends before every block was received, the receiver writes the byte ranges it
+//! already has to `<file>.sendfile-resume`. Ranges are stored in bytes rather than blocks, so a
+//! resumed transfer can map them onto a different block size: a new block counts as received
+//! only if it lies entirely within received ranges.
+
+use std::{
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::history::to_hex;
+
+/// Suffix appended to the output file's name to name its resume state.
+pub const RESUME_STATE_SUFFIX: &str = ".sendfile-resume";
+
+/// Reasons a resume state can't be applied to an offered file.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ResumeError {
+    /// The state was recorded for a file of another size.
+    #[error("resume state is for a {recorded} byte file, the offered file has {offered} bytes")]
+    SizeMismatch { recorded: u64, offered: u64 },
+    /// A recorded range lies past the end of the file.
+    #[error("resume state records bytes {0:?}, past the end of the file")]
+    OutOfBounds(Range<u64>),
+}
+
+/// Byte ranges of a file received before the transfer was interrupted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState {
+    /// Hex encoded BLAKE3 hash of the file being received.
+    pub file_hash: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Block size of the interrupted transfer.
+    pub block_size: u32,
+    /// Received byte ranges, in file order and not adjacent to each other.
+    pub received: Vec<Range<u64>>,
+}
+
+impl ResumeState {
+    /// Records which blocks of a file of `size` bytes were received.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_hash` - BLAKE3 hash of the file.
+    /// * `size` - Size of the file in bytes.
+    /// * `block_size` - Size of a block in bytes.
+    /// * `received` - Whether each block, in order, was received.
+    pub fn new(
+        file_hash: &[u8; 32],
+        size: u64,
+        block_size: u32,
+        received: impl IntoIterator<Item = bool>,
+    ) -> Self {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for (seq, _) in received.into_iter().enumerate().filter(|(_, r)| *r) {
+            let start = (seq as u64 * block_size as u64).min(size);
+            let end = (start + block_size as u64).min(size);
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+        }
+
+        Self {
+            file_hash: to_hex(file_hash),
+            size,
+            block_size,
+            received: ranges,
+        }
+    }
+
+    /// Returns whether the state was recorded for the file with `file_hash`.
+    pub fn is_for(&self, file_hash: &[u8; 32]) -> bool {
+        self.file_hash == to_hex(file_hash)
+    }
+
+    /// Returns whether each block of `block_size` bytes of a `size` byte file was received.
+    ///
+    /// Blocks only partly covered by the recorded ranges, which happens when the block size
+    /// changed, count as missing.
+    pub fn received_blocks(&self, size: u64, block_size: u32) -> Result<Vec<bool>, ResumeError> {
+        if size != self.size {
+            return Err(ResumeError::SizeMismatch {
+                recorded: self.size,
+                offered: size,
+            });
+        }
+        if let Some(range) = self.received.iter().find(|range| range.end > size) {
+            return Err(ResumeError::OutOfBounds(range.clone()));
+        }
+
+        let total_blocks = size.div_ceil(block_size as u64);
+        Ok((0..total_blocks)
+            .map(|seq| {
+                let start = seq * block_size as u64;
+                let end = (start + block_size as u64).min(size);
+                self.received
+                    .iter()
+                    .any(|range| range.start <= start && end <= range.end)
+            })
+            .collect())
+    }
+
+    /// Reads the state at `path`, `None` if there is none.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the state to `path`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)
+    }
+}
+
+/// Returns where the resume state of the output file at `path` is kept.
+pub fn resume_state_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(RESUME_STATE_SUFFIX);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_state_maps_block_sizes() {
+        // Blocks 0, 1 and 3 of 1000 bytes received, of a 4500 byte file
+        let state = ResumeState::new(&[1; 32], 4500, 1000, [true, true, false, true, false]);
+        assert_eq!(state.received, vec![0..2000, 3000..4000]);
+        assert!(state.is_for(&[1; 32]));
+        assert!(!state.is_for(&[2; 32]));
+
+        assert_eq!(
+            state.received_blocks(4500, 1000).unwrap(),
+            vec![true, true, false, true, false]
+        );
+        // 1500 byte blocks: only the first lies within a received range
+        assert_eq!(
+            state.received_blocks(4500, 1500).unwrap(),
+            vec![true, false, false]
+        );
+        // 500 byte blocks split the received ones
+        assert_eq!(
+            state.received_blocks(4500, 500).unwrap(),
+            vec![true, true, true, true, false, false, true, true, false]
+        );
+
+        assert_eq!(
+            state.received_blocks(9000, 1000),
+            Err(ResumeError::SizeMismatch {
+                recorded: 4500,
+                offered: 9000
+            })
+        );
+    }
+}
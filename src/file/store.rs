@@ -0,0 +1,378 @@
+//! Content-addressed store of file blocks, shared across transfers.
+//!
+//! Each block is kept in a file named after the hex BLAKE3 hash of its content, fanned out into
+//! 256 sub-directories by the first byte of the hash (`ab/abcdef...`). A receiver with a store
+//! copies blocks it already has from disk instead of downloading them again.
+//!
+//! The modification time of a block is refreshed whenever it is reused or added again, so
+//! [BlockStore::gc] can evict the least recently used blocks once the store grows past its size
+//! cap. A store opened [with a cap](BlockStore::with_max_size) enforces it as blocks are added.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, warn};
+
+use crate::history::to_hex;
+
+/// Default size cap of the block store (10 GiB).
+pub const DEFAULT_MAX_STORE_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Age after which a leftover temporary file is considered abandoned and removed by
+/// [BlockStore::gc].
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Most bytes added to a store with a size cap before it is enforced again (256 MiB), see
+/// [BlockStore::with_max_size].
+const MAX_GC_INTERVAL: u64 = 256 * 1024 * 1024;
+
+/// Counter making temporary file names unique within the process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A directory of blocks indexed by their BLAKE3 hash.
+#[derive(Debug, Clone)]
+pub struct BlockStore {
+    root: PathBuf,
+    /// Size cap enforced as blocks are added, see [BlockStore::with_max_size].
+    max_size: Option<u64>,
+    /// Bytes added since the cap was last enforced, shared by clones of the store.
+    added_since_gc: Arc<AtomicU64>,
+}
+
+impl BlockStore {
+    /// Opens the store rooted at `root`, creating the directory if needed.
+    pub fn open(root: &Path) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            max_size: None,
+            added_since_gc: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Enforces a size cap of `max_size` bytes while blocks are added: [BlockStore::put] runs
+    /// [BlockStore::gc] every eighth of the cap (at most every 256 MiB) added, so the store never
+    /// grows past the cap by more than that.
+    pub fn with_max_size(self, max_size: u64) -> Self {
+        Self {
+            max_size: Some(max_size),
+            ..self
+        }
+    }
+
+    /// Returns the root directory of the store.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the content of the block with the given hash, if the store has it.
+    ///
+    /// The content is re-hashed before being returned, a corrupt entry is removed and reported as
+    /// missing.
+    pub fn get(&self, hash: &[u8; 32]) -> io::Result<Option<Vec<u8>>> {
+        let path = self.path_for(hash);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        if blake3::hash(&data).as_bytes() != hash {
+            warn!("Removing corrupt block {:?} from the block store", path);
+            let _ = fs::remove_file(&path);
+            return Ok(None);
+        }
+
+        touch(&path);
+        Ok(Some(data))
+    }
+
+    /// Adds a block to the store, returning its hash. Storing a block that is already present
+    /// only marks it as recently used.
+    ///
+    /// Blocks are written to a temporary file and renamed into place, so concurrent readers never
+    /// observe a partially written block. The size cap of a store opened
+    /// [with one](BlockStore::with_max_size) is enforced along the way.
+    pub fn put(&self, data: &[u8]) -> io::Result<[u8; 32]> {
+        let hash = *blake3::hash(data).as_bytes();
+        let path = self.path_for(&hash);
+        if path.exists() {
+            touch(&path);
+            return Ok(hash);
+        }
+
+        let dir = path.parent().expect("block path always has a parent");
+        fs::create_dir_all(dir)?;
+
+        let temp_path = dir.join(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = fs::File::create(&temp_path)
+            .and_then(|mut file| file.write_all(data))
+            .and_then(|_| fs::rename(&temp_path, &path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result?;
+
+        if let Some(max_size) = self.max_size {
+            let interval = (max_size / 8).clamp(1, MAX_GC_INTERVAL);
+            let added = self
+                .added_since_gc
+                .fetch_add(data.len() as u64, Ordering::Relaxed)
+                + data.len() as u64;
+            // Only the put crossing the interval collects, the others keep adding blocks
+            if added >= interval && self.added_since_gc.swap(0, Ordering::Relaxed) >= interval {
+                let evicted = self.gc(max_size)?;
+                if evicted.blocks > 0 {
+                    debug!(
+                        "Evicted {} blocks ({} bytes) from the block store",
+                        evicted.blocks, evicted.bytes
+                    );
+                }
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Returns the number of blocks in the store and their total size.
+    pub fn stats(&self) -> io::Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        for entry in self.entries()? {
+            stats.blocks += 1;
+            stats.bytes += entry.size;
+        }
+        Ok(stats)
+    }
+
+    /// Evicts the least recently used blocks until the store is at most `max_size` bytes, and
+    /// removes temporary files abandoned by interrupted writes.
+    ///
+    /// Returns the number of blocks removed and the bytes freed.
+    pub fn gc(&self, max_size: u64) -> io::Result<StoreStats> {
+        let mut removed = StoreStats::default();
+
+        for dir in self.shard_dirs()? {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let is_temp = entry.file_name().to_string_lossy().ends_with(".tmp");
+                let is_stale = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age >= STALE_TEMP_FILE_AGE);
+                if is_temp && is_stale {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+        entries.sort_by_key(|entry| entry.last_used);
+
+        for entry in entries {
+            if total <= max_size {
+                break;
+            }
+            match fs::remove_file(&entry.path) {
+                Ok(()) => {
+                    total -= entry.size;
+                    removed.blocks += 1;
+                    removed.bytes += entry.size;
+                }
+                // Already evicted by a concurrent gc
+                Err(e) if e.kind() == io::ErrorKind::NotFound => total -= entry.size,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes every block from the store, returning the number of blocks removed and the bytes
+    /// freed.
+    pub fn clear(&self) -> io::Result<StoreStats> {
+        self.gc(0)
+    }
+
+    /// Returns every block in the store.
+    fn entries(&self) -> io::Result<Vec<StoreEntry>> {
+        let mut entries = Vec::new();
+        for dir in self.shard_dirs()? {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    entries.push(StoreEntry {
+                        path: entry.path(),
+                        size: metadata.len(),
+                        last_used: metadata.modified()?,
+                    });
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the fan-out sub-directories of the store.
+    fn shard_dirs(&self) -> io::Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Returns the path of the file holding the block with the given hash.
+    fn path_for(&self, hash: &[u8; 32]) -> PathBuf {
+        let hex = to_hex(hash);
+        self.root.join(&hex[..2]).join(hex)
+    }
+}
+
+/// Number of blocks and their total size, for a whole store or a subset of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of blocks.
+    pub blocks: u64,
+    /// Total size of the blocks in bytes.
+    pub bytes: u64,
+}
+
+/// A block on disk, as seen by [BlockStore::gc].
+struct StoreEntry {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+/// Marks the block at `path` as recently used for LRU eviction, failing to do so is harmless.
+fn touch(path: &Path) {
+    let _ = fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+}
+
+/// Returns the default location of the block store, if a cache directory is known.
+pub fn default_block_store_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("sendfile").join("blocks"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put_get_and_corruption() {
+        let root = std::env::temp_dir().join(format!("sendfile_store_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = BlockStore::open(&root).unwrap();
+
+        let hash = store.put(b"hello block").unwrap();
+        assert_eq!(hash, *blake3::hash(b"hello block").as_bytes());
+        assert_eq!(store.put(b"hello block").unwrap(), hash);
+        assert_eq!(
+            store.get(&hash).unwrap().as_deref(),
+            Some(&b"hello block"[..])
+        );
+        assert_eq!(store.get(&[0u8; 32]).unwrap(), None);
+
+        fs::write(store.path_for(&hash), b"tampered").unwrap();
+        assert_eq!(store.get(&hash).unwrap(), None);
+        assert!(!store.path_for(&hash).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_gc_evicts_least_recently_used() {
+        let root = std::env::temp_dir().join(format!("sendfile_store_gc_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = BlockStore::open(&root).unwrap();
+
+        let old = store.put(&[1u8; 100]).unwrap();
+        let new = store.put(&[2u8; 100]).unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(store.path_for(&old))
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+
+        assert_eq!(
+            store.stats().unwrap(),
+            StoreStats {
+                blocks: 2,
+                bytes: 200
+            }
+        );
+        assert_eq!(
+            store.gc(150).unwrap(),
+            StoreStats {
+                blocks: 1,
+                bytes: 100
+            }
+        );
+        assert_eq!(store.get(&old).unwrap(), None);
+        assert!(store.get(&new).unwrap().is_some());
+
+        assert_eq!(
+            store.clear().unwrap(),
+            StoreStats {
+                blocks: 1,
+                bytes: 100
+            }
+        );
+        assert_eq!(store.stats().unwrap(), StoreStats::default());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_put_refreshes_and_enforces_cap() {
+        let root = std::env::temp_dir().join(format!("sendfile_store_cap_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = BlockStore::open(&root).unwrap().with_max_size(250);
+
+        let deduplicated = store.put(&[1u8; 100]).unwrap();
+        let stale = store.put(&[2u8; 100]).unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for hash in [&deduplicated, &stale] {
+            fs::File::options()
+                .write(true)
+                .open(store.path_for(hash))
+                .unwrap()
+                .set_modified(an_hour_ago)
+                .unwrap();
+        }
+        // Added again, so no longer the least recently used
+        store.put(&[1u8; 100]).unwrap();
+        store.put(&[3u8; 100]).unwrap();
+
+        assert!(store.stats().unwrap().bytes <= 250);
+        assert!(store.get(&deduplicated).unwrap().is_some());
+        assert_eq!(store.get(&stale).unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
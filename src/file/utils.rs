@@ -1,60 +1,100 @@
 //! Utility functions for file handling, such as calculating the BLAKE3 hash of a file.
+use crate::file::buffer::AlignedBuffer;
+use crate::file::device::file_size;
 use crate::file::error::FileHashError;
+use crate::threads::{thread_name, WorkerOptions};
 use crate::transport::MAX_BLOCK_SIZE;
 use blake3::Hasher;
-use std::fs::File;
+use std::fs::{File, TryLockError};
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::thread;
 
 const PARALLEL_CHUNK_SIZE: u64 = 8 * 1024 * 1024; // 8 MB per chunk for parallel hashing
 
+/// Strategy used to read the file while calculating its BLAKE3 hash.
+///
+/// Both strategies produce the same hash, so the sender and receiver may pick independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashStrategy {
+    /// Hash fixed-size chunks concurrently, each thread seeking to its own chunk.
+    #[default]
+    Parallel,
+    /// Read the file front-to-back in large chunks from a single thread. Slower on local disks,
+    /// but avoids many concurrent positioned reads on network filesystems.
+    Sequential,
+}
+
 /// Calculates the BLAKE3 hash of a file at the given path using parallel hashing.
 pub fn get_file_blake3_hash(file_path: &std::path::Path) -> Result<[u8; 32], FileHashError> {
-    let metadata = std::fs::metadata(file_path)?;
-    let file_size = metadata.len();
+    get_file_blake3_hash_with(file_path, HashStrategy::Parallel, &WorkerOptions::default())
+}
+
+/// Calculates the BLAKE3 hash of a file at the given path using the given [HashStrategy].
+///
+/// With [HashStrategy::Parallel], `workers` controls how many hashing threads are spawned and
+/// which CPUs they are pinned to.
+pub fn get_file_blake3_hash_with(
+    file_path: &std::path::Path,
+    strategy: HashStrategy,
+    workers: &WorkerOptions,
+) -> Result<[u8; 32], FileHashError> {
+    let file_size = file_size(file_path)?;
 
     if file_size <= PARALLEL_CHUNK_SIZE {
         return Ok(hash_sequential(file_path)?);
     }
 
+    if strategy == HashStrategy::Sequential {
+        return hash_chunks_sequential(file_path, file_size);
+    }
+
     let num_chunks = file_size.div_ceil(PARALLEL_CHUNK_SIZE);
-    let _num_threads = thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(1)
-        .min(num_chunks as usize)
-        .max(1);
-
-    let chunk_handles: Vec<_> = (0..num_chunks)
-        .map(|chunk_idx| {
-            let path = file_path.to_path_buf();
-            let start = chunk_idx * PARALLEL_CHUNK_SIZE;
-            let end = ((chunk_idx + 1) * PARALLEL_CHUNK_SIZE).min(file_size);
-
-            thread::spawn(move || {
-                let mut file = File::open(&path)?;
-                file.seek(SeekFrom::Start(start))?;
-                let chunk_size = (end - start) as usize;
-                let mut buffer = vec![0u8; chunk_size];
-                file.read_exact(&mut buffer)?;
-                let mut hasher = Hasher::new();
-                hasher.update(&buffer);
-                Ok::<_, std::io::Error>(hasher.finalize())
+    let num_threads = workers.thread_count().min(num_chunks as usize);
+
+    // Each worker hashes every `num_threads`-th chunk, starting at its own index
+    let mut chunk_hashes: Vec<Option<blake3::Hash>> = vec![None; num_chunks as usize];
+    thread::scope(|scope| {
+        let worker_handles = (0..num_threads)
+            .map(|worker_index| {
+                thread::Builder::new()
+                    .name(thread_name("hash", worker_index))
+                    .spawn_scoped(scope, move || {
+                        workers.pin_current_thread(worker_index);
+                        hash_chunks_strided(
+                            file_path,
+                            file_size,
+                            worker_index as u64,
+                            num_threads as u64,
+                        )
+                    })
             })
-        })
-        .collect();
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+        for (worker_index, handle) in worker_handles.into_iter().enumerate() {
+            let hashes = match handle.join() {
+                Err(_) => {
+                    return Err(FileHashError::ThreadJoinError {
+                        chunk_index: worker_index,
+                    })
+                }
+                Ok(Err((chunk_index, source))) => {
+                    return Err(FileHashError::ChunkHashError {
+                        chunk_index: chunk_index as usize,
+                        source,
+                    })
+                }
+                Ok(Ok(hashes)) => hashes,
+            };
+            for (chunk_index, hash) in hashes {
+                chunk_hashes[chunk_index as usize] = Some(hash);
+            }
+        }
+
+        Ok(())
+    })?;
 
     let mut final_hasher = Hasher::new();
-    for (chunk_index, handle) in chunk_handles.into_iter().enumerate() {
-        let chunk_hash = match handle.join() {
-            Err(_) => return Err(FileHashError::ThreadJoinError { chunk_index }),
-            Ok(Err(e)) => {
-                return Err(FileHashError::ChunkHashError {
-                    chunk_index,
-                    source: e,
-                })
-            }
-            Ok(Ok(hash)) => hash,
-        };
+    for chunk_hash in chunk_hashes.iter().flatten() {
         final_hasher.update(chunk_hash.as_bytes());
     }
 
@@ -64,12 +104,66 @@ pub fn get_file_blake3_hash(file_path: &std::path::Path) -> Result<[u8; 32], Fil
     Ok(hash_array)
 }
 
+/// Hashes the chunks `first, first + stride, ...` of the file, returning `(chunk_index, hash)`
+/// pairs, or the failing chunk index with its error.
+fn hash_chunks_strided(
+    file_path: &std::path::Path,
+    file_size: u64,
+    first: u64,
+    stride: u64,
+) -> Result<Vec<(u64, blake3::Hash)>, (u64, std::io::Error)> {
+    let num_chunks = file_size.div_ceil(PARALLEL_CHUNK_SIZE);
+    let mut file = File::open(file_path).map_err(|e| (first, e))?;
+    let mut buffer = AlignedBuffer::zeroed(PARALLEL_CHUNK_SIZE as usize);
+    let mut hashes = Vec::new();
+
+    for chunk_idx in (first..num_chunks).step_by(stride as usize) {
+        let start = chunk_idx * PARALLEL_CHUNK_SIZE;
+        let chunk_size = (file_size - start).min(PARALLEL_CHUNK_SIZE) as usize;
+        file.seek(SeekFrom::Start(start))
+            .and_then(|_| file.read_exact(&mut buffer[..chunk_size]))
+            .map_err(|e| (chunk_idx, e))?;
+
+        let mut hasher = Hasher::new();
+        hasher.update(&buffer[..chunk_size]);
+        hashes.push((chunk_idx, hasher.finalize()));
+    }
+
+    Ok(hashes)
+}
+
+/// Computes the same chunked hash as the parallel path, reading one chunk at a time in order.
+fn hash_chunks_sequential(
+    file_path: &std::path::Path,
+    file_size: u64,
+) -> Result<[u8; 32], FileHashError> {
+    let mut file = File::open(file_path)?;
+    let mut buffer = AlignedBuffer::zeroed(PARALLEL_CHUNK_SIZE as usize);
+    let mut final_hasher = Hasher::new();
+
+    for chunk_index in 0..file_size.div_ceil(PARALLEL_CHUNK_SIZE) as usize {
+        let start = chunk_index as u64 * PARALLEL_CHUNK_SIZE;
+        let chunk_size = (file_size - start).min(PARALLEL_CHUNK_SIZE) as usize;
+        file.read_exact(&mut buffer[..chunk_size])
+            .map_err(|source| FileHashError::ChunkHashError {
+                chunk_index,
+                source,
+            })?;
+
+        let mut hasher = Hasher::new();
+        hasher.update(&buffer[..chunk_size]);
+        final_hasher.update(hasher.finalize().as_bytes());
+    }
+
+    Ok(final_hasher.finalize().into())
+}
+
 fn hash_sequential(file_path: &std::path::Path) -> Result<[u8; 32], std::io::Error> {
     let file = File::open(file_path)?;
     let mut reader = BufReader::new(file);
     let mut hasher = Hasher::new();
 
-    let mut buffer = vec![0u8; MAX_BLOCK_SIZE as usize];
+    let mut buffer = AlignedBuffer::zeroed(MAX_BLOCK_SIZE as usize);
     loop {
         let bytes_read = reader.read(&mut buffer)?;
         if bytes_read == 0 {
@@ -124,6 +218,314 @@ pub fn write_file_block(
     Ok(())
 }
 
+/// Reads a specific block from the file with a positioned read, leaving the file cursor alone.
+///
+/// Unlike [read_file_block] this only needs a shared reference, so one handle can serve
+/// concurrent readers. Returns fewer than `block_size` bytes at the end of the file.
+pub fn read_file_block_at(
+    file: &File,
+    seq: u32,
+    block_size: u32,
+) -> Result<Vec<u8>, std::io::Error> {
+    let offset = seq as u64 * block_size as u64;
+    let mut buffer = vec![0u8; block_size as usize];
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        let read = read_at(file, &mut buffer[bytes_read..], offset + bytes_read as u64)?;
+        if read == 0 {
+            break;
+        }
+        bytes_read += read;
+    }
+    buffer.truncate(bytes_read);
+    Ok(buffer)
+}
+
+/// Writes a specific block to the file with a positioned write, leaving the file cursor alone.
+///
+/// Unlike [write_file_block] this only needs a shared reference, so one handle can serve
+/// concurrent writers of distinct blocks.
+pub fn write_file_block_at(
+    file: &File,
+    seq: u32,
+    block_size: u32,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    let offset = seq as u64 * block_size as u64;
+    let mut written = 0;
+    while written < data.len() {
+        let count = write_at(file, &data[written..], offset + written as u64)?;
+        if count == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero));
+        }
+        written += count;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+    std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> Result<usize, std::io::Error> {
+    std::os::unix::fs::FileExt::write_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> Result<usize, std::io::Error> {
+    std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> Result<usize, std::io::Error> {
+    std::os::windows::fs::FileExt::seek_write(file, data, offset)
+}
+
+/// Access pattern of a file range, announced to the kernel with [advise_file].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAdvice {
+    /// The range is read in order, so the kernel reads further ahead.
+    Sequential,
+    /// The range is read soon, so the kernel starts reading it into the page cache.
+    WillNeed,
+    /// The range is not accessed again, so the kernel may drop it from the page cache.
+    DontNeed,
+}
+
+/// Announces how `len` bytes of the file from `offset` will be accessed, `len` 0 reaching the
+/// end of the file.
+///
+/// Advice is only a hint: it does nothing on platforms without `posix_fadvise`, and callers may
+/// ignore its failure.
+#[cfg(target_os = "linux")]
+pub fn advise_file(
+    file: &File,
+    offset: u64,
+    len: u64,
+    advice: FileAdvice,
+) -> Result<(), std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let advice = match advice {
+        FileAdvice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        FileAdvice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        FileAdvice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    let (Ok(offset), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(len)) else {
+        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+    };
+    // SAFETY: the descriptor is owned by `file` for the duration of the call.
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), offset, len, advice) } {
+        0 => Ok(()),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise_file(
+    _file: &File,
+    _offset: u64,
+    _len: u64,
+    _advice: FileAdvice,
+) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// Writes `len` bytes of the file from `offset` out to disk, waiting for them, then drops them
+/// from the page cache.
+///
+/// Dirty pages can't be dropped, so unlike [advise_file] with [FileAdvice::DontNeed] this frees
+/// the cache taken by freshly written data. Does nothing on platforms without `posix_fadvise`.
+#[cfg(target_os = "linux")]
+pub fn evict_file_range(file: &File, offset: u64, len: u64) -> Result<(), std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let (Ok(start), Ok(count)) = (
+        libc::off64_t::try_from(offset),
+        libc::off64_t::try_from(len),
+    ) else {
+        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+    };
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    // SAFETY: the descriptor is owned by `file` for the duration of the call.
+    if unsafe { libc::sync_file_range(file.as_raw_fd(), start, count, flags) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    advise_file(file, offset, len, FileAdvice::DontNeed)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn evict_file_range(_file: &File, _offset: u64, _len: u64) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// Computes the BLAKE3 hash of an in-memory file the same way [get_file_blake3_hash] hashes a
+/// file on disk, so the two can be compared.
+pub fn get_bytes_blake3_hash(data: &[u8]) -> [u8; 32] {
+    if data.len() as u64 <= PARALLEL_CHUNK_SIZE {
+        return *blake3::hash(data).as_bytes();
+    }
+
+    let mut final_hasher = Hasher::new();
+    for chunk in data.chunks(PARALLEL_CHUNK_SIZE as usize) {
+        final_hasher.update(blake3::hash(chunk).as_bytes());
+    }
+    final_hasher.finalize().into()
+}
+
+/// Computes the hash of the first `len` bytes of `reader` the same way [get_file_blake3_hash]
+/// hashes a file, for sending content that is not a file on disk.
+///
+/// Fails with [std::io::ErrorKind::UnexpectedEof] if the reader holds fewer than `len` bytes.
+pub fn get_reader_blake3_hash<R: Read>(
+    reader: &mut R,
+    len: u64,
+) -> Result<[u8; 32], std::io::Error> {
+    if len <= PARALLEL_CHUNK_SIZE {
+        let mut data = Vec::with_capacity(len as usize);
+        reader.take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        return Ok(*blake3::hash(&data).as_bytes());
+    }
+
+    let mut buffer = AlignedBuffer::zeroed(PARALLEL_CHUNK_SIZE as usize);
+    let mut final_hasher = Hasher::new();
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk_size = remaining.min(PARALLEL_CHUNK_SIZE) as usize;
+        reader.read_exact(&mut buffer[..chunk_size])?;
+        final_hasher.update(blake3::hash(&buffer[..chunk_size]).as_bytes());
+        remaining -= chunk_size as u64;
+    }
+    Ok(final_hasher.finalize().into())
+}
+
+/// Takes an advisory (flock-style) lock on the file without blocking.
+///
+/// The sender takes a `shared` lock on the source so other readers are not disturbed, while the
+/// receiver takes an exclusive lock on the destination. Returns `Ok(false)` if a conflicting lock
+/// is already held by another process. The lock is released on [File::unlock] or when the handle
+/// is closed.
+pub fn try_lock_file(file: &File, shared: bool) -> Result<bool, std::io::Error> {
+    let result = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Returns `true` if the filesystem containing `path` is a known network filesystem
+/// (NFS, SMB/CIFS, ...).
+///
+/// Detection is best effort and always returns `false` on platforms where it isn't supported.
+/// If `path` does not exist yet, its parent directory is inspected instead.
+pub fn is_remote_filesystem(path: &std::path::Path) -> bool {
+    let existing = if path.exists() {
+        path
+    } else {
+        match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => std::path::Path::new("."),
+            Some(parent) => parent,
+            None => return false,
+        }
+    };
+
+    remote_filesystem_check(existing)
+}
+
+#[cfg(target_os = "linux")]
+fn remote_filesystem_check(path: &std::path::Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    const NFS_SUPER_MAGIC: i64 = 0x6969;
+    const SMB_SUPER_MAGIC: i64 = 0x517B;
+    const CIFS_SUPER_MAGIC: i64 = 0xFF53_4D42;
+    const SMB2_SUPER_MAGIC: i64 = 0xFE53_4D42;
+    const CODA_SUPER_MAGIC: i64 = 0x7375_7245;
+    const AFS_SUPER_MAGIC: i64 = 0x5346_414F;
+    const FUSE_SUPER_MAGIC: i64 = 0x6573_5546;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stats = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stats` is only read on success.
+    if unsafe { libc::statfs(c_path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    // `f_type` is a different integer type depending on the target architecture
+    #[allow(clippy::unnecessary_cast)]
+    let fs_type = unsafe { stats.assume_init() }.f_type as i64;
+
+    matches!(
+        fs_type,
+        NFS_SUPER_MAGIC
+            | SMB_SUPER_MAGIC
+            | CIFS_SUPER_MAGIC
+            | SMB2_SUPER_MAGIC
+            | CODA_SUPER_MAGIC
+            | AFS_SUPER_MAGIC
+            | FUSE_SUPER_MAGIC
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn remote_filesystem_check(_path: &std::path::Path) -> bool {
+    false
+}
+
+/// Returns the bytes unprivileged users can still write on the filesystem containing `path`, or
+/// `None` if it can't be told.
+///
+/// Always returns `None` on platforms where it isn't supported. If `path` does not exist yet, its
+/// nearest existing ancestor is inspected instead.
+pub fn available_space(path: &std::path::Path) -> Option<u64> {
+    let existing = path
+        .ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                std::path::Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists())?;
+    available_space_check(existing)
+}
+
+#[cfg(unix)]
+fn available_space_check(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stats` is only read on success.
+    if unsafe { libc::statvfs(c_path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stats = unsafe { stats.assume_init() };
+    // Field types differ between targets
+    #[allow(clippy::unnecessary_cast, clippy::useless_conversion)]
+    (stats.f_bavail as u64).checked_mul(stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space_check(_path: &std::path::Path) -> Option<u64> {
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -149,6 +551,37 @@ mod tests {
         assert_eq!(hash, expected_hash);
     }
 
+    #[test]
+    fn test_sequential_hash_matches_parallel() {
+        let temp_file_path = temp_dir().join("test_sequential_hash.txt");
+        let mut temp_file = File::create(&temp_file_path).expect("Failed to create temp file");
+
+        // Spans multiple hashing chunks, with a partial last chunk
+        let content: Vec<u8> = (0..(2 * PARALLEL_CHUNK_SIZE + 12345))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        temp_file
+            .write_all(&content)
+            .expect("Failed to write to temp file");
+
+        let workers = WorkerOptions {
+            threads: Some(2),
+            cpus: vec![],
+        };
+        let parallel = get_file_blake3_hash_with(&temp_file_path, HashStrategy::Parallel, &workers)
+            .expect("Failed to get parallel hash");
+        let sequential =
+            get_file_blake3_hash_with(&temp_file_path, HashStrategy::Sequential, &workers)
+                .expect("Failed to get sequential hash");
+
+        assert_eq!(parallel, sequential);
+        assert_eq!(get_bytes_blake3_hash(&content), parallel);
+        assert_eq!(
+            get_reader_blake3_hash(&mut content.as_slice(), content.len() as u64).unwrap(),
+            parallel
+        );
+    }
+
     #[test]
     fn test_read_file_block() {
         let temp_file_path = temp_dir().join("test_read_block.txt");
@@ -186,6 +619,7 @@ mod tests {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&temp_file_path)
             .expect("Failed to create temp file");
 
@@ -209,4 +643,56 @@ mod tests {
             read_file_block(&mut file, 2, 4).expect("Failed to read block 2 after truncate");
         assert_eq!(written, b"CC");
     }
+
+    #[test]
+    fn test_try_lock_file() {
+        let temp_file_path = temp_dir().join("test_lock_file.txt");
+        let file = File::create(&temp_file_path).expect("Failed to create temp file");
+        let other = File::open(&temp_file_path).expect("Failed to open temp file");
+
+        assert!(try_lock_file(&file, false).expect("Failed to lock file"));
+        assert!(!try_lock_file(&other, true).expect("Failed to try shared lock"));
+
+        file.unlock().expect("Failed to unlock file");
+        assert!(try_lock_file(&other, true).expect("Failed to take shared lock"));
+        assert!(!try_lock_file(&file, false).expect("Failed to try exclusive lock"));
+    }
+
+    #[test]
+    fn test_evict_file_range_keeps_content() {
+        let temp_file_path = temp_dir().join(format!("test_evict_{}.bin", std::process::id()));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_file_path)
+            .expect("Failed to create temp file");
+        file.write_all(&[9u8; 8192])
+            .expect("Failed to write temp file");
+
+        // Flushed and dropped from the cache, the blocks are read back from disk
+        evict_file_range(&file, 4096, 4096).expect("Failed to evict range");
+        evict_file_range(&file, 0, 0).expect("Failed to evict whole file");
+        let block = read_file_block(&mut file, 1, 4096).expect("Failed to read evicted block");
+        assert_eq!(block, vec![9u8; 4096]);
+
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            evict_file_range(&file, u64::MAX, 1).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        std::fs::remove_file(&temp_file_path).expect("Failed to remove temp file");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_available_space_of_missing_path() {
+        let space = available_space(&temp_dir()).expect("Failed to read free space");
+        let missing = temp_dir().join("missing_dir").join("file.bin");
+        // Inspected through the temporary directory, whose space may change in between
+        assert!(available_space(&missing).is_some());
+        assert!(space > 0);
+    }
 }
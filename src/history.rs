@@ -0,0 +1,321 @@
+//! Local history of completed transfers.
+//!
+//! Entries are kept in the `history` [store](crate::state) of the sendfile data directory
+//! (`~/.local/share/sendfile/state/history.jsonl` on Linux), in the order they were recorded.
+//! Receivers keep an index of the files they received in the `received` store next to it, one
+//! entry per path, to find an identical copy of an offered file, see
+//! [ReceiveOptions::reuse_duplicates](crate::stream::options::ReceiveOptions::reuse_duplicates).
+//!
+//! Earlier versions kept both as plain JSON lines files in the data directory itself, which
+//! [migrate_legacy_files] moves into the stores.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    state::{self, next_sequence_key, JsonFileStore, StateError, StateStore},
+    stream::bundle::BundleStats,
+    transport::ReceiptV1,
+};
+
+/// Name of the store of the history.
+pub const HISTORY_STORE_NAME: &str = "history";
+
+/// Name of the store of the index of received files.
+pub const RECEIVED_STORE_NAME: &str = "received";
+
+/// Names of the files earlier versions kept the history and the index in, inside the data
+/// directory, with the store each moves to.
+const LEGACY_FILES: [(&str, &str); 2] = [
+    ("history.jsonl", HISTORY_STORE_NAME),
+    ("received.jsonl", RECEIVED_STORE_NAME),
+];
+
+/// Suffix appended to the name of a legacy file once moved into its store.
+const MIGRATED_SUFFIX: &str = ".migrated";
+
+/// Errors that can occur while reading or writing the history.
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    /// The history file could not be read or written.
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// An entry could not be encoded or decoded.
+    #[error("Malformed history entry: {0}")]
+    Json(#[from] serde_json::Error),
+    /// The store could not be read or written.
+    #[error("State store error: {0}")]
+    State(#[from] StateError),
+}
+
+/// A completed transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Completion time, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Address of the peer.
+    pub peer: String,
+    /// Name of the transferred file.
+    pub file_name: String,
+    /// Hex encoded BLAKE3 hash of the file.
+    pub file_hash: String,
+    /// Size of the file in bytes.
+    pub bytes: u64,
+    /// Receipt returned by the receiver, if it sent a valid one.
+    pub receipt: Option<StoredReceipt>,
+    /// Bytes and time every file and top-level directory took, when a directory was sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<BundleStats>,
+}
+
+/// The parts of a [ReceiptV1] needed to re-verify it later, hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredReceipt {
+    /// Time of delivery stated by the receiver, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Ed25519 public key of the receiver.
+    pub receiver_key: String,
+    /// Ed25519 signature over the receipt.
+    pub signature: String,
+}
+
+impl From<&ReceiptV1> for StoredReceipt {
+    fn from(receipt: &ReceiptV1) -> Self {
+        Self {
+            timestamp: receipt.timestamp,
+            receiver_key: to_hex(&receipt.receiver_key),
+            signature: to_hex(&receipt.signature),
+        }
+    }
+}
+
+/// A file saved by a receiver.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceivedEntry {
+    /// Time the file was received or indexed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Hex encoded BLAKE3 hash of the file.
+    pub file_hash: String,
+    /// Size of the file in bytes.
+    pub bytes: u64,
+    /// Absolute path the file was saved to. It may have been moved or changed since.
+    pub path: PathBuf,
+}
+
+/// Returns the default location of the history store, if a data directory is known.
+pub fn default_history_path() -> Option<PathBuf> {
+    state::default_store_path(HISTORY_STORE_NAME)
+}
+
+/// Appends `entry` to the history store at `path`, creating it if needed.
+pub fn append_entry(path: &Path, entry: &HistoryEntry) -> Result<(), HistoryError> {
+    append_entry_to(&JsonFileStore::open(path)?, entry)
+}
+
+/// Appends `entry` to the history kept in `store`.
+pub fn append_entry_to(store: &dyn StateStore, entry: &HistoryEntry) -> Result<(), HistoryError> {
+    Ok(store.put_as(&next_sequence_key(store)?, entry)?)
+}
+
+/// Reads every entry of the history store at `path`, oldest first.
+///
+/// A missing store is an empty history.
+pub fn load_entries(path: &Path) -> Result<Vec<HistoryEntry>, HistoryError> {
+    let store = JsonFileStore::open(path)?;
+    Ok((&store as &dyn StateStore).values_as()?)
+}
+
+/// Returns the default location of the index of received files, if a data directory is known.
+pub fn default_received_path() -> Option<PathBuf> {
+    state::default_store_path(RECEIVED_STORE_NAME)
+}
+
+/// Records `entry` in the index of received files at `path`, creating it if needed. It
+/// replaces the entry of the same path, if any.
+pub fn record_received(path: &Path, entry: &ReceivedEntry) -> Result<(), HistoryError> {
+    record_received_in(&JsonFileStore::open(path)?, entry)
+}
+
+/// Records `entry` in the index of received files kept in `store`.
+pub fn record_received_in(
+    store: &dyn StateStore,
+    entry: &ReceivedEntry,
+) -> Result<(), HistoryError> {
+    Ok(store.put_as(&received_key(&entry.path), entry)?)
+}
+
+/// Removes the entry of the file at `file` from the index of received files kept in `store`,
+/// returning whether there was one.
+pub fn forget_received_in(store: &dyn StateStore, file: &Path) -> Result<bool, HistoryError> {
+    Ok(store.remove(&received_key(file))?)
+}
+
+/// Reads every entry of the index of received files at `path`, oldest first.
+///
+/// A missing index is an empty index.
+pub fn load_received(path: &Path) -> Result<Vec<ReceivedEntry>, HistoryError> {
+    load_received_from(&JsonFileStore::open(path)?)
+}
+
+/// Reads every entry of the index of received files kept in `store`, oldest first.
+pub fn load_received_from(store: &dyn StateStore) -> Result<Vec<ReceivedEntry>, HistoryError> {
+    let mut entries: Vec<ReceivedEntry> = store.values_as()?;
+    entries.sort_by_key(|entry| entry.timestamp);
+    Ok(entries)
+}
+
+/// Returns the key of the entry of the file at `path` in the index of received files.
+fn received_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Moves the history and the index of received files that earlier versions kept in the data
+/// directory `data_dir` into their stores. Each moved file is renamed with a `.migrated`
+/// suffix, so it is moved only once.
+///
+/// # Returns
+///
+/// The number of entries moved, or a `HistoryError` if a file can't be read or a store can't be
+/// written.
+pub fn migrate_legacy_files(data_dir: &Path) -> Result<usize, HistoryError> {
+    let state_dir = state::state_dir(data_dir);
+    let mut moved = 0;
+    for (file_name, store_name) in LEGACY_FILES {
+        let legacy_path = data_dir.join(file_name);
+        if !legacy_path.is_file() {
+            continue;
+        }
+
+        let store = JsonFileStore::open(&state::store_path(&state_dir, store_name))?;
+        if store_name == HISTORY_STORE_NAME {
+            let entries: Vec<HistoryEntry> = load_lines(&legacy_path)?;
+            moved += entries.len();
+            for entry in &entries {
+                append_entry_to(&store, entry)?;
+            }
+        } else {
+            let entries: Vec<ReceivedEntry> = load_lines(&legacy_path)?;
+            moved += entries.len();
+            for entry in &entries {
+                record_received_in(&store, entry)?;
+            }
+        }
+
+        let mut migrated = legacy_path.as_os_str().to_owned();
+        migrated.push(MIGRATED_SUFFIX);
+        fs::rename(&legacy_path, migrated)?;
+    }
+    Ok(moved)
+}
+
+fn load_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, HistoryError> {
+    let file = fs::File::open(path)?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Encodes `bytes` as lowercase hex.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes a hex string, `None` if it has an odd length or a non-hex digit.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_load() {
+        let dir = std::env::temp_dir().join(format!("sendfile_history_{}", std::process::id()));
+        let path = state::store_path(&dir, HISTORY_STORE_NAME);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(load_entries(&path).unwrap().is_empty());
+
+        let entry = HistoryEntry {
+            timestamp: 1_700_000_000,
+            peer: String::from("127.0.0.1:7878"),
+            file_name: String::from("report.pdf"),
+            file_hash: to_hex(&[0xAB; 32]),
+            bytes: 4096,
+            receipt: None,
+            bundle: None,
+        };
+        append_entry(&path, &entry).unwrap();
+        append_entry(&path, &entry).unwrap();
+
+        assert_eq!(load_entries(&path).unwrap(), vec![entry.clone(), entry]);
+
+        // Entries of single files have no breakdown per file
+        let json = serde_json::to_value(load_entries(&path).unwrap()[0].clone()).unwrap();
+        assert!(json.get("bundle").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_legacy_files() {
+        let dir = std::env::temp_dir().join(format!("sendfile_legacy_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let received = |timestamp, path: &str| ReceivedEntry {
+            timestamp,
+            file_hash: to_hex(&[0x11; 32]),
+            bytes: 10,
+            path: PathBuf::from(path),
+        };
+        // An older entry for the same path is replaced by the newer one
+        let lines = [
+            received(1, "/data/a"),
+            received(2, "/data/b"),
+            received(3, "/data/a"),
+        ]
+        .iter()
+        .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+        .collect::<String>();
+        fs::write(dir.join("received.jsonl"), lines).unwrap();
+
+        assert_eq!(migrate_legacy_files(&dir).unwrap(), 3);
+        assert!(!dir.join("received.jsonl").exists());
+        assert!(dir.join("received.jsonl.migrated").exists());
+        let index = state::store_path(&state::state_dir(&dir), RECEIVED_STORE_NAME);
+        assert_eq!(
+            load_received(&index).unwrap(),
+            vec![received(2, "/data/b"), received(3, "/data/a")]
+        );
+        assert_eq!(migrate_legacy_files(&dir).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xff]), "000fff");
+        assert_eq!(from_hex("000fFF"), Some(vec![0x00, 0x0f, 0xff]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
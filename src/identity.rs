@@ -0,0 +1,152 @@
+//! Long-lived Ed25519 identity of this host, used to sign delivery receipts.
+//!
+//! Its X25519 form is also the static key of Noise channels (see [crate::noise]), so a peer
+//! authenticated by its identity key is the peer at the other end of the channel.
+//!
+//! The secret key is a 32 byte seed stored in the sendfile config directory
+//! (`~/.config/sendfile/identity.key` on Linux). It is generated on first use, and only held in
+//! memory as a [SecretKey].
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::secrets::{Secret, SecretKey};
+
+/// Name of the identity key file inside the config directory.
+const IDENTITY_FILE_NAME: &str = "identity.key";
+
+/// Errors that can occur while loading or creating an identity.
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    /// The key file could not be read or written.
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The key file does not contain a 32 byte seed.
+    #[error("Identity key {0:?} is corrupt")]
+    Corrupt(PathBuf),
+    /// The OS random number generator failed.
+    #[error("Failed to generate identity key: {0}")]
+    Random(String),
+    /// No config directory could be determined for this user.
+    #[error("No config directory available for the identity key")]
+    NoConfigDir,
+}
+
+/// Ed25519 key pair identifying this host.
+pub struct Identity {
+    /// Seed of the signing key, which is derived again for every use rather than kept.
+    seed: SecretKey,
+}
+
+impl Identity {
+    /// Loads the identity from [default_identity_path], generating it on first use.
+    pub fn load_default() -> Result<Self, IdentityError> {
+        let path = default_identity_path().ok_or(IdentityError::NoConfigDir)?;
+        Self::load_or_generate(&path)
+    }
+
+    /// Loads the identity stored at `path`, or generates and stores a new one if the file does
+    /// not exist. New key files are only readable by the current user.
+    pub fn load_or_generate(path: &Path) -> Result<Self, IdentityError> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let bytes = Secret::new(bytes);
+                let seed = SecretKey::from_slice(bytes.expose())
+                    .ok_or_else(|| IdentityError::Corrupt(path.to_path_buf()))?;
+                Ok(Self { seed })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let seed = SecretKey::random().map_err(|e| IdentityError::Random(e.to_string()))?;
+
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options.open(path)?.write_all(seed.expose())?;
+
+                Ok(Self { seed })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Creates an identity from a 32 byte secret seed.
+    pub fn from_seed(mut seed: [u8; 32]) -> Self {
+        let identity = Self {
+            seed: SecretKey::new(seed),
+        };
+        seed.zeroize();
+        identity
+    }
+
+    /// Returns the public key that identifies this host to peers.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key().verifying_key().to_bytes()
+    }
+
+    /// Signs `message`, returning the 64 byte Ed25519 signature.
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.signing_key().sign(message).to_bytes()
+    }
+
+    /// Returns the X25519 private key of this identity, whose public key is the
+    /// [x25519_public_key] of [Self::public_key].
+    pub fn x25519_private_key(&self) -> SecretKey {
+        let mut scalar = self.signing_key().to_scalar_bytes();
+        let key = SecretKey::new(scalar);
+        scalar.zeroize();
+        key
+    }
+
+    /// Returns the signing key of the seed, wiped when dropped.
+    fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(self.seed.expose())
+    }
+}
+
+/// Converts the identity public key `key` to its X25519 form, `None` if it is not a valid
+/// Ed25519 key.
+pub fn x25519_public_key(key: &[u8; 32]) -> Option<[u8; 32]> {
+    VerifyingKey::from_bytes(key)
+        .ok()
+        .map(|key| key.to_montgomery().to_bytes())
+}
+
+/// Returns the default location of the identity key, if a config directory is known.
+pub fn default_identity_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sendfile").join(IDENTITY_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_is_persisted() {
+        let dir = std::env::temp_dir().join(format!("sendfile_identity_{}", std::process::id()));
+        let path = dir.join(IDENTITY_FILE_NAME);
+        let _ = fs::remove_dir_all(&dir);
+
+        let first = Identity::load_or_generate(&path).expect("Failed to generate identity");
+        let second = Identity::load_or_generate(&path).expect("Failed to load identity");
+        assert_eq!(first.public_key(), second.public_key());
+
+        fs::write(&path, b"short").unwrap();
+        assert!(matches!(
+            Identity::load_or_generate(&path),
+            Err(IdentityError::Corrupt(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,31 @@
+pub mod authentication;
+pub mod capabilities;
+pub mod capture;
 pub mod cli;
+pub mod completions;
 pub mod connection;
+pub mod dashboard;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod discovery;
+pub mod dissector;
 pub mod file;
+pub mod history;
+pub mod identity;
+pub mod limits;
+pub mod noise;
+pub mod pairing;
+pub mod peers;
+#[cfg(test)]
+mod protocol_tests;
+pub mod quarantine;
+pub mod receipt;
+pub mod secrets;
+pub mod state;
+pub mod status;
 pub mod stream;
+pub mod telemetry;
+pub mod threads;
+pub mod tls;
 pub mod transport;
+pub mod units;
@@ -0,0 +1,32 @@
+//! Limits of the protocol and defaults of a session, in one place.
+//!
+//! Library users sizing their own buffers, validating options before handing them over or
+//! talking to peers through firewalls can use these instead of repeating the numbers. Most are
+//! defined next to the code they bound and re-exported here. Options structs take values within
+//! these bounds through their setters, e.g. [SendOptions::with_block_size], which clamp what's
+//! out of range.
+//!
+//! [SendOptions::with_block_size]: crate::stream::options::SendOptions::with_block_size
+
+pub use crate::{
+    stream::{
+        concurrency::{DEFAULT_MAX_CONCURRENCY, MIN_BLOCKS_PER_CONNECTION},
+        heartbeat::{DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_HEARTBEAT_TIMEOUT},
+        options::{
+            DEFAULT_BLOCK_SIZE, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_LISTEN_BACKLOG,
+            DEFAULT_READ_RETRIES, MAX_READ_RETRIES, MIN_BLOCK_SIZE,
+        },
+        receive::MAX_BLOCK_ATTEMPTS,
+        writer::{DEFAULT_WRITE_TIMEOUT, WRITE_CHUNK_SIZE},
+    },
+    transport::{
+        relay::DEFAULT_RELAY_PORT, FRAME_HEADER_SIZE, MAX_BATCH_MESSAGES,
+        MAX_BLOCK_HASHES_PER_MESSAGE, MAX_BLOCK_SIZE, MAX_HEADER_SIZE, MAX_MESSAGE_SIZE,
+    },
+};
+
+/// Port receivers accept handshakes on, and the only port of `--single-port` transfers.
+pub const HANDSHAKE_PORT: u16 = 7878;
+
+/// Port senders accept the receiver's data connections on.
+pub const TRANSFER_PORT: u16 = 7879;
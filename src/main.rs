@@ -1,50 +1,322 @@
-use clap::Parser;
-use log::{error, info};
-use sendfile::cli::{Cli, Commands, HANDSHAKE_PORT};
-use sendfile::stream;
-use sendfile::transport::MAX_BLOCK_SIZE;
+use std::{
+    net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-fn get_concurrency(requested: Option<u16>) -> u16 {
-    let available = std::thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(1);
-    let max_concurrency = available.min(16);
-    let concurrency = requested.unwrap_or(max_concurrency as u16);
+use clap::{CommandFactory, Parser};
+use clap_complete::CompleteEnv;
+use log::{error, info, warn};
+use sendfile::capture::{read_capture, Capture, Role};
+use sendfile::cli::{
+    CacheAction, Cli, Commands, IndexAction, PeerAction, QuarantineAction, StateAction, TlsArgs,
+    HANDSHAKE_PORT,
+};
+use sendfile::completions::{write_registration, COMPLETE_VAR};
+use sendfile::dashboard::{serve_dashboard, DashboardSources};
+use sendfile::discovery::{self, Advertisement, DiscoveredReceiver, DEFAULT_RESOLVE_TIMEOUT};
+use sendfile::dissector::lua_dissector;
+use sendfile::file::content_type::TypePolicy;
+use sendfile::file::device::file_size;
+use sendfile::file::index::index_directory;
+use sendfile::file::integrity::{check_integrity, CheckOutcome};
+use sendfile::file::store::{default_block_store_path, BlockStore};
+use sendfile::history::{
+    default_history_path, default_received_path, migrate_legacy_files, to_hex,
+};
+use sendfile::identity::{default_identity_path, Identity};
+use sendfile::pairing::{generate_code, introduce_to_receiver, introduce_to_sender};
+use sendfile::peers::{default_peers_path, Peer, PeerBundle, PeerError, PeerOptions, PeerRegistry};
+use sendfile::quarantine::{self, default_quarantine_dir, QuarantineError};
+use sendfile::secrets::{self, SecretString};
+use sendfile::state::{self, StateError, StateStore};
+use sendfile::status::{default_status_dir, query_status, serve_status, StatusServer};
+use sendfile::stream;
+use sendfile::stream::bundle::{directory_size, FileState, FileStatus};
+use sendfile::stream::cancel::{cancel_on_interrupt, INTERRUPTED_EXIT_CODE};
+use sendfile::stream::concurrency::effective_concurrency;
+use sendfile::stream::error::{ErrorFormat, ErrorReport, SendFileError};
+use sendfile::stream::options::{
+    ReceiveOptions, SendOptions, DEFAULT_BLOCK_SIZE, DEFAULT_HANDSHAKE_TIMEOUT,
+    DEFAULT_PIPELINE_DEPTH, MIN_BLOCK_SIZE,
+};
+use sendfile::stream::probe::Recommendation;
+use sendfile::stream::registry::{TransferDirection, TransferRegistry};
+use sendfile::stream::scan::ScanHook;
+use sendfile::stream::schedule::RateSchedule;
+use sendfile::stream::sessions::default_sessions_path;
+use sendfile::tls::TlsConfig;
+use sendfile::transport::relay::{self, Relay, RelayRole};
+#[cfg(unix)]
+use sendfile::transport::uds::UdsListener;
+use sendfile::transport::ws::{self, receiver_url, Proxy, TransportKind, WsError};
+use sendfile::transport::{IntroductionV1, MAX_BLOCK_SIZE};
+use sendfile::units::{Count, Elapsed, Size};
 
-    // Cap concurrency if user provides a higher value
-    let effective = concurrency.min(max_concurrency as u16);
-    if effective < concurrency {
-        info!("Capped concurrency from {} to {}", concurrency, effective);
-    }
-    effective
-}
+/// Exit status of a send or receive aborted at its time limit (`--max-duration`), or at the
+/// peer's.
+const TIME_LIMIT_EXIT_CODE: i32 = 3;
 
 fn main() {
+    // Answers the shell and exits when invoked by a registered completion script
+    CompleteEnv::with_factory(Cli::command)
+        .var(COMPLETE_VAR)
+        .complete();
     env_logger::init();
 
     let cli = Cli::parse();
+    cli.units.set_global();
+    secrets::set_locking(cli.lock_secrets);
+    if let Some(dir) = &cli.data_dir {
+        state::set_data_dir(dir.clone());
+    }
+    if let Some(dir) = state::data_dir() {
+        match migrate_legacy_files(&dir) {
+            Ok(0) => {}
+            Ok(moved) => info!("Moved {} history entries into {:?}", moved, dir),
+            Err(e) => warn!("Failed to move the history into {:?}: {}", dir, e),
+        }
+    }
+    if let Err(e) = cli.checksum_impl.set_global() {
+        error!("--checksum-impl {}: {}", cli.checksum_impl, e);
+        std::process::exit(1);
+    }
+    info!("Block checksums: {}", cli.checksum_impl.describe());
+
+    if let Some(path) = &cli.capture {
+        let role = match &cli.command {
+            Commands::Send(_) | Commands::Probe(_) => Some(Role::Sender),
+            Commands::Receive(_) => Some(Role::Receiver),
+            _ => None,
+        };
+        match role {
+            Some(role) => {
+                if let Err(e) = Capture::start(path, role) {
+                    error!("--capture {:?}: {}", path, e);
+                    std::process::exit(1);
+                }
+                info!("Recording every frame to {:?}", path);
+            }
+            None => warn!("--capture only records send, receive and probe, ignoring it"),
+        }
+    }
+    // Ctrl-C cancels the running transfers, telling their peers, instead of killing them
+    if matches!(cli.command, Commands::Send(_) | Commands::Receive(_))
+        && let Err(e) = cancel_on_interrupt(TransferRegistry::global())
+    {
+        warn!(
+            "Ctrl-C will kill transfers without telling their peers: {}",
+            e
+        );
+    }
 
     match cli.command {
         Commands::Send(args) => {
-            let address = (args.host.as_str(), HANDSHAKE_PORT);
-            let block_size = args.block_size.unwrap_or(1024 * 1024).min(MAX_BLOCK_SIZE); // Default 1MB
-            let no_compress = args.no_compress;
-            let concurrency = get_concurrency(args.concurrency);
-
-            info!(
-                "Sending file {:?} to {}:{} (block_size: {})",
-                args.file, address.0, address.1, block_size
-            );
-
-            if let Err(e) =
-                stream::send::send_file(address, &args.file, block_size, !no_compress, concurrency)
+            if args.transport == TransportKind::Ws
+                && (args.noise
+                    || args.code.is_some()
+                    || args.udp
+                    || args.single_port
+                    || args.dry_run)
             {
-                error!("Failed to send file: {}", e);
+                error!(
+                    "--transport ws can't be combined with --noise, --code, --udp, --single-port \
+                     or --dry-run"
+                );
+                std::process::exit(1);
+            }
+            let conflicts = args.directory_conflicts();
+            if args.file.is_dir() && !conflicts.is_empty() {
+                error!(
+                    "{:?} is a directory, which can't be sent with {}",
+                    args.file,
+                    conflicts.join(" or ")
+                );
+                std::process::exit(1);
+            }
+            if args.proxy.is_some() && args.transport != TransportKind::Ws {
+                error!("--proxy only applies to --transport ws");
                 std::process::exit(1);
             }
+            // Options given on the command line take precedence over the peer's preferences
+            let (host, alternate_hosts, port, peer_options) =
+                match args.host.as_deref().and_then(find_peer) {
+                    Some(peer) => {
+                        info!("Sending to trusted peer {:?}", peer.name);
+                        let mut addresses = peer.addresses;
+                        let host = addresses.remove(0);
+                        let port = peer.port.unwrap_or(HANDSHAKE_PORT);
+                        (host, addresses, port, peer.options)
+                    }
+                    None => match args.host.as_deref().and_then(find_receiver) {
+                        Some(receiver) => {
+                            let mut addresses: Vec<String> =
+                                receiver.addresses.iter().map(|a| a.to_string()).collect();
+                            let host = addresses.remove(0);
+                            (host, addresses, receiver.port, PeerOptions::default())
+                        }
+                        None => (
+                            args.host.unwrap_or_default(),
+                            Vec::new(),
+                            HANDSHAKE_PORT,
+                            PeerOptions::default(),
+                        ),
+                    },
+                };
+            let address = (host.as_str(), port);
+            // Network filesystems favour fewer, larger reads
+            let default_block_size = if args.network_fs {
+                MAX_BLOCK_SIZE
+            } else {
+                DEFAULT_BLOCK_SIZE
+            };
+            let block_size = args
+                .block_size
+                .or(args.profile.block_size())
+                .or(peer_options.block_size)
+                .unwrap_or(default_block_size)
+                .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+            let size = if args.file.is_dir() {
+                directory_size(&args.file).ok()
+            } else {
+                file_size(&args.file).ok()
+            };
+            let total_blocks = size.map(|size| size.div_ceil(block_size as u64));
+            let concurrency = effective_concurrency(
+                args.concurrency
+                    .or(args.profile.concurrency())
+                    .or(peer_options.concurrency),
+                args.max_concurrency,
+                total_blocks,
+            );
+
+            match &args.uds {
+                Some(socket) => info!(
+                    "Sending file {:?} over {:?} (block_size: {})",
+                    args.file, socket, block_size
+                ),
+                None => info!(
+                    "Sending file {:?} to {}:{} (block_size: {})",
+                    args.file, address.0, address.1, block_size
+                ),
+            }
+
+            let options = SendOptions {
+                block_size,
+                should_compress: !args.no_compress
+                    && args.profile.compress()
+                    && peer_options.compress.unwrap_or(true),
+                compress_entropy_threshold: args.compress_entropy_threshold,
+                concurrency,
+                lock: !args.no_lock,
+                network_fs: args.network_fs,
+                file_order: args.order,
+                workers: args.workers.to_options(),
+                strict: args.strict.to_policy(),
+                tls: load_tls(&args.tls),
+                noise: args.noise,
+                pairing_code: args.code.map(SecretString::new),
+                metadata: args.metadata.into_iter().collect(),
+                udp_overhead: args.udp.then_some(args.udp_overhead),
+                history_path: if args.no_history {
+                    None
+                } else {
+                    default_history_path()
+                },
+                identity_path: if args.no_auth {
+                    None
+                } else {
+                    args.identity.or_else(default_identity_path)
+                },
+                peers_path: default_peers_path(),
+                alternate_hosts,
+                keepalive: args.keepalive.to_options(),
+                heartbeat: args.heartbeat.to_options(),
+                rate_schedule: args
+                    .rate_schedule
+                    .or(args.limit_rate.map(RateSchedule::constant)),
+                write_timeout: Duration::from_secs(args.write_timeout),
+                listen_backlog: args.listen_backlog,
+                handshake_timeout: Duration::from_secs(args.handshake_timeout),
+                max_duration: args.max_duration.map(Duration::from_secs),
+                read_retries: args.read_retries,
+                single_port: args.single_port,
+                link_speed: args.link_speed * 1_000_000 / 8,
+                socket: args.profile.socket_tuning(),
+            };
+
+            if args.dry_run {
+                match stream::send::dry_run_send(address, &args.file, &options) {
+                    Ok(plan) => println!("{}", plan),
+                    Err(e) => {
+                        error!("Dry run failed: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+
+            let _status = start_status_server();
+            if args.dbus {
+                start_dbus_events();
+            }
+            let result = traced(args.otlp, || {
+                match (&args.uds, &args.relay, args.transport) {
+                    (Some(socket), _, _) => send_over_uds(socket, &args.file, &options),
+                    (None, Some(relay), _) => {
+                        let code = args.relay_code.as_deref().unwrap_or_default();
+                        info!("Sending through relay {}", relay);
+                        relay::connect(relay, RelayRole::Sender, code)
+                            .map_err(SendFileError::from)
+                            .and_then(|transport| {
+                                stream::send::send_over(transport, &args.file, &options)
+                            })
+                    }
+                    (None, None, TransportKind::Tcp) => {
+                        stream::send::send_file(address, &args.file, &options)
+                    }
+                    (None, None, TransportKind::Ws) => {
+                        let url = receiver_url(address.0, address.1, options.tls.is_some());
+                        info!("Sending over WebSocket {}", url);
+                        let proxy = match &args.proxy {
+                            Some(proxy) => Some(Proxy::parse(proxy)),
+                            None => Proxy::from_env(url.starts_with("wss://")),
+                        }
+                        .transpose();
+                        proxy
+                            .and_then(|proxy| {
+                                ws::connect(&url, options.tls.as_deref(), proxy.as_ref())
+                            })
+                            .map_err(SendFileError::from)
+                            .and_then(|transport| {
+                                stream::send::send_over(transport, &args.file, &options)
+                            })
+                    }
+                }
+            });
+            if let Err(e) = result {
+                report_failure("Failed to send file", &e, cli.error_format);
+                std::process::exit(exit_code(&e));
+            }
         }
         Commands::Receive(args) => {
-            let concurrency = get_concurrency(args.concurrency);
+            if args.transport == TransportKind::Ws
+                && (args.pair || args.auto_retry || args.single_port || args.dry_run)
+            {
+                error!(
+                    "--transport ws can't be combined with --pair, --auto-retry, --single-port or \
+                     --dry-run"
+                );
+                std::process::exit(1);
+            }
+            // The block count is only known after the handshake, where it is applied again
+            let concurrency = effective_concurrency(
+                args.concurrency.or(args.profile.concurrency()),
+                args.max_concurrency,
+                None,
+            )
+            .min(args.profile.max_connections());
             let bind_address = ("0.0.0.0", HANDSHAKE_PORT);
 
             info!(
@@ -52,10 +324,974 @@ fn main() {
                 bind_address.0, bind_address.1, args.file, concurrency
             );
 
-            if let Err(e) = stream::receive::receive_file(bind_address, &args.file, concurrency) {
-                error!("Failed to receive file: {}", e);
+            let scan = match args.scan_cmd {
+                Some(command) => {
+                    let Some(quarantine_dir) = args.quarantine_dir.or_else(default_quarantine_dir)
+                    else {
+                        error!("No data directory available, use --quarantine-dir");
+                        std::process::exit(1);
+                    };
+                    Some(ScanHook {
+                        command,
+                        quarantine_dir,
+                        timeout: Duration::from_secs(args.scan_timeout),
+                    })
+                }
+                None => None,
+            };
+
+            let pairing_code = if args.pair {
+                match generate_code() {
+                    Ok(code) => {
+                        println!("Pairing code: {}", code);
+                        println!("On the sender: sendfile send <FILE> <HOST> --code {}", code);
+                        Some(SecretString::new(code))
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+
+            let options = ReceiveOptions {
+                concurrency,
+                pipeline_depth: args
+                    .pipeline_depth
+                    .or(args.profile.pipeline_depth())
+                    .unwrap_or(DEFAULT_PIPELINE_DEPTH),
+                lock: !args.no_lock,
+                network_fs: args.network_fs,
+                preallocate: !args.no_preallocate,
+                drop_cache: args.no_cache_pollution,
+                disk_limit_rate: args.disk_limit_rate,
+                atomic_batch: args.atomic_batch,
+                allow_device: args.yes_i_mean_a_device,
+                workers: args.workers.to_options(),
+                strict: args.strict.to_policy(),
+                tls: load_tls(&args.tls),
+                pairing_code,
+                metadata_store: args.metadata_store,
+                identity_path: if args.no_receipt {
+                    None
+                } else {
+                    args.identity.or_else(default_identity_path)
+                },
+                block_store: if args.dedup {
+                    args.block_store.or_else(default_block_store_path)
+                } else {
+                    args.block_store
+                },
+                block_store_max_size: args.block_store_max_size,
+                xattrs: args.xattrs,
+                received_index: if args.no_history {
+                    None
+                } else {
+                    default_received_path()
+                },
+                reuse_duplicates: args.reuse_duplicates,
+                offer_handler: None,
+                type_policy: TypePolicy {
+                    accept: args.accept_types,
+                    reject: args.reject_types,
+                },
+                peers_path: default_peers_path(),
+                // The code of --pair and --relay vouches for the sender, as does access to the
+                // --uds socket
+                paired_only: !args.open && args.relay.is_none() && args.uds.is_none(),
+                auto_retry: args
+                    .auto_retry
+                    .then(|| Duration::from_secs(args.retry_budget)),
+                sessions: default_sessions_path(),
+                single_port: args.single_port,
+                keepalive: args.keepalive.to_options(),
+                heartbeat: args.heartbeat.to_options(),
+                best_effort: args.best_effort,
+                scan,
+                listen_backlog: args.listen_backlog,
+                handshake_timeout: Duration::from_secs(args.handshake_timeout),
+                max_duration: args.max_duration.map(Duration::from_secs),
+                profile: args.profile,
+                socket: args.profile.socket_tuning(),
+                max_clock_skew: Duration::from_secs(args.max_clock_skew),
+            };
+            if options.block_store.is_some() && !options.profile.buffers_beyond_block() {
+                warn!("--profile low-memory does not reuse blocks from the block store");
+            }
+
+            if args.dry_run {
+                match stream::receive::dry_run_receive(bind_address, &args.file, &options) {
+                    Ok(plan) => println!("{}", plan),
+                    Err(e) => {
+                        error!("Dry run failed: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+
+            let _status = start_status_server();
+            if args.dbus {
+                start_dbus_events();
+            }
+            let _advertisement = if args.advertise {
+                start_advertisement(args.name, &options)
+            } else {
+                None
+            };
+            let result = traced(args.otlp, || {
+                match (&args.uds, &args.relay, args.transport) {
+                    (Some(socket), _, _) => receive_over_uds(socket, &args.file, &options),
+                    (None, Some(relay), _) => {
+                        receive_through_relay(relay, args.relay_code, &args.file, &options)
+                    }
+                    (None, None, TransportKind::Tcp) => {
+                        stream::receive::receive_file(bind_address, &args.file, &options)
+                    }
+                    (None, None, TransportKind::Ws) => TcpListener::bind(bind_address)
+                        .map_err(WsError::from)
+                        .and_then(|listener| {
+                            ws::accept(&listener, options.tls.as_deref(), options.handshake_timeout)
+                        })
+                        .map_err(SendFileError::from)
+                        .and_then(|transport| {
+                            stream::receive::receive_over(transport, &args.file, &options)
+                        }),
+                }
+            });
+            if let Err(e) = result {
+                report_failure("Failed to receive file", &e, cli.error_format);
+                std::process::exit(exit_code(&e));
+            }
+        }
+        Commands::Check(args) => match check_integrity(&args.file) {
+            Ok(CheckOutcome::Intact(record)) => {
+                println!(
+                    "OK: {} matches the hash recorded when it was received ({})",
+                    args.file.display(),
+                    record.received_at
+                );
+            }
+            Ok(CheckOutcome::Modified { record, actual }) => {
+                println!(
+                    "FAILED: {} has changed since it was received (expected {}, got {})",
+                    args.file.display(),
+                    to_hex(&record.hash),
+                    to_hex(&actual)
+                );
+                std::process::exit(1);
+            }
+            Ok(CheckOutcome::Unrecorded) => {
+                println!(
+                    "{} has no recorded hash, receive it with --xattrs",
+                    args.file.display()
+                );
+                std::process::exit(2);
+            }
+            Err(e) => {
+                error!("Failed to check {:?}: {}", args.file, e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Status(args) => {
+            let processes = match query_status(&default_status_dir()) {
+                Ok(processes) => processes,
+                Err(e) => {
+                    error!("Failed to query running transfers: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            if args.json {
+                let transfers: Vec<_> = processes
+                    .iter()
+                    .flat_map(|process| {
+                        process.transfers.iter().map(|transfer| {
+                            serde_json::json!({ "pid": process.pid, "transfer": transfer })
+                        })
+                    })
+                    .collect();
+                println!("{}", serde_json::Value::Array(transfers));
+            } else {
+                print_status(&processes);
+            }
+        }
+        Commands::Dashboard(args) => {
+            let sources = DashboardSources {
+                status_dir: default_status_dir(),
+                history_path: default_history_path(),
+            };
+            if let Err(e) = serve_dashboard(args.listen.as_str(), sources) {
+                error!("Failed to serve dashboard on {}: {}", args.listen, e);
                 std::process::exit(1);
             }
         }
+        Commands::Peer(args) => {
+            let Some(path) = args.peers.or_else(default_peers_path) else {
+                error!("No config directory available, use --peers");
+                std::process::exit(1);
+            };
+
+            if let Err(e) = run_peer_command(&path, args.action) {
+                error!("Peer command failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Pair(args) => {
+            let Some(path) = args.peers.or_else(default_peers_path) else {
+                error!("No config directory available, use --peers");
+                std::process::exit(1);
+            };
+            let identity = match &args.identity {
+                Some(identity_path) => Identity::load_or_generate(identity_path),
+                None => Identity::load_default(),
+            };
+            let identity = match identity {
+                Ok(identity) => identity,
+                Err(e) => {
+                    error!("Failed to load the identity: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let introduce_as = args.introduce_as.unwrap_or_else(discovery::host_name);
+
+            let result = match (&args.host, args.code) {
+                (Some(host), Some(code)) => {
+                    pair_with_host(host, &SecretString::new(code), &identity, &introduce_as)
+                }
+                _ => wait_for_pairing(&identity, &introduce_as),
+            };
+            let introduction = match result {
+                Ok(introduction) => introduction,
+                Err(e) => {
+                    report_failure("Pairing failed", &e, cli.error_format);
+                    std::process::exit(exit_code(&e));
+                }
+            };
+            if let Err(e) = store_paired_peer(&path, introduction, args.name, args.host) {
+                error!("Failed to store the paired peer: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Quarantine(args) => {
+            let Some(root) = args.quarantine_dir.or_else(default_quarantine_dir) else {
+                error!("No data directory available, use --quarantine-dir");
+                std::process::exit(1);
+            };
+
+            if let Err(e) = run_quarantine_command(&root, args.action) {
+                error!("Quarantine command failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Probe(args) => {
+            let peer = find_peer(&args.host);
+            let (host, alternate_hosts, port) = match &peer {
+                Some(peer) => {
+                    info!("Probing trusted peer {:?}", peer.name);
+                    let mut addresses = peer.addresses.clone();
+                    let host = addresses.remove(0);
+                    (host, addresses, peer.port.unwrap_or(HANDSHAKE_PORT))
+                }
+                None => (args.host.clone(), Vec::new(), HANDSHAKE_PORT),
+            };
+            let options = SendOptions {
+                alternate_hosts,
+                tls: load_tls(&args.tls),
+                noise: args.noise,
+                ..SendOptions::default()
+            };
+
+            let duration = Duration::from_secs(args.duration);
+            let report = match stream::probe::probe((host.as_str(), port), &options, duration) {
+                Ok(report) => report,
+                Err(e) => {
+                    error!("Probe failed: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!("{}", report);
+
+            if args.save {
+                let (Some(peer), Some(path)) = (peer, default_peers_path()) else {
+                    error!(
+                        "{:?} is not a trusted peer, use its alias to save",
+                        args.host
+                    );
+                    std::process::exit(1);
+                };
+                let recommendation = report.recommend();
+                if let Err(e) = save_recommendation(&path, &peer.name, &recommendation) {
+                    error!("Failed to save options of peer {:?}: {}", peer.name, e);
+                    std::process::exit(1);
+                }
+                println!("Saved options of peer {:?}", peer.name);
+            }
+        }
+        Commands::Cache(args) => {
+            let Some(root) = args.block_store.or_else(default_block_store_path) else {
+                error!("No cache directory available, use --block-store");
+                std::process::exit(1);
+            };
+
+            if let Err(e) = run_cache_command(&root, args.action) {
+                error!("Failed to access block store {:?}: {}", root, e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Index(args) => {
+            let Some(index) = args.index.or_else(default_received_path) else {
+                error!("No data directory available, use --index");
+                std::process::exit(1);
+            };
+
+            let (dir, rebuild) = match args.action {
+                IndexAction::Build { dir } => (dir, true),
+                IndexAction::Update { dir } => (dir, false),
+            };
+            match index_directory(&index, &dir, rebuild, &args.workers.to_options()) {
+                Ok(stats) => println!(
+                    "Indexed {} files ({}), {} unchanged, {} removed",
+                    Count(stats.hashed as u64),
+                    Size(stats.bytes),
+                    Count(stats.unchanged as u64),
+                    Count(stats.removed as u64)
+                ),
+                Err(e) => {
+                    error!("Failed to index {:?} into {:?}: {}", dir, index, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::State(args) => {
+            let Some(state_dir) = state::default_state_dir() else {
+                error!("No data directory available, use --data-dir");
+                std::process::exit(1);
+            };
+
+            if let Err(e) = run_state_command(&state_dir, args.action) {
+                error!("Failed to access the stores in {:?}: {}", state_dir, e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Discover(args) => {
+            let receivers = match discovery::discover(Duration::from_secs(args.timeout)) {
+                Ok(receivers) => receivers,
+                Err(e) => {
+                    error!("Failed to discover receivers: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if args.json {
+                match serde_json::to_string(&receivers) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => {
+                        error!("Failed to encode receivers: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else if receivers.is_empty() {
+                println!("No receivers found");
+            } else {
+                for receiver in &receivers {
+                    println!("{}", receiver);
+                }
+            }
+        }
+        Commands::Relay(args) => {
+            let listener = match TcpListener::bind(("0.0.0.0", args.port)) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to listen on port {}: {}", args.port, e);
+                    std::process::exit(1);
+                }
+            };
+            info!("Relaying on port {}", args.port);
+            let relay = Arc::new(Relay::new(Duration::from_secs(args.wait_timeout)));
+            if let Err(e) = relay.serve(&listener) {
+                error!("Relay failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Completions(args) => {
+            // Completions are answered by this very binary, wherever it is installed
+            let completer = std::env::current_exe()
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|_| String::from("sendfile"));
+            let mut stdout = std::io::stdout();
+            if let Err(e) = write_registration(args.shell, "sendfile", &completer, &mut stdout) {
+                error!("Failed to write completions: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Replay(args) => match read_capture(&args.path) {
+            Ok(replay) => print!("{}", replay),
+            Err(e) => {
+                error!("Failed to replay {:?}: {}", args.path, e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Dissector => match lua_dissector() {
+            Ok(lua) => print!("{}", lua),
+            Err(e) => {
+                error!("Failed to generate the dissector: {}", e);
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+/// Returns the exit status of a failed send or receive: [TIME_LIMIT_EXIT_CODE] once a time limit
+/// ran out, so scheduled jobs can tell it from other failures, [INTERRUPTED_EXIT_CODE] once
+/// cancelled with Ctrl-C, 1 otherwise.
+fn exit_code(error: &SendFileError) -> i32 {
+    match error.root() {
+        SendFileError::TimeLimitExceeded(_) => TIME_LIMIT_EXIT_CODE,
+        SendFileError::Cancelled => INTERRUPTED_EXIT_CODE,
+        _ => 1,
+    }
+}
+
+/// Reports the failed send or receive `error` in `format`, after `summary` when logged.
+fn report_failure(summary: &str, error: &SendFileError, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => error!("{}: {}", summary, error),
+        ErrorFormat::Json => match serde_json::to_string(&ErrorReport::from(error)) {
+            Ok(json) => eprintln!("{}", json),
+            Err(e) => error!("{}: {} ({})", summary, error, e),
+        },
+    }
+}
+
+/// Returns the trusted peer aliased `name`, if it has an address to send to.
+fn find_peer(name: &str) -> Option<Peer> {
+    let path = default_peers_path()?;
+    let registry = PeerRegistry::load(&path)
+        .map_err(|e| warn!("Failed to read trusted peers {:?}: {}", path, e))
+        .ok()?;
+    let peer = registry.find(name)?;
+    if peer.addresses.is_empty() {
+        warn!("Peer {:?} has no address, using it as a host name", name);
+        return None;
+    }
+    Some(peer.clone())
+}
+
+/// Sends `file` to the receiver listening on the Unix domain socket `socket` (`send --uds`).
+#[cfg(unix)]
+fn send_over_uds(socket: &Path, file: &Path, options: &SendOptions) -> Result<(), SendFileError> {
+    let transport = std::os::unix::net::UnixStream::connect(socket)?;
+    stream::send::send_over(transport, file, options)
+}
+
+/// Receives `file` from the first sender connecting to the Unix domain socket created at
+/// `socket` (`receive --uds`).
+#[cfg(unix)]
+fn receive_over_uds(
+    socket: &Path,
+    file: &Path,
+    options: &ReceiveOptions,
+) -> Result<(), SendFileError> {
+    let listener = UdsListener::bind(socket)?;
+    info!("Listening on {:?}", socket);
+    let transport = listener.accept()?;
+    stream::receive::receive_over(transport, file, options)
+}
+
+#[cfg(not(unix))]
+fn send_over_uds(_: &Path, _: &Path, _: &SendOptions) -> Result<(), SendFileError> {
+    Err(uds_unsupported())
+}
+
+#[cfg(not(unix))]
+fn receive_over_uds(_: &Path, _: &Path, _: &ReceiveOptions) -> Result<(), SendFileError> {
+    Err(uds_unsupported())
+}
+
+#[cfg(not(unix))]
+fn uds_unsupported() -> SendFileError {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+    .into()
+}
+
+/// Receives `file` from the sender arriving at `relay` under `code`, or under a new one-time code
+/// printed for the sender when none is given (`receive --relay`).
+fn receive_through_relay(
+    relay: &str,
+    code: Option<String>,
+    file: &Path,
+    options: &ReceiveOptions,
+) -> Result<(), SendFileError> {
+    let code = match code {
+        Some(code) => code,
+        None => {
+            let code = generate_code()?;
+            println!("Rendezvous code: {}", code);
+            println!(
+                "On the sender: sendfile send <FILE> --relay {} --relay-code {}",
+                relay, code
+            );
+            code
+        }
+    };
+    info!("Waiting for the sender at relay {}", relay);
+    let transport = relay::connect(relay, RelayRole::Receiver, &code)?;
+    stream::receive::receive_over(transport, file, options)
+}
+
+/// Looks up the receiver announced as `host` on the local network, unless `host` is an address,
+/// a URL or a name DNS resolves.
+fn find_receiver(host: &str) -> Option<DiscoveredReceiver> {
+    if host.parse::<IpAddr>().is_ok()
+        || host.contains("://")
+        || (host, HANDSHAKE_PORT)
+            .to_socket_addrs()
+            .is_ok_and(|mut addresses| addresses.next().is_some())
+    {
+        return None;
+    }
+    match discovery::resolve(host, DEFAULT_RESOLVE_TIMEOUT) {
+        Ok(receiver) => {
+            info!("Found receiver {}", receiver);
+            Some(receiver)
+        }
+        Err(e) => {
+            warn!("{}", e);
+            None
+        }
+    }
+}
+
+/// Stores the block size and concurrency recommended by a probe in the options of the trusted
+/// peer named `name`.
+fn save_recommendation(
+    path: &Path,
+    name: &str,
+    recommendation: &Recommendation,
+) -> Result<(), PeerError> {
+    let mut registry = PeerRegistry::load(path)?;
+    let Some(mut peer) = registry.find(name).cloned() else {
+        return Ok(());
+    };
+    peer.options.block_size = Some(recommendation.block_size);
+    peer.options.concurrency = Some(recommendation.concurrency);
+    registry.insert(peer);
+    registry.save(path)
+}
+
+/// Loads the TLS credentials requested with `--tls`, exiting if they can't be.
+fn load_tls(args: &TlsArgs) -> Option<Arc<TlsConfig>> {
+    args.to_config().unwrap_or_else(|e| {
+        error!("Failed to load TLS credentials: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Lets `sendfile status` query this process, the transfer still runs if it fails.
+fn start_status_server() -> Option<StatusServer> {
+    serve_status(&default_status_dir(), TransferRegistry::global())
+        .map_err(|e| warn!("Status socket unavailable: {}", e))
+        .ok()
+}
+
+/// Announces this receiver on the local network as `name`, the host name by default, with the
+/// fingerprint of its identity key. The receive still runs if it fails.
+fn start_advertisement(name: Option<String>, options: &ReceiveOptions) -> Option<Advertisement> {
+    let name = name.unwrap_or_else(discovery::host_name);
+    let fingerprint = options
+        .identity_path
+        .as_deref()
+        .and_then(|path| Identity::load_or_generate(path).ok())
+        .map(|identity| to_hex(&identity.public_key()));
+    match discovery::advertise(&name, HANDSHAKE_PORT, fingerprint.as_deref()) {
+        Ok(advertisement) => {
+            info!(
+                "Announcing this receiver as {:?} on the local network",
+                name
+            );
+            Some(advertisement)
+        }
+        Err(e) => {
+            warn!("Failed to announce this receiver: {}", e);
+            None
+        }
+    }
+}
+
+/// Emits D-Bus signals for the transfers of this process, the transfer still runs if it fails.
+fn start_dbus_events() {
+    #[cfg(feature = "dbus")]
+    if let Err(e) = sendfile::dbus::spawn_dbus_events(TransferRegistry::global()) {
+        warn!("D-Bus events unavailable: {}", e);
+    }
+    #[cfg(not(feature = "dbus"))]
+    warn!("D-Bus events unavailable: sendfile was built without the dbus feature");
+}
+
+/// Runs `transfer`, exporting its traces over OTLP if `otlp` is set. The spans still buffered
+/// are exported before returning, the transfer still runs if exporting them fails.
+fn traced<T>(otlp: bool, transfer: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "otel")]
+    let _export = otlp
+        .then(sendfile::telemetry::start_otlp_export)
+        .and_then(|export| {
+            export
+                .inspect_err(|e| warn!("OpenTelemetry traces unavailable: {}", e))
+                .ok()
+        });
+    #[cfg(not(feature = "otel"))]
+    if otlp {
+        warn!("OpenTelemetry traces unavailable: sendfile was built without the otel feature");
+    }
+    transfer()
+}
+
+fn print_status(processes: &[sendfile::status::ProcessStatus]) {
+    let transfers: Vec<_> = processes
+        .iter()
+        .flat_map(|process| process.transfers.iter().map(|t| (process.pid, t)))
+        .collect();
+    if transfers.is_empty() {
+        println!("No active transfers");
+        return;
+    }
+
+    println!(
+        "{:<8} {:<8} {:<22} {:<24} PROGRESS",
+        "PID", "DIR", "PEER", "FILE"
+    );
+    for (pid, transfer) in transfers {
+        let direction = match transfer.direction {
+            TransferDirection::Send => "send",
+            TransferDirection::Receive => "receive",
+        };
+        let progress = match transfer.total_bytes {
+            Some(total) if total > 0 => format!(
+                "{}/{} ({}%)",
+                Size(transfer.bytes_transferred),
+                Size(total),
+                transfer.bytes_transferred * 100 / total
+            ),
+            Some(total) => format!("{}/{}", Size(transfer.bytes_transferred), Size(total)),
+            None => String::from("handshake"),
+        };
+        let paused = if transfer.paused { " [paused]" } else { "" };
+        println!(
+            "{:<8} {:<8} {:<22} {:<24} {}{}",
+            pid, direction, transfer.peer, transfer.file_name, progress, paused
+        );
+        if let Some(stage) = transfer.bottleneck {
+            println!("{:<8} hint: {}", "", stage.hint());
+        }
+        print_file_statuses(&transfer.files);
+    }
+}
+
+/// Prints the files of a directory being transferred that are under way or failed, followed by
+/// how many are done and queued.
+fn print_file_statuses(files: &[FileStatus]) {
+    if files.is_empty() {
+        return;
+    }
+    let count = |state| files.iter().filter(|file| file.state == state).count();
+    for file in files {
+        let state = match file.state {
+            FileState::Transferring if file.size > 0 => {
+                format!("transferring {}%", file.bytes_transferred * 100 / file.size)
+            }
+            FileState::Transferring => String::from("transferring"),
+            FileState::Failed => String::from("failed"),
+            FileState::Queued | FileState::Done => continue,
+        };
+        println!("{:<8}   {:<53} {}", "", file.path, state);
+    }
+    println!(
+        "{:<8}   {} of {} files done, {} queued",
+        "",
+        count(FileState::Done),
+        files.len(),
+        count(FileState::Queued)
+    );
+}
+
+fn run_peer_command(path: &Path, action: PeerAction) -> Result<(), PeerError> {
+    match action {
+        PeerAction::Export {
+            name,
+            addresses,
+            port,
+            block_size,
+            concurrency,
+            no_compress,
+            identity,
+            output,
+        } => {
+            let identity = match identity {
+                Some(identity_path) => Identity::load_or_generate(&identity_path)?,
+                None => Identity::load_default()?,
+            };
+            let options = PeerOptions {
+                block_size,
+                concurrency,
+                compress: no_compress.then_some(false),
+            };
+            let bundle = PeerBundle::export(&identity, name, addresses, port, options);
+            let json = serde_json::to_string_pretty(&bundle)?;
+            match output {
+                Some(output) => std::fs::write(&output, json + "\n")?,
+                None => println!("{}", json),
+            }
+        }
+        PeerAction::Import { file, name } => {
+            let mut peer = PeerBundle::read(&file)?.peer;
+            if let Some(name) = name {
+                peer.name = name;
+            }
+            let mut registry = PeerRegistry::load(path)?;
+            let name = peer.name.clone();
+            let key = peer.public_key.clone();
+            match registry.insert(peer) {
+                Some(_) => println!("Updated trusted peer {:?} ({})", name, key),
+                None => println!("Trusting peer {:?} ({})", name, key),
+            }
+            registry.save(path)?;
+        }
+        PeerAction::List => {
+            let registry = PeerRegistry::load(path)?;
+            if registry.peers.is_empty() {
+                println!("No trusted peers");
+            }
+            for peer in &registry.peers {
+                println!(
+                    "{:<16} {} {}",
+                    peer.name,
+                    peer.public_key,
+                    peer.addresses.join(",")
+                );
+            }
+        }
+        PeerAction::Remove { name } => {
+            let mut registry = PeerRegistry::load(path)?;
+            if registry.remove(&name).is_none() {
+                println!("No trusted peer named {:?}", name);
+                std::process::exit(1);
+            }
+            registry.save(path)?;
+            println!("Removed peer {:?}", name);
+        }
+    }
+    Ok(())
+}
+
+/// Pairs with the machine running `sendfile pair` on `host` using the `code` it printed, and
+/// introduces this machine as `name` (`sendfile pair HOST CODE`).
+fn pair_with_host(
+    host: &str,
+    code: &SecretString,
+    identity: &Identity,
+    name: &str,
+) -> Result<IntroductionV1, SendFileError> {
+    let stream = TcpStream::connect((host, HANDSHAKE_PORT))?;
+    stream.set_read_timeout(Some(DEFAULT_HANDSHAKE_TIMEOUT))?;
+    introduce_to_receiver(stream, code.expose(), identity, name)
+}
+
+/// Prints a one-time code and pairs with the first machine connecting with it, introducing this
+/// machine as `name` (`sendfile pair`).
+fn wait_for_pairing(identity: &Identity, name: &str) -> Result<IntroductionV1, SendFileError> {
+    let code = SecretString::new(generate_code()?);
+    let listener = TcpListener::bind(("0.0.0.0", HANDSHAKE_PORT))?;
+    println!("Pairing code: {}", code.expose());
+    println!(
+        "On the other machine: sendfile pair <HOST> {}",
+        code.expose()
+    );
+    let (stream, address) = listener.accept()?;
+    info!("Pairing with {}", address);
+    // A machine that connects and stays silent fails the pairing rather than hanging it
+    stream.set_read_timeout(Some(DEFAULT_HANDSHAKE_TIMEOUT))?;
+    introduce_to_sender(stream, code.expose(), identity, name)
+}
+
+/// Stores the machine that introduced itself with `introduction` as a trusted peer in the peers
+/// file at `path`, under `name` if given. `host` is where it was reached, if this machine
+/// connected to it.
+fn store_paired_peer(
+    path: &Path,
+    introduction: IntroductionV1,
+    name: Option<String>,
+    host: Option<String>,
+) -> Result<(), PeerError> {
+    let public_key = to_hex(&introduction.public_key);
+    let name = name.unwrap_or(introduction.name).trim().to_string();
+    // A machine introducing itself without a name is named after its key
+    let name = if name.is_empty() {
+        format!("peer-{}", &public_key[..8])
+    } else {
+        name
+    };
+    let peer = Peer {
+        name: name.clone(),
+        public_key: public_key.clone(),
+        addresses: host.into_iter().collect(),
+        port: None,
+        options: PeerOptions::default(),
+    };
+    let mut registry = PeerRegistry::load(path)?;
+    match registry.insert_paired(peer)? {
+        Some(_) => println!("Paired again with {:?} ({})", name, public_key),
+        None => println!("Paired with {:?} ({})", name, public_key),
+    }
+    registry.save(path)
+}
+
+fn run_quarantine_command(root: &Path, action: QuarantineAction) -> Result<(), QuarantineError> {
+    match action {
+        QuarantineAction::List { json } => {
+            let held = quarantine::list(root)?;
+            if json {
+                println!("{}", serde_json::to_string(&held)?);
+                return Ok(());
+            }
+            if held.is_empty() {
+                println!("No quarantined files");
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            for file in &held {
+                let age = Duration::from_secs(now.saturating_sub(file.record.received_at));
+                println!(
+                    "{}  {} ({}) from {}, {} ago",
+                    file.id,
+                    file.record.file_name,
+                    Size(file.record.size),
+                    file.record.sender,
+                    Elapsed(age)
+                );
+                println!("    for {}", file.record.destination.display());
+                println!("    held: {}", file.record.reason);
+            }
+        }
+        QuarantineAction::Release { id, to, force } => {
+            let path = quarantine::release(root, &id, to.as_deref(), force)?;
+            println!("Released {} to {}", id, path.display());
+        }
+        QuarantineAction::Purge { ids, all } => {
+            let ids = if all {
+                quarantine::list(root)?
+                    .into_iter()
+                    .map(|file| file.id)
+                    .collect()
+            } else {
+                ids
+            };
+            let mut bytes = 0;
+            for id in &ids {
+                bytes += quarantine::purge(root, id)?;
+            }
+            println!("Purged {} files ({})", Count(ids.len() as u64), Size(bytes));
+        }
+    }
+    Ok(())
+}
+
+fn run_state_command(state_dir: &Path, action: StateAction) -> Result<(), StateError> {
+    let names = state::list_stores(state_dir)?;
+    let check_name = |name: &String| {
+        if !names.contains(name) {
+            error!("No store named {:?} in {:?}", name, state_dir);
+            std::process::exit(1);
+        }
+    };
+
+    match action {
+        StateAction::List { json } => {
+            let stats = names
+                .iter()
+                .map(|name| state::store_stats(state_dir, name))
+                .collect::<Result<Vec<_>, _>>()?;
+            if json {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else if stats.is_empty() {
+                println!("No stores in {}", state_dir.display());
+            } else {
+                for stats in &stats {
+                    println!(
+                        "{:<12} {:>8} records {:>10}  {} stale lines",
+                        stats.name,
+                        Count(stats.records as u64),
+                        Size(stats.bytes).to_string(),
+                        Count(stats.stale_lines as u64)
+                    );
+                }
+            }
+        }
+        StateAction::Show { name } => {
+            check_name(&name);
+            for (key, value) in state::open_store(state_dir, &name)?.records()? {
+                println!("{}", serde_json::json!({ "key": key, "value": value }));
+            }
+        }
+        StateAction::Compact { name } => {
+            if let Some(name) = &name {
+                check_name(name);
+            }
+            for name in names
+                .iter()
+                .filter(|n| name.as_ref().is_none_or(|name| name == *n))
+            {
+                let store = state::open_store(state_dir, name)?;
+                let stale = store.stale_lines();
+                store.compact()?;
+                println!("Compacted {}, dropped {} lines", name, Count(stale as u64));
+            }
+        }
+        StateAction::Clear { name } => {
+            check_name(&name);
+            let store = state::open_store(state_dir, &name)?;
+            let removed = (&store as &dyn StateStore).clear()?;
+            println!("Removed {} records from {}", Count(removed as u64), name);
+        }
+    }
+    Ok(())
+}
+
+fn run_cache_command(root: &Path, action: CacheAction) -> std::io::Result<()> {
+    let store = BlockStore::open(root)?;
+    match action {
+        CacheAction::Gc { max_size } => {
+            let evicted = store.gc(max_size)?;
+            println!(
+                "Evicted {} blocks ({})",
+                Count(evicted.blocks),
+                Size(evicted.bytes)
+            );
+        }
+        CacheAction::Stats => {
+            let stats = store.stats()?;
+            println!("Location: {}", root.display());
+            println!("Blocks:   {}", Count(stats.blocks));
+            println!(
+                "Size:     {} ({} bytes)",
+                Size(stats.bytes),
+                Count(stats.bytes)
+            );
+        }
+        CacheAction::Clear => {
+            let removed = store.clear()?;
+            println!(
+                "Removed {} blocks ({})",
+                Count(removed.blocks),
+                Size(removed.bytes)
+            );
+        }
     }
+    Ok(())
 }
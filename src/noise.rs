@@ -0,0 +1,747 @@
+//! Optional Noise channel encrypting the handshake and data connections (`send --noise`).
+//!
+//! An alternative to [crate::tls] for peers that know each other by their identity keys rather
+//! than through a certificate authority. A sender started with `--noise` advertises
+//! [Capabilities::NOISE](crate::capabilities::Capabilities::NOISE) and follows its handshake with
+//! the first `NoiseHandshake` of a Noise_XX handshake. Receivers supporting it answer, and every
+//! message after the handshake, on either side, goes through the channel ([NoiseStream]).
+//!
+//! The static key of each peer is the X25519 form of its identity key (see [crate::identity]),
+//! so the key a sender authenticates with is checked against the key of the channel, and senders
+//! check the receiver's key against the trusted peer they send to. Peers without an identity use
+//! a key of their own for the transfer. The handshake frame is the prologue of the Noise
+//! handshake, so a handshake altered on its way fails the Noise handshake.
+//!
+//! Data connections run a Noise_KK handshake with the static keys learned on the handshake
+//! connection ([NoisePeer]), so only the two peers of the transfer can open them.
+//!
+//! Peers paired with a one-time code (see [crate::pairing]) run a Noise_XXpsk3 handshake keyed
+//! by the pairing instead, before the handshake frame, which then goes through the channel.
+
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use log::debug;
+use snow::{Builder, HandshakeState, TransportState};
+use thiserror::Error;
+
+use crate::{
+    capture::{CaptureTap, Direction},
+    connection::read_next_payload,
+    history::to_hex,
+    identity::{x25519_public_key, Identity},
+    secrets::{Secret, SecretKey},
+    stream::{error::SendFileError, preconnected::Connection},
+    tls::MaybeTlsStream,
+    transport::{
+        attach_headers_for, NoiseHandshakeV1, ReceiverMessageV1, SenderMessageV1, SESSION_ID_SIZE,
+        TEXT_FRAMING_PROTOCOL_VERSION,
+    },
+};
+
+/// Noise pattern of the handshake connection, where peers learn each other's static key.
+const HANDSHAKE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Noise pattern of the handshake connection of paired peers, bound to the pairing's session key.
+const PAIRED_PATTERN: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+
+/// Position of the pre-shared key in [PAIRED_PATTERN].
+const PAIRED_PSK_LOCATION: u8 = 3;
+
+/// Noise pattern of data connections, where both static keys are known.
+const DATA_PATTERN: &str = "Noise_KK_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message.
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+/// Bytes of the authentication tag of every encrypted record.
+const TAG_LEN: usize = 16;
+
+/// Largest plaintext of a record.
+const MAX_RECORD_PLAINTEXT: usize = MAX_NOISE_MESSAGE - TAG_LEN;
+
+/// Bytes of the big endian length preceding every record.
+const RECORD_LEN_SIZE: usize = 2;
+
+/// Buffer size of the frames of the Noise handshakes, which are at most a hundred bytes.
+const HANDSHAKE_FRAME_SIZE: usize = 1024;
+
+/// Errors that can occur while setting up or using a Noise channel.
+#[derive(Error, Debug)]
+pub enum NoiseError {
+    /// The Noise handshake failed, or a record could not be decrypted.
+    #[error("Noise error: {0}")]
+    Snow(#[from] snow::Error),
+    /// The peer completed the handshake without a static key.
+    #[error("Peer presented no Noise static key")]
+    MissingStaticKey,
+    /// The sender authenticated with another identity key than the key of the channel.
+    #[error("Noise key of the peer does not match its identity key")]
+    IdentityMismatch,
+    /// The receiver's key is not the key of the trusted peer the file is sent to.
+    #[error("Receiver's Noise key {key} is not the key of trusted peer {peer:?}")]
+    UntrustedKey { key: String, peer: String },
+}
+
+/// Static X25519 key pair of a peer's Noise channels.
+#[derive(Clone)]
+pub struct NoiseKey {
+    private: SecretKey,
+    public: [u8; 32],
+}
+
+impl Debug for NoiseKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseKey")
+            .field("public", &to_hex(&self.public))
+            .finish_non_exhaustive()
+    }
+}
+
+impl NoiseKey {
+    /// Returns the key of `identity`, or a new key for this transfer if there is none.
+    pub fn new(identity: Option<&Identity>) -> Result<Self, NoiseError> {
+        match identity {
+            Some(identity) => Ok(Self {
+                private: identity.x25519_private_key(),
+                public: x25519_public_key(&identity.public_key())
+                    .expect("identity keys are valid Ed25519 keys"),
+            }),
+            None => {
+                let keypair = Builder::new(params(HANDSHAKE_PATTERN)).generate_keypair()?;
+                let private = Secret::new(keypair.private);
+                Ok(Self {
+                    private: SecretKey::from_slice(private.expose())
+                        .ok_or(NoiseError::MissingStaticKey)?,
+                    public: to_key(&keypair.public).ok_or(NoiseError::MissingStaticKey)?,
+                })
+            }
+        }
+    }
+
+    /// Returns the public key peers see.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+}
+
+/// A Noise handshake in progress.
+pub struct NoiseHandshake {
+    state: HandshakeState,
+    key: NoiseKey,
+}
+
+impl NoiseHandshake {
+    /// Starts the handshake of the handshake connection as the sender, bound to the handshake
+    /// frame `prologue`.
+    pub fn initiator(key: &NoiseKey, prologue: &[u8]) -> Result<Self, NoiseError> {
+        Self::build(HANDSHAKE_PATTERN, key, None, prologue, None, true)
+    }
+
+    /// Starts the handshake of the handshake connection as the receiver, bound to the handshake
+    /// frame `prologue`.
+    pub fn responder(key: &NoiseKey, prologue: &[u8]) -> Result<Self, NoiseError> {
+        Self::build(HANDSHAKE_PATTERN, key, None, prologue, None, false)
+    }
+
+    /// Starts the handshake of the handshake connection as a sender paired with the receiver,
+    /// bound to the pairing's `session_key`.
+    pub fn paired_initiator(key: &NoiseKey, session_key: &[u8; 32]) -> Result<Self, NoiseError> {
+        Self::build(PAIRED_PATTERN, key, None, &[], Some(session_key), true)
+    }
+
+    /// Starts the handshake of the handshake connection as a receiver paired with the sender,
+    /// bound to the pairing's `session_key`.
+    pub fn paired_responder(key: &NoiseKey, session_key: &[u8; 32]) -> Result<Self, NoiseError> {
+        Self::build(PAIRED_PATTERN, key, None, &[], Some(session_key), false)
+    }
+
+    fn build(
+        pattern: &str,
+        key: &NoiseKey,
+        remote: Option<&[u8; 32]>,
+        prologue: &[u8],
+        psk: Option<&[u8; 32]>,
+        initiator: bool,
+    ) -> Result<Self, NoiseError> {
+        let mut builder = Builder::new(params(pattern))
+            .local_private_key(key.private.expose())
+            .prologue(prologue);
+        if let Some(remote) = remote {
+            builder = builder.remote_public_key(remote);
+        }
+        if let Some(psk) = psk {
+            builder = builder.psk(PAIRED_PSK_LOCATION, psk);
+        }
+        let state = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
+        Ok(Self {
+            state,
+            key: key.clone(),
+        })
+    }
+
+    /// Returns the next message this side sends.
+    pub fn write_message(&mut self) -> Result<NoiseHandshakeV1, NoiseError> {
+        let mut payload = vec![0u8; HANDSHAKE_FRAME_SIZE];
+        let len = self.state.write_message(&[], &mut payload)?;
+        payload.truncate(len);
+        Ok(NoiseHandshakeV1 { payload })
+    }
+
+    /// Reads the next message of the peer.
+    pub fn read_message(&mut self, message: &NoiseHandshakeV1) -> Result<(), NoiseError> {
+        self.state
+            .read_message(&message.payload, &mut [0u8; HANDSHAKE_FRAME_SIZE])?;
+        Ok(())
+    }
+}
+
+/// Peer of a Noise handshake connection, whose data connections must hold the same static key.
+#[derive(Debug, Clone)]
+pub struct NoisePeer {
+    key: NoiseKey,
+    remote: [u8; 32],
+}
+
+impl NoisePeer {
+    /// Returns the static key of the peer.
+    pub fn remote_key(&self) -> &[u8; 32] {
+        &self.remote
+    }
+
+    /// Checks that the peer's static key is the X25519 form of the identity key `key`.
+    pub fn check_identity(&self, key: &[u8; 32]) -> Result<(), NoiseError> {
+        match x25519_public_key(key) {
+            Some(remote) if remote == self.remote => Ok(()),
+            _ => Err(NoiseError::IdentityMismatch),
+        }
+    }
+
+    /// Encrypts a data connection opened to the peer, framing its handshake with
+    /// `protocol_version` and tagging it with `session_id`.
+    pub fn connect<S: Read + Write>(
+        &self,
+        stream: &mut NoiseStream<S>,
+        protocol_version: u8,
+        session_id: Option<&[u8; SESSION_ID_SIZE]>,
+    ) -> Result<(), SendFileError> {
+        let mut handshake =
+            NoiseHandshake::build(DATA_PATTERN, &self.key, Some(&self.remote), &[], None, true)?;
+        let mut buffer = [0u8; HANDSHAKE_FRAME_SIZE];
+        let message = ReceiverMessageV1::NoiseHandshake(handshake.write_message()?);
+        let payload = message.to_bytes(&mut buffer)?;
+        stream.write_all(&attach_headers_for(protocol_version, session_id, payload))?;
+        stream.flush()?;
+
+        let (reply, _, received) = read_sender_message(stream, session_id)?;
+        handshake.read_message(&reply)?;
+        Ok(stream.start(handshake, &received)?)
+    }
+
+    /// Encrypts a data connection the peer opened, answering with the framing of its handshake.
+    /// Handshakes tagged with a session must be tagged with `session_id`.
+    pub fn accept<S: Read + Write>(
+        &self,
+        stream: &mut NoiseStream<S>,
+        session_id: Option<&[u8; SESSION_ID_SIZE]>,
+    ) -> Result<(), SendFileError> {
+        let mut handshake = NoiseHandshake::build(
+            DATA_PATTERN,
+            &self.key,
+            Some(&self.remote),
+            &[],
+            None,
+            false,
+        )?;
+        let (message, protocol_version, received) = read_receiver_message(stream, session_id)?;
+        handshake.read_message(&message)?;
+
+        let mut buffer = [0u8; HANDSHAKE_FRAME_SIZE];
+        let reply = SenderMessageV1::NoiseHandshake(handshake.write_message()?);
+        let payload = reply.to_bytes(&mut buffer)?;
+        stream.write_all(&attach_headers_for(protocol_version, session_id, payload))?;
+        stream.flush()?;
+        Ok(stream.start(handshake, &received)?)
+    }
+}
+
+/// Runs the Noise `handshake` of the handshake connection as the sender, started with
+/// [NoiseHandshake::initiator] once the handshake frame is written, or with
+/// [NoiseHandshake::paired_initiator] once paired: every later message on `stream` is encrypted.
+///
+/// Receivers that don't support the channel fail on the first Noise message and close the
+/// connection, which fails the handshake.
+pub fn initiate<S: Read + Write>(
+    stream: &mut NoiseStream<S>,
+    mut handshake: NoiseHandshake,
+) -> Result<(), SendFileError> {
+    let mut buffer = [0u8; HANDSHAKE_FRAME_SIZE];
+    let message = SenderMessageV1::NoiseHandshake(handshake.write_message()?);
+    // Framed as the handshake, the receiver has not picked a version yet
+    let payload = message.to_bytes(&mut buffer)?;
+    stream.write_all(&attach_headers_for(
+        TEXT_FRAMING_PROTOCOL_VERSION,
+        None,
+        payload,
+    ))?;
+    stream.flush()?;
+
+    let (reply, _, received) = read_receiver_message(stream, None).map_err(|e| {
+        SendFileError::ConnectionFailed(format!(
+            "No Noise handshake from the receiver, which may not support --noise: {e}"
+        ))
+    })?;
+    handshake.read_message(&reply)?;
+    let message = SenderMessageV1::NoiseHandshake(handshake.write_message()?);
+    let payload = message.to_bytes(&mut buffer)?;
+    stream.write_all(&attach_headers_for(
+        TEXT_FRAMING_PROTOCOL_VERSION,
+        None,
+        payload,
+    ))?;
+    Ok(stream.start(handshake, &received)?)
+}
+
+/// Reads the next Noise handshake message of the receiver on `stream`, which must belong to the
+/// session `session_id` if it is tagged with one.
+///
+/// # Returns
+///
+/// The message, the protocol version it is framed with and the bytes read past it.
+fn read_receiver_message<S: Read>(
+    stream: &mut S,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> Result<(NoiseHandshakeV1, u8, Vec<u8>), SendFileError> {
+    let mut buffer = [0u8; HANDSHAKE_FRAME_SIZE];
+    let result = read_next_payload::<ReceiverMessageV1, _>(stream, &mut buffer, 0)?;
+    result.check_session(session_id, result.protocol_version)?;
+    let received = result
+        .next_payload_index
+        .map(|index| buffer[index..result.total_bytes_read].to_vec())
+        .unwrap_or_default();
+    match result.message {
+        ReceiverMessageV1::NoiseHandshake(message) => {
+            Ok((message, result.protocol_version, received))
+        }
+        message => Err(SendFileError::UnexpectedMessage {
+            received: format!("{:?}", message),
+            expected: String::from("NoiseHandshake"),
+        }),
+    }
+}
+
+/// Reads the next Noise handshake message of the sender on `stream`, see
+/// [read_receiver_message].
+fn read_sender_message<S: Read>(
+    stream: &mut S,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> Result<(NoiseHandshakeV1, u8, Vec<u8>), SendFileError> {
+    let mut buffer = [0u8; HANDSHAKE_FRAME_SIZE];
+    let result = read_next_payload::<SenderMessageV1, _>(stream, &mut buffer, 0)?;
+    result.check_session(session_id, result.protocol_version)?;
+    let protocol_version = result.protocol_version;
+    let end = result.total_bytes_read;
+    let next = result.next_payload_index;
+    let message = match result.message {
+        SenderMessageV1::NoiseHandshake(message) => message,
+        message => {
+            return Err(SendFileError::UnexpectedMessage {
+                received: format!("{:?}", message),
+                expected: String::from("NoiseHandshake"),
+            });
+        }
+    };
+    let received = next
+        .map(|index| buffer[index..end].to_vec())
+        .unwrap_or_default();
+    Ok((message, protocol_version, received))
+}
+
+/// Connection that passes bytes through until a Noise handshake completes on it, then encrypts
+/// them.
+///
+/// Encrypted bytes are sent as records of at most [MAX_NOISE_MESSAGE] bytes, each preceded by
+/// its length. A write interrupted by a timeout keeps the rest of its record, sent before
+/// anything else by the next write or flush, so retrying writes that time out never corrupts the
+/// stream.
+pub struct NoiseStream<S> {
+    inner: S,
+    /// Channel once the handshake completed, `None` before.
+    transport: Option<TransportState>,
+    /// Peer of the channel, for data connections to check.
+    peer: Option<NoisePeer>,
+    /// Encrypted bytes read from `inner` and not decrypted yet.
+    incoming: Vec<u8>,
+    /// Decrypted record, read from `plaintext_pos` on.
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+    /// Encrypted record, written to `inner` up to `outgoing_pos`.
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+    /// Records the plaintext of the connection while `--capture` is on.
+    tap: Option<CaptureTap>,
+    /// Plaintext handed back with [Self::unread], read before anything else.
+    unread: Vec<u8>,
+}
+
+impl<S> NoiseStream<S> {
+    /// Wraps `inner`, passing bytes through until [Self::start] is called.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            transport: None,
+            peer: None,
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+            outgoing: Vec::new(),
+            outgoing_pos: 0,
+            tap: CaptureTap::global(),
+            unread: Vec::new(),
+        }
+    }
+
+    /// Returns the wrapped connection.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the peer of the channel, `None` until it is started.
+    pub fn peer(&self) -> Option<&NoisePeer> {
+        self.peer.as_ref()
+    }
+
+    /// Hands `bytes`, read from the connection but not used, back to it: they are read again
+    /// before anything else.
+    pub fn unread(&mut self, bytes: &[u8]) {
+        self.unread.splice(0..0, bytes.iter().copied());
+    }
+
+    /// Encrypts everything from now on with the channel of the completed `handshake`.
+    /// `received` holds the bytes already read past the last handshake message, encrypted.
+    pub fn start(&mut self, handshake: NoiseHandshake, received: &[u8]) -> Result<(), NoiseError> {
+        let remote = handshake
+            .state
+            .get_remote_static()
+            .and_then(to_key)
+            .ok_or(NoiseError::MissingStaticKey)?;
+        self.transport = Some(handshake.state.into_transport_mode()?);
+        debug!("Noise channel up with peer key {}", to_hex(&remote));
+        self.peer = Some(NoisePeer {
+            key: handshake.key,
+            remote,
+        });
+        if let Some(tap) = &self.tap {
+            // They were recorded as they were read, but are records of the channel
+            tap.retract(Direction::Received, received.len());
+        }
+        self.incoming = received.to_vec();
+        Ok(())
+    }
+}
+
+impl NoiseStream<MaybeTlsStream> {
+    /// Returns whether data from the peer can be read, without waiting for it, see
+    /// [MaybeTlsStream::has_pending_data].
+    pub fn has_pending_data(&mut self) -> io::Result<bool> {
+        Ok(!self.unread.is_empty()
+            || self.plaintext_pos < self.plaintext.len()
+            || !self.incoming.is_empty()
+            || self.inner.has_pending_data()?)
+    }
+}
+
+impl<S: Read> Read for NoiseStream<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if !self.unread.is_empty() {
+            // Recorded when they were first read
+            let len = buffer.len().min(self.unread.len());
+            buffer[..len].copy_from_slice(&self.unread[..len]);
+            self.unread.drain(..len);
+            return Ok(len);
+        }
+        let len = self.read_plaintext(buffer)?;
+        if let Some(tap) = &self.tap {
+            tap.record(Direction::Received, &buffer[..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl<S: Read> NoiseStream<S> {
+    /// Reads the bytes of the connection, decrypted once the channel is started.
+    fn read_plaintext(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let Some(transport) = &mut self.transport else {
+            return self.inner.read(buffer);
+        };
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        while self.plaintext_pos == self.plaintext.len() {
+            if self.incoming.len() >= RECORD_LEN_SIZE {
+                let len = u16::from_be_bytes([self.incoming[0], self.incoming[1]]) as usize;
+                let end = RECORD_LEN_SIZE + len;
+                if self.incoming.len() >= end {
+                    self.plaintext.resize(len, 0);
+                    let plaintext_len = transport
+                        .read_message(&self.incoming[RECORD_LEN_SIZE..end], &mut self.plaintext)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    self.plaintext.truncate(plaintext_len);
+                    self.plaintext_pos = 0;
+                    self.incoming.drain(..end);
+                    continue;
+                }
+            }
+
+            let filled = self.incoming.len();
+            self.incoming
+                .resize(filled + RECORD_LEN_SIZE + MAX_NOISE_MESSAGE, 0);
+            let result = self.inner.read(&mut self.incoming[filled..]);
+            self.incoming
+                .truncate(filled + result.as_ref().copied().unwrap_or(0));
+            match result? {
+                0 if filled == 0 => return Ok(0),
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed in the middle of an encrypted record",
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        let len = buffer.len().min(self.plaintext.len() - self.plaintext_pos);
+        buffer[..len].copy_from_slice(&self.plaintext[self.plaintext_pos..][..len]);
+        self.plaintext_pos += len;
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for NoiseStream<S> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let len = self.write_plaintext(buffer)?;
+        if let Some(tap) = &self.tap {
+            tap.record(Direction::Sent, &buffer[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        write_record(&mut self.inner, &self.outgoing, &mut self.outgoing_pos)?;
+        self.inner.flush()
+    }
+}
+
+impl<S: Write> NoiseStream<S> {
+    /// Writes bytes to the connection, encrypted once the channel is started.
+    fn write_plaintext(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let Some(transport) = &mut self.transport else {
+            return self.inner.write(buffer);
+        };
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        // A record left half written by a timeout goes out before the next one
+        write_record(&mut self.inner, &self.outgoing, &mut self.outgoing_pos)?;
+
+        let len = buffer.len().min(MAX_RECORD_PLAINTEXT);
+        self.outgoing.resize(RECORD_LEN_SIZE + len + TAG_LEN, 0);
+        let sealed = transport
+            .write_message(&buffer[..len], &mut self.outgoing[RECORD_LEN_SIZE..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.outgoing[..RECORD_LEN_SIZE].copy_from_slice(&(sealed as u16).to_be_bytes());
+        self.outgoing.truncate(RECORD_LEN_SIZE + sealed);
+        self.outgoing_pos = 0;
+
+        match write_record(&mut self.inner, &self.outgoing, &mut self.outgoing_pos) {
+            // The bytes are in the record, whose rest goes out with the next write or flush
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(len)
+            }
+            result => result.map(|()| len),
+        }
+    }
+}
+
+impl<S: Connection> Connection for NoiseStream<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// Writes `record` to `inner` from `written` on, advancing it.
+fn write_record<W: Write>(inner: &mut W, record: &[u8], written: &mut usize) -> io::Result<()> {
+    while *written < record.len() {
+        match inner.write(&record[*written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => *written += len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn params(pattern: &str) -> snow::params::NoiseParams {
+    pattern.parse().expect("valid Noise pattern")
+}
+
+fn to_key(bytes: &[u8]) -> Option<[u8; 32]> {
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, net::TcpStream, thread};
+
+    use crate::{identity::Identity, transport::CURRENT_PROTOCOL_VERSION};
+
+    use super::*;
+
+    const SESSION: [u8; SESSION_ID_SIZE] = [5; SESSION_ID_SIZE];
+
+    #[test]
+    fn test_identity_keys_match_their_noise_keys() {
+        let identity = Identity::from_seed([7; 32]);
+        let key = NoiseKey::new(Some(&identity)).unwrap();
+        let peer = NoisePeer {
+            key: NoiseKey::new(None).unwrap(),
+            remote: key.public_key(),
+        };
+        peer.check_identity(&identity.public_key()).unwrap();
+        let stranger = Identity::from_seed([8; 32]);
+        assert!(peer.check_identity(&stranger.public_key()).is_err());
+
+        // The handshake only completes if the converted keys form a key pair
+        let (mut sender, mut receiver) = channel(&key, &NoiseKey::new(None).unwrap());
+        assert_eq!(
+            receiver.peer().unwrap().remote_key(),
+            &key.public_key(),
+            "the receiver sees the identity's key"
+        );
+        sender.write_all(b"ping").unwrap();
+        sender.flush().unwrap();
+        let mut buffer = [0u8; 4];
+        receiver.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+    }
+
+    /// Runs the handshake connection's Noise handshake between `sender` and `receiver` over
+    /// loopback, returning both ends.
+    fn channel(
+        sender: &NoiseKey,
+        receiver: &NoiseKey,
+    ) -> (NoiseStream<TcpStream>, NoiseStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            let accepted = scope.spawn(move || {
+                let mut stream = NoiseStream::new(listener.accept().unwrap().0);
+                let mut handshake = NoiseHandshake::responder(receiver, b"prologue").unwrap();
+                let (message, _, _) = read_sender_message(&mut stream, None).unwrap();
+                handshake.read_message(&message).unwrap();
+                let reply = ReceiverMessageV1::NoiseHandshake(handshake.write_message().unwrap());
+                let mut buffer = [0u8; HANDSHAKE_FRAME_SIZE];
+                let payload = reply.to_bytes(&mut buffer).unwrap();
+                stream
+                    .write_all(&attach_headers_for(2, None, payload))
+                    .unwrap();
+                let (message, _, received) = read_sender_message(&mut stream, None).unwrap();
+                handshake.read_message(&message).unwrap();
+                stream.start(handshake, &received).unwrap();
+                stream
+            });
+            let mut stream = NoiseStream::new(TcpStream::connect(address).unwrap());
+            let handshake = NoiseHandshake::initiator(sender, b"prologue").unwrap();
+            initiate(&mut stream, handshake).unwrap();
+            (stream, accepted.join().unwrap())
+        })
+    }
+
+    #[test]
+    fn test_data_connections_are_encrypted_with_the_pinned_keys() {
+        let (sender_key, receiver_key) =
+            (NoiseKey::new(None).unwrap(), NoiseKey::new(None).unwrap());
+        let (sender, receiver) = channel(&sender_key, &receiver_key);
+        let sender_peer = sender.peer().unwrap().clone();
+        let receiver_peer = receiver.peer().unwrap().clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        thread::scope(|scope| {
+            let served = scope.spawn(|| {
+                let mut stream = NoiseStream::new(listener.accept().unwrap().0);
+                sender_peer.accept(&mut stream, Some(&SESSION)).unwrap();
+                // Larger than a record
+                stream.write_all(&data).unwrap();
+                stream.flush().unwrap();
+            });
+            let mut stream = NoiseStream::new(TcpStream::connect(address).unwrap());
+            receiver_peer
+                .connect(&mut stream, CURRENT_PROTOCOL_VERSION, Some(&SESSION))
+                .unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            served.join().unwrap();
+            assert_eq!(received, data);
+        });
+
+        // A host holding another key can't open data connections
+        let impostor = NoisePeer {
+            key: NoiseKey::new(None).unwrap(),
+            remote: *receiver_peer.remote_key(),
+        };
+        thread::scope(|scope| {
+            let served = scope.spawn(|| {
+                let mut stream = NoiseStream::new(listener.accept().unwrap().0);
+                sender_peer.accept(&mut stream, Some(&SESSION))
+            });
+            let mut stream = NoiseStream::new(TcpStream::connect(address).unwrap());
+            let _ = impostor.connect(&mut stream, CURRENT_PROTOCOL_VERSION, Some(&SESSION));
+            drop(stream);
+            assert!(served.join().unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn test_tampered_prologue_fails_the_handshake() {
+        let mut initiator = NoiseHandshake::initiator(&NoiseKey::new(None).unwrap(), b"a").unwrap();
+        let mut responder = NoiseHandshake::responder(&NoiseKey::new(None).unwrap(), b"b").unwrap();
+        responder
+            .read_message(&initiator.write_message().unwrap())
+            .unwrap();
+        let reply = responder.write_message().unwrap();
+        assert!(initiator.read_message(&reply).is_err());
+    }
+
+    #[test]
+    fn test_paired_handshake_needs_the_session_key() {
+        let run = |initiator_key: &[u8; 32], responder_key: &[u8; 32]| {
+            let mut initiator =
+                NoiseHandshake::paired_initiator(&NoiseKey::new(None).unwrap(), initiator_key)
+                    .unwrap();
+            let mut responder =
+                NoiseHandshake::paired_responder(&NoiseKey::new(None).unwrap(), responder_key)
+                    .unwrap();
+            responder.read_message(&initiator.write_message()?)?;
+            initiator.read_message(&responder.write_message()?)?;
+            responder.read_message(&initiator.write_message()?)
+        };
+        run(&[1; 32], &[1; 32]).unwrap();
+        assert!(run(&[1; 32], &[2; 32]).is_err());
+    }
+}
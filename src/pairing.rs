@@ -0,0 +1,547 @@
+//! Pairing of a sender with a receiver through a one-time code (`receive --pair`, `send --code`).
+//!
+//! The receiver prints a short code like `7-orbit-velvet`, which its user passes on to whoever
+//! sends. Both peers run SPAKE2 over ristretto255 with the code as password: the sender opens the
+//! handshake connection with its blinded key ([PairingV1]), the receiver answers with its own
+//! ([PairingReplyV1]), and only peers that used the same code derive the same session key. Each
+//! side proves it holds the key before anything about the file is sent, so a wrong code fails on
+//! both ends, and the messages give away nothing to test other codes with: an attacker gets one
+//! guess per connection, and the receiver gives up on its code after a failed pairing.
+//!
+//! The session key then keys a Noise_XXpsk3 handshake (see [crate::noise]), whose channel
+//! carries the handshake and every message after it, and whose static keys pin the data
+//! connections as with `--noise`.
+//!
+//! `sendfile pair` pairs two machines for good the same way: the one running it without
+//! arguments prints the code and waits, the other connects with `sendfile pair HOST CODE`
+//! ([introduce_to_receiver], [introduce_to_sender]). Over the channel, each introduces itself
+//! with its identity key and a name ([IntroductionV1]), and the key must be the one its channel
+//! was set up with. Both then store the other as a trusted peer (see [crate::peers]), which
+//! receivers only accepting paired senders check the sender's key against.
+
+use std::io::{Read, Write};
+
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+};
+use thiserror::Error;
+
+use crate::{
+    connection::read_next_payload,
+    identity::Identity,
+    noise::{self, NoiseHandshake, NoiseKey, NoiseStream},
+    secrets::{Secret, SecretKey, SecretString},
+    stream::{error::SendFileError, receive::pair_with_sender},
+    transport::{
+        attach_headers_for, IntroductionV1, PairingConfirmV1, PairingV1, ReceiverMessageV1,
+        SenderMessageV1, TEXT_FRAMING_PROTOCOL_VERSION,
+    },
+};
+
+/// Words of the pairing codes, one byte of the code each.
+#[rustfmt::skip]
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adobe", "agent", "alarm", "album", "alpine",
+    "amber", "anchor", "angle", "ankle", "apple", "apron", "arena", "arrow",
+    "aspen", "atlas", "attic", "audio", "autumn", "avenue", "bacon", "badge",
+    "bagel", "bakery", "bamboo", "banjo", "barley", "basin", "basket", "beacon",
+    "bison", "blanket", "blossom", "bonfire", "border", "bottle", "bramble", "breeze",
+    "brick", "bridge", "bronze", "bucket", "buffalo", "bugle", "butter", "cabin",
+    "cactus", "camera", "canal", "candle", "canoe", "canyon", "carbon", "cargo",
+    "carpet", "castle", "cedar", "cellar", "chalk", "cherry", "chimney", "cider",
+    "cinema", "circus", "citrus", "clover", "cobalt", "cocoa", "comet", "copper",
+    "coral", "cotton", "cougar", "cradle", "crater", "crayon", "cricket", "crystal",
+    "cycle", "dagger", "daisy", "dancer", "delta", "denim", "desert", "diesel",
+    "dolphin", "domino", "dragon", "drum", "eagle", "easel", "ebony", "echo",
+    "elbow", "ember", "emerald", "engine", "falcon", "feather", "fender", "ferry",
+    "fiddle", "fjord", "flannel", "flute", "forest", "fossil", "fountain", "fox",
+    "galaxy", "garden", "garlic", "geyser", "ginger", "glacier", "goblet", "granite",
+    "gravel", "guitar", "hammock", "harbor", "harvest", "hazel", "helmet", "hermit",
+    "hickory", "honey", "horizon", "husky", "iceberg", "igloo", "indigo", "iris",
+    "island", "ivory", "jacket", "jaguar", "jasmine", "jelly", "jigsaw", "jungle",
+    "kayak", "kernel", "kettle", "kiwi", "lagoon", "lantern", "laser", "lemon",
+    "lilac", "linen", "lizard", "lobster", "locket", "magnet", "mango", "maple",
+    "marble", "meadow", "melon", "meteor", "mirror", "mitten", "mosaic", "muffin",
+    "napkin", "nectar", "needle", "nickel", "noodle", "nutmeg", "oasis", "ocean",
+    "olive", "onion", "opal", "orbit", "orchid", "otter", "oyster", "paddle",
+    "palace", "panda", "paper", "parrot", "pebble", "pelican", "pepper", "piano",
+    "pickle", "pigeon", "pillow", "pinecone", "planet", "pocket", "pony", "poppy",
+    "puzzle", "quartz", "quiver", "rabbit", "radar", "raisin", "ranch", "raven",
+    "ribbon", "river", "rocket", "saddle", "saffron", "salmon", "sandal", "satin",
+    "sequoia", "shadow", "silver", "sketch", "sparrow", "spider", "spruce", "statue",
+    "summit", "tablet", "tango", "teapot", "thistle", "thunder", "timber", "tomato",
+    "topaz", "torch", "tractor", "trumpet", "tulip", "tundra", "turtle", "umbrella",
+    "unicorn", "valley", "vanilla", "velvet", "violin", "volcano", "waffle", "walnut",
+    "walrus", "wander", "willow", "window", "wizard", "yogurt", "zebra", "zipper",
+];
+
+/// Highest number opening a pairing code.
+const MAX_CODE_NUMBER: u16 = 99;
+
+/// Context of the key derivations of a pairing, see [blake3::derive_key].
+const PASSWORD_CONTEXT: &str = "sendfile pairing v1 password";
+const GENERATOR_M_CONTEXT: &str = "sendfile pairing v1 generator M";
+const GENERATOR_N_CONTEXT: &str = "sendfile pairing v1 generator N";
+const TRANSCRIPT_CONTEXT: &str = "sendfile pairing v1 transcript";
+const SESSION_KEY_CONTEXT: &str = "sendfile pairing v1 session key";
+const SENDER_CONFIRMATION_CONTEXT: &str = "sendfile pairing v1 sender confirmation";
+const RECEIVER_CONFIRMATION_CONTEXT: &str = "sendfile pairing v1 receiver confirmation";
+
+/// Buffer size of the frames of a pairing, which are under a hundred bytes.
+const PAIRING_FRAME_SIZE: usize = 256;
+
+/// Longest name a peer introduces itself with, in characters, longer ones are cut.
+pub const MAX_PEER_NAME_LEN: usize = 64;
+
+/// Buffer size of the frames of an introduction, [MAX_PEER_NAME_LEN] characters of the name
+/// included.
+const INTRODUCTION_FRAME_SIZE: usize = 512;
+
+/// Errors that can occur while pairing with a one-time code.
+#[derive(Error, Debug)]
+pub enum PairingError {
+    /// The OS random number generator failed.
+    #[error("Failed to generate a pairing secret: {0}")]
+    Random(String),
+    /// The code is not a number followed by two words of the list.
+    #[error("Invalid pairing code {0:?}, expected a code like 7-orbit-velvet")]
+    InvalidCode(String),
+    /// The peer's blinded key is not a valid point.
+    #[error("Peer sent an invalid pairing key")]
+    InvalidElement,
+    /// The peer's confirmation doesn't match, it used another code.
+    #[error("Pairing failed, the peer used another code")]
+    WrongCode,
+    /// The sender did not open its connection with a pairing.
+    #[error("Sender did not pair, it must send with this receiver's code (send --code)")]
+    Unpaired,
+    /// The sender did not confirm the pairing, which burns the code as a wrong code would.
+    #[error("Sender did not confirm the pairing, it may have used another code: {0}")]
+    Unconfirmed(String),
+    /// The peer paired but did not introduce itself, it is not running `sendfile pair`.
+    #[error("Peer did not introduce itself, it must run sendfile pair: {0}")]
+    Unintroduced(String),
+}
+
+/// Side of a pairing, each blinding its key with its own generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Sender,
+    Receiver,
+}
+
+/// A pairing in progress: this side's secret, waiting for the peer's element.
+pub struct Pairing {
+    side: Side,
+    password: Secret<Scalar>,
+    secret: Secret<Scalar>,
+    element: [u8; 32],
+}
+
+impl Pairing {
+    /// Starts the sender's side of a pairing with `code`.
+    pub fn sender(code: &str) -> Result<Self, PairingError> {
+        Self::new(Side::Sender, code)
+    }
+
+    /// Starts the receiver's side of a pairing with `code`.
+    pub fn receiver(code: &str) -> Result<Self, PairingError> {
+        Self::new(Side::Receiver, code)
+    }
+
+    fn new(side: Side, code: &str) -> Result<Self, PairingError> {
+        let password = password_scalar(SecretString::new(parse_code(code)?).expose());
+        let seed = Secret::<[u8; 64]>::random().map_err(|e| PairingError::Random(e.to_string()))?;
+        let secret = Secret::new(Scalar::from_bytes_mod_order_wide(seed.expose()));
+        let blinding = match side {
+            Side::Sender => generator(GENERATOR_M_CONTEXT),
+            Side::Receiver => generator(GENERATOR_N_CONTEXT),
+        };
+        let element = (RISTRETTO_BASEPOINT_POINT * secret.expose() + blinding * password.expose())
+            .compress()
+            .to_bytes();
+        Ok(Self {
+            side,
+            password,
+            secret,
+            element,
+        })
+    }
+
+    /// Returns the blinded key sent to the peer.
+    pub fn element(&self) -> [u8; 32] {
+        self.element
+    }
+
+    /// Derives the session keys from the blinded key of the peer.
+    pub fn finish(self, peer_element: &[u8; 32]) -> Result<PairingKeys, PairingError> {
+        let peer = CompressedRistretto(*peer_element)
+            .decompress()
+            .ok_or(PairingError::InvalidElement)?;
+        let peer_blinding = match self.side {
+            Side::Sender => generator(GENERATOR_N_CONTEXT),
+            Side::Receiver => generator(GENERATOR_M_CONTEXT),
+        };
+        let shared = (peer - peer_blinding * self.password.expose()) * self.secret.expose();
+        let (sender_element, receiver_element) = match self.side {
+            Side::Sender => (&self.element, peer_element),
+            Side::Receiver => (peer_element, &self.element),
+        };
+
+        let mut transcript = blake3::Hasher::new_derive_key(TRANSCRIPT_CONTEXT);
+        transcript.update(sender_element);
+        transcript.update(receiver_element);
+        transcript.update(shared.compress().as_bytes());
+        transcript.update(self.password.expose().as_bytes());
+        let transcript = transcript.finalize();
+        let confirmation = |context| {
+            let key = blake3::derive_key(context, transcript.as_bytes());
+            blake3::keyed_hash(&key, transcript.as_bytes())
+        };
+        Ok(PairingKeys {
+            side: self.side,
+            session_key: SecretKey::new(blake3::derive_key(
+                SESSION_KEY_CONTEXT,
+                transcript.as_bytes(),
+            )),
+            sender_confirmation: confirmation(SENDER_CONFIRMATION_CONTEXT),
+            receiver_confirmation: confirmation(RECEIVER_CONFIRMATION_CONTEXT),
+        })
+    }
+}
+
+/// Keys of a completed pairing, only shared with the peer if both used the same code.
+pub struct PairingKeys {
+    side: Side,
+    session_key: SecretKey,
+    sender_confirmation: blake3::Hash,
+    receiver_confirmation: blake3::Hash,
+}
+
+impl PairingKeys {
+    /// Returns the key the Noise channel of the transfer is bound to.
+    pub fn session_key(&self) -> &[u8; 32] {
+        self.session_key.expose()
+    }
+
+    /// Returns the confirmation this side sends to prove it holds the session key.
+    pub fn confirmation(&self) -> [u8; 32] {
+        match self.side {
+            Side::Sender => self.sender_confirmation.into(),
+            Side::Receiver => self.receiver_confirmation.into(),
+        }
+    }
+
+    /// Checks the confirmation of the peer, in constant time.
+    pub fn check_peer(&self, confirmation: &[u8; 32]) -> Result<(), PairingError> {
+        let expected = match self.side {
+            Side::Sender => self.receiver_confirmation,
+            Side::Receiver => self.sender_confirmation,
+        };
+        if expected == blake3::Hash::from(*confirmation) {
+            Ok(())
+        } else {
+            Err(PairingError::WrongCode)
+        }
+    }
+}
+
+/// Generates a new one-time code, a number and two words like `7-orbit-velvet`.
+pub fn generate_code() -> Result<String, PairingError> {
+    let mut random = [0u8; 4];
+    getrandom::fill(&mut random).map_err(|e| PairingError::Random(e.to_string()))?;
+    let number = u16::from_le_bytes([random[0], random[1]]) % MAX_CODE_NUMBER + 1;
+    Ok(format!(
+        "{}-{}-{}",
+        number, WORDS[random[2] as usize], WORDS[random[3] as usize]
+    ))
+}
+
+/// Returns `code` in the form both peers derive the password from, so case and surrounding
+/// whitespace don't matter, or an error if it is not a code [generate_code] could return.
+pub fn parse_code(code: &str) -> Result<String, PairingError> {
+    let normalized = code.trim().to_lowercase();
+    let invalid = || PairingError::InvalidCode(code.to_string());
+    let mut parts = normalized.split('-');
+    let number: u16 = parts
+        .next()
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(invalid)?;
+    let words_known = parts.by_ref().take(2).filter(|w| WORDS.contains(w)).count() == 2;
+    if !(1..=MAX_CODE_NUMBER).contains(&number) || !words_known || parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(normalized)
+}
+
+fn password_scalar(code: &str) -> Secret<Scalar> {
+    let mut wide = Secret::new([0u8; 64]);
+    blake3::Hasher::new_derive_key(PASSWORD_CONTEXT)
+        .update(code.as_bytes())
+        .finalize_xof()
+        .fill(wide.expose_mut());
+    Secret::new(Scalar::from_bytes_mod_order_wide(wide.expose()))
+}
+
+/// Returns a generator nobody knows the discrete logarithm of, hashed from `context`.
+fn generator(context: &str) -> RistrettoPoint {
+    let mut wide = [0u8; 64];
+    blake3::Hasher::new_derive_key(context)
+        .finalize_xof()
+        .fill(&mut wide);
+    RistrettoPoint::from_uniform_bytes(&wide)
+}
+
+/// Pairs with the receiver connected on `stream` using its one-time `code`, then runs the Noise
+/// handshake keyed by the pairing with the static `key`: every later message on `stream`,
+/// starting with the handshake, is encrypted.
+pub fn pair_with_receiver<S: Read + Write>(
+    stream: &mut NoiseStream<S>,
+    code: &str,
+    key: &NoiseKey,
+) -> Result<(), SendFileError> {
+    let pairing = Pairing::sender(code)?;
+    let mut buffer = [0u8; PAIRING_FRAME_SIZE];
+    let message = SenderMessageV1::Pairing(PairingV1 {
+        element: pairing.element(),
+    });
+    // Framed as the handshake, the receiver has not picked a version yet
+    let payload = message.to_bytes(&mut buffer)?;
+    stream.write_all(&attach_headers_for(
+        TEXT_FRAMING_PROTOCOL_VERSION,
+        None,
+        payload,
+    ))?;
+    stream.flush()?;
+
+    let reply = read_next_payload::<ReceiverMessageV1, _>(stream, &mut buffer, 0)
+        .map_err(|e| {
+            SendFileError::ConnectionFailed(format!(
+                "No pairing reply from the receiver, which may not be waiting for a code \
+                 (receive --pair): {e}"
+            ))
+        })?
+        .message;
+    // The receiver waits for the confirmation, nothing was read past its reply
+    let reply = match reply {
+        ReceiverMessageV1::PairingReply(reply) => reply,
+        message => {
+            return Err(SendFileError::UnexpectedMessage {
+                received: format!("{:?}", message),
+                expected: String::from("PairingReply"),
+            });
+        }
+    };
+    let keys = pairing.finish(&reply.element)?;
+    keys.check_peer(&reply.confirmation)?;
+
+    let message = SenderMessageV1::PairingConfirm(PairingConfirmV1 {
+        confirmation: keys.confirmation(),
+    });
+    let payload = message.to_bytes(&mut buffer)?;
+    stream.write_all(&attach_headers_for(
+        TEXT_FRAMING_PROTOCOL_VERSION,
+        None,
+        payload,
+    ))?;
+    noise::initiate(
+        stream,
+        NoiseHandshake::paired_initiator(key, keys.session_key())?,
+    )
+}
+
+/// Pairs with the machine at the other end of `stream` that printed `code` (`sendfile pair HOST
+/// CODE`), then introduces this machine as `name`, owner of `identity`, and reads its
+/// introduction.
+///
+/// # Returns
+///
+/// The introduction of the other machine, whose key is the one its channel was set up with.
+pub fn introduce_to_receiver<S: Read + Write>(
+    stream: S,
+    code: &str,
+    identity: &Identity,
+    name: &str,
+) -> Result<IntroductionV1, SendFileError> {
+    let mut stream = NoiseStream::new(stream);
+    pair_with_receiver(&mut stream, code, &NoiseKey::new(Some(identity))?)?;
+    let message = SenderMessageV1::Introduction(introduction(identity, name));
+    let mut buffer = [0u8; INTRODUCTION_FRAME_SIZE];
+    let payload = message.to_bytes(&mut buffer)?;
+    stream.write_all(&attach_headers_for(
+        TEXT_FRAMING_PROTOCOL_VERSION,
+        None,
+        payload,
+    ))?;
+    stream.flush()?;
+
+    let reply = read_next_payload::<ReceiverMessageV1, _>(&mut stream, &mut buffer, 0)
+        .map_err(|e| PairingError::Unintroduced(e.to_string()))?
+        .message;
+    let ReceiverMessageV1::Introduction(peer) = reply else {
+        return Err(PairingError::Unintroduced(format!("received {:?}", reply)).into());
+    };
+    check_introduction(&stream, peer)
+}
+
+/// Pairs with the machine connected on `stream` using this machine's one-time `code`
+/// (`sendfile pair`), then reads its introduction and introduces this machine as `name`, owner
+/// of `identity`.
+///
+/// Once the other machine has seen the pairing reply, a failure is final, see
+/// [pair_with_sender].
+///
+/// # Returns
+///
+/// The introduction of the other machine, whose key is the one its channel was set up with.
+pub fn introduce_to_sender<S: Read + Write>(
+    stream: S,
+    code: &str,
+    identity: &Identity,
+    name: &str,
+) -> Result<IntroductionV1, SendFileError> {
+    let mut stream = NoiseStream::new(stream);
+    pair_with_sender(&mut stream, code, &NoiseKey::new(Some(identity))?)?;
+    let mut buffer = [0u8; INTRODUCTION_FRAME_SIZE];
+    // The sender waits for the reply, nothing was read past its introduction
+    let message = read_next_payload::<SenderMessageV1, _>(&mut stream, &mut buffer, 0)
+        .map_err(|e| PairingError::Unintroduced(e.to_string()))?
+        .message;
+    let SenderMessageV1::Introduction(peer) = message else {
+        return Err(PairingError::Unintroduced(format!("received {:?}", message)).into());
+    };
+    let peer = check_introduction(&stream, peer)?;
+
+    let reply = ReceiverMessageV1::Introduction(introduction(identity, name));
+    let payload = reply.to_bytes(&mut buffer)?;
+    stream.write_all(&attach_headers_for(
+        TEXT_FRAMING_PROTOCOL_VERSION,
+        None,
+        payload,
+    ))?;
+    stream.flush()?;
+    Ok(peer)
+}
+
+/// Returns the introduction of the owner of `identity` as `name`, cut to [MAX_PEER_NAME_LEN].
+fn introduction(identity: &Identity, name: &str) -> IntroductionV1 {
+    IntroductionV1 {
+        public_key: identity.public_key(),
+        name: name.chars().take(MAX_PEER_NAME_LEN).collect(),
+    }
+}
+
+/// Checks that the key `peer` introduced itself with is the one of the channel on `stream`, and
+/// cuts its name to [MAX_PEER_NAME_LEN].
+fn check_introduction<S>(
+    stream: &NoiseStream<S>,
+    mut peer: IntroductionV1,
+) -> Result<IntroductionV1, SendFileError> {
+    let channel = stream.peer().ok_or_else(|| {
+        PairingError::Unintroduced(String::from("no channel was set up with the peer"))
+    })?;
+    channel.check_identity(&peer.public_key)?;
+    peer.name = peer.name.trim().chars().take(MAX_PEER_NAME_LEN).collect();
+    Ok(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+
+    fn pair(sender_code: &str, receiver_code: &str) -> (PairingKeys, PairingKeys) {
+        let sender = Pairing::sender(sender_code).unwrap();
+        let receiver = Pairing::receiver(receiver_code).unwrap();
+        let (sender_element, receiver_element) = (sender.element(), receiver.element());
+        (
+            sender.finish(&receiver_element).unwrap(),
+            receiver.finish(&sender_element).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_peers_with_the_same_code_share_a_session_key() {
+        let (sender, receiver) = pair("7-orbit-velvet", " 7-Orbit-Velvet\n");
+        assert_eq!(sender.session_key(), receiver.session_key());
+        sender.check_peer(&receiver.confirmation()).unwrap();
+        receiver.check_peer(&sender.confirmation()).unwrap();
+        assert_ne!(
+            sender.confirmation(),
+            receiver.confirmation(),
+            "a confirmation can't be reflected back"
+        );
+
+        // Every pairing derives a new key
+        let (again, _) = pair("7-orbit-velvet", "7-orbit-velvet");
+        assert_ne!(again.session_key(), sender.session_key());
+    }
+
+    #[test]
+    fn test_another_code_fails_on_both_sides() {
+        let (sender, receiver) = pair("7-orbit-velvet", "7-orbit-violin");
+        assert_ne!(sender.session_key(), receiver.session_key());
+        assert!(matches!(
+            sender.check_peer(&receiver.confirmation()),
+            Err(PairingError::WrongCode)
+        ));
+        assert!(matches!(
+            receiver.check_peer(&sender.confirmation()),
+            Err(PairingError::WrongCode)
+        ));
+
+        let sender = Pairing::sender("7-orbit-velvet").unwrap();
+        assert!(matches!(
+            sender.finish(&[0xFF; 32]),
+            Err(PairingError::InvalidElement)
+        ));
+    }
+
+    #[test]
+    fn test_generated_codes_are_valid() {
+        for _ in 0..100 {
+            let code = generate_code().unwrap();
+            assert_eq!(parse_code(&code).unwrap(), code);
+        }
+        for invalid in [
+            "",
+            "orbit-velvet",
+            "0-orbit-velvet",
+            "100-orbit-velvet",
+            "7-orbit",
+            "7-orbit-velvett",
+            "7-orbit-velvet-acid",
+        ] {
+            assert!(
+                matches!(parse_code(invalid), Err(PairingError::InvalidCode(_))),
+                "{invalid:?} accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn test_paired_machines_exchange_identities() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let nas = Identity::from_seed([2; 32]);
+            introduce_to_sender(stream, "7-orbit-velvet", &nas, "nas").unwrap()
+        });
+
+        let laptop = Identity::from_seed([1; 32]);
+        let stream = TcpStream::connect(address).unwrap();
+        let nas = introduce_to_receiver(stream, "7-orbit-velvet", &laptop, "laptop").unwrap();
+        assert_eq!(nas.public_key, Identity::from_seed([2; 32]).public_key());
+        assert_eq!(nas.name, "nas");
+        let introduced = receiver.join().unwrap();
+        assert_eq!(introduced.public_key, laptop.public_key());
+        assert_eq!(introduced.name, "laptop");
+    }
+}
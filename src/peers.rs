@@ -0,0 +1,314 @@
+//! Trusted peers and the bundles used to exchange them.
+//!
+//! `sendfile peer export` writes a small JSON bundle with this host's public key, the addresses
+//! it can be reached at and its preferred transfer options. Importing that bundle on another
+//! machine with `sendfile peer import` adds it to the peers file
+//! (`~/.config/sendfile/peers.json` on Linux), after which transfers between the two machines
+//! are authenticated against the stored key.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    history::{from_hex, to_hex},
+    identity::{Identity, IdentityError},
+};
+
+/// Name of the peers file inside the config directory.
+const PEERS_FILE_NAME: &str = "peers.json";
+
+/// Version of the bundle format written by this build.
+pub const BUNDLE_FORMAT: u32 = 1;
+
+/// Errors that can occur while reading or writing peers and bundles.
+#[derive(Error, Debug)]
+pub enum PeerError {
+    /// The peers file or a bundle could not be read or written.
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The peers file or a bundle is not valid JSON.
+    #[error("Malformed peer data: {0}")]
+    Json(#[from] serde_json::Error),
+    /// A public key is not 32 hex encoded bytes.
+    #[error("Invalid public key {0:?}")]
+    InvalidKey(String),
+    /// The bundle was written by a newer version of sendfile.
+    #[error("Unsupported bundle format {0}, expected {BUNDLE_FORMAT}")]
+    UnsupportedFormat(u32),
+    /// The identity to export could not be loaded.
+    #[error("Identity error: {0}")]
+    Identity(#[from] IdentityError),
+    /// Another peer is already stored under the alias of a newly paired one.
+    #[error("A peer named {0:?} already exists, pick another alias with --name")]
+    NameTaken(String),
+}
+
+/// Transfer options a peer prefers, applied when sending to it unless overridden.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerOptions {
+    /// Block size in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_size: Option<u32>,
+    /// Number of concurrent connections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<u16>,
+    /// Whether blocks are compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<bool>,
+}
+
+/// A host this machine trusts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    /// Local alias of the peer.
+    pub name: String,
+    /// Hex encoded Ed25519 public key of the peer's identity.
+    pub public_key: String,
+    /// Host names or IP addresses the peer can be reached at, in order of preference.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Port the peer receives handshakes on, if not the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub options: PeerOptions,
+}
+
+impl Peer {
+    /// Returns the decoded public key.
+    pub fn key(&self) -> Result<[u8; 32], PeerError> {
+        parse_key(&self.public_key)
+    }
+}
+
+/// A [Peer] exported for import on another machine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerBundle {
+    /// Version of the bundle format, see [BUNDLE_FORMAT].
+    pub format: u32,
+    pub peer: Peer,
+}
+
+impl PeerBundle {
+    /// Creates a bundle describing the host owning `identity`.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Identity of this host.
+    /// * `name` - Alias suggested to the importing machine.
+    /// * `addresses` - Addresses this host can be reached at, in order of preference.
+    /// * `port` - Port this host receives on, `None` for the default one.
+    /// * `options` - Transfer options this host prefers.
+    pub fn export(
+        identity: &Identity,
+        name: String,
+        addresses: Vec<String>,
+        port: Option<u16>,
+        options: PeerOptions,
+    ) -> Self {
+        Self {
+            format: BUNDLE_FORMAT,
+            peer: Peer {
+                name,
+                public_key: to_hex(&identity.public_key()),
+                addresses,
+                port,
+                options,
+            },
+        }
+    }
+
+    /// Reads and validates the bundle at `path`.
+    pub fn read(path: &Path) -> Result<Self, PeerError> {
+        let bundle: Self = serde_json::from_slice(&fs::read(path)?)?;
+        if bundle.format != BUNDLE_FORMAT {
+            return Err(PeerError::UnsupportedFormat(bundle.format));
+        }
+        bundle.peer.key()?;
+        Ok(bundle)
+    }
+}
+
+/// The peers this machine trusts, as stored in the peers file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerRegistry {
+    pub peers: Vec<Peer>,
+}
+
+impl PeerRegistry {
+    /// Reads the peers file at `path`. A missing file has no peers.
+    pub fn load(path: &Path) -> Result<Self, PeerError> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the peers file at `path`, creating its directory if needed.
+    pub fn save(&self, path: &Path) -> Result<(), PeerError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut json = serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+
+        // Replace the file atomically so a crash never leaves it half written
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, json)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Adds `peer`, replacing any peer with the same name.
+    ///
+    /// # Returns
+    ///
+    /// The replaced peer, if any.
+    pub fn insert(&mut self, peer: Peer) -> Option<Peer> {
+        match self.peers.iter_mut().find(|p| p.name == peer.name) {
+            Some(existing) => Some(std::mem::replace(existing, peer)),
+            None => {
+                self.peers.push(peer);
+                None
+            }
+        }
+    }
+
+    /// Adds `peer`, paired with `sendfile pair`, replacing the peer with the same key: pairing
+    /// again with a machine updates it. Fails with [PeerError::NameTaken] rather than replacing
+    /// another peer stored under the same name.
+    ///
+    /// # Returns
+    ///
+    /// The replaced peer, if any.
+    pub fn insert_paired(&mut self, peer: Peer) -> Result<Option<Peer>, PeerError> {
+        let key = peer.key()?;
+        if self
+            .find(&peer.name)
+            .is_some_and(|existing| existing.key().ok() != Some(key))
+        {
+            return Err(PeerError::NameTaken(peer.name));
+        }
+        match self.peers.iter_mut().find(|p| p.key().ok() == Some(key)) {
+            Some(existing) => Ok(Some(std::mem::replace(existing, peer))),
+            None => {
+                self.peers.push(peer);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes the peer named `name`, returning it if it existed.
+    pub fn remove(&mut self, name: &str) -> Option<Peer> {
+        let index = self.peers.iter().position(|p| p.name == name)?;
+        Some(self.peers.remove(index))
+    }
+
+    /// Returns the peer named `name`.
+    pub fn find(&self, name: &str) -> Option<&Peer> {
+        self.peers.iter().find(|p| p.name == name)
+    }
+
+    /// Returns the peer whose identity is `key`.
+    pub fn find_by_key(&self, key: &[u8; 32]) -> Option<&Peer> {
+        self.peers.iter().find(|p| p.key().ok() == Some(*key))
+    }
+
+    /// Returns the peer reachable at `address`.
+    pub fn find_by_address(&self, address: &str) -> Option<&Peer> {
+        self.peers
+            .iter()
+            .find(|p| p.addresses.iter().any(|a| a == address))
+    }
+}
+
+/// Returns the default location of the peers file, if a config directory is known.
+pub fn default_peers_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sendfile").join(PEERS_FILE_NAME))
+}
+
+/// Decodes a hex encoded Ed25519 public key.
+fn parse_key(hex: &str) -> Result<[u8; 32], PeerError> {
+    from_hex(hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| PeerError::InvalidKey(hex.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle_import_into_registry() {
+        let dir = std::env::temp_dir().join(format!("sendfile_peers_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let identity = Identity::from_seed([3; 32]);
+        let bundle = PeerBundle::export(
+            &identity,
+            String::from("nas"),
+            vec![String::from("192.168.1.20"), String::from("nas.local")],
+            None,
+            PeerOptions {
+                concurrency: Some(4),
+                ..PeerOptions::default()
+            },
+        );
+        let bundle_path = dir.join("nas.json");
+        fs::write(&bundle_path, serde_json::to_vec(&bundle).unwrap()).unwrap();
+        let imported = PeerBundle::read(&bundle_path).unwrap();
+        assert_eq!(imported, bundle);
+
+        let peers_path = dir.join(PEERS_FILE_NAME);
+        let mut registry = PeerRegistry::load(&peers_path).unwrap();
+        assert!(registry.insert(imported.peer.clone()).is_none());
+        registry.save(&peers_path).unwrap();
+
+        let registry = PeerRegistry::load(&peers_path).unwrap();
+        assert_eq!(registry.find("nas"), Some(&imported.peer));
+        assert_eq!(
+            registry.find_by_key(&identity.public_key()),
+            Some(&imported.peer)
+        );
+        assert_eq!(registry.find_by_address("nas.local"), Some(&imported.peer));
+
+        let mut future = bundle;
+        future.format = BUNDLE_FORMAT + 1;
+        fs::write(&bundle_path, serde_json::to_vec(&future).unwrap()).unwrap();
+        assert!(matches!(
+            PeerBundle::read(&bundle_path),
+            Err(PeerError::UnsupportedFormat(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pairing_again_replaces_the_peer() {
+        let peer = |name: &str, seed| Peer {
+            name: String::from(name),
+            public_key: to_hex(&Identity::from_seed([seed; 32]).public_key()),
+            addresses: Vec::new(),
+            port: None,
+            options: PeerOptions::default(),
+        };
+        let mut registry = PeerRegistry::default();
+        assert!(registry.insert_paired(peer("nas", 1)).unwrap().is_none());
+        assert_eq!(
+            registry.insert_paired(peer("storage", 1)).unwrap(),
+            Some(peer("nas", 1))
+        );
+        assert_eq!(registry.peers, vec![peer("storage", 1)]);
+        assert!(matches!(
+            registry.insert_paired(peer("storage", 2)),
+            Err(PeerError::NameTaken(name)) if name == "storage"
+        ));
+    }
+}
@@ -0,0 +1,657 @@
+//! Protocol conformance tests.
+//!
+//! Every message the peers exchange is serialized from a fixed sample, framed in each of the
+//! [FRAMINGS] and compared with its golden frame in `tests/golden`, so a change to a message, the
+//! order of its fields or the framing fails here instead of breaking peers running another
+//! version. Each golden frame is also read back with [read_next_payload] and must decode to its
+//! sample, and every sample must walk the [Layouts] the Wireshark dissector is generated from.
+//!
+//! Golden frames are never edited: a message that has to change gets a new variant, and a framing
+//! that has to change a new protocol version. Frames of new messages are written by running the
+//! tests with `UPDATE_GOLDEN=1`, and checked in with them.
+
+use std::{
+    fs,
+    io::{self, Cursor},
+    path::PathBuf,
+};
+
+use crate::{
+    capabilities::{Capabilities, ChecksumAlgorithm, CompressionCodec},
+    connection::{read_next_payload, StreamReadError},
+    dissector::Layouts,
+    transport::{
+        attach_headers, attach_session_headers, attach_text_headers, AbortV1, AlgorithmsV1,
+        AuthenticationV1, BatchV1, BatchedMessageV1, BlockHashesRequestV1, BlockHashesV1,
+        BlockUnreadableV1, CancelV1, ClockV1, ConnHelloV1, DataV1, FileDataV1, FileEntryV1,
+        FileHeaderV1, FileListV1, FileRequestV1, FrameHeader, GoodbyeV1, HandshakeAckV1,
+        HandshakeRejectV1, HandshakeV1, HaveBlocksV1, IntroductionV1, MetadataV1, NoReceiptV1, NoiseHandshakeV1,
+        OfferResponseV1, PairingConfirmV1, PairingReplyV1, PairingV1, PingV1, PongV1, ProbeAckV1,
+        ProbeV1, ProgressV1, ProtocolVersionV1, ProtocolVersionsV1, PushAckV1, PushRangeV1,
+        ReceiptV1, ReceiverErrorV1, ReceiverMessageV1, RejectReasonV1, RequestRangeV1, RequestV1,
+        RetryAfterV1, SenderErrorV1, SenderMessageV1, SessionV1, ThrottleV1, TransferCompleteV1,
+        UdpBlockV1, UdpRequestV1, VerifyBlockV1, VerifyResponseV1, ZeroBlockV1,
+        CURRENT_PROTOCOL_VERSION, FRAME_FLAG_SESSION, FRAME_HEADER_SIZE, MAX_HEADER_SIZE,
+        SESSION_FRAMING_PROTOCOL_VERSION, SESSION_ID_SIZE, TEXT_FRAMING_PROTOCOL_VERSION,
+    },
+};
+
+/// Bytes of hex per line of a golden file.
+const GOLDEN_LINE_BYTES: usize = 32;
+
+const FILE_HASH: [u8; 32] = [0xAA; 32];
+
+/// Session id golden frames of version 3 are tagged with.
+const SESSION_ID: [u8; SESSION_ID_SIZE] = [0x5E; SESSION_ID_SIZE];
+
+/// Frames messages the way a protocol version does.
+type Framing = fn(&[u8]) -> Box<[u8]>;
+
+/// Framings read by [read_next_payload], with the directory of their golden frames: the text
+/// headers of version 1, the binary header of version 2 and the session-tagged header of
+/// version 3.
+const FRAMINGS: [(&str, Framing); 3] = [
+    ("", attach_text_headers),
+    ("v2/", attach_headers),
+    ("v3/", attach_sample_session_headers),
+];
+
+/// Frames a message of version 3, tagged with [SESSION_ID].
+fn attach_sample_session_headers(payload: &[u8]) -> Box<[u8]> {
+    attach_session_headers(&SESSION_ID, payload)
+}
+
+/// Name of the golden file of a sender message. Exhaustive, so a new message cannot be added
+/// without a golden frame.
+fn sender_golden_name(message: &SenderMessageV1) -> &'static str {
+    match message {
+        SenderMessageV1::Handshake(_) => "sender_v1_handshake",
+        SenderMessageV1::Data(_) => "sender_v1_data",
+        SenderMessageV1::Error(_) => "sender_v1_error",
+        SenderMessageV1::VerifyResponse(_) => "sender_v1_verify_response",
+        SenderMessageV1::BlockHashes(_) => "sender_v1_block_hashes",
+        SenderMessageV1::Authentication(_) => "sender_v1_authentication",
+        SenderMessageV1::BlockUnreadable(_) => "sender_v1_block_unreadable",
+        SenderMessageV1::FileHeader(_) => "sender_v1_file_header",
+        SenderMessageV1::Probe(_) => "sender_v1_probe",
+        SenderMessageV1::Session(_) => "sender_v1_session",
+        SenderMessageV1::ProtocolVersions(_) => "sender_v1_protocol_versions",
+        SenderMessageV1::NoiseHandshake(_) => "sender_v1_noise_handshake",
+        SenderMessageV1::Pairing(_) => "sender_v1_pairing",
+        SenderMessageV1::PairingConfirm(_) => "sender_v1_pairing_confirm",
+        SenderMessageV1::Metadata(_) => "sender_v1_metadata",
+        SenderMessageV1::UdpBlock(_) => "sender_v1_udp_block",
+        SenderMessageV1::ZeroBlock(_) => "sender_v1_zero_block",
+        SenderMessageV1::Ping(_) => "sender_v1_ping",
+        SenderMessageV1::Pong(_) => "sender_v1_pong",
+        SenderMessageV1::FileList(_) => "sender_v1_file_list",
+        SenderMessageV1::FileData(_) => "sender_v1_file_data",
+        SenderMessageV1::Abort(_) => "sender_v1_abort",
+        SenderMessageV1::Throttle(_) => "sender_v1_throttle",
+        SenderMessageV1::Cancel(_) => "sender_v1_cancel",
+        SenderMessageV1::Clock(_) => "sender_v1_clock",
+        SenderMessageV1::RetryAfter(_) => "sender_v1_retry_after",
+        SenderMessageV1::Introduction(_) => "sender_v1_introduction",
+        SenderMessageV1::Goodbye(_) => "sender_v1_goodbye",
+    }
+}
+
+/// Name of the golden file of a receiver message, see [sender_golden_name].
+fn receiver_golden_name(message: &ReceiverMessageV1) -> &'static str {
+    match message {
+        ReceiverMessageV1::Request(_) => "receiver_v1_request",
+        ReceiverMessageV1::Progress(_) => "receiver_v1_progress",
+        ReceiverMessageV1::TransferComplete(_) => "receiver_v1_transfer_complete",
+        ReceiverMessageV1::Error(_) => "receiver_v1_error",
+        ReceiverMessageV1::VerifyBlock(_) => "receiver_v1_verify_block",
+        ReceiverMessageV1::Receipt(_) => "receiver_v1_receipt",
+        ReceiverMessageV1::BlockHashesRequest(_) => "receiver_v1_block_hashes_request",
+        ReceiverMessageV1::OfferResponse(_) => "receiver_v1_offer_response",
+        ReceiverMessageV1::ProbeAck(_) => "receiver_v1_probe_ack",
+        ReceiverMessageV1::ConnHello(_) => "receiver_v1_conn_hello",
+        ReceiverMessageV1::ProtocolVersion(_) => "receiver_v1_protocol_version",
+        ReceiverMessageV1::NoiseHandshake(_) => "receiver_v1_noise_handshake",
+        ReceiverMessageV1::PairingReply(_) => "receiver_v1_pairing_reply",
+        ReceiverMessageV1::UdpRequest(_) => "receiver_v1_udp_request",
+        ReceiverMessageV1::SparseRequest(_) => "receiver_v1_sparse_request",
+        ReceiverMessageV1::Ping(_) => "receiver_v1_ping",
+        ReceiverMessageV1::Pong(_) => "receiver_v1_pong",
+        ReceiverMessageV1::FileRequest(_) => "receiver_v1_file_request",
+        ReceiverMessageV1::Batch(_) => "receiver_v1_batch",
+        ReceiverMessageV1::Algorithms(_) => "receiver_v1_algorithms",
+        ReceiverMessageV1::HandshakeAck(_) => "receiver_v1_handshake_ack",
+        ReceiverMessageV1::HandshakeReject(_) => "receiver_v1_handshake_reject",
+        ReceiverMessageV1::HaveBlocks(_) => "receiver_v1_have_blocks",
+        ReceiverMessageV1::PushRange(_) => "receiver_v1_push_range",
+        ReceiverMessageV1::PushAck(_) => "receiver_v1_push_ack",
+        ReceiverMessageV1::RequestRange(_) => "receiver_v1_request_range",
+        ReceiverMessageV1::Cancel(_) => "receiver_v1_cancel",
+        ReceiverMessageV1::Introduction(_) => "receiver_v1_introduction",
+        ReceiverMessageV1::Goodbye(_) => "receiver_v1_goodbye",
+        ReceiverMessageV1::NoReceipt(_) => "receiver_v1_no_receipt",
+    }
+}
+
+fn sender_samples() -> Vec<SenderMessageV1<'static>> {
+    vec![
+        SenderMessageV1::Handshake(HandshakeV1 {
+            file_hash: &FILE_HASH,
+            total_size: 10 * 1024 * 1024 + 17,
+            concurrency: 8,
+            file_name: "golden.bin",
+            block_size: 1024 * 1024,
+            software_version: "0.1.0",
+            capabilities: Capabilities::from_bits(0x0301_0F0F),
+        }),
+        SenderMessageV1::Data(DataV1 {
+            seq: 3,
+            checksum: 0xCBF4_3926,
+            file_hash: &FILE_HASH,
+            compressed: true,
+            data: b"123456789",
+        }),
+        SenderMessageV1::Error(SenderErrorV1 {
+            code: 404,
+            message: String::from("File hash mismatch"),
+        }),
+        SenderMessageV1::VerifyResponse(VerifyResponseV1 {
+            file_hash: FILE_HASH,
+            seq: 300,
+            valid: true,
+        }),
+        SenderMessageV1::BlockHashes(BlockHashesV1 {
+            file_hash: FILE_HASH,
+            start_seq: 128,
+            hashes: vec![[0x11; 32], [0x22; 32]],
+        }),
+        SenderMessageV1::Authentication(AuthenticationV1 {
+            file_hash: FILE_HASH,
+            timestamp: 1_760_000_000,
+            sender_key: [0x33; 32],
+            signature: vec![0x44; 64],
+        }),
+        SenderMessageV1::BlockUnreadable(BlockUnreadableV1 {
+            seq: 7,
+            reason: String::from("Input/output error (os error 5)"),
+        }),
+        SenderMessageV1::FileHeader(FileHeaderV1 {
+            file_hash: FILE_HASH,
+            bytes: b"%PDF-1.7\n".to_vec(),
+        }),
+        SenderMessageV1::Probe(ProbeV1 {
+            seq: 2,
+            echo: true,
+            payload: &[0x55; 16],
+        }),
+        SenderMessageV1::Session(SessionV1 {
+            session_id: [0x66; 16],
+            token: [0x77; 32],
+        }),
+        SenderMessageV1::ProtocolVersions(ProtocolVersionsV1 {
+            versions: vec![1, 2],
+        }),
+        SenderMessageV1::NoiseHandshake(NoiseHandshakeV1 {
+            payload: vec![0xCC; 32],
+        }),
+        SenderMessageV1::Pairing(PairingV1 {
+            element: [0x1E; 32],
+        }),
+        SenderMessageV1::PairingConfirm(PairingConfirmV1 {
+            confirmation: [0xC5; 32],
+        }),
+        SenderMessageV1::Metadata(MetadataV1 {
+            entries: [("source", "build-42"), ("ticket", "1234")]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }),
+        SenderMessageV1::UdpBlock(UdpBlockV1 {
+            seq: 42,
+            length: 1_048_620,
+            datagrams: 962,
+        }),
+        SenderMessageV1::ZeroBlock(ZeroBlockV1 { seq: 42 }),
+        SenderMessageV1::Ping(PingV1 { seq: 7 }),
+        SenderMessageV1::Pong(PongV1 { seq: 7 }),
+        SenderMessageV1::FileList(FileListV1 {
+            files: vec![
+                FileEntryV1 {
+                    path: String::from("docs/notes.txt"),
+                    size: 1234,
+                    hash: [0x1F; 32],
+                },
+                FileEntryV1 {
+                    path: String::from("photo.jpg"),
+                    size: 2 * 1024 * 1024,
+                    hash: [0x2F; 32],
+                },
+            ],
+        }),
+        SenderMessageV1::FileData(FileDataV1 {
+            file_index: 1,
+            data: DataV1 {
+                seq: 1,
+                checksum: 0xCBF4_3926,
+                file_hash: &FILE_HASH,
+                compressed: false,
+                data: b"123456789",
+            },
+        }),
+        SenderMessageV1::Abort(AbortV1 {
+            file_hash: FILE_HASH,
+            reason: String::from("Cancelled by the sender"),
+        }),
+        SenderMessageV1::Throttle(ThrottleV1 {
+            file_hash: FILE_HASH,
+            bytes_per_second: Some(5 * 1024 * 1024),
+        }),
+        SenderMessageV1::Cancel(CancelV1 {
+            file_hash: FILE_HASH,
+            reason: String::from("cancelled by the sender"),
+        }),
+        SenderMessageV1::Clock(ClockV1 {
+            unix_millis: 1_700_000_000_000,
+        }),
+        SenderMessageV1::RetryAfter(RetryAfterV1 {
+            code: 503,
+            message: String::from("Sender is at its connection limit"),
+            retry_after_ms: 1000,
+        }),
+        SenderMessageV1::Introduction(IntroductionV1 {
+            public_key: [0x1D; 32],
+            name: String::from("laptop"),
+        }),
+        SenderMessageV1::Goodbye(GoodbyeV1 {
+            file_hash: FILE_HASH,
+        }),
+    ]
+}
+
+fn receiver_samples() -> Vec<ReceiverMessageV1> {
+    vec![
+        ReceiverMessageV1::Request(RequestV1 {
+            file_hash: FILE_HASH,
+            seq: 42,
+        }),
+        ReceiverMessageV1::Progress(ProgressV1 {
+            file_hash: FILE_HASH,
+            bytes_received: 5 * 1024 * 1024,
+        }),
+        ReceiverMessageV1::TransferComplete(TransferCompleteV1 {
+            file_hash: FILE_HASH,
+        }),
+        ReceiverMessageV1::Error(ReceiverErrorV1 {
+            code: 500,
+            message: String::from("No space left on device"),
+        }),
+        ReceiverMessageV1::VerifyBlock(VerifyBlockV1 {
+            file_hash: FILE_HASH,
+            seq: 9,
+            checksum: 0xDEAD_BEEF,
+        }),
+        ReceiverMessageV1::Receipt(ReceiptV1 {
+            file_hash: FILE_HASH,
+            bytes: 10 * 1024 * 1024 + 17,
+            timestamp: 1_760_000_060,
+            receiver_key: [0x88; 32],
+            signature: vec![0x99; 64],
+        }),
+        ReceiverMessageV1::BlockHashesRequest(BlockHashesRequestV1 {
+            file_hash: FILE_HASH,
+            start_seq: 0,
+            count: 4096,
+        }),
+        ReceiverMessageV1::OfferResponse(OfferResponseV1 {
+            file_hash: FILE_HASH,
+            accepted: false,
+            reason: String::from("File type is not accepted"),
+        }),
+        ReceiverMessageV1::ProbeAck(ProbeAckV1 {
+            seq: 2,
+            bytes_received: 65536,
+        }),
+        ReceiverMessageV1::ConnHello(ConnHelloV1 {
+            session_id: [0x66; 16],
+            auth: [0xBB; 32],
+        }),
+        ReceiverMessageV1::ProtocolVersion(ProtocolVersionV1 { version: 2 }),
+        ReceiverMessageV1::NoiseHandshake(NoiseHandshakeV1 {
+            payload: vec![0xDD; 96],
+        }),
+        ReceiverMessageV1::PairingReply(PairingReplyV1 {
+            element: [0x2E; 32],
+            confirmation: [0xC7; 32],
+        }),
+        ReceiverMessageV1::UdpRequest(UdpRequestV1 {
+            file_hash: FILE_HASH,
+            seq: 42,
+            port: 40_000,
+            tag: 0x0123_4567_89AB_CDEF,
+        }),
+        ReceiverMessageV1::SparseRequest(RequestV1 {
+            file_hash: FILE_HASH,
+            seq: 42,
+        }),
+        ReceiverMessageV1::Ping(PingV1 { seq: 7 }),
+        ReceiverMessageV1::Pong(PongV1 { seq: 7 }),
+        ReceiverMessageV1::FileRequest(FileRequestV1 {
+            file_hash: FILE_HASH,
+            file_index: 1,
+            seq: 1,
+        }),
+        ReceiverMessageV1::Batch(BatchV1 {
+            messages: vec![
+                BatchedMessageV1::VerifyBlock(VerifyBlockV1 {
+                    file_hash: FILE_HASH,
+                    seq: 9,
+                    checksum: 0xDEAD_BEEF,
+                }),
+                BatchedMessageV1::VerifyBlock(VerifyBlockV1 {
+                    file_hash: FILE_HASH,
+                    seq: 10,
+                    checksum: 0xCBF4_3926,
+                }),
+                BatchedMessageV1::Request(RequestV1 {
+                    file_hash: FILE_HASH,
+                    seq: 11,
+                }),
+            ],
+        }),
+        ReceiverMessageV1::Algorithms(AlgorithmsV1 {
+            compression: CompressionCodec::Zstd,
+            checksum: ChecksumAlgorithm::Crc32c,
+        }),
+        ReceiverMessageV1::HandshakeAck(HandshakeAckV1 {
+            file_hash: FILE_HASH,
+            capabilities: Capabilities::RECEIPT | Capabilities::CONN_HELLO,
+            compression: CompressionCodec::Zstd,
+            checksum: ChecksumAlgorithm::Crc32c,
+            block_size: 1024 * 1024,
+            concurrency: 8,
+        }),
+        ReceiverMessageV1::HandshakeReject(HandshakeRejectV1 {
+            file_hash: FILE_HASH,
+            reason: RejectReasonV1::DiskFull,
+            message: String::from("Needs 2 GiB, 1 GiB free"),
+        }),
+        ReceiverMessageV1::HaveBlocks(HaveBlocksV1 {
+            file_hash: FILE_HASH,
+            start_seq: 64,
+            count: 200,
+            runs: vec![10, 2, 150, 1],
+            sparse: true,
+        }),
+        ReceiverMessageV1::PushRange(PushRangeV1 {
+            file_hash: FILE_HASH,
+            start_seq: 64,
+            count: 200,
+            window: 32,
+            sparse: true,
+        }),
+        ReceiverMessageV1::PushAck(PushAckV1 {
+            file_hash: FILE_HASH,
+            window: 3,
+            missing: vec![97, 130],
+        }),
+        ReceiverMessageV1::RequestRange(RequestRangeV1 {
+            file_hash: FILE_HASH,
+            start_seq: 300,
+            end_seq: 364,
+            sparse: false,
+        }),
+        ReceiverMessageV1::Cancel(CancelV1 {
+            file_hash: FILE_HASH,
+            reason: String::from("cancelled by the receiver"),
+        }),
+        ReceiverMessageV1::Introduction(IntroductionV1 {
+            public_key: [0x2D; 32],
+            name: String::from("nas"),
+        }),
+        ReceiverMessageV1::Goodbye(GoodbyeV1 {
+            file_hash: FILE_HASH,
+        }),
+        ReceiverMessageV1::NoReceipt(NoReceiptV1 {
+            file_hash: FILE_HASH,
+            reason: String::from("receipts are disabled"),
+        }),
+    ]
+}
+
+fn sender_frame(message: &SenderMessageV1, framing: Framing) -> Box<[u8]> {
+    let mut buffer = vec![0u8; 4096];
+    framing(message.to_bytes(&mut buffer).unwrap())
+}
+
+fn receiver_frame(message: &ReceiverMessageV1, framing: Framing) -> Box<[u8]> {
+    let mut buffer = vec![0u8; 4096];
+    framing(message.to_bytes(&mut buffer).unwrap())
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.hex"))
+}
+
+fn to_hex(frame: &[u8]) -> String {
+    frame
+        .chunks(GOLDEN_LINE_BYTES)
+        .map(|line| line.iter().map(|byte| format!("{byte:02x}")).collect())
+        .collect::<Vec<String>>()
+        .join("\n")
+        + "\n"
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+/// Returns the golden frame `name`, after writing `frame` as it if it is missing and
+/// `UPDATE_GOLDEN` is set.
+fn golden_frame(name: &str, frame: &[u8]) -> Vec<u8> {
+    let path = golden_path(name);
+    if !path.exists() && std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, to_hex(frame)).unwrap();
+    }
+    let text = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Missing golden frame {} ({e}), run the tests with UPDATE_GOLDEN=1 to write it",
+            path.display()
+        )
+    });
+    from_hex(&text)
+}
+
+/// Reads `frame` as a sender message, then checks its re-encoding reads back the same.
+fn assert_sender_round_trips(frame: &[u8]) {
+    let mut buffer = vec![0u8; frame.len() + 1];
+    let Ok(result) =
+        read_next_payload::<SenderMessageV1, _>(&mut Cursor::new(frame), &mut buffer, 0)
+    else {
+        return;
+    };
+    let reencoded = sender_frame(&result.message, attach_headers);
+    let mut buffer = vec![0u8; reencoded.len() + 1];
+    let reread = read_next_payload::<SenderMessageV1, _>(&mut &reencoded[..], &mut buffer, 0)
+        .expect("Re-encoded frame does not read back");
+    assert_eq!(reread.message, result.message);
+}
+
+/// Receiver counterpart of [assert_sender_round_trips].
+fn assert_receiver_round_trips(frame: &[u8]) {
+    let mut buffer = vec![0u8; frame.len() + 1];
+    let Ok(result) =
+        read_next_payload::<ReceiverMessageV1, _>(&mut Cursor::new(frame), &mut buffer, 0)
+    else {
+        return;
+    };
+    let reencoded = receiver_frame(&result.message, attach_headers);
+    let mut buffer = vec![0u8; reencoded.len() + 1];
+    let reread = read_next_payload::<ReceiverMessageV1, _>(&mut &reencoded[..], &mut buffer, 0)
+        .expect("Re-encoded frame does not read back");
+    assert_eq!(reread.message, result.message);
+}
+
+/// Frames derived from `frame` by truncating it and by flipping each of its bytes.
+fn corruptions(frame: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    let truncated = (0..frame.len()).map(|len| frame[..len].to_vec());
+    let flipped = (0..frame.len()).map(|index| {
+        let mut corrupted = frame.to_vec();
+        corrupted[index] ^= 0xFF;
+        corrupted
+    });
+    truncated.chain(flipped)
+}
+
+#[test]
+fn test_sender_messages_match_golden_frames() {
+    for (dir, framing) in FRAMINGS {
+        for message in sender_samples() {
+            let name = format!("{dir}{}", sender_golden_name(&message));
+            let frame = sender_frame(&message, framing);
+            let mut golden = golden_frame(&name, &frame);
+            assert_eq!(
+                to_hex(&frame),
+                to_hex(&golden),
+                "{name} changed on the wire"
+            );
+
+            let len = golden.len();
+            let result =
+                read_next_payload::<SenderMessageV1, _>(&mut io::empty(), &mut golden, len)
+                    .unwrap_or_else(|e| panic!("{name} no longer decodes: {e}"));
+            assert_eq!(result.message, message, "{name} decodes differently");
+        }
+    }
+}
+
+#[test]
+fn test_receiver_messages_match_golden_frames() {
+    for (dir, framing) in FRAMINGS {
+        for message in receiver_samples() {
+            let name = format!("{dir}{}", receiver_golden_name(&message));
+            let frame = receiver_frame(&message, framing);
+            let mut golden = golden_frame(&name, &frame);
+            assert_eq!(
+                to_hex(&frame),
+                to_hex(&golden),
+                "{name} changed on the wire"
+            );
+
+            let len = golden.len();
+            let result =
+                read_next_payload::<ReceiverMessageV1, _>(&mut io::empty(), &mut golden, len)
+                    .unwrap_or_else(|e| panic!("{name} no longer decodes: {e}"));
+            assert_eq!(result.message, message, "{name} decodes differently");
+        }
+    }
+}
+
+/// Name of the variant of a message, as its `Debug` output starts with it.
+fn variant_name(message: &impl std::fmt::Debug) -> String {
+    let debug = format!("{message:?}");
+    debug
+        .split(['(', ' '])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[test]
+fn test_dissector_layouts_walk_every_message() {
+    let layouts = Layouts::protocol().unwrap();
+    let mut buffer = vec![0u8; 1024];
+    for message in sender_samples() {
+        let payload = message.to_bytes(&mut buffer).unwrap();
+        let variant = layouts.decode("SenderMessageV1", payload);
+        assert_eq!(variant.map(String::from), Some(variant_name(&message)));
+    }
+    for message in receiver_samples() {
+        let payload = message.to_bytes(&mut buffer).unwrap();
+        let variant = layouts.decode("ReceiverMessageV1", payload);
+        assert_eq!(variant.map(String::from), Some(variant_name(&message)));
+    }
+}
+
+#[test]
+fn test_corrupted_frames_fail_cleanly() {
+    for (_, framing) in FRAMINGS {
+        for message in sender_samples() {
+            for frame in corruptions(&sender_frame(&message, framing)) {
+                assert_sender_round_trips(&frame);
+            }
+        }
+        for message in receiver_samples() {
+            for frame in corruptions(&receiver_frame(&message, framing)) {
+                assert_receiver_round_trips(&frame);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_frames_with_hostile_headers_are_rejected() {
+    let version = TEXT_FRAMING_PROTOCOL_VERSION;
+    let text_frames = [
+        format!("Ver: {version}\r\nLen: {}\r\n\r\n", usize::MAX),
+        format!("Ver: {version}\r\nLen: 99999999999999999999999\r\n\r\n"),
+        format!("Ver: {version}\r\nLen: -1\r\n\r\n"),
+        format!("Ver: {}\r\nLen: 0\r\n\r\n", version + 1),
+        format!("Ver: {version}\r\n\r\n"),
+        format!("Ver: {version}\r\nLen: {}", " ".repeat(MAX_HEADER_SIZE)),
+    ]
+    .map(String::into_bytes);
+
+    let header = FrameHeader::new(0);
+    let mut bad_checksum = header.encode();
+    bad_checksum[FRAME_HEADER_SIZE - 1] ^= 0x01;
+    let binary_frames = [
+        FrameHeader::new(u32::MAX).encode(),
+        FrameHeader {
+            version: CURRENT_PROTOCOL_VERSION + 1,
+            ..header
+        }
+        .encode(),
+        FrameHeader {
+            flags: 0xFF,
+            ..header
+        }
+        .encode(),
+        // Tagged frames are those of version 3, and only them
+        FrameHeader {
+            flags: FRAME_FLAG_SESSION,
+            ..header
+        }
+        .encode(),
+        FrameHeader {
+            version: SESSION_FRAMING_PROTOCOL_VERSION,
+            ..header
+        }
+        .encode(),
+        // Cut off in the session id
+        FrameHeader::with_session(0).encode(),
+        bad_checksum,
+    ]
+    .map(Vec::from);
+
+    for frame in text_frames.into_iter().chain(binary_frames) {
+        let mut buffer = vec![0u8; 1024];
+        let result = read_next_payload::<ReceiverMessageV1, _>(&mut &frame[..], &mut buffer, 0);
+        assert!(
+            matches!(
+                result,
+                Err(StreamReadError::BufferSmallerThanExpected { .. }
+                    | StreamReadError::InvalidMessageFormat { .. }
+                    | StreamReadError::InvalidFrameHeader(_)
+                    | StreamReadError::UnsupportedProtocolVersion { .. }
+                    | StreamReadError::UnexpectedEof)
+            ),
+            "{frame:?} was not rejected: {result:?}"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Files held back because they failed the receiver's scan.
+//!
+//! Each held file gets its own directory in the quarantine directory
+//! (`~/.local/share/sendfile/quarantine` on Linux), named after the time it was received and its
+//! hash, holding the file under its original name and a [QuarantineRecord] describing it.
+//! Operators review held files with `sendfile quarantine list`, then [release] or [purge] them.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Name of the record describing a held file, inside its quarantine entry.
+pub const QUARANTINE_RECORD_NAME: &str = "quarantine.json";
+
+/// Errors that can occur while holding files in quarantine.
+#[derive(Error, Debug)]
+pub enum QuarantineError {
+    /// A held file or its record could not be read or written.
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    /// A record could not be encoded or decoded.
+    #[error("Malformed quarantine record: {0}")]
+    Json(#[from] serde_json::Error),
+    /// No held file has the given id.
+    #[error("No quarantined file with id {0:?}")]
+    NotFound(String),
+    /// Releasing the file would overwrite an existing file.
+    #[error("{0:?} already exists")]
+    Exists(PathBuf),
+}
+
+/// Description of a file held in quarantine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuarantineRecord {
+    /// Name of the file on the sender side.
+    pub file_name: String,
+    /// Where the file would have been saved.
+    pub destination: PathBuf,
+    /// Hex encoded BLAKE3 hash of the file.
+    pub file_hash: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Address of the sender.
+    pub sender: String,
+    /// Hex encoded public key of the sender, `None` if it did not authenticate.
+    pub sender_key: Option<String>,
+    /// Receive time, in seconds since the Unix epoch.
+    pub received_at: u64,
+    /// Why the file was held, e.g. the output of the scan command.
+    pub reason: String,
+}
+
+impl QuarantineRecord {
+    /// Returns the name of the quarantine entry of the file.
+    pub fn id(&self) -> String {
+        let hash_prefix = self.file_hash.get(..12).unwrap_or(&self.file_hash);
+        format!("{}-{}", self.received_at, hash_prefix)
+    }
+
+    /// Returns the name the file is held under, its name on the sender side without any
+    /// directory.
+    fn held_name(&self) -> &Path {
+        Path::new(&self.file_name)
+            .file_name()
+            .map(Path::new)
+            .unwrap_or(Path::new("unnamed_file"))
+    }
+}
+
+/// A file held in quarantine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeldFile {
+    /// Name of its quarantine entry, see [QuarantineRecord::id].
+    pub id: String,
+    /// Path of the held file.
+    pub path: PathBuf,
+    /// Description of the file.
+    #[serde(flatten)]
+    pub record: QuarantineRecord,
+}
+
+/// Returns the default quarantine directory, if a data directory is known.
+pub fn default_quarantine_dir() -> Option<PathBuf> {
+    crate::state::data_dir().map(|dir| dir.join("quarantine"))
+}
+
+/// Moves the file at `path` into a new entry of the quarantine directory `root`, along with
+/// `record`.
+///
+/// # Returns
+///
+/// The directory of the entry, or a [QuarantineError] if the file could not be moved, in which
+/// case it is left at `path`.
+pub fn hold(
+    root: &Path,
+    path: &Path,
+    record: &QuarantineRecord,
+) -> Result<PathBuf, QuarantineError> {
+    let entry = root.join(record.id());
+    fs::create_dir_all(&entry)?;
+
+    move_file(path, &entry.join(record.held_name()))?;
+    fs::write(
+        entry.join(QUARANTINE_RECORD_NAME),
+        serde_json::to_vec_pretty(record)?,
+    )?;
+    Ok(entry)
+}
+
+/// Returns the files held in the quarantine directory `root`, oldest first. A missing directory
+/// holds no files, entries without a readable record are skipped.
+pub fn list(root: &Path) -> Result<Vec<HeldFile>, QuarantineError> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut held = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(id) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        if let Ok(file) = find(root, &id) {
+            held.push(file);
+        }
+    }
+    held.sort_by(|a, b| (a.record.received_at, &a.id).cmp(&(b.record.received_at, &b.id)));
+    Ok(held)
+}
+
+/// Returns the file held under `id` in the quarantine directory `root`.
+pub fn find(root: &Path, id: &str) -> Result<HeldFile, QuarantineError> {
+    // Ids are single path components, anything else can't name an entry
+    if id.is_empty() || Path::new(id).file_name() != Some(id.as_ref()) {
+        return Err(QuarantineError::NotFound(id.to_string()));
+    }
+    let entry = root.join(id);
+    let record = match fs::read(entry.join(QUARANTINE_RECORD_NAME)) {
+        Ok(json) => serde_json::from_slice::<QuarantineRecord>(&json)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(QuarantineError::NotFound(id.to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(HeldFile {
+        id: id.to_string(),
+        path: entry.join(record.held_name()),
+        record,
+    })
+}
+
+/// Moves the file held under `id` out of the quarantine directory `root` and removes its entry.
+///
+/// # Arguments
+///
+/// * `root` - Quarantine directory.
+/// * `id` - Id of the held file.
+/// * `to` - Where to move the file, a directory receiving it under its original name. `None`
+///   moves it to the destination it was received for.
+/// * `overwrite` - Whether to replace an existing file at the destination.
+///
+/// # Returns
+///
+/// Where the file was moved, or a [QuarantineError], [QuarantineError::Exists] if a file is in
+/// the way.
+pub fn release(
+    root: &Path,
+    id: &str,
+    to: Option<&Path>,
+    overwrite: bool,
+) -> Result<PathBuf, QuarantineError> {
+    let held = find(root, id)?;
+    let destination = match to {
+        Some(to) if to.is_dir() => to.join(held.record.held_name()),
+        Some(to) => to.to_path_buf(),
+        None => held.record.destination.clone(),
+    };
+    if !overwrite && destination.exists() {
+        return Err(QuarantineError::Exists(destination));
+    }
+
+    move_file(&held.path, &destination)?;
+    fs::remove_dir_all(root.join(id))?;
+    Ok(destination)
+}
+
+/// Deletes the file held under `id` in the quarantine directory `root`, with its entry.
+///
+/// Returns the size of the deleted file in bytes.
+pub fn purge(root: &Path, id: &str) -> Result<u64, QuarantineError> {
+    let held = find(root, id)?;
+    fs::remove_dir_all(root.join(id))?;
+    Ok(held.record.size)
+}
+
+/// Moves a file, copying it if `to` is on another filesystem.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_moves_file_with_its_record() {
+        let dir = std::env::temp_dir().join(format!("sendfile-quarantine-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("invoice.pdf");
+        fs::write(&path, b"suspicious").unwrap();
+
+        let record = QuarantineRecord {
+            file_name: String::from("invoice.pdf"),
+            destination: path.clone(),
+            file_hash: String::from("00112233445566778899aabbccddeeff"),
+            size: 10,
+            sender: String::from("10.0.0.5:51234"),
+            sender_key: None,
+            received_at: 1_700_000_000,
+            reason: String::from("scan command exited with exit status: 1"),
+        };
+        let entry = hold(&dir.join("quarantine"), &path, &record).unwrap();
+
+        assert_eq!(entry, dir.join("quarantine/1700000000-001122334455"));
+        assert!(!path.exists());
+        assert_eq!(fs::read(entry.join("invoice.pdf")).unwrap(), b"suspicious");
+        let stored: QuarantineRecord =
+            serde_json::from_slice(&fs::read(entry.join(QUARANTINE_RECORD_NAME)).unwrap()).unwrap();
+        assert_eq!(stored, record);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_release_and_purge() {
+        let dir = std::env::temp_dir().join(format!("sendfile-release-{}", std::process::id()));
+        let root = dir.join("quarantine");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ids = Vec::new();
+        for (received_at, file_name, hash) in [(20, "b.zip", "bb"), (10, "a.exe", "aa")] {
+            let path = dir.join(file_name);
+            fs::write(&path, file_name).unwrap();
+            let record = QuarantineRecord {
+                file_name: String::from(file_name),
+                destination: path.clone(),
+                file_hash: String::from(hash),
+                size: file_name.len() as u64,
+                sender: String::from("10.0.0.5:51234"),
+                sender_key: None,
+                received_at,
+                reason: String::from("infected"),
+            };
+            hold(&root, &path, &record).unwrap();
+            ids.push(record.id());
+        }
+
+        let held = list(&root).unwrap();
+        let names: Vec<_> = held.iter().map(|h| h.record.file_name.as_str()).collect();
+        assert_eq!(names, ["a.exe", "b.zip"]);
+        assert!(matches!(
+            find(&root, "../quarantine"),
+            Err(QuarantineError::NotFound(_))
+        ));
+
+        // Released to where it was received for, unless a file is in the way
+        fs::write(dir.join("b.zip"), "newer").unwrap();
+        assert!(matches!(
+            release(&root, &ids[0], None, false),
+            Err(QuarantineError::Exists(_))
+        ));
+        let released = release(&root, &ids[0], None, true).unwrap();
+        assert_eq!(fs::read(&released).unwrap(), b"b.zip");
+
+        assert_eq!(purge(&root, &ids[1]).unwrap(), 5);
+        assert!(list(&root).unwrap().is_empty());
+        assert!(list(&dir.join("missing")).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
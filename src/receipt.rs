@@ -0,0 +1,118 @@
+//! Signed delivery receipts.
+//!
+//! Once a file has been written and its hash verified, the receiver signs a [ReceiptV1] with its
+//! [Identity] and returns it to the sender, which keeps it in its history as evidence that the
+//! file was delivered intact.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use thiserror::Error;
+
+use crate::{identity::Identity, transport::ReceiptV1};
+
+/// Domain separation prefix of the signed payload, so a receipt signature cannot be replayed as
+/// a signature over anything else.
+const RECEIPT_CONTEXT: &[u8] = b"sendfile-receipt-v1";
+
+/// Errors that can occur while checking a receipt.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ReceiptError {
+    /// The receipt is for a different file than the one that was sent.
+    #[error("Receipt is for a different file")]
+    FileMismatch,
+    /// The receipt reports a different size than the one that was sent.
+    #[error("Receipt reports {received} bytes, expected {expected}")]
+    SizeMismatch { expected: u64, received: u64 },
+    /// The public key or signature is malformed, or the signature does not match.
+    #[error("Invalid receipt signature")]
+    InvalidSignature,
+}
+
+/// Creates a receipt for a verified file, signed by `identity` and timestamped now.
+pub fn sign_receipt(identity: &Identity, file_hash: [u8; 32], bytes: u64) -> ReceiptV1 {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut receipt = ReceiptV1 {
+        file_hash,
+        bytes,
+        timestamp,
+        receiver_key: identity.public_key(),
+        signature: Vec::new(),
+    };
+    receipt.signature = identity.sign(&signed_payload(&receipt)).to_vec();
+    receipt
+}
+
+/// Checks that `receipt` covers the file with `file_hash` and `bytes`, and that its signature
+/// was made by the key it carries.
+pub fn verify_receipt(
+    receipt: &ReceiptV1,
+    file_hash: &[u8; 32],
+    bytes: u64,
+) -> Result<(), ReceiptError> {
+    if receipt.file_hash != *file_hash {
+        return Err(ReceiptError::FileMismatch);
+    }
+    if receipt.bytes != bytes {
+        return Err(ReceiptError::SizeMismatch {
+            expected: bytes,
+            received: receipt.bytes,
+        });
+    }
+
+    let key = VerifyingKey::from_bytes(&receipt.receiver_key)
+        .map_err(|_| ReceiptError::InvalidSignature)?;
+    let signature =
+        Signature::from_slice(&receipt.signature).map_err(|_| ReceiptError::InvalidSignature)?;
+    key.verify_strict(&signed_payload(receipt), &signature)
+        .map_err(|_| ReceiptError::InvalidSignature)
+}
+
+/// Returns the bytes covered by the receipt signature.
+fn signed_payload(receipt: &ReceiptV1) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(RECEIPT_CONTEXT.len() + 32 + 8 + 8 + 32);
+    payload.extend_from_slice(RECEIPT_CONTEXT);
+    payload.extend_from_slice(&receipt.file_hash);
+    payload.extend_from_slice(&receipt.bytes.to_le_bytes());
+    payload.extend_from_slice(&receipt.timestamp.to_le_bytes());
+    payload.extend_from_slice(&receipt.receiver_key);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ReceiverMessageV1;
+
+    #[test]
+    fn test_receipt_roundtrip_and_tampering() {
+        let identity = Identity::from_seed([7; 32]);
+        let receipt = sign_receipt(&identity, [0xAB; 32], 4096);
+
+        let mut buffer = [0u8; 1024];
+        let message = ReceiverMessageV1::Receipt(receipt.clone());
+        let serialized = message.to_bytes(&mut buffer).expect("Failed to serialize");
+        let ReceiverMessageV1::Receipt(decoded) =
+            ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize")
+        else {
+            panic!("Expected a receipt");
+        };
+        assert_eq!(verify_receipt(&decoded, &[0xAB; 32], 4096), Ok(()));
+
+        assert_eq!(
+            verify_receipt(&decoded, &[0xCD; 32], 4096),
+            Err(ReceiptError::FileMismatch)
+        );
+
+        let mut tampered = decoded;
+        tampered.timestamp += 1;
+        assert_eq!(
+            verify_receipt(&tampered, &[0xAB; 32], 4096),
+            Err(ReceiptError::InvalidSignature)
+        );
+    }
+}
@@ -0,0 +1,294 @@
+//! Containers for key material: the identity seed, Noise static keys, TLS private keys, pairing
+//! codes and the secrets derived from them.
+//!
+//! A [Secret] keeps its value on the heap, where it stays put until it is dropped, is wiped
+//! when dropped, and shows up as `Secret([REDACTED])` in `Debug` output, so options and errors
+//! holding one can be logged. With [set_locking] (`--lock-secrets`), the pages holding every
+//! secret are also locked in memory (`mlock`), so they are never written to swap. Locking is
+//! best effort: a process over its locked memory limit (`ulimit -l`) warns once and keeps its
+//! secrets unlocked, and platforms other than Unix don't lock at all.
+//!
+//! Pages are shared by the secrets on them, and only unlocked once the last of them is dropped.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, Once,
+    },
+};
+
+use curve25519_dalek::scalar::Scalar;
+use log::warn;
+use rustls::pki_types::PrivateKeyDer;
+use zeroize::Zeroize;
+
+/// Whether new secrets lock their pages, see [set_locking].
+static LOCKING: AtomicBool = AtomicBool::new(false);
+
+/// Number of secrets on every locked page, by the page's address.
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Warns once that pages could not be locked.
+static LOCK_FAILED: Once = Once::new();
+
+/// Makes secrets created from now on lock the pages holding them in memory, for the rest of the
+/// process.
+pub fn set_locking(enabled: bool) {
+    LOCKING.store(enabled, Ordering::Relaxed);
+}
+
+/// A value holding secret bytes, see [Secret].
+pub trait SecretMemory: Zeroize {
+    /// Returns the bytes of the value that must not leak, wherever they are stored.
+    fn secret_bytes(&self) -> &[u8];
+}
+
+impl<const N: usize> SecretMemory for [u8; N] {
+    fn secret_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl SecretMemory for Vec<u8> {
+    fn secret_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl SecretMemory for String {
+    fn secret_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl SecretMemory for Scalar {
+    fn secret_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl SecretMemory for PrivateKeyDer<'static> {
+    fn secret_bytes(&self) -> &[u8] {
+        self.secret_der()
+    }
+}
+
+/// Key material, wiped when dropped and left out of `Debug` output.
+pub struct Secret<T: SecretMemory> {
+    /// Boxed so the value is never moved, and neither copied nor left behind by moves of the
+    /// secret.
+    value: Box<T>,
+    /// Whether the pages of the value were locked, and must be unlocked when it is dropped.
+    locked: bool,
+}
+
+/// A 32 byte key.
+pub type SecretKey = Secret<[u8; 32]>;
+
+/// A passphrase or a pairing code.
+pub type SecretString = Secret<String>;
+
+impl<T: SecretMemory> Secret<T> {
+    /// Takes `value` in, locking its pages if [set_locking] was enabled.
+    pub fn new(value: T) -> Self {
+        Self::boxed(Box::new(value))
+    }
+
+    fn boxed(value: Box<T>) -> Self {
+        let locked = LOCKING.load(Ordering::Relaxed) && lock(value.secret_bytes());
+        Self { value, locked }
+    }
+
+    /// Returns the secret value.
+    pub fn expose(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<const N: usize> Secret<[u8; N]> {
+    /// Copies a key of exactly `N` bytes out of `bytes`, `None` if it has another length.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != N {
+            return None;
+        }
+        let mut value = Box::new([0u8; N]);
+        value.copy_from_slice(bytes);
+        Some(Self::boxed(value))
+    }
+
+    /// Returns the key to be filled in, which stays where it is unlike a string would.
+    pub fn expose_mut(&mut self) -> &mut [u8; N] {
+        &mut self.value
+    }
+
+    /// Returns a key of `N` random bytes from the OS random number generator.
+    pub fn random() -> Result<Self, getrandom::Error> {
+        let mut value = Box::new([0u8; N]);
+        getrandom::fill(value.as_mut_slice())?;
+        Ok(Self::boxed(value))
+    }
+}
+
+impl<T: SecretMemory + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.value.as_ref().clone())
+    }
+}
+
+impl<T: SecretMemory> Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+impl<T: SecretMemory> Drop for Secret<T> {
+    fn drop(&mut self) {
+        // Before zeroizing, which empties values such as strings
+        let pages = self.locked.then(|| page_range(self.value.secret_bytes()));
+        self.value.zeroize();
+        if let Some(pages) = pages {
+            unlock(pages);
+        }
+    }
+}
+
+/// Returns the addresses of the first and past the last page holding `bytes`, `None` if empty.
+fn page_range(bytes: &[u8]) -> Option<(usize, usize)> {
+    if bytes.is_empty() {
+        return None;
+    }
+    let size = page_size();
+    let start = bytes.as_ptr() as usize;
+    Some((
+        start / size * size,
+        (start + bytes.len()).div_ceil(size) * size,
+    ))
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+/// Locks the pages holding `bytes` that no other secret locked yet.
+///
+/// # Returns
+///
+/// Whether the pages are locked, and must be unlocked with [unlock].
+fn lock(bytes: &[u8]) -> bool {
+    let Some((start, end)) = page_range(bytes) else {
+        return false;
+    };
+    let mut pages = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let size = page_size();
+    for page in (start..end).step_by(size) {
+        if !pages.contains_key(&page)
+            && let Err(e) = lock_page(page, size)
+        {
+            LOCK_FAILED.call_once(|| {
+                warn!(
+                    "Failed to lock secrets in memory, they may be swapped out: {}",
+                    e
+                )
+            });
+            // Undoes the pages locked so far
+            for locked in (start..page).step_by(size) {
+                release_page(&mut pages, locked, size);
+            }
+            return false;
+        }
+        *pages.entry(page).or_default() += 1;
+    }
+    true
+}
+
+/// Unlocks the pages of a secret locked by [lock] that no other secret holds.
+fn unlock(pages: Option<(usize, usize)>) {
+    let Some((start, end)) = pages else {
+        return;
+    };
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let size = page_size();
+    for page in (start..end).step_by(size) {
+        release_page(&mut locked, page, size);
+    }
+}
+
+fn release_page(pages: &mut BTreeMap<usize, usize>, page: usize, size: usize) {
+    let Some(count) = pages.get_mut(&page) else {
+        return;
+    };
+    *count -= 1;
+    if *count == 0 {
+        pages.remove(&page);
+        unlock_page(page, size);
+    }
+}
+
+#[cfg(unix)]
+fn lock_page(page: usize, size: usize) -> std::io::Result<()> {
+    // SAFETY: mlock only changes whether the page may be swapped out, the page is mapped as it
+    // holds a live secret
+    match unsafe { libc::mlock(page as *const libc::c_void, size) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_page(_page: usize, _size: usize) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn unlock_page(page: usize, size: usize) {
+    // SAFETY: see lock_page, the page was locked by it
+    unsafe { libc::munlock(page as *const libc::c_void, size) };
+}
+
+#[cfg(not(unix))]
+fn unlock_page(_page: usize, _size: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_are_redacted_and_locked() {
+        let key = SecretKey::from_slice(&[7; 32]).unwrap();
+        assert_eq!(key.expose(), &[7; 32]);
+        assert_eq!(format!("{:?}", key), "Secret([REDACTED])");
+        assert!(SecretKey::from_slice(&[7; 31]).is_none());
+        assert!(SecretKey::from_slice(&[7; 33]).is_none());
+
+        let code = SecretString::new(String::from("7-orbit-velvet"));
+        assert_eq!(
+            format!("{:?}", Some(code.clone())),
+            "Some(Secret([REDACTED]))"
+        );
+
+        // The pages of both copies stay locked until the last of them is dropped
+        let bytes = [1u8; 64];
+        if !lock(&bytes) {
+            // Over the locked memory limit of this environment
+            return;
+        }
+        assert!(lock(&bytes));
+        let page = page_range(&bytes).unwrap().0;
+        unlock(page_range(&bytes));
+        assert_eq!(LOCKED_PAGES.lock().unwrap().get(&page), Some(&1));
+        unlock(page_range(&bytes));
+        assert_eq!(LOCKED_PAGES.lock().unwrap().get(&page), None);
+    }
+}
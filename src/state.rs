@@ -0,0 +1,467 @@
+//! Key-value stores keeping the local state of sendfile: the history of transfers and the index
+//! of received files, see [crate::history], and the sessions a receiver has in progress, see
+//! [crate::stream::sessions].
+//!
+//! State lives in the sendfile data directory (`~/.local/share/sendfile` on Linux, or
+//! `--data-dir` / `SENDFILE_DATA_DIR`), one store per kind of state under `state/`. Features
+//! access their records through the [StateStore] trait rather than a file format of their own,
+//! so a store can be inspected, compacted or cleared with `sendfile state` whatever it holds.
+//!
+//! [JsonFileStore] keeps a store in a JSON lines file: every change appends a line with the key
+//! and its new value, or a tombstone for a removed key, and the file is replayed when opened.
+//! Lines of replaced or removed records pile up until the store is compacted. [MemoryStore]
+//! keeps one in memory, e.g. for tests.
+//!
+//! Resume states are not kept here: they describe a partly received file and stay next to it,
+//! so they move and disappear along with it, see [crate::file::resume].
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// Environment variable overriding the data directory, unless `--data-dir` is given.
+pub const DATA_DIR_VAR: &str = "SENDFILE_DATA_DIR";
+
+/// Name of the directory holding the stores inside the data directory.
+const STATE_DIR_NAME: &str = "state";
+
+/// Extension of the files of [JsonFileStore]s.
+const STORE_EXTENSION: &str = "jsonl";
+
+/// Data directory set with [set_data_dir].
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Errors that can occur while reading or writing a store.
+#[derive(Error, Debug)]
+pub enum StateError {
+    /// The store could not be read or written.
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// A record could not be encoded or decoded.
+    #[error("Malformed record: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// A store of JSON values by key.
+pub trait StateStore: Send + Sync {
+    /// Returns the value of `key`, `None` if there is none.
+    fn get(&self, key: &str) -> Result<Option<Value>, StateError>;
+
+    /// Sets the value of `key`, replacing the previous one.
+    fn put(&self, key: &str, value: Value) -> Result<(), StateError>;
+
+    /// Removes `key`, returning whether it had a value.
+    fn remove(&self, key: &str) -> Result<bool, StateError>;
+
+    /// Returns every record, in key order.
+    fn records(&self) -> Result<Vec<(String, Value)>, StateError>;
+
+    /// Reclaims the space taken by replaced and removed records. Does nothing by default.
+    fn compact(&self) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+impl dyn StateStore + '_ {
+    /// Returns the value of `key` decoded as a `T`, `None` if there is none.
+    pub fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StateError> {
+        Ok(self.get(key)?.map(serde_json::from_value).transpose()?)
+    }
+
+    /// Sets the value of `key` to `value` encoded as JSON.
+    pub fn put_as<T: Serialize>(&self, key: &str, value: &T) -> Result<(), StateError> {
+        self.put(key, serde_json::to_value(value)?)
+    }
+
+    /// Returns every value decoded as a `T`, in key order.
+    pub fn values_as<T: DeserializeOwned>(&self) -> Result<Vec<T>, StateError> {
+        self.records()?
+            .into_iter()
+            .map(|(_, value)| Ok(serde_json::from_value(value)?))
+            .collect()
+    }
+
+    /// Removes every record.
+    pub fn clear(&self) -> Result<usize, StateError> {
+        let records = self.records()?;
+        for (key, _) in &records {
+            self.remove(key)?;
+        }
+        self.compact()?;
+        Ok(records.len())
+    }
+}
+
+/// Returns the key appending a value to a store used as a log: one past the last key, zero
+/// padded so keys sort in the order they were added.
+pub fn next_sequence_key(store: &dyn StateStore) -> Result<String, StateError> {
+    let next = store
+        .records()?
+        .last()
+        .and_then(|(key, _)| key.parse::<u64>().ok())
+        .map_or(0, |last| last + 1);
+    Ok(format!("{next:016}"))
+}
+
+/// A store kept in memory, lost when dropped.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: Mutex<BTreeMap<String, Value>>,
+}
+
+impl MemoryStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Value>> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Value>, StateError> {
+        Ok(self.lock().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: Value) -> Result<(), StateError> {
+        self.lock().insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, StateError> {
+        Ok(self.lock().remove(key).is_some())
+    }
+
+    fn records(&self) -> Result<Vec<(String, Value)>, StateError> {
+        Ok(self
+            .lock()
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+/// A line of a [JsonFileStore]: a key and its value, or a tombstone removing the key.
+#[derive(Serialize, Deserialize)]
+struct StoreLine {
+    key: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    value: Value,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    removed: bool,
+}
+
+/// A store kept in a JSON lines file, see the [module](self) documentation.
+#[derive(Debug)]
+pub struct JsonFileStore {
+    path: PathBuf,
+    inner: Mutex<JsonFileState>,
+}
+
+#[derive(Debug)]
+struct JsonFileState {
+    records: BTreeMap<String, Value>,
+    /// Lines of the file superseded by a later one.
+    stale_lines: usize,
+}
+
+impl JsonFileStore {
+    /// Opens the store kept in the file at `path`. A missing file is an empty store, created on
+    /// the first change.
+    pub fn open(path: &Path) -> Result<Self, StateError> {
+        let mut state = JsonFileState {
+            records: BTreeMap::new(),
+            stale_lines: 0,
+        };
+        match fs::File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let line: StoreLine = serde_json::from_str(&line)?;
+                    state.stale_lines += if line.removed {
+                        // The tombstone itself is stale too
+                        1 + state.records.remove(&line.key).is_some() as usize
+                    } else {
+                        state.records.insert(line.key, line.value).is_some() as usize
+                    };
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            inner: Mutex::new(state),
+        })
+    }
+
+    /// Returns the path of the file the store is kept in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of lines of the file that [StateStore::compact] would drop.
+    pub fn stale_lines(&self) -> usize {
+        self.lock().stale_lines
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JsonFileState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn append(&self, line: &StoreLine) -> Result<(), StateError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut bytes = serde_json::to_vec(line)?;
+        bytes.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+impl StateStore for JsonFileStore {
+    fn get(&self, key: &str) -> Result<Option<Value>, StateError> {
+        Ok(self.lock().records.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: Value) -> Result<(), StateError> {
+        let mut state = self.lock();
+        self.append(&StoreLine {
+            key: key.to_owned(),
+            value: value.clone(),
+            removed: false,
+        })?;
+        if state.records.insert(key.to_owned(), value).is_some() {
+            state.stale_lines += 1;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, StateError> {
+        let mut state = self.lock();
+        if !state.records.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(&StoreLine {
+            key: key.to_owned(),
+            value: Value::Null,
+            removed: true,
+        })?;
+        state.records.remove(key);
+        state.stale_lines += 2;
+        Ok(true)
+    }
+
+    fn records(&self) -> Result<Vec<(String, Value)>, StateError> {
+        Ok(self
+            .lock()
+            .records
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn compact(&self) -> Result<(), StateError> {
+        let mut state = self.lock();
+        if state.stale_lines == 0 {
+            return Ok(());
+        }
+
+        let mut content = Vec::new();
+        for (key, value) in &state.records {
+            serde_json::to_writer(
+                &mut content,
+                &StoreLine {
+                    key: key.clone(),
+                    value: value.clone(),
+                    removed: false,
+                },
+            )?;
+            content.push(b'\n');
+        }
+
+        // Written aside and renamed, so a crash never leaves a truncated store
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, content)?;
+        fs::rename(&temp, &self.path)?;
+        state.stale_lines = 0;
+        Ok(())
+    }
+}
+
+/// Makes `dir` the data directory for the rest of the process, instead of the one of
+/// [DATA_DIR_VAR] or the platform's. Only the first call has an effect.
+pub fn set_data_dir(dir: PathBuf) {
+    let _ = DATA_DIR.set(dir);
+}
+
+/// Returns the sendfile data directory, if one is known: the one set with [set_data_dir], else
+/// [DATA_DIR_VAR], else `sendfile` in the platform's data directory.
+pub fn data_dir() -> Option<PathBuf> {
+    if let Some(dir) = DATA_DIR.get() {
+        return Some(dir.clone());
+    }
+    match std::env::var_os(DATA_DIR_VAR) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => dirs::data_dir().map(|dir| dir.join("sendfile")),
+    }
+}
+
+/// Returns the directory holding the stores in the data directory `data_dir`.
+pub fn state_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(STATE_DIR_NAME)
+}
+
+/// Returns the default directory holding the stores, if a data directory is known.
+pub fn default_state_dir() -> Option<PathBuf> {
+    data_dir().map(|dir| state_dir(&dir))
+}
+
+/// Returns the default location of the store named `name`, if a data directory is known.
+pub fn default_store_path(name: &str) -> Option<PathBuf> {
+    default_state_dir().map(|dir| store_path(&dir, name))
+}
+
+/// Returns the path of the store named `name` in the directory `state_dir`.
+pub fn store_path(state_dir: &Path, name: &str) -> PathBuf {
+    state_dir.join(format!("{name}.{STORE_EXTENSION}"))
+}
+
+/// Summary of a store, as shown by `sendfile state list`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    /// Name of the store.
+    pub name: String,
+    /// Path of its file.
+    pub path: PathBuf,
+    /// Number of records.
+    pub records: usize,
+    /// Lines that compacting would drop.
+    pub stale_lines: usize,
+    /// Size of its file in bytes.
+    pub bytes: u64,
+}
+
+/// Returns the names of the stores in `state_dir`, sorted. A missing directory has none.
+pub fn list_stores(state_dir: &Path) -> Result<Vec<String>, StateError> {
+    let entries = match fs::read_dir(state_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == STORE_EXTENSION)
+            && let Some(name) = path.file_stem().and_then(|name| name.to_str())
+        {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Opens the store named `name` in `state_dir`.
+pub fn open_store(state_dir: &Path, name: &str) -> Result<JsonFileStore, StateError> {
+    JsonFileStore::open(&store_path(state_dir, name))
+}
+
+/// Returns the summary of the store named `name` in `state_dir`.
+pub fn store_stats(state_dir: &Path, name: &str) -> Result<StoreStats, StateError> {
+    let store = open_store(state_dir, name)?;
+    let bytes = match fs::metadata(store.path()) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(StoreStats {
+        name: name.to_owned(),
+        path: store.path().to_owned(),
+        records: store.records()?.len(),
+        stale_lines: store.stale_lines(),
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_file_store_replays_and_compacts() {
+        let dir = std::env::temp_dir().join(format!("sendfile_state_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = store_path(&dir, "things");
+
+        let store = JsonFileStore::open(&path).unwrap();
+        store.put("a", Value::from(1)).unwrap();
+        store.put("b", Value::from(2)).unwrap();
+        store.put("a", Value::from(3)).unwrap();
+        assert!(store.remove("b").unwrap());
+        assert!(!store.remove("b").unwrap());
+        assert_eq!(store.stale_lines(), 3);
+
+        let reopened = JsonFileStore::open(&path).unwrap();
+        assert_eq!(
+            reopened.records().unwrap(),
+            vec![(String::from("a"), Value::from(3))]
+        );
+        assert_eq!(reopened.stale_lines(), 3);
+
+        reopened.compact().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        let compacted = JsonFileStore::open(&path).unwrap();
+        assert_eq!(compacted.get("a").unwrap(), Some(Value::from(3)));
+        assert_eq!(compacted.stale_lines(), 0);
+
+        assert_eq!(list_stores(&dir).unwrap(), vec![String::from("things")]);
+        assert_eq!(store_stats(&dir, "things").unwrap().records, 1);
+        assert_eq!((&compacted as &dyn StateStore).clear().unwrap(), 1);
+        assert!(JsonFileStore::open(&path)
+            .unwrap()
+            .records()
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sequence_keys_sort_in_order() {
+        let store = MemoryStore::new();
+        let store: &dyn StateStore = &store;
+        for value in 0..12u32 {
+            let key = next_sequence_key(store).unwrap();
+            store.put_as(&key, &value).unwrap();
+        }
+        assert_eq!(
+            store.values_as::<u32>().unwrap(),
+            (0..12).collect::<Vec<_>>()
+        );
+        assert_eq!(store.get_as::<u32>("0000000000000011").unwrap(), Some(11));
+    }
+}
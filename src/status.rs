@@ -0,0 +1,230 @@
+//! Status socket through which a running sendfile process reports its active transfers.
+//!
+//! While a send or receive runs from the command line, the process listens on a Unix socket
+//! named `<pid>.sock` in the status directory (`$XDG_RUNTIME_DIR/sendfile` on Linux) and answers
+//! every connection with a JSON array of [TransferStatus]. `sendfile status` queries every socket
+//! in the directory.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::stream::registry::{TransferRegistry, TransferStatus};
+
+/// Errors that can occur while querying status sockets.
+#[derive(Error, Debug)]
+pub enum StatusError {
+    /// A status socket could not be read.
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    /// A process answered with a malformed status.
+    #[error("Malformed status: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Active transfers of one sendfile process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessStatus {
+    /// Process identifier.
+    pub pid: u32,
+    /// Transfers the process is running.
+    pub transfers: Vec<TransferStatus>,
+}
+
+/// Returns the directory status sockets are created in.
+pub fn default_status_dir() -> PathBuf {
+    dirs::runtime_dir()
+        .map(|dir| dir.join("sendfile"))
+        .unwrap_or_else(|| std::env::temp_dir().join("sendfile-status"))
+}
+
+/// Answers status queries for `registry` until dropped, after which the socket is removed.
+pub struct StatusServer {
+    path: PathBuf,
+}
+
+impl StatusServer {
+    /// Returns the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{query_status, serve_status};
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs::{self, DirBuilder},
+        io::{Read, Write},
+        os::unix::{
+            fs::DirBuilderExt,
+            net::{UnixListener, UnixStream},
+        },
+        path::Path,
+        thread,
+        time::Duration,
+    };
+
+    use log::{debug, warn};
+
+    use super::*;
+    use crate::threads::thread_name;
+
+    /// Time allowed to a process to answer a status query, and to a client to read the answer.
+    const QUERY_TIMEOUT_SECS: u64 = 2;
+
+    /// Starts answering status queries on `<pid>.sock` in `dir` from a background thread.
+    pub fn serve_status(
+        dir: &Path,
+        registry: &'static TransferRegistry,
+    ) -> Result<StatusServer, StatusError> {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let path = dir.join(format!("{}.sock", std::process::id()));
+        // Left behind by an earlier process with the same pid
+        let _ = fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path)?;
+        let server = StatusServer { path };
+        thread::Builder::new()
+            .name(thread_name("status", 0))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let result = stream.and_then(|mut stream| {
+                        // A client that never reads would hold up every later query
+                        stream.set_write_timeout(Some(Duration::from_secs(QUERY_TIMEOUT_SECS)))?;
+                        let mut response = serde_json::to_vec(&registry.snapshot())?;
+                        response.push(b'\n');
+                        stream.write_all(&response)
+                    });
+                    if let Err(e) = result {
+                        debug!("Failed to answer status query: {}", e);
+                    }
+                }
+            })?;
+        Ok(server)
+    }
+
+    /// Asks every process with a socket in `dir` for its active transfers.
+    ///
+    /// Sockets of processes that are gone are removed. Processes that don't answer in time or
+    /// answer with a malformed status are logged and left out.
+    pub fn query_status(dir: &Path) -> Result<Vec<ProcessStatus>, StatusError> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut processes = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(pid) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".sock"))
+                .and_then(|pid| pid.parse().ok())
+            else {
+                continue;
+            };
+
+            let mut stream = match UnixStream::connect(&path) {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to query {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let transfers = read_status(&mut stream)
+                .and_then(|response| serde_json::from_slice(&response).map_err(StatusError::from));
+            match transfers {
+                Ok(transfers) => processes.push(ProcessStatus { pid, transfers }),
+                Err(e) => warn!("Failed to query {:?}: {}", path, e),
+            }
+        }
+
+        processes.sort_by_key(|process| process.pid);
+        Ok(processes)
+    }
+
+    /// Reads the answer of a process to a status query.
+    fn read_status(stream: &mut UnixStream) -> Result<Vec<u8>, StatusError> {
+        stream.set_read_timeout(Some(Duration::from_secs(QUERY_TIMEOUT_SECS)))?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::stream::{handle::TransferControl, registry::TransferDirection};
+        use std::sync::Arc;
+
+        #[test]
+        fn test_serve_and_query_status() {
+            let dir = std::env::temp_dir().join(format!("sendfile_status_{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            // A socket whose process is gone
+            drop(UnixListener::bind(dir.join("1.sock")).unwrap());
+            // A process answering with a malformed status
+            let broken = UnixListener::bind(dir.join("2.sock")).unwrap();
+            let broken = thread::spawn(move || {
+                let (mut stream, _) = broken.accept().unwrap();
+                stream.write_all(b"not json").unwrap();
+            });
+
+            let registry: &'static TransferRegistry = Box::leak(Box::default());
+            let _registration = registry.register(
+                TransferDirection::Send,
+                String::from("10.0.0.2:7878"),
+                String::from("video.mkv"),
+                Arc::new(TransferControl::new()),
+            );
+            let server = serve_status(&dir, registry).unwrap();
+
+            let processes = query_status(&dir).unwrap();
+            assert_eq!(processes.len(), 1);
+            assert_eq!(processes[0].pid, std::process::id());
+            assert_eq!(processes[0].transfers, registry.snapshot());
+            assert!(!dir.join("1.sock").exists());
+            broken.join().unwrap();
+            fs::remove_file(dir.join("2.sock")).unwrap();
+
+            drop(server);
+            assert!(query_status(&dir).unwrap().is_empty());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+/// Status sockets are Unix domain sockets, unavailable on this platform.
+#[cfg(not(unix))]
+pub fn serve_status(
+    _dir: &Path,
+    _registry: &'static TransferRegistry,
+) -> Result<StatusServer, StatusError> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Status sockets require Unix").into())
+}
+
+/// Status sockets are Unix domain sockets, unavailable on this platform.
+#[cfg(not(unix))]
+pub fn query_status(_dir: &Path) -> Result<Vec<ProcessStatus>, StatusError> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Status sockets require Unix").into())
+}
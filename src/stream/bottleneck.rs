@@ -0,0 +1,220 @@
+//! Finding which stage of the transfer pipeline limits its speed.
+//!
+//! Each connection thread records the time it spends in every [Stage] it runs in the
+//! [StageTimings] of its transfer: the sender reads, compresses and sends blocks, the receiver
+//! waits for them, decompresses and writes them. A stage taking most of the recorded time is the
+//! bottleneck, reported with a hint on how to relieve it while the transfer runs (logged at the
+//! `info` level and shown by `sendfile status`) and in its summary.
+//!
+//! Each side only sees its own stages: time the receiver spends waiting for data includes the
+//! sender's reads and compression.
+
+use std::{
+    fmt::{self, Display},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Share of the recorded time a stage must take to be reported as the bottleneck.
+const BOTTLENECK_SHARE: f64 = 0.5;
+/// Least recorded time over which a bottleneck is told, shorter samples are noise.
+const MIN_SAMPLE: Duration = Duration::from_millis(200);
+/// Interval over which the live bottleneck is measured.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Stage of the transfer pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Sender reading blocks from its disk.
+    DiskRead,
+    /// Sender compressing blocks.
+    Compression,
+    /// Blocks in flight: the sender writing them to the connection, the receiver waiting for
+    /// them.
+    Network,
+    /// Receiver decompressing blocks.
+    Decompression,
+    /// Receiver writing blocks to its disk.
+    DiskWrite,
+}
+
+impl Stage {
+    /// Every stage, in pipeline order.
+    pub const ALL: [Self; 5] = [
+        Self::DiskRead,
+        Self::Compression,
+        Self::Network,
+        Self::Decompression,
+        Self::DiskWrite,
+    ];
+
+    /// Returns a one-line hint naming the stage as the bottleneck, with the options that may
+    /// relieve it.
+    pub fn hint(self) -> &'static str {
+        match self {
+            Self::DiskRead => {
+                "disk read is the bottleneck; consider a larger --block-size or --network-fs for network filesystems"
+            }
+            Self::Compression => "compression is the bottleneck; consider --no-compress",
+            Self::Network => {
+                "network is the bottleneck; consider `sendfile probe` to tune --block-size and --concurrency"
+            }
+            Self::Decompression => {
+                "decompression is the bottleneck; consider sending with --no-compress"
+            }
+            Self::DiskWrite => {
+                "disk write is the bottleneck; consider --network-fs for network filesystems or a faster destination"
+            }
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::DiskRead => "disk read",
+            Self::Compression => "compression",
+            Self::Network => "network",
+            Self::Decompression => "decompression",
+            Self::DiskWrite => "disk write",
+        };
+        f.pad(name)
+    }
+}
+
+/// Time spent in each [Stage] by the connections of a transfer, added up over every connection.
+#[derive(Debug, Default)]
+pub struct StageTimings {
+    nanos: [AtomicU64; 5],
+}
+
+impl StageTimings {
+    /// Adds `elapsed` to the time spent in `stage`.
+    pub fn record(&self, stage: Stage, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.nanos[stage.index()].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, recording the time it takes as spent in `stage`.
+    pub fn time<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let started_at = Instant::now();
+        let result = f();
+        self.record(stage, started_at.elapsed());
+        result
+    }
+
+    /// Returns the time spent in each stage so far.
+    pub fn snapshot(&self) -> StageSample {
+        StageSample(
+            self.nanos
+                .each_ref()
+                .map(|nanos| nanos.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+/// Time spent in each [Stage], in nanoseconds, as returned by [StageTimings::snapshot].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StageSample([u64; 5]);
+
+impl StageSample {
+    /// Returns the time spent in `stage`.
+    pub fn get(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.0[stage.index()])
+    }
+
+    /// Returns the time spent in each stage since `earlier` was taken.
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self(std::array::from_fn(|index| {
+            self.0[index].saturating_sub(earlier.0[index])
+        }))
+    }
+
+    /// Returns the stage that took most of the time, `None` if no stage dominates or too little
+    /// time was recorded to tell.
+    pub fn bottleneck(&self) -> Option<Stage> {
+        let total: u64 = self.0.iter().sum();
+        if total < MIN_SAMPLE.as_nanos() as u64 {
+            return None;
+        }
+        let slowest = Stage::ALL
+            .into_iter()
+            .max_by_key(|stage| self.0[stage.index()])?;
+        let share = self.0[slowest.index()] as f64 / total as f64;
+        (share >= BOTTLENECK_SHARE).then_some(slowest)
+    }
+}
+
+/// Follows the bottleneck of a running transfer over successive intervals.
+#[derive(Debug)]
+pub(crate) struct BottleneckMonitor {
+    last_sample: StageSample,
+    last_check: Instant,
+    current: Option<Stage>,
+}
+
+impl BottleneckMonitor {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_sample: StageSample::default(),
+            last_check: Instant::now(),
+            current: None,
+        }
+    }
+
+    /// Returns the bottleneck of the last interval, as of the last check.
+    pub(crate) fn current(&self) -> Option<Stage> {
+        self.current
+    }
+
+    /// Measures the bottleneck over the time since the last check, at most once per interval.
+    ///
+    /// Returns the new bottleneck when it changed to another stage.
+    pub(crate) fn check(&mut self, timings: &StageTimings) -> Option<Stage> {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return None;
+        }
+        let sample = timings.snapshot();
+        let bottleneck = sample.since(&self.last_sample).bottleneck();
+        self.last_sample = sample;
+        self.last_check = Instant::now();
+
+        let changed = bottleneck.is_some() && bottleneck != self.current;
+        self.current = bottleneck;
+        bottleneck.filter(|_| changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bottleneck_takes_most_of_the_time() {
+        let timings = StageTimings::default();
+        timings.record(Stage::DiskRead, Duration::from_millis(100));
+        timings.record(Stage::Compression, Duration::from_millis(700));
+        timings.record(Stage::Network, Duration::from_millis(200));
+        let first = timings.snapshot();
+        assert_eq!(first.bottleneck(), Some(Stage::Compression));
+
+        // Only the time since the previous sample counts
+        timings.record(Stage::Network, Duration::from_millis(300));
+        timings.record(Stage::DiskRead, Duration::from_millis(250));
+        let recent = timings.snapshot().since(&first);
+        assert_eq!(recent.get(Stage::Compression), Duration::ZERO);
+        assert_eq!(recent.bottleneck(), Some(Stage::Network));
+
+        // No stage dominates, or too little was recorded
+        timings.record(Stage::Compression, Duration::from_millis(200));
+        assert_eq!(timings.snapshot().since(&first).bottleneck(), None);
+        assert_eq!(StageSample::default().bottleneck(), None);
+    }
+}
@@ -0,0 +1,946 @@
+//! Directories sent as one transfer.
+//!
+//! Sending a directory file by file would take a handshake and a new set of data connections for
+//! every file. Instead, a sender given a directory offers it as a single file named after the
+//! directory, whose size is the size of all its files and whose hash is [Bundle::hash], and lists
+//! the files in a [FileListV1] once the receiver chose
+//! [MULTI_FILE_PROTOCOL_VERSION](crate::transport::MULTI_FILE_PROTOCOL_VERSION). The blocks of
+//! every file are then spread over the data connections like the blocks of a single file.
+//!
+//! Every file starts on a block of its own, and blocks are numbered across the bundle in file
+//! list order: file `i` holds the blocks from [Bundle::first_block] of `i` on. Receivers request
+//! blocks in that order, so the sender picks the order files are transferred in by the order it
+//! lists them in, see [FileOrder]. Blocks are
+//! requested with a [FileRequestV1](crate::transport::FileRequestV1) naming the file and the
+//! block within it, answered with a [FileDataV1](crate::transport::FileDataV1). Messages without
+//! a file index, e.g. `BlockUnreadable` or `BlockHashesRequest`, number blocks across the bundle.
+//!
+//! The progress of every file is followed in a [BundleProgress], listed with the status of the
+//! transfer (see [crate::stream::registry]). Once the transfer completes, the bytes and time each
+//! file and top-level directory took are logged, and recorded by the sender in the history entry
+//! of the transfer with its receipt, see [BundleStats].
+//!
+//! Each received file is verified against its own hash, receivers with
+//! [ReceiveOptions::atomic_batch](crate::stream::options::ReceiveOptions::atomic_batch) writing
+//! the files next to the destination and moving them into it once all are, see
+//! [BundleSink::commit]. Bundles are not resumed, and their blocks are neither sent as UDP
+//! datagrams nor named as blocks of zeros: `send` refuses a directory with `--udp` or `--dry-run`
+//! (see [SendArgs::directory_conflicts](crate::cli::SendArgs::directory_conflicts)), and
+//! receivers scanning files with `--scan-cmd` decline directories, which can't be scanned.
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io, mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    file::{
+        device::file_size,
+        error::FileHashError,
+        utils::{get_file_blake3_hash_with, read_file_block_at, write_file_block_at, HashStrategy},
+        FileMetadata,
+    },
+    stream::{sink::BlockSink, source::BlockSource, throttle::RateLimiter},
+    threads::WorkerOptions,
+    transport::{FileEntryV1, FileListV1},
+    units::{Count, Elapsed, Size},
+};
+
+/// Errors of a directory sent or received as one transfer.
+#[derive(Error, Debug)]
+pub enum BundleError {
+    /// A path of the file list is empty, absolute, leaves the directory or isn't valid UTF-8.
+    #[error("Invalid path {0:?} in the directory, use relative '/'-separated UTF-8 paths")]
+    InvalidPath(String),
+    /// Two files of the file list have the same path.
+    #[error("{0:?} is listed twice in the directory")]
+    DuplicatePath(String),
+    /// The file list doesn't fit in a message.
+    #[error("Directory has too many files to send at once, send its subdirectories instead")]
+    ListTooLarge,
+    /// The directory has more blocks than sequence numbers can count.
+    #[error("Directory has too many blocks, send it with a larger --block-size")]
+    TooManyBlocks,
+    /// The file list is not the one the handshake offered.
+    #[error("File list does not match the offered directory")]
+    ListMismatch,
+    /// A file of the directory could not be listed, read or written.
+    #[error("{path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    /// A file of the directory could not be hashed.
+    #[error("Failed to hash {path:?}: {source}")]
+    Hash {
+        path: PathBuf,
+        source: FileHashError,
+    },
+    /// A received file doesn't have the hash the sender listed.
+    #[error("Integrity check failed for {0:?}")]
+    FileMismatch(PathBuf),
+}
+
+/// Files of a directory sent as one transfer, and where their blocks are numbered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    files: Vec<FileEntryV1>,
+    block_size: u32,
+    /// Number of the first block of every file, followed by the number of blocks of the bundle.
+    first_blocks: Vec<u32>,
+}
+
+impl Bundle {
+    /// Creates the bundle of `files`, in the order their blocks are numbered, split into blocks
+    /// of `block_size` bytes.
+    ///
+    /// # Returns
+    ///
+    /// The bundle, or a [BundleError] if a path is invalid or listed twice, or if the files have
+    /// more blocks than a `u32` counts.
+    pub fn new(files: Vec<FileEntryV1>, block_size: u32) -> Result<Self, BundleError> {
+        let mut paths = HashSet::new();
+        let mut first_blocks = Vec::with_capacity(files.len() + 1);
+        let mut next_block = 0u64;
+        for file in &files {
+            check_path(&file.path)?;
+            if !paths.insert(file.path.as_str()) {
+                return Err(BundleError::DuplicatePath(file.path.clone()));
+            }
+            first_blocks.push(next_block as u32);
+            next_block += file.size.div_ceil(block_size as u64);
+            if next_block > u32::MAX as u64 {
+                return Err(BundleError::TooManyBlocks);
+            }
+        }
+        first_blocks.push(next_block as u32);
+
+        Ok(Self {
+            files,
+            block_size,
+            first_blocks,
+        })
+    }
+
+    /// Returns the files of the bundle.
+    pub fn files(&self) -> &[FileEntryV1] {
+        &self.files
+    }
+
+    /// Returns the size of the blocks the files are split into.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Returns the number of blocks of every file together.
+    pub fn total_blocks(&self) -> u32 {
+        self.first_blocks[self.files.len()]
+    }
+
+    /// Returns the size of every file together.
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+
+    /// Returns the number of the first block of file `file_index` in the bundle.
+    pub fn first_block(&self, file_index: u32) -> Option<u32> {
+        self.first_blocks
+            .get(file_index as usize)
+            .filter(|_| (file_index as usize) < self.files.len())
+            .copied()
+    }
+
+    /// Returns the hash the bundle is offered with: the BLAKE3 hash of the path, size and hash
+    /// of every file, in order, so it changes with any of them.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        for file in &self.files {
+            hasher.update(&(file.path.len() as u64).to_le_bytes());
+            hasher.update(file.path.as_bytes());
+            hasher.update(&file.size.to_le_bytes());
+            hasher.update(&file.hash);
+        }
+        *hasher.finalize().as_bytes()
+    }
+
+    /// Returns the file block `seq` of the bundle belongs to, and its number within the file.
+    pub fn locate(&self, seq: u32) -> Option<(u32, u32)> {
+        if seq >= self.total_blocks() {
+            return None;
+        }
+        // Empty files have no block, the last file starting at or before `seq` holds it
+        let file_index = self.first_blocks.partition_point(|&first| first <= seq) - 1;
+        Some((file_index as u32, seq - self.first_blocks[file_index]))
+    }
+
+    /// Returns the number in the bundle of block `seq` of file `file_index`, `None` if there is
+    /// no such block.
+    pub fn bundle_seq(&self, file_index: u32, seq: u32) -> Option<u32> {
+        let first = self.first_block(file_index)?;
+        let end = self.first_blocks[file_index as usize + 1];
+        first.checked_add(seq).filter(|&seq| seq < end)
+    }
+
+    /// Returns the length of block `seq` of the bundle, 0 past its last block.
+    pub fn block_len(&self, seq: u32) -> u64 {
+        let Some((file_index, file_seq)) = self.locate(seq) else {
+            return 0;
+        };
+        let offset = file_seq as u64 * self.block_size as u64;
+        self.files[file_index as usize]
+            .size
+            .saturating_sub(offset)
+            .min(self.block_size as u64)
+    }
+
+    /// Returns the message listing the files of the bundle.
+    pub fn file_list(&self) -> FileListV1 {
+        FileListV1 {
+            files: self.files.clone(),
+        }
+    }
+
+    /// Returns where file `file_index` is stored under `root`.
+    fn path_in(&self, root: &Path, file_index: u32) -> PathBuf {
+        let mut path = root.to_path_buf();
+        path.extend(self.files[file_index as usize].path.split('/'));
+        path
+    }
+}
+
+/// Where a file of a bundle is at, see [FileStatus].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileState {
+    /// None of its blocks went through yet.
+    Queued,
+    /// Some of its blocks went through, or all of them and the receiver is verifying it.
+    Transferring,
+    /// Every block went through, and the receiver verified the file.
+    Done,
+    /// The received file doesn't have the hash the sender listed.
+    Failed,
+}
+
+/// Progress of a file of a bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStatus {
+    /// `/`-separated path of the file in the directory.
+    pub path: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Bytes of the file transferred so far.
+    pub bytes_transferred: u64,
+    pub state: FileState,
+}
+
+/// [FileProgress::outcome] of a file not verified yet.
+const UNVERIFIED: u8 = 0;
+/// [FileProgress::outcome] of a file that matched its hash.
+const VERIFIED: u8 = 1;
+/// [FileProgress::outcome] of a file that didn't match its hash.
+const MISMATCHED: u8 = 2;
+
+/// Bytes and time that a file, or the files under a top-level directory, took in a transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathStats {
+    /// `/`-separated path of the file, or name of the top-level directory, `.` for the files at
+    /// the root of the bundle.
+    pub path: String,
+    /// Number of files counted.
+    pub files: u64,
+    /// Bytes transferred, blocks sent again after a failed attempt included.
+    pub bytes: u64,
+    /// Time spent on their blocks in milliseconds: the time between two blocks of the transfer
+    /// is counted for the file of the later one, so the durations of every file add up to the
+    /// transfer.
+    pub duration_ms: u64,
+}
+
+/// Breakdown of a bundle transfer, per file in file list order and per top-level directory in
+/// order of appearance, see [BundleProgress::stats].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleStats {
+    pub files: Vec<PathStats>,
+    pub directories: Vec<PathStats>,
+}
+
+impl BundleStats {
+    /// Logs the top-level directories that took longest first, then every file at the `debug`
+    /// level.
+    pub fn log(&self) {
+        let mut directories: Vec<&PathStats> = self.directories.iter().collect();
+        directories.sort_by_key(|directory| Reverse(directory.duration_ms));
+        for directory in directories {
+            info!(
+                "{}: {} files, {} in {}",
+                directory.path,
+                Count(directory.files),
+                Size(directory.bytes),
+                Elapsed(Duration::from_millis(directory.duration_ms))
+            );
+        }
+        for file in &self.files {
+            debug!(
+                "{}: {} in {}",
+                file.path,
+                Size(file.bytes),
+                Elapsed(Duration::from_millis(file.duration_ms))
+            );
+        }
+    }
+}
+
+/// Live progress of the files of a bundle, counted as their blocks go through.
+#[derive(Debug)]
+pub struct BundleProgress {
+    files: Vec<FileProgress>,
+    /// Whether files are only done once verified, as on the receiver. On the sender, a file is
+    /// done once all of its blocks are sent.
+    verifies: bool,
+    /// When the last block of any file went through, or the progress was created.
+    last_block: Mutex<Instant>,
+}
+
+#[derive(Debug)]
+struct FileProgress {
+    path: String,
+    size: u64,
+    bytes: AtomicU64,
+    /// Time spent on the blocks of the file in microseconds, see [PathStats::duration_ms].
+    elapsed: AtomicU64,
+    /// [UNVERIFIED], [VERIFIED] or [MISMATCHED].
+    outcome: AtomicU8,
+}
+
+impl BundleProgress {
+    /// Follows the files of `bundle`, none of which went through yet. With `verifies`, files are
+    /// done once [Self::verified] rather than once all of their blocks went through.
+    pub fn new(bundle: &Bundle, verifies: bool) -> Self {
+        let files = bundle
+            .files()
+            .iter()
+            .map(|file| FileProgress {
+                path: file.path.clone(),
+                size: file.size,
+                bytes: AtomicU64::new(0),
+                elapsed: AtomicU64::new(0),
+                outcome: AtomicU8::new(UNVERIFIED),
+            })
+            .collect();
+        Self {
+            files,
+            verifies,
+            last_block: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Counts `bytes` more of file `file_index` as transferred, and the time since the previous
+    /// block as spent on it.
+    pub fn add_bytes(&self, file_index: u32, bytes: u64) {
+        if let Some(file) = self.files.get(file_index as usize) {
+            let now = Instant::now();
+            let previous = mem::replace(
+                &mut *self.last_block.lock().unwrap_or_else(|e| e.into_inner()),
+                now,
+            );
+            file.bytes.fetch_add(bytes, Ordering::SeqCst);
+            file.elapsed.fetch_add(
+                now.duration_since(previous).as_micros() as u64,
+                Ordering::SeqCst,
+            );
+        }
+    }
+
+    /// Records whether file `file_index` matched its hash once received.
+    pub fn verified(&self, file_index: u32, matched: bool) {
+        if let Some(file) = self.files.get(file_index as usize) {
+            let outcome = if matched { VERIFIED } else { MISMATCHED };
+            file.outcome.store(outcome, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the progress of every file, in file list order.
+    pub fn snapshot(&self) -> Vec<FileStatus> {
+        self.files
+            .iter()
+            .map(|file| {
+                // Blocks sent again after a failed attempt are counted twice
+                let bytes_transferred = file.bytes.load(Ordering::SeqCst).min(file.size);
+                let complete = bytes_transferred == file.size;
+                let state = match file.outcome.load(Ordering::SeqCst) {
+                    VERIFIED => FileState::Done,
+                    MISMATCHED => FileState::Failed,
+                    _ if complete && !self.verifies => FileState::Done,
+                    _ if bytes_transferred > 0 => FileState::Transferring,
+                    _ => FileState::Queued,
+                };
+                FileStatus {
+                    path: file.path.clone(),
+                    size: file.size,
+                    bytes_transferred,
+                    state,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the bytes and time every file and top-level directory took so far.
+    pub fn stats(&self) -> BundleStats {
+        let mut stats = BundleStats::default();
+        let mut elapsed = Vec::new();
+        for file in &self.files {
+            let bytes = file.bytes.load(Ordering::SeqCst);
+            let micros = file.elapsed.load(Ordering::SeqCst);
+            stats.files.push(PathStats {
+                path: file.path.clone(),
+                files: 1,
+                bytes,
+                duration_ms: micros / 1000,
+            });
+
+            let directory = match file.path.split_once('/') {
+                Some((directory, _)) => directory,
+                None => ".",
+            };
+            let index = match stats.directories.iter().position(|d| d.path == directory) {
+                Some(index) => index,
+                None => {
+                    stats.directories.push(PathStats {
+                        path: directory.to_string(),
+                        files: 0,
+                        bytes: 0,
+                        duration_ms: 0,
+                    });
+                    elapsed.push(0);
+                    stats.directories.len() - 1
+                }
+            };
+            stats.directories[index].files += 1;
+            stats.directories[index].bytes += bytes;
+            // Summed before rounding, so many small files don't round down to nothing
+            elapsed[index] += micros;
+        }
+        for (directory, micros) in stats.directories.iter_mut().zip(elapsed) {
+            directory.duration_ms = micros / 1000;
+        }
+        stats
+    }
+}
+
+/// Checks that `path` names a file inside the directory, so a hostile sender can't write
+/// elsewhere.
+fn check_path(path: &str) -> Result<(), BundleError> {
+    let valid = !path.is_empty()
+        && !path.contains(['\\', '\0'])
+        && path
+            .split('/')
+            .all(|component| !matches!(component, "" | "." | ".."));
+    if !valid {
+        return Err(BundleError::InvalidPath(path.to_string()));
+    }
+    Ok(())
+}
+
+/// Order the files of a directory are listed, and so transferred, in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FileOrder {
+    /// By relative path.
+    #[default]
+    Name,
+    /// Smallest file first, so the receiver gets many files done early.
+    SizeAsc,
+    /// Largest file first.
+    SizeDesc,
+    /// Most recently modified file first, e.g. to collect the latest logs first.
+    Mtime,
+}
+
+/// Lists the regular files under `root` by path, directories
+/// walked as they come. Symbolic links and special files are skipped.
+///
+/// # Returns
+///
+/// The `/`-separated path of every file relative to `root`, with its location on disk.
+pub fn list_files(root: &Path) -> Result<Vec<(String, PathBuf)>, BundleError> {
+    let mut files = Vec::new();
+    list_files_in(root, "", &mut files)?;
+    Ok(files)
+}
+
+fn list_files_in(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), BundleError> {
+    let io_error = |source| BundleError::Io {
+        path: dir.to_path_buf(),
+        source,
+    };
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .map_err(io_error)?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            return Err(BundleError::InvalidPath(path.display().to_string()));
+        };
+        let relative = format!("{}{}", prefix, name);
+        let file_type = entry.file_type().map_err(io_error)?;
+        if file_type.is_dir() {
+            list_files_in(&path, &format!("{}/", relative), files)?;
+        } else if file_type.is_file() {
+            files.push((relative, path));
+        } else {
+            warn!("Skipping {:?}, not a regular file", path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the size of every regular file under `root` together.
+pub fn directory_size(root: &Path) -> Result<u64, BundleError> {
+    list_files(root)?
+        .into_iter()
+        .map(|(_, path)| {
+            file_size(&path).map_err(|source| BundleError::Io {
+                path: path.clone(),
+                source,
+            })
+        })
+        .sum()
+}
+
+/// The files of a directory, read as the blocks of its [Bundle].
+pub struct BundleSource {
+    root: PathBuf,
+    bundle: Bundle,
+}
+
+impl BundleSource {
+    /// Lists and hashes the files under `root` in `order`, split into blocks of `block_size`
+    /// bytes.
+    ///
+    /// # Returns
+    ///
+    /// The source, and the metadata the directory is offered with.
+    pub fn open(
+        root: &Path,
+        block_size: u32,
+        order: FileOrder,
+        strategy: HashStrategy,
+        workers: &WorkerOptions,
+    ) -> Result<(Self, FileMetadata), BundleError> {
+        let mut files = Vec::new();
+        for (relative, path) in list_files(root)? {
+            debug!("Hashing {:?}", path);
+            let io_error = |source| BundleError::Io {
+                path: path.clone(),
+                source,
+            };
+            let size = file_size(&path).map_err(io_error)?;
+            let modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .map_err(io_error)?;
+            let hash = get_file_blake3_hash_with(&path, strategy, workers)
+                .map_err(|source| BundleError::Hash { path, source })?;
+            let file = FileEntryV1 {
+                path: relative,
+                size,
+                hash,
+            };
+            files.push((file, modified));
+        }
+        // Stable sorts, files of the same size or time stay in path order
+        match order {
+            FileOrder::Name => {}
+            FileOrder::SizeAsc => files.sort_by_key(|(file, _)| file.size),
+            FileOrder::SizeDesc => files.sort_by_key(|(file, _)| Reverse(file.size)),
+            FileOrder::Mtime => files.sort_by_key(|(_, modified)| Reverse(*modified)),
+        }
+        let files = files.into_iter().map(|(file, _)| file).collect();
+        let bundle = Bundle::new(files, block_size)?;
+        info!(
+            "Sending directory {:?} of {} files",
+            root,
+            bundle.files().len()
+        );
+
+        let name = root
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("unnamed_directory")
+            .to_string();
+        let metadata = FileMetadata::new(name, bundle.total_size(), bundle.hash());
+        let source = Self {
+            root: root.to_path_buf(),
+            bundle,
+        };
+        Ok((source, metadata))
+    }
+}
+
+impl BlockSource for BundleSource {
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        let Some((file_index, file_seq)) = self.bundle.locate(seq) else {
+            return Ok(Vec::new());
+        };
+        let file = File::open(self.bundle.path_in(&self.root, file_index))?;
+        read_file_block_at(&file, file_seq, block_size)
+    }
+
+    fn bundle(&self) -> Option<&Bundle> {
+        Some(&self.bundle)
+    }
+}
+
+/// Writes the blocks of a [Bundle] to its files under a directory.
+///
+/// Files are opened for every block, so directories of many files don't hold as many handles.
+pub struct BundleSink<'a> {
+    root: PathBuf,
+    bundle: &'a Bundle,
+    /// Limits the rate blocks are written at, `None` writes them as fast as they arrive.
+    write_limit: Option<RateLimiter>,
+}
+
+impl<'a> BundleSink<'a> {
+    /// Creates every file of `bundle` under `root` with its final size, over any file already
+    /// there, and the directories holding them.
+    pub fn create(root: &Path, bundle: &'a Bundle) -> Result<Self, BundleError> {
+        fs::create_dir_all(root).map_err(|source| BundleError::Io {
+            path: root.to_path_buf(),
+            source,
+        })?;
+        for (file_index, file) in bundle.files().iter().enumerate() {
+            let path = bundle.path_in(root, file_index as u32);
+            let created = match path.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(()),
+            }
+            .and_then(|()| File::create(&path))
+            .and_then(|created| created.set_len(file.size));
+            created.map_err(|source| BundleError::Io { path, source })?;
+        }
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            bundle,
+            write_limit: None,
+        })
+    }
+
+    /// Limits writes to `bytes_per_second`, blocking each write until the rate allows it. `None`
+    /// leaves writes unlimited.
+    pub fn with_write_limit(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.write_limit = bytes_per_second.map(RateLimiter::new);
+        self
+    }
+
+    /// Hashes every file under the directory and checks it against the sender's hash, recording
+    /// the outcome of each file in `progress`.
+    pub fn verify(
+        &self,
+        strategy: HashStrategy,
+        workers: &WorkerOptions,
+        progress: &BundleProgress,
+    ) -> Result<(), BundleError> {
+        for (file_index, file) in self.bundle.files().iter().enumerate() {
+            let path = self.bundle.path_in(&self.root, file_index as u32);
+            let hash = get_file_blake3_hash_with(&path, strategy, workers).map_err(|source| {
+                BundleError::Hash {
+                    path: path.clone(),
+                    source,
+                }
+            })?;
+            progress.verified(file_index as u32, hash == file.hash);
+            if hash != file.hash {
+                return Err(BundleError::FileMismatch(path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves every file of the bundle from the directory it was written to under `root`,
+    /// replacing files already there, then removes the directory it was written to, for
+    /// receivers staging the files until all of them are verified.
+    ///
+    /// Files already moved stay under `root` if one can't be.
+    pub fn commit(&self, root: &Path) -> Result<(), BundleError> {
+        for file_index in 0..self.bundle.files().len() as u32 {
+            let staged = self.bundle.path_in(&self.root, file_index);
+            let path = self.bundle.path_in(root, file_index);
+            match path.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(()),
+            }
+            .and_then(|()| fs::rename(&staged, &path))
+            .map_err(|source| BundleError::Io { path, source })?;
+        }
+        // Only the directories of the files are left
+        fs::remove_dir_all(&self.root).map_err(|source| BundleError::Io {
+            path: self.root.clone(),
+            source,
+        })
+    }
+
+    /// Opens the file block `seq` of the bundle belongs to, `None` past the last block.
+    fn open_block(&self, seq: u32, write: bool) -> io::Result<Option<(File, u32)>> {
+        let Some((file_index, file_seq)) = self.bundle.locate(seq) else {
+            return Ok(None);
+        };
+        let path = self.bundle.path_in(&self.root, file_index);
+        let file = OpenOptions::new().read(true).write(write).open(path)?;
+        Ok(Some((file, file_seq)))
+    }
+}
+
+impl BlockSink for BundleSink<'_> {
+    fn write_block(&self, seq: u32, block_size: u32, data: &[u8]) -> io::Result<()> {
+        let Some((file, file_seq)) = self.open_block(seq, true)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Block {} is past the end of the directory", seq),
+            ));
+        };
+        if let Some(limiter) = &self.write_limit {
+            limiter.acquire(data.len() as u64);
+        }
+        write_file_block_at(&file, file_seq, block_size, data)
+    }
+
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        match self.open_block(seq, false)? {
+            Some((file, file_seq)) => read_file_block_at(&file, file_seq, block_size),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64) -> FileEntryV1 {
+        FileEntryV1 {
+            path: path.to_string(),
+            size,
+            hash: [size as u8; 32],
+        }
+    }
+
+    #[test]
+    fn test_bundle_numbers_blocks_across_files() {
+        let bundle = Bundle::new(
+            vec![entry("a", 10), entry("empty", 0), entry("sub/b", 4)],
+            4,
+        )
+        .unwrap();
+        assert_eq!(bundle.total_blocks(), 4);
+        assert_eq!(bundle.total_size(), 14);
+
+        assert_eq!(bundle.locate(0), Some((0, 0)));
+        assert_eq!(bundle.locate(2), Some((0, 2)));
+        // The empty file has no block
+        assert_eq!(bundle.locate(3), Some((2, 0)));
+        assert_eq!(bundle.locate(4), None);
+        assert_eq!(bundle.bundle_seq(2, 0), Some(3));
+        assert_eq!(bundle.bundle_seq(0, 3), None);
+        assert_eq!(bundle.bundle_seq(1, 0), None);
+        assert_eq!(bundle.bundle_seq(3, 0), None);
+        assert_eq!(bundle.block_len(2), 2);
+        assert_eq!(bundle.block_len(3), 4);
+
+        // Any change to the list changes the hash
+        let renamed = Bundle::new(
+            vec![entry("a", 10), entry("empty", 0), entry("sub/c", 4)],
+            4,
+        )
+        .unwrap();
+        assert_ne!(bundle.hash(), renamed.hash());
+    }
+
+    #[test]
+    fn test_bundle_progress() {
+        let bundle =
+            Bundle::new(vec![entry("a", 10), entry("b", 4), entry("empty", 0)], 4).unwrap();
+        let states = |progress: &BundleProgress| {
+            progress
+                .snapshot()
+                .into_iter()
+                .map(|file| (file.bytes_transferred, file.state))
+                .collect::<Vec<_>>()
+        };
+
+        // Sent files are done once every block is sent, blocks sent twice are counted once
+        let sent = BundleProgress::new(&bundle, false);
+        sent.add_bytes(0, 4);
+        sent.add_bytes(1, 4);
+        sent.add_bytes(1, 4);
+        assert_eq!(
+            states(&sent),
+            [
+                (4, FileState::Transferring),
+                (4, FileState::Done),
+                (0, FileState::Done)
+            ]
+        );
+
+        // Received files are done once verified
+        let received = BundleProgress::new(&bundle, true);
+        received.add_bytes(0, 10);
+        assert_eq!(
+            states(&received),
+            [
+                (10, FileState::Transferring),
+                (0, FileState::Queued),
+                (0, FileState::Queued)
+            ]
+        );
+        received.verified(0, true);
+        received.verified(1, false);
+        assert_eq!(received.snapshot()[0].state, FileState::Done);
+        assert_eq!(received.snapshot()[1].state, FileState::Failed);
+    }
+
+    #[test]
+    fn test_bundle_stats() {
+        let files = vec![entry("logs/a", 8), entry("logs/b", 4), entry("c", 4)];
+        let progress = BundleProgress::new(&Bundle::new(files, 4).unwrap(), false);
+        progress.add_bytes(0, 4);
+        progress.add_bytes(0, 4);
+        progress.add_bytes(1, 4);
+        // The time until a block goes through is spent on its file
+        std::thread::sleep(Duration::from_millis(50));
+        progress.add_bytes(2, 4);
+
+        let stats = progress.stats();
+        let summary = |stats: &[PathStats]| {
+            stats
+                .iter()
+                .map(|stats| (stats.path.clone(), stats.files, stats.bytes))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            summary(&stats.files),
+            [
+                (String::from("logs/a"), 1, 8),
+                (String::from("logs/b"), 1, 4),
+                (String::from("c"), 1, 4)
+            ]
+        );
+        assert_eq!(
+            summary(&stats.directories),
+            [(String::from("logs"), 2, 12), (String::from("."), 1, 4)]
+        );
+        assert!(stats.files[2].duration_ms >= 50);
+        assert_eq!(stats.directories[1].duration_ms, stats.files[2].duration_ms);
+        assert!(stats.directories[0].duration_ms < 50);
+
+        // Recorded in the history as JSON
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["directories"][0]["bytes"], 12);
+        assert_eq!(serde_json::from_value::<BundleStats>(json).unwrap(), stats);
+    }
+
+    #[test]
+    fn test_bundle_refuses_escaping_paths() {
+        for path in [
+            "",
+            "/etc/passwd",
+            "../x",
+            "a/../../x",
+            "a//b",
+            "./a",
+            "a\\b",
+        ] {
+            assert!(
+                matches!(
+                    Bundle::new(vec![entry(path, 1)], 4),
+                    Err(BundleError::InvalidPath(_))
+                ),
+                "{:?} accepted",
+                path
+            );
+        }
+        assert!(matches!(
+            Bundle::new(vec![entry("a", 1), entry("a", 2)], 4),
+            Err(BundleError::DuplicatePath(_))
+        ));
+        assert!(matches!(
+            Bundle::new(vec![entry("a", u64::MAX / 2)], 1),
+            Err(BundleError::TooManyBlocks)
+        ));
+    }
+
+    #[test]
+    fn test_bundle_source_and_sink_round_trip() {
+        let base = std::env::temp_dir().join(format!("sendfile-bundle-{}", std::process::id()));
+        let source_dir = base.join("photos");
+        fs::create_dir_all(source_dir.join("2024")).unwrap();
+        fs::write(source_dir.join("b.txt"), b"0123456789").unwrap();
+        fs::write(source_dir.join("2024/a.txt"), b"abc").unwrap();
+        fs::write(source_dir.join("empty"), b"").unwrap();
+
+        let (source, metadata) = BundleSource::open(
+            &source_dir,
+            4,
+            FileOrder::Name,
+            HashStrategy::Sequential,
+            &WorkerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(metadata.name(), "photos");
+        assert_eq!(metadata.size(), 13);
+        let bundle = source.bundle().unwrap();
+        let paths: Vec<_> = bundle
+            .files()
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(paths, ["2024/a.txt", "b.txt", "empty"]);
+        assert_eq!(metadata.hash(), bundle.hash());
+
+        let target = base.join("received");
+        let sink = BundleSink::create(&target, bundle).unwrap();
+        for seq in (0..bundle.total_blocks()).rev() {
+            let block = source.read_block(seq, 4).unwrap();
+            sink.write_block(seq, 4, &block).unwrap();
+        }
+        let progress = BundleProgress::new(bundle, true);
+        sink.verify(
+            HashStrategy::Sequential,
+            &WorkerOptions::default(),
+            &progress,
+        )
+        .unwrap();
+        assert_eq!(fs::read(target.join("b.txt")).unwrap(), b"0123456789");
+        assert_eq!(fs::read(target.join("empty")).unwrap(), b"");
+
+        fs::write(target.join("2024/a.txt"), b"abd").unwrap();
+        assert!(matches!(
+            sink.verify(
+                HashStrategy::Sequential,
+                &WorkerOptions::default(),
+                &progress
+            ),
+            Err(BundleError::FileMismatch(_))
+        ));
+        assert_eq!(progress.snapshot()[0].state, FileState::Failed);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
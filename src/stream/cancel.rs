@@ -0,0 +1,78 @@
+//! Cooperative cancellation of transfers, with Ctrl-C or
+//! [TransferHandle::cancel](crate::stream::handle::TransferHandle::cancel).
+//!
+//! A side cancelling a transfer keeps the blocks written so far, like at a time limit, and tells
+//! its peer with a `Cancel` message before closing its connections if the peer advertised
+//! [Capabilities::CANCEL]: the sender on its data connections, the receiver on its data
+//! connections and the handshake connection. The peer then stops at once, instead of retrying or
+//! waiting for new connections, and fails with [SendFileError::CancelledByPeer]. Peers lacking
+//! the capability only see the connections close.
+//!
+//! [Capabilities::CANCEL]: crate::capabilities::Capabilities::CANCEL
+//! [SendFileError::CancelledByPeer]: crate::stream::error::SendFileError::CancelledByPeer
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use log::warn;
+
+use crate::{stream::registry::TransferRegistry, threads::thread_name};
+
+/// Exit status of a process interrupted with Ctrl-C, the shell's for SIGINT.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Interval at which the interrupt watcher checks for Ctrl-C.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of times SIGINT was received since [cancel_on_interrupt].
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the reason a `role` ("sender" or "receiver") cancelling the transfer gives its peer.
+pub(crate) fn cancel_reason(role: &str) -> String {
+    format!("cancelled by the {}", role)
+}
+
+/// Cancels the transfers of `registry` on the first Ctrl-C, so their peers are told and the
+/// blocks received so far are kept. The process exits right away if no transfer is running, or
+/// on a second Ctrl-C.
+///
+/// # Returns
+///
+/// An error if the signal handler or its watcher thread could not be set up.
+pub fn cancel_on_interrupt(registry: &'static TransferRegistry) -> std::io::Result<()> {
+    // SAFETY: the handler only touches an atomic and calls `_exit`, both async-signal-safe
+    let previous = unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+    if previous == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error());
+    }
+    thread::Builder::new()
+        .name(thread_name("interrupt", 0))
+        .spawn(move || {
+            while INTERRUPTS.load(Ordering::SeqCst) == 0 {
+                thread::sleep(INTERRUPT_POLL_INTERVAL);
+            }
+            match registry.cancel_all() {
+                0 => std::process::exit(INTERRUPTED_EXIT_CODE),
+                count => warn!(
+                    "Interrupted, cancelling {} transfer(s), press Ctrl-C again to quit at once",
+                    count
+                ),
+            }
+        })?;
+    Ok(())
+}
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    if INTERRUPTS.fetch_add(1, Ordering::SeqCst) > 0 {
+        // SAFETY: `_exit` is async-signal-safe
+        unsafe { libc::_exit(INTERRUPTED_EXIT_CODE) };
+    }
+}
@@ -0,0 +1,195 @@
+//! CRC-32 checksums of data blocks.
+//!
+//! Every block sent is checksummed by both peers, so its cost adds up on fast links. By default
+//! the checksum is computed by `crc-fast`, which picks the fastest carry-less multiplication
+//! instructions of the CPU at runtime (PCLMULQDQ/VPCLMULQDQ on x86, PMULL on ARMv8). The
+//! implementation can be overridden with `--checksum-impl`, e.g. to rule it out when debugging
+//! checksum mismatches. Every implementation computes the same checksums, so peers using
+//! different ones interoperate.
+//!
+//! Blocks are checksummed with CRC-32 (ISO-HDLC) unless both peers support CRC-32C
+//! (Castagnoli), see [ChecksumAlgorithm]. Frame headers always carry a CRC-32.
+//!
+//! `cargo bench --bench checksum` measures the cost of each implementation per block.
+
+use std::{
+    fmt::{self, Display},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use clap::ValueEnum;
+use crc_fast::{checksum, get_calculator_target, CrcAlgorithm};
+
+use crate::capabilities::ChecksumAlgorithm;
+
+/// Target `crc-fast` reports when the CPU lacks the instructions it accelerates checksums with.
+const SOFTWARE_TARGET: &str = "software-fallback-tables";
+
+/// [ChecksumImpl] used by [block_checksum], as set by [ChecksumImpl::set_global].
+static GLOBAL_IMPL: AtomicU8 = AtomicU8::new(ChecksumImpl::Auto as u8);
+
+/// Lookup table of the bytewise CRC-32 (ISO-HDLC) used by [ChecksumImpl::Software].
+const CRC32_TABLE: [u32; 256] = crc32_table(0xEDB8_8320);
+
+/// Lookup table of the bytewise CRC-32C (Castagnoli) used by [ChecksumImpl::Software].
+const CRC32C_TABLE: [u32; 256] = crc32_table(0x82F6_3B78);
+
+/// Implementation computing block checksums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ChecksumImpl {
+    /// The fastest implementation the CPU supports.
+    #[default]
+    Auto,
+    /// Instructions accelerating CRCs, refused on CPUs without them.
+    Hardware,
+    /// Portable table lookups, slow but independent of the CPU.
+    Software,
+}
+
+impl ChecksumImpl {
+    /// Returns the implementation used by [block_checksum], [ChecksumImpl::Auto] unless changed
+    /// with [ChecksumImpl::set_global].
+    pub fn global() -> Self {
+        match GLOBAL_IMPL.load(Ordering::Relaxed) {
+            1 => Self::Hardware,
+            2 => Self::Software,
+            _ => Self::Auto,
+        }
+    }
+
+    /// Makes this the implementation used by [block_checksum] for the rest of the process.
+    ///
+    /// # Returns
+    ///
+    /// An error if this is [ChecksumImpl::Hardware] and the CPU has no accelerated
+    /// implementation, in which case the global implementation is left unchanged.
+    pub fn set_global(self) -> Result<(), String> {
+        if self == Self::Hardware && hardware_target().is_none() {
+            return Err(String::from(
+                "this CPU has no hardware accelerated CRC-32 implementation",
+            ));
+        }
+        GLOBAL_IMPL.store(self as u8, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns the CRC-32 (ISO-HDLC) of `data` computed by this implementation.
+    pub fn checksum(self, data: &[u8]) -> u32 {
+        match self {
+            Self::Auto | Self::Hardware => checksum(CrcAlgorithm::Crc32IsoHdlc, data) as u32,
+            Self::Software => software_crc32(&CRC32_TABLE, data),
+        }
+    }
+
+    /// Returns the CRC-32C (Castagnoli) of `data` computed by this implementation.
+    pub fn crc32c(self, data: &[u8]) -> u32 {
+        match self {
+            Self::Auto | Self::Hardware => checksum(CrcAlgorithm::Crc32Iscsi, data) as u32,
+            Self::Software => software_crc32(&CRC32C_TABLE, data),
+        }
+    }
+
+    /// Returns a description of what computes the checksums, e.g.
+    /// `hardware (x86_64-sse-pclmulqdq)`.
+    pub fn describe(self) -> String {
+        match (self, hardware_target()) {
+            (Self::Software, _) => String::from("software (forced)"),
+            (_, Some(target)) => format!("hardware ({target})"),
+            (_, None) => String::from("software (no CPU support)"),
+        }
+    }
+}
+
+impl Display for ChecksumImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Auto => "auto",
+            Self::Hardware => "hardware",
+            Self::Software => "software",
+        };
+        f.pad(name)
+    }
+}
+
+/// Returns the CRC-32 (ISO-HDLC) of a block, computed by the global [ChecksumImpl].
+pub fn block_checksum(data: &[u8]) -> u32 {
+    ChecksumImpl::global().checksum(data)
+}
+
+/// Returns the checksum of a block with the `algorithm` negotiated with the peer, computed by the
+/// global [ChecksumImpl].
+pub fn block_checksum_with(algorithm: ChecksumAlgorithm, data: &[u8]) -> u32 {
+    match algorithm {
+        ChecksumAlgorithm::Crc32 => block_checksum(data),
+        ChecksumAlgorithm::Crc32c => ChecksumImpl::global().crc32c(data),
+    }
+}
+
+/// Returns the accelerated code path `crc-fast` dispatches to on this CPU, `None` if it falls
+/// back to table lookups.
+pub fn hardware_target() -> Option<String> {
+    let target = get_calculator_target(CrcAlgorithm::Crc32IsoHdlc);
+    (target != SOFTWARE_TARGET).then_some(target)
+}
+
+fn software_crc32(table: &[u32; 256], data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+/// Builds the lookup table of the reflected CRC-32 with the reversed polynomial `poly`.
+const fn crc32_table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_implementations_agree() {
+        assert_eq!(ChecksumImpl::Software.checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(ChecksumImpl::Auto.checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(ChecksumImpl::Software.checksum(b""), 0);
+
+        let block: Vec<u8> = (0..100_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        assert_eq!(
+            ChecksumImpl::Software.checksum(&block),
+            ChecksumImpl::Auto.checksum(&block)
+        );
+    }
+
+    #[test]
+    fn test_crc32c_implementations_agree() {
+        assert_eq!(ChecksumImpl::Software.crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(ChecksumImpl::Auto.crc32c(b"123456789"), 0xE306_9283);
+
+        let block: Vec<u8> = (0..100_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        assert_eq!(
+            ChecksumImpl::Software.crc32c(&block),
+            ChecksumImpl::Auto.crc32c(&block)
+        );
+        assert_eq!(
+            block_checksum_with(ChecksumAlgorithm::Crc32, &block),
+            block_checksum(&block)
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Clock skew between the sender and the receiver.
+//!
+//! Preserved modification times, the schedules of [crate::stream::schedule] and the timestamps
+//! of signed receipts all assume the peers' clocks agree. Senders advertising
+//! [Capabilities::CLOCK] follow their handshake with the time on their clock (`Clock`), which
+//! the receiver compares with its own as soon as it reads it. A skew beyond
+//! [ReceiveOptions::max_clock_skew] is logged as a warning, and the skew is reported with the
+//! summary of the transfer. The transfer itself goes ahead either way.
+//!
+//! The time the handshake spent on the wire counts as skew, which is negligible next to the
+//! threshold on any link a transfer completes on.
+//!
+//! [Capabilities::CLOCK]: crate::capabilities::Capabilities::CLOCK
+//! [ReceiveOptions::max_clock_skew]: crate::stream::options::ReceiveOptions::max_clock_skew
+
+use std::{
+    fmt::{self, Display},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::units::Elapsed;
+
+/// Difference between the sender's clock and the receiver's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// Milliseconds the sender's clock is ahead of the receiver's, negative when it is behind.
+    millis: i64,
+}
+
+impl ClockSkew {
+    /// Returns the skew of a sender whose clock read `sender_millis` when the receiver's read
+    /// `receiver_millis`, both in milliseconds since the Unix epoch.
+    pub fn between(sender_millis: u64, receiver_millis: u64) -> Self {
+        Self {
+            millis: (sender_millis as i128 - receiver_millis as i128)
+                .clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+        }
+    }
+
+    /// Returns the skew of a sender whose clock reads `sender_millis` now.
+    pub fn measure(sender_millis: u64) -> Self {
+        Self::between(sender_millis, unix_millis())
+    }
+
+    /// Returns the milliseconds the sender's clock is ahead, negative when it is behind.
+    pub fn millis(&self) -> i64 {
+        self.millis
+    }
+
+    /// Returns how far apart the clocks are, ahead or behind.
+    pub fn magnitude(&self) -> Duration {
+        Duration::from_millis(self.millis.unsigned_abs())
+    }
+
+    /// Returns whether the clocks are further apart than `threshold`.
+    pub fn exceeds(&self, threshold: Duration) -> bool {
+        self.magnitude() > threshold
+    }
+}
+
+impl Display for ClockSkew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = if self.millis < 0 { "behind" } else { "ahead" };
+        write!(
+            f,
+            "sender's clock {} {}",
+            Elapsed(self.magnitude()),
+            direction
+        )
+    }
+}
+
+/// Returns the time on this side's clock, in milliseconds since the Unix epoch. A clock set
+/// before the epoch reads 0.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skew_is_signed() {
+        let ahead = ClockSkew::between(1_700_000_090_500, 1_700_000_000_000);
+        assert_eq!(ahead.millis(), 90_500);
+        assert!(ahead.exceeds(Duration::from_secs(90)));
+        assert!(!ahead.exceeds(Duration::from_secs(91)));
+        assert_eq!(ahead.to_string(), "sender's clock 1m 30s ahead");
+
+        let behind = ClockSkew::between(1_700_000_000_000, 1_700_000_002_400);
+        assert_eq!(behind.millis(), -2_400);
+        assert_eq!(behind.magnitude(), Duration::from_millis(2_400));
+        assert_eq!(behind.to_string(), "sender's clock 2.4s behind");
+
+        assert!(ClockSkew::measure(unix_millis()).magnitude() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,101 @@
+//! Compression of data blocks with the codec negotiated with the receiver.
+//!
+//! Blocks are compressed with gzip unless both peers support zstd, which compresses about as
+//! well several times faster, see `cargo bench --bench compression`. Receivers decode blocks
+//! with a [BlockInflater](crate::stream::inflate::BlockInflater) of the same codec.
+
+use std::io::{self, Write};
+
+use flate2::{write::GzEncoder, Compression};
+
+use crate::capabilities::CompressionCodec;
+
+/// zstd level blocks are compressed with, about as fast as the default gzip level.
+pub const ZSTD_LEVEL: i32 = 1;
+
+/// Compresses blocks with one codec, reusing its compression state from one block to the next.
+pub struct BlockCompressor {
+    codec: CompressionCodec,
+    /// zstd context, created with the first block compressed with zstd.
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl BlockCompressor {
+    /// Creates a compressor of blocks with `codec`.
+    pub fn new(codec: CompressionCodec) -> Self {
+        Self { codec, zstd: None }
+    }
+
+    /// Returns the codec blocks are compressed with.
+    pub fn codec(&self) -> CompressionCodec {
+        self.codec
+    }
+
+    /// Compresses `data` into `output`, replacing what it held.
+    ///
+    /// # Returns
+    ///
+    /// An error if the codec fails, or if it is [CompressionCodec::None].
+    pub fn compress(&mut self, data: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        output.clear();
+        match self.codec {
+            CompressionCodec::None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Compression was not negotiated",
+            )),
+            CompressionCodec::Gzip => {
+                let mut encoder = GzEncoder::new(output, Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish().map(|_| ())
+            }
+            CompressionCodec::Zstd => {
+                let compressor = match &mut self.zstd {
+                    Some(compressor) => compressor,
+                    None => self.zstd.insert(zstd::bulk::Compressor::new(ZSTD_LEVEL)?),
+                };
+                output.reserve(zstd::zstd_safe::compress_bound(data.len()));
+                compressor.compress_to_buffer(data, output).map(|_| ())
+            }
+        }
+    }
+}
+
+impl Default for BlockCompressor {
+    /// Compresses with gzip, which every peer supports.
+    fn default() -> Self {
+        Self::new(CompressionCodec::Gzip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::inflate::BlockInflater;
+
+    #[test]
+    fn test_compressed_blocks_inflate_with_same_codec() {
+        let block: Vec<u8> = b"a block of text, ".repeat(500);
+        let mut compressed = Vec::new();
+        let mut output = vec![0u8; block.len()];
+
+        for codec in [CompressionCodec::Gzip, CompressionCodec::Zstd] {
+            let mut compressor = BlockCompressor::new(codec);
+            let mut inflater = BlockInflater::with_codec(codec);
+            for _ in 0..2 {
+                compressor.compress(&block, &mut compressed).unwrap();
+                assert!(compressed.len() < block.len());
+                let len = inflater.inflate(&compressed, &mut output).unwrap();
+                assert_eq!(&output[..len], &block[..]);
+            }
+        }
+
+        let mut zstd = BlockCompressor::new(CompressionCodec::Zstd);
+        zstd.compress(&block, &mut compressed).unwrap();
+        assert!(BlockInflater::new()
+            .inflate(&compressed, &mut output)
+            .is_err());
+        assert!(BlockCompressor::new(CompressionCodec::None)
+            .compress(&block, &mut compressed)
+            .is_err());
+    }
+}
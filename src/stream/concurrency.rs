@@ -0,0 +1,86 @@
+//! Selection of the number of concurrent data connections for a transfer.
+
+use log::info;
+
+/// Default upper bound on the number of concurrent connections.
+pub const DEFAULT_MAX_CONCURRENCY: u16 = 16;
+
+/// Minimum number of blocks each connection should be responsible for. Opening more connections
+/// than this allows only adds setup overhead for small files.
+pub const MIN_BLOCKS_PER_CONNECTION: u64 = 4;
+
+/// Returns the number of concurrent connections to use for a transfer.
+///
+/// - `requested`: Concurrency explicitly asked for by the user, if any. Defaults to the available
+///   parallelism of the machine.
+/// - `ceiling`: Upper bound applied to both the default and an explicit request.
+/// - `total_blocks`: Number of blocks in the file, if known. Concurrency is reduced so each
+///   connection handles at least [MIN_BLOCKS_PER_CONNECTION] blocks.
+///
+/// The result is always at least 1.
+pub fn effective_concurrency(
+    requested: Option<u16>,
+    ceiling: u16,
+    total_blocks: Option<u64>,
+) -> u16 {
+    let ceiling = ceiling.max(1);
+    let available = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(u16::MAX as usize) as u16;
+
+    let concurrency = requested.unwrap_or(available.min(ceiling));
+
+    // Cap concurrency if user provides a higher value
+    let mut effective = concurrency.min(ceiling);
+    if effective < concurrency {
+        info!("Capped concurrency from {} to {}", concurrency, effective);
+    }
+
+    if let Some(total_blocks) = total_blocks {
+        effective = cap_to_blocks(effective, total_blocks);
+    }
+
+    effective.max(1)
+}
+
+/// Reduces `concurrency` so that every connection is assigned at least
+/// [MIN_BLOCKS_PER_CONNECTION] blocks, keeping at least one connection.
+pub fn cap_to_blocks(concurrency: u16, total_blocks: u64) -> u16 {
+    let max_for_blocks =
+        (total_blocks / MIN_BLOCKS_PER_CONNECTION).clamp(1, u16::MAX as u64) as u16;
+    let capped = concurrency.min(max_for_blocks).max(1);
+    if capped < concurrency {
+        info!(
+            "Reduced concurrency from {} to {} for {} blocks",
+            concurrency, capped, total_blocks
+        );
+    }
+    capped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explicit_request_is_capped_by_ceiling() {
+        assert_eq!(effective_concurrency(Some(64), 8, None), 8);
+        assert_eq!(effective_concurrency(Some(4), 8, None), 4);
+    }
+
+    #[test]
+    fn test_never_below_one() {
+        assert_eq!(effective_concurrency(Some(0), 8, None), 1);
+        assert_eq!(effective_concurrency(Some(4), 0, None), 1);
+        assert_eq!(effective_concurrency(Some(4), 8, Some(0)), 1);
+    }
+
+    #[test]
+    fn test_small_files_use_fewer_connections() {
+        assert_eq!(cap_to_blocks(16, 1), 1);
+        assert_eq!(cap_to_blocks(16, 10), 2);
+        assert_eq!(cap_to_blocks(16, 1000), 16);
+        assert_eq!(effective_concurrency(Some(8), 16, Some(12)), 3);
+    }
+}
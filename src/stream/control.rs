@@ -0,0 +1,386 @@
+//! Control channel on the handshake connection.
+//!
+//! Data connections come and go: they are lost and retried, ranges end at different times, and
+//! the connection that happens to report the transfer complete can drop before it does. When
+//! both peers advertise [Capabilities::CONTROL_CHANNEL](crate::capabilities::Capabilities::CONTROL_CHANNEL),
+//! the handshake connection stays the one stable link between them for the whole transfer:
+//!
+//! - While blocks are downloaded, the receiver reports the bytes received so far on it
+//!   (`Progress`) every [PROGRESS_INTERVAL], which also keeps it alive, and reports the transfer
+//!   complete there once every block is received (`TransferComplete`).
+//! - The sender counts the reported bytes as its progress, keeps waiting for data connections
+//!   while the receiver reports between two rounds of them, and stops waiting once told the
+//!   transfer is complete, whatever became of the data connections.
+//! - The sender pushes its directives on it: `Abort` when it is cancelled, and `Throttle` when
+//!   its rate limit changes, see [TransferControl::limit_rate].
+//!
+//! The sender learns that the receiver uses the channel from the capabilities of its
+//! `HandshakeAck`. Transfers whose handshake connection also carries blocks (`--single-port`,
+//! [Preconnected](crate::stream::preconnected::Preconnected)) have no control channel.
+
+use std::{
+    io::{self, Write},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    connection::read_next_payload,
+    noise::NoiseStream,
+    stream::{error::SendFileError, handle::TransferControl, preconnected::Connection},
+    tls::MaybeTlsStream,
+    transport::{
+        attach_headers_for, AbortV1, ProgressV1, ReceiverMessageV1, SenderMessageV1, ThrottleV1,
+        TransferCompleteV1, SESSION_ID_SIZE,
+    },
+};
+
+/// Time between two progress reports of the receiver.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time allowed to the rest of a directive once its first bytes arrived.
+const DIRECTIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between two reads of the directives while lingering, see [ProgressReporter::linger].
+const LINGER_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Size of the buffer directives are read into, far more than they take.
+const DIRECTIVE_BUFFER_SIZE: usize = 1024;
+
+/// Receiver end of the control channel: reports progress, and applies the sender's directives.
+pub(crate) struct ProgressReporter {
+    file_hash: [u8; 32],
+    protocol_version: u8,
+    session_id: Option<[u8; SESSION_ID_SIZE]>,
+    last_report: Option<Instant>,
+    buffer: Vec<u8>,
+    /// Bytes read from the channel past the last directive.
+    pending: Vec<u8>,
+    /// Reason the sender gave for aborting the transfer, once it did.
+    abort: Option<String>,
+}
+
+impl ProgressReporter {
+    /// Creates the reporter of the transfer of the file `file_hash`, framing its messages with
+    /// `protocol_version` and tagging them with `session_id`.
+    pub(crate) fn new(
+        file_hash: [u8; 32],
+        protocol_version: u8,
+        session_id: Option<[u8; SESSION_ID_SIZE]>,
+    ) -> Self {
+        Self {
+            file_hash,
+            protocol_version,
+            session_id,
+            last_report: None,
+            buffer: vec![0u8; DIRECTIVE_BUFFER_SIZE],
+            pending: Vec::new(),
+            abort: None,
+        }
+    }
+
+    /// Runs `work` on another thread, reporting the progress of `control` on `stream` every
+    /// [PROGRESS_INTERVAL] until it returns, see [Self::report_due].
+    ///
+    /// # Returns
+    ///
+    /// What `work` returned. Reporting stops at the first failed report, the connection's next
+    /// use reports the error, or once the sender aborted the transfer.
+    pub(crate) fn report_while<T: Send>(
+        &mut self,
+        stream: &mut NoiseStream<MaybeTlsStream>,
+        control: &TransferControl,
+        work: impl FnOnce() -> T + Send,
+    ) -> T {
+        thread::scope(|scope| {
+            let (done, finished) = mpsc::channel();
+            let worker = scope.spawn(move || {
+                let result = work();
+                let _ = done.send(());
+                result
+            });
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                finished.recv_timeout(PROGRESS_INTERVAL)
+            {
+                if let Err(e) = self.report_due(stream, control) {
+                    debug!("Stopped reporting progress: {}", e);
+                    break;
+                }
+            }
+            worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    }
+
+    /// Reports the progress of `control` on `stream` unless it was reported less than
+    /// [PROGRESS_INTERVAL] ago, then applies the directives the sender pushed meanwhile to
+    /// `control`.
+    ///
+    /// # Returns
+    ///
+    /// [SendFileError::AbortedBySender] once the sender aborted the transfer, which also
+    /// cancels `control`, or an error if the channel failed.
+    pub(crate) fn report_due(
+        &mut self,
+        stream: &mut NoiseStream<MaybeTlsStream>,
+        control: &TransferControl,
+    ) -> Result<(), SendFileError> {
+        self.check_aborted()?;
+        if self
+            .last_report
+            .is_none_or(|last| last.elapsed() >= PROGRESS_INTERVAL)
+        {
+            let progress = ReceiverMessageV1::Progress(ProgressV1 {
+                file_hash: self.file_hash,
+                bytes_received: control.progress().bytes_transferred,
+            });
+            self.send(stream, &progress)?;
+            self.last_report = Some(Instant::now());
+        }
+        self.apply_directives(stream, control)?;
+        self.check_aborted()
+    }
+
+    /// Waits up to [PROGRESS_INTERVAL] for the directives the sender pushes on `stream` once its
+    /// data connections are gone, and applies them to `control`.
+    ///
+    /// # Returns
+    ///
+    /// [SendFileError::AbortedBySender] if the sender aborted the transfer meanwhile. A failed
+    /// channel is left for the connection's next use to report.
+    pub(crate) fn linger(
+        &mut self,
+        stream: &mut NoiseStream<MaybeTlsStream>,
+        control: &TransferControl,
+    ) -> Result<(), SendFileError> {
+        let until = Instant::now() + PROGRESS_INTERVAL;
+        while self.abort.is_none() && Instant::now() < until {
+            if let Err(e) = self.apply_directives(stream, control) {
+                debug!("Stopped reading directives: {}", e);
+                break;
+            }
+            thread::sleep(LINGER_POLL_INTERVAL);
+        }
+        self.check_aborted()
+    }
+
+    /// Reports on `stream` that every block was received.
+    pub(crate) fn complete(
+        &mut self,
+        stream: &mut NoiseStream<MaybeTlsStream>,
+    ) -> Result<(), SendFileError> {
+        let complete = ReceiverMessageV1::TransferComplete(TransferCompleteV1 {
+            file_hash: self.file_hash,
+        });
+        self.send(stream, &complete)?;
+        debug!("Reported the transfer complete on the control channel");
+        Ok(())
+    }
+
+    /// Fails with [SendFileError::AbortedBySender] if the sender aborted the transfer.
+    pub(crate) fn check_aborted(&self) -> Result<(), SendFileError> {
+        match &self.abort {
+            Some(reason) => Err(SendFileError::AbortedBySender(reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn send(
+        &self,
+        stream: &mut NoiseStream<MaybeTlsStream>,
+        message: &ReceiverMessageV1,
+    ) -> Result<(), SendFileError> {
+        let mut buffer = [0u8; 64];
+        let payload = message.to_bytes(&mut buffer)?;
+        stream.write_all(&attach_headers_for(
+            self.protocol_version,
+            self.session_id.as_ref(),
+            payload,
+        ))?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Reads the directives that arrived on `stream`, without waiting for more, and applies
+    /// them to `control`.
+    fn apply_directives(
+        &mut self,
+        stream: &mut NoiseStream<MaybeTlsStream>,
+        control: &TransferControl,
+    ) -> Result<(), SendFileError> {
+        while !self.pending.is_empty() || stream.has_pending_data()? {
+            stream.set_read_timeout(Some(DIRECTIVE_TIMEOUT))?;
+            let filled_len = self.pending.len();
+            self.buffer[..filled_len].copy_from_slice(&self.pending);
+            let result =
+                read_next_payload::<SenderMessageV1, _>(stream, &mut self.buffer, filled_len)?;
+            result.check_session(self.session_id.as_ref(), self.protocol_version)?;
+            let unread = result
+                .next_payload_index
+                .map(|next_idx| next_idx..result.total_bytes_read);
+            match result.message {
+                SenderMessageV1::Abort(AbortV1 { file_hash, reason })
+                    if file_hash == self.file_hash =>
+                {
+                    warn!("Sender aborted the transfer: {}", reason);
+                    self.abort = Some(reason);
+                    control.cancel();
+                    return Ok(());
+                }
+                SenderMessageV1::Throttle(ThrottleV1 {
+                    file_hash,
+                    bytes_per_second,
+                }) if file_hash == self.file_hash => {
+                    info!("Sender asked to throttle the transfer");
+                    control.limit_rate(bytes_per_second);
+                }
+                message => debug!("Ignoring {:?} on the control channel", message),
+            }
+            self.pending.clear();
+            if let Some(unread) = unread {
+                self.pending.extend_from_slice(&self.buffer[unread]);
+            }
+        }
+        stream.set_read_timeout(None)?;
+        Ok(())
+    }
+}
+
+/// Sender end of the control channel: pushes directives to a receiver reporting on it.
+pub(crate) struct ControlChannel {
+    /// BLAKE3 hash of the file being transferred.
+    pub(crate) file_hash: [u8; 32],
+    session_id: Option<[u8; SESSION_ID_SIZE]>,
+    /// Protocol version of the receiver's reports, which directives are framed with. `None`
+    /// until it reported, directives wait for it.
+    protocol_version: Option<u8>,
+    /// Rate limit the receiver was last told about.
+    pushed_rate: Option<u64>,
+}
+
+impl ControlChannel {
+    /// Creates the channel of the transfer of the file `file_hash`, whose directives are tagged
+    /// with `session_id`.
+    pub(crate) fn new(file_hash: [u8; 32], session_id: Option<[u8; SESSION_ID_SIZE]>) -> Self {
+        Self {
+            file_hash,
+            session_id,
+            protocol_version: None,
+            pushed_rate: None,
+        }
+    }
+
+    /// Notes that the receiver reported on the channel in frames of `protocol_version`.
+    pub(crate) fn heard(&mut self, protocol_version: u8) {
+        self.protocol_version = Some(protocol_version);
+    }
+
+    /// Tells the receiver on `stream` about the rate limit of `control` if it changed since it
+    /// was last told.
+    pub(crate) fn push_rate_limit<W: Write>(
+        &mut self,
+        stream: &mut W,
+        control: &TransferControl,
+    ) -> io::Result<()> {
+        let rate = control.rate_limit();
+        if rate == self.pushed_rate || self.protocol_version.is_none() {
+            return Ok(());
+        }
+        self.push(
+            stream,
+            &SenderMessageV1::Throttle(ThrottleV1 {
+                file_hash: self.file_hash,
+                bytes_per_second: rate,
+            }),
+        )?;
+        self.pushed_rate = rate;
+        Ok(())
+    }
+
+    /// Tells the receiver on `stream` that the transfer is aborted for `reason`, if it reported
+    /// on the channel.
+    pub(crate) fn push_abort<W: Write>(&self, stream: &mut W, reason: &str) -> io::Result<()> {
+        self.push(
+            stream,
+            &SenderMessageV1::Abort(AbortV1 {
+                file_hash: self.file_hash,
+                reason: reason.to_string(),
+            }),
+        )
+    }
+
+    fn push<W: Write>(&self, stream: &mut W, directive: &SenderMessageV1) -> io::Result<()> {
+        let Some(protocol_version) = self.protocol_version else {
+            return Ok(());
+        };
+        let mut buffer = [0u8; DIRECTIVE_BUFFER_SIZE];
+        let payload = directive.to_bytes(&mut buffer).map_err(io::Error::other)?;
+        stream.write_all(&attach_headers_for(
+            protocol_version,
+            self.session_id.as_ref(),
+            payload,
+        ))?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{CURRENT_PROTOCOL_VERSION, MAX_MESSAGE_SIZE};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_reports_progress_and_applies_directives() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut stream = NoiseStream::new(MaybeTlsStream::Plain(client));
+        let session_id = Some([7; SESSION_ID_SIZE]);
+        let control = TransferControl::new();
+        control.add_bytes(4096);
+
+        let mut reporter = ProgressReporter::new([1; 32], CURRENT_PROTOCOL_VERSION, session_id);
+        reporter.report_due(&mut stream, &control).unwrap();
+        // Not due again yet
+        reporter.report_due(&mut stream, &control).unwrap();
+
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+        let report =
+            read_next_payload::<ReceiverMessageV1, _>(&mut server, &mut buffer, 0).unwrap();
+        assert_eq!(
+            report.message,
+            ReceiverMessageV1::Progress(ProgressV1 {
+                file_hash: [1; 32],
+                bytes_received: 4096,
+            })
+        );
+        assert!(report.next_payload_index.is_none());
+
+        let mut channel = ControlChannel::new([1; 32], session_id);
+        let sender = TransferControl::new();
+        // Directives wait for the receiver to report on the channel
+        let mut early = Vec::new();
+        channel.push_abort(&mut early, "too early").unwrap();
+        assert!(early.is_empty());
+        channel.heard(report.protocol_version);
+        sender.limit_rate(Some(1 << 20));
+        channel.push_rate_limit(&mut server, &sender).unwrap();
+        // Only pushed again once it changes
+        channel.push_rate_limit(&mut server, &sender).unwrap();
+        channel.push_abort(&mut server, "cancelled").unwrap();
+
+        reporter.last_report = None;
+        thread::sleep(Duration::from_millis(50));
+        let result = reporter.report_due(&mut stream, &control);
+        assert!(
+            matches!(result, Err(SendFileError::AbortedBySender(reason)) if reason == "cancelled")
+        );
+        assert_eq!(control.rate_limit(), Some(1 << 20));
+        assert!(control.is_cancelled());
+    }
+}
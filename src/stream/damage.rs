@@ -0,0 +1,184 @@
+//! Damage reports of files received with holes.
+//!
+//! In best-effort mode, blocks the sender could not read (e.g. bad sectors on a failing disk)
+//! are zero-filled by the receiver instead of aborting the transfer. The byte ranges affected
+//! are written to a [DamageReport] next to the received file, so the damaged regions can be
+//! found and possibly recovered later.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::to_hex;
+
+/// Suffix appended to the received file's name to name its damage report.
+pub const DAMAGE_REPORT_SUFFIX: &str = ".damage.json";
+
+/// Range of a file that could not be read by the sender.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hole {
+    /// Offset of the first missing byte.
+    pub start: u64,
+    /// Offset past the last missing byte.
+    pub end: u64,
+    /// Read error reported by the sender.
+    pub reason: String,
+}
+
+/// Zero-filled ranges of a received file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DamageReport {
+    /// Name of the file on the sender side.
+    pub file_name: String,
+    /// Hex encoded BLAKE3 hash of the intact file, which the received file does not match.
+    pub file_hash: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Missing ranges, in file order.
+    pub holes: Vec<Hole>,
+}
+
+impl DamageReport {
+    /// Builds the report of a file of `size` bytes in which `blocks` of `block_size` bytes
+    /// could not be read. Adjacent blocks that failed with the same error are merged.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_name` - Name of the file on the sender side.
+    /// * `file_hash` - BLAKE3 hash of the intact file.
+    /// * `size` - Size of the file in bytes.
+    /// * `block_size` - Size of a block in bytes.
+    /// * `blocks` - Unreadable blocks with their read error, in increasing order.
+    pub fn new(
+        file_name: String,
+        file_hash: &[u8; 32],
+        size: u64,
+        block_size: u32,
+        blocks: &[(u32, String)],
+    ) -> Self {
+        let mut holes: Vec<Hole> = Vec::new();
+        for (seq, reason) in blocks {
+            let range = block_range(*seq, block_size, size);
+            match holes.last_mut() {
+                Some(last) if last.end == range.start && last.reason == *reason => {
+                    last.end = range.end;
+                }
+                _ => holes.push(Hole {
+                    start: range.start,
+                    end: range.end,
+                    reason: reason.clone(),
+                }),
+            }
+        }
+
+        Self {
+            file_name,
+            file_hash: to_hex(file_hash),
+            size,
+            holes,
+        }
+    }
+
+    /// Returns the missing byte ranges.
+    pub fn ranges(&self) -> Vec<Range<u64>> {
+        merge_ranges(self.holes.iter().map(|hole| hole.start..hole.end))
+    }
+
+    /// Returns the number of missing bytes.
+    pub fn missing_bytes(&self) -> u64 {
+        self.holes.iter().map(|hole| hole.end - hole.start).sum()
+    }
+
+    /// Writes the report as JSON to `path`.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut json = serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+        fs::write(path, json)
+    }
+}
+
+/// Returns where the damage report of the file at `path` is written.
+pub fn damage_report_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(DAMAGE_REPORT_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Returns the byte ranges covered by `blocks` of `block_size` bytes in a file of `size` bytes,
+/// merging adjacent blocks.
+pub fn block_ranges(
+    blocks: impl IntoIterator<Item = u32>,
+    block_size: u32,
+    size: u64,
+) -> Vec<Range<u64>> {
+    merge_ranges(
+        blocks
+            .into_iter()
+            .map(|seq| block_range(seq, block_size, size)),
+    )
+}
+
+/// Formats byte ranges for display, e.g. `4096..8192, 20480..20500`.
+pub fn format_ranges(ranges: &[Range<u64>]) -> String {
+    let mut formatted = String::new();
+    for (index, range) in ranges.iter().enumerate() {
+        if index > 0 {
+            formatted.push_str(", ");
+        }
+        let _ = write!(formatted, "{}..{}", range.start, range.end);
+    }
+    formatted
+}
+
+fn block_range(seq: u32, block_size: u32, size: u64) -> Range<u64> {
+    let start = (seq as u64 * block_size as u64).min(size);
+    start..(start + block_size as u64).min(size)
+}
+
+fn merge_ranges(ranges: impl Iterator<Item = Range<u64>>) -> Vec<Range<u64>> {
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_damage_report_merges_holes() {
+        let eio = String::from("Input/output error");
+        let blocks = [
+            (1, eio.clone()),
+            (2, eio.clone()),
+            (3, String::from("Bad sector")),
+            (9, eio),
+        ];
+        let report = DamageReport::new(String::from("disk.img"), &[0; 32], 9500, 1000, &blocks);
+
+        assert_eq!(report.holes.len(), 3);
+        assert_eq!((report.holes[0].start, report.holes[0].end), (1000, 3000));
+        assert_eq!((report.holes[1].start, report.holes[1].end), (3000, 4000));
+        // The last block is short
+        assert_eq!((report.holes[2].start, report.holes[2].end), (9000, 9500));
+
+        assert_eq!(report.ranges(), vec![1000..4000, 9000..9500]);
+        assert_eq!(report.missing_bytes(), 3500);
+        assert_eq!(block_ranges([1, 2, 3, 9], 1000, 9500), report.ranges());
+        assert_eq!(format_ranges(&report.ranges()), "1000..4000, 9000..9500");
+        assert_eq!(
+            damage_report_path(Path::new("/tmp/disk.img")),
+            PathBuf::from("/tmp/disk.img.damage.json")
+        );
+    }
+}
@@ -1,6 +1,32 @@
+//! Errors of transfers, see [SendFileError].
+//!
+//! Errors of operations on a block, a connection or a file carry an [ErrorContext] naming the
+//! operation and, when known, the peer, the file and the block, attached with
+//! [ErrorContextExt::context] where they are returned. The context is shown after the message
+//! (`IO error: No space left on device (while writing block 12 of "backup.tar", peer
+//! 10.0.0.2:7878)`), and each error has a stable [code](SendFileError::code) so scripts don't
+//! parse messages: `--error-format json` prints failures as an [ErrorReport].
+
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
 use thiserror::Error;
 
-use crate::{connection::StreamReadError, transport::TransportError};
+use crate::{
+    connection::StreamReadError,
+    file::{content_type::TYPE_REJECTION_PREFIX, metadata::MetadataError},
+    noise::NoiseError,
+    pairing::PairingError,
+    stream::bundle::BundleError,
+    tls::TlsError,
+    transport::{
+        relay::RelayError, ws::WsError, HandshakeRejectV1, RejectReasonV1, TransportError,
+    },
+};
 
 /// Errors that can occur during file transfer (sending or receiving).
 #[derive(Error, Debug)]
@@ -56,4 +82,407 @@ pub enum SendFileError {
         expected: [u8; 32],
         received: [u8; 32],
     },
+
+    /// The peer does not support a feature required for this transfer.
+    #[error("Peer is sendfile {peer_version}, which doesn't support {capability}")]
+    MissingCapability {
+        peer_version: String,
+        capability: String,
+    },
+
+    /// The peers share no protocol version, listed are the versions the peer supports.
+    #[error("No protocol version in common with the peer, which supports {0:?}")]
+    NoCommonProtocolVersion(Vec<u8>),
+
+    /// TLS credentials could not be loaded, or a connection could not be secured.
+    #[error("{0}")]
+    Tls(#[from] TlsError),
+
+    /// The Noise channel could not be set up, or its peer is not who it claims to be.
+    #[error("{0}")]
+    Noise(#[from] NoiseError),
+
+    /// Pairing with the peer's one-time code failed.
+    #[error("{0}")]
+    Pairing(#[from] PairingError),
+
+    /// Metadata attached to the file is invalid or too large.
+    #[error("{0}")]
+    Metadata(#[from] MetadataError),
+
+    /// The WebSocket of `--transport ws` could not be opened.
+    #[error("{0}")]
+    WebSocket(#[from] WsError),
+
+    /// The connection through the relay of `--relay` could not be set up.
+    #[error("{0}")]
+    Relay(#[from] RelayError),
+
+    /// A directory could not be sent or received as one transfer, see [crate::stream::bundle].
+    #[error("{0}")]
+    Bundle(#[from] BundleError),
+
+    /// The output path is a block device, which is only written over when explicitly allowed.
+    #[error("{0:?} is a block device, pass --yes-i-mean-a-device to write over it")]
+    DeviceNotConfirmed(std::path::PathBuf),
+
+    /// The output block device is smaller than the file.
+    #[error("Block device {path:?} holds {size} bytes, the file needs {needed}")]
+    DeviceTooSmall {
+        path: std::path::PathBuf,
+        size: u64,
+        needed: u64,
+    },
+
+    /// Strict mode requirements could not be met, the transfer was refused.
+    #[error("Strict mode refused the transfer: {0}")]
+    StrictModeViolation(String),
+
+    /// Another process holds a conflicting advisory lock on the file.
+    #[error("File {0:?} is locked by another process (use --no-lock to skip locking)")]
+    FileLocked(std::path::PathBuf),
+
+    /// The sender's authentication is invalid.
+    #[error("Sender authentication failed: {0}")]
+    AuthenticationFailed(String),
+
+    /// The receiver rejected the offered file.
+    #[error("File offer rejected: {0}")]
+    OfferRejected(String),
+
+    /// The receiver refuses files of this type, see
+    /// [TypePolicy](crate::file::content_type::TypePolicy).
+    #[error("Receiver refuses this type of file: {0}")]
+    TypeRejected(String),
+
+    /// The received file failed the receiver's [scan](crate::stream::scan) and was quarantined.
+    #[error("File failed the receiver's scan: {0}")]
+    ScanFailed(String),
+
+    /// The receiver verified the file but answered that it signs no receipt for it.
+    #[error("Receiver sent no receipt: {0}")]
+    NoReceipt(String),
+
+    /// The sender could not read a block of the file from its disk.
+    #[error("Sender could not read block {seq}: {reason}")]
+    BlockUnreadable { seq: u32, reason: String },
+
+    /// The sender could not read some byte ranges of the file, so the receiver either aborted or
+    /// zero-filled them in best-effort mode.
+    #[error(
+        "File is incomplete, the sender could not read bytes {}",
+        crate::stream::damage::format_ranges(ranges)
+    )]
+    IncompleteFile { ranges: Vec<std::ops::Range<u64>> },
+
+    /// The progress recorded by an interrupted transfer doesn't fit the offered file.
+    #[error("Cannot resume: {0}")]
+    ResumeFailed(String),
+
+    /// The transfer was cancelled through its [TransferHandle](crate::stream::handle::TransferHandle).
+    #[error("Transfer was cancelled")]
+    Cancelled,
+
+    /// The time limit of this side or of the peer ran out, see [crate::stream::time_limit].
+    /// Blocks received so far are kept for a later transfer to resume from.
+    #[error("Transfer aborted: {0}")]
+    TimeLimitExceeded(String),
+
+    /// The sender aborted the transfer on the control channel, see [crate::stream::control].
+    /// Blocks received so far are kept for a later transfer to resume from.
+    #[error("Sender aborted the transfer: {0}")]
+    AbortedBySender(String),
+
+    /// The peer cancelled the transfer and said so before closing its connections, see
+    /// [crate::stream::cancel]. Blocks received so far are kept for a later transfer to resume
+    /// from.
+    #[error("Peer cancelled the transfer: {0}")]
+    CancelledByPeer(String),
+
+    /// The sender can't serve a request now and asked to be retried after `retry_after`, see
+    /// [crate::stream::retry_after].
+    #[error(
+        "Sender asked to retry in {}: {message}",
+        crate::units::Elapsed(*retry_after)
+    )]
+    SenderUnavailable {
+        message: String,
+        retry_after: std::time::Duration,
+    },
+
+    /// A thread of the transfer panicked, see [crate::stream::panic].
+    #[error("Thread {worker} panicked: {message}")]
+    WorkerPanicked { worker: String, message: String },
+
+    /// An error of the operation described by `context`.
+    #[error("{source} ({context})")]
+    Context {
+        context: ErrorContext,
+        source: Box<SendFileError>,
+    },
+}
+
+/// Where an error happened: the operation that failed and, when known, the peer, the file and
+/// the block it failed on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorContext {
+    /// What was being done, e.g. "writing" or "connecting".
+    pub operation: &'static str,
+    /// Address of the peer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// File being sent or received.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Sequence number of the block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u32>,
+}
+
+impl ErrorContext {
+    /// Returns the context of `operation`, on no particular peer, file or block.
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            operation,
+            peer: None,
+            file: None,
+            seq: None,
+        }
+    }
+
+    /// Sets the address of the peer.
+    pub fn with_peer(mut self, peer: impl Display) -> Self {
+        self.peer = Some(peer.to_string());
+        self
+    }
+
+    /// Sets the file being sent or received.
+    pub fn with_file(mut self, file: impl AsRef<Path>) -> Self {
+        self.file = Some(file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the sequence number of the block.
+    pub fn with_seq(mut self, seq: u32) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// Returns this context, completed with what `outer` knows and it doesn't. The operation
+    /// stays this one's, the closest to the failure.
+    fn within(self, outer: ErrorContext) -> Self {
+        Self {
+            operation: self.operation,
+            peer: self.peer.or(outer.peer),
+            file: self.file.or(outer.file),
+            seq: self.seq.or(outer.seq),
+        }
+    }
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "while {}", self.operation)?;
+        if let Some(seq) = self.seq {
+            write!(f, " block {}", seq)?;
+        }
+        if let Some(file) = &self.file {
+            let of = if self.seq.is_some() { " of" } else { "" };
+            write!(f, "{} {:?}", of, file)?;
+        }
+        if let Some(peer) = &self.peer {
+            write!(f, ", peer {}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Attaches an [ErrorContext] to the error of a `Result`.
+pub trait ErrorContextExt<T> {
+    /// Returns the result, its error completed with `context`, see [SendFileError::with_context].
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T, SendFileError>;
+}
+
+impl<T, E: Into<SendFileError>> ErrorContextExt<T> for Result<T, E> {
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T, SendFileError> {
+        self.map_err(|e| e.into().with_context(context()))
+    }
+}
+
+/// How failures of `send` and `receive` are printed (`--error-format`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ErrorFormat {
+    /// A log line with the message and its context.
+    #[default]
+    Human,
+    /// An [ErrorReport] as one line of JSON on stderr.
+    Json,
+}
+
+/// Structured form of a [SendFileError], printed with `--error-format json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorReport {
+    /// Stable code of the error, see [SendFileError::code].
+    pub code: &'static str,
+    /// Message of the error, without its context.
+    pub message: String,
+    /// Where the error happened, if known.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub context: Option<ErrorContext>,
+}
+
+impl From<&SendFileError> for ErrorReport {
+    fn from(error: &SendFileError) -> Self {
+        Self {
+            code: error.code(),
+            message: error.root().to_string(),
+            context: error.context().cloned(),
+        }
+    }
+}
+
+impl SendFileError {
+    /// Returns the error for an offer the receiver rejected for `reason`, a
+    /// [SendFileError::TypeRejected] if its [TypePolicy](crate::file::content_type::TypePolicy)
+    /// refused the file.
+    pub fn rejected(reason: String) -> Self {
+        match reason.strip_prefix(TYPE_REJECTION_PREFIX) {
+            Some(reason) => Self::TypeRejected(reason.to_string()),
+            None => Self::OfferRejected(reason),
+        }
+    }
+
+    /// Returns the error for a handshake the receiver refused with `reject`, see
+    /// [Self::rejected].
+    pub fn handshake_rejected(reject: HandshakeRejectV1) -> Self {
+        match reject.reason {
+            RejectReasonV1::FileType => Self::TypeRejected(reject.message),
+            _ => Self::OfferRejected(reject.message),
+        }
+    }
+
+    /// Returns this error with `context`. An error that already has a context keeps it, completed
+    /// with what `context` adds, rather than nesting contexts.
+    pub fn with_context(self, context: ErrorContext) -> Self {
+        match self {
+            Self::Context {
+                context: inner,
+                source,
+            } => Self::Context {
+                context: inner.within(context),
+                source,
+            },
+            error => Self::Context {
+                context,
+                source: Box::new(error),
+            },
+        }
+    }
+
+    /// Returns the error without its [ErrorContext], to match on its kind.
+    pub fn root(&self) -> &SendFileError {
+        match self {
+            Self::Context { source, .. } => source.root(),
+            error => error,
+        }
+    }
+
+    /// Returns where the error happened, if known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Self::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Returns the stable code of the kind of error, for scripts to tell errors apart without
+    /// parsing their messages.
+    pub fn code(&self) -> &'static str {
+        match self.root() {
+            Self::Io(_) => "io",
+            Self::FileMetadata(_) => "file_metadata",
+            Self::Transport(_) => "transport",
+            Self::InvalidAddress(_) => "invalid_address",
+            Self::Stream(_) => "stream",
+            Self::UnexpectedMessage { .. } => "unexpected_message",
+            Self::BlockHashMismatch { .. } => "block_hash_mismatch",
+            Self::BlockSequenceMismatch { .. } => "block_sequence_mismatch",
+            Self::ChecksumMismatch { .. } => "checksum_mismatch",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::ConnectionFailed(_) => "connection_failed",
+            Self::IntegrityCheckFailed { .. } => "integrity_check_failed",
+            Self::MissingCapability { .. } => "missing_capability",
+            Self::NoCommonProtocolVersion(_) => "no_common_protocol_version",
+            Self::Tls(_) => "tls",
+            Self::Noise(_) => "noise",
+            Self::Pairing(_) => "pairing",
+            Self::Metadata(_) => "metadata",
+            Self::WebSocket(_) => "websocket",
+            Self::Relay(_) => "relay",
+            Self::Bundle(_) => "bundle",
+            Self::DeviceNotConfirmed(_) => "device_not_confirmed",
+            Self::DeviceTooSmall { .. } => "device_too_small",
+            Self::StrictModeViolation(_) => "strict_mode_violation",
+            Self::FileLocked(_) => "file_locked",
+            Self::AuthenticationFailed(_) => "authentication_failed",
+            Self::OfferRejected(_) => "offer_rejected",
+            Self::TypeRejected(_) => "type_rejected",
+            Self::ScanFailed(_) => "scan_failed",
+            Self::NoReceipt(_) => "no_receipt",
+            Self::BlockUnreadable { .. } => "block_unreadable",
+            Self::IncompleteFile { .. } => "incomplete_file",
+            Self::ResumeFailed(_) => "resume_failed",
+            Self::Cancelled => "cancelled",
+            Self::TimeLimitExceeded(_) => "time_limit_exceeded",
+            Self::AbortedBySender(_) => "aborted_by_sender",
+            Self::CancelledByPeer(_) => "cancelled_by_peer",
+            Self::SenderUnavailable { .. } => "sender_unavailable",
+            Self::WorkerPanicked { .. } => "worker_panicked",
+            Self::Context { source, .. } => source.code(),
+        }
+    }
+
+    /// Returns whether the error is a read or write that timed out.
+    pub fn is_timeout(&self) -> bool {
+        let io_error = match self.root() {
+            Self::Io(e) | Self::Stream(StreamReadError::Io(e)) | Self::Tls(TlsError::Io(e)) => e,
+            _ => return false,
+        };
+        matches!(
+            io_error.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contexts_are_merged() {
+        let error = SendFileError::from(std::io::Error::other("No space left on device"))
+            .with_context(ErrorContext::new("writing").with_seq(12))
+            .with_context(
+                ErrorContext::new("receiving")
+                    .with_file("backup.tar")
+                    .with_peer("10.0.0.2:7878"),
+            );
+
+        assert!(matches!(error.root(), SendFileError::Io(_)));
+        assert_eq!(error.code(), "io");
+        assert_eq!(
+            error.to_string(),
+            "IO error: No space left on device \
+             (while writing block 12 of \"backup.tar\", peer 10.0.0.2:7878)"
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorReport::from(&error)).unwrap(),
+            r#"{"code":"io","message":"IO error: No space left on device","operation":"writing","peer":"10.0.0.2:7878","file":"backup.tar","seq":12}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorReport::from(&SendFileError::Cancelled)).unwrap(),
+            r#"{"code":"cancelled","message":"Transfer was cancelled"}"#
+        );
+    }
 }
@@ -0,0 +1,252 @@
+//! Estimates of how well a file compresses and how long sending it takes.
+//!
+//! Before a transfer, up to [COMPRESSION_SAMPLE_BLOCKS] blocks spread evenly over the file are
+//! gzip-compressed like the sender does. Their ratio predicts the bytes sent on the wire, and the
+//! time compressing them took tells whether compression would become the bottleneck: on a fast
+//! link, compressing data that barely shrinks only slows the transfer down.
+//!
+//! While sending, the byte entropy of a few KB of each block is estimated with
+//! [sampled_entropy], and blocks that look encrypted or already compressed are sent without
+//! trying to compress them.
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+use crate::stream::source::BlockSource;
+
+/// Largest number of blocks compressed to estimate the compression ratio of a file.
+pub const COMPRESSION_SAMPLE_BLOCKS: u32 = 8;
+
+/// Smallest share of the file compression must save to be worth enabling.
+pub const MIN_COMPRESSION_SAVINGS: f64 = 0.05;
+
+/// Number of bytes of a block [sampled_entropy] looks at.
+pub const ENTROPY_SAMPLE_LEN: usize = 4096;
+
+/// Default entropy in bits per byte above which a block is sent without trying to compress it.
+/// Random bytes sampled over [ENTROPY_SAMPLE_LEN] bytes come out just under 8.
+pub const DEFAULT_ENTROPY_THRESHOLD: f64 = 7.8;
+
+/// Number of runs [sampled_entropy] takes its sample in, spread over the block.
+const ENTROPY_SAMPLE_RUNS: usize = 4;
+
+/// Result of compressing a sample of the blocks of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionEstimate {
+    /// Number of bytes sampled.
+    pub sampled_bytes: u64,
+    /// Size of the sampled bytes once compressed, blocks that don't shrink counting at their
+    /// original size like the sender sends them.
+    pub compressed_bytes: u64,
+    /// Time spent compressing the sample on one thread.
+    pub compress_time: Duration,
+}
+
+impl CompressionEstimate {
+    /// Compresses up to [COMPRESSION_SAMPLE_BLOCKS] blocks spread evenly over a `size` byte file.
+    /// The same blocks are picked every time for a given size and block size.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Content of the file.
+    /// * `size` - Size of the file in bytes.
+    /// * `block_size` - Size of a block in bytes.
+    ///
+    /// # Returns
+    ///
+    /// The estimate, or the error of a failed block read.
+    pub fn sample(source: &dyn BlockSource, size: u64, block_size: u32) -> io::Result<Self> {
+        let total_blocks = size.div_ceil(block_size as u64);
+        let samples = total_blocks.min(COMPRESSION_SAMPLE_BLOCKS as u64);
+
+        let mut estimate = Self {
+            sampled_bytes: 0,
+            compressed_bytes: 0,
+            compress_time: Duration::ZERO,
+        };
+        for index in 0..samples {
+            let seq = (index * total_blocks / samples) as u32;
+            let data = source.read_block(seq, block_size)?;
+
+            let started = Instant::now();
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            let len = encoder.finish()?.len().min(data.len());
+            estimate.compress_time += started.elapsed();
+
+            estimate.sampled_bytes += data.len() as u64;
+            estimate.compressed_bytes += len as u64;
+        }
+        Ok(estimate)
+    }
+
+    /// Returns the compressed size relative to the original size, 1 if nothing was sampled.
+    pub fn ratio(&self) -> f64 {
+        if self.sampled_bytes == 0 {
+            return 1.0;
+        }
+        self.compressed_bytes as f64 / self.sampled_bytes as f64
+    }
+
+    /// Returns the estimated number of bytes sent for a `size` byte file once compressed.
+    pub fn wire_bytes(&self, size: u64) -> u64 {
+        if self.sampled_bytes == 0 {
+            return size;
+        }
+        (size as u128 * self.compressed_bytes as u128 / self.sampled_bytes as u128) as u64
+    }
+
+    /// Returns the estimated time to send a `size` byte file.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Size of the file in bytes.
+    /// * `link_speed` - Speed of the link to the receiver in bytes per second.
+    /// * `connections` - Number of data connections, each compressing on its own thread.
+    /// * `compress` - Whether blocks are compressed.
+    pub fn duration(
+        &self,
+        size: u64,
+        link_speed: u64,
+        connections: u16,
+        compress: bool,
+    ) -> Duration {
+        let wire_bytes = if compress {
+            self.wire_bytes(size)
+        } else {
+            size
+        };
+        let network = wire_bytes as f64 / link_speed.max(1) as f64;
+
+        // Compression runs concurrently with the network, the slower of the two sets the pace
+        let cpu = if compress && self.sampled_bytes > 0 {
+            let per_byte = self.compress_time.as_secs_f64() / self.sampled_bytes as f64;
+            size as f64 * per_byte / connections.max(1) as f64
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64(network.max(cpu))
+    }
+
+    /// Returns whether compressing makes the transfer faster: the sample shrinks by at least
+    /// [MIN_COMPRESSION_SAVINGS] and compressing doesn't take longer than sending uncompressed.
+    pub fn is_worthwhile(&self, size: u64, link_speed: u64, connections: u16) -> bool {
+        self.ratio() <= 1.0 - MIN_COMPRESSION_SAVINGS
+            && self.duration(size, link_speed, connections, true)
+                < self.duration(size, link_speed, connections, false)
+    }
+}
+
+/// Estimates the Shannon entropy of `data` in bits per byte, from up to [ENTROPY_SAMPLE_LEN]
+/// bytes taken in a few runs spread over it. Returns 0 for empty data and at most 8.
+pub fn sampled_entropy(data: &[u8]) -> f64 {
+    let mut counts = [0u32; 256];
+    let mut sampled = 0usize;
+    if data.len() <= ENTROPY_SAMPLE_LEN {
+        data.iter().for_each(|byte| counts[*byte as usize] += 1);
+        sampled = data.len();
+    } else {
+        let run_len = ENTROPY_SAMPLE_LEN / ENTROPY_SAMPLE_RUNS;
+        for run in 0..ENTROPY_SAMPLE_RUNS {
+            let start = run * (data.len() - run_len) / (ENTROPY_SAMPLE_RUNS - 1);
+            data[start..start + run_len]
+                .iter()
+                .for_each(|byte| counts[*byte as usize] += 1);
+            sampled += run_len;
+        }
+    }
+    if sampled == 0 {
+        return 0.0;
+    }
+
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / sampled as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::source::ReaderSource;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_sample_is_deterministic() {
+        // Compressible first half, incompressible second half
+        let mut content = vec![0u8; 8000];
+        let mut state = 0x2545_f491u32;
+        for byte in &mut content[4000..] {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *byte = state as u8;
+        }
+        let source = ReaderSource::new(Cursor::new(content), 8000);
+
+        let estimate = CompressionEstimate::sample(&source, 8000, 1000).unwrap();
+        assert_eq!(estimate.sampled_bytes, 8000);
+        assert!((4000..4500).contains(&estimate.wire_bytes(8000)));
+        let again = CompressionEstimate::sample(&source, 8000, 1000).unwrap();
+        assert_eq!(again.compressed_bytes, estimate.compressed_bytes);
+
+        let empty = CompressionEstimate::sample(&source, 0, 1000).unwrap();
+        assert_eq!(empty.ratio(), 1.0);
+        assert_eq!(empty.wire_bytes(0), 0);
+    }
+
+    #[test]
+    fn test_sampled_entropy() {
+        let mut random = vec![0u8; 1 << 20];
+        let mut state = 0x2545_f491u32;
+        for byte in &mut random {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *byte = state as u8;
+        }
+        assert!(sampled_entropy(&random) > DEFAULT_ENTROPY_THRESHOLD);
+
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(100_000);
+        assert!(sampled_entropy(&text) < 5.0);
+        assert_eq!(sampled_entropy(&[7u8; 100]), 0.0);
+        assert_eq!(sampled_entropy(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_compression_pays_off_on_slow_links() {
+        // Halves the data at 1 MB/s on one thread
+        let estimate = CompressionEstimate {
+            sampled_bytes: 1_000_000,
+            compressed_bytes: 500_000,
+            compress_time: Duration::from_secs(1),
+        };
+        let size = 10_000_000;
+
+        assert_eq!(
+            estimate.duration(size, 1_000_000, 1, false),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            estimate.duration(size, 1_000_000, 2, true),
+            Duration::from_secs(5)
+        );
+        assert!(estimate.is_worthwhile(size, 1_000_000, 2));
+        // Compressing becomes the bottleneck on a fast link
+        assert!(!estimate.is_worthwhile(size, 100_000_000, 2));
+
+        let incompressible = CompressionEstimate {
+            compressed_bytes: 990_000,
+            ..estimate
+        };
+        assert!(!incompressible.is_worthwhile(size, 1_000, 16));
+    }
+}
@@ -0,0 +1,633 @@
+//! Handles for managing transfers running in the background.
+//!
+//! [start_send_file](crate::stream::send::start_send_file) and
+//! [start_receive_file](crate::stream::receive::start_receive_file) run a transfer on its own
+//! thread and return a [TransferHandle], so GUIs and services can poll its progress, pause or
+//! cancel it without blocking a thread on the transfer.
+
+use std::{
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use crate::{
+    stream::{
+        bottleneck::{BottleneckMonitor, Stage, StageTimings},
+        bundle::{BundleProgress, BundleStats, FileStatus},
+        error::SendFileError,
+        panic::contain_panic,
+        throttle::RateLimiter,
+    },
+    telemetry::TraceContext,
+};
+
+/// Interval at which paused transfers check whether they were resumed or cancelled.
+const PAUSE_POLL_MS: u64 = 100;
+
+/// Total size of a transfer that has not completed its handshake yet.
+const UNKNOWN_TOTAL: u64 = u64::MAX;
+
+/// Progress of a transfer, as returned by [TransferHandle::progress].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// Bytes of the file transferred (or reused) so far.
+    pub bytes_transferred: u64,
+    /// Size of the file, `None` until the handshake completed.
+    pub total_bytes: Option<u64>,
+}
+
+/// Control flags and progress counters shared between a transfer and its [TransferHandle].
+///
+/// Blocking calls such as [send_file](crate::stream::send::send_file) use a private instance.
+#[derive(Debug)]
+pub struct TransferControl {
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+    /// Set when the time limit of this side ran out, see [crate::stream::time_limit].
+    expired: AtomicBool,
+    /// Reason the peer gave for aborting the transfer at its time limit.
+    peer_abort: Mutex<Option<String>>,
+    /// Reason the peer gave for cancelling the transfer, see [crate::stream::cancel].
+    peer_cancel: Mutex<Option<String>>,
+    /// Thread and message of the first panic of a thread of the transfer, see
+    /// [crate::stream::panic].
+    panic: Mutex<Option<(String, String)>>,
+    bytes_transferred: AtomicU64,
+    total_bytes: AtomicU64,
+    /// Bytes the receiver reported receiving on the control channel, [UNKNOWN_TOTAL] until it
+    /// does, see [crate::stream::control].
+    reported_bytes: AtomicU64,
+    /// Limit of [Self::limit_rate], `None` without one.
+    rate_limit: Mutex<Option<Arc<RateLimiter>>>,
+    /// Connections of the transfer, shut down on cancellation to unblock pending reads.
+    streams: Mutex<Vec<TcpStream>>,
+    /// Control channels of the transfer, whose reads only are shut down on cancellation so the
+    /// peer can still be told, see [crate::stream::control].
+    control_streams: Mutex<Vec<TcpStream>>,
+    /// Time the connections spent in each stage of the pipeline.
+    timings: Arc<StageTimings>,
+    bottleneck: Mutex<BottleneckMonitor>,
+    /// Progress of every file when a directory is transferred, see [crate::stream::bundle].
+    files: Mutex<Option<Arc<BundleProgress>>>,
+    /// Span of the transfer its connections are traced under, see [crate::telemetry].
+    trace: Mutex<TraceContext>,
+}
+
+impl TransferControl {
+    /// Creates the control state of a transfer that is running and has made no progress.
+    pub fn new() -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            expired: AtomicBool::new(false),
+            peer_abort: Mutex::new(None),
+            peer_cancel: Mutex::new(None),
+            panic: Mutex::new(None),
+            bytes_transferred: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(UNKNOWN_TOTAL),
+            reported_bytes: AtomicU64::new(UNKNOWN_TOTAL),
+            rate_limit: Mutex::new(None),
+            streams: Mutex::new(Vec::new()),
+            control_streams: Mutex::new(Vec::new()),
+            timings: Arc::new(StageTimings::default()),
+            bottleneck: Mutex::new(BottleneckMonitor::new()),
+            files: Mutex::new(None),
+            trace: Mutex::new(TraceContext::default()),
+        }
+    }
+
+    /// Returns the progress of the transfer. Senders count the bytes the receiver reported
+    /// receiving once it reports them, see [crate::stream::control].
+    pub fn progress(&self) -> TransferProgress {
+        let total = self.total_bytes.load(Ordering::SeqCst);
+        let total_bytes = (total != UNKNOWN_TOTAL).then_some(total);
+        let bytes_transferred = match self.reported_bytes.load(Ordering::SeqCst) {
+            UNKNOWN_TOTAL => self.bytes_transferred.load(Ordering::SeqCst),
+            reported => reported,
+        };
+        // Blocks sent again after a failed attempt are counted twice
+        let bytes_transferred = bytes_transferred.min(total_bytes.unwrap_or(u64::MAX));
+        TransferProgress {
+            bytes_transferred,
+            total_bytes,
+        }
+    }
+
+    /// Returns the stage of the pipeline that limited the transfer over the last few seconds, see
+    /// [crate::stream::bottleneck].
+    pub fn bottleneck(&self) -> Option<Stage> {
+        self.bottleneck
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .current()
+    }
+
+    /// Returns the progress of every file when a directory is transferred, in the order they
+    /// are listed, none for single files.
+    pub fn files(&self) -> Vec<FileStatus> {
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|progress| progress.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Stops requesting (or serving) blocks until [TransferControl::resume] is called.
+    pub fn pause(&self) {
+        info!("Pausing transfer");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes a paused transfer.
+    pub fn resume(&self) {
+        info!("Resuming transfer");
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether the transfer is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Limits the transfer to `bytes_per_second`, `None` lifts the limit. A sender also asks
+    /// the receiver to request blocks no faster, see [crate::stream::control].
+    pub fn limit_rate(&self, bytes_per_second: Option<u64>) {
+        let limiter = bytes_per_second
+            .filter(|rate| *rate > 0)
+            .map(|rate| Arc::new(RateLimiter::new(rate)));
+        match &limiter {
+            Some(limiter) => info!("Limiting transfer to {} bytes/s", limiter.rate()),
+            None => info!("Lifting the rate limit of the transfer"),
+        }
+        *self.rate_limit.lock().unwrap_or_else(|e| e.into_inner()) = limiter;
+    }
+
+    /// Returns the limit set with [Self::limit_rate] in bytes per second, `None` without one.
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.rate_limit
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|limiter| limiter.rate())
+    }
+
+    /// Aborts the transfer, which then fails with [SendFileError::Cancelled].
+    ///
+    /// Blocks already written on the receiving side are kept, so the file can be resumed by a
+    /// later transfer. Only reads are shut down, so the peer can still be told, see
+    /// [crate::stream::cancel].
+    pub fn cancel(&self) {
+        info!("Cancelling transfer");
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.shut_reads();
+    }
+
+    /// Returns whether the transfer was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the transfer once its time limit ran out. Only reads are shut down, so the peer
+    /// can still be told why the connections are closed.
+    pub(crate) fn expire(&self) {
+        self.expired.store(true, Ordering::SeqCst);
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.shut_reads();
+    }
+
+    /// Shuts the reads of every connection of the transfer down, unblocking pending reads.
+    fn shut_reads(&self) {
+        let streams = self.streams.lock().unwrap_or_else(|e| e.into_inner());
+        let control_streams = self
+            .control_streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        for stream in streams.iter().chain(control_streams.iter()) {
+            // The peer may already have closed the connection
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Returns whether the time limit of the transfer ran out.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the transfer the peer aborted at its time limit, giving `reason`.
+    pub(crate) fn abort_by_peer(&self, reason: String) {
+        warn!("Peer aborted the transfer: {}", reason);
+        *self.peer_abort.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+        self.cancel();
+    }
+
+    /// Returns the reason the peer gave for aborting the transfer, if it did.
+    pub(crate) fn peer_abort(&self) -> Option<String> {
+        self.peer_abort
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Cancels the transfer the peer cancelled, giving `reason`, see [crate::stream::cancel].
+    pub(crate) fn cancel_by_peer(&self, reason: String) {
+        warn!("Peer cancelled the transfer: {}", reason);
+        *self.peer_cancel.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+        self.cancel();
+    }
+
+    /// Returns the reason the peer gave for cancelling the transfer, if it did.
+    pub(crate) fn peer_cancel(&self) -> Option<String> {
+        self.peer_cancel
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Cancels the transfer a thread of which panicked with `message`, see
+    /// [crate::stream::panic]. The first panic is the one the transfer fails with.
+    pub(crate) fn fail_on_panic(&self, worker: String, message: String) {
+        self.panic
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert((worker, message));
+        self.cancel();
+    }
+
+    /// Returns whether the transfer was cancelled on this side, rather than by the peer or at a
+    /// time limit, so the peer is to be told with a `Cancel`.
+    pub(crate) fn is_cancelled_here(&self) -> bool {
+        self.is_cancelled()
+            && !self.is_expired()
+            && self.peer_abort().is_none()
+            && self.peer_cancel().is_none()
+    }
+
+    /// Returns the error a cancelled transfer fails with: [SendFileError::WorkerPanicked] if one
+    /// of its threads panicked, [SendFileError::CancelledByPeer] if the peer cancelled it,
+    /// [SendFileError::Cancelled] otherwise.
+    pub(crate) fn cancellation(&self) -> SendFileError {
+        let panic = self.panic.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Some((worker, message)) = panic {
+            return SendFileError::WorkerPanicked { worker, message };
+        }
+        match self.peer_cancel() {
+            Some(reason) => SendFileError::CancelledByPeer(reason),
+            None => SendFileError::Cancelled,
+        }
+    }
+
+    /// Blocks while the transfer is paused.
+    ///
+    /// Fails with [Self::cancellation] once the transfer is cancelled, paused or not.
+    pub(crate) fn checkpoint(&self) -> Result<(), SendFileError> {
+        while self.is_paused() && !self.is_cancelled() {
+            thread::sleep(Duration::from_millis(PAUSE_POLL_MS));
+        }
+        if self.is_cancelled() {
+            return Err(self.cancellation());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_total_bytes(&self, total: u64) {
+        self.total_bytes.store(total, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_bytes(&self, bytes: u64) {
+        self.bytes_transferred.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Follows the progress of the files of the directory transferred in `progress`.
+    pub(crate) fn track_files(&self, progress: Arc<BundleProgress>) {
+        *self.files.lock().unwrap_or_else(|e| e.into_inner()) = Some(progress);
+    }
+
+    /// Counts `bytes` more of file `file_index` of the directory transferred, see
+    /// [Self::track_files].
+    pub(crate) fn add_file_bytes(&self, file_index: u32, bytes: u64) {
+        if let Some(progress) = &*self.files.lock().unwrap_or_else(|e| e.into_inner()) {
+            progress.add_bytes(file_index, bytes);
+        }
+    }
+
+    /// Returns the bytes and time every file and top-level directory of the directory
+    /// transferred took so far, none for single files.
+    pub(crate) fn file_stats(&self) -> Option<BundleStats> {
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|progress| progress.stats())
+    }
+
+    /// Returns the bytes this side sent or received itself, whatever the peer reports on the
+    /// control channel.
+    pub(crate) fn own_bytes(&self) -> u64 {
+        self.bytes_transferred.load(Ordering::SeqCst)
+    }
+
+    /// Counts the bytes the receiver reported receiving on the control channel as the progress
+    /// of the transfer, `None` counts the bytes sent again once the channel is lost.
+    pub(crate) fn report_bytes(&self, bytes: Option<u64>) {
+        self.reported_bytes
+            .store(bytes.unwrap_or(UNKNOWN_TOTAL), Ordering::SeqCst);
+    }
+
+    /// Waits as long as the limit of [Self::limit_rate] requires before `bytes` more cross the
+    /// network.
+    pub(crate) fn throttle(&self, bytes: u64) {
+        let limiter = self
+            .rate_limit
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some(limiter) = limiter {
+            limiter.acquire(bytes);
+        }
+    }
+
+    /// Returns the timings the connections record the stages of the pipeline in.
+    pub(crate) fn timings(&self) -> &Arc<StageTimings> {
+        &self.timings
+    }
+
+    /// Returns the span of the transfer, the root of a new trace until [Self::set_trace] is
+    /// called.
+    pub(crate) fn trace(&self) -> TraceContext {
+        self.trace.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Traces the connections of the transfer under `trace`.
+    pub(crate) fn set_trace(&self, trace: TraceContext) {
+        *self.trace.lock().unwrap_or_else(|e| e.into_inner()) = trace;
+    }
+
+    /// Logs a hint when the stage limiting the transfer changed. Cheap enough to call for every
+    /// block, the bottleneck is only measured every few seconds.
+    pub(crate) fn check_bottleneck(&self) {
+        // Another connection is already checking
+        let Ok(mut monitor) = self.bottleneck.try_lock() else {
+            return;
+        };
+        if let Some(stage) = monitor.check(&self.timings) {
+            info!("Hint: {}", stage.hint());
+        }
+    }
+
+    /// Shuts the reads of the control channel `stream` down when the transfer is cancelled, so
+    /// the peer can still be told why.
+    pub(crate) fn register_control(&self, stream: &TcpStream) {
+        match stream.try_clone() {
+            Ok(clone) => {
+                if self.is_cancelled() {
+                    let _ = clone.shutdown(Shutdown::Read);
+                }
+                self.control_streams
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(clone);
+            }
+            Err(e) => warn!("Connection can't be interrupted on cancellation: {}", e),
+        }
+    }
+
+    /// Shuts the reads of `stream` down when the transfer is cancelled.
+    pub(crate) fn register(&self, stream: &TcpStream) {
+        match stream.try_clone() {
+            Ok(clone) => {
+                if self.is_cancelled() {
+                    let _ = clone.shutdown(Shutdown::Read);
+                }
+                self.streams
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(clone);
+            }
+            Err(e) => warn!("Connection can't be interrupted on cancellation: {}", e),
+        }
+    }
+}
+
+impl Default for TransferControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A transfer running on a background thread.
+///
+/// Dropping the handle detaches the transfer, which keeps running. Call
+/// [TransferHandle::cancel] first to stop it.
+#[derive(Debug)]
+pub struct TransferHandle<T = ()> {
+    control: Arc<TransferControl>,
+    thread: Option<JoinHandle<Result<T, SendFileError>>>,
+}
+
+impl<T: Send + 'static> TransferHandle<T> {
+    /// Runs `transfer` on a new thread named after `role`, sharing `control` with it. A panic
+    /// fails the transfer with [SendFileError::WorkerPanicked], see [crate::stream::panic].
+    pub(crate) fn spawn<F>(
+        role: &str,
+        control: Arc<TransferControl>,
+        transfer: F,
+    ) -> Result<Self, SendFileError>
+    where
+        F: FnOnce(&Arc<TransferControl>) -> Result<T, SendFileError> + Send + 'static,
+    {
+        let thread = thread::Builder::new()
+            .name(crate::threads::thread_name(role, 0))
+            .spawn({
+                let control = control.clone();
+                move || contain_panic(&control, || transfer(&control))
+            })?;
+
+        Ok(Self {
+            control,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the progress of the transfer.
+    pub fn progress(&self) -> TransferProgress {
+        self.control.progress()
+    }
+
+    /// Returns the progress of every file of a directory, see [TransferControl::files].
+    pub fn files(&self) -> Vec<FileStatus> {
+        self.control.files()
+    }
+
+    /// Pauses the transfer, see [TransferControl::pause].
+    pub fn pause(&self) {
+        self.control.pause();
+    }
+
+    /// Resumes a paused transfer.
+    pub fn resume(&self) {
+        self.control.resume();
+    }
+
+    /// Returns whether the transfer is paused.
+    pub fn is_paused(&self) -> bool {
+        self.control.is_paused()
+    }
+
+    /// Limits the rate of the transfer, see [TransferControl::limit_rate].
+    pub fn limit_rate(&self, bytes_per_second: Option<u64>) {
+        self.control.limit_rate(bytes_per_second);
+    }
+
+    /// Cancels the transfer, see [TransferControl::cancel]. Returns immediately, use
+    /// [TransferHandle::wait] to wait for the transfer to wind down.
+    pub fn cancel(&self) {
+        self.control.cancel();
+    }
+
+    /// Returns whether the transfer has finished, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Returns the result of the transfer if it has finished, without blocking.
+    ///
+    /// The result is handed out once: `None` means the transfer is still running, or that its
+    /// result was already returned by an earlier call.
+    pub fn try_wait(&mut self) -> Option<Result<T, SendFileError>> {
+        if !self.thread.as_ref()?.is_finished() {
+            return None;
+        }
+        self.thread.take().map(join)
+    }
+
+    /// Blocks until the transfer finishes and returns its result.
+    ///
+    /// # Panics
+    ///
+    /// If the result was already returned by [TransferHandle::try_wait], or if the transfer
+    /// thread panicked.
+    pub fn wait(mut self) -> Result<T, SendFileError> {
+        let thread = self
+            .thread
+            .take()
+            .expect("Transfer result was already taken by try_wait");
+        join(thread)
+    }
+}
+
+fn join<T>(thread: JoinHandle<Result<T, SendFileError>>) -> Result<T, SendFileError> {
+    thread
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_progress_is_capped_by_total() {
+        let control = TransferControl::new();
+        control.add_bytes(10);
+        assert_eq!(
+            control.progress(),
+            TransferProgress {
+                bytes_transferred: 10,
+                total_bytes: None
+            }
+        );
+
+        control.set_total_bytes(8);
+        assert_eq!(control.progress().bytes_transferred, 8);
+
+        // What the receiver reported wins over what was sent
+        control.report_bytes(Some(6));
+        assert_eq!(control.progress().bytes_transferred, 6);
+        control.report_bytes(None);
+        assert_eq!(control.progress().bytes_transferred, 8);
+    }
+
+    #[test]
+    fn test_cancel_interrupts_pause() {
+        let control = Arc::new(TransferControl::new());
+        control.pause();
+
+        let mut handle = TransferHandle::spawn("test", control, |control| {
+            control.checkpoint()?;
+            Ok(42)
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(2 * PAUSE_POLL_MS));
+        assert!(handle.try_wait().is_none());
+
+        let start = Instant::now();
+        handle.cancel();
+        assert!(matches!(handle.wait(), Err(SendFileError::Cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_panicking_transfers_fail_alone() {
+        let panicking = TransferHandle::spawn("test", Arc::new(TransferControl::new()), |_| {
+            let seq: Option<u32> = std::hint::black_box(None);
+            Ok(seq.expect("bad block number"))
+        })
+        .unwrap();
+        let other =
+            TransferHandle::spawn("test", Arc::new(TransferControl::new()), |_| Ok(7)).unwrap();
+
+        assert!(matches!(
+            panicking.wait(),
+            Err(SendFileError::WorkerPanicked { message, .. }) if message == "bad block number"
+        ));
+        assert_eq!(other.wait().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_peer_cancel_is_reported() {
+        let control = TransferControl::new();
+        control.cancel_by_peer(String::from("cancelled by the receiver"));
+        assert!(control.is_cancelled());
+        assert!(!control.is_cancelled_here());
+        assert!(matches!(
+            control.checkpoint(),
+            Err(SendFileError::CancelledByPeer(reason)) if reason == "cancelled by the receiver"
+        ));
+
+        let control = TransferControl::new();
+        control.cancel();
+        assert!(control.is_cancelled_here());
+        assert!(matches!(
+            control.checkpoint(),
+            Err(SendFileError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_resume_completes_transfer() {
+        let control = Arc::new(TransferControl::new());
+        control.pause();
+
+        let mut handle = TransferHandle::spawn("test", control, |control| {
+            control.checkpoint()?;
+            Ok(42)
+        })
+        .unwrap();
+        handle.resume();
+
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(handle.try_wait().unwrap().unwrap(), 42);
+        assert!(handle.try_wait().is_none());
+    }
+}
@@ -0,0 +1,317 @@
+//! Heartbeats on idle connections.
+//!
+//! Long verification phases and slow disks can leave a connection without traffic long enough
+//! for a NAT or firewall to drop it, and a peer that vanished without closing its connections
+//! otherwise goes unnoticed until a long timeout. When both peers advertise
+//! [Capabilities::HEARTBEAT](crate::capabilities::Capabilities::HEARTBEAT), a peer that waited
+//! [Heartbeat::interval] on a data connection sends a `Ping`, answered with a `Pong` once the
+//! other peer reads it, and gives up on the connection once nothing came from the peer for
+//! [Heartbeat::timeout].
+//!
+//! Only the receiver learns the negotiated features, so it opens every data connection with a
+//! `Ping` and senders only ping connections a `Ping` came in on. While blocks are downloaded and
+//! the received file is hashed, the receiver also pings the handshake connection, which it
+//! doesn't read: those pings go unanswered, and the sender waits for the receipt as long as they
+//! keep coming.
+
+use std::{
+    cell::Cell,
+    io::{self, Read, Write},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::{
+    stream::preconnected::Connection,
+    transport::{attach_headers_for, PingV1, ReceiverMessageV1, SenderMessageV1, SESSION_ID_SIZE},
+};
+
+/// Default time a peer waits on a connection before pinging, `--heartbeat-interval`.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Default time without anything from the peer after which it is given up on,
+/// `--heartbeat-timeout`.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Heartbeat settings of the connections of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Time a peer waits on a connection before pinging, and between two pings.
+    pub interval: Duration,
+    /// Time without a single byte from the peer after which its connection is dropped.
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    /// Pings every 15 seconds, drops peers silent for two minutes.
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+        }
+    }
+}
+
+/// Peer a connection carries the messages of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Sender,
+    Receiver,
+}
+
+/// Connection that pings the peer while a read waits on it, once pinging is enabled with
+/// [Connection::enable_heartbeat], and fails reads once the peer was silent for
+/// [Heartbeat::timeout].
+pub(crate) struct Heartbeating<S> {
+    inner: S,
+    heartbeat: Heartbeat,
+    side: Side,
+    /// Protocol version pings are framed with, `None` until pinging is enabled.
+    protocol_version: Option<u8>,
+    /// Session id pings are tagged with.
+    session_id: Option<[u8; SESSION_ID_SIZE]>,
+    /// Read timeout set by the user of the connection.
+    read_timeout: Cell<Option<Duration>>,
+    last_heard: Instant,
+    last_ping: Option<Instant>,
+    seq: u32,
+}
+
+impl<S: Connection> Heartbeating<S> {
+    /// Wraps `inner`, carrying the messages of `side`, without pinging yet.
+    pub(crate) fn new(inner: S, heartbeat: Heartbeat, side: Side) -> Self {
+        Self {
+            inner,
+            heartbeat,
+            side,
+            protocol_version: None,
+            session_id: None,
+            read_timeout: Cell::new(None),
+            last_heard: Instant::now(),
+            last_ping: None,
+            seq: 0,
+        }
+    }
+
+    /// Pings the peer now, if pinging is enabled.
+    pub(crate) fn ping(&mut self) -> io::Result<()> {
+        let Some(protocol_version) = self.protocol_version else {
+            return Ok(());
+        };
+        self.seq = self.seq.wrapping_add(1);
+        write_ping(
+            &mut self.inner,
+            self.side,
+            self.seq,
+            protocol_version,
+            self.session_id.as_ref(),
+        )?;
+        self.last_ping = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Returns whether the peer is due a ping: it was silent and wasn't pinged for
+    /// [Heartbeat::interval].
+    fn ping_due(&self) -> bool {
+        let last_sign = match self.last_ping {
+            Some(last_ping) => last_ping.max(self.last_heard),
+            None => self.last_heard,
+        };
+        last_sign.elapsed() >= self.heartbeat.interval
+    }
+
+    /// Reads from the connection, pinging the peer every [Heartbeat::interval] it waits.
+    fn read_pinging(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            let mut wait = self.heartbeat.interval;
+            if let Some(timeout) = self.read_timeout.get() {
+                match timeout.checked_sub(started.elapsed()) {
+                    Some(remaining) if !remaining.is_zero() => wait = wait.min(remaining),
+                    _ => return Err(io::ErrorKind::TimedOut.into()),
+                }
+            }
+            self.inner.set_read_timeout(Some(wait))?;
+            match self.inner.read(buffer) {
+                Err(e) if is_timeout(&e) => {}
+                result => return result,
+            }
+
+            if self.last_heard.elapsed() >= self.heartbeat.timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "Peer sent nothing for {} seconds, assuming it is gone",
+                        self.heartbeat.timeout.as_secs()
+                    ),
+                ));
+            }
+            if self.ping_due() {
+                self.ping()?;
+            }
+        }
+    }
+}
+
+impl<S: Connection> Read for Heartbeating<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.protocol_version.is_none() {
+            return self.inner.read(buffer);
+        }
+        let result = self.read_pinging(buffer);
+        self.inner.set_read_timeout(self.read_timeout.get())?;
+        if result.is_ok() {
+            self.last_heard = Instant::now();
+        }
+        result
+    }
+}
+
+impl<S: Write> Write for Heartbeating<S> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.inner.write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Connection> Connection for Heartbeating<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout.set(timeout);
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn enable_heartbeat(
+        &mut self,
+        protocol_version: u8,
+        session_id: Option<[u8; SESSION_ID_SIZE]>,
+    ) {
+        if self.protocol_version.is_none() {
+            debug!("Peer answers heartbeats, pinging it when idle");
+            self.protocol_version = Some(protocol_version);
+            self.session_id = session_id;
+            self.last_heard = Instant::now();
+        }
+    }
+}
+
+/// Runs `work` on another thread, pinging the peer on `stream` every [Heartbeat::interval]
+/// until it returns, so the connection doesn't look idle meanwhile. Without `heartbeat`,
+/// `work` runs without pings.
+///
+/// # Arguments
+///
+/// * `stream` - Connection to ping, which nothing reads meanwhile.
+/// * `heartbeat` - Heartbeat negotiated with the peer, if any.
+/// * `side` - Peer the messages of `stream` are sent by.
+/// * `protocol_version` - Protocol version the pings are framed with.
+/// * `session_id` - Session id the pings are tagged with.
+/// * `work` - What the connection waits on.
+///
+/// # Returns
+///
+/// What `work` returned. Pinging stops at the first failed ping, the connection's next use
+/// reports the error.
+pub(crate) fn ping_while<W: Write, T: Send>(
+    stream: &mut W,
+    heartbeat: Option<Heartbeat>,
+    side: Side,
+    protocol_version: u8,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+    work: impl FnOnce() -> T + Send,
+) -> T {
+    let Some(heartbeat) = heartbeat else {
+        return work();
+    };
+    thread::scope(|scope| {
+        let (done, finished) = mpsc::channel();
+        let worker = scope.spawn(move || {
+            let result = work();
+            let _ = done.send(());
+            result
+        });
+        let mut seq = 0u32;
+        while let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(heartbeat.interval) {
+            seq = seq.wrapping_add(1);
+            if let Err(e) = write_ping(stream, side, seq, protocol_version, session_id) {
+                debug!("Stopped pinging the idle connection: {}", e);
+                break;
+            }
+        }
+        worker
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Writes a `Ping` of `side` numbered `seq` to `stream`.
+fn write_ping<W: Write>(
+    stream: &mut W,
+    side: Side,
+    seq: u32,
+    protocol_version: u8,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> io::Result<()> {
+    let ping = PingV1 { seq };
+    let mut buffer = [0u8; 16];
+    let payload = match side {
+        Side::Sender => SenderMessageV1::Ping(ping).to_bytes(&mut buffer),
+        Side::Receiver => ReceiverMessageV1::Ping(ping).to_bytes(&mut buffer),
+    }
+    .map_err(io::Error::other)?;
+    stream.write_all(&attach_headers_for(protocol_version, session_id, payload))?;
+    stream.flush()
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::read_next_payload,
+        transport::{CURRENT_PROTOCOL_VERSION, MAX_MESSAGE_SIZE},
+    };
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_pings_idle_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(400),
+        };
+        let mut stream = Heartbeating::new(client, heartbeat, Side::Receiver);
+        stream.enable_heartbeat(CURRENT_PROTOCOL_VERSION, Some([3; SESSION_ID_SIZE]));
+
+        let writer = thread::spawn(move || {
+            let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+            let ping =
+                read_next_payload::<ReceiverMessageV1, _>(&mut server, &mut buffer, 0).unwrap();
+            assert_eq!(ping.message, ReceiverMessageV1::Ping(PingV1 { seq: 1 }));
+            assert_eq!(ping.session_id, Some([3; SESSION_ID_SIZE]));
+            server.write_all(b"data").unwrap();
+            server
+        });
+        let mut data = [0u8; 4];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"data");
+        let _server = writer.join().unwrap();
+
+        // A peer that stays silent is given up on
+        let e = stream.read(&mut data).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
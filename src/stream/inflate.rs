@@ -0,0 +1,226 @@
+//! Decoding of compressed blocks into a fixed buffer.
+//!
+//! Senders compress blocks as single gzip members, or as single zstd frames when both peers
+//! negotiated zstd, see [crate::stream::compress]. A [BlockInflater] decodes them with one
+//! decoder state kept for the whole connection, straight into a buffer of the connection, so
+//! receiving compressed blocks allocates no memory per block.
+
+use std::io;
+
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+
+use crate::capabilities::CompressionCodec;
+
+/// First bytes of every gzip member.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Compression method of gzip members, deflate.
+const DEFLATE_METHOD: u8 = 8;
+
+/// Length of the fixed part of a gzip header: magic, method, flags, time, extra flags and OS.
+const GZIP_HEADER_SIZE: usize = 10;
+
+/// Length of the gzip trailer: CRC-32 and length of the decoded data.
+const GZIP_TRAILER_SIZE: usize = 8;
+
+/// Header flag of a CRC-16 of the header.
+const FLAG_HCRC: u8 = 0x02;
+
+/// Header flag of an extra field.
+const FLAG_EXTRA: u8 = 0x04;
+
+/// Header flag of a NUL terminated file name.
+const FLAG_NAME: u8 = 0x08;
+
+/// Header flag of a NUL terminated comment.
+const FLAG_COMMENT: u8 = 0x10;
+
+/// Decodes compressed blocks, reusing its decoder state from one block to the next.
+pub struct BlockInflater {
+    codec: CompressionCodec,
+    decompress: Decompress,
+    /// zstd context, created with the first block decoded with zstd.
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl BlockInflater {
+    /// Creates an inflater of gzip compressed blocks, allocating its inflate state once.
+    pub fn new() -> Self {
+        Self::with_codec(CompressionCodec::Gzip)
+    }
+
+    /// Creates an inflater of blocks compressed with `codec`.
+    pub fn with_codec(codec: CompressionCodec) -> Self {
+        Self {
+            codec,
+            decompress: Decompress::new(false),
+            zstd: None,
+        }
+    }
+
+    /// Decodes the compressed block `data` into `output`.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes decoded at the start of `output`, or an `InvalidData` error if `data`
+    /// is not a valid gzip member or zstd frame, fails its checksum or decodes to more than
+    /// `output` holds.
+    pub fn inflate(&mut self, data: &[u8], output: &mut [u8]) -> io::Result<usize> {
+        match self.codec {
+            CompressionCodec::None => Err(invalid_data(String::from(
+                "Compressed block, but compression was not negotiated",
+            ))),
+            CompressionCodec::Gzip => self.inflate_gzip(data, output),
+            CompressionCodec::Zstd => {
+                let decompressor = match &mut self.zstd {
+                    Some(decompressor) => decompressor,
+                    None => self.zstd.insert(zstd::bulk::Decompressor::new()?),
+                };
+                decompressor
+                    .decompress_to_buffer(data, output)
+                    .map_err(|e| invalid_data(format!("Corrupt zstd frame: {}", e)))
+            }
+        }
+    }
+
+    /// Decodes the gzip member `data` into `output`.
+    fn inflate_gzip(&mut self, data: &[u8], output: &mut [u8]) -> io::Result<usize> {
+        let deflated = &data[gzip_header_len(data)?..];
+
+        self.decompress.reset(false);
+        let status = self
+            .decompress
+            .decompress(deflated, output, FlushDecompress::Finish)
+            .map_err(|e| invalid_data(format!("Corrupt deflate stream: {}", e)))?;
+        let len = self.decompress.total_out() as usize;
+        if status != Status::StreamEnd {
+            return Err(invalid_data(if len == output.len() {
+                format!("Block decodes to more than {} bytes", output.len())
+            } else {
+                String::from("Truncated deflate stream")
+            }));
+        }
+
+        let trailer = deflated
+            .get(self.decompress.total_in() as usize..)
+            .and_then(|rest| rest.get(..GZIP_TRAILER_SIZE))
+            .ok_or_else(|| invalid_data(String::from("Missing gzip trailer")))?;
+        let expected_crc = u32::from_le_bytes(trailer[..4].try_into().unwrap_or_default());
+        let expected_len = u32::from_le_bytes(trailer[4..].try_into().unwrap_or_default());
+        let mut crc = Crc::new();
+        crc.update(&output[..len]);
+        if crc.sum() != expected_crc || len as u32 != expected_len {
+            return Err(invalid_data(String::from("Gzip checksum mismatch")));
+        }
+        Ok(len)
+    }
+}
+
+impl Default for BlockInflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the length of the gzip header `data` starts with, optional fields included.
+fn gzip_header_len(data: &[u8]) -> io::Result<usize> {
+    let truncated = || invalid_data(String::from("Truncated gzip header"));
+    if data.len() < GZIP_HEADER_SIZE || data[..2] != GZIP_MAGIC {
+        return Err(invalid_data(String::from("Not a gzip member")));
+    }
+    if data[2] != DEFLATE_METHOD {
+        return Err(invalid_data(format!(
+            "Unknown gzip compression method {}",
+            data[2]
+        )));
+    }
+
+    let flags = data[3];
+    let mut len = GZIP_HEADER_SIZE;
+    if flags & FLAG_EXTRA != 0 {
+        let extra = data.get(len..len + 2).ok_or_else(truncated)?;
+        len += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .get(len..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or_else(truncated)?;
+            len += end + 1;
+        }
+    }
+    if flags & FLAG_HCRC != 0 {
+        len += 2;
+    }
+    if len > data.len() {
+        return Err(truncated());
+    }
+    Ok(len)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression, GzBuilder};
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_inflate_blocks_into_fixed_buffer() {
+        let mut inflater = BlockInflater::new();
+        let mut output = [0u8; 64];
+        let first = b"first block, first block, first block";
+        let second = [7u8; 64];
+
+        let len = inflater.inflate(&gzip(first), &mut output).unwrap();
+        assert_eq!(&output[..len], first);
+        let len = inflater.inflate(&gzip(&second), &mut output).unwrap();
+        assert_eq!(&output[..len], second);
+        assert_eq!(inflater.inflate(&gzip(b""), &mut output).unwrap(), 0);
+
+        // Optional header fields
+        let mut encoder = GzBuilder::new()
+            .filename("block.bin")
+            .comment("comment")
+            .extra(vec![1, 2, 3])
+            .write(Vec::new(), Compression::fast());
+        encoder.write_all(first).unwrap();
+        let len = inflater
+            .inflate(&encoder.finish().unwrap(), &mut output)
+            .unwrap();
+        assert_eq!(&output[..len], first);
+    }
+
+    #[test]
+    fn test_inflate_rejects_invalid_blocks() {
+        let mut inflater = BlockInflater::new();
+        let mut output = [0u8; 32];
+
+        assert!(inflater.inflate(b"not gzip data", &mut output).is_err());
+        assert!(inflater.inflate(&gzip(&[7u8; 33]), &mut output).is_err());
+
+        let mut corrupted = gzip(b"block");
+        let crc = corrupted.len() - GZIP_TRAILER_SIZE;
+        corrupted[crc] ^= 0xFF;
+        assert!(inflater.inflate(&corrupted, &mut output).is_err());
+
+        let truncated = gzip(b"block");
+        assert!(inflater
+            .inflate(&truncated[..truncated.len() - 3], &mut output)
+            .is_err());
+        // Still usable after errors
+        assert_eq!(inflater.inflate(&gzip(b"ok"), &mut output).unwrap(), 2);
+    }
+}
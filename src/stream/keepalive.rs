@@ -0,0 +1,87 @@
+//! TCP keepalive on transfer connections.
+//!
+//! A peer that vanishes without closing its connections (power loss, NAT mapping expired, cable
+//! pulled) otherwise leaves reads blocked forever. With keepalive the kernel probes idle
+//! connections and fails pending reads once the peer stopped answering for
+//! [Keepalive::detection_time].
+
+use std::{io, net::TcpStream, time::Duration};
+
+use log::warn;
+use socket2::{SockRef, TcpKeepalive};
+
+/// Keepalive settings applied to every connection of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe is sent.
+    pub idle: Duration,
+    /// Time between two unanswered probes.
+    pub interval: Duration,
+    /// Number of unanswered probes after which the connection is dropped.
+    pub count: u32,
+}
+
+impl Keepalive {
+    /// Enables keepalive with these settings on `stream`.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let keepalive = TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(unix, windows))]
+        let keepalive = keepalive.with_interval(self.interval);
+        #[cfg(unix)]
+        let keepalive = keepalive.with_retries(self.count);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+
+    /// Returns the longest time a dead peer can go unnoticed on an idle connection.
+    pub fn detection_time(&self) -> Duration {
+        self.idle + self.interval * self.count
+    }
+}
+
+impl Default for Keepalive {
+    /// Detects dead peers within a minute.
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(10),
+            count: 3,
+        }
+    }
+}
+
+/// Applies `keepalive`, if any, to `stream`. The connection is still used if it fails.
+pub(crate) fn configure_keepalive(stream: &TcpStream, keepalive: Option<&Keepalive>) {
+    if let Some(keepalive) = keepalive
+        && let Err(e) = keepalive.apply(stream)
+    {
+        warn!("Failed to enable TCP keepalive: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_apply_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let keepalive = Keepalive {
+            idle: Duration::from_secs(20),
+            interval: Duration::from_secs(5),
+            count: 4,
+        };
+        keepalive.apply(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.tcp_keepalive_time().unwrap(), keepalive.idle);
+            assert_eq!(socket.tcp_keepalive_interval().unwrap(), keepalive.interval);
+            assert_eq!(socket.tcp_keepalive_retries().unwrap(), keepalive.count);
+        }
+        assert_eq!(keepalive.detection_time(), Duration::from_secs(40));
+    }
+}
@@ -1,7 +1,40 @@
+pub mod bottleneck;
+pub mod bundle;
+pub mod cancel;
+pub mod checksum;
+pub mod clock;
+pub mod compress;
+pub mod concurrency;
+pub mod control;
+pub mod damage;
 pub mod error;
+pub mod estimate;
+pub mod handle;
+pub mod heartbeat;
+pub mod inflate;
+pub mod keepalive;
+pub mod offer;
+pub mod options;
+pub mod panic;
+pub mod plan;
+pub mod preconnected;
+pub mod probe;
+pub mod profile;
 pub mod receive;
+pub mod registry;
+pub mod retry_after;
+pub mod scan;
+pub mod schedule;
 pub mod send;
+pub mod sessions;
+pub mod sink;
+pub mod socket;
+pub mod source;
+pub mod throttle;
+pub mod time_limit;
+pub mod udp;
 pub mod utils;
+pub mod writer;
 
 #[cfg(test)]
 mod receive_tests;
@@ -0,0 +1,70 @@
+//! Per-file decisions of a receiver embedded in another program.
+//!
+//! By default a receiver accepts every offered file and saves it under the output path given to
+//! [receive_file](crate::stream::receive::receive_file). An [OfferHandler] set in
+//! [ReceiveOptions::offer_handler](crate::stream::options::ReceiveOptions::offer_handler) can
+//! instead rename, redirect or reject each file once its handshake has been read.
+
+use std::{fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use crate::file::metadata::Metadata;
+
+/// A file offered by a sender, as described by its handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OfferInfo {
+    /// Name of the file on the sender side.
+    pub file_name: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// BLAKE3 hash of the file.
+    pub hash: [u8; 32],
+    /// Address the sender connected from.
+    pub sender_addr: SocketAddr,
+    /// Public key the sender authenticated with, `None` if it did not authenticate. Compare it
+    /// with [trusted peers](crate::peers::PeerRegistry::find_by_key) to only accept files from
+    /// known machines.
+    pub sender_key: Option<[u8; 32]>,
+    /// Key/value metadata the sender attached to the file (`send --meta`), empty if it
+    /// attached none.
+    pub metadata: Metadata,
+    /// Where the file is saved if it is accepted as-is. For
+    /// [receive_to_memory](crate::stream::receive::receive_to_memory), the file name.
+    pub default_path: PathBuf,
+}
+
+/// What to do with an offered file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Save the file at [OfferInfo::default_path].
+    Accept,
+    /// Save the file at another path. A directory receives the file under its original name.
+    /// Ignored by [receive_to_memory](crate::stream::receive::receive_to_memory).
+    AcceptAs(PathBuf),
+    /// Refuse the file, the reason is reported to the sender.
+    Reject(String),
+}
+
+/// Callback deciding the fate of each offered file.
+///
+/// The callback runs on the receiving thread before any block is downloaded, so the sender is
+/// kept waiting until it returns.
+#[derive(Clone)]
+pub struct OfferHandler(Arc<dyn Fn(&OfferInfo) -> Decision + Send + Sync>);
+
+impl OfferHandler {
+    /// Wraps `decide` into a handler.
+    pub fn new(decide: impl Fn(&OfferInfo) -> Decision + Send + Sync + 'static) -> Self {
+        Self(Arc::new(decide))
+    }
+
+    /// Returns the decision for `offer`.
+    pub fn decide(&self, offer: &OfferInfo) -> Decision {
+        (self.0)(offer)
+    }
+}
+
+impl Debug for OfferHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OfferHandler(..)")
+    }
+}
@@ -0,0 +1,463 @@
+//! Options controlling the behaviour of a send or receive session.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    capabilities::{Capabilities, StrictPolicy},
+    file::{
+        content_type::TypePolicy,
+        duplicate::DuplicateMode,
+        metadata::{Metadata, MetadataStore},
+        store::DEFAULT_MAX_STORE_SIZE,
+        utils::HashStrategy,
+    },
+    history::default_history_path,
+    identity::default_identity_path,
+    peers::default_peers_path,
+    secrets::SecretString,
+    stream::{
+        bundle::FileOrder, estimate::DEFAULT_ENTROPY_THRESHOLD, heartbeat::Heartbeat,
+        keepalive::Keepalive, offer::OfferHandler, profile::ReceiveProfile, scan::ScanHook,
+        schedule::RateSchedule, socket::SocketTuning, writer::DEFAULT_WRITE_TIMEOUT,
+    },
+    threads::WorkerOptions,
+    tls::TlsConfig,
+    transport::MAX_BLOCK_SIZE,
+};
+
+/// Default size of a file block (1 MB).
+pub const DEFAULT_BLOCK_SIZE: u32 = 1024 * 1024;
+
+/// Smallest block size [SendOptions::with_block_size] accepts. Blocks are at most
+/// [MAX_BLOCK_SIZE].
+pub const MIN_BLOCK_SIZE: u32 = 1;
+
+/// Default number of times a failed block read is retried, see [SendOptions::read_retries].
+pub const DEFAULT_READ_RETRIES: u32 = 3;
+
+/// Most retries of a failed block read [SendOptions::with_read_retries] accepts. The delay
+/// between two reads doubles from 100 ms, so the last retry waits close to two minutes.
+pub const MAX_READ_RETRIES: u32 = 10;
+
+/// Default number of block requests a data connection keeps outstanding, see
+/// [ReceiveOptions::pipeline_depth].
+pub const DEFAULT_PIPELINE_DEPTH: u32 = 8;
+
+/// Most outstanding block requests [ReceiveOptions::with_pipeline_depth] accepts.
+pub const MAX_PIPELINE_DEPTH: u32 = 256;
+
+/// Default number of connections the listeners queue before they are accepted.
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 128;
+
+/// Default time a peer has to send its first message once its connection is accepted.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default skew between the peers' clocks beyond which the receiver warns, see
+/// [ReceiveOptions::max_clock_skew].
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5);
+
+/// Default assumed speed of the link to the receiver (1 Gbit/s), in bytes per second.
+pub const DEFAULT_LINK_SPEED: u64 = 125_000_000;
+
+/// Options for sending a file.
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    /// Size of each data block in bytes.
+    pub block_size: u32,
+    /// Whether blocks may be gzip-compressed before being sent.
+    pub should_compress: bool,
+    /// Entropy in bits per byte above which a block is sent without trying to compress it, see
+    /// [sampled_entropy](crate::stream::estimate::sampled_entropy). 8 tries every block.
+    pub compress_entropy_threshold: f64,
+    /// Maximum number of concurrent data connections to serve.
+    pub concurrency: u16,
+    /// Whether to hold a shared advisory lock on the source file during the transfer.
+    pub lock: bool,
+    /// Tune I/O for a source that lives on a network filesystem (NFS/SMB): hash the file with
+    /// large sequential reads instead of parallel positioned reads.
+    pub network_fs: bool,
+    /// Order the files of a directory are transferred in, see [crate::stream::bundle]. Unused
+    /// when sending a file.
+    pub file_order: FileOrder,
+    /// Sizing and CPU pinning of the hashing and compression workers.
+    pub workers: WorkerOptions,
+    /// Refuse to send unless the transfer is encrypted, authenticated and uses a recent enough
+    /// protocol version.
+    pub strict: Option<StrictPolicy>,
+    /// Credentials the handshake and data connections are secured with, see [crate::tls].
+    /// `None` sends in plaintext. Ignored by [send_over](crate::stream::send::send_over), whose
+    /// connection is secured by the caller.
+    pub tls: Option<Arc<TlsConfig>>,
+    /// Whether the handshake and data connections are encrypted with a Noise channel set up in
+    /// the handshake, see [crate::noise]. Receivers lacking [Capabilities::NOISE] can't be sent
+    /// to. Ignored by [send_over](crate::stream::send::send_over).
+    pub noise: bool,
+    /// One-time code printed by the receiver (`receive --pair`) to pair with before the
+    /// handshake, see [crate::pairing]. Implies a Noise channel keyed by the pairing. `None`
+    /// sends without pairing. Ignored by [send_over](crate::stream::send::send_over).
+    pub pairing_code: Option<SecretString>,
+    /// Key/value metadata sent along with the file (`send --meta`), see
+    /// [crate::file::metadata]. Receivers lacking [Capabilities::METADATA] don't get it.
+    pub metadata: Metadata,
+    /// Repair datagrams sent on top of each block, in percent, to receivers asking for blocks as
+    /// UDP datagrams, see [crate::stream::udp]. The least sent, more are while blocks fail to be
+    /// rebuilt. `None` keeps blocks on the data connections.
+    /// Ignored with [Self::tls], [Self::noise] or [Self::pairing_code], and by
+    /// [send_over](crate::stream::send::send_over).
+    pub udp_overhead: Option<u32>,
+    /// History file completed transfers and their receipts are appended to. `None` disables
+    /// the history.
+    pub history_path: Option<PathBuf>,
+    /// Identity key the sender authenticates to the receiver with. `None` sends the file
+    /// unauthenticated.
+    pub identity_path: Option<PathBuf>,
+    /// Trusted peers file, used to check that the receipt is signed by the expected receiver.
+    /// `None` trusts any receipt with a valid signature.
+    pub peers_path: Option<PathBuf>,
+    /// Further addresses of the receiver, tried in order when the one passed to
+    /// [send_file](crate::stream::send::send_file) can't be reached.
+    pub alternate_hosts: Vec<String>,
+    /// TCP keepalive enabled on every connection, so a vanished receiver is detected even when
+    /// no heartbeat is negotiated. `None` leaves keepalive to the system default.
+    pub keepalive: Option<Keepalive>,
+    /// Time a receiver may go without accepting any data before its connection is dropped.
+    pub write_timeout: Duration,
+    /// Number of data connections the transfer listener queues before they are accepted.
+    pub listen_backlog: u32,
+    /// Time a data connection has to send its first request before it is dropped, so
+    /// connections that never send anything don't hold a connection slot.
+    pub handshake_timeout: Duration,
+    /// Time after which a transfer still running is aborted with
+    /// [SendFileError::TimeLimitExceeded](crate::stream::error::SendFileError::TimeLimitExceeded),
+    /// hashing the file included. `None` lets it run until it completes.
+    pub max_duration: Option<Duration>,
+    /// Number of times a failed block read is retried before the receiver is told the block is
+    /// unreadable.
+    pub read_retries: u32,
+    /// Open every connection to the receiver's handshake port instead of listening for its data
+    /// connections, reusing the handshake connection as the first data connection, for senders
+    /// behind a firewall or NAT. The receiver must use [ReceiveOptions::single_port] too.
+    /// Ignored with [Self::udp_overhead].
+    pub single_port: bool,
+    /// Assumed speed of the link to the receiver in bytes per second, used to estimate the
+    /// transfer time and whether compressing blocks makes the transfer faster.
+    pub link_speed: u64,
+    /// Socket options of the data connections, see [crate::stream::socket].
+    pub socket: SocketTuning,
+    /// Heartbeats on idle connections, see [crate::stream::heartbeat]. `None` doesn't offer
+    /// them, so neither peer pings.
+    pub heartbeat: Option<Heartbeat>,
+    /// Rate limits the transfer is held to by time of day, told to receivers with a control
+    /// channel, see [crate::stream::schedule]. `None` sends as fast as the link allows.
+    pub rate_schedule: Option<RateSchedule>,
+}
+
+impl SendOptions {
+    /// Sets [Self::block_size], clamped to [MIN_BLOCK_SIZE]..=[MAX_BLOCK_SIZE].
+    pub fn with_block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+        self
+    }
+
+    /// Sets [Self::concurrency], at least one connection.
+    pub fn with_concurrency(mut self, concurrency: u16) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets [Self::read_retries], at most [MAX_READ_RETRIES].
+    pub fn with_read_retries(mut self, read_retries: u32) -> Self {
+        self.read_retries = read_retries.min(MAX_READ_RETRIES);
+        self
+    }
+
+    /// Returns the [HashStrategy] suited to where the source file lives.
+    pub fn hash_strategy(&self) -> HashStrategy {
+        hash_strategy_for(self.network_fs)
+    }
+
+    /// Returns the capabilities this sender offers with its options: [Capabilities::ENCRYPTION]
+    /// only with [Self::tls], [Capabilities::NOISE] only with [Self::noise] or
+    /// [Self::pairing_code], [Capabilities::METADATA] only with [Self::metadata],
+    /// [Capabilities::UDP_FEC] only with [Self::udp_overhead] over unencrypted connections,
+    /// [Capabilities::HEARTBEAT] only with [Self::heartbeat].
+    pub fn local_capabilities(&self) -> Capabilities {
+        let mut local = Capabilities::local_with_encryption(self.tls.is_some());
+        let encrypted = self.tls.is_some() || self.noise || self.pairing_code.is_some();
+        if !self.noise && self.pairing_code.is_none() {
+            local = local.without(Capabilities::NOISE);
+        }
+        if self.udp_overhead.is_none() || encrypted {
+            local = local.without(Capabilities::UDP_FEC);
+        }
+        if self.metadata.is_empty() {
+            local = local.without(Capabilities::METADATA);
+        }
+        if self.heartbeat.is_none() {
+            local = local.without(Capabilities::HEARTBEAT);
+        }
+        // The handshake connection of a single-port transfer carries blocks
+        if self.single_port {
+            local = local.without(Capabilities::CONTROL_CHANNEL);
+        }
+        local
+    }
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            should_compress: true,
+            compress_entropy_threshold: DEFAULT_ENTROPY_THRESHOLD,
+            concurrency: 1,
+            lock: true,
+            network_fs: false,
+            file_order: FileOrder::Name,
+            workers: WorkerOptions::default(),
+            strict: None,
+            tls: None,
+            noise: false,
+            pairing_code: None,
+            metadata: Metadata::new(),
+            udp_overhead: None,
+            history_path: default_history_path(),
+            identity_path: default_identity_path(),
+            peers_path: default_peers_path(),
+            alternate_hosts: Vec::new(),
+            keepalive: Some(Keepalive::default()),
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_duration: None,
+            read_retries: DEFAULT_READ_RETRIES,
+            single_port: false,
+            link_speed: DEFAULT_LINK_SPEED,
+            socket: SocketTuning::default(),
+            heartbeat: Some(Heartbeat::default()),
+            rate_schedule: None,
+        }
+    }
+}
+
+/// Options for receiving a file.
+#[derive(Debug, Clone)]
+pub struct ReceiveOptions {
+    /// Maximum number of concurrent data connections to open to the sender.
+    pub concurrency: u16,
+    /// Number of block requests each data connection sends ahead of the answers it reads, so
+    /// a long round trip is paid once per window rather than once per block. The sender answers
+    /// them in order. 1 waits for each block before requesting the next one.
+    pub pipeline_depth: u32,
+    /// Whether to hold an exclusive advisory lock on the output file during the transfer.
+    pub lock: bool,
+    /// Tune I/O for a destination on a network filesystem (NFS/SMB): block writes are serialized
+    /// instead of being issued concurrently from every connection.
+    pub network_fs: bool,
+    /// Whether to pre-allocate the output file to its final size before any block is written.
+    pub preallocate: bool,
+    /// Write each block out to disk and drop it from the page cache, so receiving a huge file
+    /// doesn't evict everything else cached on the machine. Costs throughput, since every write
+    /// waits for the disk.
+    pub drop_cache: bool,
+    /// Bytes per second written to the output file at most, leaving disk bandwidth to other
+    /// workloads sharing the destination disk. Independent of the network, blocks are received
+    /// as fast as they are written. `None` writes blocks as fast as they arrive.
+    pub disk_limit_rate: Option<u64>,
+    /// Write the files of a directory next to the output directory, and only move them into it
+    /// once every file is verified, so a consumer never sees part of the set. A failed transfer
+    /// removes them and leaves the output directory as it was. Unused when receiving a file.
+    pub atomic_batch: bool,
+    /// Whether an output path naming a block device is written over, see
+    /// [crate::file::device]. Otherwise receiving to a device fails, so a mistyped path can't
+    /// wipe a disk.
+    pub allow_device: bool,
+    /// Sizing and CPU pinning of the hashing and decompression workers.
+    pub workers: WorkerOptions,
+    /// Refuse to receive unless the transfer is encrypted, authenticated and uses a recent
+    /// enough protocol version.
+    pub strict: Option<StrictPolicy>,
+    /// Credentials the handshake and data connections are secured with, see [crate::tls].
+    /// `None` receives in plaintext. Ignored by
+    /// [receive_over](crate::stream::receive::receive_over).
+    pub tls: Option<Arc<TlsConfig>>,
+    /// One-time code senders must pair with before their handshake is read, see
+    /// [crate::pairing]. A failed pairing fails the receive, so the code can't be guessed.
+    /// `None` accepts senders without pairing. Ignored by
+    /// [receive_over](crate::stream::receive::receive_over).
+    pub pairing_code: Option<SecretString>,
+    /// Where the metadata senders attach to their file is stored next to it once the file is
+    /// accepted, see [crate::file::metadata]. `None` only logs it and hands it to the
+    /// [ReceiveOptions::offer_handler] and [ReceiveOptions::scan].
+    pub metadata_store: Option<MetadataStore>,
+    /// Identity key used to sign delivery receipts. `None` disables receipts.
+    pub identity_path: Option<PathBuf>,
+    /// Content-addressed block store to reuse blocks from previously received files. `None`
+    /// downloads every block.
+    pub block_store: Option<PathBuf>,
+    /// Size cap of the block store, least recently used blocks are evicted as blocks are
+    /// added and after each transfer.
+    pub block_store_max_size: u64,
+    /// Record the file hash and receive time in the output file's extended attributes, for later
+    /// verification with `sendfile check`.
+    pub xattrs: bool,
+    /// Index every received file is recorded in, see [crate::history::ReceivedEntry], and
+    /// [Self::reuse_duplicates] looks offered files up in. `None` records nothing.
+    pub received_index: Option<PathBuf>,
+    /// Place an identical file received before under the output path at the destination, with
+    /// a hard link or a copy, and report the transfer complete without downloading it, see
+    /// [crate::file::duplicate]. `None` downloads every offered file.
+    pub reuse_duplicates: Option<DuplicateMode>,
+    /// Decides whether each offered file is accepted and where it is saved. `None` accepts every
+    /// file at the output path.
+    pub offer_handler: Option<OfferHandler>,
+    /// Types of files accepted or rejected, checked before [ReceiveOptions::offer_handler]. The
+    /// default accepts every file.
+    pub type_policy: TypePolicy,
+    /// Trusted peers file, used to name authenticated senders. `None` only checks that the
+    /// sender's signature is valid.
+    pub peers_path: Option<PathBuf>,
+    /// Refuse senders that don't authenticate as a peer of [Self::peers_path], paired with
+    /// `sendfile pair` (see [crate::pairing]) or imported with `sendfile peer import`. Refused
+    /// senders are told why, and the next one is awaited. Senders pairing with
+    /// [Self::pairing_code] are accepted, as the code vouches for them.
+    pub paired_only: bool,
+    /// Time budget to recover from losing every connection (e.g. a Wi-Fi roam) by reconnecting
+    /// to the sender, or accepting a new handshake for the same file, and resuming from the
+    /// blocks already received. `None` gives up once every connection is lost.
+    pub auto_retry: Option<Duration>,
+    /// Store the sessions in progress are described in, so a receiver restarted while a file is
+    /// partly received resumes it at the path it was written to when the same sender offers it
+    /// again, see [crate::stream::sessions]. `None` records nothing.
+    pub sessions: Option<PathBuf>,
+    /// Accept the sender's data connections on the handshake port instead of connecting to it,
+    /// receiving the first range of blocks over the handshake connection, for senders behind a
+    /// firewall or NAT. The sender must use [SendOptions::single_port] too. [Self::auto_retry]
+    /// does not apply.
+    pub single_port: bool,
+    /// Zero-fill blocks the sender reports unreadable, record them in a
+    /// [DamageReport](crate::stream::damage::DamageReport) next to the file, and fail with
+    /// [IncompleteFile](crate::stream::error::SendFileError::IncompleteFile) once every other
+    /// block is received. Otherwise the transfer is aborted on the first unreadable block.
+    pub best_effort: bool,
+    /// TCP keepalive enabled on every connection, so a vanished sender is detected even when no
+    /// heartbeat is negotiated. `None` leaves keepalive to the system default.
+    pub keepalive: Option<Keepalive>,
+    /// Command every received file is scanned with before it is accepted, files failing it are
+    /// quarantined. `None` accepts files once their hash is verified.
+    pub scan: Option<ScanHook>,
+    /// Number of senders the listener queues before they are accepted.
+    pub listen_backlog: u32,
+    /// Time a sender has to complete its handshake once its connection is accepted. Senders that
+    /// don't are dropped and the next one is accepted.
+    pub handshake_timeout: Duration,
+    /// Time after which a receive still running, waiting for the sender included, is aborted
+    /// with [SendFileError::TimeLimitExceeded](crate::stream::error::SendFileError::TimeLimitExceeded).
+    /// The blocks received so far are kept to resume from. `None` lets it run until it completes.
+    pub max_duration: Option<Duration>,
+    /// Preset of the resources the receive uses, see [crate::stream::profile]. Limits set by
+    /// [ReceiveProfile::LowMemory] override [Self::concurrency] and [Self::block_store].
+    pub profile: ReceiveProfile,
+    /// Socket options of the data connections, see [crate::stream::socket].
+    pub socket: SocketTuning,
+    /// Heartbeats on idle connections, see [crate::stream::heartbeat]. `None` doesn't accept
+    /// them, so neither peer pings.
+    pub heartbeat: Option<Heartbeat>,
+    /// Skew between the sender's clock and this side's beyond which a warning is logged, see
+    /// [crate::stream::clock].
+    pub max_clock_skew: Duration,
+}
+
+impl ReceiveOptions {
+    /// Sets [Self::concurrency], at least one connection.
+    pub fn with_concurrency(mut self, concurrency: u16) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets [Self::pipeline_depth], between 1 and [MAX_PIPELINE_DEPTH] requests.
+    pub fn with_pipeline_depth(mut self, pipeline_depth: u32) -> Self {
+        self.pipeline_depth = pipeline_depth.clamp(1, MAX_PIPELINE_DEPTH);
+        self
+    }
+
+    /// Returns the [HashStrategy] suited to where the output file lives.
+    pub fn hash_strategy(&self) -> HashStrategy {
+        hash_strategy_for(self.network_fs)
+    }
+}
+
+impl Default for ReceiveOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 1,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            lock: true,
+            network_fs: false,
+            preallocate: true,
+            drop_cache: false,
+            disk_limit_rate: None,
+            atomic_batch: false,
+            allow_device: false,
+            workers: WorkerOptions::default(),
+            strict: None,
+            tls: None,
+            pairing_code: None,
+            metadata_store: None,
+            identity_path: default_identity_path(),
+            block_store: None,
+            block_store_max_size: DEFAULT_MAX_STORE_SIZE,
+            xattrs: false,
+            received_index: None,
+            reuse_duplicates: None,
+            offer_handler: None,
+            type_policy: TypePolicy::default(),
+            peers_path: default_peers_path(),
+            paired_only: false,
+            auto_retry: None,
+            sessions: None,
+            single_port: false,
+            best_effort: false,
+            keepalive: Some(Keepalive::default()),
+            scan: None,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_duration: None,
+            profile: ReceiveProfile::Standard,
+            socket: SocketTuning::default(),
+            heartbeat: Some(Heartbeat::default()),
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+        }
+    }
+}
+
+fn hash_strategy_for(network_fs: bool) -> HashStrategy {
+    if network_fs {
+        HashStrategy::Sequential
+    } else {
+        HashStrategy::Parallel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setters_clamp_to_limits() {
+        let options = SendOptions::default()
+            .with_block_size(0)
+            .with_concurrency(0)
+            .with_read_retries(u32::MAX);
+        assert_eq!(options.block_size, MIN_BLOCK_SIZE);
+        assert_eq!(options.concurrency, 1);
+        assert_eq!(options.read_retries, MAX_READ_RETRIES);
+        let options = options.with_block_size(u32::MAX);
+        assert_eq!(options.block_size, MAX_BLOCK_SIZE);
+        assert_eq!(ReceiveOptions::default().with_concurrency(0).concurrency, 1);
+        let options = ReceiveOptions::default().with_pipeline_depth(0);
+        assert_eq!(options.pipeline_depth, 1);
+        let options = options.with_pipeline_depth(u32::MAX);
+        assert_eq!(options.pipeline_depth, MAX_PIPELINE_DEPTH);
+    }
+}
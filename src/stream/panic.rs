@@ -0,0 +1,121 @@
+//! Containment of panics in the threads of a transfer.
+//!
+//! A bug such as an index out of bounds on a bad block number panics the thread it happens on.
+//! Connection threads and the threads of [TransferHandle]s run their work through
+//! [contain_panic], which turns a panic into [SendFileError::WorkerPanicked], logs it with its
+//! backtrace, and cancels the transfer so its other threads stop and the peer is told, see
+//! [crate::stream::cancel]. The transfer fails with that error like with any other, its
+//! registration is marked failed, and other transfers of the process keep running.
+//!
+//! [TransferHandle]: crate::stream::handle::TransferHandle
+
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
+    panic::{self, AssertUnwindSafe},
+    sync::Once,
+    thread,
+};
+
+use log::error;
+
+use crate::stream::{error::SendFileError, handle::TransferControl};
+
+/// Installs [report_panic] as the panic hook once.
+static HOOK: Once = Once::new();
+
+thread_local! {
+    /// Number of [contain_panic] calls running on this thread.
+    static CONTAINED: Cell<usize> = const { Cell::new(0) };
+    /// Where the last contained panic of this thread happened, and its backtrace.
+    static LAST_PANIC: RefCell<Option<(String, Backtrace)>> = const { RefCell::new(None) };
+}
+
+/// Runs `work`, turning a panic into [SendFileError::WorkerPanicked] and cancelling `control`.
+///
+/// # Returns
+///
+/// What `work` returned, or [SendFileError::WorkerPanicked] if it panicked.
+pub(crate) fn contain_panic<T>(
+    control: &TransferControl,
+    work: impl FnOnce() -> Result<T, SendFileError>,
+) -> Result<T, SendFileError> {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CONTAINED.get() == 0 {
+                return previous(info);
+            }
+            let location = info
+                .location()
+                .map_or_else(|| String::from("an unknown location"), |l| l.to_string());
+            LAST_PANIC.set(Some((location, Backtrace::force_capture())));
+        }));
+    });
+
+    CONTAINED.set(CONTAINED.get() + 1);
+    let result = panic::catch_unwind(AssertUnwindSafe(work));
+    CONTAINED.set(CONTAINED.get() - 1);
+    let payload = match result {
+        Ok(result) => return result,
+        Err(payload) => payload,
+    };
+
+    let worker = thread::current().name().unwrap_or("unnamed").to_string();
+    let message = panic_message(payload.as_ref());
+    match LAST_PANIC.take() {
+        Some((location, backtrace)) => error!(
+            "Thread {} panicked at {}: {}\n{}",
+            worker, location, message, backtrace
+        ),
+        None => error!("Thread {} panicked: {}", worker, message),
+    }
+    control.fail_on_panic(worker.clone(), message.clone());
+    Err(SendFileError::WorkerPanicked { worker, message })
+}
+
+/// Returns the message a panic was raised with.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => message.to_string(),
+        (None, Some(message)) => message.clone(),
+        (None, None) => String::from("unknown panic"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panics_fail_the_transfer() {
+        let control = TransferControl::new();
+        let received_blocks = [false; 4];
+        let result = thread::scope(|scope| {
+            thread::Builder::new()
+                .name(String::from("recv-3"))
+                .spawn_scoped(scope, || {
+                    contain_panic(&control, || Ok(received_blocks[std::hint::black_box(6)]))
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        });
+
+        assert!(matches!(
+            &result,
+            Err(SendFileError::WorkerPanicked { worker, message })
+                if worker == "recv-3" && message.starts_with("index out of bounds")
+        ));
+        assert!(control.is_cancelled());
+        assert!(matches!(
+            control.cancellation(),
+            SendFileError::WorkerPanicked { .. }
+        ));
+        assert_eq!(contain_panic(&control, || Ok(7)).unwrap(), 7);
+    }
+}
@@ -0,0 +1,266 @@
+//! Reports of dry runs, describing what a transfer would do without moving any data.
+//!
+//! `send --dry-run` hashes the file and offers it to the receiver, which answers with its
+//! decision and the conflicts it found, then both sides stop before any data connection is
+//! opened. `receive --dry-run` does the same from the receiving end, rejecting the offer once it
+//! is described. Compression is estimated on blocks at fixed offsets, see
+//! [CompressionEstimate], so a dry run of an unchanged file reports the same on-wire size.
+
+use std::{
+    fmt::{self, Display},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    capabilities::FeatureSet,
+    history::to_hex,
+    stream::estimate::CompressionEstimate,
+    units::{Count, Elapsed, Size},
+};
+
+/// Separates the conflicts a receiver lists in the reason of an accepted dry-run offer.
+pub const CONFLICT_SEPARATOR: &str = "; ";
+
+/// Reason a dry-running receiver gives the sender for rejecting its offer.
+pub const RECEIVER_DRY_RUN_REASON: &str = "Receiver is doing a dry run";
+
+/// Answer of the receiver to a dry-run offer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReceiverAnswer {
+    /// The file would be accepted, despite the listed conflicts.
+    Accepted(Vec<String>),
+    /// The file would be rejected for the given reason.
+    Rejected(String),
+    /// The receiver did not answer, it predates offer responses.
+    Unknown,
+}
+
+/// What `send --dry-run` found out about a transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendPlan {
+    /// Name of the file announced to the receiver.
+    pub file_name: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Size of a block in bytes.
+    pub block_size: u32,
+    /// Number of data connections offered to the receiver.
+    pub concurrency: u16,
+    /// Compression of sampled blocks, `None` if compression is disabled.
+    pub compression: Option<CompressionEstimate>,
+    /// Assumed speed of the link to the receiver in bytes per second.
+    pub link_speed: u64,
+    /// Problems found on the sender side.
+    pub conflicts: Vec<String>,
+    /// What the receiver made of the offer.
+    pub receiver: ReceiverAnswer,
+}
+
+/// What `receive --dry-run` found out about an offered file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivePlan {
+    /// Name of the file on the sender side.
+    pub file_name: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Size of a block in bytes.
+    pub block_size: u32,
+    /// Number of data connections, capped by both sides and the block count.
+    pub concurrency: u16,
+    /// Address of the sender.
+    pub sender_addr: SocketAddr,
+    /// Verified public key of the sender, `None` if it did not authenticate.
+    pub sender_key: Option<[u8; 32]>,
+    /// Features negotiated with the sender.
+    pub features: FeatureSet,
+    /// Where the file would be written.
+    pub destination: PathBuf,
+    /// Reason the offer handler would reject the file, `None` if it would be accepted.
+    pub rejection: Option<String>,
+    /// Problems found at the destination.
+    pub conflicts: Vec<String>,
+}
+
+impl ReceivePlan {
+    /// Returns whether the offer of a dry-running sender is accepted, with the reason if not or
+    /// the conflicts, separated by [CONFLICT_SEPARATOR], if it is.
+    pub(crate) fn answer(&self) -> (bool, String) {
+        match &self.rejection {
+            Some(reason) => (false, reason.clone()),
+            None => (true, self.conflicts.join(CONFLICT_SEPARATOR)),
+        }
+    }
+}
+
+impl SendPlan {
+    /// Returns whether blocks would be compressed.
+    pub fn compress(&self) -> bool {
+        self.compression.is_some_and(|estimate| {
+            estimate.is_worthwhile(self.size, self.link_speed, self.concurrency)
+        })
+    }
+
+    /// Returns the estimated number of bytes sent.
+    pub fn wire_bytes(&self) -> u64 {
+        match self.compression {
+            Some(estimate) if self.compress() => estimate.wire_bytes(self.size),
+            _ => self.size,
+        }
+    }
+
+    /// Returns the estimated duration of the transfer.
+    pub fn estimated_duration(&self) -> Duration {
+        let estimate = self.compression.unwrap_or(CompressionEstimate {
+            sampled_bytes: 0,
+            compressed_bytes: 0,
+            compress_time: Duration::ZERO,
+        });
+        estimate.duration(
+            self.size,
+            self.link_speed,
+            self.concurrency,
+            self.compress(),
+        )
+    }
+}
+
+impl Display for SendPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Dry run, nothing was sent")?;
+        writeln!(
+            f,
+            "File:        {} ({}, {} bytes)",
+            self.file_name,
+            Size(self.size),
+            Count(self.size)
+        )?;
+        writeln!(f, "BLAKE3:      {}", to_hex(&self.file_hash))?;
+        writeln!(
+            f,
+            "Blocks:      {} of {} over up to {} connections",
+            Count(self.size.div_ceil(self.block_size as u64)),
+            Size(self.block_size as u64),
+            self.concurrency
+        )?;
+        match self.compression {
+            Some(estimate) if self.compress() => writeln!(
+                f,
+                "On the wire: ~{} compressed ({:.0}% of the file)",
+                Size(self.wire_bytes()),
+                estimate.ratio() * 100.0
+            )?,
+            Some(estimate) => writeln!(
+                f,
+                "On the wire: {}, compressing to ~{:.0}% would not pay off",
+                Size(self.wire_bytes()),
+                estimate.ratio() * 100.0
+            )?,
+            None => writeln!(
+                f,
+                "On the wire: {}, compression disabled",
+                Size(self.wire_bytes())
+            )?,
+        }
+        writeln!(
+            f,
+            "ETA:         ~{} at {} Mbit/s",
+            Elapsed(self.estimated_duration()),
+            self.link_speed * 8 / 1_000_000
+        )?;
+
+        let mut conflicts = self.conflicts.clone();
+        match &self.receiver {
+            ReceiverAnswer::Accepted(notes) => {
+                writeln!(f, "Receiver:    would accept the file")?;
+                conflicts.extend(notes.iter().map(|note| format!("receiver: {note}")));
+            }
+            ReceiverAnswer::Rejected(reason) => {
+                writeln!(f, "Receiver:    would reject the file: {}", reason)?
+            }
+            ReceiverAnswer::Unknown => writeln!(f, "Receiver:    did not answer the offer")?,
+        }
+        write_conflicts(f, &conflicts)
+    }
+}
+
+impl Display for ReceivePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Dry run, nothing was received")?;
+        writeln!(
+            f,
+            "File:        {} ({}, {} bytes)",
+            self.file_name,
+            Size(self.size),
+            Count(self.size)
+        )?;
+        writeln!(f, "BLAKE3:      {}", to_hex(&self.file_hash))?;
+        writeln!(
+            f,
+            "Blocks:      {} of {} over {} connections",
+            Count(self.size.div_ceil(self.block_size as u64)),
+            Size(self.block_size as u64),
+            self.concurrency
+        )?;
+        match &self.sender_key {
+            Some(key) => writeln!(f, "Sender:      {} (key {})", self.sender_addr, to_hex(key))?,
+            None => writeln!(f, "Sender:      {} (unauthenticated)", self.sender_addr)?,
+        }
+        writeln!(f, "Features:    {}", self.features)?;
+        match &self.rejection {
+            Some(reason) => writeln!(f, "Destination: rejected: {}", reason)?,
+            None => writeln!(f, "Destination: {}", self.destination.display())?,
+        }
+        write_conflicts(f, &self.conflicts)
+    }
+}
+
+fn write_conflicts(f: &mut fmt::Formatter<'_>, conflicts: &[String]) -> fmt::Result {
+    if conflicts.is_empty() {
+        return write!(f, "Conflicts:   none");
+    }
+    write!(f, "Conflicts:")?;
+    for conflict in conflicts {
+        write!(f, "\n  - {}", conflict)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_plan_report() {
+        let mut plan = SendPlan {
+            file_name: String::from("data.bin"),
+            size: 8_000_000,
+            file_hash: [0; 32],
+            block_size: 1_000_000,
+            concurrency: 4,
+            compression: Some(CompressionEstimate {
+                sampled_bytes: 1_000_000,
+                compressed_bytes: 250_000,
+                compress_time: Duration::from_millis(10),
+            }),
+            link_speed: 1_000_000,
+            conflicts: Vec::new(),
+            receiver: ReceiverAnswer::Accepted(vec![String::from("/tmp/data.bin exists")]),
+        };
+        let report = plan.to_string();
+        assert!(report.contains("File:        data.bin (7.6 MiB, 8,000,000 bytes)"));
+        assert!(report.contains("Blocks:      8 of 976.6 KiB over up to 4 connections"));
+        assert!(report.contains("On the wire: ~1.9 MiB compressed (25% of the file)"));
+        assert!(report.contains("ETA:         ~2.0s at 8 Mbit/s"));
+        assert!(report.ends_with("Conflicts:\n  - receiver: /tmp/data.bin exists"));
+
+        plan.compression = None;
+        assert_eq!(plan.wire_bytes(), 8_000_000);
+        assert_eq!(plan.estimated_duration(), Duration::from_secs(8));
+    }
+}
@@ -0,0 +1,119 @@
+//! Transfers over a connection established by the caller.
+//!
+//! [send_over](crate::stream::send::send_over) and
+//! [receive_over](crate::stream::receive::receive_over) run the whole protocol over a connection
+//! the caller already has (a TLS tunnel, a multiplexed stream, an SSH channel) instead of the
+//! handshake and data connections the peers open themselves. The handshake, the answer to the
+//! offer, every block and the receipt go over it in turn: the transfer uses a single data
+//! connection and neither side listens.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use crate::transport::SESSION_ID_SIZE;
+
+/// Peer recorded for transfers over a [Preconnected] connection, whose address is unknown.
+pub(crate) const PRECONNECTED_PEER: &str = "pre-connected";
+
+/// Connection a peer exchanges messages over.
+pub(crate) trait Connection: Read + Write {
+    /// Bounds how long reads block, `None` blocks until data arrives.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Starts pinging the peer while reads wait, framing the pings with `protocol_version` and
+    /// tagging them with `session_id`, once the peer is known to answer them. Only
+    /// [Heartbeating](crate::stream::heartbeat::Heartbeating) connections ping, others ignore it.
+    fn enable_heartbeat(
+        &mut self,
+        _protocol_version: u8,
+        _session_id: Option<[u8; SESSION_ID_SIZE]>,
+    ) {
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Connection established by the caller, whose timeouts are left to the caller.
+pub(crate) struct Preconnected<T>(pub(crate) T);
+
+impl<T: Read> Read for Preconnected<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buffer)
+    }
+}
+
+impl<T: Write> Write for Preconnected<T> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.0.write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<T: Read + Write> Connection for Preconnected<T> {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::TcpListener, thread};
+
+    use crate::stream::{
+        options::{ReceiveOptions, SendOptions},
+        receive::receive_over,
+        send::send_over,
+    };
+
+    #[test]
+    fn test_transfer_over_caller_connection() {
+        let dir =
+            std::env::temp_dir().join(format!("sendfile_preconnected_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        let send_options = SendOptions {
+            block_size: 64 * 1024,
+            concurrency: 4,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            concurrency: 4,
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+
+        // Any connection works, the peers only see a stream of bytes
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            let receiver = scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                receive_over(stream, &output, &receive_options)
+            });
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            send_over(stream, &source, &send_options).unwrap();
+            receiver.join().unwrap().unwrap();
+        });
+
+        assert_eq!(fs::read(&output).unwrap(), data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,444 @@
+//! Measuring the path to a receiver, to pick transfer options suited to it.
+//!
+//! `sendfile probe` connects to the receiver's handshake port like a sender, advertising
+//! [Capabilities::PROBE] instead of offering a file. Once the receiver is ready, timed [ProbeV1]
+//! messages are exchanged on the handshake connection: empty echoed probes measure the round trip
+//! time, then filler payloads are streamed for a fixed duration to measure the throughput. The
+//! path MTU is read from the kernel's view of the connection where available.
+//!
+//! Throughput is measured over a single connection, so on links where one TCP window can't fill
+//! the pipe it is a lower bound and the recommendations err on the side of more connections.
+
+use std::{
+    fmt::{self, Display},
+    io::{Cursor, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+
+use crate::{
+    capabilities::Capabilities,
+    connection::{read_next_payload, StreamReadError},
+    file::FileMetadata,
+    stream::{
+        concurrency::DEFAULT_MAX_CONCURRENCY,
+        error::SendFileError,
+        options::SendOptions,
+        preconnected::Connection,
+        source::ReaderSource,
+        utils::{initialize_handshake, read_handshake_answer},
+    },
+    transport::{
+        attach_headers_for, ProbeAckV1, ProbeV1, ReceiverMessageV1, SenderMessageV1,
+        MAX_BLOCK_SIZE, MAX_MESSAGE_SIZE,
+    },
+    units::{Elapsed, Rate, Size},
+};
+
+/// Name of the file announced in the handshake of a probe, never written by receivers that
+/// support probes.
+pub const PROBE_FILE_NAME: &str = ".sendfile-probe";
+
+/// Default time spent streaming filler payloads to measure the throughput.
+pub const DEFAULT_PROBE_DURATION: Duration = Duration::from_secs(3);
+
+/// Number of echoed probes timed to measure the round trip time.
+const PROBE_PINGS: u32 = 10;
+/// Size of the filler payload of each streamed probe.
+const PROBE_PAYLOAD_SIZE: usize = 256 * 1024;
+/// Time either side waits for the next message before giving up on the probe.
+const PROBE_TIMEOUT_SECS: u64 = 15;
+/// Smallest recommended block size, below which per-block overhead dominates.
+const MIN_RECOMMENDED_BLOCK_SIZE: u32 = 256 * 1024;
+
+/// What a probe measured on the path to the receiver.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeReport {
+    /// Address of the probed receiver.
+    pub receiver: String,
+    /// Shortest round trip time of an echoed probe.
+    pub rtt_min: Duration,
+    /// Average round trip time of the echoed probes.
+    pub rtt_avg: Duration,
+    /// Path MTU in bytes, `None` where the kernel doesn't report it.
+    pub path_mtu: Option<u32>,
+    /// Maximum segment size of the connection in bytes, `None` where the kernel doesn't report
+    /// it.
+    pub mss: Option<u32>,
+    /// Payload bytes the receiver acknowledged.
+    pub bytes: u64,
+    /// Time from the first streamed payload until the receiver acknowledged the last one.
+    pub elapsed: Duration,
+}
+
+/// Transfer options recommended for a probed path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recommendation {
+    /// Block size in bytes, for `--block-size`.
+    pub block_size: u32,
+    /// Number of data connections, for `--concurrency`.
+    pub concurrency: u16,
+    /// Smallest socket buffer in bytes that lets the path be filled, twice its bandwidth-delay
+    /// product.
+    pub window: u64,
+}
+
+impl ProbeReport {
+    /// Returns the measured throughput in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Returns the bandwidth-delay product of the path in bytes, the data in flight needed to
+    /// keep it busy.
+    pub fn bandwidth_delay_product(&self) -> u64 {
+        (self.throughput() * self.rtt_min.as_secs_f64()).round() as u64
+    }
+
+    /// Recommends transfer options for the path.
+    ///
+    /// Each data connection has a single block in flight, so a connection moves a block per
+    /// round trip plus the time to transmit it. Blocks are sized to the bandwidth-delay product
+    /// within the supported range, and enough connections are opened to cover what one block
+    /// can't, plus one to hide the request round trip.
+    pub fn recommend(&self) -> Recommendation {
+        let bdp = self.bandwidth_delay_product();
+        let block_size =
+            bdp.next_power_of_two()
+                .clamp(MIN_RECOMMENDED_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32;
+        let concurrency =
+            (bdp.div_ceil(block_size as u64) + 1).min(DEFAULT_MAX_CONCURRENCY as u64) as u16;
+
+        Recommendation {
+            block_size,
+            concurrency,
+            window: 2 * bdp,
+        }
+    }
+}
+
+impl Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let recommendation = self.recommend();
+        writeln!(f, "Probe of {}", self.receiver)?;
+        writeln!(
+            f,
+            "RTT:         {} min, {} avg",
+            Millis(self.rtt_min),
+            Millis(self.rtt_avg)
+        )?;
+        match (self.path_mtu, self.mss) {
+            (Some(mtu), Some(mss)) => writeln!(f, "Path MTU:    {} bytes (MSS {})", mtu, mss)?,
+            (Some(mtu), None) => writeln!(f, "Path MTU:    {} bytes", mtu)?,
+            _ => writeln!(f, "Path MTU:    unknown")?,
+        }
+        writeln!(
+            f,
+            "Throughput:  {} ({} in {})",
+            Rate(self.throughput()),
+            Size(self.bytes),
+            Elapsed(self.elapsed)
+        )?;
+        writeln!(
+            f,
+            "Recommended: --block-size {} --concurrency {}",
+            recommendation.block_size, recommendation.concurrency
+        )?;
+        write!(
+            f,
+            "Window:      socket buffers of at least {} (net.core.rmem_max, net.core.wmem_max)",
+            Size(recommendation.window)
+        )
+    }
+}
+
+/// Duration displayed in milliseconds, for round trip times too short for [Elapsed].
+struct Millis(Duration);
+
+impl Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2} ms", self.0.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Measures the path to the receiver at `address`.
+///
+/// # Arguments
+///
+/// * `address` - The receiver's address and handshake port.
+/// * `options` - Options the handshake is made with, see [SendOptions].
+/// * `duration` - Time spent streaming payloads to measure the throughput.
+///
+/// # Returns
+///
+/// A [ProbeReport], or a `SendFileError` if the receiver can't be reached, is busy or doesn't
+/// support probes.
+pub fn probe(
+    address: (&str, u16),
+    options: &SendOptions,
+    duration: Duration,
+) -> Result<ProbeReport, SendFileError> {
+    let file_metadata = FileMetadata::new(String::from(PROBE_FILE_NAME), 0, [0; 32]);
+    let source = ReaderSource::new(Cursor::new(Vec::new()), 0);
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let (mut stream, _) = initialize_handshake(
+        &mut buffer,
+        address,
+        &file_metadata,
+        &source,
+        options,
+        Capabilities::PROBE,
+        None,
+    )?;
+    stream.set_read_timeout(Some(Duration::from_secs(PROBE_TIMEOUT_SECS)))?;
+
+    // Receivers that predate probes take it for the offer of an empty file
+    let (answer, protocol_version) = read_handshake_answer(&mut stream, &mut buffer, None)?;
+    match answer {
+        ReceiverMessageV1::ProbeAck(_) => {}
+        ReceiverMessageV1::OfferResponse(response) if !response.accepted => {
+            return Err(SendFileError::rejected(response.reason));
+        }
+        ReceiverMessageV1::OfferResponse(_) => {
+            return Err(SendFileError::ConnectionFailed(String::from(
+                "Receiver doesn't support probes",
+            )));
+        }
+        message => {
+            return Err(SendFileError::UnexpectedMessage {
+                received: format!("{:?}", message),
+                expected: String::from("ProbeAck"),
+            });
+        }
+    }
+    info!("Receiver is ready, probing for {}", Elapsed(duration));
+
+    let mut seq = 0;
+    let mut rtt_min = Duration::MAX;
+    let mut rtt_total = Duration::ZERO;
+    for _ in 0..PROBE_PINGS {
+        seq += 1;
+        let sent_at = Instant::now();
+        send_probe(&mut stream, &mut buffer, protocol_version, seq, true, &[])?;
+        read_probe_ack(&mut stream, &mut buffer, seq)?;
+        let rtt = sent_at.elapsed();
+        rtt_min = rtt_min.min(rtt);
+        rtt_total += rtt;
+    }
+    debug!("Round trip time: {:?} min", rtt_min);
+
+    let payload = vec![0u8; PROBE_PAYLOAD_SIZE];
+    let started_at = Instant::now();
+    while started_at.elapsed() < duration {
+        seq += 1;
+        send_probe(
+            &mut stream,
+            &mut buffer,
+            protocol_version,
+            seq,
+            false,
+            &payload,
+        )?;
+    }
+    seq += 1;
+    send_probe(&mut stream, &mut buffer, protocol_version, seq, true, &[])?;
+    let ack = read_probe_ack(&mut stream, &mut buffer, seq)?;
+    let elapsed = started_at.elapsed();
+    let (path_mtu, mss) = path_info(stream.get_ref().tcp()).unzip();
+
+    Ok(ProbeReport {
+        receiver: format!("{}:{}", address.0, address.1),
+        rtt_min,
+        rtt_avg: rtt_total / PROBE_PINGS,
+        path_mtu,
+        mss,
+        bytes: ack.bytes_received,
+        elapsed,
+    })
+}
+
+/// Answers the probe of the sender connected on `stream`, once its handshake is read, framing
+/// acknowledgements with `protocol_version`.
+///
+/// Returns the number of payload bytes received once the sender closes the connection.
+pub(crate) fn answer_probe<S: Connection>(
+    stream: &mut S,
+    protocol_version: u8,
+) -> Result<u64, SendFileError> {
+    stream.set_read_timeout(Some(Duration::from_secs(PROBE_TIMEOUT_SECS)))?;
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let mut write_buffer = [0u8; 32];
+    let mut filled_len = 0;
+    let mut bytes_received = 0;
+    send_probe_ack(
+        stream,
+        &mut write_buffer,
+        protocol_version,
+        0,
+        bytes_received,
+    )?;
+
+    loop {
+        let result = match read_next_payload::<SenderMessageV1, _>(stream, &mut buffer, filled_len)
+        {
+            Ok(result) => result,
+            // The sender is done
+            Err(StreamReadError::UnexpectedEof) => return Ok(bytes_received),
+            Err(e) => return Err(e.into()),
+        };
+        let (next_idx, total_bytes_read) = (result.next_payload_index, result.total_bytes_read);
+        let probe = match result.message {
+            SenderMessageV1::Probe(probe) => probe,
+            message => {
+                return Err(SendFileError::UnexpectedMessage {
+                    received: format!("{:?}", message),
+                    expected: String::from("Probe"),
+                });
+            }
+        };
+        bytes_received += probe.payload.len() as u64;
+        let (seq, echo) = (probe.seq, probe.echo);
+
+        filled_len = if let Some(next_idx) = next_idx {
+            buffer.copy_within(next_idx..total_bytes_read, 0);
+            total_bytes_read - next_idx
+        } else {
+            0
+        };
+        if echo {
+            send_probe_ack(
+                stream,
+                &mut write_buffer,
+                protocol_version,
+                seq,
+                bytes_received,
+            )?;
+        }
+    }
+}
+
+fn send_probe<S: Write>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    protocol_version: u8,
+    seq: u32,
+    echo: bool,
+    payload: &[u8],
+) -> Result<(), SendFileError> {
+    let message = SenderMessageV1::Probe(ProbeV1 { seq, echo, payload });
+    stream.write_all(&attach_headers_for(
+        protocol_version,
+        None,
+        message.to_bytes(buffer)?,
+    ))?;
+    Ok(())
+}
+
+fn send_probe_ack<S: Write>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    protocol_version: u8,
+    seq: u32,
+    bytes_received: u64,
+) -> Result<(), SendFileError> {
+    let message = ReceiverMessageV1::ProbeAck(ProbeAckV1 {
+        seq,
+        bytes_received,
+    });
+    stream.write_all(&attach_headers_for(
+        protocol_version,
+        None,
+        message.to_bytes(buffer)?,
+    ))?;
+    Ok(())
+}
+
+/// Waits for the receiver to acknowledge the probe numbered `seq`.
+fn read_probe_ack<S: Read>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    seq: u32,
+) -> Result<ProbeAckV1, SendFileError> {
+    match read_next_payload::<ReceiverMessageV1, _>(stream, buffer, 0)?.message {
+        ReceiverMessageV1::ProbeAck(ack) if ack.seq == seq => Ok(ack),
+        message => Err(SendFileError::UnexpectedMessage {
+            received: format!("{:?}", message),
+            expected: format!("ProbeAck {}", seq),
+        }),
+    }
+}
+
+/// Returns the path MTU and maximum segment size of the connection, as seen by the kernel.
+#[cfg(target_os = "linux")]
+fn path_info(stream: &TcpStream) -> Option<(u32, u32)> {
+    use std::os::fd::AsRawFd;
+
+    let mut info = std::mem::MaybeUninit::<libc::tcp_info>::zeroed();
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: `info` is large enough for `len` bytes and only read on success.
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            info.as_mut_ptr().cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    let info = unsafe { info.assume_init() };
+    Some((info.tcpi_pmtu, info.tcpi_snd_mss))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn path_info(_stream: &TcpStream) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(throughput: u64, rtt: Duration) -> ProbeReport {
+        ProbeReport {
+            receiver: String::from("10.0.0.5:7878"),
+            rtt_min: rtt,
+            rtt_avg: rtt,
+            path_mtu: Some(1500),
+            mss: Some(1448),
+            bytes: throughput * 2,
+            elapsed: Duration::from_secs(2),
+        }
+    }
+
+    #[test]
+    fn test_recommendations_follow_bandwidth_delay_product() {
+        // 1 Gbit/s LAN, 0.2 ms: a 25 kB product fits in the smallest block
+        let lan = report(125_000_000, Duration::from_micros(200)).recommend();
+        assert_eq!(lan.block_size, 256 * 1024);
+        assert_eq!(lan.concurrency, 2);
+        assert_eq!(lan.window, 50_000);
+
+        // 1 Gbit/s over 20 ms
+        let wan = report(125_000_000, Duration::from_millis(20)).recommend();
+        assert_eq!(wan.block_size, MAX_BLOCK_SIZE);
+        assert_eq!(wan.concurrency, 2);
+
+        // 10 Gbit/s over 80 ms needs more connections than allowed
+        let long_fat = report(1_250_000_000, Duration::from_millis(80)).recommend();
+        assert_eq!(long_fat.block_size, MAX_BLOCK_SIZE);
+        assert_eq!(long_fat.concurrency, DEFAULT_MAX_CONCURRENCY);
+
+        let text = report(125_000_000, Duration::from_millis(20)).to_string();
+        assert!(text.contains("RTT:         20.00 ms min, 20.00 ms avg"));
+        assert!(text.contains("Path MTU:    1500 bytes (MSS 1448)"));
+        assert!(text.contains("Recommended: --block-size 4194304 --concurrency 2"));
+    }
+}
@@ -0,0 +1,240 @@
+//! Transfer profiles (`send --profile`, `receive --profile`), presets of options suited to a kind
+//! of link or device.
+//!
+//! The `lan-10g` profiles bundle the options making the most of fast local networks, where CPU
+//! time rather than bandwidth limits the transfer: uncompressed blocks of [LAN_BLOCK_SIZE] over
+//! [LAN_CONCURRENCY] connections with [LAN_SOCKET_BUFFER_SIZE] socket buffers and Nagle's
+//! algorithm on, each connection keeping [LAN_PIPELINE_DEPTH] block requests outstanding. Options
+//! given explicitly take precedence. Block checksums are negotiated by the peers, so the profiles
+//! leave them as they are.
+//!
+//! [ReceiveProfile::LowMemory] makes receiving feasible on routers and single-board computers
+//! with tens of MB of RAM. Files are received over a single connection whose buffers are sized
+//! to the block size rather than to the largest block, offers of blocks larger than
+//! [LOW_MEMORY_MAX_BLOCK_SIZE] are rejected, and nothing holding more than a block is used: no
+//! UDP data plane buffering repair symbols, no block store prefetching the hashes of every block.
+//! Compressed blocks are decoded into the connection's buffers, see [crate::stream::inflate], so
+//! no memory is allocated per block.
+
+use clap::ValueEnum;
+
+use crate::{
+    stream::socket::SocketTuning,
+    transport::{MAX_BLOCK_SIZE, MAX_MESSAGE_SIZE},
+    units::Size,
+};
+
+/// Block size of [SendProfile::Lan10g], the largest there is.
+pub const LAN_BLOCK_SIZE: u32 = MAX_BLOCK_SIZE;
+
+/// Data connections of the `lan-10g` profiles, unless `--concurrency` is given.
+pub const LAN_CONCURRENCY: u16 = 8;
+
+/// Block requests each connection of [ReceiveProfile::Lan10g] keeps outstanding, unless
+/// `--pipeline-depth` is given. At 4 MiB per block, enough to keep a 10 Gbit/s link busy through
+/// the time the sender takes to read and send a block.
+pub const LAN_PIPELINE_DEPTH: u32 = 16;
+
+/// Socket buffers of the `lan-10g` profiles, above the bandwidth-delay product of 10 Gbit/s at a
+/// round trip of 5 ms.
+pub const LAN_SOCKET_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Largest block accepted by [ReceiveProfile::LowMemory].
+pub const LOW_MEMORY_MAX_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// Size of the buffer the handshake is read into by [ReceiveProfile::LowMemory], room for the
+/// handshake and the messages following it.
+const LOW_MEMORY_HANDSHAKE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Bytes a message carries besides its block.
+const MESSAGE_OVERHEAD: usize = MAX_MESSAGE_SIZE - MAX_BLOCK_SIZE as usize;
+
+/// Preset of the resources a receiver uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReceiveProfile {
+    /// Buffers sized for the largest block, every feature available.
+    #[default]
+    Standard,
+    /// A single connection, blocks of at most 64 KiB and buffers sized to them.
+    LowMemory,
+    /// Tuned for 10 Gbit/s local networks: 8 connections with large socket buffers.
+    #[value(name = "lan-10g")]
+    Lan10g,
+}
+
+impl ReceiveProfile {
+    /// Returns the most data connections a transfer is received over.
+    pub fn max_connections(self) -> u16 {
+        match self {
+            Self::Standard | Self::Lan10g => u16::MAX,
+            Self::LowMemory => 1,
+        }
+    }
+
+    /// Returns the number of data connections used unless `--concurrency` is given, `None` for
+    /// the default of [crate::stream::concurrency::effective_concurrency].
+    pub fn concurrency(self) -> Option<u16> {
+        match self {
+            Self::Standard | Self::LowMemory => None,
+            Self::Lan10g => Some(LAN_CONCURRENCY),
+        }
+    }
+
+    /// Returns the number of block requests each connection keeps outstanding unless
+    /// `--pipeline-depth` is given, `None` for the default of
+    /// [DEFAULT_PIPELINE_DEPTH](crate::stream::options::DEFAULT_PIPELINE_DEPTH).
+    pub fn pipeline_depth(self) -> Option<u32> {
+        match self {
+            Self::Standard | Self::LowMemory => None,
+            Self::Lan10g => Some(LAN_PIPELINE_DEPTH),
+        }
+    }
+
+    /// Returns the socket options of the data connections.
+    pub fn socket_tuning(self) -> SocketTuning {
+        match self {
+            Self::Standard | Self::LowMemory => SocketTuning::default(),
+            Self::Lan10g => lan_socket_tuning(),
+        }
+    }
+
+    /// Checks that blocks of `block_size` bytes can be received.
+    ///
+    /// # Returns
+    ///
+    /// The reason an offer of such blocks is rejected, if it is.
+    pub fn check_block_size(self, block_size: u32) -> Result<(), String> {
+        match self {
+            Self::LowMemory if block_size > LOW_MEMORY_MAX_BLOCK_SIZE => Err(format!(
+                "Blocks of {} are larger than the {} this low-memory receiver accepts, send with \
+                 --block-size {}",
+                Size(block_size as u64),
+                Size(LOW_MEMORY_MAX_BLOCK_SIZE as u64),
+                LOW_MEMORY_MAX_BLOCK_SIZE
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the size of the buffers a data connection receiving blocks of `block_size` bytes
+    /// reads messages into and decodes blocks into.
+    pub fn message_buffer_size(self, block_size: u32) -> usize {
+        match self {
+            Self::Standard | Self::Lan10g => MAX_MESSAGE_SIZE,
+            Self::LowMemory => block_size as usize + MESSAGE_OVERHEAD,
+        }
+    }
+
+    /// Returns the size of the buffer the handshake is read into.
+    pub fn handshake_buffer_size(self) -> usize {
+        match self {
+            Self::Standard | Self::Lan10g => MAX_MESSAGE_SIZE,
+            Self::LowMemory => LOW_MEMORY_HANDSHAKE_BUFFER_SIZE,
+        }
+    }
+
+    /// Returns whether features holding more than a block in memory are used: the UDP data
+    /// plane and the block store.
+    pub fn buffers_beyond_block(self) -> bool {
+        self != Self::LowMemory
+    }
+}
+
+/// Preset of the options a sender uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SendProfile {
+    /// Compressed 1 MiB blocks over as many connections as the CPU has threads.
+    #[default]
+    Standard,
+    /// Tuned for 10 Gbit/s local networks: uncompressed 4 MiB blocks over 8 connections with
+    /// large socket buffers.
+    #[value(name = "lan-10g")]
+    Lan10g,
+}
+
+impl SendProfile {
+    /// Returns the block size used unless `--block-size` is given, `None` for the default.
+    pub fn block_size(self) -> Option<u32> {
+        match self {
+            Self::Standard => None,
+            Self::Lan10g => Some(LAN_BLOCK_SIZE),
+        }
+    }
+
+    /// Returns the number of data connections used unless `--concurrency` is given, `None` for
+    /// the default of [crate::stream::concurrency::effective_concurrency].
+    pub fn concurrency(self) -> Option<u16> {
+        match self {
+            Self::Standard => None,
+            Self::Lan10g => Some(LAN_CONCURRENCY),
+        }
+    }
+
+    /// Returns whether blocks are compressed, unless `--no-compress` is given.
+    pub fn compress(self) -> bool {
+        self == Self::Standard
+    }
+
+    /// Returns the socket options of the data connections.
+    pub fn socket_tuning(self) -> SocketTuning {
+        match self {
+            Self::Standard => SocketTuning::default(),
+            Self::Lan10g => lan_socket_tuning(),
+        }
+    }
+}
+
+/// Socket options of the `lan-10g` profiles.
+fn lan_socket_tuning() -> SocketTuning {
+    SocketTuning {
+        buffer_size: Some(LAN_SOCKET_BUFFER_SIZE),
+        nodelay: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_low_memory_limits() {
+        let profile = ReceiveProfile::LowMemory;
+        assert_eq!(profile.max_connections(), 1);
+        assert!(profile.check_block_size(LOW_MEMORY_MAX_BLOCK_SIZE).is_ok());
+        assert!(profile
+            .check_block_size(MAX_BLOCK_SIZE)
+            .unwrap_err()
+            .contains("--block-size 65536"));
+        assert!(profile.message_buffer_size(4096) < 8192);
+        assert!(!profile.buffers_beyond_block());
+
+        let standard = ReceiveProfile::Standard;
+        assert!(standard.check_block_size(MAX_BLOCK_SIZE).is_ok());
+        assert_eq!(standard.message_buffer_size(4096), MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn test_lan_profiles() {
+        let send = SendProfile::Lan10g;
+        assert_eq!(send.block_size(), Some(MAX_BLOCK_SIZE));
+        assert_eq!(send.concurrency(), Some(LAN_CONCURRENCY));
+        assert!(!send.compress());
+        assert!(!send.socket_tuning().nodelay);
+        assert_eq!(
+            SendProfile::Standard.socket_tuning(),
+            SocketTuning::default()
+        );
+
+        let receive = ReceiveProfile::Lan10g;
+        assert_eq!(receive.concurrency(), Some(LAN_CONCURRENCY));
+        assert_eq!(receive.pipeline_depth(), Some(LAN_PIPELINE_DEPTH));
+        assert_eq!(ReceiveProfile::Standard.pipeline_depth(), None);
+        assert_eq!(receive.socket_tuning(), send.socket_tuning());
+        assert!(receive.check_block_size(MAX_BLOCK_SIZE).is_ok());
+        assert!(receive.buffers_beyond_block());
+        assert_eq!(
+            ReceiveProfile::from_str("lan-10g", false),
+            Ok(ReceiveProfile::Lan10g)
+        );
+    }
+}
@@ -1,46 +1,135 @@
 use std::{
-    borrow::Cow,
-    fs::OpenOptions,
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
     io::{Read, Write},
     net::{SocketAddr, TcpListener, TcpStream},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crc_fast::{checksum, CrcAlgorithm};
-use flate2::read::GzDecoder;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
 use crate::{
-    cli::TRANSFER_PORT,
-    connection::read_next_payload,
-    file::utils::{get_file_blake3_hash, read_file_block, write_file_block},
-    stream::error::SendFileError,
+    authentication::{conn_hello, verify_authentication, SeenAuthentications},
+    capabilities::{
+        Capabilities, ChecksumAlgorithm, CompressionCodec, FeatureSet, SOFTWARE_VERSION,
+    },
+    connection::{read_next_payload, read_next_payload_with, ReadPayloadResult},
+    file::{
+        buffer::AlignedBuffer,
+        content_type::TYPE_REJECTION_PREFIX,
+        device::{device_size, is_block_device},
+        duplicate::{find_candidates, place_duplicate, DuplicateMode},
+        integrity::{store_integrity, IntegrityRecord},
+        metadata::{check_metadata, store_metadata, Metadata},
+        resume::{resume_state_path, ResumeState},
+        store::BlockStore,
+        utils::{
+            advise_file, available_space, get_bytes_blake3_hash, get_file_blake3_hash_with,
+            get_reader_blake3_hash, is_remote_filesystem, try_lock_file, FileAdvice,
+        },
+    },
+    history::{record_received, to_hex, ReceivedEntry},
+    identity::Identity,
+    limits::TRANSFER_PORT,
+    noise::{NoiseHandshake, NoiseKey, NoisePeer, NoiseStream},
+    pairing::{Pairing, PairingError},
+    peers::PeerRegistry,
+    quarantine::{self, QuarantineRecord},
+    receipt::sign_receipt,
+    stream::{
+        bottleneck::Stage,
+        bundle::{Bundle, BundleError, BundleProgress, BundleSink},
+        cancel::cancel_reason,
+        checksum::block_checksum_with,
+        clock::ClockSkew,
+        concurrency::cap_to_blocks,
+        control::ProgressReporter,
+        damage::{damage_report_path, DamageReport},
+        error::{ErrorContext, ErrorContextExt, SendFileError},
+        handle::{TransferControl, TransferHandle},
+        heartbeat::{ping_while, Heartbeat, Heartbeating, Side},
+        inflate::BlockInflater,
+        keepalive::{configure_keepalive, Keepalive},
+        offer::{Decision, OfferInfo},
+        options::ReceiveOptions,
+        panic::contain_panic,
+        plan::{ReceivePlan, RECEIVER_DRY_RUN_REASON},
+        preconnected::{Connection, Preconnected, PRECONNECTED_PEER},
+        probe::answer_probe,
+        registry::{Registration, TransferDirection, TransferRegistry},
+        retry_after::sender_unavailable,
+        scan::{staged_path, ScanHook, ScanSubject, SCAN_FAILED_CODE},
+        sessions::{find_session, forget_session, record_session, SessionDescriptor},
+        sink::{BlockSink, FileSink, MemorySink},
+        socket::SocketTuning,
+        time_limit::{abort_reason, with_time_limit, TIME_LIMIT_CODE},
+        udp::UdpReceiver,
+        utils::bind_listener,
+    },
+    telemetry::{BlockSpans, TraceContext, TraceSpan},
+    threads::thread_name,
+    tls::{MaybeTlsStream, TlsPeer},
     transport::{
-        attach_headers, DataV1, ReceiverMessageV1, RequestV1, SenderMessageV1, TransferCompleteV1,
-        VerifyBlockV1, MAX_MESSAGE_SIZE,
+        attach_headers_for, choose_protocol_version, AlgorithmsV1, AuthenticationV1, BatchV1,
+        BatchedMessageV1, BlockHashesRequestV1, CancelV1, ConnHelloV1, DataV1, FileDataV1,
+        FileRequestV1, FrameHeader, GoodbyeV1, HandshakeAckV1, HandshakeRejectV1, HaveBlocksV1,
+        NoReceiptV1, NoiseHandshakeV1, OfferResponseV1, PairingConfirmV1, PairingReplyV1, PairingV1,
+        PongV1, ProtocolVersionV1, PushAckV1, PushRangeV1, ReceiverErrorV1, ReceiverMessageV1,
+        RejectReasonV1, RequestRangeV1, RequestV1, SenderErrorV1, SenderMessageV1, SessionV1,
+        TransferCompleteV1, UdpRequestV1, VerifyBlockV1, FRAME_HEADER_SIZE,
+        HANDSHAKE_ACK_PROTOCOL_VERSION, MAX_BATCH_MESSAGES, MAX_BLOCK_HASHES_PER_MESSAGE,
+        MAX_MESSAGE_SIZE, MULTI_FILE_PROTOCOL_VERSION, SESSION_FRAMING_PROTOCOL_VERSION,
+        SESSION_ID_SIZE, TEXT_FRAMING_PROTOCOL_VERSION,
     },
+    units::{Count, Elapsed, Rate, Size},
 };
 
-const MAX_RETRIES: u32 = 3;
-const INITIAL_RETRY_DELAY_MS: u64 = 500;
+/// Times a data connection tries downloading a block before it gives up.
+pub const MAX_BLOCK_ATTEMPTS: u32 = 3;
+/// Blocks the sender streams between two acknowledgements, see [Capabilities::PUSH_STREAM]. Two
+/// windows are in flight at once.
+///
+/// [Capabilities::PUSH_STREAM]: crate::capabilities::Capabilities::PUSH_STREAM
+pub const PUSH_WINDOW: u32 = 64;
+/// Consecutive missing blocks asked for with a single request, see
+/// [Capabilities::RANGE_REQUEST]. The next run is requested while the last blocks of the previous
+/// one are still on their way, as many as [ReceiveOptions::pipeline_depth].
+///
+/// [Capabilities::RANGE_REQUEST]: crate::capabilities::Capabilities::RANGE_REQUEST
+pub const REQUEST_RANGE_BLOCKS: u32 = 64;
+/// Delay before retrying a block, doubled on each retry. Tests inject faults on purpose and retry
+/// right away.
+const INITIAL_RETRY_DELAY_MS: u64 = if cfg!(test) { 0 } else { 500 };
+const ACCEPT_POLL_MS: u64 = 100;
+/// Delay before the first retry round of [ReceiveOptions::auto_retry], doubled after each round
+/// that made no progress.
+const INITIAL_ROUND_DELAY_MS: u64 = 1000;
+const MAX_ROUND_DELAY_MS: u64 = 30_000;
+/// Room for a message following the handshake, large enough for an authentication or a file
+/// header.
+const TRAILING_MESSAGE_SIZE: usize = 2048;
+/// Time the sender has to answer a goodbye before the connection is closed anyway, see
+/// [Capabilities::GOODBYE].
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Starts receiving a file on the specified address.
 ///
 /// This function binds to the given address and listens for incoming connections.
 /// It handles the initial handshake and then spawns multiple threads to download
-/// file blocks concurrently.
+/// file blocks concurrently. Once the file is verified, a signed receipt is returned to the
+/// sender if both sides support it and [ReceiveOptions::identity_path] is set.
 ///
 /// # Arguments
 ///
 /// * `bind_addr` - The address and port to bind to (e.g., ("0.0.0.0", 7878)).
 /// * `path` - The output path where the received file will be saved.
-/// * `concurrency` - The number of concurrent connections to accept.
+/// * `options` - Concurrency, locking and I/O tuning for this receive, see [ReceiveOptions].
 ///
 /// # Returns
 ///
@@ -48,116 +137,2634 @@ const INITIAL_RETRY_DELAY_MS: u64 = 500;
 pub fn receive_file(
     bind_addr: (&str, u16),
     path: &std::path::Path,
-    mut concurrency: u16,
+    options: &ReceiveOptions,
+) -> Result<(), SendFileError> {
+    receive_file_with(bind_addr, path, options, &Arc::new(TransferControl::new()))
+}
+
+/// Starts receiving a file on a background thread, see [receive_file].
+///
+/// Cancelling the transfer keeps the blocks received so far, a later receive into the same path
+/// resumes from them.
+///
+/// # Returns
+///
+/// A [TransferHandle] to follow, pause or cancel the transfer, or a `SendFileError` if the
+/// thread could not be started.
+pub fn start_receive_file(
+    bind_addr: (&str, u16),
+    path: &Path,
+    options: &ReceiveOptions,
+) -> Result<TransferHandle, SendFileError> {
+    let host = bind_addr.0.to_string();
+    let port = bind_addr.1;
+    let path = path.to_path_buf();
+    let options = options.clone();
+
+    TransferHandle::spawn(
+        "receiver",
+        Arc::new(TransferControl::new()),
+        move |control| receive_file_with((&host, port), &path, &options, control),
+    )
+}
+
+fn receive_file_with(
+    bind_addr: (&str, u16),
+    path: &Path,
+    options: &ReceiveOptions,
+    control: &Arc<TransferControl>,
+) -> Result<(), SendFileError> {
+    with_time_limit(options.max_duration, control, || {
+        let mut session = accept_transfer(bind_addr, Some(path), options, control)?;
+        let mut registration = register_session(&session, control);
+        let result = receive_session(&mut session, path, options, control)
+            .context(|| session_context(&session, session.sender_addr));
+        session.trace.record(&result);
+        result?;
+        registration.mark_succeeded();
+        Ok(())
+    })
+}
+
+/// Receives a file like [receive_file], over a connection the caller already established with
+/// the sender (e.g. a TLS tunnel, a multiplexed stream or an SSH channel) instead of listening
+/// for it. The sender must run [send_over](crate::stream::send::send_over) on its end.
+///
+/// The whole transfer goes over `transport`, with a single data connection regardless of
+/// [ReceiveOptions::concurrency], see [crate::stream::preconnected]. It runs on the calling
+/// thread, timeouts are left to the caller's transport, and a lost connection is not retried.
+/// The sender address seen by [ReceiveOptions::offer_handler] is unspecified.
+///
+/// # Arguments
+///
+/// * `transport` - Connection to the sender.
+/// * `path` - The output path where the received file will be saved.
+/// * `options` - Locking and I/O tuning for this receive, see [ReceiveOptions].
+///
+/// # Returns
+///
+/// A `Result` indicating success or a `SendFileError`. Senders only probing or doing a dry run
+/// fail with [SendFileError::UnexpectedMessage].
+pub fn receive_over<T: Read + Write>(
+    transport: T,
+    path: &Path,
+    options: &ReceiveOptions,
+) -> Result<(), SendFileError> {
+    let options = &ReceiveOptions {
+        concurrency: 1,
+        auto_retry: None,
+        // The sender address is unspecified
+        sessions: None,
+        tls: None,
+        pairing_code: None,
+        ..options.clone()
+    };
+    let control = Arc::new(TransferControl::new());
+    let sender_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    with_time_limit(options.max_duration, &control, || {
+        let mut session = read_handshake(Preconnected(transport), sender_addr, options)?;
+        if let Some(rejection) = refuse_unpaired(&mut session, options) {
+            return Err(SendFileError::rejected(rejection.to_string()));
+        }
+        if session.features.probe || session.features.dry_run {
+            if session.features.offer_response {
+                send_offer_response(&mut session, false, String::from("Not a transfer"))?;
+            }
+            return Err(SendFileError::UnexpectedMessage {
+                received: String::from("Handshake of a probe or dry run"),
+                expected: String::from("Handshake of a transfer"),
+            });
+        }
+        control.set_total_bytes(session.total_size);
+
+        let mut registration = TransferRegistry::global().register(
+            TransferDirection::Receive,
+            PRECONNECTED_PEER.to_string(),
+            session.file_name.clone(),
+            control.clone(),
+        );
+        let result = receive_session(&mut session, path, options, &control)
+            .context(|| session_context(&session, PRECONNECTED_PEER));
+        session.trace.record(&result);
+        result?;
+        registration.mark_succeeded();
+        Ok(())
+    })
+}
+
+/// Returns the context of errors receiving the file offered in `session` from `peer`.
+fn session_context<S>(session: &Session<S>, peer: impl std::fmt::Display) -> ErrorContext {
+    ErrorContext::new("receiving")
+        .with_file(&session.file_name)
+        .with_peer(peer)
+}
+
+/// Receives the file offered in `session` at `path`, which may be a directory to save it in,
+/// verifies it and sends the receipt.
+fn receive_session<S: BlockDownload>(
+    session: &mut Session<S>,
+    path: &Path,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<(), SendFileError> {
+    control.set_trace(session.trace.context());
+    if session.bundle.is_some() {
+        return receive_bundle(session, path, options, control);
+    }
+    let ReceiveOptions {
+        lock,
+        network_fs,
+        preallocate,
+        drop_cache,
+        disk_limit_rate,
+        ..
+    } = *options;
+
+    let default_path = match resumable_session(session, options) {
+        Some(descriptor) => {
+            info!(
+                "Resuming the earlier session at {:?}",
+                descriptor.output_path
+            );
+            descriptor.output_path
+        }
+        None => determine_final_path(path, &session.file_name),
+    };
+    let final_path = answer_offer(session, default_path, true, options)?;
+    info!("Output file path: {:?}", final_path);
+
+    if !network_fs && is_remote_filesystem(&final_path) {
+        warn!(
+            "Destination {:?} is on a network filesystem, consider --network-fs",
+            final_path
+        );
+    }
+
+    // Checked along with the offer
+    let device = is_block_device(&final_path);
+    if !device
+        && let Some(mode) = options.reuse_duplicates
+        && let Some(source) = find_duplicate(session, path, options)
+    {
+        let stats = reuse_duplicate(session, &source, &final_path, mode, options, control)?;
+        record_received_file(session, &stats, &final_path, options);
+        return Ok(());
+    }
+    // Files to scan are written next to their destination, and only renamed to it once they
+    // pass. Devices are never scanned
+    let write_path = match options.scan {
+        Some(_) => staged_path(&final_path),
+        None => final_path.clone(),
+    };
+    // Without block verification a resumed file is downloaded again in full. Devices are written
+    // over in full, whatever they held
+    let is_existing_file = write_path.exists() && session.features.verify_blocks && !device;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&write_path)?;
+
+    if lock && !try_lock_file(&file, false)? {
+        return Err(SendFileError::FileLocked(write_path));
+    }
+
+    if preallocate && !device {
+        file.set_len(session.total_size)?;
+    }
+
+    // On network filesystems, serialize writes rather than letting every connection issue its
+    // own positioned writes concurrently
+    let sink =
+        FileSink::new(file.try_clone()?, network_fs, drop_cache).with_write_limit(disk_limit_rate);
+    if let Some(rate) = disk_limit_rate {
+        info!("Limiting disk writes to {}", Rate(rate as f64));
+    }
+    if !device {
+        remember_session(session, &final_path, &write_path, options);
+    }
+    let resume_path = resume_state_path(&write_path);
+    let stats = run_transfer(
+        session,
+        &sink,
+        &final_path,
+        is_existing_file,
+        (!device).then_some(resume_path.as_path()),
+        options,
+        control,
+    )?;
+
+    if !preallocate && !device {
+        // Blocks may have been written out of order, or over a larger pre-existing file
+        file.set_len(session.total_size)?;
+    }
+    check_skipped_blocks(session, &stats, (!device).then_some(final_path.as_path()))?;
+
+    // The sender waits for the receipt meanwhile
+    let total_size = session.total_size;
+    let heartbeat = session_heartbeat(session, options);
+    let session_id = session.session_id();
+    let actual_hash = ping_while(
+        &mut session.stream,
+        heartbeat,
+        Side::Receiver,
+        session.protocol_version,
+        session_id.as_ref(),
+        || {
+            if device {
+                // Only the start of the device holds the file
+                get_reader_blake3_hash(&mut &file, total_size)
+            } else {
+                Ok(get_file_blake3_hash_with(
+                    &write_path,
+                    options.hash_strategy(),
+                    &options.workers,
+                )
+                .expect("Failed to compute file hash after transfer"))
+            }
+        },
+    )?;
+    verify_integrity(session.expected_hash, actual_hash)?;
+    if drop_cache {
+        // Hashing read the whole file back into the cache
+        if let Err(e) = advise_file(&file, 0, 0, FileAdvice::DontNeed) {
+            debug!("Failed to drop {:?} from the page cache: {}", final_path, e);
+        }
+    }
+
+    if lock {
+        file.unlock()?;
+    }
+
+    if let Some(hook) = &options.scan {
+        scan_received_file(session, hook, &write_path, &final_path)?;
+        fs::rename(&write_path, &final_path)?;
+    }
+
+    if device {
+        // Nothing is recorded next to a device
+        finish_session(session, &stats, &final_path, options);
+        return Ok(());
+    }
+
+    if let Some(sessions) = &options.sessions
+        && let Err(e) = forget_session(sessions, &session.expected_hash)
+    {
+        warn!("Failed to remove the session from {:?}: {}", sessions, e);
+    }
+    record_received_file(session, &stats, &final_path, options);
+    Ok(())
+}
+
+/// Looks up the session of the file offered in `session` in [ReceiveOptions::sessions], if it
+/// was started by the same sender before the receiver was restarted.
+fn resumable_session<S>(
+    session: &Session<S>,
+    options: &ReceiveOptions,
+) -> Option<SessionDescriptor> {
+    let sessions = options.sessions.as_ref()?;
+    match find_session(sessions, &session.expected_hash) {
+        Ok(descriptor) => descriptor.filter(|descriptor| {
+            descriptor.is_sent_by(session.sender_addr.ip(), session.sender_key.as_ref())
+        }),
+        Err(e) => {
+            warn!("Failed to read the sessions in {:?}: {}", sessions, e);
+            None
+        }
+    }
+}
+
+/// Records the session of the file offered in `session`, saved at `final_path` and written to
+/// `write_path` meanwhile, in [ReceiveOptions::sessions].
+fn remember_session<S>(
+    session: &Session<S>,
+    final_path: &Path,
+    write_path: &Path,
+    options: &ReceiveOptions,
+) {
+    let Some(sessions) = &options.sessions else {
+        return;
+    };
+    let descriptor = SessionDescriptor {
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        peer: session.sender_addr.ip(),
+        sender_key: session.sender_key.as_ref().map(|key| to_hex(key)),
+        file_name: session.file_name.clone(),
+        file_hash: to_hex(&session.expected_hash),
+        bytes: session.total_size,
+        output_path: std::path::absolute(final_path).unwrap_or_else(|_| final_path.to_path_buf()),
+        write_path: std::path::absolute(write_path).unwrap_or_else(|_| write_path.to_path_buf()),
+    };
+    if let Err(e) = record_session(sessions, &descriptor) {
+        warn!("Failed to record the session in {:?}: {}", sessions, e);
+    }
+}
+
+/// Receives the directory offered in `session` at `path`, which may be a directory to save it
+/// in, verifies every file of it and sends the receipt, see [crate::stream::bundle].
+///
+/// Directories are received in full every time: files already at the destination are written
+/// over rather than resumed. With [ReceiveOptions::atomic_batch], they are only written over once
+/// every file is verified.
+fn receive_bundle<S: BlockDownload>(
+    session: &mut Session<S>,
+    path: &Path,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<(), SendFileError> {
+    let default_path = determine_final_path(path, &session.file_name);
+    let root = answer_offer(session, default_path, true, options)?;
+    info!("Output directory path: {:?}", root);
+
+    let bundle = session
+        .bundle
+        .clone()
+        .expect("Only directories are received as bundles");
+    // Batches are written next to the directory, and only moved into it once all verified
+    let write_root = if options.atomic_batch {
+        staged_path(&root)
+    } else {
+        root.clone()
+    };
+    let sink = BundleSink::create(&write_root, &bundle)?.with_write_limit(options.disk_limit_rate);
+    let progress = Arc::new(BundleProgress::new(&bundle, true));
+    control.track_files(progress.clone());
+    if let Some(rate) = options.disk_limit_rate {
+        info!("Limiting disk writes to {}", Rate(rate as f64));
+    }
+    let mut receive = || {
+        let stats = run_transfer(session, &sink, &root, false, None, options, control)?;
+        check_skipped_blocks(session, &stats, None)?;
+
+        // The sender waits for the receipt meanwhile
+        let heartbeat = session_heartbeat(session, options);
+        let session_id = session.session_id();
+        ping_while(
+            &mut session.stream,
+            heartbeat,
+            Side::Receiver,
+            session.protocol_version,
+            session_id.as_ref(),
+            || sink.verify(options.hash_strategy(), &options.workers, &progress),
+        )?;
+        if options.atomic_batch {
+            sink.commit(&root)?;
+            info!(
+                "Moved the {} files of the batch into place",
+                bundle.files().len()
+            );
+        }
+        Ok(stats)
+    };
+    let stats = match receive() {
+        Ok(stats) => stats,
+        Err(e) => {
+            if options.atomic_batch
+                && let Err(e) = fs::remove_dir_all(&write_root)
+            {
+                warn!("Failed to remove the staged batch {:?}: {}", write_root, e);
+            }
+            return Err(e);
+        }
+    };
+
+    finish_session(session, &stats, &root, options);
+    progress.stats().log();
+    Ok(())
+}
+
+/// Records the verified file at `final_path` in its extended attributes, metadata store and
+/// the index of received files as enabled in `options`, then completes the session.
+fn record_received_file<S: Write>(
+    session: &mut Session<S>,
+    stats: &TransferStats,
+    final_path: &Path,
+    options: &ReceiveOptions,
+) {
+    if options.xattrs {
+        let record = IntegrityRecord {
+            hash: session.expected_hash,
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        if let Err(e) = store_integrity(final_path, &record) {
+            warn!(
+                "Failed to record integrity metadata on {:?}: {}",
+                final_path, e
+            );
+        }
+    }
+
+    if let Some(store) = options.metadata_store
+        && !session.metadata.is_empty()
+        && let Err(e) = store_metadata(final_path, &session.metadata, store)
+    {
+        warn!("Failed to store the metadata of {:?}: {}", final_path, e);
+    }
+
+    if let Some(index) = &options.received_index {
+        let entry = ReceivedEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            file_hash: to_hex(&session.expected_hash),
+            bytes: session.total_size,
+            path: final_path
+                .canonicalize()
+                .unwrap_or_else(|_| final_path.to_path_buf()),
+        };
+        if let Err(e) = record_received(index, &entry) {
+            warn!("Failed to record {:?} in {:?}: {}", final_path, index, e);
+        }
+    }
+
+    finish_session(session, stats, final_path, options);
+}
+
+/// Looks up a file received before under the output path `path` with the content of the
+/// offered file, for [ReceiveOptions::reuse_duplicates].
+///
+/// Candidates from [ReceiveOptions::received_index] are hashed, so files changed since they
+/// were received are skipped.
+fn find_duplicate<S>(
+    session: &Session<S>,
+    path: &Path,
+    options: &ReceiveOptions,
+) -> Option<PathBuf> {
+    let index = options.received_index.as_ref()?;
+    let root = if path.is_dir() {
+        path
+    } else {
+        path.parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+    };
+    let candidates = match find_candidates(index, root, session.expected_hash, session.total_size) {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            warn!("Failed to look up received files in {:?}: {}", index, e);
+            return None;
+        }
+    };
+    candidates.into_iter().find(|candidate| {
+        match get_file_blake3_hash_with(candidate, options.hash_strategy(), &options.workers) {
+            Ok(hash) if hash == session.expected_hash => true,
+            Ok(_) => {
+                debug!("{:?} changed since it was received", candidate);
+                false
+            }
+            Err(e) => {
+                debug!("Failed to hash {:?}: {}", candidate, e);
+                false
+            }
+        }
+    })
+}
+
+/// Places `source`, which holds the offered file, at `final_path` with `mode` and reports the
+/// transfer complete to the sender without downloading any block.
+fn reuse_duplicate<S: BlockDownload>(
+    session: &mut Session<S>,
+    source: &Path,
+    final_path: &Path,
+    mode: DuplicateMode,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<TransferStats, SendFileError> {
+    let started = Instant::now();
+    if final_path
+        .canonicalize()
+        .is_ok_and(|existing| existing == source)
+    {
+        info!("{:?} already holds the file", final_path);
+    } else {
+        match place_duplicate(source, final_path, mode)? {
+            DuplicateMode::Link => info!("Linked {:?} instead of downloading it", source),
+            DuplicateMode::Copy => info!("Copied {:?} instead of downloading it", source),
+        }
+    }
+
+    // No block is written, every one of them is already in place
+    let sink = MemorySink::new(Vec::new());
+    let state = ReceiverState {
+        udp_fec: false,
+        ..receiver_state(session, &sink, final_path, options, control)
+    };
+    for received in &state.received_blocks {
+        received.store(true, Ordering::SeqCst);
+    }
+    // Drops the progress of an earlier, interrupted transfer to the same path
+    save_progress(&state, &resume_state_path(final_path));
+    S::report_complete(session, &state, options)?;
+
+    Ok(TransferStats {
+        bytes_received: 0,
+        bytes_reused: 0,
+        block_store: None,
+        unreadable_blocks: Vec::new(),
+        elapsed: started.elapsed(),
+        bottleneck: None,
+    })
+}
+
+/// Checks that the block device at `path` may be written over with a file of `size` bytes.
+///
+/// Fails unless [ReceiveOptions::allow_device] is set, with a scan hook, since a device can't be
+/// quarantined, and if the device is smaller than the file.
+fn check_device(path: &Path, size: u64, options: &ReceiveOptions) -> Result<(), SendFileError> {
+    if !options.allow_device {
+        return Err(SendFileError::DeviceNotConfirmed(path.to_path_buf()));
+    }
+    if options.scan.is_some() {
+        return Err(SendFileError::InvalidRequest(format!(
+            "{:?} is a block device, which can't be scanned and quarantined",
+            path
+        )));
+    }
+    let device_size = device_size(&File::open(path)?)?;
+    if device_size < size {
+        return Err(SendFileError::DeviceTooSmall {
+            path: path.to_path_buf(),
+            size: device_size,
+            needed: size,
+        });
+    }
+    warn!("Writing {} over block device {:?}", Size(size), path);
+    Ok(())
+}
+
+/// Scans the received file, written at `path` until it passes, with `hook`, moving it into the
+/// quarantine and telling the sender if it fails.
+///
+/// Fails with [SendFileError::ScanFailed] if the file did not pass.
+fn scan_received_file<S: Write>(
+    session: &mut Session<S>,
+    hook: &ScanHook,
+    path: &Path,
+    destination: &Path,
 ) -> Result<(), SendFileError> {
+    let file_hash = to_hex(&session.expected_hash);
+    let sender = session.sender_addr.to_string();
+    let subject = ScanSubject {
+        file_name: &session.file_name,
+        file_hash: &file_hash,
+        sender: &sender,
+        metadata: &session.metadata,
+    };
+    info!("Scanning {:?} with {:?}", path, hook.command);
+    let Err(reason) = hook.scan(path, &subject) else {
+        return Ok(());
+    };
+
+    let record = QuarantineRecord {
+        file_name: session.file_name.clone(),
+        destination: destination.to_path_buf(),
+        file_hash,
+        size: session.total_size,
+        sender,
+        sender_key: session.sender_key.as_ref().map(|key| to_hex(key)),
+        received_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        reason: reason.clone(),
+    };
+    match quarantine::hold(&hook.quarantine_dir, path, &record) {
+        Ok(entry) => warn!(
+            "{:?} failed the scan, quarantined in {:?}: {}",
+            destination, entry, reason
+        ),
+        Err(e) => {
+            // Never leave a file that failed the scan next to its destination
+            error!("Failed to quarantine {:?}, deleting it: {}", path, e);
+            if let Err(e) = fs::remove_file(path) {
+                error!("Failed to delete {:?}: {}", path, e);
+            }
+        }
+    }
+
+    let msg = ReceiverMessageV1::Error(ReceiverErrorV1 {
+        code: SCAN_FAILED_CODE,
+        message: reason.clone(),
+    });
+    let mut buffer = vec![0u8; 256 + reason.len()];
+    let session_id = session.session_id();
+    if let Err(e) = send_message(
+        &mut session.stream,
+        &msg,
+        &mut buffer,
+        session.protocol_version,
+        session_id.as_ref(),
+    )
+    .and_then(|_| Ok(session.stream.flush()?))
+    {
+        warn!("Failed to tell the sender its file failed the scan: {}", e);
+    }
+    Err(SendFileError::ScanFailed(reason))
+}
+
+/// Receives a file into `buffer` instead of writing it to disk.
+///
+/// Works like [receive_file], for services that process incoming payloads in memory. Any
+/// existing content of `buffer` is replaced, its allocation is reused. Resuming and locking do
+/// not apply, and [ReceiveOptions::xattrs] is ignored.
+///
+/// # Arguments
+///
+/// * `bind_addr` - The address and port to bind to (e.g., ("0.0.0.0", 7878)).
+/// * `buffer` - Receives the content of the file.
+/// * `options` - Concurrency and protocol options for this receive, see [ReceiveOptions].
+///
+/// # Returns
+///
+/// The name, size and hash of the received file, or a `SendFileError`. On error the content of
+/// `buffer` is unspecified.
+pub fn receive_to_memory(
+    bind_addr: (&str, u16),
+    buffer: &mut Vec<u8>,
+    options: &ReceiveOptions,
+) -> Result<ReceivedFile, SendFileError> {
+    let control = Arc::new(TransferControl::new());
+    with_time_limit(options.max_duration, &control, || {
+        let mut session = accept_transfer(bind_addr, None, options, &control)?;
+        let mut registration = register_session(&session, &control);
+        if session.bundle.is_some() {
+            let rejection = Rejection::new(
+                RejectReasonV1::Unsupported,
+                "Directories can't be received in memory",
+            );
+            if session.features.offer_response {
+                answer_handshake(&mut session, Some(&rejection))?;
+            }
+            return Err(SendFileError::rejected(rejection.to_string()));
+        }
+        let display_path = PathBuf::from(&session.file_name);
+        answer_offer(&mut session, display_path.clone(), false, options)?;
+
+        let sink = MemorySink::new(std::mem::take(buffer));
+        let result = run_transfer(
+            &mut session,
+            &sink,
+            &display_path,
+            false,
+            None,
+            options,
+            &control,
+        );
+        *buffer = sink.into_inner();
+        let stats = result?;
+        check_skipped_blocks(&session, &stats, None)?;
+
+        // Trailing blocks that were never written (e.g. an empty file) leave the buffer short
+        buffer.resize(session.total_size as usize, 0);
+        verify_integrity(session.expected_hash, get_bytes_blake3_hash(buffer))?;
+
+        finish_session(&mut session, &stats, &display_path, options);
+        registration.mark_succeeded();
+        Ok(ReceivedFile {
+            name: session.file_name,
+            size: session.total_size,
+            hash: session.expected_hash,
+        })
+    })
+}
+
+/// A file received by [receive_to_memory].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedFile {
+    /// Name of the file on the sender side.
+    pub name: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// BLAKE3 hash of the file, verified against the received content.
+    pub hash: [u8; 32],
+}
+
+/// Waits for a sender like [receive_file] and describes what receiving its file would do, then
+/// rejects the offer without writing anything.
+///
+/// A sender doing a dry run itself gets the answer [receive_file] would give it.
+///
+/// # Arguments
+///
+/// * `bind_addr` - The address and port to bind to (e.g., ("0.0.0.0", 7878)).
+/// * `path` - The output path the file would be saved to.
+/// * `options` - Options of the receive that would be made, see [ReceiveOptions].
+///
+/// # Returns
+///
+/// A [ReceivePlan] describing the offered file and its destination, or a `SendFileError`.
+pub fn dry_run_receive(
+    bind_addr: (&str, u16),
+    path: &Path,
+    options: &ReceiveOptions,
+) -> Result<ReceivePlan, SendFileError> {
+    let control = TransferControl::new();
+    let mut session = accept_session(bind_addr, options, &control)?;
+    let default_path = determine_final_path(path, &session.file_name);
+    let plan = plan_receive(&session, default_path, options);
+
+    if session.features.dry_run {
+        let (accepted, reason) = plan.answer();
+        send_offer_response(&mut session, accepted, reason)?;
+    } else if session.features.offer_response {
+        let rejection = Rejection::new(RejectReasonV1::Declined, RECEIVER_DRY_RUN_REASON);
+        answer_handshake(&mut session, Some(&rejection))?;
+    }
+    Ok(plan)
+}
+
+/// A transfer after the handshake has been accepted and features negotiated.
+struct Session<S = MaybeTlsStream> {
+    /// Handshake connection, kept open to return the receipt. Encrypted from the end of the
+    /// handshake on if the sender set up a Noise channel, see [crate::noise], or from the start
+    /// of the handshake if it paired, see [crate::pairing].
+    stream: NoiseStream<S>,
+    /// Address of the sender, unspecified for sessions over a [Preconnected] connection.
+    sender_addr: SocketAddr,
+    file_name: String,
+    expected_hash: [u8; 32],
+    total_size: u64,
+    block_size: u32,
+    /// Number of data connections, already capped by both sides and the block count.
+    concurrency: u16,
+    features: FeatureSet,
+    /// Verified public key of the sender, `None` if it did not authenticate.
+    sender_key: Option<[u8; 32]>,
+    /// First bytes of the file, `None` if the sender did not send them.
+    file_header: Option<Vec<u8>>,
+    /// Metadata the sender attached to the file, empty if it attached none.
+    metadata: Metadata,
+    /// Skew of the sender's clock, `None` if it did not send the time on it, see
+    /// [crate::stream::clock].
+    clock_skew: Option<ClockSkew>,
+    /// Hello every data connection opens with, `None` if the sender does not expect one.
+    conn_hello: Option<ConnHelloV1>,
+    /// Protocol version every message to the sender is framed with.
+    protocol_version: u8,
+    /// Files of the offered directory, `None` if a single file is offered, see
+    /// [crate::stream::bundle].
+    bundle: Option<Arc<Bundle>>,
+    /// Sender of a TLS handshake connection, whose certificate data connections must present.
+    tls: Option<TlsPeer>,
+    /// Handshake listener, kept with [ReceiveOptions::auto_retry] so a restarted sender can
+    /// take over the transfer.
+    listener: Option<TcpListener>,
+    /// Span of the transfer, started when the handshake is read, see [crate::telemetry].
+    trace: TraceSpan,
+}
+
+impl<S> Session<S> {
+    /// Returns the id of the sender's session, `None` if it does not expect hellos.
+    fn session_id(&self) -> Option<[u8; SESSION_ID_SIZE]> {
+        self.conn_hello.as_ref().map(|hello| hello.session_id)
+    }
+}
+
+/// Totals of a finished [run_transfer].
+struct TransferStats {
+    bytes_received: u64,
+    bytes_reused: u64,
+    block_store: Option<BlockStore>,
+    /// Blocks zero-filled in best-effort mode because the sender could not read them, with the
+    /// read error.
+    unreadable_blocks: Vec<(u32, String)>,
+    /// Time spent verifying and downloading blocks.
+    elapsed: Duration,
+    /// Stage of the pipeline that took most of the time, see [crate::stream::bottleneck].
+    bottleneck: Option<Stage>,
+}
+
+/// Waits for a sender on `bind_addr`, reads its handshake and negotiates features.
+fn accept_session(
+    bind_addr: (&str, u16),
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<Session, SendFileError> {
     info!(
         "Listening on {}:{} with concurrency {}",
-        bind_addr.0, bind_addr.1, concurrency
+        bind_addr.0, bind_addr.1, options.concurrency
     );
 
-    let listener = TcpListener::bind(bind_addr)?;
-    let (mut stream, sender_addr) = listener.accept()?;
-    info!("Accepted connection from {}", sender_addr);
+    let listener = bind_listener(bind_addr, options.listen_backlog)?;
+    // Poll so that a cancelled transfer stops waiting for a sender
+    listener.set_nonblocking(true)?;
+    let mut session = loop {
+        let (stream, sender_addr) = loop {
+            control.checkpoint()?;
+            match listener.accept() {
+                Ok(accepted) => break accepted,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        match read_session(stream, sender_addr, options, control) {
+            Ok(session) => break session,
+            // A peer that connects and stays silent must not keep other senders waiting
+            Err(e) if e.is_timeout() => warn!(
+                "Dropping {}: no handshake within {:?}",
+                sender_addr, options.handshake_timeout
+            ),
+            // Nor must a peer that can't prove it may send
+            Err(SendFileError::Tls(e)) => warn!("Dropping {}: {}", sender_addr, e),
+            Err(SendFileError::Pairing(e @ PairingError::Unpaired)) => {
+                warn!("Dropping {}: {}", sender_addr, e)
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    control.set_total_bytes(session.total_size);
+    // Kept to accept a new handshake for the same file if the sender is lost, or the data
+    // connections of a single-port sender
+    if options.auto_retry.is_some() || options.single_port {
+        session.listener = Some(listener);
+    }
+    Ok(session)
+}
+
+/// Accepts the next sender that wants its file transferred, answering the dry runs and probes of
+/// other senders meanwhile, see [answer_dry_run] and [answer_probe]. Senders that aren't paired
+/// are refused first with [ReceiveOptions::paired_only].
+fn accept_transfer(
+    bind_addr: (&str, u16),
+    output_path: Option<&Path>,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<Session, SendFileError> {
+    loop {
+        let mut session = accept_session(bind_addr, options, control)?;
+        if refuse_unpaired(&mut session, options).is_some() {
+            continue;
+        }
+        if session.features.probe {
+            info!("Answering probe of {}", session.sender_addr);
+            match answer_probe(&mut session.stream, session.protocol_version) {
+                Ok(bytes) => info!("Probe of {} sent {}", session.sender_addr, Size(bytes)),
+                Err(e) => warn!("Failed to answer probe of {}: {}", session.sender_addr, e),
+            }
+            continue;
+        }
+        if !session.features.dry_run {
+            return Ok(session);
+        }
+        if let Err(e) = answer_dry_run(&mut session, output_path, options) {
+            warn!("Failed to answer dry run of {}: {}", session.sender_addr, e);
+        }
+    }
+}
+
+/// Reads the handshake of the sender connected on `stream`, secured with
+/// [ReceiveOptions::tls] if set, and negotiates features.
+fn read_session(
+    stream: TcpStream,
+    sender_addr: SocketAddr,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<Session, SendFileError> {
+    stream.set_nonblocking(false)?;
+    configure_keepalive(&stream, options.keepalive.as_ref());
+    control.register(&stream);
+    info!("Accepted connection from {}", sender_addr);
+    let (stream, tls) = match &options.tls {
+        Some(config) => {
+            // A sender that never completes the TLS handshake is dropped like a silent one
+            stream.set_read_timeout(Some(options.handshake_timeout))?;
+            let stream = config.accept(stream)?;
+            let peer = TlsPeer::pin(config.clone(), &stream)?;
+            (stream, Some(peer))
+        }
+        None => (MaybeTlsStream::Plain(stream), None),
+    };
+    let mut session = read_handshake(stream, sender_addr, options)?;
+    session.tls = tls;
+    Ok(session)
+}
+
+/// Reads the handshake the sender wrote on `stream`, and the messages following it, then
+/// negotiates features. With [ReceiveOptions::pairing_code], the sender must pair with it first.
+///
+/// The session is traced from then on, see [crate::telemetry].
+fn read_handshake<S: Connection>(
+    stream: S,
+    sender_addr: SocketAddr,
+    options: &ReceiveOptions,
+) -> Result<Session<S>, SendFileError> {
+    let trace = TraceSpan::root("receive");
+    let result = trace.context().in_span("handshake", || {
+        read_handshake_messages(stream, sender_addr, options)
+    });
+    trace.record(&result);
+    let mut session = result?;
+    trace.set_str("file.name", &session.file_name);
+    trace.set_int("file.size", session.total_size);
+    trace.set_str("peer", &session.sender_addr.to_string());
+    session.trace = trace;
+    Ok(session)
+}
+
+/// Reads the handshake and the messages following it as [read_handshake] does.
+fn read_handshake_messages<S: Connection>(
+    stream: S,
+    sender_addr: SocketAddr,
+    options: &ReceiveOptions,
+) -> Result<Session<S>, SendFileError> {
+    let mut stream = NoiseStream::new(stream);
+    stream.set_read_timeout(Some(options.handshake_timeout))?;
+    if let Some(code) = &options.pairing_code {
+        pair_with_sender(&mut stream, code.expose(), &noise_key(options)?)?;
+    }
+    let mut buffer = vec![0u8; options.profile.handshake_buffer_size()];
+    let result = read_next_payload_with(
+        &mut stream,
+        &mut buffer,
+        0,
+        SenderMessageV1::from_first_bytes,
+    )?;
+    // The authentication and file header may have been read along with the handshake
+    let leftover = result
+        .next_payload_index
+        .map(|index| index..result.total_bytes_read);
+    let protocol_version = result.protocol_version;
+    let handshake = match result.message {
+        SenderMessageV1::Handshake(h) => h,
+        _ => {
+            return Err(SendFileError::UnexpectedMessage {
+                received: format!("{:?}", result.message),
+                expected: String::from("Handshake"),
+            });
+        }
+    };
+
+    let expected_hash: [u8; 32] = handshake.file_hash.try_into().map_err(|_| {
+        SendFileError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid file hash length",
+        ))
+    })?;
+
+    info!(
+        "Received handshake: file={}, size={}, block_size={}, concurrency={}",
+        handshake.file_name, handshake.total_size, handshake.block_size, handshake.concurrency
+    );
+    let mut local = Capabilities::local_with_encryption(options.tls.is_some());
+    if options.heartbeat.is_none() {
+        local = local.without(Capabilities::HEARTBEAT);
+    }
+    // The handshake connection of a single-port transfer carries blocks
+    if options.single_port {
+        local = local.without(Capabilities::CONTROL_CHANNEL);
+    }
+    info!(
+        "Peer is sendfile {} (capabilities: {}), local is sendfile {} (capabilities: {})",
+        handshake.software_version, handshake.capabilities, SOFTWARE_VERSION, local
+    );
+
+    let total_blocks = handshake.total_size.div_ceil(handshake.block_size as u64);
+
+    // Use the minimum of sender's and receiver's concurrency to avoid overwhelming the sender
+    let concurrency = options
+        .concurrency
+        .min(handshake.concurrency)
+        .min(options.profile.max_connections());
+    let concurrency = cap_to_blocks(concurrency, total_blocks);
+
+    let Some((features, downgrades)) = FeatureSet::negotiate(local, handshake.capabilities) else {
+        // No checksum algorithm in common, blocks could not be verified
+        return Err(missing_capability(
+            handshake.software_version,
+            local & Capabilities::CRC32,
+        ));
+    };
+    for downgrade in &downgrades {
+        warn!("Degraded feature: {}", downgrade);
+    }
+    info!("Negotiated features: {}", features);
+
+    let handshake_capabilities = handshake.capabilities;
+    let handshake_end = leftover
+        .as_ref()
+        .map_or(result.total_bytes_read, |range| range.start);
+    let mut session = Session {
+        file_name: handshake.file_name.to_string(),
+        expected_hash,
+        total_size: handshake.total_size,
+        block_size: handshake.block_size,
+        concurrency,
+        features,
+        stream,
+        sender_addr,
+        sender_key: None,
+        file_header: None,
+        metadata: Metadata::new(),
+        clock_skew: None,
+        conn_hello: None,
+        protocol_version,
+        bundle: None,
+        tls: None,
+        listener: None,
+        trace: TraceSpan::none(),
+    };
+
+    let mut pending = leftover
+        .map(|range| buffer[range].to_vec())
+        .unwrap_or_default();
+    // Paired senders set up the channel before the handshake
+    if session.features.noise && session.stream.peer().is_none() {
+        accept_noise_channel(
+            &mut session,
+            &buffer[..handshake_end],
+            &mut pending,
+            options,
+        )?;
+    }
+    // Verified once the session it covers is read
+    let authentication = if session.features.authentication {
+        Some(read_authentication(&mut session.stream, &mut pending)?)
+    } else {
+        warn!("Sender did not authenticate, its identity is unknown");
+        None
+    };
+    if session.features.file_header {
+        let header = read_file_header(&mut session.stream, &mut pending, &expected_hash)?;
+        session.file_header = Some(header);
+    }
+    let transfer = if session.features.conn_hello {
+        let transfer =
+            read_trailing_message(&mut session.stream, &mut pending, "Session", |message| {
+                match message {
+                    SenderMessageV1::Session(transfer) => Some(transfer),
+                    _ => None,
+                }
+            })?;
+        session.conn_hello = Some(conn_hello(&transfer));
+        Some(transfer)
+    } else {
+        None
+    };
+    if let Some(authentication) = authentication {
+        let key = check_authentication(&authentication, &expected_hash, transfer.as_ref())?;
+        if let Some(peer) = session.stream.peer() {
+            peer.check_identity(&key)?;
+        }
+        log_sender_identity(&key, options);
+        session.sender_key = Some(key);
+    }
+    let offered = if session.features.version_negotiation {
+        negotiate_protocol_version(&mut session, &mut pending)?
+    } else {
+        Vec::new()
+    };
+    // Only senders of a directory offer the version listing its files
+    if session.protocol_version >= MULTI_FILE_PROTOCOL_VERSION
+        && offered.contains(&MULTI_FILE_PROTOCOL_VERSION)
+    {
+        let room = options.profile.handshake_buffer_size();
+        let bundle = read_file_list(&mut session, &mut pending, room)?;
+        session.bundle = Some(Arc::new(bundle));
+    }
+    if session.features.metadata {
+        session.metadata = read_metadata(&mut session.stream, &mut pending)?;
+    }
+    if session.features.clock {
+        let skew = read_clock_skew(&mut session.stream, &mut pending, options)?;
+        session.clock_skew = Some(skew);
+    }
+
+    if let Some(policy) = &options.strict {
+        policy
+            .check(local & handshake_capabilities, session.protocol_version)
+            .map_err(SendFileError::StrictModeViolation)?;
+    }
+    session.stream.set_read_timeout(None)?;
+    Ok(session)
+}
+
+/// Runs the Noise handshake the sender started after its handshake frame `prologue`, `pending`
+/// holding any bytes already read past the handshake. Every later message on the handshake
+/// connection is encrypted, `pending` included.
+fn accept_noise_channel<S: Read + Write>(
+    session: &mut Session<S>,
+    prologue: &[u8],
+    pending: &mut Vec<u8>,
+    options: &ReceiveOptions,
+) -> Result<(), SendFileError> {
+    let handshake = NoiseHandshake::responder(&noise_key(options)?, prologue)?;
+    run_noise_responder(
+        &mut session.stream,
+        handshake,
+        pending,
+        session.protocol_version,
+    )?;
+    info!("Handshake connection encrypted with a Noise channel");
+    Ok(())
+}
+
+/// Pairs with the sender connected on `stream` using this receiver's one-time `code`, then
+/// answers the Noise handshake keyed by the pairing with the static `key`: the handshake and
+/// every later message are encrypted.
+///
+/// Once the sender has seen the reply, a failure is final: a sender that doesn't confirm the
+/// pairing may have tried a code and must not get another try.
+pub(crate) fn pair_with_sender<S: Read + Write>(
+    stream: &mut NoiseStream<S>,
+    code: &str,
+    key: &NoiseKey,
+) -> Result<(), SendFileError> {
+    let mut pending = Vec::new();
+    let PairingV1 { element } =
+        read_trailing_message(stream, &mut pending, "Pairing", |message| match message {
+            SenderMessageV1::Pairing(request) => Some(request),
+            _ => None,
+        })
+        .map_err(|e| match e {
+            SendFileError::UnexpectedMessage { .. } => PairingError::Unpaired.into(),
+            e => e,
+        })?;
+    let pairing = Pairing::receiver(code)?;
+    let own_element = pairing.element();
+    let keys = pairing.finish(&element)?;
+    let reply = ReceiverMessageV1::PairingReply(PairingReplyV1 {
+        element: own_element,
+        confirmation: keys.confirmation(),
+    });
+    send_message(
+        stream,
+        &reply,
+        &mut [0u8; 256],
+        TEXT_FRAMING_PROTOCOL_VERSION,
+        None,
+    )?;
+    stream.flush()?;
+
+    fn extract(message: SenderMessageV1) -> Option<PairingConfirmV1> {
+        match message {
+            SenderMessageV1::PairingConfirm(confirm) => Some(confirm),
+            _ => None,
+        }
+    }
+    let PairingConfirmV1 { confirmation } =
+        read_trailing_message(stream, &mut pending, "PairingConfirm", extract)
+            .map_err(|e| PairingError::Unconfirmed(e.to_string()))?;
+    keys.check_peer(&confirmation)?;
+    info!("Paired with the sender");
+
+    let handshake = NoiseHandshake::paired_responder(key, keys.session_key())?;
+    run_noise_responder(
+        stream,
+        handshake,
+        &mut pending,
+        TEXT_FRAMING_PROTOCOL_VERSION,
+    )?;
+    info!("Handshake connection encrypted with a Noise channel keyed by the pairing");
+    Ok(())
+}
+
+/// Returns the static key of this receiver's Noise channels, tied to its identity if it has one.
+fn noise_key(options: &ReceiveOptions) -> Result<NoiseKey, SendFileError> {
+    let identity = options.identity_path.as_deref().and_then(|path| {
+        Identity::load_or_generate(path)
+            .map_err(|e| {
+                warn!(
+                    "Noise key not tied to an identity, identity unavailable: {}",
+                    e
+                )
+            })
+            .ok()
+    });
+    Ok(NoiseKey::new(identity.as_ref())?)
+}
+
+/// Answers the Noise `handshake` the sender started on `stream`, `pending` holding any bytes
+/// already read past the previous message, then encrypts every later message, `pending`
+/// included. Replies are framed with `protocol_version`.
+fn run_noise_responder<S: Read + Write>(
+    stream: &mut NoiseStream<S>,
+    mut handshake: NoiseHandshake,
+    pending: &mut Vec<u8>,
+    protocol_version: u8,
+) -> Result<(), SendFileError> {
+    fn extract(message: SenderMessageV1) -> Option<NoiseHandshakeV1> {
+        match message {
+            SenderMessageV1::NoiseHandshake(message) => Some(message),
+            _ => None,
+        }
+    }
+    let message = read_trailing_message(stream, pending, "NoiseHandshake", extract)?;
+    handshake.read_message(&message)?;
+    let reply = ReceiverMessageV1::NoiseHandshake(handshake.write_message()?);
+    send_message(stream, &reply, &mut [0u8; 256], protocol_version, None)?;
+    stream.flush()?;
+    let message = read_trailing_message(stream, pending, "NoiseHandshake", extract)?;
+    handshake.read_message(&message)?;
+    stream.start(handshake, &std::mem::take(pending))?;
+    Ok(())
+}
+
+/// Reads the protocol versions the sender supports from the handshake connection, `pending`
+/// holding any bytes already read past the previous message, and answers with the highest one
+/// both sides speak. Every later message is framed with it.
+///
+/// Returns the versions the sender offered.
+fn negotiate_protocol_version<S: Read + Write>(
+    session: &mut Session<S>,
+    pending: &mut Vec<u8>,
+) -> Result<Vec<u8>, SendFileError> {
+    let offered = read_trailing_message(
+        &mut session.stream,
+        pending,
+        "ProtocolVersions",
+        |message| match message {
+            SenderMessageV1::ProtocolVersions(versions) => Some(versions.versions),
+            _ => None,
+        },
+    )?;
+    // Frames are only tagged with a session the sender sent
+    let taggable: Vec<u8> = offered
+        .iter()
+        .copied()
+        .filter(|&version| {
+            session.conn_hello.is_some() || version < SESSION_FRAMING_PROTOCOL_VERSION
+        })
+        .collect();
+    let Some(version) = choose_protocol_version(&taggable) else {
+        return Err(SendFileError::NoCommonProtocolVersion(offered));
+    };
+    info!("Using protocol version {}", version);
+
+    session.protocol_version = version;
+    let session_id = session.session_id();
+    let msg = ReceiverMessageV1::ProtocolVersion(ProtocolVersionV1 { version });
+    send_message(
+        &mut session.stream,
+        &msg,
+        &mut [0u8; 64],
+        version,
+        session_id.as_ref(),
+    )?;
+    session.stream.flush()?;
+    Ok(offered)
+}
+
+/// Reads the next message the sender wrote on the handshake connection after its handshake.
+///
+/// # Arguments
+///
+/// * `stream` - Handshake connection.
+/// * `pending` - Bytes already read past the previous message, replaced with those read past
+///   this one.
+/// * `expected` - Name of the expected message, for errors.
+/// * `extract` - Returns the expected message, or `None` for any other message.
+fn read_trailing_message<S: Read, T>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+    expected: &str,
+    extract: impl FnOnce(SenderMessageV1) -> Option<T>,
+) -> Result<T, SendFileError> {
+    read_large_trailing_message(stream, pending, TRAILING_MESSAGE_SIZE, expected, extract)
+}
+
+/// Reads the next message of the sender like [read_trailing_message], with `room` bytes for it
+/// rather than [TRAILING_MESSAGE_SIZE].
+fn read_large_trailing_message<S: Read, T>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+    room: usize,
+    expected: &str,
+    extract: impl FnOnce(SenderMessageV1) -> Option<T>,
+) -> Result<T, SendFileError> {
+    let mut buffer = std::mem::take(pending);
+    let filled = buffer.len();
+    buffer.resize(filled + room, 0);
+    let result = read_next_payload::<SenderMessageV1, _>(stream, &mut buffer, filled)?;
+    let rest = result
+        .next_payload_index
+        .map(|index| index..result.total_bytes_read);
+    let received = format!("{:?}", result.message);
+    let Some(message) = extract(result.message) else {
+        return Err(SendFileError::UnexpectedMessage {
+            received,
+            expected: String::from(expected),
+        });
+    };
+    if let Some(rest) = rest {
+        pending.extend_from_slice(&buffer[rest]);
+    }
+    Ok(message)
+}
+
+/// Reads the sender's [AuthenticationV1] from the handshake connection, `pending` holding any
+/// bytes already read past the handshake.
+fn read_authentication<S: Read>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+) -> Result<AuthenticationV1, SendFileError> {
+    read_trailing_message(stream, pending, "Authentication", |message| match message {
+        SenderMessageV1::Authentication(authentication) => Some(authentication),
+        _ => None,
+    })
+}
+
+/// Verifies the sender's `authentication` for the file with `file_hash` and the `session` it
+/// handed, and that it was not accepted before.
+///
+/// Returns the verified public key of the sender.
+fn check_authentication(
+    authentication: &AuthenticationV1,
+    file_hash: &[u8; 32],
+    session: Option<&SessionV1>,
+) -> Result<[u8; 32], SendFileError> {
+    verify_authentication(authentication, file_hash, session)
+        .and_then(|()| SeenAuthentications::global().admit(authentication))
+        .map_err(|e| SendFileError::AuthenticationFailed(e.to_string()))?;
+    Ok(authentication.sender_key)
+}
+
+/// Reads the first bytes of the file the sender wrote on the handshake connection, `pending`
+/// holding any bytes already read past the previous message.
+fn read_file_header<S: Read>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+    file_hash: &[u8; 32],
+) -> Result<Vec<u8>, SendFileError> {
+    let header = read_trailing_message(stream, pending, "FileHeader", |message| match message {
+        SenderMessageV1::FileHeader(header) => Some(header),
+        _ => None,
+    })?;
+    if header.file_hash != *file_hash {
+        return Err(SendFileError::InvalidRequest(String::from(
+            "File header is for another file",
+        )));
+    }
+    Ok(header.bytes)
+}
+
+/// Reads the metadata the sender attached to the file from the handshake connection, `pending`
+/// holding any bytes already read past the previous message.
+///
+/// Fails with [SendFileError::Metadata] if the metadata could not be stored or passed on safely.
+fn read_metadata<S: Read>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+) -> Result<Metadata, SendFileError> {
+    let metadata = read_trailing_message(stream, pending, "Metadata", |message| match message {
+        SenderMessageV1::Metadata(metadata) => Some(metadata.entries),
+        _ => None,
+    })?;
+    check_metadata(&metadata)?;
+    for (key, value) in &metadata {
+        info!("File metadata: {}={}", key, value);
+    }
+    Ok(metadata)
+}
+
+/// Reads the time on the sender's clock from the handshake connection, `pending` holding any
+/// bytes already read past the previous message, and warns if it is further from this side's
+/// than [ReceiveOptions::max_clock_skew].
+///
+/// Returns the skew of the sender's clock.
+fn read_clock_skew<S: Read>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+    options: &ReceiveOptions,
+) -> Result<ClockSkew, SendFileError> {
+    let clock = read_trailing_message(stream, pending, "Clock", |message| match message {
+        SenderMessageV1::Clock(clock) => Some(clock),
+        _ => None,
+    })?;
+    let skew = ClockSkew::measure(clock.unix_millis);
+    if skew.exceeds(options.max_clock_skew) {
+        warn!(
+            "Clocks disagree ({}): modification times, scheduled transfers and receipt \
+             timestamps may be misleading",
+            skew
+        );
+    } else {
+        debug!("Clock skew: {}", skew);
+    }
+    Ok(skew)
+}
+
+/// Reads the files of the offered directory from the handshake connection, `pending` holding any
+/// bytes already read past the previous message, in at most `room` bytes.
+///
+/// Fails with [BundleError::ListMismatch] unless the list is the one the handshake offered.
+fn read_file_list<S: Read>(
+    session: &mut Session<S>,
+    pending: &mut Vec<u8>,
+    room: usize,
+) -> Result<Bundle, SendFileError> {
+    let file_list =
+        read_large_trailing_message(&mut session.stream, pending, room, "FileList", |message| {
+            match message {
+                SenderMessageV1::FileList(file_list) => Some(file_list),
+                _ => None,
+            }
+        })?;
+    let bundle = Bundle::new(file_list.files, session.block_size)?;
+    if bundle.hash() != session.expected_hash || bundle.total_size() != session.total_size {
+        return Err(BundleError::ListMismatch.into());
+    }
+    info!(
+        "Sender offers directory {:?} of {} files",
+        session.file_name,
+        bundle.files().len()
+    );
+    Ok(bundle)
+}
+
+/// Returns the trusted peers of [ReceiveOptions::peers_path], none if it can't be read.
+fn load_peers(options: &ReceiveOptions) -> PeerRegistry {
+    match options.peers_path.as_deref().map(PeerRegistry::load) {
+        Some(Ok(peers)) => peers,
+        Some(Err(e)) => {
+            warn!("Failed to read trusted peers: {}", e);
+            PeerRegistry::default()
+        }
+        None => PeerRegistry::default(),
+    }
+}
+
+/// Logs which trusted peer, if any, the authenticated sender is.
+fn log_sender_identity(key: &[u8; 32], options: &ReceiveOptions) {
+    match load_peers(options).find_by_key(key) {
+        Some(peer) => info!("Sender authenticated as trusted peer {:?}", peer.name),
+        None => warn!(
+            "Sender authenticated with key {}, which is not a trusted peer",
+            to_hex(key)
+        ),
+    }
+}
+
+/// Refuses the sender of `session` unless it may send with [ReceiveOptions::paired_only],
+/// telling it why if it supports it.
+///
+/// # Returns
+///
+/// The rejection the sender was refused with, `None` if it may send.
+fn refuse_unpaired<S: Write>(
+    session: &mut Session<S>,
+    options: &ReceiveOptions,
+) -> Option<Rejection> {
+    if !options.paired_only || options.pairing_code.is_some() {
+        return None;
+    }
+    let message = match &session.sender_key {
+        Some(key) if load_peers(options).find_by_key(key).is_some() => return None,
+        Some(key) => format!("Sender key {} is not a paired peer", to_hex(key)),
+        None => String::from("Sender did not authenticate"),
+    };
+    let rejection = Rejection::new(
+        RejectReasonV1::Declined,
+        format!("{message}, pair with the receiver first (sendfile pair)"),
+    );
+    warn!("Refusing {}: {}", session.sender_addr, rejection);
+    if session.features.offer_response
+        && let Err(e) = answer_handshake(session, Some(&rejection))
+    {
+        warn!(
+            "Failed to tell {} it is refused: {}",
+            session.sender_addr, e
+        );
+    }
+    Some(rejection)
+}
+
+/// Lists the session in the [TransferRegistry] until the returned guard is dropped.
+fn register_session<S>(
+    session: &Session<S>,
+    control: &Arc<TransferControl>,
+) -> Registration<'static> {
+    TransferRegistry::global().register(
+        TransferDirection::Receive,
+        session.sender_addr.to_string(),
+        session.file_name.clone(),
+        control.clone(),
+    )
+}
+
+/// Why an offered file is refused, as told to the sender.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rejection {
+    reason: RejectReasonV1,
+    message: String,
+}
+
+impl Rejection {
+    fn new(reason: RejectReasonV1, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    /// Formats the reason of the [OfferResponseV1] of senders predating [HandshakeRejectV1],
+    /// which tell refused types by their prefix.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            RejectReasonV1::FileType => write!(f, "{}{}", TYPE_REJECTION_PREFIX, self.message),
+            _ => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Asks [ReceiveOptions::offer_handler] what to do with the offered file and tells the sender,
+/// if it supports it, whether the file was accepted.
+///
+/// Files received `on_disk` are refused if their destination hasn't enough free space left.
+///
+/// Returns where to save the file, or [SendFileError::OfferRejected].
+fn answer_offer<S: Write>(
+    session: &mut Session<S>,
+    default_path: PathBuf,
+    on_disk: bool,
+    options: &ReceiveOptions,
+) -> Result<PathBuf, SendFileError> {
+    let (final_path, rejection) = decide_offer(session, default_path, options);
+
+    if rejection.is_none()
+        && is_block_device(&final_path)
+        && let Err(e) = check_device(&final_path, session.total_size, options)
+    {
+        if session.features.offer_response {
+            let reason = match e {
+                SendFileError::DeviceTooSmall { .. } => RejectReasonV1::DiskFull,
+                _ => RejectReasonV1::Declined,
+            };
+            answer_handshake(session, Some(&Rejection::new(reason, e.to_string())))?;
+        }
+        return Err(e);
+    }
+    let rejection = match rejection {
+        None if on_disk && !is_block_device(&final_path) => check_free_space(session, &final_path),
+        rejection => rejection,
+    };
+
+    if session.features.offer_response {
+        answer_handshake(session, rejection.as_ref())?;
+    }
+
+    match rejection {
+        Some(rejection) => {
+            info!("Rejected file {:?}: {}", session.file_name, rejection);
+            Err(SendFileError::rejected(rejection.to_string()))
+        }
+        None => Ok(final_path),
+    }
+}
+
+/// Checks the offered file, or every file of the offered directory, against
+/// [ReceiveOptions::type_policy] and the block size limit of [ReceiveOptions::profile], then asks
+/// [ReceiveOptions::offer_handler] what to do with it.
+///
+/// Returns where to save the file, and why it is rejected if it is.
+fn decide_offer<S>(
+    session: &Session<S>,
+    default_path: PathBuf,
+    options: &ReceiveOptions,
+) -> (PathBuf, Option<Rejection>) {
+    let checked = match &session.bundle {
+        // Only names are known before the files arrive
+        Some(bundle) => bundle
+            .files()
+            .iter()
+            .try_for_each(|file| options.type_policy.check(&file.path, None)),
+        None => options
+            .type_policy
+            .check(&session.file_name, session.file_header.as_deref()),
+    };
+    if let Err(reason) = checked {
+        return (
+            default_path,
+            Some(Rejection::new(RejectReasonV1::FileType, reason)),
+        );
+    }
+    if session.bundle.is_some() && options.scan.is_some() {
+        return (
+            default_path,
+            Some(Rejection::new(
+                RejectReasonV1::Unsupported,
+                "Directories can't be scanned, receive without --scan",
+            )),
+        );
+    }
+    if let Err(reason) = options.profile.check_block_size(session.block_size) {
+        return (
+            default_path,
+            Some(Rejection::new(RejectReasonV1::BlockSize, reason)),
+        );
+    }
+
+    let decision = match &options.offer_handler {
+        Some(handler) => handler.decide(&OfferInfo {
+            file_name: session.file_name.clone(),
+            size: session.total_size,
+            hash: session.expected_hash,
+            sender_addr: session.sender_addr,
+            sender_key: session.sender_key,
+            metadata: session.metadata.clone(),
+            default_path: default_path.clone(),
+        }),
+        None => Decision::Accept,
+    };
+
+    match decision {
+        Decision::Accept => (default_path, None),
+        Decision::AcceptAs(path) => (determine_final_path(&path, &session.file_name), None),
+        Decision::Reject(reason) => (
+            default_path,
+            Some(Rejection::new(RejectReasonV1::Declined, reason)),
+        ),
+    }
+}
+
+/// Checks that the filesystem of `path` has room for what the offered file, or directory, adds
+/// to it: its size, less what is already there and is resumed or written over.
+///
+/// Returns why the file is rejected if it hasn't, nothing if the free space can't be told.
+fn check_free_space<S>(session: &Session<S>, path: &Path) -> Option<Rejection> {
+    let existing_len = |path: &Path| fs::metadata(path).map_or(0, |metadata| metadata.len());
+    let needed = match &session.bundle {
+        Some(bundle) => bundle
+            .files()
+            .iter()
+            .map(|file| {
+                file.size
+                    .saturating_sub(existing_len(&path.join(&file.path)))
+            })
+            .sum(),
+        None => session.total_size.saturating_sub(existing_len(path)),
+    };
+    let available = available_space(path)?;
+    (needed > available).then(|| {
+        Rejection::new(
+            RejectReasonV1::DiskFull,
+            format!(
+                "Not enough space for {:?}: {} needed, {} available",
+                session.file_name,
+                Size(needed),
+                Size(available)
+            ),
+        )
+    })
+}
+
+/// Tells the sender whether its file is accepted, with a [HandshakeAckV1] carrying the
+/// parameters of the transfer or a [HandshakeRejectV1] from
+/// [HANDSHAKE_ACK_PROTOCOL_VERSION] on, with an [OfferResponseV1] before.
+fn answer_handshake<S: Write>(
+    session: &mut Session<S>,
+    rejection: Option<&Rejection>,
+) -> Result<(), SendFileError> {
+    if session.protocol_version < HANDSHAKE_ACK_PROTOCOL_VERSION {
+        return send_offer_response(
+            session,
+            rejection.is_none(),
+            rejection.map(|r| r.to_string()).unwrap_or_default(),
+        );
+    }
+    let msg = match rejection {
+        Some(rejection) => ReceiverMessageV1::HandshakeReject(HandshakeRejectV1 {
+            file_hash: session.expected_hash,
+            reason: rejection.reason,
+            message: rejection.message.clone(),
+        }),
+        None => ReceiverMessageV1::HandshakeAck(HandshakeAckV1 {
+            file_hash: session.expected_hash,
+            capabilities: session.features.capabilities(),
+            compression: session.features.compression,
+            checksum: session.features.checksum,
+            block_size: session.block_size,
+            concurrency: session.concurrency,
+        }),
+    };
+    let mut buffer = vec![0u8; 256 + rejection.map_or(0, |r| r.message.len())];
+    let session_id = session.session_id();
+    send_message(
+        &mut session.stream,
+        &msg,
+        &mut buffer,
+        session.protocol_version,
+        session_id.as_ref(),
+    )?;
+    session.stream.flush()?;
+    Ok(())
+}
+
+/// Tells the sender whether its file is accepted, with the reason if not, or with the conflicts
+/// found by a dry run.
+fn send_offer_response<S: Write>(
+    session: &mut Session<S>,
+    accepted: bool,
+    reason: String,
+) -> Result<(), SendFileError> {
+    let mut buffer = vec![0u8; 256 + reason.len()];
+    let msg = ReceiverMessageV1::OfferResponse(OfferResponseV1 {
+        file_hash: session.expected_hash,
+        accepted,
+        reason,
+    });
+    let session_id = session.session_id();
+    send_message(
+        &mut session.stream,
+        &msg,
+        &mut buffer,
+        session.protocol_version,
+        session_id.as_ref(),
+    )?;
+    session.stream.flush()?;
+    Ok(())
+}
+
+/// Describes what receiving the offered file would do, without touching the destination.
+fn plan_receive<S>(
+    session: &Session<S>,
+    default_path: PathBuf,
+    options: &ReceiveOptions,
+) -> ReceivePlan {
+    let (destination, rejection) = decide_offer(session, default_path, options);
+    let rejection = rejection.map(|r| r.to_string());
+    let conflicts = match rejection {
+        Some(_) => Vec::new(),
+        None => find_conflicts(session, &destination, options),
+    };
+
+    ReceivePlan {
+        file_name: session.file_name.clone(),
+        size: session.total_size,
+        file_hash: session.expected_hash,
+        block_size: session.block_size,
+        concurrency: session.concurrency,
+        sender_addr: session.sender_addr,
+        sender_key: session.sender_key,
+        features: session.features,
+        destination,
+        rejection,
+        conflicts,
+    }
+}
+
+/// Lists what is already at `path` or would get in the way of writing the offered file there.
+fn find_conflicts<S>(session: &Session<S>, path: &Path, options: &ReceiveOptions) -> Vec<String> {
+    let mut conflicts = Vec::new();
+
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+        && !parent.is_dir()
+    {
+        conflicts.push(format!("{} does not exist", parent.display()));
+    }
+
+    if path.exists() {
+        if session.features.verify_blocks {
+            conflicts.push(format!(
+                "{} exists, its blocks will be verified and only differing ones downloaded",
+                path.display()
+            ));
+        } else {
+            conflicts.push(format!(
+                "{} exists and will be overwritten, the sender can't verify its blocks",
+                path.display()
+            ));
+        }
+
+        if options.lock
+            && let Ok(file) = std::fs::File::open(path)
+            && !try_lock_file(&file, false).unwrap_or(true)
+        {
+            conflicts.push(format!("{} is locked by another process", path.display()));
+        }
+    }
+
+    match ResumeState::load(&resume_state_path(path)) {
+        Ok(None) => {}
+        Ok(Some(state)) if !state.is_for(&session.expected_hash) => {
+            conflicts.push(String::from("resume state of another file will be ignored"));
+        }
+        Ok(Some(state)) => match state.received_blocks(session.total_size, session.block_size) {
+            Ok(blocks) => conflicts.push(format!(
+                "resuming, {} of {} blocks were already received",
+                blocks.iter().filter(|received| **received).count(),
+                blocks.len()
+            )),
+            Err(e) => conflicts.push(format!("cannot resume: {}", e)),
+        },
+        Err(e) => conflicts.push(format!("unreadable resume state will be ignored: {}", e)),
+    }
+
+    if !options.network_fs && is_remote_filesystem(path) {
+        conflicts.push(format!(
+            "{} is on a network filesystem, consider --network-fs",
+            path.display()
+        ));
+    }
+
+    conflicts
+}
+
+/// Answers a sender's dry run with the decision on its file and, if accepted, the conflicts
+/// found at the destination.
+///
+/// `output_path` is where files are received, `None` for files received in memory.
+fn answer_dry_run<S: Write>(
+    session: &mut Session<S>,
+    output_path: Option<&Path>,
+    options: &ReceiveOptions,
+) -> Result<(), SendFileError> {
+    info!(
+        "Answering dry run of {} for {:?}",
+        session.sender_addr, session.file_name
+    );
+    let (accepted, reason) = match output_path {
+        Some(path) => {
+            let default_path = determine_final_path(path, &session.file_name);
+            plan_receive(session, default_path, options).answer()
+        }
+        // Files received in memory have no destination to conflict with
+        None => {
+            let default_path = PathBuf::from(&session.file_name);
+            let (_, rejection) = decide_offer(session, default_path, options);
+            (
+                rejection.is_none(),
+                rejection.map(|r| r.to_string()).unwrap_or_default(),
+            )
+        }
+    };
+    send_offer_response(session, accepted, reason)
+}
+
+/// Downloads every block of the session into `sink` over concurrent data connections.
+///
+/// `display_path` only names the destination in logs. With `is_existing_file`, blocks already
+/// in the sink are verified with the sender and only mismatching ones are downloaded. Fails with
+/// [SendFileError::Cancelled] if `control` cancels the transfer, blocks already in the sink are
+/// left in place.
+///
+/// With [ReceiveOptions::auto_retry], blocks still missing once every connection ended are
+/// downloaded in further rounds, see [wait_for_retry].
+fn run_transfer<S: BlockDownload>(
+    session: &mut Session<S>,
+    sink: &dyn BlockSink,
+    display_path: &Path,
+    is_existing_file: bool,
+    resume_path: Option<&Path>,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<TransferStats, SendFileError> {
+    let reuse_blocks = session.features.block_hashes && options.profile.buffers_beyond_block();
+    let block_store = match &options.block_store {
+        Some(root) if reuse_blocks => match BlockStore::open(root) {
+            Ok(store) => Some(store.with_max_size(options.block_store_max_size)),
+            Err(e) => {
+                warn!(
+                    "Block store {:?} unavailable, not reusing blocks: {}",
+                    root, e
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let written_blocks = match resume_path {
+        Some(path) if is_existing_file => load_progress(session, path)?,
+        _ => None,
+    };
+
+    let mut state = ReceiverState {
+        is_existing_file,
+        written_blocks,
+        block_store,
+        ..receiver_state(session, sink, display_path, options, control)
+    };
+
+    let started = Instant::now();
+    let total_blocks = state.received_blocks.len() as u32;
+    let ranges = split_blocks_into_ranges(total_blocks, session.concurrency);
+    let result = S::download_blocks(session, &mut state, &ranges, options, control);
+    if let Some(path) = resume_path {
+        save_progress(&state, path);
+    }
+    if result.is_err() && control.is_expired() {
+        send_time_limit_abort(session, options);
+    } else if result.is_err() && control.is_cancelled_here() && session.features.cancel {
+        let session_id = session.session_id();
+        send_cancel(
+            &mut session.stream,
+            session.expected_hash,
+            session.protocol_version,
+            session_id.as_ref(),
+        );
+    }
+    result?;
+
+    let unreadable_blocks = std::mem::take(&mut *lock_unreadable(&state))
+        .into_iter()
+        .collect();
+    Ok(TransferStats {
+        bytes_received: state.bytes_received.load(Ordering::SeqCst),
+        bytes_reused: state.bytes_reused.load(Ordering::SeqCst),
+        block_store: state.block_store,
+        unreadable_blocks,
+        elapsed: started.elapsed(),
+        bottleneck: state.control.timings().snapshot().bottleneck(),
+    })
+}
+
+/// Builds the state of a transfer of the session into `sink`, with no block received yet and
+/// neither an existing file nor a block store to reuse blocks from.
+fn receiver_state<'a, S>(
+    session: &Session<S>,
+    sink: &'a dyn BlockSink,
+    display_path: &Path,
+    options: &ReceiveOptions,
+    control: &'a TransferControl,
+) -> ReceiverState<'a> {
+    let total_blocks = match &session.bundle {
+        Some(bundle) => bundle.total_blocks(),
+        None => session.total_size.div_ceil(session.block_size as u64) as u32,
+    };
+    let received_blocks: Vec<AtomicBool> =
+        (0..total_blocks).map(|_| AtomicBool::new(false)).collect();
+
+    ReceiverState {
+        file_hash: session.expected_hash,
+        total_size: session.total_size,
+        block_size: session.block_size,
+        _total_blocks: total_blocks,
+        sender_addr: session.sender_addr,
+        received_blocks,
+        bytes_received: AtomicU64::new(0),
+        file_path: display_path.to_path_buf(),
+        is_existing_file: false,
+        written_blocks: None,
+        sink,
+        block_store: None,
+        bytes_reused: AtomicU64::new(0),
+        control,
+        keepalive: options.keepalive,
+        socket: options.socket,
+        heartbeat: session_heartbeat(session, options),
+        best_effort: options.best_effort,
+        unreadable_blocks: Mutex::new(BTreeMap::new()),
+        conn_hello: session.conn_hello.clone(),
+        protocol_version: session.protocol_version,
+        tls: session.tls.clone(),
+        noise: session.stream.peer().cloned(),
+        // Blocks of a directory are requested by file, see [crate::stream::bundle]
+        udp_fec: session.features.udp_fec
+            && options.profile.buffers_beyond_block()
+            && session.bundle.is_none(),
+        zero_blocks: session.features.zero_blocks && session.bundle.is_none(),
+        batching: session.features.batching,
+        block_bitmap: session.features.block_bitmap && session.bundle.is_none(),
+        push_stream: session.features.push_stream && session.bundle.is_none(),
+        range_request: session.features.range_request && session.bundle.is_none(),
+        cancel: session.features.cancel,
+        goodbye: session.features.goodbye,
+        pipeline_depth: options.pipeline_depth.max(1) as usize,
+        compression: session.features.compression,
+        checksum: session.features.checksum,
+        bundle: session.bundle.clone(),
+        buffer_size: options.profile.message_buffer_size(session.block_size),
+        retry_after: Mutex::new(None),
+    }
+}
+
+/// Returns the heartbeats of the session's connections, `None` unless both peers enabled them.
+fn session_heartbeat<S>(session: &Session<S>, options: &ReceiveOptions) -> Option<Heartbeat> {
+    options.heartbeat.filter(|_| session.features.heartbeat)
+}
+
+/// Handshake connection of a [Session], which decides how its blocks are downloaded.
+trait BlockDownload: Connection + Sized {
+    /// Downloads the missing blocks of `ranges`, one data connection per range.
+    fn download_blocks(
+        session: &mut Session<Self>,
+        state: &mut ReceiverState,
+        ranges: &[std::ops::Range<u32>],
+        options: &ReceiveOptions,
+        control: &TransferControl,
+    ) -> Result<(), SendFileError>;
+
+    /// Reports the transfer complete without downloading any block, every block of `state`
+    /// being received already.
+    fn report_complete(
+        session: &mut Session<Self>,
+        state: &ReceiverState,
+        options: &ReceiveOptions,
+    ) -> Result<(), SendFileError>;
+}
+
+impl BlockDownload for MaybeTlsStream {
+    fn download_blocks(
+        session: &mut Session<Self>,
+        state: &mut ReceiverState,
+        ranges: &[std::ops::Range<u32>],
+        options: &ReceiveOptions,
+        control: &TransferControl,
+    ) -> Result<(), SendFileError> {
+        if options.single_port {
+            return run_single_port(session, state, ranges, options, control);
+        }
+        run_rounds(session, state, ranges, options, control)
+    }
+
+    fn report_complete(
+        session: &mut Session<Self>,
+        state: &ReceiverState,
+        options: &ReceiveOptions,
+    ) -> Result<(), SendFileError> {
+        if options.single_port {
+            return transfer_range(&mut session.stream, state, 0, 0, false);
+        }
+        run_connection(state, 0, 0)
+    }
+}
+
+/// The handshake connection is the only data connection, so the session has a single range and
+/// can't be retried once the connection is lost.
+impl<T: Read + Write> BlockDownload for Preconnected<T> {
+    fn download_blocks(
+        session: &mut Session<Self>,
+        state: &mut ReceiverState,
+        ranges: &[std::ops::Range<u32>],
+        _options: &ReceiveOptions,
+        control: &TransferControl,
+    ) -> Result<(), SendFileError> {
+        for range in ranges {
+            match transfer_range(&mut session.stream, state, range.start, range.end, false) {
+                Err(_) if control.is_cancelled() => return Err(control.cancellation()),
+                result => result?,
+            }
+        }
+        if !state.best_effort
+            && let Some((seq, reason)) = lock_unreadable(state).pop_first()
+        {
+            return Err(SendFileError::BlockUnreadable { seq, reason });
+        }
+        Ok(())
+    }
+
+    fn report_complete(
+        session: &mut Session<Self>,
+        state: &ReceiverState,
+        _options: &ReceiveOptions,
+    ) -> Result<(), SendFileError> {
+        transfer_range(&mut session.stream, state, 0, 0, false)
+    }
+}
+
+/// Runs data connection rounds until every block is received. With
+/// [auto_retry](ReceiveOptions::auto_retry), lost connections are retried until the budget is
+/// spent.
+fn run_rounds(
+    session: &mut Session,
+    state: &mut ReceiverState,
+    ranges: &[std::ops::Range<u32>],
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<(), SendFileError> {
+    let deadline = options.auto_retry.map(|budget| Instant::now() + budget);
+    let mut retry_delay = Duration::from_millis(INITIAL_ROUND_DELAY_MS);
+
+    loop {
+        let missing_before = count_missing_blocks(state);
+        // Nothing but progress goes over the handshake connection until the receipt
+        let mut reporter = session.features.control_channel.then(|| {
+            ProgressReporter::new(
+                session.expected_hash,
+                session.protocol_version,
+                session.session_id(),
+            )
+        });
+        match reporter.as_mut() {
+            Some(reporter) => {
+                reporter.report_while(&mut session.stream, control, || {
+                    run_round(state, ranges, options)
+                });
+                reporter.check_aborted()?;
+            }
+            None => {
+                let heartbeat = state.heartbeat;
+                let session_id = state.session_id();
+                ping_while(
+                    &mut session.stream,
+                    heartbeat,
+                    Side::Receiver,
+                    state.protocol_version,
+                    session_id.as_ref(),
+                    || run_round(state, ranges, options),
+                );
+            }
+        }
+
+        if control.is_cancelled() {
+            return Err(control.cancellation());
+        }
+        if !options.best_effort
+            && let Some((seq, reason)) = lock_unreadable(state).pop_first()
+        {
+            return Err(SendFileError::BlockUnreadable { seq, reason });
+        }
+        let missing = count_missing_blocks(state);
+        if missing == 0 {
+            // Whatever became of the data connection that reported it
+            if let Some(reporter) = reporter.as_mut()
+                && let Err(e) = reporter.complete(&mut session.stream)
+            {
+                warn!(
+                    "Failed to report the transfer complete on the control channel: {}",
+                    e
+                );
+            }
+            break;
+        }
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining == Some(Duration::ZERO) {
+            error!("Retry budget exhausted with {} blocks missing", missing);
+        }
+        let Some(remaining) = remaining.filter(|remaining| !remaining.is_zero()) else {
+            // A cancelled sender drops the data connections before it says so
+            if let Some(reporter) = reporter.as_mut() {
+                reporter.linger(&mut session.stream, control)?;
+            }
+            break;
+        };
+
+        if missing < missing_before {
+            // The connections made progress before dropping, the network is likely back soon
+            retry_delay = Duration::from_millis(INITIAL_ROUND_DELAY_MS);
+        }
+        // A sender that asked to be retried later knows better when it can serve the blocks
+        let delay = take_retry_after(state).unwrap_or(retry_delay);
+        warn!(
+            "Lost every connection with {} blocks missing, retrying in {:?}",
+            missing, delay
+        );
+        wait_for_retry(
+            session,
+            delay.min(remaining),
+            options,
+            control,
+            reporter.as_mut(),
+        )?;
+        state.sender_addr = session.sender_addr;
+        state.conn_hello = session.conn_hello.clone();
+        retry_delay = (retry_delay * 2).min(Duration::from_millis(MAX_ROUND_DELAY_MS));
+    }
+    Ok(())
+}
+
+/// Downloads the blocks of `ranges` from a sender with
+/// [single_port](ReceiveOptions::single_port): the first range over the handshake connection,
+/// every other range with missing blocks over a connection the sender opens to the handshake
+/// listener. Once every connection ended, the transfer is reported complete on the handshake
+/// connection.
+fn run_single_port(
+    session: &mut Session,
+    state: &mut ReceiverState,
+    ranges: &[std::ops::Range<u32>],
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<(), SendFileError> {
+    let Some((first, others)) = ranges.split_first() else {
+        return Ok(());
+    };
+    let state = &*state;
+    let others = pending_ranges(state, others);
+    let Session {
+        stream, listener, ..
+    } = session;
+    let result = thread::scope(|scope| {
+        let first = thread::Builder::new()
+            .name(thread_name("recv", 0))
+            .spawn_scoped(scope, || {
+                options.workers.pin_current_thread(0);
+                contain_panic(state.control, || {
+                    serve_range(stream, state, first.start, first.end)
+                })
+            })?;
+        for (index, range) in others.into_iter().enumerate() {
+            let tcp = match accept_data_connection(listener.as_ref(), state, options, control) {
+                Ok(tcp) => tcp,
+                Err(e) => {
+                    error!("No data connection for range {:?}: {}", range, e);
+                    break;
+                }
+            };
+            let spawn_result = thread::Builder::new()
+                .name(thread_name("recv", index + 1))
+                .spawn_scoped(scope, move || {
+                    // Decompression happens on the connection thread
+                    options.workers.pin_current_thread(index + 1);
+                    let result = contain_panic(state.control, || {
+                        run_data_connection(tcp, state, range.start, range.end)
+                    })
+                    .context(|| connection_context(state));
+                    if let Err(e) = result {
+                        error!("Connection error in range {:?}: {}", range, e);
+                    }
+                });
+            if let Err(e) = spawn_result {
+                error!("Failed to spawn receive thread {}: {}", index + 1, e);
+            }
+        }
+        first
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    });
+
+    if control.is_cancelled() {
+        return Err(control.cancellation());
+    }
+    result?;
+    if !options.best_effort
+        && let Some((seq, reason)) = lock_unreadable(state).pop_first()
+    {
+        return Err(SendFileError::BlockUnreadable { seq, reason });
+    }
+    if is_transfer_complete(state) {
+        send_transfer_complete(stream, state)?;
+        if state.goodbye {
+            say_goodbye(stream, state);
+        }
+    }
+    Ok(())
+}
+
+/// Waits on `listener` for a data connection of the sender of `state`, for at most
+/// [ReceiveOptions::handshake_timeout]. Connections from other hosts are dropped.
+fn accept_data_connection(
+    listener: Option<&TcpListener>,
+    state: &ReceiverState,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+) -> Result<TcpStream, SendFileError> {
+    let Some(listener) = listener else {
+        return Err(SendFileError::ConnectionFailed(String::from(
+            "Not listening for data connections",
+        )));
+    };
+    let deadline = Instant::now() + options.handshake_timeout;
+    loop {
+        control.checkpoint()?;
+        match listener.accept() {
+            Ok((tcp, addr)) if addr.ip() == state.sender_addr.ip() => {
+                tcp.set_nonblocking(false)?;
+                info!("Accepted data connection from {}", addr);
+                return Ok(tcp);
+            }
+            Ok((_, addr)) => warn!("Dropping connection from {}: not the sender", addr),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(SendFileError::Io(std::io::ErrorKind::TimedOut.into()));
+                }
+                thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Records the blocks received so far at `path`, so an interrupted transfer can resume without
+/// verifying blocks that were never written. The record is removed once the file is complete.
+fn save_progress(state: &ReceiverState, path: &Path) {
+    if is_transfer_complete(state) {
+        if let Err(e) = std::fs::remove_file(path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove resume state {:?}: {}", path, e);
+        }
+        return;
+    }
+
+    let resume_state = ResumeState::new(
+        &state.file_hash,
+        state.total_size,
+        state.block_size,
+        state
+            .received_blocks
+            .iter()
+            .map(|received| received.load(Ordering::SeqCst)),
+    );
+    match resume_state.save(path) {
+        Ok(()) => info!("Saved transfer progress to {:?}", path),
+        Err(e) => warn!("Failed to save transfer progress to {:?}: {}", path, e),
+    }
+}
 
-    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
-    let result = read_next_payload::<SenderMessageV1, _>(&mut stream, &mut buffer, 0)?;
-    let handshake = match result.message {
-        SenderMessageV1::Handshake(h) => h,
-        _ => {
-            return Err(SendFileError::UnexpectedMessage {
-                received: format!("{:?}", result.message),
-                expected: String::from("Handshake"),
-            });
+/// Reads the blocks already written to the output file from the resume state at `path`, mapped
+/// onto the block size of `session`.
+///
+/// Returns `None` if there is no usable state, in which case every existing block is verified.
+fn load_progress<S>(session: &Session<S>, path: &Path) -> Result<Option<Vec<bool>>, SendFileError> {
+    let resume_state = match ResumeState::load(path) {
+        Ok(Some(resume_state)) => resume_state,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            warn!("Ignoring unreadable resume state {:?}: {}", path, e);
+            return Ok(None);
         }
     };
+    if !resume_state.is_for(&session.expected_hash) {
+        warn!("Ignoring resume state {:?} of another file", path);
+        return Ok(None);
+    }
+    if resume_state.block_size != session.block_size {
+        info!(
+            "Transfer was started with {} byte blocks, mapping its progress onto {} byte blocks",
+            resume_state.block_size, session.block_size
+        );
+    }
 
-    let expected_hash: [u8; 32] = handshake.file_hash.try_into().map_err(|_| {
-        SendFileError::Io(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid file hash length",
-        ))
-    })?;
+    resume_state
+        .received_blocks(session.total_size, session.block_size)
+        .map(Some)
+        .map_err(|e| SendFileError::ResumeFailed(format!("{}, delete {:?} to start over", e, path)))
+}
 
-    info!(
-        "Received handshake: file={}, size={}, block_size={}, concurrency={}",
-        handshake.file_name, handshake.total_size, handshake.block_size, handshake.concurrency
+/// Fails with [SendFileError::IncompleteFile] if blocks were zero-filled in best-effort mode, in
+/// which case the file can't match its hash.
+///
+/// The holes are recorded in a [DamageReport] next to the file at `path`, if any.
+fn check_skipped_blocks<S>(
+    session: &Session<S>,
+    stats: &TransferStats,
+    path: Option<&Path>,
+) -> Result<(), SendFileError> {
+    if stats.unreadable_blocks.is_empty() {
+        return Ok(());
+    }
+    let report = DamageReport::new(
+        session.file_name.clone(),
+        &session.expected_hash,
+        session.total_size,
+        session.block_size,
+        &stats.unreadable_blocks,
     );
+    warn!(
+        "{} of {:?} could not be read by the sender and were zero-filled",
+        Size(report.missing_bytes()),
+        session.file_name
+    );
+    if let Some(path) = path {
+        let report_path = damage_report_path(path);
+        match report.save(&report_path) {
+            Ok(()) => warn!("Damage report written to {:?}", report_path),
+            Err(e) => error!("Failed to write damage report {:?}: {}", report_path, e),
+        }
+    }
+    Err(SendFileError::IncompleteFile {
+        ranges: report.ranges(),
+    })
+}
 
-    let final_path = determine_final_path(path, handshake.file_name);
-    info!("Output file path: {:?}", final_path);
+/// Runs one data connection per range that still has missing blocks, until they all end.
+fn run_round(state: &ReceiverState, ranges: &[std::ops::Range<u32>], options: &ReceiveOptions) {
+    thread::scope(|scope| {
+        for (index, range) in pending_ranges(state, ranges).into_iter().enumerate() {
+            let spawn_result = thread::Builder::new()
+                .name(thread_name("recv", index))
+                .spawn_scoped(scope, move || {
+                    // Decompression happens on the connection thread
+                    options.workers.pin_current_thread(index);
+                    let result = contain_panic(state.control, || {
+                        run_connection(state, range.start, range.end)
+                    });
+                    if let Err(e) = result {
+                        error!("Connection error in range {:?}: {}", range, e);
+                        note_retry_after(state, &e);
+                    }
+                });
 
-    // Use the minimum of sender's and receiver's concurrency to avoid overwhelming the sender
-    concurrency = concurrency.min(handshake.concurrency);
+            if let Err(e) = spawn_result {
+                error!("Failed to spawn receive thread {}: {}", index, e);
+            }
+        }
+    });
+}
 
-    let total_blocks = handshake.total_size.div_ceil(handshake.block_size as u64) as u32;
+/// Returns the ranges that still have missing blocks.
+fn pending_ranges(
+    state: &ReceiverState,
+    ranges: &[std::ops::Range<u32>],
+) -> Vec<std::ops::Range<u32>> {
+    ranges
+        .iter()
+        .filter(|range| {
+            state.received_blocks[range.start as usize..range.end as usize]
+                .iter()
+                .any(|received| !received.load(Ordering::SeqCst))
+        })
+        .cloned()
+        .collect()
+}
 
-    let is_existing_file = final_path.exists();
+fn count_missing_blocks(state: &ReceiverState) -> usize {
+    state
+        .received_blocks
+        .iter()
+        .filter(|received| !received.load(Ordering::SeqCst))
+        .count()
+}
 
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&final_path)?;
+/// Notes the wait the sender asked for if a data connection failed with `error` because of
+/// [SendFileError::SenderUnavailable], keeping the longest of the round.
+fn note_retry_after(state: &ReceiverState, error: &SendFileError) {
+    if let SendFileError::SenderUnavailable { retry_after, .. } = error.root() {
+        let mut noted = lock_retry_after(state);
+        *noted = Some(noted.map_or(*retry_after, |noted| noted.max(*retry_after)));
+    }
+}
 
-    file.set_len(handshake.total_size)?;
+/// Returns the wait the sender asked for in the last round, if any, and forgets it.
+fn take_retry_after(state: &ReceiverState) -> Option<Duration> {
+    lock_retry_after(state).take()
+}
 
-    let received_blocks: Vec<AtomicBool> =
-        (0..total_blocks).map(|_| AtomicBool::new(false)).collect();
+fn lock_retry_after<'a>(state: &'a ReceiverState) -> std::sync::MutexGuard<'a, Option<Duration>> {
+    state.retry_after.lock().unwrap_or_else(|e| e.into_inner())
+}
 
-    let state = Arc::new(ReceiverState {
-        file_hash: expected_hash,
-        _total_size: handshake.total_size,
-        block_size: handshake.block_size,
-        _total_blocks: total_blocks,
-        sender_addr,
-        received_blocks,
-        bytes_received: AtomicU64::new(0),
-        file_path: final_path.clone(),
-        is_existing_file,
-    });
+/// Waits `delay` before the next retry round, reporting progress with `reporter` if the
+/// session has a control channel, see [crate::stream::control].
+///
+/// Meanwhile a restarted sender offering the same file can take over the session: its handshake
+/// is accepted and the next round connects to it, resuming from the blocks already received.
+fn wait_for_retry(
+    session: &mut Session,
+    delay: Duration,
+    options: &ReceiveOptions,
+    control: &TransferControl,
+    mut reporter: Option<&mut ProgressReporter>,
+) -> Result<(), SendFileError> {
+    let until = Instant::now() + delay;
+    while Instant::now() < until {
+        control.checkpoint()?;
 
-    let ranges = split_blocks_into_ranges(total_blocks, concurrency);
+        // Keeps the sender waiting for the next round
+        let reported = reporter
+            .as_deref_mut()
+            .map(|reporter| reporter.report_due(&mut session.stream, control));
+        match reported {
+            Some(Err(e @ SendFileError::AbortedBySender(_))) => return Err(e),
+            Some(Err(e)) => {
+                warn!("Stopped reporting progress on the control channel: {}", e);
+                reporter = None;
+            }
+            _ => {}
+        }
 
-    thread::scope(|scope| {
-        for range in ranges {
-            let state = state.clone();
-            scope.spawn(move || {
-                if let Err(e) = run_connection(state, range.start, range.end) {
-                    error!("Connection error in range {:?}: {}", range, e);
+        let accepted = match &session.listener {
+            Some(listener) => listener.accept(),
+            None => Err(std::io::ErrorKind::WouldBlock.into()),
+        };
+        match accepted {
+            Ok((stream, sender_addr)) => {
+                match read_session(stream, sender_addr, options, control) {
+                    Ok(mut offer) => {
+                        if refuse_unpaired(&mut offer, options).is_none()
+                            && rejoin_session(session, offer)?
+                        {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) => warn!("Failed to read handshake from {}: {}", sender_addr, e),
                 }
-            });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+            }
+            Err(e) => warn!("Failed to accept a new handshake: {}", e),
         }
-    });
+    }
+    Ok(())
+}
 
-    let actual_hash =
-        get_file_blake3_hash(&final_path).expect("Failed to compute file hash after transfer");
+/// Hands `session` over to the sender of `offer` if it offers the same file with the same block
+/// size, otherwise turns the offer down. Dry runs and probes are always turned down.
+///
+/// Returns whether the session was taken over.
+fn rejoin_session<S: Write>(
+    session: &mut Session<S>,
+    mut offer: Session<S>,
+) -> Result<bool, SendFileError> {
+    if offer.features.dry_run
+        || offer.features.probe
+        || offer.expected_hash != session.expected_hash
+        || offer.block_size != session.block_size
+    {
+        info!(
+            "Turning down {:?} from {}, still receiving {:?}",
+            offer.file_name, offer.sender_addr, session.file_name
+        );
+        if offer.features.offer_response {
+            let rejection = Rejection::new(RejectReasonV1::Busy, "Receiver is busy");
+            answer_handshake(&mut offer, Some(&rejection))?;
+        }
+        return Ok(false);
+    }
 
-    let is_file_integrity_ok = actual_hash
-        .iter()
-        .zip(expected_hash.iter())
-        .all(|(a, b)| a == b);
+    info!(
+        "Sender {} took over the transfer of {:?}",
+        offer.sender_addr, session.file_name
+    );
+    if offer.features.offer_response {
+        answer_handshake(&mut offer, None)?;
+    }
+    session.stream = offer.stream;
+    session.sender_addr = offer.sender_addr;
+    session.sender_key = offer.sender_key;
+    session.features = offer.features;
+    session.metadata = offer.metadata;
+    session.conn_hello = offer.conn_hello;
+    session.protocol_version = offer.protocol_version;
+    session.tls = offer.tls;
+    Ok(true)
+}
 
-    if is_file_integrity_ok {
+/// Fails with [SendFileError::IntegrityCheckFailed] unless the received content hashes to the
+/// hash announced by the sender.
+fn verify_integrity(expected: [u8; 32], actual: [u8; 32]) -> Result<(), SendFileError> {
+    if actual == expected {
         info!("File integrity verified successfully");
+        Ok(())
     } else {
-        return Err(SendFileError::IntegrityCheckFailed {
-            expected: expected_hash,
-            received: actual_hash,
-        });
+        Err(SendFileError::IntegrityCheckFailed {
+            expected,
+            received: actual,
+        })
+    }
+}
+
+/// Wraps up a verified transfer: returns the receipt, logs the summary and prunes the block
+/// store.
+///
+/// A sender that negotiated receipts is always answered, with a `NoReceipt` if the receiver has
+/// no identity to sign one with, so it doesn't wait for one.
+fn finish_session<S: Write>(
+    session: &mut Session<S>,
+    stats: &TransferStats,
+    display_path: &Path,
+    options: &ReceiveOptions,
+) {
+    if session.features.receipt
+        && let Err(e) = send_receipt(
+            &mut session.stream,
+            options.identity_path.as_deref(),
+            session.expected_hash,
+            session.total_size,
+            session.protocol_version,
+            session.conn_hello.as_ref().map(|hello| &hello.session_id),
+        )
+    {
+        warn!("Failed to send delivery receipt: {}", e);
     }
 
-    let bytes_received = state.bytes_received.load(Ordering::SeqCst);
     info!(
-        "Transfer complete: {} bytes received for file {:?}",
-        bytes_received, state.file_path
+        "Transfer complete: {} received for file {:?} in {} ({}) ({})",
+        Size(stats.bytes_received),
+        display_path,
+        Elapsed(stats.elapsed),
+        Rate(stats.bytes_received as f64 / stats.elapsed.as_secs_f64().max(0.001)),
+        session.features
     );
-
-    Ok(())
+    if let Some(skew) = session.clock_skew {
+        info!("Clock skew: {}", skew);
+    }
+    if let Some(stage) = stats.bottleneck {
+        info!("Hint: {}", stage.hint());
+    }
+    if let Some(store) = &stats.block_store {
+        info!("{} reused from the block store", Size(stats.bytes_reused));
+        match store.gc(options.block_store_max_size) {
+            Ok(evicted) if evicted.blocks > 0 => info!(
+                "Evicted {} blocks ({}) from the block store",
+                Count(evicted.blocks),
+                Size(evicted.bytes)
+            ),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to prune block store {:?}: {}", store.root(), e),
+        }
+    }
 }
 
-struct ReceiverState {
+struct ReceiverState<'a> {
     file_hash: [u8; 32],
-    _total_size: u64,
+    total_size: u64,
     block_size: u32,
     _total_blocks: u32,
     sender_addr: SocketAddr,
@@ -165,6 +2772,126 @@ struct ReceiverState {
     bytes_received: AtomicU64,
     file_path: PathBuf,
     is_existing_file: bool,
+    /// Blocks of the existing file written by an interrupted transfer, if it left a resume
+    /// state. Other blocks are downloaded without being verified first.
+    written_blocks: Option<Vec<bool>>,
+    /// Destination every connection writes its blocks to.
+    sink: &'a dyn BlockSink,
+    /// Local store blocks are reused from and added to, if enabled and supported by the sender.
+    block_store: Option<BlockStore>,
+    /// Bytes copied from the block store instead of being downloaded.
+    bytes_reused: AtomicU64,
+    /// Pause and cancellation flags of the transfer, and its progress.
+    control: &'a TransferControl,
+    /// TCP keepalive enabled on every data connection.
+    keepalive: Option<Keepalive>,
+    /// Socket options of every data connection.
+    socket: SocketTuning,
+    /// Heartbeats on every data connection, `None` unless negotiated.
+    heartbeat: Option<Heartbeat>,
+    /// Whether blocks the sender can't read are skipped rather than aborting the transfer.
+    best_effort: bool,
+    /// Blocks the sender could not read, with the reason it gave.
+    unreadable_blocks: Mutex<BTreeMap<u32, String>>,
+    /// Hello every data connection opens with, `None` if the sender does not expect one.
+    conn_hello: Option<ConnHelloV1>,
+    /// Protocol version every message to the sender is framed with.
+    protocol_version: u8,
+    /// Sender whose certificate data connections check, `None` for plain connections.
+    tls: Option<TlsPeer>,
+    /// Sender of the Noise channel data connections are encrypted for, `None` without one.
+    noise: Option<NoisePeer>,
+    /// Whether blocks are requested as UDP datagrams, see [crate::stream::udp].
+    udp_fec: bool,
+    /// Whether blocks of zeros are requested to be only named, see
+    /// [Capabilities::ZERO_BLOCKS](crate::capabilities::Capabilities::ZERO_BLOCKS).
+    zero_blocks: bool,
+    /// Whether control messages are batched, see
+    /// [Capabilities::BATCHING](crate::capabilities::Capabilities::BATCHING).
+    batching: bool,
+    /// Whether the blocks of `written_blocks` are named to the sender rather than verified, see
+    /// [Capabilities::BLOCK_BITMAP](crate::capabilities::Capabilities::BLOCK_BITMAP).
+    block_bitmap: bool,
+    /// Block requests each data connection keeps outstanding, see
+    /// [ReceiveOptions::pipeline_depth].
+    pipeline_depth: usize,
+    /// Whether the blocks of a range are streamed by the sender rather than requested one by
+    /// one, see [Capabilities::PUSH_STREAM](crate::capabilities::Capabilities::PUSH_STREAM).
+    push_stream: bool,
+    /// Whether runs of consecutive blocks are requested with a single message, see
+    /// [Capabilities::RANGE_REQUEST](crate::capabilities::Capabilities::RANGE_REQUEST).
+    range_request: bool,
+    /// Whether the sender is told on the data connections when the transfer is cancelled, see
+    /// [Capabilities::CANCEL](crate::capabilities::Capabilities::CANCEL).
+    cancel: bool,
+    /// Whether data connections end with a goodbye, see [Capabilities::GOODBYE].
+    goodbye: bool,
+    /// Codec compressed blocks are decoded with, see [crate::stream::compress].
+    compression: CompressionCodec,
+    /// Algorithm blocks are checksummed with, see [crate::stream::checksum].
+    checksum: ChecksumAlgorithm,
+    /// Files of the offered directory the blocks belong to, `None` for a single file, see
+    /// [crate::stream::bundle].
+    bundle: Option<Arc<Bundle>>,
+    /// Size of the buffers every data connection reads messages and decodes blocks into, see
+    /// [ReceiveProfile::message_buffer_size].
+    buffer_size: usize,
+    /// Longest wait a sender asked for on a data connection that failed this round, waited
+    /// before the next one instead of the backoff, see [crate::stream::retry_after].
+    retry_after: Mutex<Option<Duration>>,
+}
+
+impl ReceiverState<'_> {
+    /// Returns the id of the sender's session, `None` if it does not expect hellos.
+    fn session_id(&self) -> Option<[u8; SESSION_ID_SIZE]> {
+        self.conn_hello.as_ref().map(|hello| hello.session_id)
+    }
+}
+
+/// Signs a receipt for the verified file with the identity at `identity_path` and sends it to
+/// the sender on the handshake connection.
+///
+/// Without an identity, or if it can't be loaded, a `NoReceipt` saying why is sent instead.
+fn send_receipt<W: Write>(
+    stream: &mut W,
+    identity_path: Option<&Path>,
+    file_hash: [u8; 32],
+    bytes: u64,
+    protocol_version: u8,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> Result<(), SendFileError> {
+    let identity = match identity_path.map(Identity::load_or_generate) {
+        Some(Ok(identity)) => Ok(identity),
+        Some(Err(e)) => Err(format!("identity unavailable: {}", e)),
+        None => Err(String::from("receipts are disabled")),
+    };
+    let msg = match identity {
+        Ok(identity) => {
+            let receipt = sign_receipt(&identity, file_hash, bytes);
+            info!(
+                "Sending delivery receipt signed by {}",
+                to_hex(&receipt.receiver_key)
+            );
+            ReceiverMessageV1::Receipt(receipt)
+        }
+        Err(reason) => {
+            info!("Sending no delivery receipt: {}", reason);
+            ReceiverMessageV1::NoReceipt(NoReceiptV1 { file_hash, reason })
+        }
+    };
+
+    let mut buffer = vec![0u8; 512];
+    send_message(stream, &msg, &mut buffer, protocol_version, session_id)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Builds the error reported when the peer lacks a `required` capability.
+fn missing_capability(peer_version: &str, required: Capabilities) -> SendFileError {
+    SendFileError::MissingCapability {
+        peer_version: peer_version.to_string(),
+        capability: required.to_string(),
+    }
 }
 
 fn determine_final_path(output_path: &std::path::Path, file_name: &str) -> PathBuf {
@@ -193,101 +2920,577 @@ fn split_blocks_into_ranges(total_blocks: u32, concurrency: u16) -> Vec<std::ops
 }
 
 fn run_connection(
-    state: Arc<ReceiverState>,
+    state: &ReceiverState,
     range_start: u32,
     range_end: u32,
 ) -> Result<(), SendFileError> {
     // Connect to the sender for this thread's assigned block range
-    let mut stream = TcpStream::connect((state.sender_addr.ip(), TRANSFER_PORT))?;
-    stream.set_nodelay(true)?;
+    let address = SocketAddr::new(state.sender_addr.ip(), TRANSFER_PORT);
+    let tcp = TcpStream::connect(address)
+        .context(|| ErrorContext::new("connecting").with_peer(address))?;
+    run_data_connection(tcp, state, range_start, range_end).context(|| connection_context(state))
+}
 
-    if state.is_existing_file {
-        verify_existing_blocks(&mut stream, &state, range_start, range_end)?;
-    } else {
-        download_missing_blocks(&mut stream, &state, range_start, range_end)?;
+/// Returns the context of errors on a data connection to the sender of `state`.
+fn connection_context(state: &ReceiverState) -> ErrorContext {
+    ErrorContext::new("receiving").with_peer(state.sender_addr)
+}
+
+/// Secures the data connection `tcp` like the handshake connection, then receives the missing
+/// blocks of `range_start..range_end` over it.
+fn run_data_connection(
+    tcp: TcpStream,
+    state: &ReceiverState,
+    range_start: u32,
+    range_end: u32,
+) -> Result<(), SendFileError> {
+    state.socket.apply(&tcp)?;
+    configure_keepalive(&tcp, state.keepalive.as_ref());
+    state.control.register(&tcp);
+    let stream = match &state.tls {
+        Some(peer) => peer.connect(tcp)?,
+        None => MaybeTlsStream::Plain(tcp),
+    };
+    let mut stream = NoiseStream::new(stream);
+    if let Some(peer) = &state.noise {
+        peer.connect(
+            &mut stream,
+            state.protocol_version,
+            state.session_id().as_ref(),
+        )?;
+    }
+    let Some(heartbeat) = state.heartbeat else {
+        return transfer_range(&mut stream, state, range_start, range_end, true);
+    };
+    let mut stream = Heartbeating::new(stream, heartbeat, Side::Receiver);
+    stream.enable_heartbeat(state.protocol_version, state.session_id());
+    // Tells the sender it may ping this connection too
+    stream.ping()?;
+    transfer_range(&mut stream, state, range_start, range_end, true)
+}
+
+/// Receives the missing blocks of `range_start..range_end` over a data connection, reporting the
+/// transfer complete once every block is received.
+///
+/// # Arguments
+///
+/// * `stream` - Data connection to the sender.
+/// * `state` - State of the transfer.
+/// * `range_start` - First block of the range.
+/// * `range_end` - Block past the end of the range.
+/// * `dedicated` - Whether the connection was opened for the range alone, and says goodbye once
+///   it is received rather than only after reporting the transfer complete, see
+///   [Capabilities::GOODBYE].
+fn transfer_range<S: Connection>(
+    stream: &mut S,
+    state: &ReceiverState,
+    range_start: u32,
+    range_end: u32,
+    dedicated: bool,
+) -> Result<(), SendFileError> {
+    if let Err(e) = serve_range(stream, state, range_start, range_end) {
+        if state.cancel && state.control.is_cancelled_here() {
+            let session_id = state.session_id();
+            send_cancel(
+                stream,
+                state.file_hash,
+                state.protocol_version,
+                session_id.as_ref(),
+            );
+        }
+        return Err(e);
     }
 
-    if is_transfer_complete(&state) {
-        send_transfer_complete(&mut stream, &state)?;
+    let complete = is_transfer_complete(state);
+    if complete {
+        send_transfer_complete(stream, state)?;
     } else {
         info!(
             "Range {}-{} complete, but transfer not fully complete yet",
             range_start, range_end
         );
     }
+    if state.goodbye && (complete || dedicated) {
+        say_goodbye(stream, state);
+    }
 
     Ok(())
 }
 
-fn verify_existing_blocks(
-    stream: &mut TcpStream,
+/// Receives the missing blocks of `range_start..range_end` over a data connection, without
+/// reporting the transfer complete.
+fn serve_range<S: Read + Write>(
+    stream: &mut S,
     state: &ReceiverState,
     range_start: u32,
     range_end: u32,
 ) -> Result<(), SendFileError> {
-    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    state.control.trace().in_span("connection", || {
+        receive_range(stream, state, range_start, range_end)
+    })
+}
+
+/// Receives the missing blocks of `range_start..range_end` as [serve_range] does.
+fn receive_range<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    range_start: u32,
+    range_end: u32,
+) -> Result<(), SendFileError> {
+    if let Some(hello) = &state.conn_hello {
+        let msg = ReceiverMessageV1::ConnHello(hello.clone());
+        send_message(
+            stream,
+            &msg,
+            &mut [0u8; 64],
+            state.protocol_version,
+            state.session_id().as_ref(),
+        )?;
+    }
+    // Senders that don't negotiate algorithms use gzip and CRC-32
+    if (state.compression, state.checksum) != (CompressionCodec::Gzip, ChecksumAlgorithm::Crc32) {
+        debug!(
+            "Telling the sender to use {} compression and {} checksums",
+            state.compression, state.checksum
+        );
+        let msg = ReceiverMessageV1::Algorithms(AlgorithmsV1 {
+            compression: state.compression,
+            checksum: state.checksum,
+        });
+        send_message(
+            stream,
+            &msg,
+            &mut [0u8; 16],
+            state.protocol_version,
+            state.session_id().as_ref(),
+        )?;
+    }
+
+    match &state.written_blocks {
+        Some(written) if state.is_existing_file && state.block_bitmap => {
+            receive_missing_blocks(stream, state, written, range_start, range_end)
+        }
+        _ if state.is_existing_file => {
+            verify_existing_blocks(stream, state, range_start, range_end)
+        }
+        // Blocks sent over UDP or looked up in the store are requested one by one
+        _ if state.push_stream && !state.udp_fec && state.block_store.is_none() => {
+            stream_missing_blocks(stream, state, range_start, range_end)
+        }
+        _ => download_missing_blocks(stream, state, range_start, range_end),
+    }
+}
+
+/// Asks the sender to stream the blocks of `range_start..range_end` from the first missing one
+/// to the last, see [PushRangeV1], and receives them. Every window is acknowledged with the
+/// blocks that arrived damaged, which the sender sends again before the window after next.
+fn stream_missing_blocks<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    range_start: u32,
+    range_end: u32,
+) -> Result<(), SendFileError> {
+    let is_missing = |seq: &u32| !state.received_blocks[*seq as usize].load(Ordering::SeqCst);
+    let (Some(first), Some(last)) = (
+        (range_start..range_end).find(is_missing),
+        (range_start..range_end).rev().find(is_missing),
+    ) else {
+        return Ok(());
+    };
+    let push = PushRangeV1 {
+        file_hash: state.file_hash,
+        start_seq: first,
+        count: last + 1 - first,
+        window: PUSH_WINDOW,
+        sparse: state.zero_blocks,
+    };
+    let windows = push.windows();
+    info!(
+        "Streaming the blocks {}..{} in {} windows",
+        first,
+        last + 1,
+        windows
+    );
+    let mut message_buffer = vec![0u8; 128];
+    send_message(
+        stream,
+        &ReceiverMessageV1::PushRange(push),
+        &mut message_buffer,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )?;
+    stream.flush()?;
+
+    let mut buffer = AlignedBuffer::zeroed(state.buffer_size);
     let mut filled_len = 0;
-    let mut write_buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let mut write_buffer = AlignedBuffer::zeroed(state.buffer_size);
+    let mut inflater = BlockInflater::with_codec(state.compression);
+    let timings = state.control.timings();
+    let mut blocks = BlockSpans::new();
+    let mut attempts: BTreeMap<u32, u32> = BTreeMap::new();
+    let mut resent = Vec::new();
 
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&state.file_path)?;
+    for window in 0.. {
+        let mut missing = Vec::new();
+        // Each window is followed by the blocks the previous acknowledgement asked for again
+        for seq in push.window_blocks(window).chain(resent) {
+            state.control.checkpoint()?;
+            blocks.begin();
+            let result = timings.time(Stage::Network, || {
+                read_sender_message(
+                    stream,
+                    &mut buffer,
+                    filled_len,
+                    state.protocol_version,
+                    state.session_id().as_ref(),
+                )
+            })?;
+            let (next_idx, total_bytes_read) = (result.next_payload_index, result.total_bytes_read);
+            let stored = if is_missing(&seq) {
+                store_block_answer(state, seq, result.message, &mut write_buffer, &mut inflater)
+            } else {
+                // Received over another connection before this one was served
+                Ok(())
+            };
+            filled_len = match next_idx {
+                Some(next_idx) => {
+                    buffer.copy_within(next_idx..total_bytes_read, 0);
+                    total_bytes_read - next_idx
+                }
+                None => 0,
+            };
+
+            match stored {
+                Ok(()) => {
+                    state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+                    blocks.done(block_len(state, seq) as u64);
+                }
+                Err(SendFileError::BlockUnreadable { seq, reason }) => {
+                    skip_unreadable_block(state, seq, reason, &mut write_buffer)?;
+                }
+                Err(e) if is_damaged_block(&e) => {
+                    let attempt = attempts.entry(seq).or_insert(1);
+                    if *attempt >= MAX_BLOCK_ATTEMPTS {
+                        error!(
+                            "Max retries ({}) exceeded for block {}: {}",
+                            MAX_BLOCK_ATTEMPTS, seq, e
+                        );
+                        return Err(max_retries_exceeded(seq));
+                    }
+                    *attempt += 1;
+                    warn!(
+                        "Block {} arrived damaged, will receive it again: {}",
+                        seq, e
+                    );
+                    missing.push(seq);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let done = window + 1 >= windows && missing.is_empty();
+        let ack = ReceiverMessageV1::PushAck(PushAckV1 {
+            file_hash: state.file_hash,
+            window,
+            missing: missing.clone(),
+        });
+        // Sequence numbers take at most 5 bytes each
+        message_buffer.resize(128 + 5 * missing.len(), 0);
+        send_message(
+            stream,
+            &ack,
+            &mut message_buffer,
+            state.protocol_version,
+            state.session_id().as_ref(),
+        )?;
+        stream.flush()?;
+        if done {
+            break;
+        }
+        resent = missing;
+    }
+
+    Ok(())
+}
+
+/// Tells the sender which blocks of `range_start..range_end` were received or `written` by the
+/// interrupted transfer, and receives all the others as it pushes them, see
+/// [HaveBlocksV1]. Written blocks are trusted without being verified, the hash of the whole
+/// file is checked once it is complete. Blocks that arrive damaged are requested again.
+fn receive_missing_blocks<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    written: &[bool],
+    range_start: u32,
+    range_end: u32,
+) -> Result<(), SendFileError> {
+    let mut buffer = AlignedBuffer::zeroed(state.buffer_size);
+    let mut filled_len = 0;
+    let mut write_buffer = AlignedBuffer::zeroed(state.buffer_size);
+    let mut inflater = BlockInflater::with_codec(state.compression);
+    let timings = state.control.timings();
+    let mut blocks = BlockSpans::new();
+    let mut damaged = Vec::new();
+
+    let mut start = range_start;
+    while start < range_end {
+        state.control.checkpoint()?;
+        let have = HaveBlocksV1::new(
+            state.file_hash,
+            start..range_end,
+            |seq| {
+                written[seq as usize] || state.received_blocks[seq as usize].load(Ordering::SeqCst)
+            },
+            state.zero_blocks,
+        );
+        let missing = have.missing().unwrap_or_default();
+        let end = start + have.count;
+        for seq in start..end {
+            if written[seq as usize]
+                && !state.received_blocks[seq as usize].swap(true, Ordering::SeqCst)
+            {
+                let len = block_len(state, seq) as u64;
+                state.bytes_received.fetch_add(len, Ordering::SeqCst);
+                state.control.add_bytes(len);
+            }
+        }
+        info!(
+            "Holding {} of the blocks {}..{}, receiving the others",
+            have.count - missing.iter().map(|range| range.len() as u32).sum::<u32>(),
+            start,
+            end
+        );
+
+        // Runs take at most 5 bytes each
+        let mut message_buffer = vec![0u8; 64 + 5 * have.runs.len()];
+        send_message(
+            stream,
+            &ReceiverMessageV1::HaveBlocks(have),
+            &mut message_buffer,
+            state.protocol_version,
+            state.session_id().as_ref(),
+        )?;
+        stream.flush()?;
+
+        for seq in missing.into_iter().flatten() {
+            blocks.begin();
+            let result = timings.time(Stage::Network, || {
+                read_sender_message(
+                    stream,
+                    &mut buffer,
+                    filled_len,
+                    state.protocol_version,
+                    state.session_id().as_ref(),
+                )
+            })?;
+            let (next_idx, total_bytes_read) = (result.next_payload_index, result.total_bytes_read);
+            let stored =
+                store_block_answer(state, seq, result.message, &mut write_buffer, &mut inflater);
+            filled_len = match next_idx {
+                Some(next_idx) => {
+                    buffer.copy_within(next_idx..total_bytes_read, 0);
+                    total_bytes_read - next_idx
+                }
+                None => 0,
+            };
+
+            match stored {
+                Ok(()) => {
+                    state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+                    blocks.done(block_len(state, seq) as u64);
+                }
+                Err(SendFileError::BlockUnreadable { seq, reason }) => {
+                    skip_unreadable_block(state, seq, reason, &mut write_buffer)?;
+                }
+                Err(e) if is_damaged_block(&e) => {
+                    warn!("Block {} arrived damaged, will re-download: {}", seq, e);
+                    damaged.push(seq);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        start = end;
+    }
+
+    for seq in damaged {
+        download_block_or_skip(
+            stream,
+            state,
+            seq,
+            &mut buffer,
+            &mut write_buffer,
+            &mut inflater,
+            &mut None,
+        )?;
+        state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+    }
+
+    Ok(())
+}
+
+/// Verifies the blocks of `range_start..range_end` already on disk against the sender's, in
+/// batches of [MAX_BATCH_MESSAGES] if it supports them, and downloads those that don't match.
+fn verify_existing_blocks<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    range_start: u32,
+    range_end: u32,
+) -> Result<(), SendFileError> {
+    let mut buffer = AlignedBuffer::zeroed(state.buffer_size);
+    let mut filled_len = 0;
+    let mut write_buffer = AlignedBuffer::zeroed(state.buffer_size);
+    let mut inflater = BlockInflater::with_codec(state.compression);
+    let batch_size = if state.batching {
+        MAX_BATCH_MESSAGES
+    } else {
+        1
+    };
+    let mut batch = Vec::with_capacity(batch_size);
 
     for seq in range_start..range_end {
+        state.control.checkpoint()?;
         if state.received_blocks[seq as usize].load(Ordering::SeqCst) {
             continue;
         }
 
-        let block_data = read_file_block(&mut file, seq, state.block_size)?;
+        let block_data = match &state.written_blocks {
+            Some(written) if !written[seq as usize] => Vec::new(),
+            _ => state.sink.read_block(seq, state.block_size)?,
+        };
 
         if block_data.is_empty() {
+            // Nothing on disk for this block yet (e.g. the file was not pre-allocated, or the
+            // interrupted transfer never got to it)
+            download_block_or_skip(
+                stream,
+                state,
+                seq,
+                &mut buffer,
+                &mut write_buffer,
+                &mut inflater,
+                &mut None,
+            )?;
+            state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
             continue;
         }
 
-        let checksum_val = checksum(CrcAlgorithm::Crc32IsoHdlc, &block_data) as u32;
-
-        let msg = ReceiverMessageV1::VerifyBlock(VerifyBlockV1 {
+        let verify = VerifyBlockV1 {
             file_hash: state.file_hash,
             seq,
-            checksum: checksum_val,
-        });
+            checksum: block_checksum_with(state.checksum, &block_data),
+        };
+        batch.push((verify, block_data.len() as u64));
+        if batch.len() == batch_size {
+            filled_len = TraceContext::current().in_span("verify", || {
+                verify_blocks(
+                    stream,
+                    state,
+                    &batch,
+                    &mut buffer,
+                    filled_len,
+                    &mut write_buffer,
+                    &mut inflater,
+                )
+            })?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        TraceContext::current().in_span("verify", || {
+            verify_blocks(
+                stream,
+                state,
+                &batch,
+                &mut buffer,
+                filled_len,
+                &mut write_buffer,
+                &mut inflater,
+            )
+        })?;
+    }
 
-        send_message(stream, &msg, &mut write_buffer)?;
+    Ok(())
+}
 
-        let (valid, next_filled_len) = read_verify_response(stream, &mut buffer, filled_len, seq)?;
+/// Asks the sender whether the blocks of `batch`, with their length on disk, match its own, in
+/// a single frame if there are several, and downloads those that don't.
+///
+/// # Returns
+///
+/// The number of bytes of the next message left at the start of `buffer`.
+fn verify_blocks<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    batch: &[(VerifyBlockV1, u64)],
+    buffer: &mut [u8],
+    mut filled_len: usize,
+    write_buffer: &mut [u8],
+    inflater: &mut BlockInflater,
+) -> Result<usize, SendFileError> {
+    let msg = match batch {
+        [(verify, _)] => ReceiverMessageV1::VerifyBlock(verify.clone()),
+        _ => ReceiverMessageV1::Batch(BatchV1 {
+            messages: batch
+                .iter()
+                .map(|(verify, _)| BatchedMessageV1::VerifyBlock(verify.clone()))
+                .collect(),
+        }),
+    };
+    send_message(
+        stream,
+        &msg,
+        write_buffer,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )?;
 
+    // Every answer is read before blocks are downloaded over the same connection
+    let mut mismatched = Vec::new();
+    for (verify, len) in batch {
+        let (valid, next_filled_len) =
+            read_verify_response(stream, state, buffer, filled_len, verify.seq)?;
         filled_len = next_filled_len;
 
         if valid {
-            state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
-            state
-                .bytes_received
-                .fetch_add(block_data.len() as u64, Ordering::SeqCst);
-            info!("Block {} verified successfully", seq);
+            state.received_blocks[verify.seq as usize].store(true, Ordering::SeqCst);
+            state.bytes_received.fetch_add(*len, Ordering::SeqCst);
+            state.control.add_bytes(*len);
+            info!("Block {} verified successfully", verify.seq);
         } else {
-            info!("Block {} verification failed, will re-download", seq);
-            request_and_download_block(
-                stream,
-                state,
-                seq,
-                &mut buffer,
-                &mut write_buffer,
-                &mut file,
-            )?;
+            info!("Block {} verification failed, will re-download", verify.seq);
+            mismatched.push(verify.seq);
         }
     }
+    for seq in mismatched {
+        download_block_or_skip(
+            stream,
+            state,
+            seq,
+            buffer,
+            write_buffer,
+            inflater,
+            &mut None,
+        )?;
+        state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+    }
 
-    Ok(())
+    Ok(filled_len)
 }
 
-fn read_verify_response(
-    stream: &mut TcpStream,
-    buffer: &mut Vec<u8>,
+fn read_verify_response<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    buffer: &mut [u8],
     filled_len: usize,
     seq: u32,
 ) -> Result<(bool, usize), SendFileError> {
-    let result = read_next_payload::<SenderMessageV1, _>(stream, buffer, filled_len)?;
+    let result = read_sender_message(
+        stream,
+        buffer,
+        filled_len,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )?;
 
     let (valid, next_idx, total_bytes_read) = match result.message {
         SenderMessageV1::VerifyResponse(resp) => {
@@ -305,6 +3508,10 @@ fn read_verify_response(
                 )
             }
         }
+        SenderMessageV1::Error(err) if err.code == TIME_LIMIT_CODE => {
+            return Err(sender_abort(state, err));
+        }
+        SenderMessageV1::Cancel(cancel) => return Err(sender_cancel(state, cancel)),
         SenderMessageV1::Error(err) => {
             error!("Sender error during verify: {} - {}", err.code, err.message);
             (false, result.next_payload_index, result.total_bytes_read)
@@ -326,87 +3533,428 @@ fn read_verify_response(
     Ok((valid, next_filled_len))
 }
 
-fn download_missing_blocks(
-    stream: &mut TcpStream,
+/// Downloads the blocks of `range_start..range_end` not received yet, keeping up to
+/// [ReceiveOptions::pipeline_depth] requests outstanding unless they are received over UDP.
+/// Runs of consecutive blocks are requested with a single message if the sender supports it.
+/// Blocks that arrive damaged are requested again once every other block is in.
+fn download_missing_blocks<S: Read + Write>(
+    stream: &mut S,
     state: &ReceiverState,
     range_start: u32,
     range_end: u32,
 ) -> Result<(), SendFileError> {
-    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
-    let mut write_buffer = vec![0u8; MAX_MESSAGE_SIZE];
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&state.file_path)?;
+    let mut buffer = AlignedBuffer::zeroed(state.buffer_size);
+    let mut write_buffer = AlignedBuffer::zeroed(state.buffer_size);
+    let mut inflater = BlockInflater::with_codec(state.compression);
+
+    let block_hashes = match &state.block_store {
+        Some(_) => fetch_block_hashes(
+            stream,
+            state,
+            range_start,
+            range_end,
+            &mut buffer,
+            &mut write_buffer,
+        )?,
+        None => Vec::new(),
+    };
+    let mut udp = if state.udp_fec {
+        UdpReceiver::bind(state.sender_addr.ip())
+            .inspect_err(|e| warn!("Receiving blocks over TCP only, UDP unavailable: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    let mut blocks = BlockSpans::new();
+    let mut pipeline = RequestPipeline::default();
+    // Consecutive blocks not requested yet, see [REQUEST_RANGE_BLOCKS]
+    let mut run: Option<std::ops::Range<u32>> = None;
 
     for seq in range_start..range_end {
+        state.control.checkpoint()?;
+        if !state.best_effort && !lock_unreadable(state).is_empty() {
+            // Another connection hit an unreadable block, the transfer is being aborted
+            return Ok(());
+        }
         if state.received_blocks[seq as usize].load(Ordering::SeqCst) {
             continue;
         }
 
-        let mut retry_count = 0u32;
-        let mut retry_delay = INITIAL_RETRY_DELAY_MS;
+        if let Some(hash) = block_hashes.get((seq - range_start) as usize)
+            && reuse_stored_block(state, seq, hash)
+        {
+            state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+            continue;
+        }
 
-        loop {
-            match request_and_download_block(
+        blocks.begin();
+        // Blocks sent as datagrams are announced on the connection one at a time
+        if udp.is_some() {
+            download_with_retries(
                 stream,
                 state,
                 seq,
                 &mut buffer,
                 &mut write_buffer,
-                &mut file,
-            ) {
-                Ok(()) => {
-                    state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
-                    break;
+                &mut inflater,
+                &mut udp,
+            )?;
+            state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+            blocks.done(block_len(state, seq) as u64);
+            continue;
+        }
+
+        if state.range_request {
+            if let Some(run) = run.as_mut()
+                && run.end == seq
+                && run.len() < REQUEST_RANGE_BLOCKS as usize
+            {
+                run.end += 1;
+                continue;
+            }
+            let Some(previous) = run.replace(seq..seq + 1) else {
+                continue;
+            };
+            send_range_request(stream, state, previous.clone(), &mut write_buffer)?;
+            pipeline.outstanding.extend(previous);
+        } else {
+            send_block_request(stream, state, seq, &mut write_buffer)?;
+            pipeline.outstanding.push_back(seq);
+        }
+        while pipeline.outstanding.len() >= state.pipeline_depth {
+            pipeline.receive_next(
+                stream,
+                state,
+                &mut buffer,
+                &mut write_buffer,
+                &mut inflater,
+                &mut blocks,
+            )?;
+        }
+    }
+    if let Some(run) = run {
+        send_range_request(stream, state, run.clone(), &mut write_buffer)?;
+        pipeline.outstanding.extend(run);
+    }
+    while !pipeline.outstanding.is_empty() {
+        pipeline.receive_next(
+            stream,
+            state,
+            &mut buffer,
+            &mut write_buffer,
+            &mut inflater,
+            &mut blocks,
+        )?;
+    }
+
+    for seq in pipeline.damaged {
+        blocks.begin();
+        download_with_retries(
+            stream,
+            state,
+            seq,
+            &mut buffer,
+            &mut write_buffer,
+            &mut inflater,
+            &mut udp,
+        )?;
+        state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+        blocks.done(block_len(state, seq) as u64);
+    }
+
+    Ok(())
+}
+
+/// Block requests a data connection sent ahead of the sender's answers, see
+/// [ReceiveOptions::pipeline_depth].
+#[derive(Default)]
+struct RequestPipeline {
+    /// Blocks requested and not read yet, in the order the sender answers them.
+    outstanding: VecDeque<u32>,
+    /// Bytes of the next answer already read at the start of the buffer.
+    filled_len: usize,
+    /// Blocks that arrived damaged, requested again once the others are in.
+    damaged: Vec<u32>,
+}
+
+impl RequestPipeline {
+    /// Reads the answer to the oldest outstanding request into `buffer`, and writes its block.
+    /// A block that can't be written is noted as damaged, as the answer was read whole and the
+    /// connection is still in step with the sender.
+    ///
+    /// # Returns
+    ///
+    /// An error if the connection failed, or if the sender could not read the block and the
+    /// transfer is not best-effort.
+    fn receive_next<S: Read + Write>(
+        &mut self,
+        stream: &mut S,
+        state: &ReceiverState,
+        buffer: &mut [u8],
+        write_buffer: &mut [u8],
+        inflater: &mut BlockInflater,
+        blocks: &mut BlockSpans,
+    ) -> Result<(), SendFileError> {
+        let Some(seq) = self.outstanding.pop_front() else {
+            return Ok(());
+        };
+        stream.flush()?;
+        let filled_len = self.filled_len;
+        let result = state
+            .control
+            .timings()
+            .time(Stage::Network, || {
+                read_sender_message(
+                    stream,
+                    buffer,
+                    filled_len,
+                    state.protocol_version,
+                    state.session_id().as_ref(),
+                )
+            })
+            .map_err(|e| {
+                warn!("Failed to read response for block {}: {}", seq, e);
+                SendFileError::ConnectionFailed(format!(
+                    "Failed to read response for block {}: {}",
+                    seq, e
+                ))
+            })?;
+        let (next_idx, total_bytes_read) = (result.next_payload_index, result.total_bytes_read);
+        let stored = store_block_answer(state, seq, result.message, write_buffer, inflater);
+        self.filled_len = match next_idx {
+            Some(next_idx) => {
+                buffer.copy_within(next_idx..total_bytes_read, 0);
+                total_bytes_read - next_idx
+            }
+            None => 0,
+        };
+
+        match stored {
+            Ok(()) => {
+                state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+                blocks.done(block_len(state, seq) as u64);
+            }
+            Err(_) if state.control.is_cancelled() => return Err(state.control.cancellation()),
+            Err(SendFileError::BlockUnreadable { seq, reason }) => {
+                skip_unreadable_block(state, seq, reason, write_buffer)?;
+            }
+            Err(e @ SendFileError::SenderUnavailable { .. }) => {
+                warn!(
+                    "Sender can't serve block {} now, will ask again: {}",
+                    seq, e
+                );
+                self.damaged.push(seq);
+            }
+            Err(e) => {
+                warn!("Block {} arrived damaged, will re-download: {}", seq, e);
+                self.damaged.push(seq);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Downloads block `seq` on its own, retrying up to [MAX_BLOCK_ATTEMPTS] times after a growing
+/// delay, or after the time the sender asked for, see [crate::stream::retry_after].
+fn download_with_retries<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    seq: u32,
+    buffer: &mut [u8],
+    write_buffer: &mut [u8],
+    inflater: &mut BlockInflater,
+    udp: &mut Option<UdpReceiver>,
+) -> Result<(), SendFileError> {
+    let mut retry_count = 0u32;
+    let mut retry_delay = INITIAL_RETRY_DELAY_MS;
+
+    loop {
+        match download_block_or_skip(stream, state, seq, buffer, write_buffer, inflater, udp) {
+            Ok(()) => return Ok(()),
+            Err(_) if state.control.is_cancelled() => return Err(state.control.cancellation()),
+            Err(e @ SendFileError::BlockUnreadable { .. }) => return Err(e),
+            Err(e) => {
+                retry_count += 1;
+                if retry_count >= MAX_BLOCK_ATTEMPTS {
+                    error!(
+                        "Max retries ({}) exceeded for block {}: {}",
+                        MAX_BLOCK_ATTEMPTS, seq, e
+                    );
+                    return Err(max_retries_exceeded(seq));
                 }
-                Err(e) => {
-                    retry_count += 1;
-                    if retry_count >= MAX_RETRIES {
-                        error!(
-                            "Max retries ({}) exceeded for block {}: {}",
-                            MAX_RETRIES, seq, e
-                        );
-                        return Err(SendFileError::Io(std::io::Error::new(
-                            std::io::ErrorKind::TimedOut,
-                            format!("Max retries exceeded for block {}", seq),
-                        )));
+
+                error!("Had to retry: {}", e);
+                match e.root() {
+                    // The sender knows better when it can serve the block
+                    SendFileError::SenderUnavailable { retry_after, .. } => {
+                        thread::sleep(*retry_after)
                     }
+                    _ => {
+                        retry_delay *= 2;
+                        thread::sleep(Duration::from_millis(retry_delay));
+                    }
+                }
+            }
+        }
+    }
+}
 
-                    error!("Had to retry: {}", e);
-                    retry_delay *= 2;
-                    thread::sleep(Duration::from_millis(retry_delay));
+/// Returns whether `error` storing a block means it arrived damaged and may be received again.
+fn is_damaged_block(error: &SendFileError) -> bool {
+    matches!(
+        error.root(),
+        SendFileError::ChecksumMismatch { .. } | SendFileError::Io(_)
+    )
+}
+
+/// Returns the error of block `seq` failing [MAX_BLOCK_ATTEMPTS] times.
+fn max_retries_exceeded(seq: u32) -> SendFileError {
+    SendFileError::Io(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "Max retries exceeded",
+    ))
+    .with_context(ErrorContext::new("receiving").with_seq(seq))
+}
+
+/// Requests the hashes of the blocks in `range_start..range_end` from the sender, in runs of at
+/// most [MAX_BLOCK_HASHES_PER_MESSAGE].
+fn fetch_block_hashes<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    range_start: u32,
+    range_end: u32,
+    buffer: &mut [u8],
+    write_buffer: &mut [u8],
+) -> Result<Vec<[u8; 32]>, SendFileError> {
+    let mut hashes = Vec::with_capacity((range_end - range_start) as usize);
+
+    while range_start + (hashes.len() as u32) < range_end {
+        let start_seq = range_start + hashes.len() as u32;
+        let msg = ReceiverMessageV1::BlockHashesRequest(BlockHashesRequestV1 {
+            file_hash: state.file_hash,
+            start_seq,
+            count: (range_end - start_seq).min(MAX_BLOCK_HASHES_PER_MESSAGE),
+        });
+        send_message(
+            stream,
+            &msg,
+            write_buffer,
+            state.protocol_version,
+            state.session_id().as_ref(),
+        )?;
+        stream.flush()?;
+
+        let result = read_sender_message(
+            stream,
+            buffer,
+            0,
+            state.protocol_version,
+            state.session_id().as_ref(),
+        )?;
+        match result.message {
+            SenderMessageV1::BlockHashes(response) if response.start_seq == start_seq => {
+                if response.hashes.is_empty() {
+                    // Past the end of the sender's file, nothing more to look up
+                    break;
                 }
+                hashes.extend(response.hashes);
+            }
+            SenderMessageV1::Error(err) if err.code == TIME_LIMIT_CODE => {
+                return Err(sender_abort(state, err));
+            }
+            SenderMessageV1::Cancel(cancel) => return Err(sender_cancel(state, cancel)),
+            message => {
+                return Err(SendFileError::UnexpectedMessage {
+                    received: format!("{:?}", message),
+                    expected: String::from("BlockHashes"),
+                });
             }
         }
     }
 
-    Ok(())
+    Ok(hashes)
+}
+
+/// Copies block `seq` from the block store into the output file if the store has a block with
+/// `hash`. Returns `false` if the block has to be downloaded.
+fn reuse_stored_block(state: &ReceiverState, seq: u32, hash: &[u8; 32]) -> bool {
+    let Some(store) = &state.block_store else {
+        return false;
+    };
+
+    let data = match store.get(hash) {
+        Ok(Some(data)) => data,
+        Ok(None) => return false,
+        Err(e) => {
+            warn!("Failed to read block {} from the block store: {}", seq, e);
+            return false;
+        }
+    };
+
+    if let Err(e) = state.sink.write_block(seq, state.block_size, &data) {
+        warn!("Failed to write reused block {}: {}", seq, e);
+        return false;
+    }
+
+    state
+        .bytes_reused
+        .fetch_add(data.len() as u64, Ordering::SeqCst);
+    state.control.add_bytes(data.len() as u64);
+    info!("Block {} reused from the block store", seq);
+    true
 }
 
-fn request_and_download_block(
-    stream: &mut TcpStream,
+/// Requests block `seq` and reads the answer, as UDP datagrams with `udp`, over `stream`
+/// otherwise or if too many datagrams were lost. `udp` is dropped once a block was requested
+/// without receiving any datagram, as they likely don't make it through.
+///
+/// Compressed blocks are decoded into `write_buffer` with `inflater`.
+fn request_and_download_block<S: Read + Write>(
+    stream: &mut S,
     state: &ReceiverState,
     seq: u32,
     buffer: &mut [u8],
     write_buffer: &mut [u8],
-    file: &mut std::fs::File,
+    inflater: &mut BlockInflater,
+    udp: &mut Option<UdpReceiver>,
 ) -> Result<(), SendFileError> {
-    let msg = ReceiverMessageV1::Request(RequestV1 {
-        file_hash: state.file_hash,
-        seq,
-    });
+    let frame_len = match udp.as_mut() {
+        Some(link) => {
+            let frame_len = request_over_udp(stream, state, seq, link, buffer, write_buffer)?;
+            if frame_len.is_none() && link.datagrams_received() == 0 {
+                warn!("No UDP datagrams from the sender, receiving blocks over TCP");
+                *udp = None;
+            }
+            frame_len
+        }
+        None => None,
+    };
 
-    if let Err(e) = send_message(stream, &msg, write_buffer) {
-        warn!("Failed to send request for block {}: {}", seq, e);
-        return Err(SendFileError::ConnectionFailed(format!(
-            "Failed to send request for block {}: {}",
-            seq, e
-        )));
-    }
-    stream.flush()?;
+    let timings = state.control.timings();
+    let read = match frame_len {
+        // Datagrams of other transfers are refused like their frames on the connection
+        Some(len) => read_next_payload::<SenderMessageV1, _>(&mut std::io::empty(), buffer, len)
+            .and_then(|result| {
+                result.check_session(state.session_id().as_ref(), state.protocol_version)?;
+                Ok(result)
+            })
+            .map_err(SendFileError::from),
+        None => {
+            send_block_request(stream, state, seq, write_buffer)?;
+            stream.flush()?;
 
-    let result = match read_next_payload::<SenderMessageV1, _>(stream, buffer, 0) {
+            timings.time(Stage::Network, || {
+                read_sender_message(
+                    stream,
+                    buffer,
+                    0,
+                    state.protocol_version,
+                    state.session_id().as_ref(),
+                )
+            })
+        }
+    };
+    let result = match read {
         Ok(r) => r,
         Err(e) => {
             warn!("Failed to read response for block {}: {}", seq, e);
@@ -417,34 +3965,317 @@ fn request_and_download_block(
         }
     };
 
-    match result.message {
-        SenderMessageV1::Data(data) => process_data_block(state, seq, data, write_buffer, file),
-        SenderMessageV1::Error(err) => {
-            error!(
-                "Sender error for block {}: {} - {}",
-                seq, err.code, err.message
-            );
-            Err(SendFileError::ConnectionFailed(format!(
-                "Sender error {}: {}",
-                err.code, err.message
-            )))
-        }
-        _ => {
-            warn!("Unexpected message type for block {}", seq);
-            Err(SendFileError::UnexpectedMessage {
-                received: format!("{:?}", result.message),
-                expected: "Data".to_string(),
-            })
+    store_block_answer(state, seq, result.message, write_buffer, inflater)
+}
+
+/// Sends the request for block `seq`, by file for a directory, without flushing it.
+fn send_block_request<S: Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    seq: u32,
+    write_buffer: &mut [u8],
+) -> Result<(), SendFileError> {
+    let request = RequestV1 {
+        file_hash: state.file_hash,
+        seq,
+    };
+    let located = state.bundle.as_ref().and_then(|bundle| bundle.locate(seq));
+    let msg = match located {
+        Some((file_index, file_seq)) => ReceiverMessageV1::FileRequest(FileRequestV1 {
+            file_hash: state.file_hash,
+            file_index,
+            seq: file_seq,
+        }),
+        None if state.zero_blocks => ReceiverMessageV1::SparseRequest(request),
+        None => ReceiverMessageV1::Request(request),
+    };
+
+    send_message(
+        stream,
+        &msg,
+        write_buffer,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )
+    .map_err(|e| {
+        warn!("Failed to send request for block {}: {}", seq, e);
+        SendFileError::ConnectionFailed(format!("Failed to send request for block {}: {}", seq, e))
+    })
+}
+
+/// Sends the request for the blocks of `run`, see [RequestRangeV1], without flushing it.
+fn send_range_request<S: Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    run: std::ops::Range<u32>,
+    write_buffer: &mut [u8],
+) -> Result<(), SendFileError> {
+    let msg = ReceiverMessageV1::RequestRange(RequestRangeV1 {
+        file_hash: state.file_hash,
+        start_seq: run.start,
+        end_seq: run.end,
+        sparse: state.zero_blocks,
+    });
+
+    send_message(
+        stream,
+        &msg,
+        write_buffer,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )
+    .map_err(|e| {
+        warn!("Failed to send request for blocks {:?}: {}", run, e);
+        SendFileError::ConnectionFailed(format!(
+            "Failed to send request for blocks {:?}: {}",
+            run, e
+        ))
+    })
+}
+
+/// Writes the sender's answer `message` for block `seq`, decoding it into `write_buffer` with
+/// `inflater` if it is compressed.
+///
+/// # Returns
+///
+/// [SendFileError::BlockUnreadable] if the sender could not read the block,
+/// [SendFileError::SenderUnavailable] if it asked to be retried later, or another error if
+/// `message` is not a valid answer.
+fn store_block_answer(
+    state: &ReceiverState,
+    seq: u32,
+    message: SenderMessageV1,
+    write_buffer: &mut [u8],
+    inflater: &mut BlockInflater,
+) -> Result<(), SendFileError> {
+    match message {
+        SenderMessageV1::Data(data) => process_data_block(state, seq, data, write_buffer, inflater),
+        SenderMessageV1::FileData(file_data) => {
+            let file_index = file_data.file_index;
+            let data = bundle_block(state, file_data)?;
+            process_data_block(state, seq, data, write_buffer, inflater)?;
+            state
+                .control
+                .add_file_bytes(file_index, block_len(state, seq) as u64);
+            Ok(())
+        }
+        SenderMessageV1::ZeroBlock(zero) if zero.seq == seq => {
+            write_zero_block(state, seq, write_buffer)
+        }
+        SenderMessageV1::BlockUnreadable(unreadable) if unreadable.seq == seq => {
+            Err(SendFileError::BlockUnreadable {
+                seq,
+                reason: unreadable.reason,
+            })
+        }
+        SenderMessageV1::Error(err) if err.code == TIME_LIMIT_CODE => Err(sender_abort(state, err)),
+        SenderMessageV1::Cancel(cancel) => Err(sender_cancel(state, cancel)),
+        SenderMessageV1::RetryAfter(hint) => Err(sender_unavailable(hint)),
+        SenderMessageV1::Error(err) => {
+            error!(
+                "Sender error for block {}: {} - {}",
+                seq, err.code, err.message
+            );
+            Err(SendFileError::ConnectionFailed(format!(
+                "Sender error {}: {}",
+                err.code, err.message
+            )))
+        }
+        message => {
+            warn!("Unexpected message type for block {}", seq);
+            Err(SendFileError::UnexpectedMessage {
+                received: format!("{:?}", message),
+                expected: "Data".to_string(),
+            })
+        }
+    }
+}
+
+/// Requests block `seq` as UDP datagrams sent to `link`, and rebuilds the sender's answer from
+/// them into `buffer`.
+///
+/// # Returns
+///
+/// The length of the answer in `buffer`, or `None` if it could not be rebuilt.
+fn request_over_udp<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    seq: u32,
+    link: &mut UdpReceiver,
+    buffer: &mut [u8],
+    write_buffer: &mut [u8],
+) -> Result<Option<usize>, SendFileError> {
+    let msg = ReceiverMessageV1::UdpRequest(UdpRequestV1 {
+        file_hash: state.file_hash,
+        seq,
+        port: link.port()?,
+        tag: link.tag(),
+    });
+    send_message(
+        stream,
+        &msg,
+        write_buffer,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )?;
+    stream.flush()?;
+
+    let announced = match read_sender_message(
+        stream,
+        buffer,
+        0,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )?
+    .message
+    {
+        SenderMessageV1::UdpBlock(announced) if announced.seq == seq => announced,
+        SenderMessageV1::Error(err) if err.code == TIME_LIMIT_CODE => {
+            return Err(sender_abort(state, err));
+        }
+        SenderMessageV1::Cancel(cancel) => return Err(sender_cancel(state, cancel)),
+        SenderMessageV1::Error(err) => {
+            return Err(SendFileError::ConnectionFailed(format!(
+                "Sender error {}: {}",
+                err.code, err.message
+            )));
+        }
+        message => {
+            return Err(SendFileError::UnexpectedMessage {
+                received: format!("{:?}", message),
+                expected: String::from("UdpBlock"),
+            });
+        }
+    };
+    let timings = state.control.timings();
+    let frame = timings.time(Stage::Network, || link.receive(&announced, buffer.len()))?;
+    Ok(frame.map(|frame| {
+        buffer[..frame.len()].copy_from_slice(&frame);
+        frame.len()
+    }))
+}
+
+/// Downloads block `seq`. If the sender can't read it, the block is skipped in best-effort mode
+/// (and counted as received), otherwise [SendFileError::BlockUnreadable] is returned.
+fn download_block_or_skip<S: Read + Write>(
+    stream: &mut S,
+    state: &ReceiverState,
+    seq: u32,
+    buffer: &mut [u8],
+    write_buffer: &mut [u8],
+    inflater: &mut BlockInflater,
+    udp: &mut Option<UdpReceiver>,
+) -> Result<(), SendFileError> {
+    match request_and_download_block(stream, state, seq, buffer, write_buffer, inflater, udp) {
+        Err(SendFileError::BlockUnreadable { seq, reason }) => {
+            skip_unreadable_block(state, seq, reason, write_buffer)
+        }
+        result => result,
+    }
+}
+
+/// Records that the sender could not read block `seq`, and zero-fills it in best-effort mode
+/// (counting it as received). Returns [SendFileError::BlockUnreadable] otherwise.
+fn skip_unreadable_block(
+    state: &ReceiverState,
+    seq: u32,
+    reason: String,
+    write_buffer: &mut [u8],
+) -> Result<(), SendFileError> {
+    lock_unreadable(state).insert(seq, reason.clone());
+    if !state.best_effort {
+        error!("Sender could not read block {}: {}", seq, reason);
+        return Err(SendFileError::BlockUnreadable { seq, reason });
+    }
+    warn!(
+        "Sender could not read block {}, zero-filling it: {}",
+        seq, reason
+    );
+    // Explicitly, as a resumed file may hold stale data there
+    let zeros = zeroed_block(state, seq, write_buffer);
+    state.sink.write_block(seq, state.block_size, zeros)?;
+    state.received_blocks[seq as usize].store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Writes the zeros of block `seq`, which the sender only named, see
+/// [Capabilities::ZERO_BLOCKS](crate::capabilities::Capabilities::ZERO_BLOCKS). Explicitly, as
+/// the file may hold stale data there.
+fn write_zero_block(
+    state: &ReceiverState,
+    seq: u32,
+    write_buffer: &mut [u8],
+) -> Result<(), SendFileError> {
+    let zeros = zeroed_block(state, seq, write_buffer);
+    let timings = state.control.timings();
+    timings.time(Stage::DiskWrite, || {
+        state.sink.write_block(seq, state.block_size, zeros)
+    })?;
+
+    let len = zeros.len() as u64;
+    state.bytes_received.fetch_add(len, Ordering::SeqCst);
+    state.control.add_bytes(len);
+    Ok(())
+}
+
+/// Returns the length of block `seq`, shorter than the block size for the last block.
+fn block_len(state: &ReceiverState, seq: u32) -> usize {
+    match &state.bundle {
+        Some(bundle) => bundle.block_len(seq) as usize,
+        None => {
+            let offset = seq as u64 * state.block_size as u64;
+            state
+                .total_size
+                .saturating_sub(offset)
+                .min(state.block_size as u64) as usize
         }
     }
 }
 
+/// Zeroes the start of `buffer` as long as block `seq` and returns it.
+fn zeroed_block<'b>(state: &ReceiverState, seq: u32, buffer: &'b mut [u8]) -> &'b [u8] {
+    let zeros = &mut buffer[..block_len(state, seq)];
+    zeros.fill(0);
+    zeros
+}
+
+fn lock_unreadable<'a>(
+    state: &'a ReceiverState,
+) -> std::sync::MutexGuard<'a, BTreeMap<u32, String>> {
+    state
+        .unreadable_blocks
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns the block of a file of the offered directory as block of the bundle, see
+/// [crate::stream::bundle].
+fn bundle_block<'b>(
+    state: &ReceiverState,
+    file_data: FileDataV1<'b>,
+) -> Result<DataV1<'b>, SendFileError> {
+    let FileDataV1 { file_index, data } = file_data;
+    let seq = state
+        .bundle
+        .as_ref()
+        .and_then(|bundle| bundle.bundle_seq(file_index, data.seq))
+        .ok_or_else(|| {
+            SendFileError::InvalidRequest(format!(
+                "Sender sent block {} of file {}, which the directory doesn't have",
+                data.seq, file_index
+            ))
+        })?;
+    Ok(DataV1 { seq, ..data })
+}
+
+/// Writes the block `data` received for block `seq`, decoding it into `write_buffer` with
+/// `inflater` if it is compressed.
 fn process_data_block(
     state: &ReceiverState,
     seq: u32,
     data: DataV1,
     write_buffer: &mut [u8],
-    file: &mut std::fs::File,
+    inflater: &mut BlockInflater,
 ) -> Result<(), SendFileError> {
     if seq != data.seq {
         return Err(SendFileError::BlockSequenceMismatch {
@@ -452,7 +4283,7 @@ fn process_data_block(
             received: data.seq,
         });
     }
-    let computed_checksum = checksum(CrcAlgorithm::Crc32IsoHdlc, data.data) as u32;
+    let computed_checksum = block_checksum_with(state.checksum, data.data);
     if computed_checksum != data.checksum {
         warn!(
             "Checksum mismatch for block {}: expected {}, got {}",
@@ -465,49 +4296,220 @@ fn process_data_block(
         });
     }
 
-    let block_data: Cow<[u8]> = if data.compressed {
-        match decompress_gzip(data.data) {
-            Ok(d) => Cow::Owned(d),
+    let timings = state.control.timings();
+    let block_data: &[u8] = if data.compressed {
+        match timings.time(Stage::Decompression, || {
+            inflater.inflate(data.data, write_buffer)
+        }) {
+            Ok(len) => &write_buffer[..len],
             Err(e) => {
                 warn!("Failed to decompress block {}: {}", seq, e);
-                return Err(SendFileError::Io(e));
+                return Err(SendFileError::Io(e)
+                    .with_context(ErrorContext::new("decompressing").with_seq(seq)));
             }
         }
     } else {
-        Cow::Borrowed(data.data)
+        data.data
     };
 
-    if let Err(e) = write_file_block(file, seq, state.block_size, &block_data) {
+    let written = timings.time(Stage::DiskWrite, || {
+        state.sink.write_block(seq, state.block_size, block_data)
+    });
+    if let Err(e) = written {
         warn!("Failed to write block {}: {}", seq, e);
-        return Err(SendFileError::Io(e));
+        return Err(SendFileError::Io(e).with_context(ErrorContext::new("writing").with_seq(seq)));
+    }
+
+    if let Some(store) = &state.block_store
+        && let Err(e) = store.put(block_data)
+    {
+        warn!("Failed to add block {} to the block store: {}", seq, e);
     }
 
     state
         .bytes_received
         .fetch_add(block_data.len() as u64, Ordering::SeqCst);
-
-    let _ = write_buffer;
+    state.control.add_bytes(block_data.len() as u64);
+    // No faster than the sender asked on the control channel
+    state.control.throttle(block_data.len() as u64);
+    state.control.check_bottleneck();
     Ok(())
 }
 
-fn decompress_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut decoder = GzDecoder::new(data);
-    let mut decompressed = Vec::new();
-    decoder.read_to_end(&mut decompressed)?;
-    Ok(decompressed)
+/// Reads the next message of the sender on a data connection, answering the pings in front of
+/// it and skipping the answers to ours, see [crate::stream::heartbeat]. Takes `filled_len` like
+/// [read_next_payload].
+fn read_sender_message<'b, S: Read + Write>(
+    stream: &mut S,
+    buffer: &'b mut [u8],
+    mut filled_len: usize,
+    protocol_version: u8,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> Result<ReadPayloadResult<SenderMessageV1<'b>>, SendFileError> {
+    loop {
+        let result = read_next_payload::<SenderMessageV1, _>(stream, buffer, filled_len)?;
+        result.check_session(session_id, protocol_version)?;
+        let (total_bytes_read, next_payload_index) =
+            (result.total_bytes_read, result.next_payload_index);
+        let ping = match result.message {
+            SenderMessageV1::Ping(ping) => Some(ping),
+            SenderMessageV1::Pong(_) => None,
+            // Decoded again, as a message borrowing `buffer` can't be returned from the loop
+            _ => {
+                return Ok(read_next_payload(
+                    &mut std::io::empty(),
+                    buffer,
+                    total_bytes_read,
+                )?)
+            }
+        };
+        if let Some(ping) = ping {
+            debug!("Heartbeat {} from the sender", ping.seq);
+            let msg = ReceiverMessageV1::Pong(PongV1 { seq: ping.seq });
+            send_message(stream, &msg, &mut [0u8; 64], protocol_version, session_id)?;
+            stream.flush()?;
+        }
+        filled_len = match next_payload_index {
+            Some(next_idx) => {
+                buffer.copy_within(next_idx..total_bytes_read, 0);
+                total_bytes_read - next_idx
+            }
+            None => 0,
+        };
+    }
 }
 
+/// Writes `msg` to `stream` framed with `protocol_version`, tagged with `session_id` from
+/// [SESSION_FRAMING_PROTOCOL_VERSION] on. `buffer` is used to encode it.
 fn send_message<W: Write>(
     stream: &mut W,
     msg: &ReceiverMessageV1,
     buffer: &mut [u8],
+    protocol_version: u8,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
 ) -> Result<(), SendFileError> {
+    let session_id = session_id.filter(|_| protocol_version >= SESSION_FRAMING_PROTOCOL_VERSION);
+    let header_size = FRAME_HEADER_SIZE + session_id.map_or(0, |id| id.len());
+    // Framed in place when there's room, so requesting a block allocates nothing
+    if protocol_version > TEXT_FRAMING_PROTOCOL_VERSION && buffer.len() > header_size {
+        let (header, body) = buffer.split_at_mut(header_size);
+        if let Ok(payload) = msg.to_bytes(body) {
+            let len = payload.len() as u32;
+            let (fixed, tag) = header.split_at_mut(FRAME_HEADER_SIZE);
+            match session_id {
+                Some(id) => {
+                    fixed.copy_from_slice(&FrameHeader::with_session(len).encode());
+                    tag.copy_from_slice(id);
+                }
+                None => fixed.copy_from_slice(&FrameHeader::new(len).encode()),
+            }
+            stream.write_all(&buffer[..header_size + len as usize])?;
+            return Ok(());
+        }
+    }
     let payload = msg.to_bytes(buffer)?;
-    let packet = attach_headers(payload);
+    let packet = attach_headers_for(protocol_version, session_id, payload);
     stream.write_all(&packet)?;
     Ok(())
 }
 
+/// Cancels the transfer the sender aborted at its time limit with `err`, see
+/// [crate::stream::time_limit].
+fn sender_abort(state: &ReceiverState, err: SenderErrorV1) -> SendFileError {
+    state.control.abort_by_peer(err.message);
+    SendFileError::Cancelled
+}
+
+/// Cancels the transfer the sender cancelled with `cancel`, see [crate::stream::cancel].
+fn sender_cancel(state: &ReceiverState, cancel: CancelV1) -> SendFileError {
+    state.control.cancel_by_peer(cancel.reason);
+    state.control.cancellation()
+}
+
+/// Tells the sender on the handshake connection that the transfer is aborted because the time
+/// limit of [ReceiveOptions::max_duration] ran out.
+fn send_time_limit_abort<S: Write>(session: &mut Session<S>, options: &ReceiveOptions) {
+    let msg = ReceiverMessageV1::Error(ReceiverErrorV1 {
+        code: TIME_LIMIT_CODE,
+        message: abort_reason("receiver", options.max_duration),
+    });
+    let mut buffer = vec![0u8; 256];
+    let session_id = session.session_id();
+    if let Err(e) = send_message(
+        &mut session.stream,
+        &msg,
+        &mut buffer,
+        session.protocol_version,
+        session_id.as_ref(),
+    )
+    .and_then(|_| Ok(session.stream.flush()?))
+    {
+        debug!("Failed to tell the sender about the time limit: {}", e);
+    }
+}
+
+/// Tells the sender on `stream` that the transfer of the file hashed `file_hash` is cancelled on
+/// this side, see [crate::stream::cancel].
+fn send_cancel<S: Write>(
+    stream: &mut S,
+    file_hash: [u8; 32],
+    protocol_version: u8,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) {
+    let msg = ReceiverMessageV1::Cancel(CancelV1 {
+        file_hash,
+        reason: cancel_reason("receiver"),
+    });
+    if let Err(e) = send_message(stream, &msg, &mut [0u8; 256], protocol_version, session_id)
+        .and_then(|_| Ok(stream.flush()?))
+    {
+        debug!("Failed to tell the sender the transfer is cancelled: {}", e);
+    }
+}
+
+/// Tells the sender on `stream` that nothing more is requested on the connection (`Goodbye`), then
+/// waits up to [GOODBYE_TIMEOUT] for its own goodbye, so the connection is closed once both sides
+/// are done with it, see [Capabilities::GOODBYE]. The blocks of the connection are received
+/// already, so failures are only logged.
+fn say_goodbye<S: Connection>(stream: &mut S, state: &ReceiverState) {
+    let session_id = state.session_id();
+    let msg = ReceiverMessageV1::Goodbye(GoodbyeV1 {
+        file_hash: state.file_hash,
+    });
+    let mut buffer = [0u8; 256];
+    let result = send_message(
+        stream,
+        &msg,
+        &mut buffer,
+        state.protocol_version,
+        session_id.as_ref(),
+    )
+    .and_then(|_| Ok(stream.flush()?))
+    .and_then(|_| Ok(stream.set_read_timeout(Some(GOODBYE_TIMEOUT))?))
+    .and_then(|_| {
+        let reply = read_sender_message(
+            stream,
+            &mut buffer,
+            0,
+            state.protocol_version,
+            session_id.as_ref(),
+        )?;
+        match reply.message {
+            SenderMessageV1::Goodbye(_) => Ok(()),
+            message => Err(SendFileError::UnexpectedMessage {
+                received: format!("{:?}", message),
+                expected: String::from("Goodbye"),
+            }),
+        }
+    });
+    // Reads on the connection block for as long as they take again, as after the handshake
+    let _ = stream.set_read_timeout(None);
+    match result {
+        Ok(()) => debug!("Sender said goodbye, closing connection"),
+        Err(e) => debug!("Failed to say goodbye to the sender: {}", e),
+    }
+}
+
 fn is_transfer_complete(state: &ReceiverState) -> bool {
     state
         .received_blocks
@@ -515,8 +4517,8 @@ fn is_transfer_complete(state: &ReceiverState) -> bool {
         .all(|b| b.load(Ordering::SeqCst))
 }
 
-fn send_transfer_complete(
-    stream: &mut TcpStream,
+fn send_transfer_complete<S: Read + Write>(
+    stream: &mut S,
     state: &ReceiverState,
 ) -> Result<(), SendFileError> {
     let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
@@ -525,7 +4527,13 @@ fn send_transfer_complete(
         file_hash: state.file_hash,
     });
 
-    send_message(stream, &msg, &mut buffer)?;
+    send_message(
+        stream,
+        &msg,
+        &mut buffer,
+        state.protocol_version,
+        state.session_id().as_ref(),
+    )?;
 
     info!("Sent TransferComplete for file {:?}", state.file_path);
     Ok(())
@@ -541,7 +4549,10 @@ pub fn split_blocks_into_ranges_for_test(
 
 #[cfg(test)]
 pub fn decompress_gzip_for_test(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    decompress_gzip(data)
+    let mut output = vec![0u8; crate::transport::MAX_BLOCK_SIZE as usize];
+    let len = BlockInflater::new().inflate(data, &mut output)?;
+    output.truncate(len);
+    Ok(output)
 }
 
 #[cfg(test)]
@@ -557,9 +4568,13 @@ pub fn is_transfer_complete_for_test(received_blocks: &[bool], total_blocks: u32
         .all(|&b| b)
 }
 
+#[cfg(test)]
+mod simulation_tests;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stream::checksum::block_checksum;
     use flate2::write::GzEncoder;
     use flate2::Compression;
     use std::sync::atomic::AtomicU64;
@@ -577,9 +4592,17 @@ mod tests {
             file.set_len(1024).unwrap();
         }
 
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file_path)
+            .unwrap();
+        let sink = FileSink::new(file, false, false);
+        let control = TransferControl::new();
+
         let state = ReceiverState {
             file_hash: [0u8; 32],
-            _total_size: 100,
+            total_size: 100,
             block_size: 1024,
             _total_blocks: 1,
             sender_addr: "127.0.0.1:0".parse().unwrap(),
@@ -587,6 +4610,34 @@ mod tests {
             bytes_received: AtomicU64::new(0),
             file_path: file_path.clone(),
             is_existing_file: false,
+            written_blocks: None,
+            sink: &sink,
+            block_store: None,
+            bytes_reused: AtomicU64::new(0),
+            control: &control,
+            keepalive: None,
+            socket: SocketTuning::default(),
+            heartbeat: None,
+            best_effort: false,
+            unreadable_blocks: Mutex::new(BTreeMap::new()),
+            conn_hello: None,
+            protocol_version: crate::transport::CURRENT_PROTOCOL_VERSION,
+            tls: None,
+            noise: None,
+            udp_fec: false,
+            zero_blocks: false,
+            batching: false,
+            block_bitmap: false,
+            push_stream: false,
+            range_request: false,
+            cancel: false,
+            goodbye: false,
+            pipeline_depth: 1,
+            compression: CompressionCodec::Gzip,
+            checksum: ChecksumAlgorithm::Crc32,
+            bundle: None,
+            buffer_size: MAX_MESSAGE_SIZE,
+            retry_after: Mutex::new(None),
         };
 
         // Create compressed data
@@ -596,7 +4647,7 @@ mod tests {
         let compressed_data = encoder.finish().unwrap();
 
         // Calculate checksum on COMPRESSED data (as per sender logic)
-        let checksum_val = checksum(CrcAlgorithm::Crc32IsoHdlc, &compressed_data) as u32;
+        let checksum_val = block_checksum(&compressed_data);
 
         let data = DataV1 {
             seq: 0,
@@ -607,14 +4658,15 @@ mod tests {
         };
 
         let mut write_buffer = vec![0u8; 1024];
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&file_path)
-            .unwrap();
 
         // Execute
-        let result = process_data_block(&state, 0, data, &mut write_buffer, &mut file);
+        let result = process_data_block(
+            &state,
+            0,
+            data,
+            &mut write_buffer,
+            &mut BlockInflater::new(),
+        );
 
         // Verify
         assert!(
@@ -626,4 +4678,902 @@ mod tests {
         // Cleanup
         let _ = std::fs::remove_file(file_path);
     }
+
+    #[test]
+    fn test_missing_capability_names_peer_version() {
+        let err = missing_capability("0.3.0", Capabilities::ZSTD);
+        assert_eq!(
+            err.to_string(),
+            "Peer is sendfile 0.3.0, which doesn't support zstd"
+        );
+    }
+
+    #[test]
+    fn test_legacy_sender_is_missing_capabilities() {
+        use crate::transport::{attach_text_headers, UNKNOWN_SOFTWARE_VERSION};
+
+        // Handshake of a sender predating its software version and capabilities
+        #[derive(serde::Serialize)]
+        enum BaselineSenderMessage<'a> {
+            Handshake(&'a [u8], u64, u16, &'a str, u32),
+        }
+        let mut payload = [0u8; 256];
+        let payload = postcard::to_slice(
+            &BaselineSenderMessage::Handshake(&[7; 32], 1024, 4, "old.bin", 1024),
+            &mut payload,
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        sender.write_all(&attach_text_headers(payload)).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let output = std::env::temp_dir().join("sendfile_legacy_sender.bin");
+        let options = ReceiveOptions {
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+
+        let err = receive_over(stream, &output, &options).unwrap_err();
+        assert!(matches!(
+            err.root(),
+            SendFileError::MissingCapability { peer_version, .. }
+                if peer_version == UNKNOWN_SOFTWARE_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_single_port_transfer() {
+        use crate::stream::{options::SendOptions, send::send_file};
+
+        let dir = std::env::temp_dir().join(format!("sendfile_single_port_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        // Neither side uses the fixed data port, so any free port will do
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let send_options = SendOptions {
+            block_size: 64 * 1024,
+            concurrency: 3,
+            single_port: true,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            concurrency: 3,
+            single_port: true,
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        thread::scope(|scope| {
+            let receiver =
+                scope.spawn(|| receive_file(("127.0.0.1", port), &output, &receive_options));
+            thread::sleep(Duration::from_millis(200));
+            send_file(("127.0.0.1", port), &source, &send_options).unwrap();
+            receiver.join().unwrap().unwrap();
+        });
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_directory_files_go_out_in_the_chosen_order() {
+        use crate::stream::{bundle::FileOrder, options::SendOptions, send::send_over};
+        use std::{io, time::SystemTime};
+
+        /// Connection keeping every byte the receiver reads from it.
+        struct Recorded {
+            stream: TcpStream,
+            read: Arc<Mutex<Vec<u8>>>,
+        }
+
+        impl Read for Recorded {
+            fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+                let len = self.stream.read(buffer)?;
+                self.read.lock().unwrap().extend_from_slice(&buffer[..len]);
+                Ok(len)
+            }
+        }
+
+        impl Write for Recorded {
+            fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+                self.stream.write(buffer)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.stream.flush()
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("sendfile_order_{}", std::process::id()));
+        let source = dir.join("logs");
+        std::fs::create_dir_all(&source).unwrap();
+        // Each file filled with a byte of its own, so its blocks can be told apart on the wire
+        let files = [
+            ("a.log", 3000, b'x', 20),
+            ("b.log", 1000, b'y', 30),
+            ("c.log", 5000, b'z', 10),
+        ];
+        for (name, size, fill, age) in files {
+            std::fs::write(source.join(name), vec![fill; size]).unwrap();
+            let file = File::options().write(true).open(source.join(name)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age * 60))
+                .unwrap();
+        }
+
+        let transfer = |order| {
+            let output = dir.join(format!("{:?}", order));
+            std::fs::create_dir_all(&output).unwrap();
+            let send_options = SendOptions {
+                block_size: 4096,
+                should_compress: false,
+                file_order: order,
+                history_path: None,
+                identity_path: None,
+                peers_path: None,
+                ..SendOptions::default()
+            };
+            let receive_options = ReceiveOptions {
+                identity_path: None,
+                peers_path: None,
+                ..ReceiveOptions::default()
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let read = Arc::new(Mutex::new(Vec::new()));
+            thread::scope(|scope| {
+                let receiver = scope.spawn(|| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let recorded = Recorded {
+                        stream,
+                        read: read.clone(),
+                    };
+                    receive_over(recorded, &output, &receive_options)
+                });
+                let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                send_over(stream, &source, &send_options).unwrap();
+                receiver.join().unwrap().unwrap();
+            });
+            for (name, size, fill, _) in files {
+                assert_eq!(
+                    std::fs::read(output.join("logs").join(name)).unwrap(),
+                    vec![fill; size]
+                );
+            }
+
+            // Files named in the file list, then their blocks sent, in that order
+            let read = read.lock().unwrap();
+            let position = |needle: &[u8]| {
+                read.windows(needle.len())
+                    .position(|window| window == needle)
+                    .unwrap()
+            };
+            let listed: Vec<usize> = files
+                .iter()
+                .map(|(name, ..)| position(name.as_bytes()))
+                .collect();
+            let sent: Vec<usize> = files
+                .iter()
+                .map(|(_, _, fill, _)| position(&[*fill; 512]))
+                .collect();
+            let order_of = |positions: Vec<usize>| {
+                let mut names: Vec<(usize, &str)> = positions
+                    .into_iter()
+                    .zip(files.iter().map(|(name, ..)| *name))
+                    .collect();
+                names.sort();
+                names.into_iter().map(|(_, name)| name).collect::<Vec<_>>()
+            };
+            let listed = order_of(listed);
+            assert_eq!(listed, order_of(sent));
+            listed
+        };
+
+        assert_eq!(transfer(FileOrder::Name), ["a.log", "b.log", "c.log"]);
+        assert_eq!(transfer(FileOrder::SizeAsc), ["b.log", "a.log", "c.log"]);
+        assert_eq!(transfer(FileOrder::SizeDesc), ["c.log", "a.log", "b.log"]);
+        assert_eq!(transfer(FileOrder::Mtime), ["c.log", "a.log", "b.log"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_atomic_batch_leaves_the_destination_untouched_on_failure() {
+        use crate::stream::{options::SendOptions, send::send_over};
+        use std::{io, net::Shutdown};
+
+        /// Connection dropped once the receiver read `remaining` bytes from it.
+        struct Cutoff {
+            stream: TcpStream,
+            remaining: usize,
+        }
+
+        impl Read for Cutoff {
+            fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+                if self.remaining == 0 {
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    return Err(io::ErrorKind::ConnectionReset.into());
+                }
+                let len = buffer.len().min(self.remaining);
+                let len = self.stream.read(&mut buffer[..len])?;
+                self.remaining -= len;
+                Ok(len)
+            }
+        }
+
+        impl Write for Cutoff {
+            fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+                self.stream.write(buffer)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.stream.flush()
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("sendfile_batch_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = dir.join("batch");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        let files = [("a.bin", b'a'), ("b.bin", b'b'), ("sub/c.bin", b'c')];
+        for (name, fill) in files {
+            std::fs::write(source.join(name), vec![fill; 40_000]).unwrap();
+        }
+        let output = dir.join("output");
+        let destination = output.join("batch");
+        std::fs::create_dir_all(&destination).unwrap();
+        std::fs::write(destination.join("a.bin"), b"old").unwrap();
+
+        // Drops the connection after `cutoff` bytes, halfway through the files
+        let transfer = |atomic_batch, cutoff| {
+            let send_options = SendOptions {
+                block_size: 4096,
+                should_compress: false,
+                history_path: None,
+                identity_path: None,
+                peers_path: None,
+                ..SendOptions::default()
+            };
+            let receive_options = ReceiveOptions {
+                atomic_batch,
+                identity_path: None,
+                peers_path: None,
+                ..ReceiveOptions::default()
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            thread::scope(|scope| {
+                let receiver = scope.spawn(|| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let remaining = cutoff;
+                    receive_over(Cutoff { stream, remaining }, &output, &receive_options)
+                });
+                let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let _ = send_over(stream, &source, &send_options);
+                receiver.join().unwrap()
+            })
+        };
+
+        assert!(transfer(true, 60_000).is_err());
+        assert_eq!(std::fs::read(destination.join("a.bin")).unwrap(), b"old");
+        assert!(!destination.join("b.bin").exists());
+        assert!(!destination.join("sub").exists());
+        assert!(!staged_path(&destination).exists());
+
+        // Without it, the files received so far are left behind
+        assert!(transfer(false, 60_000).is_err());
+        assert_ne!(std::fs::read(destination.join("a.bin")).unwrap(), b"old");
+        assert!(destination.join("sub/c.bin").exists());
+
+        transfer(true, usize::MAX).unwrap();
+        for (name, fill) in files {
+            assert_eq!(
+                std::fs::read(destination.join(name)).unwrap(),
+                vec![fill; 40_000]
+            );
+        }
+        assert!(!staged_path(&destination).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_paired_only_refuses_unknown_senders() {
+        use crate::{
+            identity::Identity,
+            peers::{Peer, PeerOptions},
+            stream::{options::SendOptions, send::send_over},
+        };
+
+        let dir = std::env::temp_dir().join(format!("sendfile_paired_only_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        let identity_path = dir.join("identity.key");
+        let peers_path = dir.join("peers.json");
+        let send_options = SendOptions {
+            block_size: 16 * 1024,
+            history_path: None,
+            identity_path: Some(identity_path.clone()),
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            identity_path: None,
+            peers_path: Some(peers_path.clone()),
+            paired_only: true,
+            ..ReceiveOptions::default()
+        };
+        let transfer = || {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::scope(|scope| {
+                let receiver = scope.spawn(|| {
+                    let (stream, _) = listener.accept().unwrap();
+                    receive_over(stream, &output, &receive_options)
+                });
+                let sent = send_over(TcpStream::connect(addr).unwrap(), &source, &send_options);
+                (sent, receiver.join().unwrap())
+            })
+        };
+
+        let (sent, received) = transfer();
+        assert!(matches!(
+            sent.as_ref().map_err(SendFileError::root),
+            Err(SendFileError::OfferRejected(reason)) if reason.contains("not a paired peer")
+        ));
+        assert!(matches!(received, Err(SendFileError::OfferRejected(_))));
+
+        let identity = Identity::load_or_generate(&identity_path).unwrap();
+        let mut peers = PeerRegistry::default();
+        peers.insert(Peer {
+            name: String::from("laptop"),
+            public_key: to_hex(&identity.public_key()),
+            addresses: Vec::new(),
+            port: None,
+            options: PeerOptions::default(),
+        });
+        peers.save(&peers_path).unwrap();
+        let (sent, received) = transfer();
+        sent.unwrap();
+        received.unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_receiver_without_identity_answers_no_receipt() {
+        use crate::stream::{options::SendOptions, send::send_over};
+
+        let dir = std::env::temp_dir().join(format!("sendfile_no_receipt_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        let send_options = SendOptions {
+            block_size: 16 * 1024,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let started = Instant::now();
+        thread::scope(|scope| {
+            let receiver = scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                // Kept open until the sender is done, as a tunnel would be
+                let kept = stream.try_clone().unwrap();
+                (receive_over(stream, &output, &receive_options), kept)
+            });
+            send_over(TcpStream::connect(addr).unwrap(), &source, &send_options).unwrap();
+            assert!(started.elapsed() < Duration::from_secs(30));
+            receiver.join().unwrap().0.unwrap();
+        });
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_scanned_file_is_renamed_once_it_passes() {
+        use crate::stream::{options::SendOptions, send::send_over};
+
+        let dir = std::env::temp_dir().join(format!("sendfile_scan_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let quarantine_dir = dir.join("quarantine");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        let send_options = SendOptions {
+            block_size: 16 * 1024,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let transfer = |command: String| {
+            let receive_options = ReceiveOptions {
+                identity_path: None,
+                peers_path: None,
+                scan: Some(ScanHook {
+                    command,
+                    quarantine_dir: quarantine_dir.clone(),
+                    timeout: Duration::from_secs(30),
+                }),
+                ..ReceiveOptions::default()
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::scope(|scope| {
+                let receiver = scope.spawn(|| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let kept = stream.try_clone().unwrap();
+                    (receive_over(stream, &output, &receive_options), kept)
+                });
+                let sent = send_over(TcpStream::connect(addr).unwrap(), &source, &send_options);
+                (sent, receiver.join().unwrap().0)
+            })
+        };
+
+        // The staged file is scanned, with nothing at the destination yet
+        let (sent, received) = transfer(format!(
+            "f() {{ test ! -e {:?} && test \"$1\" = {:?}; }}; f",
+            output,
+            staged_path(&output)
+        ));
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert!(!staged_path(&output).exists());
+
+        std::fs::remove_file(&output).unwrap();
+        let (sent, received) = transfer(String::from("false"));
+        assert!(sent.is_err());
+        let received = received.unwrap_err();
+        assert!(matches!(received.root(), SendFileError::ScanFailed(_)), "{:?}", received);
+        assert!(!output.exists());
+        assert!(!staged_path(&output).exists());
+        assert_eq!(std::fs::read_dir(&quarantine_dir).unwrap().count(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_resume_verifies_blocks_in_batches() {
+        use crate::stream::{options::SendOptions, send::send_file};
+
+        let dir = std::env::temp_dir().join(format!("sendfile_batching_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("source.bin.out");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        // 74 blocks on disk, more than a batch, two of them stale
+        let mut existing = data.clone();
+        existing[10_000] ^= 0xFF;
+        existing[290_000] ^= 0xFF;
+        std::fs::write(&output, &existing).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let send_options = SendOptions {
+            block_size: 4096,
+            concurrency: 1,
+            single_port: true,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            concurrency: 1,
+            single_port: true,
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        thread::scope(|scope| {
+            let receiver =
+                scope.spawn(|| receive_file(("127.0.0.1", port), &output, &receive_options));
+            thread::sleep(Duration::from_millis(200));
+            send_file(("127.0.0.1", port), &source, &send_options).unwrap();
+            receiver.join().unwrap().unwrap();
+        });
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_restarted_receiver_resumes_recorded_session() {
+        use crate::stream::{
+            options::SendOptions,
+            send::send_file,
+            sessions::{find_session, record_session, SessionDescriptor},
+        };
+
+        let dir = std::env::temp_dir().join(format!("sendfile_restart_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let earlier = dir.join("earlier");
+        let output_dir = dir.join("output");
+        std::fs::create_dir_all(&earlier).unwrap();
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let source = dir.join("source.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        let hash = *blake3::hash(&data).as_bytes();
+
+        // The receiver was stopped with half of the file written to its earlier output directory
+        let partial = earlier.join("source.bin");
+        std::fs::write(&partial, &data[..50_000]).unwrap();
+        ResumeState::new(&hash, data.len() as u64, 4096, (0..25).map(|seq| seq < 12))
+            .save(&resume_state_path(&partial))
+            .unwrap();
+        let sessions = dir.join("sessions.jsonl");
+        record_session(
+            &sessions,
+            &SessionDescriptor {
+                started_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                peer: "127.0.0.1".parse().unwrap(),
+                sender_key: None,
+                file_name: String::from("source.bin"),
+                file_hash: to_hex(&hash),
+                bytes: data.len() as u64,
+                output_path: partial.clone(),
+                write_path: partial.clone(),
+            },
+        )
+        .unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let send_options = SendOptions {
+            block_size: 4096,
+            single_port: true,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            single_port: true,
+            identity_path: None,
+            peers_path: None,
+            sessions: Some(sessions.clone()),
+            // Any receiver resumes recorded sessions, not only one retrying lost senders
+            auto_retry: None,
+            ..ReceiveOptions::default()
+        };
+        thread::scope(|scope| {
+            let receiver =
+                scope.spawn(|| receive_file(("127.0.0.1", port), &output_dir, &receive_options));
+            thread::sleep(Duration::from_millis(200));
+            send_file(("127.0.0.1", port), &source, &send_options).unwrap();
+            receiver.join().unwrap().unwrap();
+        });
+
+        assert_eq!(std::fs::read(&partial).unwrap(), data);
+        assert!(!output_dir.join("source.bin").exists());
+        assert_eq!(find_session(&sessions, &hash).unwrap(), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_receiver_rejoins_restarted_sender() {
+        use crate::stream::{
+            options::SendOptions,
+            send::send_file_with,
+        };
+
+        let dir = std::env::temp_dir().join(format!("sendfile_rejoin_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let data: Vec<u8> = (0..2_000_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        std::fs::write(&source, &data).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let send_options = SendOptions {
+            block_size: 16 * 1024,
+            concurrency: 2,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            auto_retry: Some(Duration::from_secs(30)),
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        thread::scope(|scope| {
+            let receiver =
+                scope.spawn(|| receive_file(("127.0.0.1", port), &output, &receive_options));
+            thread::sleep(Duration::from_millis(200));
+
+            let control = Arc::new(TransferControl::new());
+            control.limit_rate(Some(1024 * 1024));
+            let first = scope.spawn({
+                let (control, source, send_options) = (control.clone(), &source, &send_options);
+                move || send_file_with(("127.0.0.1", port), source, send_options, &control)
+            });
+            let started = Instant::now();
+            while control.progress().bytes_transferred < 256 * 1024 {
+                assert!(
+                    started.elapsed() < Duration::from_secs(20),
+                    "Sender never started"
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+            // The sender goes away without telling the receiver, as if it was killed: the
+            // control keeps clones of its connections, so it is dropped to close them
+            control.cancel_by_peer(String::from("killed"));
+            drop(control);
+            assert!(first.join().unwrap().is_err());
+
+            // The sender started again takes over the session and only serves the missing blocks
+            let restarted = Arc::new(TransferControl::new());
+            send_file_with(("127.0.0.1", port), &source, &send_options, &restarted).unwrap();
+            receiver.join().unwrap().unwrap();
+            assert!(restarted.own_bytes() < data.len() as u64);
+        });
+
+        let received = std::fs::read(&output).unwrap();
+        assert_eq!(blake3::hash(&received), blake3::hash(&data));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Handshake connection whose writes stay readable once it is moved into a session.
+    #[derive(Clone, Default)]
+    struct SharedStream(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Returns a session of a 1 MB file hashing to `hash` from `sender_addr`, offered over
+    /// `stream`.
+    fn offered_session(
+        hash: [u8; 32],
+        block_size: u32,
+        sender_addr: &str,
+        stream: SharedStream,
+    ) -> Session<SharedStream> {
+        let (mut features, _) =
+            FeatureSet::negotiate(Capabilities::local(), Capabilities::local()).unwrap();
+        // Only set by senders asking for them
+        features.dry_run = false;
+        features.probe = false;
+        Session {
+            stream: NoiseStream::new(stream),
+            sender_addr: sender_addr.parse().unwrap(),
+            file_name: String::from("image.iso"),
+            expected_hash: hash,
+            total_size: 1_000_000,
+            block_size,
+            concurrency: 2,
+            features,
+            sender_key: None,
+            file_header: None,
+            metadata: Metadata::new(),
+            clock_skew: None,
+            conn_hello: None,
+            protocol_version: crate::transport::CURRENT_PROTOCOL_VERSION,
+            bundle: None,
+            tls: None,
+            listener: None,
+            trace: TraceSpan::none(),
+        }
+    }
+
+    #[test]
+    fn test_rejoin_session_accepts_the_same_file() {
+        let mut session =
+            offered_session([1; 32], 4096, "192.0.2.7:40000", SharedStream::default());
+        let restarted = SharedStream::default();
+        let offer = offered_session([1; 32], 4096, "192.0.2.8:40001", restarted.clone());
+
+        assert!(rejoin_session(&mut session, offer).unwrap());
+        assert_eq!(session.sender_addr, "192.0.2.8:40001".parse().unwrap());
+        // The restarted sender is told its offer is accepted, and is answered from now on
+        let acknowledged = restarted.0.lock().unwrap().len();
+        assert!(acknowledged > 0);
+        session.stream.write_all(b"next").unwrap();
+        assert_eq!(restarted.0.lock().unwrap().len(), acknowledged + 4);
+    }
+
+    #[test]
+    fn test_rejoin_session_turns_down_other_offers() {
+        let original = SharedStream::default();
+        let mut session = offered_session([1; 32], 4096, "192.0.2.7:40000", original.clone());
+        let mut dry_run =
+            offered_session([1; 32], 4096, "192.0.2.7:40001", SharedStream::default());
+        dry_run.features.dry_run = true;
+        let offers = [
+            // Another file
+            offered_session([2; 32], 4096, "192.0.2.7:40001", SharedStream::default()),
+            // The same file in other blocks
+            offered_session([1; 32], 8192, "192.0.2.7:40001", SharedStream::default()),
+            dry_run,
+        ];
+
+        for offer in offers {
+            let turned_down = offer.stream.get_ref().clone();
+            assert!(!rejoin_session(&mut session, offer).unwrap());
+            // The sender is told the receiver is busy
+            let answer = turned_down.0.lock().unwrap().clone();
+            assert!(
+                answer
+                    .windows(b"Receiver is busy".len())
+                    .any(|window| window == b"Receiver is busy")
+            );
+        }
+        assert_eq!(session.sender_addr, "192.0.2.7:40000".parse().unwrap());
+        assert!(original.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resume_names_written_blocks() {
+        use crate::stream::{options::SendOptions, send::send_file};
+
+        let dir = std::env::temp_dir().join(format!("sendfile_bitmap_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("source.bin.out");
+        let mut data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        data[2 * 4096..3 * 4096].fill(0);
+        std::fs::write(&source, &data).unwrap();
+        // Of the 74 blocks, the interrupted transfer wrote the first 40 but 2, 5 and 20..25,
+        // which hold garbage like the rest of the file
+        let written = |seq: usize| seq < 40 && seq != 2 && seq != 5 && !(20..25).contains(&seq);
+        let existing: Vec<u8> = data
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| if written(i / 4096) { byte } else { !byte })
+            .collect();
+        std::fs::write(&output, &existing).unwrap();
+        ResumeState::new(
+            blake3::hash(&data).as_bytes(),
+            data.len() as u64,
+            4096,
+            (0..74).map(written),
+        )
+        .save(&resume_state_path(&output))
+        .unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let send_options = SendOptions {
+            block_size: 4096,
+            concurrency: 2,
+            single_port: true,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            concurrency: 2,
+            single_port: true,
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        thread::scope(|scope| {
+            let receiver =
+                scope.spawn(|| receive_file(("127.0.0.1", port), &output, &receive_options));
+            thread::sleep(Duration::from_millis(200));
+            send_file(("127.0.0.1", port), &source, &send_options).unwrap();
+            receiver.join().unwrap().unwrap();
+        });
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert!(!resume_state_path(&output).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reuse_duplicate() {
+        use std::os::unix::fs::MetadataExt;
+
+        use crate::{
+            file::duplicate::DuplicateMode,
+            stream::{options::SendOptions, send::send_file},
+        };
+
+        let dir = std::env::temp_dir().join(format!("sendfile_reuse_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("out").join("old")).unwrap();
+        let source = dir.join("source.bin");
+        let first = dir.join("out").join("old").join("first.bin");
+        let second = dir.join("out").join("second.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let send_options = SendOptions {
+            block_size: 64 * 1024,
+            single_port: true,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            single_port: true,
+            received_index: Some(dir.join("received.jsonl")),
+            reuse_duplicates: Some(DuplicateMode::Link),
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        for output in [&first, &second] {
+            thread::scope(|scope| {
+                let receiver =
+                    scope.spawn(|| receive_file(("127.0.0.1", port), output, &receive_options));
+                thread::sleep(Duration::from_millis(200));
+                send_file(("127.0.0.1", port), &source, &send_options).unwrap();
+                receiver.join().unwrap().unwrap();
+            });
+        }
+
+        assert_eq!(std::fs::read(&second).unwrap(), data);
+        // Linked to the first file rather than downloaded
+        assert_eq!(std::fs::metadata(&first).unwrap().nlink(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,468 @@
+//! Simulated transfers checking the invariants of the block scheduler.
+//!
+//! Each case receives a random file through the real receive path ([transfer_range] over the
+//! ranges of [split_blocks_into_ranges]), with the real sender answering requests in-process. A
+//! [FaultyStream] stands in for each data connection and drops it or corrupts blocks as scripted
+//! by the case, and receivers may restart between rounds, resuming from a [ResumeState] with
+//! another block size. Whatever happens:
+//!
+//! - blocks are only written within the file and within their block,
+//! - a block is written exactly once until the receiver restarts, and only once verified,
+//! - a round without faults completes the file, and the file is identical to the sender's.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Cursor, Read, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use proptest::{prelude::*, test_runner::TestCaseError};
+
+use super::{pending_ranges, split_blocks_into_ranges, transfer_range, ReceiverState};
+use crate::{
+    capabilities::{ChecksumAlgorithm, CompressionCodec},
+    connection::read_next_payload,
+    file::resume::ResumeState,
+    stream::{
+        estimate::DEFAULT_ENTROPY_THRESHOLD, handle::TransferControl, preconnected::Connection,
+        profile::ReceiveProfile, send::ConnectionHandler, sink::BlockSink, socket::SocketTuning,
+        source::ReaderSource,
+    },
+    transport::{PushRangeV1, ReceiverMessageV1, SenderMessageV1, CURRENT_PROTOCOL_VERSION},
+};
+
+/// Faults injected into one data connection.
+#[derive(Debug, Clone, Default)]
+struct ConnectionFaults {
+    /// Number of blocks sent before the connection drops, `None` if it never does.
+    drop_after: Option<usize>,
+    /// Responses, counted from 0, whose block data is corrupted.
+    corrupt: BTreeSet<usize>,
+}
+
+/// One round of data connections.
+#[derive(Debug, Clone, Default)]
+struct Round {
+    /// Faults of the connection of each pending range, in order. Missing entries have no faults.
+    faults: Vec<ConnectionFaults>,
+    /// Block size the receiver restarts with before the round, if it does.
+    restart: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct Scenario {
+    data: Vec<u8>,
+    block_size: u32,
+    concurrency: u16,
+    compress: bool,
+    /// Block requests each connection keeps outstanding.
+    pipeline_depth: usize,
+    /// Whether the sender streams the blocks of each range, see [super::stream_missing_blocks].
+    push: bool,
+    /// Whether runs of blocks are requested with a single message, see
+    /// [super::send_range_request].
+    range_request: bool,
+    rounds: Vec<Round>,
+}
+
+/// Data connection to an in-process sender, injecting faults into its answers.
+struct FaultyStream<'a> {
+    handler: ConnectionHandler<ReaderSource<Cursor<&'a [u8]>>>,
+    compress: bool,
+    faults: ConnectionFaults,
+    total_blocks: u64,
+    /// Range being streamed, once the receiver asked for it.
+    push: Option<PushRangeV1>,
+    /// Answers not read yet.
+    outbound: Vec<u8>,
+    read_pos: usize,
+    responses: usize,
+    dropped: bool,
+}
+
+impl<'a> FaultyStream<'a> {
+    fn new(data: &'a [u8], file_hash: [u8; 32], block_size: u32, scenario: &Scenario) -> Self {
+        Self {
+            handler: ConnectionHandler {
+                entropy_threshold: DEFAULT_ENTROPY_THRESHOLD,
+                ..ConnectionHandler::for_test(
+                    ReaderSource::new(Cursor::new(data), data.len() as u64),
+                    file_hash,
+                    block_size,
+                )
+            },
+            compress: scenario.compress,
+            faults: ConnectionFaults::default(),
+            total_blocks: (data.len() as u64).div_ceil(block_size as u64),
+            push: None,
+            outbound: Vec::new(),
+            read_pos: 0,
+            responses: 0,
+            dropped: false,
+        }
+    }
+
+    fn reset() -> io::Error {
+        io::Error::from(io::ErrorKind::ConnectionReset)
+    }
+
+    /// Queues the answers of `answer`, one block each, dropping the connection or corrupting
+    /// them as scripted.
+    fn answer(&mut self, mut answer: Vec<u8>) -> io::Result<()> {
+        let mut start = 0;
+        while start < answer.len() {
+            let len = answer.len() - start;
+            let end = read_next_payload::<SenderMessageV1, _>(
+                &mut io::empty(),
+                &mut answer[start..],
+                len,
+            )
+            .map_err(io::Error::other)?
+            .next_payload_index
+            .map_or(answer.len(), |next| start + next);
+            if self.faults.drop_after == Some(self.responses) {
+                self.dropped = true;
+                return Ok(());
+            }
+            if self.faults.corrupt.contains(&self.responses) {
+                // The block data ends the message
+                answer[end - 1] ^= 0xFF;
+            }
+            self.outbound.extend_from_slice(&answer[start..end]);
+            self.responses += 1;
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+impl Write for FaultyStream<'_> {
+    /// Takes one whole message, as the receiver writes them.
+    fn write(&mut self, frame: &[u8]) -> io::Result<usize> {
+        if self.dropped {
+            return Err(Self::reset());
+        }
+        let mut buffer = frame.to_vec();
+        let message =
+            read_next_payload::<ReceiverMessageV1, _>(&mut io::empty(), &mut buffer, frame.len())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .message;
+
+        match message {
+            ReceiverMessageV1::Request(request) => {
+                let mut answer = Vec::new();
+                self.handler
+                    .handle_data_request(&request, &mut answer, self.compress)
+                    .map_err(io::Error::other)?;
+                self.answer(answer)?;
+            }
+            ReceiverMessageV1::RequestRange(range) => {
+                let mut answer = Vec::new();
+                self.handler
+                    .handle_request_range(
+                        &range,
+                        self.total_blocks,
+                        &mut answer,
+                        self.compress,
+                        |_| Ok(()),
+                    )
+                    .map_err(io::Error::other)?;
+                self.answer(answer)?;
+            }
+            ReceiverMessageV1::PushRange(push) => {
+                let mut answer = Vec::new();
+                self.handler
+                    .handle_push_range(&push, self.total_blocks, &mut answer, self.compress, |_| {
+                        Ok(())
+                    })
+                    .map_err(io::Error::other)?;
+                self.push = Some(push);
+                self.answer(answer)?;
+            }
+            ReceiverMessageV1::PushAck(ack) => {
+                let push = self
+                    .push
+                    .ok_or_else(|| io::Error::other("Ack before PushRange"))?;
+                let mut answer = Vec::new();
+                self.handler
+                    .handle_push_ack(&ack, &push, &mut answer, self.compress, |_| Ok(()))
+                    .map_err(io::Error::other)?;
+                self.answer(answer)?;
+            }
+            ReceiverMessageV1::ConnHello(_) | ReceiverMessageV1::TransferComplete(_) => {}
+            message => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected message {message:?}"),
+                ));
+            }
+        }
+        Ok(frame.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FaultyStream<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let pending = &self.outbound[self.read_pos..];
+        if pending.is_empty() {
+            return if self.dropped {
+                Err(Self::reset())
+            } else {
+                Ok(0)
+            };
+        }
+        let len = pending.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&pending[..len]);
+        self.read_pos += len;
+        Ok(len)
+    }
+}
+
+impl Connection for FaultyStream<'_> {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sink recording every write, refusing those outside the file or their block.
+struct RecordingSink {
+    content: Mutex<Vec<u8>>,
+    block_size: u32,
+    writes: Mutex<Vec<u32>>,
+    violations: Mutex<Vec<String>>,
+}
+
+impl RecordingSink {
+    fn new(content: Vec<u8>, block_size: u32) -> Self {
+        let total_blocks = content.len().div_ceil(block_size as usize);
+        Self {
+            content: Mutex::new(content),
+            block_size,
+            writes: Mutex::new(vec![0; total_blocks]),
+            violations: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl BlockSink for RecordingSink {
+    fn write_block(&self, seq: u32, block_size: u32, data: &[u8]) -> io::Result<()> {
+        let mut content = self.content.lock().unwrap();
+        let start = seq as usize * block_size as usize;
+        let end = start + data.len();
+        if block_size != self.block_size || data.len() > block_size as usize || end > content.len()
+        {
+            self.violations.lock().unwrap().push(format!(
+                "block {seq} of {block_size} bytes written with {} bytes",
+                data.len()
+            ));
+            return Ok(());
+        }
+        content[start..end].copy_from_slice(data);
+        self.writes.lock().unwrap()[seq as usize] += 1;
+        Ok(())
+    }
+
+    fn read_block(&self, _seq: u32, _block_size: u32) -> io::Result<Vec<u8>> {
+        Err(io::Error::other(
+            "Simulated receivers do not resume into files",
+        ))
+    }
+}
+
+fn receiver_state<'a>(
+    sink: &'a RecordingSink,
+    control: &'a TransferControl,
+    file_hash: [u8; 32],
+    received: &[bool],
+) -> ReceiverState<'a> {
+    let total_size = sink.content.lock().unwrap().len() as u64;
+    ReceiverState {
+        file_hash,
+        total_size,
+        block_size: sink.block_size,
+        _total_blocks: received.len() as u32,
+        sender_addr: "127.0.0.1:0".parse().unwrap(),
+        received_blocks: received.iter().map(|&r| AtomicBool::new(r)).collect(),
+        bytes_received: AtomicU64::new(0),
+        file_path: PathBuf::from("simulated"),
+        is_existing_file: false,
+        written_blocks: None,
+        sink,
+        block_store: None,
+        bytes_reused: AtomicU64::new(0),
+        control,
+        keepalive: None,
+        socket: SocketTuning::default(),
+        heartbeat: None,
+        best_effort: false,
+        unreadable_blocks: Mutex::new(Default::default()),
+        conn_hello: None,
+        protocol_version: CURRENT_PROTOCOL_VERSION,
+        tls: None,
+        noise: None,
+        udp_fec: false,
+        zero_blocks: false,
+        batching: false,
+        block_bitmap: false,
+        push_stream: false,
+        range_request: false,
+        cancel: false,
+        goodbye: false,
+        pipeline_depth: 1,
+        compression: CompressionCodec::Gzip,
+        checksum: ChecksumAlgorithm::Crc32,
+        bundle: None,
+        // As small as buffers get, so every message must fit a block
+        buffer_size: ReceiveProfile::LowMemory.message_buffer_size(sink.block_size),
+        retry_after: Mutex::new(None),
+    }
+}
+
+fn simulate(scenario: &Scenario) -> Result<(), TestCaseError> {
+    let data = &scenario.data[..];
+    let size = data.len() as u64;
+    let file_hash: [u8; 32] = blake3::hash(data).into();
+    let control = TransferControl::new();
+
+    let mut block_size = scenario.block_size;
+    let mut received = vec![false; size.div_ceil(block_size as u64) as usize];
+    let mut content = vec![0u8; data.len()];
+    let clean_round = Round::default();
+
+    for (index, round) in scenario.rounds.iter().chain([&clean_round]).enumerate() {
+        if let Some(restart_size) = round.restart {
+            let resume = ResumeState::new(&file_hash, size, block_size, received.iter().copied());
+            received = resume.received_blocks(size, restart_size).unwrap();
+            block_size = restart_size;
+        }
+
+        let sink = RecordingSink::new(content, block_size);
+        let mut state = receiver_state(&sink, &control, file_hash, &received);
+        state.pipeline_depth = scenario.pipeline_depth;
+        state.push_stream = scenario.push;
+        state.range_request = scenario.range_request;
+        let ranges = split_blocks_into_ranges(received.len() as u32, scenario.concurrency);
+        thread::scope(|scope| {
+            for (connection, range) in pending_ranges(&state, &ranges).into_iter().enumerate() {
+                let mut stream = FaultyStream::new(data, file_hash, block_size, scenario);
+                stream.faults = round.faults.get(connection).cloned().unwrap_or_default();
+                let state = &state;
+                scope.spawn(move || {
+                    // Failed connections are picked up by the next round
+                    let _ = transfer_range(&mut stream, state, range.start, range.end, true);
+                });
+            }
+        });
+
+        let violations = sink.violations.lock().unwrap().clone();
+        prop_assert!(violations.is_empty(), "round {}: {:?}", index, violations);
+        let writes = sink.writes.lock().unwrap().clone();
+        for (seq, was_received) in received.iter().enumerate() {
+            let is_received = state.received_blocks[seq].load(Ordering::SeqCst);
+            let expected_writes = u32::from(is_received && !was_received);
+            prop_assert_eq!(
+                writes[seq],
+                expected_writes,
+                "round {}: block {} written {} times",
+                index,
+                seq,
+                writes[seq]
+            );
+        }
+
+        received = state
+            .received_blocks
+            .iter()
+            .map(|r| r.load(Ordering::SeqCst))
+            .collect();
+        content = sink.content.into_inner().unwrap();
+    }
+
+    prop_assert!(
+        received.iter().all(|&r| r),
+        "clean round left blocks missing"
+    );
+    prop_assert!(content == data, "received file differs from the sent file");
+    Ok(())
+}
+
+fn block_size_strategy() -> impl Strategy<Value = u32> {
+    prop_oneof![Just(1000u32), Just(4096), Just(16384), Just(65536)]
+}
+
+fn round_strategy() -> impl Strategy<Value = Round> {
+    let faults = (
+        proptest::option::of(0usize..12),
+        proptest::collection::btree_set(0usize..12, 0..4),
+    )
+        .prop_map(|(drop_after, corrupt)| ConnectionFaults {
+            drop_after,
+            corrupt,
+        });
+    (
+        proptest::collection::vec(faults, 0..8),
+        proptest::option::weighted(0.3, block_size_strategy()),
+    )
+        .prop_map(|(faults, restart)| Round { faults, restart })
+}
+
+fn scenario_strategy() -> impl Strategy<Value = Scenario> {
+    (
+        0usize..200_000,
+        any::<u8>(),
+        block_size_strategy(),
+        1u16..=8,
+        any::<bool>(),
+        1usize..=8,
+        any::<bool>(),
+        any::<bool>(),
+        proptest::collection::vec(round_strategy(), 0..4),
+    )
+        .prop_map(
+            |(
+                size,
+                seed,
+                block_size,
+                concurrency,
+                compress,
+                pipeline_depth,
+                push,
+                range_request,
+                rounds,
+            )| {
+                // Runs of repeated bytes, so some blocks compress and others don't
+                let data = (0..size)
+                    .map(|i| ((i / 64) as u8).wrapping_mul(seed) ^ (i % 3) as u8)
+                    .collect();
+                Scenario {
+                    data,
+                    block_size,
+                    concurrency,
+                    compress,
+                    pipeline_depth,
+                    push,
+                    range_request,
+                    rounds,
+                }
+            },
+        )
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_simulated_transfers_deliver_every_block_once(scenario in scenario_strategy()) {
+        simulate(&scenario)?;
+    }
+}
@@ -0,0 +1,331 @@
+//! Process-wide list of the transfers currently running.
+//!
+//! Every send and receive registers itself in [TransferRegistry::global] for its duration, so a
+//! long-running process can report what it is doing (see [crate::status]) and observers can
+//! [subscribe](TransferRegistry::subscribe) to transfers starting and finishing.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stream::{bottleneck::Stage, bundle::FileStatus, handle::TransferControl};
+
+/// Whether this side sends or receives the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Send,
+    Receive,
+}
+
+/// Point-in-time view of an active transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferStatus {
+    /// Identifier of the transfer, unique within the process.
+    pub id: u64,
+    pub direction: TransferDirection,
+    /// Address of the peer.
+    pub peer: String,
+    /// Name of the transferred file.
+    pub file_name: String,
+    /// Bytes transferred (or reused) so far.
+    pub bytes_transferred: u64,
+    /// Size of the file in bytes.
+    pub total_bytes: Option<u64>,
+    /// Whether the transfer is paused.
+    pub paused: bool,
+    /// Start of the transfer, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// Stage of the pipeline currently limiting the transfer, see [crate::stream::bottleneck].
+    #[serde(default)]
+    pub bottleneck: Option<Stage>,
+    /// Progress of every file when a directory is transferred, see [crate::stream::bundle].
+    #[serde(default)]
+    pub files: Vec<FileStatus>,
+}
+
+/// Change in the set of active transfers, see [TransferRegistry::subscribe].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryEvent {
+    /// A transfer was registered.
+    Started(TransferStatus),
+    /// A transfer ended.
+    Finished {
+        id: u64,
+        file_name: String,
+        /// Whether the file was delivered, `false` if the transfer failed or was cancelled.
+        succeeded: bool,
+    },
+}
+
+struct Entry {
+    direction: TransferDirection,
+    peer: String,
+    file_name: String,
+    started_at: u64,
+    control: Arc<TransferControl>,
+}
+
+/// Thread-safe set of active transfers with their live counters.
+#[derive(Default)]
+pub struct TransferRegistry {
+    next_id: AtomicU64,
+    entries: Mutex<BTreeMap<u64, Entry>>,
+    subscribers: Mutex<Vec<Sender<RegistryEvent>>>,
+}
+
+impl TransferRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the registry the transfers of this process register in.
+    pub fn global() -> &'static Self {
+        static GLOBAL: OnceLock<TransferRegistry> = OnceLock::new();
+        GLOBAL.get_or_init(Self::new)
+    }
+
+    /// Adds a transfer whose progress is tracked by `control`. It is listed until the returned
+    /// guard is dropped.
+    pub fn register(
+        &self,
+        direction: TransferDirection,
+        peer: String,
+        file_name: String,
+        control: Arc<TransferControl>,
+    ) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let entry = Entry {
+            direction,
+            peer,
+            file_name,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            control,
+        };
+        self.lock().insert(id, entry);
+        if let Some(status) = self.snapshot().into_iter().find(|status| status.id == id) {
+            self.notify(RegistryEvent::Started(status));
+        }
+        Registration {
+            registry: self,
+            id,
+            succeeded: false,
+        }
+    }
+
+    /// Returns a channel receiving every transfer started or finished from now on.
+    pub fn subscribe(&self) -> Receiver<RegistryEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sender);
+        receiver
+    }
+
+    fn notify(&self, event: RegistryEvent) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Returns the status of every active transfer, oldest first.
+    pub fn snapshot(&self) -> Vec<TransferStatus> {
+        self.lock()
+            .iter()
+            .map(|(id, entry)| {
+                let progress = entry.control.progress();
+                TransferStatus {
+                    id: *id,
+                    direction: entry.direction,
+                    peer: entry.peer.clone(),
+                    file_name: entry.file_name.clone(),
+                    bytes_transferred: progress.bytes_transferred,
+                    total_bytes: progress.total_bytes,
+                    paused: entry.control.is_paused(),
+                    started_at: entry.started_at,
+                    bottleneck: entry.control.bottleneck(),
+                    files: entry.control.files(),
+                }
+            })
+            .collect()
+    }
+
+    /// Cancels every active transfer, see [TransferControl::cancel].
+    ///
+    /// # Returns
+    ///
+    /// The number of transfers cancelled.
+    pub fn cancel_all(&self) -> usize {
+        let entries = self.lock();
+        for entry in entries.values() {
+            entry.control.cancel();
+        }
+        entries.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps a transfer listed in its [TransferRegistry] until dropped.
+pub struct Registration<'a> {
+    registry: &'a TransferRegistry,
+    id: u64,
+    succeeded: bool,
+}
+
+impl Registration<'_> {
+    /// Returns the identifier of the transfer.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Records that the file was delivered. Transfers dropped without it are reported as failed.
+    pub fn mark_succeeded(&mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let entry = self.registry.lock().remove(&self.id);
+        if let Some(entry) = entry {
+            self.registry.notify(RegistryEvent::Finished {
+                id: self.id,
+                file_name: entry.file_name,
+                succeeded: self.succeeded,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration_lifetime() {
+        let registry = TransferRegistry::new();
+        let events = registry.subscribe();
+        let control = Arc::new(TransferControl::new());
+        control.set_total_bytes(100);
+        control.add_bytes(40);
+
+        let registration = registry.register(
+            TransferDirection::Receive,
+            String::from("127.0.0.1:7878"),
+            String::from("backup.tar"),
+            control.clone(),
+        );
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].id, registration.id());
+        assert_eq!(snapshot[0].bytes_transferred, 40);
+        assert_eq!(snapshot[0].total_bytes, Some(100));
+
+        assert!(matches!(events.try_recv(), Ok(RegistryEvent::Started(_))));
+
+        assert_eq!(registry.cancel_all(), 1);
+        assert!(control.is_cancelled());
+
+        drop(registration);
+        assert!(registry.snapshot().is_empty());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RegistryEvent::Finished {
+                succeeded: false,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_lists_the_files_of_a_directory() {
+        use crate::{
+            stream::bundle::{Bundle, BundleProgress, FileState},
+            transport::FileEntryV1,
+        };
+
+        let registry = TransferRegistry::new();
+        let control = Arc::new(TransferControl::new());
+        let _registration = registry.register(
+            TransferDirection::Send,
+            String::from("127.0.0.1:7878"),
+            String::from("photos"),
+            control.clone(),
+        );
+        assert!(registry.snapshot()[0].files.is_empty());
+
+        let files = ["a.jpg", "b.jpg"].map(|path| FileEntryV1 {
+            path: String::from(path),
+            size: 8,
+            hash: [0; 32],
+        });
+        let bundle = Bundle::new(files.to_vec(), 4).unwrap();
+        control.track_files(Arc::new(BundleProgress::new(&bundle, false)));
+        control.add_file_bytes(0, 8);
+        control.add_file_bytes(1, 4);
+        let statuses = &registry.snapshot()[0].files;
+        assert_eq!(statuses[0].path, "a.jpg");
+        assert_eq!(statuses[0].state, FileState::Done);
+        assert_eq!(statuses[1].bytes_transferred, 4);
+        assert_eq!(statuses[1].state, FileState::Transferring);
+
+        // Listed in status queries with their state
+        let json = serde_json::to_value(&registry.snapshot()[0]).unwrap();
+        assert_eq!(json["files"][1]["state"], "transferring");
+    }
+
+    #[test]
+    fn test_subscribers_see_delivered_files() {
+        let registry = TransferRegistry::new();
+        let events = registry.subscribe();
+        let mut registration = registry.register(
+            TransferDirection::Send,
+            String::from("127.0.0.1:7878"),
+            String::from("backup.tar"),
+            Arc::new(TransferControl::new()),
+        );
+        let id = registration.id();
+        registration.mark_succeeded();
+        drop(registration);
+
+        let Ok(RegistryEvent::Started(status)) = events.try_recv() else {
+            panic!("Missing start of the transfer");
+        };
+        assert_eq!(status.id, id);
+        assert_eq!(status.direction, TransferDirection::Send);
+        assert_eq!(
+            events.try_recv(),
+            Ok(RegistryEvent::Finished {
+                id,
+                file_name: String::from("backup.tar"),
+                succeeded: true,
+            })
+        );
+
+        // Dropped subscribers are forgotten
+        drop(events);
+        let _registration = registry.register(
+            TransferDirection::Send,
+            String::from("127.0.0.1:7878"),
+            String::from("backup.tar"),
+            Arc::new(TransferControl::new()),
+        );
+        assert!(registry.subscribers.lock().unwrap().is_empty());
+    }
+}
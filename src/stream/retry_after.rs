@@ -0,0 +1,80 @@
+//! Retry hints of senders that expect to serve a request later.
+//!
+//! A sender past its connection limit, or whose disk can't read a block for now (e.g. a network
+//! filesystem timing out), used to drop the connection or report the block unreadable, and the
+//! receiver backed off blindly. To receivers advertising [Capabilities::RETRY_AFTER] it answers
+//! with a `RetryAfter` error instead, of code [UNAVAILABLE_CODE], naming how long to wait. The
+//! receiver requests the block again once that time has passed rather than after its own
+//! exponential backoff, and a connection refused that way is opened again in the next round of
+//! [ReceiveOptions::auto_retry] after the hinted time. Hints are capped at [MAX_RETRY_AFTER].
+//!
+//! [Capabilities::RETRY_AFTER]: crate::capabilities::Capabilities::RETRY_AFTER
+//! [ReceiveOptions::auto_retry]: crate::stream::options::ReceiveOptions::auto_retry
+
+use std::{io, time::Duration};
+
+use crate::{stream::error::SendFileError, transport::RetryAfterV1};
+
+/// Code of the error a sender asking to be retried later sends, as in HTTP.
+pub const UNAVAILABLE_CODE: u16 = 503;
+
+/// Time a sender past its connection limit asks the receiver to wait before connecting again.
+pub const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Longest wait a receiver accepts from a sender's hint.
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Returns the hint of a sender that can't serve a request now for `reason`, to be retried after
+/// `retry_after`.
+pub(crate) fn retry_hint(reason: String, retry_after: Duration) -> RetryAfterV1 {
+    RetryAfterV1 {
+        code: UNAVAILABLE_CODE,
+        message: reason,
+        retry_after_ms: retry_after.as_millis() as u64,
+    }
+}
+
+/// Returns the error of a request the sender answered with `hint`, whose wait is capped at
+/// [MAX_RETRY_AFTER].
+pub(crate) fn sender_unavailable(hint: RetryAfterV1) -> SendFileError {
+    SendFileError::SenderUnavailable {
+        message: hint.message,
+        retry_after: Duration::from_millis(hint.retry_after_ms).min(MAX_RETRY_AFTER),
+    }
+}
+
+/// Returns whether the failed read `error` may succeed if tried again later.
+pub(crate) fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ResourceBusy
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hints_are_capped() {
+        let hint = retry_hint(String::from("busy"), Duration::from_millis(1500));
+        assert_eq!(hint.retry_after_ms, 1500);
+        assert!(matches!(
+            sender_unavailable(hint),
+            SendFileError::SenderUnavailable { retry_after, .. }
+                if retry_after == Duration::from_millis(1500)
+        ));
+
+        let hint = retry_hint(String::from("busy"), Duration::from_secs(3600));
+        assert!(matches!(
+            sender_unavailable(hint),
+            SendFileError::SenderUnavailable { retry_after, .. } if retry_after == MAX_RETRY_AFTER
+        ));
+
+        assert!(is_transient(&io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(!is_transient(&io::Error::from(io::ErrorKind::InvalidData)));
+    }
+}
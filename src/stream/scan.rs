@@ -0,0 +1,236 @@
+//! Scanning received files before they are accepted, e.g. with an anti-virus.
+//!
+//! With a [ScanHook], the receiver writes the file next to its destination, at its
+//! [staged_path]. Once every block is written and the file hash verified, it runs the command of
+//! the hook on the staged file, and only renames it to its destination if it passes. A non-zero
+//! exit, or a command running longer than [ScanHook::timeout], moves the staged file into the
+//! [quarantine](crate::quarantine) instead, and the sender is told the file was refused with a
+//! [SCAN_FAILED_CODE] error instead of a receipt.
+
+use std::{
+    ffi::OsString,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    file::metadata::{metadata_env, Metadata},
+    threads::thread_name,
+    units::Elapsed,
+};
+
+/// Code of the error sent to the sender when its file fails the scan.
+pub const SCAN_FAILED_CODE: u16 = 451;
+
+/// Suffix appended to the destination of a file to get the path it is written at until it
+/// passes the scan.
+pub const STAGED_SUFFIX: &str = ".sendfile-part";
+
+/// How long the scan command may run before the file is considered failed.
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often the scan command is checked for exit.
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Returns the path a file received at `path` is written at until it passes the scan, e.g.
+/// `report.pdf.sendfile-part`.
+pub fn staged_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(STAGED_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Command received files are scanned with, and where files failing it are held.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanHook {
+    /// Shell command run with the path of the file appended, e.g. `clamscan --no-summary`. The
+    /// file passes if it exits with status 0.
+    pub command: String,
+    /// Quarantine directory files failing the scan are moved into.
+    pub quarantine_dir: PathBuf,
+    /// How long the command may run, it is killed and the file fails the scan past it.
+    pub timeout: Duration,
+}
+
+/// Details of the scanned file passed to the scan command in `SENDFILE_*` environment variables.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanSubject<'a> {
+    /// Name of the file on the sender side, in `SENDFILE_FILE_NAME`.
+    pub file_name: &'a str,
+    /// Hex encoded BLAKE3 hash of the file, in `SENDFILE_HASH`.
+    pub file_hash: &'a str,
+    /// Address of the sender, in `SENDFILE_SENDER`.
+    pub sender: &'a str,
+    /// Metadata the sender attached to the file, each entry in a `SENDFILE_META_<KEY>`
+    /// variable, see [metadata_env].
+    pub metadata: &'a Metadata,
+}
+
+impl ScanHook {
+    /// Runs the scan command on the file at `path` and waits for it to exit, killing it after
+    /// [ScanHook::timeout].
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the file passed, or why it did not: the exit status and the last line the
+    /// command printed, that it timed out, or why it could not be run.
+    pub fn scan(&self, path: &Path, subject: &ScanSubject) -> Result<(), String> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{} \"$1\"", self.command))
+            .arg("sh")
+            .arg(path)
+            .env("SENDFILE_FILE_NAME", subject.file_name)
+            .env("SENDFILE_HASH", subject.file_hash)
+            .env("SENDFILE_SENDER", subject.sender)
+            .envs(metadata_env(subject.metadata))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // Killed along with the processes it started on timeout
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("scan command could not be run: {}", e))?;
+
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let (status, stdout, stderr) = thread::scope(|scope| {
+            let read_stdout = read_output(scope, &mut stdout, 0);
+            let read_stderr = read_output(scope, &mut stderr, 1);
+            let status = wait_with_timeout(&mut child, self.timeout);
+            let stdout = read_stdout.map(|t| t.join().unwrap_or_default());
+            let stderr = read_stderr.map(|t| t.join().unwrap_or_default());
+            (status, stdout, stderr)
+        });
+        let status = status.map_err(|e| format!("scan command could not be waited for: {}", e))?;
+        let Some(status) = status else {
+            return Err(format!(
+                "scan command timed out after {}",
+                Elapsed(self.timeout)
+            ));
+        };
+        if status.success() {
+            return Ok(());
+        }
+
+        let stdout = String::from_utf8_lossy(stdout.as_deref().unwrap_or_default());
+        let stderr = String::from_utf8_lossy(stderr.as_deref().unwrap_or_default());
+        let last_line = stdout
+            .lines()
+            .chain(stderr.lines())
+            .rfind(|line| !line.trim().is_empty());
+        match last_line {
+            Some(line) => Err(format!(
+                "scan command exited with {}: {}",
+                status,
+                line.trim()
+            )),
+            None => Err(format!("scan command exited with {}", status)),
+        }
+    }
+}
+
+/// Reads `output` of the scan command to its end on a thread of `scope`, so the command never
+/// blocks on a full pipe.
+///
+/// Returns [None] if the thread could not be spawned, the output is then left unread.
+fn read_output<'scope, R: Read + Send>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    output: &'scope mut R,
+    index: usize,
+) -> Option<thread::ScopedJoinHandle<'scope, Vec<u8>>> {
+    thread::Builder::new()
+        .name(thread_name("scan-output", index))
+        .spawn_scoped(scope, move || {
+            let mut bytes = Vec::new();
+            // Whatever was read before an error is still worth reporting
+            let _ = output.read_to_end(&mut bytes);
+            bytes
+        })
+        .ok()
+}
+
+/// Waits for `child` to exit, killing it and the processes it started once `timeout` passed.
+///
+/// Returns the exit status of `child`, or [None] if it was killed.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        thread::sleep(SCAN_POLL_INTERVAL);
+    }
+
+    #[cfg(unix)]
+    // SAFETY: kill only sends a signal, to the process group the child leads
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = child.kill();
+    child.wait()?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_exit_status() {
+        let path = std::env::temp_dir().join(format!("sendfile-scan-{}", std::process::id()));
+        std::fs::write(&path, b"X5O!P%@AP").unwrap();
+        let subject = ScanSubject {
+            file_name: "eicar.com",
+            file_hash: "00",
+            sender: "10.0.0.5:51234",
+            metadata: &Metadata::from([(String::from("ticket"), String::from("1234"))]),
+        };
+        let hook = |command: &str| ScanHook {
+            command: String::from(command),
+            quarantine_dir: PathBuf::new(),
+            timeout: DEFAULT_SCAN_TIMEOUT,
+        };
+
+        assert_eq!(hook("test -s").scan(&path, &subject), Ok(()));
+        assert_eq!(hook("grep -q X5O").scan(&path, &subject), Ok(()));
+        assert_eq!(
+            hook("test \"$SENDFILE_META_TICKET\" = 1234 && test -s").scan(&path, &subject),
+            Ok(())
+        );
+        assert_eq!(
+            hook("echo \"$SENDFILE_FILE_NAME: infected\"; false").scan(&path, &subject),
+            Err(String::from(
+                "scan command exited with exit status: 1: eicar.com: infected"
+            ))
+        );
+
+        let hanging = ScanHook {
+            timeout: Duration::from_millis(200),
+            ..hook("sleep 30; true")
+        };
+        let started = Instant::now();
+        assert_eq!(
+            hanging.scan(&path, &subject),
+            Err(String::from("scan command timed out after 0.2s"))
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_staged_path() {
+        assert_eq!(
+            staged_path(Path::new("/tmp/report.pdf")),
+            PathBuf::from("/tmp/report.pdf.sendfile-part")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Bandwidth schedules of the sender (`--limit-rate`, `--rate-schedule`), to de-prioritize a
+//! transfer during office hours without babysitting it.
+//!
+//! A [RateSchedule] maps times of day (local time) to rate limits. While a transfer runs, the
+//! limit of the current time is applied to its [TransferControl] whenever it changes, which
+//! throttles the blocks the sender serves and, through the control channel, tells the receiver
+//! to slow down as well, see [crate::stream::control].
+
+use std::{
+    sync::{Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::info;
+
+use crate::{stream::handle::TransferControl, units::Rate};
+
+/// Minutes in a day, the times of a schedule are below it.
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Time between two checks of the schedule while a transfer runs.
+const SCHEDULE_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Rate limits by time of day, repeated every day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateSchedule {
+    /// Minute of the day each limit starts at, in bytes per second or `None` for no limit,
+    /// sorted by minute.
+    entries: Vec<(u16, Option<u64>)>,
+}
+
+impl RateSchedule {
+    /// Creates a schedule applying `rate` bytes per second all day.
+    pub fn constant(rate: u64) -> Self {
+        Self {
+            entries: vec![(0, Some(rate))],
+        }
+    }
+
+    /// Creates a schedule from the minute of the day each limit starts at, and the limit in bytes
+    /// per second (`None` for no limit). A limit lasts until the next one starts, the last one
+    /// of the day until the first one.
+    ///
+    /// # Returns
+    ///
+    /// The schedule, or an error if there are no limits, a minute is past the end of the day or
+    /// two limits start at the same minute.
+    pub fn new(entries: impl IntoIterator<Item = (u16, Option<u64>)>) -> Result<Self, String> {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by_key(|&(minute, _)| minute);
+        if entries.is_empty() {
+            return Err(String::from("a rate schedule needs at least one limit"));
+        }
+        if let Some(&(minute, _)) = entries
+            .iter()
+            .find(|(minute, _)| *minute >= MINUTES_PER_DAY)
+        {
+            return Err(format!("minute {minute} is past the end of the day"));
+        }
+        if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!(
+                "two limits start at {:02}:{:02}",
+                pair[0].0 / 60,
+                pair[0].0 % 60
+            ));
+        }
+        Ok(Self { entries })
+    }
+
+    /// Returns the limit in bytes per second at `minute` of the day, `None` for no limit.
+    pub fn rate_at(&self, minute: u16) -> Option<u64> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= minute)
+            .or(self.entries.last())
+            .and_then(|&(_, rate)| rate)
+    }
+}
+
+/// Runs `transfer`, applying the limit `schedule` sets at the current local time to `control`
+/// from the start and whenever it changes. Limits set on `control` meanwhile, e.g. with
+/// [TransferHandle::limit_rate](crate::stream::handle::TransferHandle::limit_rate), hold until
+/// the schedule changes.
+pub(crate) fn with_rate_schedule<T>(
+    schedule: Option<&RateSchedule>,
+    control: &TransferControl,
+    transfer: impl FnOnce() -> T,
+) -> T {
+    let Some(schedule) = schedule else {
+        return transfer();
+    };
+    let finished = Mutex::new(false);
+    let wakeup = Condvar::new();
+    thread::scope(|scope| {
+        let mut applied = schedule.rate_at(local_minute_of_day());
+        control.limit_rate(applied);
+        let (finished, wakeup) = (&finished, &wakeup);
+        scope.spawn(move || {
+            let mut done = finished.lock().unwrap_or_else(|e| e.into_inner());
+            while !*done {
+                done = wakeup
+                    .wait_timeout(done, SCHEDULE_POLL_INTERVAL)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+                let rate = schedule.rate_at(local_minute_of_day());
+                if rate != applied && !*done {
+                    match rate {
+                        Some(rate) => {
+                            info!("Rate schedule limits the transfer to {}", Rate(rate as f64))
+                        }
+                        None => info!("Rate schedule lifts the limit of the transfer"),
+                    }
+                    control.limit_rate(rate);
+                    applied = rate;
+                }
+            }
+        });
+        let result = transfer();
+        *finished.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wakeup.notify_all();
+        result
+    })
+}
+
+/// Returns the current minute of the day in local time.
+fn local_minute_of_day() -> u16 {
+    // SAFETY: `time` accepts a null pointer, and `localtime_r` only writes to `tm`, read on
+    // success.
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+        if libc::localtime_r(&now, tm.as_mut_ptr()).is_null() {
+            return 0;
+        }
+        let tm = tm.assume_init();
+        (tm.tm_hour * 60 + tm.tm_min) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule_wraps_around_midnight() {
+        let schedule = RateSchedule::new([(18 * 60, None), (8 * 60, Some(1 << 20))]).unwrap();
+        assert_eq!(schedule.rate_at(0), None);
+        assert_eq!(schedule.rate_at(8 * 60), Some(1 << 20));
+        assert_eq!(schedule.rate_at(17 * 60 + 59), Some(1 << 20));
+        assert_eq!(schedule.rate_at(18 * 60), None);
+        assert_eq!(
+            RateSchedule::constant(500).rate_at(MINUTES_PER_DAY - 1),
+            Some(500)
+        );
+
+        assert!(RateSchedule::new([]).is_err());
+        assert!(RateSchedule::new([(MINUTES_PER_DAY, None)]).is_err());
+        assert!(RateSchedule::new([(60, None), (60, Some(1))]).is_err());
+
+        let control = TransferControl::new();
+        let rate = with_rate_schedule(Some(&RateSchedule::constant(4096)), &control, || {
+            control.rate_limit()
+        });
+        assert_eq!(rate, Some(4096));
+    }
+}
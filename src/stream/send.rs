@@ -1,161 +1,1654 @@
 use crate::{
-    cli::TRANSFER_PORT,
+    authentication::{new_session, verify_conn_hello},
+    capabilities::{Capabilities, ChecksumAlgorithm, CompressionCodec},
     connection::read_next_payload,
-    file::utils::read_file_block,
-    stream::{error::SendFileError, utils::initialize_handshake},
+    file::{
+        buffer::AlignedBuffer,
+        utils::{is_remote_filesystem, try_lock_file},
+        FileMetadata,
+    },
+    history::{append_entry, to_hex, HistoryEntry, StoredReceipt},
+    limits::TRANSFER_PORT,
+    noise::{NoisePeer, NoiseStream},
+    peers::PeerRegistry,
+    receipt::verify_receipt,
+    stream::{
+        bottleneck::{Stage, StageTimings},
+        bundle::{BundleProgress, BundleSource},
+        cancel::cancel_reason,
+        checksum::block_checksum_with,
+        compress::BlockCompressor,
+        control::ControlChannel,
+        damage::block_ranges,
+        error::{ErrorContext, ErrorContextExt, SendFileError},
+        estimate::{sampled_entropy, CompressionEstimate},
+        handle::{TransferControl, TransferHandle},
+        heartbeat::{Heartbeat, Heartbeating, Side},
+        keepalive::configure_keepalive,
+        options::SendOptions,
+        panic::contain_panic,
+        plan::{ReceiverAnswer, SendPlan, CONFLICT_SEPARATOR},
+        preconnected::{Connection, Preconnected, PRECONNECTED_PEER},
+        registry::{TransferDirection, TransferRegistry},
+        retry_after::{is_transient, retry_hint, BUSY_RETRY_AFTER, MAX_RETRY_AFTER},
+        scan::SCAN_FAILED_CODE,
+        schedule::with_rate_schedule,
+        source::{BlockSource, FileSource, ReaderSource},
+        time_limit::{abort_reason, with_time_limit, TIME_LIMIT_CODE},
+        udp::UdpSender,
+        utils::{
+            accept_protocol_version, await_handshake_answer, bind_listener, handshake_answer,
+            handshake_frames, initialize_handshake, load_identity, read_handshake_answer,
+            HandshakeAnswer, OFFER_RESPONSE_TIMEOUT_SECS,
+        },
+        writer::{ChunkedWriter, WRITE_POLL_INTERVAL},
+    },
+    telemetry::{BlockSpans, TraceSpan},
+    threads::thread_name,
+    tls::{MaybeTlsStream, TlsPeer},
     transport::{
-        DataV1, ProgressV1, ReceiverErrorV1, ReceiverMessageV1, RequestV1, SenderErrorV1,
-        SenderMessageV1, TransferCompleteV1, VerifyBlockV1, VerifyResponseV1, MAX_MESSAGE_SIZE,
+        attach_headers_for, AlgorithmsV1, BlockHashesRequestV1, BlockHashesV1, BlockUnreadableV1,
+        CancelV1, DataV1, FileDataV1, FileRequestV1, GoodbyeV1, HaveBlocksV1, OfferResponseV1,
+        PingV1, PongV1, ProgressV1, PushAckV1, PushRangeV1, ReceiptV1, ReceiverErrorV1,
+        ReceiverMessageV1, RequestRangeV1, RequestV1, SenderErrorV1, SenderMessageV1, SessionV1,
+        TransferCompleteV1, UdpBlockV1, UdpRequestV1, VerifyBlockV1, VerifyResponseV1, ZeroBlockV1,
+        CURRENT_PROTOCOL_VERSION, MAX_BATCH_MESSAGES, MAX_BLOCK_HASHES_PER_MESSAGE,
+        MAX_MESSAGE_SIZE, MAX_PUSH_WINDOW, MAX_REQUEST_RANGE_BLOCKS, SESSION_ID_SIZE,
     },
+    units::{Elapsed, Size},
 };
-use crc_fast::{checksum, CrcAlgorithm};
-use flate2::{write::GzEncoder, Compression};
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::{
+    collections::BTreeMap,
     fs::File,
-    io::Write,
-    net::{TcpListener, TcpStream},
+    io::{Read, Seek, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
     path::Path,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex, MutexGuard,
     },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 const POLL_SLEEP_MS: u64 = 500;
 const INACTIVITY_TIMEOUT_SECS: u64 = 15;
+const RECEIPT_TIMEOUT_SECS: u64 = 300;
+/// Interval at which single-port data connections check whether the receiver accepted the file.
+const ACCEPT_POLL_MS: u64 = 50;
+/// Delay before the first retry of a failed block read, doubled on every further retry.
+const INITIAL_READ_RETRY_DELAY_MS: u64 = 100;
 
 /// Sends a file to the specified address using the custom file transfer protocol.
+///
+/// When [SendOptions::lock] is set, a shared advisory lock is held on the source file for the
+/// duration of the transfer so cooperating writers cannot modify it mid-transfer.
+///
+/// Once the transfer completes, the signed receipt returned by the receiver is verified and the
+/// transfer is recorded in [SendOptions::history_path].
 pub fn send_file(
     address: (&str, u16),
     file_path: &Path,
-    block_size: u32,
-    should_compress: bool,
-    concurrency: u16,
+    options: &SendOptions,
 ) -> Result<(), SendFileError> {
-    let mut transport_buffer = vec![0u8; MAX_MESSAGE_SIZE];
-    let file_hash = initialize_handshake(
-        &mut transport_buffer,
+    send_file_with(
         address,
         file_path,
-        block_size,
-        concurrency,
+        options,
+        &Arc::new(TransferControl::new()),
     )
-    .expect("Failed to initialize handshake");
+}
 
-    let listener = TcpListener::bind(("0.0.0.0", TRANSFER_PORT))?;
+/// Starts sending a file on a background thread, see [send_file].
+///
+/// # Returns
+///
+/// A [TransferHandle] to follow, pause or cancel the transfer, or a `SendFileError` if the
+/// thread could not be started.
+pub fn start_send_file(
+    address: (&str, u16),
+    file_path: &Path,
+    options: &SendOptions,
+) -> Result<TransferHandle, SendFileError> {
+    let host = address.0.to_string();
+    let port = address.1;
+    let file_path = file_path.to_path_buf();
+    let options = options.clone();
+
+    TransferHandle::spawn("sender", Arc::new(TransferControl::new()), move |control| {
+        send_file_with((&host, port), &file_path, &options, control)
+    })
+}
+
+/// Sends a file like [send_file], followed and cancelled with `control`.
+pub(crate) fn send_file_with(
+    address: (&str, u16),
+    file_path: &Path,
+    options: &SendOptions,
+    control: &Arc<TransferControl>,
+) -> Result<(), SendFileError> {
+    let peer = format!("{}:{}", address.0, address.1);
+    with_time_limit(options.max_duration, control, || {
+        send_path(file_path, options, |file_metadata, source| {
+            traced_send(file_metadata, &peer, control, || {
+                with_rate_schedule(options.rate_schedule.as_ref(), control, || {
+                    send_source(address, file_metadata, source, options, control)
+                })
+            })
+        })
+    })
+}
+
+/// Runs `send` in the span of the transfer of `file_metadata` to `peer`, see
+/// [crate::telemetry], and names both in the context of its error.
+fn traced_send(
+    file_metadata: &FileMetadata,
+    peer: &str,
+    control: &TransferControl,
+    send: impl FnOnce() -> Result<(), SendFileError>,
+) -> Result<(), SendFileError> {
+    let span = TraceSpan::root("send");
+    span.set_str("file.name", file_metadata.name());
+    span.set_int("file.size", file_metadata.size());
+    span.set_str("peer", peer);
+    control.set_trace(span.context());
+    let result = send().context(|| {
+        ErrorContext::new("sending")
+            .with_file(file_metadata.name())
+            .with_peer(peer)
+    });
+    span.record(&result);
+    result
+}
+
+/// Follows the progress of every file of `source` in `control` when it is a directory, see
+/// [TransferControl::files].
+fn track_files(source: &dyn BlockSource, control: &TransferControl) {
+    if let Some(bundle) = source.bundle() {
+        control.track_files(Arc::new(BundleProgress::new(bundle, false)));
+    }
+}
+
+/// Hashes the file at `file_path` and hands it to `send`, holding the lock of
+/// [SendOptions::lock] meanwhile. A directory is handed over as one [BundleSource], without
+/// locking its files.
+fn send_path(
+    file_path: &Path,
+    options: &SendOptions,
+    send: impl FnOnce(&FileMetadata, &dyn BlockSource) -> Result<(), SendFileError>,
+) -> Result<(), SendFileError> {
+    let SendOptions {
+        lock, network_fs, ..
+    } = *options;
+
+    check_strict_mode(options)?;
+
+    if !network_fs && is_remote_filesystem(file_path) {
+        warn!(
+            "Source {:?} is on a network filesystem, consider --network-fs",
+            file_path
+        );
+    }
+
+    if file_path.is_dir() {
+        debug!("Listing and hashing the files of {:?}", file_path);
+        let (source, file_metadata) = BundleSource::open(
+            file_path,
+            options.block_size,
+            options.file_order,
+            options.hash_strategy(),
+            &options.workers,
+        )?;
+        return send(&file_metadata, &source);
+    }
+
+    let opening = || ErrorContext::new("opening").with_file(file_path);
+    let source_lock = if lock {
+        let file = File::open(file_path).context(opening)?;
+        if !try_lock_file(&file, true).context(opening)? {
+            return Err(SendFileError::FileLocked(file_path.to_path_buf()));
+        }
+        Some(file)
+    } else {
+        None
+    };
+
+    debug!("Calculating file metadata for {:?}", file_path);
+    let file_metadata =
+        FileMetadata::from_file_with(file_path, options.hash_strategy(), &options.workers)
+            .context(|| ErrorContext::new("hashing").with_file(file_path))?;
+    let source = FileSource::new(File::open(file_path).context(opening)?).context(opening)?;
+
+    send(&file_metadata, &source)?;
+
+    if let Some(file) = source_lock {
+        file.unlock()?;
+    }
+
+    Ok(())
+}
+
+/// Sends a file like [send_file], over a connection the caller already established with the
+/// receiver (e.g. a TLS tunnel, a multiplexed stream or an SSH channel) instead of connecting to
+/// it. The receiver must run [receive_over](crate::stream::receive::receive_over) on its end.
+///
+/// The whole transfer goes over `transport`, with a single data connection regardless of
+/// [SendOptions::concurrency], see [crate::stream::preconnected]. It runs on the calling thread,
+/// and timeouts are left to the caller's transport.
+///
+/// # Arguments
+///
+/// * `transport` - Connection to the receiver.
+/// * `file_path` - Path to the file to send.
+/// * `options` - Block size, compression and locking for this send, see [SendOptions].
+///
+/// # Returns
+///
+/// A `Result` indicating success or a `SendFileError`.
+pub fn send_over<T: Read + Write>(
+    transport: T,
+    file_path: &Path,
+    options: &SendOptions,
+) -> Result<(), SendFileError> {
+    let control = Arc::new(TransferControl::new());
+    with_time_limit(options.max_duration, &control, || {
+        send_path(file_path, options, |file_metadata, source| {
+            traced_send(file_metadata, PRECONNECTED_PEER, &control, || {
+                with_rate_schedule(options.rate_schedule.as_ref(), &control, || {
+                    send_source_over(
+                        Preconnected(transport),
+                        file_metadata,
+                        source,
+                        options,
+                        &control,
+                    )
+                })
+            })
+        })
+    })
+}
+
+/// Sends the first `len` bytes of `reader` as a file named `name`, without materializing it on
+/// disk first (e.g. a database snapshot or an archive member).
+///
+/// `hash` must be the hash of the content as computed by
+/// [get_reader_blake3_hash](crate::file::utils::get_reader_blake3_hash), the receiver verifies
+/// the received file against it. [SendOptions::lock] and [SendOptions::network_fs] do not apply.
+///
+/// # Arguments
+///
+/// * `address` - The receiver's address and handshake port.
+/// * `reader` - Content to send, read from its start.
+/// * `name` - File name announced to the receiver.
+/// * `len` - Number of bytes to send.
+/// * `hash` - Hash of those bytes.
+/// * `options` - Block size, compression and concurrency for this send, see [SendOptions].
+///
+/// # Returns
+///
+/// A `Result` indicating success or a `SendFileError`.
+pub fn send_from_reader<R: Read + Seek + Send>(
+    address: (&str, u16),
+    reader: R,
+    name: &str,
+    len: u64,
+    hash: [u8; 32],
+    options: &SendOptions,
+) -> Result<(), SendFileError> {
+    check_strict_mode(options)?;
+
+    let file_metadata = FileMetadata::new(name.to_string(), len, hash);
+    let source = ReaderSource::new(reader, len);
+    let control = Arc::new(TransferControl::new());
+    let peer = format!("{}:{}", address.0, address.1);
+    with_time_limit(options.max_duration, &control, || {
+        traced_send(&file_metadata, &peer, &control, || {
+            with_rate_schedule(options.rate_schedule.as_ref(), &control, || {
+                send_source(address, &file_metadata, &source, options, &control)
+            })
+        })
+    })
+}
+
+/// Hashes a file and offers it to the receiver like [send_file], but stops once the receiver
+/// has answered, without sending any data.
+///
+/// The receiver, if it supports [Capabilities::DRY_RUN], answers with its decision and the
+/// conflicts it found at the destination, then waits for the next sender.
+///
+/// # Arguments
+///
+/// * `address` - The receiver's address and handshake port.
+/// * `file_path` - Path to the file that would be sent.
+/// * `options` - Options of the transfer that would be made, see [SendOptions].
+///
+/// # Returns
+///
+/// A [SendPlan] describing the transfer, or a `SendFileError` if the file can't be read or the
+/// receiver can't be reached.
+pub fn dry_run_send(
+    address: (&str, u16),
+    file_path: &Path,
+    options: &SendOptions,
+) -> Result<SendPlan, SendFileError> {
+    check_strict_mode(options)?;
+    if file_path.is_dir() {
+        return Err(SendFileError::InvalidRequest(format!(
+            "{:?} is a directory, dry runs only offer files",
+            file_path
+        )));
+    }
+
+    let mut conflicts = Vec::new();
+    if !options.network_fs && is_remote_filesystem(file_path) {
+        conflicts.push(format!(
+            "{} is on a network filesystem, consider --network-fs",
+            file_path.display()
+        ));
+    }
+
+    // The shared lock, if taken, is released when the file is closed
+    let source = File::open(file_path)?;
+    if options.lock && !try_lock_file(&source, true)? {
+        conflicts.push(format!(
+            "{} is locked by another process",
+            file_path.display()
+        ));
+    }
+
+    let file_metadata =
+        FileMetadata::from_file_with(file_path, options.hash_strategy(), &options.workers)?;
+    let compression = if options.should_compress {
+        Some(CompressionEstimate::sample(
+            &source,
+            file_metadata.size(),
+            options.block_size,
+        )?)
+    } else {
+        None
+    };
+
+    let mut transport_buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let (mut handshake_stream, _) = initialize_handshake(
+        &mut transport_buffer,
+        address,
+        &file_metadata,
+        &source,
+        options,
+        Capabilities::DRY_RUN,
+        None,
+    )?;
+    let receiver = match read_offer_response(&mut handshake_stream, &mut transport_buffer) {
+        Ok(response) if response.accepted => ReceiverAnswer::Accepted(
+            response
+                .reason
+                .split(CONFLICT_SEPARATOR)
+                .filter(|note| !note.is_empty())
+                .map(String::from)
+                .collect(),
+        ),
+        Ok(response) => ReceiverAnswer::Rejected(response.reason),
+        Err(e) => {
+            warn!("No answer to the offer from the receiver: {}", e);
+            ReceiverAnswer::Unknown
+        }
+    };
+
+    Ok(SendPlan {
+        file_name: file_metadata.name().to_string(),
+        size: file_metadata.size(),
+        file_hash: file_metadata.hash(),
+        block_size: options.block_size,
+        concurrency: options.concurrency,
+        compression,
+        link_speed: options.link_speed,
+        conflicts,
+        receiver,
+    })
+}
+
+/// Refuses to start if this side alone cannot satisfy strict mode. The receiver's capabilities
+/// aren't known before the handshake, so they are checked on its side.
+fn check_strict_mode(options: &SendOptions) -> Result<(), SendFileError> {
+    if let Some(policy) = &options.strict {
+        policy
+            .check(options.local_capabilities(), CURRENT_PROTOCOL_VERSION)
+            .map_err(SendFileError::StrictModeViolation)?;
+    }
+    Ok(())
+}
+
+/// Announces the file described by `file_metadata` to the receiver, then serves its blocks from
+/// `source` until the receiver reports completion or `control` cancels the transfer.
+fn send_source(
+    address: (&str, u16),
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    control: &Arc<TransferControl>,
+) -> Result<(), SendFileError> {
+    if options.single_port && options.udp_overhead.is_none() {
+        return send_source_single_port(address, file_metadata, source, options, control);
+    }
+    let concurrency = options.concurrency;
+    let options = &SendOptions {
+        should_compress: options.should_compress
+            && should_compress(source, file_metadata.size(), options),
+        ..options.clone()
+    };
+
+    // Before the handshake, as the receiver connects as soon as it has read it
+    let listener = bind_listener(("0.0.0.0", TRANSFER_PORT), options.listen_backlog)?;
     listener.set_nonblocking(true)?;
     info!("Sender listening on 0.0.0.0:{}", TRANSFER_PORT);
 
+    let session =
+        new_session().map_err(|e| SendFileError::Io(std::io::Error::other(e.to_string())))?;
+    let mut transport_buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let (mut handshake_stream, mut answer) = control.trace().in_span("handshake", || {
+        initialize_handshake(
+            &mut transport_buffer,
+            address,
+            file_metadata,
+            source,
+            options,
+            Capabilities::empty(),
+            Some(&session),
+        )
+    })?;
+    // Left open to tell the receiver when the transfer is cancelled
+    control.register_control(handshake_stream.get_ref().tcp());
+    control.set_total_bytes(file_metadata.size());
+    track_files(source, control);
+    let mut registration = TransferRegistry::global().register(
+        TransferDirection::Send,
+        format!("{}:{}", address.0, address.1),
+        file_metadata.name().to_string(),
+        control.clone(),
+    );
+
     let active_connections = Arc::new(AtomicUsize::new(0));
-    let transfer_complete = Arc::new(AtomicBool::new(false));
+    let tls = match &options.tls {
+        Some(config) => Some(TlsPeer::pin(config.clone(), handshake_stream.get_ref())?),
+        None => None,
+    };
+    let shared = &SharedTransfer {
+        tls,
+        noise: handshake_stream.peer().cloned(),
+        cancel_notice: AtomicBool::new(acknowledges(&answer, Capabilities::CANCEL)),
+        retry_hints: AtomicBool::new(acknowledges(&answer, Capabilities::RETRY_AFTER)),
+        goodbye: AtomicBool::new(acknowledges(&answer, Capabilities::GOODBYE)),
+        ..SharedTransfer::new(session)
+    };
+    let mut channel = control_channel(&answer, file_metadata, &shared.session);
+    // Data connections, shut down once the receiver reported the transfer complete on the
+    // control channel
+    let connections = &Mutex::new(Vec::new());
     let mut inativity_start: Option<std::time::Instant> = None;
+    let mut connection_index = 0usize;
+    let mut rejection = None;
+    // Bytes of the handshake connection read past the messages polled so far
+    let mut pending = Vec::new();
 
-    thread::scope(|scope| loop {
-        if transfer_complete.load(Ordering::Relaxed) {
-            break;
-        }
-
-        if active_connections.load(Ordering::Relaxed) == 0 {
-            if let Some(start) = inativity_start {
-                if start.elapsed().as_secs() >= INACTIVITY_TIMEOUT_SECS {
-                    error!("No active connections for 15 seconds, shutting down sender");
-                    break;
-                }
-            } else {
-                warn!("No active connections, waiting for incoming connections...");
-                inativity_start.replace(std::time::Instant::now());
+    thread::scope(|scope| {
+        loop {
+            if shared.complete.load(Ordering::Relaxed) || control.is_cancelled() {
+                break;
             }
-        } else {
-            inativity_start.take();
-        }
 
-        match listener.accept() {
-            Ok((stream, addr)) => {
-                info!("Accepted connection from {}", addr);
-                if active_connections.load(Ordering::Relaxed) >= concurrency as usize {
-                    warn!("Max connections reached, dropping incoming connection");
-                    continue;
+            // Receivers that answer the offer late do so before opening any data connection
+            if connection_index == 0 && answer == HandshakeAnswer::Unanswered {
+                match poll_offer_response(
+                    &mut handshake_stream,
+                    &mut transport_buffer,
+                    &mut pending,
+                    &shared.session,
+                    file_metadata,
+                    options,
+                ) {
+                    Ok(Some(late)) => {
+                        answer = late;
+                        channel = control_channel(&answer, file_metadata, &shared.session);
+                        shared.cancel_notice.store(
+                            acknowledges(&answer, Capabilities::CANCEL),
+                            Ordering::SeqCst,
+                        );
+                        shared.retry_hints.store(
+                            acknowledges(&answer, Capabilities::RETRY_AFTER),
+                            Ordering::SeqCst,
+                        );
+                        shared.goodbye.store(
+                            acknowledges(&answer, Capabilities::GOODBYE),
+                            Ordering::SeqCst,
+                        );
+                    }
+                    Ok(None) => {}
+                    Err(e @ (SendFileError::OfferRejected(_) | SendFileError::TypeRejected(_))) => {
+                        rejection = Some(e);
+                        break;
+                    }
+                    Err(e) => warn!("Failed to read the receiver's answer to the offer: {}", e),
                 }
+            }
 
-                if let Err(e) = stream.set_nonblocking(false) {
+            let polled = channel.as_mut().map(|open| {
+                poll_control_channel(
+                    &mut handshake_stream,
+                    &mut transport_buffer,
+                    &mut pending,
+                    shared,
+                    open,
+                    control,
+                )
+            });
+            let heard = match polled {
+                Some(Ok(heard)) => heard,
+                Some(Err(e)) => {
                     warn!(
-                        "Failed to set stream to blocking mode, dropping connection: {}",
+                        "Lost the control channel, counting progress from the blocks sent: {}",
                         e
                     );
-                    continue;
+                    control.report_bytes(None);
+                    channel = None;
+                    false
+                }
+                None => false,
+            };
+            if channel.is_some() && shared.complete.load(Ordering::SeqCst) {
+                // Receivers saying goodbye close their data connections themselves
+                if !shared.goodbye.load(Ordering::SeqCst) {
+                    for stream in lock_connections(connections).iter() {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
                 }
+                break;
+            }
 
-                if let Err(e) = stream.set_nodelay(true) {
-                    warn!("Failed to set TCP_NODELAY, dropping connection: {}", e);
-                    continue;
+            if active_connections.load(Ordering::SeqCst) == 0 {
+                // Loaded after the count, so a connection that just completed the transfer is
+                // seen and the receipt following it isn't read as an abort
+                if shared.complete.load(Ordering::SeqCst) {
+                    break;
+                }
+                // A receiver closing every connection at its time limit or on cancellation says
+                // so first, on the control channel if there is one
+                if connection_index > 0 && channel.is_none() {
+                    match poll_receiver_abort(
+                        &mut handshake_stream,
+                        &mut transport_buffer,
+                        &mut pending,
+                        &shared.session,
+                        control,
+                    ) {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(e) => warn!("Failed to read from the receiver: {}", e),
+                    }
+                }
+                if heard {
+                    // The receiver is between two rounds of data connections
+                    inativity_start.replace(std::time::Instant::now());
+                } else if let Some(start) = inativity_start {
+                    if start.elapsed().as_secs() >= INACTIVITY_TIMEOUT_SECS {
+                        error!("No active connections for 15 seconds, shutting down sender");
+                        break;
+                    }
+                } else {
+                    warn!("No active connections, waiting for incoming connections...");
+                    inativity_start.replace(std::time::Instant::now());
                 }
+            } else {
+                inativity_start.take();
+            }
 
-                let active_connections = active_connections.clone();
-                let transfer_complete = transfer_complete.clone();
-
-                active_connections.fetch_add(1, Ordering::SeqCst);
-                scope.spawn(move || {
-                    let result = handle_connection(
-                        stream,
-                        &file_hash,
-                        file_path,
-                        block_size,
-                        should_compress,
-                        transfer_complete.clone(),
-                    );
-                    active_connections.fetch_sub(1, Ordering::SeqCst);
-                    if result.is_ok() {
-                        transfer_complete.store(true, Ordering::SeqCst);
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("Accepted connection from {}", addr);
+                    if active_connections.load(Ordering::Relaxed) >= concurrency as usize {
+                        warn!("Max connections reached, dropping incoming connection");
+                        refuse_busy(stream, shared);
+                        continue;
                     }
-                });
+
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        warn!(
+                            "Failed to set stream to blocking mode, dropping connection: {}",
+                            e
+                        );
+                        continue;
+                    }
+
+                    if let Err(e) = options.socket.apply(&stream) {
+                        warn!("Failed to set TCP_NODELAY, dropping connection: {}", e);
+                        continue;
+                    }
+                    configure_keepalive(&stream, options.keepalive.as_ref());
+                    if channel.is_some()
+                        && let Ok(clone) = stream.try_clone()
+                    {
+                        lock_connections(connections).push(clone);
+                    }
+
+                    let active_connections = active_connections.clone();
+
+                    let worker_index = connection_index;
+                    connection_index += 1;
+
+                    active_connections.fetch_add(1, Ordering::SeqCst);
+                    let spawn_result = thread::Builder::new()
+                        .name(thread_name("send", worker_index))
+                        .spawn_scoped(scope, {
+                            let active_connections = active_connections.clone();
+                            move || {
+                                // Compression happens on the connection thread
+                                options.workers.pin_current_thread(worker_index);
+                                let result = contain_panic(control, || {
+                                    handle_connection(
+                                        stream,
+                                        file_metadata,
+                                        source,
+                                        options,
+                                        shared,
+                                        control,
+                                    )
+                                });
+                                // Before the connection is gone, so the receiver isn't polled
+                                // for an abort when it is about to send its receipt
+                                if matches!(result, Ok(true)) {
+                                    shared.complete.store(true, Ordering::SeqCst);
+                                }
+                                active_connections.fetch_sub(1, Ordering::SeqCst);
+                            }
+                        });
+
+                    if let Err(e) = spawn_result {
+                        error!("Failed to spawn connection thread: {}", e);
+                        active_connections.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+                Err(_) => {
+                    thread::sleep(std::time::Duration::from_millis(POLL_SLEEP_MS));
+                }
             }
-            Err(_) => {
-                thread::sleep(std::time::Duration::from_millis(POLL_SLEEP_MS));
+        }
+    });
+
+    if let Some(e) = rejection {
+        error!("Receiver rejected the file: {}", e);
+        return Err(e);
+    }
+
+    if !shared.complete.load(Ordering::SeqCst) && control.is_cancelled() {
+        if let Some(channel) = &channel
+            && control.is_cancelled_here()
+            && let Err(e) = channel.push_abort(&mut handshake_stream, &cancel_reason("sender"))
+        {
+            debug!(
+                "Failed to tell the receiver the transfer is cancelled: {}",
+                e
+            );
+        }
+        return Err(control.cancellation());
+    }
+    check_unreadable_blocks(shared, file_metadata, options)?;
+
+    if shared.complete.load(Ordering::SeqCst) {
+        if control.own_bytes() < file_metadata.size() {
+            // A sender taking over a session only serves the blocks the receiver is missing
+            info!(
+                "Sent {} of the {} bytes, the receiver already had the rest",
+                control.own_bytes(),
+                file_metadata.size()
+            );
+        }
+        let receipt = if expects_receipt(&answer) {
+            read_receipt(
+                &mut handshake_stream,
+                &mut transport_buffer,
+                &pending,
+                options.heartbeat.as_ref(),
+                &shared.session,
+            )
+        } else {
+            receipts_not_signed()
+        };
+        finish_transfer(
+            receipt,
+            &format!("{}:{}", address.0, address.1),
+            Some(address.0),
+            file_metadata,
+            options,
+            control,
+        )?;
+        registration.mark_succeeded();
+    }
+
+    Ok(())
+}
+
+/// Announces the file described by `file_metadata` to the receiver like [send_source], then
+/// serves its blocks over the handshake connection and connections opened to the same port of the
+/// receiver, see [SendOptions::single_port].
+fn send_source_single_port(
+    address: (&str, u16),
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    control: &Arc<TransferControl>,
+) -> Result<(), SendFileError> {
+    let options = &SendOptions {
+        should_compress: options.should_compress
+            && should_compress(source, file_metadata.size(), options),
+        ..options.clone()
+    };
+
+    let session =
+        new_session().map_err(|e| SendFileError::Io(std::io::Error::other(e.to_string())))?;
+    let mut transport_buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let (mut handshake_stream, answer) = control.trace().in_span("handshake", || {
+        initialize_handshake(
+            &mut transport_buffer,
+            address,
+            file_metadata,
+            source,
+            options,
+            Capabilities::empty(),
+            Some(&session),
+        )
+    })?;
+    let receiver_addr = handshake_stream.get_ref().tcp().peer_addr()?;
+    control.register(handshake_stream.get_ref().tcp());
+    control.set_total_bytes(file_metadata.size());
+    track_files(source, control);
+    let mut registration = TransferRegistry::global().register(
+        TransferDirection::Send,
+        format!("{}:{}", address.0, address.1),
+        file_metadata.name().to_string(),
+        control.clone(),
+    );
+
+    let tls = match &options.tls {
+        Some(config) => Some(TlsPeer::pin(config.clone(), handshake_stream.get_ref())?),
+        None => None,
+    };
+    let shared = &SharedTransfer {
+        tls,
+        noise: handshake_stream.peer().cloned(),
+        accepted: AtomicBool::new(answer != HandshakeAnswer::Unanswered),
+        cancel_notice: AtomicBool::new(acknowledges(&answer, Capabilities::CANCEL)),
+        retry_hints: AtomicBool::new(acknowledges(&answer, Capabilities::RETRY_AFTER)),
+        goodbye: AtomicBool::new(acknowledges(&answer, Capabilities::GOODBYE)),
+        ..SharedTransfer::new(session)
+    };
+    // Blocked writes wake up regularly to notice cancellation and stalled receivers
+    handshake_stream
+        .get_ref()
+        .tcp()
+        .set_write_timeout(Some(WRITE_POLL_INTERVAL))?;
+    // Connections the receiver has no range for are closed once the handshake connection is done
+    let connections = &Mutex::new(Vec::new());
+    let done = &AtomicBool::new(false);
+
+    let pending = thread::scope(|scope| {
+        for index in 1..options.concurrency as usize {
+            let spawn_result = thread::Builder::new()
+                .name(thread_name("send", index))
+                .spawn_scoped(scope, move || {
+                    // Data connections are only accepted once the file is
+                    while !shared.accepted.load(Ordering::SeqCst) {
+                        if done.load(Ordering::SeqCst) || control.is_cancelled() {
+                            return;
+                        }
+                        thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+                    }
+                    let stream = match open_data_connection(receiver_addr, options) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("Failed to open data connection {}: {}", index, e);
+                            return;
+                        }
+                    };
+                    if let Ok(clone) = stream.try_clone() {
+                        lock_connections(connections).push(clone);
+                    }
+                    // Compression happens on the connection thread
+                    options.workers.pin_current_thread(index);
+                    let result = contain_panic(control, || {
+                        handle_connection(stream, file_metadata, source, options, shared, control)
+                    });
+                    if let Err(e) = result
+                        && !done.load(Ordering::SeqCst)
+                    {
+                        warn!("Data connection {} failed: {}", index, e);
+                    }
+                });
+            if let Err(e) = spawn_result {
+                error!("Failed to spawn connection thread: {}", e);
             }
         }
+
+        options.workers.pin_current_thread(0);
+        let pending = serve_connection(
+            &mut handshake_stream,
+            file_metadata,
+            source,
+            options,
+            shared,
+            control,
+            None,
+        );
+        done.store(true, Ordering::SeqCst);
+        for stream in lock_connections(connections).iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        pending
     });
+    // Blocks that could not be read explain why the receiver gave up
+    check_unreadable_blocks(shared, file_metadata, options)?;
+    let pending = pending?.ok_or_else(goodbye_before_completion)?;
 
+    let receipt = if expects_receipt(&answer) {
+        read_receipt(
+            &mut handshake_stream,
+            &mut transport_buffer,
+            &pending,
+            options.heartbeat.as_ref(),
+            &shared.session,
+        )
+    } else {
+        receipts_not_signed()
+    };
+    finish_transfer(
+        receipt,
+        &format!("{}:{}", address.0, address.1),
+        Some(address.0),
+        file_metadata,
+        options,
+        control,
+    )?;
+    registration.mark_succeeded();
     Ok(())
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
-    expected_hash: &[u8; 32],
-    file_path: &Path,
-    block_size: u32,
-    should_compress: bool,
-    transfer_complete: Arc<AtomicBool>,
+/// Returns the control channel of the transfer of `file_metadata` if the receiver's `answer`
+/// acknowledged the handshake with [Capabilities::CONTROL_CHANNEL], `None` otherwise.
+fn control_channel(
+    answer: &HandshakeAnswer,
+    file_metadata: &FileMetadata,
+    session: &SessionV1,
+) -> Option<ControlChannel> {
+    acknowledges(answer, Capabilities::CONTROL_CHANNEL)
+        .then(|| ControlChannel::new(file_metadata.hash(), Some(session.session_id)))
+}
+
+/// Returns whether the receiver's `answer` acknowledged the handshake with `capability`.
+fn acknowledges(answer: &HandshakeAnswer, capability: Capabilities) -> bool {
+    matches!(answer, HandshakeAnswer::Acknowledged(ack) if ack.capabilities.contains(capability))
+}
+
+/// Returns whether the receiver's `answer` means it answers the verified transfer with a receipt,
+/// or a `NoReceipt`. Receivers acknowledging the handshake only do when they acknowledged
+/// [Capabilities::RECEIPT], older ones are waited for until they close the connection.
+fn expects_receipt(answer: &HandshakeAnswer) -> bool {
+    match answer {
+        HandshakeAnswer::Acknowledged(ack) => ack.capabilities.contains(Capabilities::RECEIPT),
+        HandshakeAnswer::Accepted | HandshakeAnswer::Unanswered => true,
+    }
+}
+
+/// Returns the outcome of waiting for the receipt of a receiver that doesn't sign any, see
+/// [expects_receipt].
+fn receipts_not_signed() -> Result<ReceiptV1, SendFileError> {
+    Err(SendFileError::NoReceipt(String::from(
+        "receiver doesn't sign receipts",
+    )))
+}
+
+/// Opens a data connection to the handshake port at `receiver_addr`, see
+/// [SendOptions::single_port].
+fn open_data_connection(
+    receiver_addr: SocketAddr,
+    options: &SendOptions,
+) -> std::io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&receiver_addr, options.handshake_timeout)?;
+    options.socket.apply(&stream)?;
+    configure_keepalive(&stream, options.keepalive.as_ref());
+    Ok(stream)
+}
+
+fn lock_connections(connections: &Mutex<Vec<TcpStream>>) -> MutexGuard<'_, Vec<TcpStream>> {
+    connections.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Announces the file described by `file_metadata` to the receiver on `transport`, then serves
+/// its blocks from `source` over it until the receiver reports completion.
+fn send_source_over<T: Read + Write>(
+    transport: Preconnected<T>,
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    control: &Arc<TransferControl>,
 ) -> Result<(), SendFileError> {
-    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
-    let mut filled_len = 0;
+    // Never encrypted, but captured like every other connection
+    let mut transport = NoiseStream::new(transport);
+    // The receiver has a single connection to request blocks on
+    let options = &SendOptions {
+        concurrency: 1,
+        tls: None,
+        noise: false,
+        pairing_code: None,
+        udp_overhead: None,
+        should_compress: options.should_compress
+            && should_compress(source, file_metadata.size(), options),
+        ..options.clone()
+    };
+
+    let session =
+        new_session().map_err(|e| SendFileError::Io(std::io::Error::other(e.to_string())))?;
+    let mut transport_buffer = vec![0u8; MAX_MESSAGE_SIZE];
+    let answer = control.trace().in_span("handshake", || {
+        let (handshake, trailing) = handshake_frames(
+            &mut transport_buffer,
+            file_metadata,
+            source,
+            options,
+            Capabilities::empty(),
+            Some(&session),
+            load_identity(options).as_ref(),
+        )?;
+        transport.write_all(&handshake)?;
+        transport.write_all(&trailing)?;
+        transport.flush()?;
+        await_handshake_answer(
+            &mut transport,
+            &mut transport_buffer,
+            file_metadata,
+            options,
+            Some(&session.session_id),
+        )
+    })?;
+    control.set_total_bytes(file_metadata.size());
+    track_files(source, control);
+    let mut registration = TransferRegistry::global().register(
+        TransferDirection::Send,
+        PRECONNECTED_PEER.to_string(),
+        file_metadata.name().to_string(),
+        control.clone(),
+    );
+
+    let shared = SharedTransfer {
+        cancel_notice: AtomicBool::new(acknowledges(&answer, Capabilities::CANCEL)),
+        retry_hints: AtomicBool::new(acknowledges(&answer, Capabilities::RETRY_AFTER)),
+        goodbye: AtomicBool::new(acknowledges(&answer, Capabilities::GOODBYE)),
+        ..SharedTransfer::new(session)
+    };
+    let pending = serve_connection(
+        &mut transport,
+        file_metadata,
+        source,
+        options,
+        &shared,
+        control,
+        None,
+    );
+    // Blocks that could not be read explain why the receiver gave up
+    check_unreadable_blocks(&shared, file_metadata, options)?;
+    let pending = pending?.ok_or_else(goodbye_before_completion)?;
+
+    let receipt = if expects_receipt(&answer) {
+        read_receipt(
+            &mut transport,
+            &mut transport_buffer,
+            &pending,
+            options.heartbeat.as_ref(),
+            &shared.session,
+        )
+    } else {
+        receipts_not_signed()
+    };
+    finish_transfer(
+        receipt,
+        PRECONNECTED_PEER,
+        None,
+        file_metadata,
+        options,
+        control,
+    )?;
+    registration.mark_succeeded();
+    Ok(())
+}
+
+/// Fails with [SendFileError::IncompleteFile] if blocks of the file could not be read: the
+/// receiver either aborted or kept a file with holes.
+fn check_unreadable_blocks(
+    shared: &SharedTransfer,
+    file_metadata: &FileMetadata,
+    options: &SendOptions,
+) -> Result<(), SendFileError> {
+    let unreadable_blocks = shared
+        .unreadable_blocks
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if unreadable_blocks.is_empty() {
+        return Ok(());
+    }
+    Err(SendFileError::IncompleteFile {
+        ranges: block_ranges(
+            unreadable_blocks.keys().copied(),
+            options.block_size,
+            file_metadata.size(),
+        ),
+    })
+}
+
+/// Wraps up a completed transfer: verifies the receipt read from the receiver and records the
+/// transfer in [SendOptions::history_path].
+///
+/// # Arguments
+///
+/// * `receipt` - Result of reading the receipt.
+/// * `peer` - Receiver recorded in the history.
+/// * `host` - Host of the receiver, whose receipt must be signed by its key if it is a trusted
+///   peer. `None` if unknown.
+/// * `file_metadata` - The file sent.
+/// * `options` - Options of the send.
+/// * `control` - Control of the transfer, for its timings.
+///
+/// # Returns
+///
+/// An error only if the receiver quarantined the file, a missing or invalid receipt is logged.
+fn finish_transfer(
+    receipt: Result<ReceiptV1, SendFileError>,
+    peer: &str,
+    host: Option<&str>,
+    file_metadata: &FileMetadata,
+    options: &SendOptions,
+    control: &TransferControl,
+) -> Result<(), SendFileError> {
+    let file_hash = file_metadata.hash();
+    let receipt = match receipt {
+        Ok(receipt) => verify_receipt(&receipt, &file_hash, file_metadata.size())
+            .map(|_| receipt)
+            .map_err(|e| warn!("Discarding receipt: {}", e))
+            .ok()
+            .filter(|receipt| {
+                host.is_none_or(|host| is_expected_receiver(options, host, &receipt.receiver_key))
+            }),
+        Err(SendFileError::ScanFailed(reason)) => {
+            error!("Receiver quarantined the file: {}", reason);
+            return Err(SendFileError::ScanFailed(reason));
+        }
+        Err(SendFileError::NoReceipt(reason)) => {
+            info!("Receiver sent no receipt: {}", reason);
+            None
+        }
+        Err(e) => {
+            warn!("No receipt received from the receiver: {}", e);
+            None
+        }
+    };
+    if let Some(stage) = control.timings().snapshot().bottleneck() {
+        info!("Hint: {}", stage.hint());
+    }
+    if let Some(receipt) = &receipt {
+        info!(
+            "Delivery receipt signed by receiver key {}",
+            to_hex(&receipt.receiver_key)
+        );
+    }
+    let bundle = control.file_stats();
+    if let Some(stats) = &bundle {
+        stats.log();
+    }
+
+    if let Some(history_path) = &options.history_path {
+        let entry = HistoryEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            peer: peer.to_string(),
+            file_name: file_metadata.name().to_string(),
+            file_hash: to_hex(&file_hash),
+            bytes: file_metadata.size(),
+            receipt: receipt.as_ref().map(StoredReceipt::from),
+            bundle,
+        };
+        if let Err(e) = append_entry(history_path, &entry) {
+            warn!("Failed to record transfer in {:?}: {}", history_path, e);
+        }
+    }
+    Ok(())
+}
 
-    let file_handler = match File::open(file_path) {
-        Ok(f) => f,
+/// Compresses a sample of the blocks of the file to predict what is sent, and decides whether
+/// compression makes the transfer faster, see [CompressionEstimate::is_worthwhile].
+///
+/// Compression is left to the probe of each connection if the sample can't be read.
+fn should_compress(source: &dyn BlockSource, size: u64, options: &SendOptions) -> bool {
+    let estimate = match CompressionEstimate::sample(source, size, options.block_size) {
+        Ok(estimate) => estimate,
         Err(e) => {
-            error!("Failed to open file: {}", e);
-            return Err(SendFileError::Io(e));
+            warn!("Failed to sample blocks to estimate compression: {}", e);
+            return true;
+        }
+    };
+
+    let compress = estimate.is_worthwhile(size, options.link_speed, options.concurrency);
+    let wire_bytes = if compress {
+        estimate.wire_bytes(size)
+    } else {
+        size
+    };
+    info!(
+        "Sampled blocks compress to {:.0}%, compression {}: ~{} on the wire, ETA ~{} at {} Mbit/s",
+        estimate.ratio() * 100.0,
+        if compress { "enabled" } else { "not worth it" },
+        Size(wire_bytes),
+        Elapsed(estimate.duration(size, options.link_speed, options.concurrency, compress)),
+        options.link_speed * 8 / 1_000_000
+    );
+    compress
+}
+
+/// Checks the key that signed the receipt against the trusted peer reachable at `host`, if
+/// there is one.
+///
+/// Returns `false` if the receipt was signed by another key, in which case it is discarded.
+fn is_expected_receiver(options: &SendOptions, host: &str, key: &[u8; 32]) -> bool {
+    let peers = match options.peers_path.as_deref().map(PeerRegistry::load) {
+        Some(Ok(peers)) => peers,
+        Some(Err(e)) => {
+            warn!("Failed to read trusted peers: {}", e);
+            return true;
+        }
+        None => return true,
+    };
+
+    match peers.find_by_address(host) {
+        Some(peer) if peer.key().ok() == Some(*key) => {
+            info!("Receipt signed by trusted peer {:?}", peer.name);
+            true
+        }
+        Some(peer) => {
+            warn!(
+                "Discarding receipt signed by {}, {} is trusted peer {:?} with key {}",
+                to_hex(key),
+                host,
+                peer.name,
+                peer.public_key
+            );
+            false
+        }
+        None => true,
+    }
+}
+
+/// Waits on the handshake connection for the receipt the receiver sends once it has verified
+/// the file. `pending` holds bytes already read from the connection. Frames tagged with
+/// another session than `session` are refused.
+///
+/// A receiver pinging the connection while it hashes the file is waited for as long as it
+/// keeps pinging, and given up on once it was silent for the [Heartbeat::timeout] of
+/// `heartbeat`.
+fn read_receipt<S: Connection>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    pending: &[u8],
+    heartbeat: Option<&Heartbeat>,
+    session: &SessionV1,
+) -> Result<ReceiptV1, SendFileError> {
+    // The receiver hashes the whole file before answering
+    stream.set_read_timeout(Some(Duration::from_secs(RECEIPT_TIMEOUT_SECS)))?;
+    buffer[..pending.len()].copy_from_slice(pending);
+    let mut filled_len = pending.len();
+    loop {
+        let result = read_next_payload::<ReceiverMessageV1, _>(stream, buffer, filled_len)?;
+        result.check_session(Some(&session.session_id), result.protocol_version)?;
+        filled_len = match result.next_payload_index {
+            Some(next_idx) => {
+                buffer.copy_within(next_idx..result.total_bytes_read, 0);
+                result.total_bytes_read - next_idx
+            }
+            None => 0,
+        };
+        match result.message {
+            ReceiverMessageV1::Receipt(receipt) => return Ok(receipt),
+            ReceiverMessageV1::Ping(_) | ReceiverMessageV1::Pong(_) => {
+                if let Some(heartbeat) = heartbeat {
+                    stream.set_read_timeout(Some(heartbeat.timeout))?;
+                }
+                continue;
+            }
+            // Left unread when the data connections came up before they were polled
+            ReceiverMessageV1::OfferResponse(_) | ReceiverMessageV1::HandshakeAck(_) => continue,
+            // Reported on the control channel before the last data connection was done
+            ReceiverMessageV1::Progress(_) | ReceiverMessageV1::TransferComplete(_) => continue,
+            ReceiverMessageV1::ProtocolVersion(choice) => {
+                accept_protocol_version(choice)?;
+                continue;
+            }
+            ReceiverMessageV1::Error(error) if error.code == SCAN_FAILED_CODE => {
+                return Err(SendFileError::ScanFailed(error.message));
+            }
+            ReceiverMessageV1::NoReceipt(answer) => {
+                return Err(SendFileError::NoReceipt(answer.reason));
+            }
+            message => {
+                return Err(SendFileError::UnexpectedMessage {
+                    received: format!("{:?}", message),
+                    expected: String::from("Receipt"),
+                });
+            }
+        }
+    }
+}
+
+/// Waits on the handshake connection for the receiver's answer to the offer.
+fn read_offer_response<S: Connection>(
+    stream: &mut S,
+    buffer: &mut [u8],
+) -> Result<OfferResponseV1, SendFileError> {
+    stream.set_read_timeout(Some(Duration::from_secs(OFFER_RESPONSE_TIMEOUT_SECS)))?;
+    match read_handshake_answer(stream, buffer, None)?.0 {
+        ReceiverMessageV1::OfferResponse(response) => Ok(response),
+        message => Err(SendFileError::UnexpectedMessage {
+            received: format!("{:?}", message),
+            expected: String::from("OfferResponse"),
+        }),
+    }
+}
+
+/// Reads the receiver's answer to the offer of `file_metadata` if it has arrived on the
+/// handshake connection after [await_handshake_answer] gave up on it, without waiting for it
+/// otherwise, see [handshake_answer]. Receivers lacking [Capabilities::OFFER_RESPONSE] never
+/// answer. `pending` is kept like in [poll_handshake_message].
+fn poll_offer_response(
+    stream: &mut NoiseStream<MaybeTlsStream>,
+    buffer: &mut [u8],
+    pending: &mut Vec<u8>,
+    session: &SessionV1,
+    file_metadata: &FileMetadata,
+    options: &SendOptions,
+) -> Result<Option<HandshakeAnswer>, SendFileError> {
+    let Some((message, _)) = poll_handshake_message(stream, buffer, pending, session)? else {
+        return Ok(None);
+    };
+    match handshake_answer(&message, file_metadata, options) {
+        Some(answer) => answer.map(Some),
+        None => Err(SendFileError::UnexpectedMessage {
+            received: format!("{:?}", message),
+            expected: String::from("HandshakeAck"),
+        }),
+    }
+}
+
+/// Reads whether the receiver aborted the transfer at its time limit or cancelled it if its
+/// message has arrived on the handshake connection, without waiting for it otherwise, and
+/// cancels `control` if it did. `pending` is kept like in [poll_handshake_message].
+///
+/// # Returns
+///
+/// Whether the receiver aborted the transfer, or an error if the connection failed.
+fn poll_receiver_abort(
+    stream: &mut NoiseStream<MaybeTlsStream>,
+    buffer: &mut [u8],
+    pending: &mut Vec<u8>,
+    session: &SessionV1,
+    control: &TransferControl,
+) -> Result<bool, SendFileError> {
+    match poll_handshake_message(stream, buffer, pending, session)? {
+        Some((ReceiverMessageV1::Error(error), _)) if error.code == TIME_LIMIT_CODE => {
+            control.abort_by_peer(error.message);
+            Ok(true)
+        }
+        Some((ReceiverMessageV1::Cancel(cancel), _)) => {
+            control.cancel_by_peer(cancel.reason);
+            Ok(true)
+        }
+        Some((message, _)) => {
+            debug!("Ignoring {:?} on the handshake connection", message);
+            Ok(false)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Reads the reports the receiver sent on the control channel `channel` of the handshake
+/// connection, without waiting for more, then tells it the rate limit of `control` if it
+/// changed, see [crate::stream::control]. `pending` is kept like in [poll_handshake_message].
+///
+/// Reported progress is counted as the progress of `control`. A transfer reported complete is
+/// marked so in `shared`, and the messages following the report are left for [read_receipt].
+///
+/// # Returns
+///
+/// Whether the receiver sent anything, or an error if the channel failed.
+fn poll_control_channel(
+    stream: &mut NoiseStream<MaybeTlsStream>,
+    buffer: &mut [u8],
+    pending: &mut Vec<u8>,
+    shared: &SharedTransfer,
+    channel: &mut ControlChannel,
+    control: &TransferControl,
+) -> Result<bool, SendFileError> {
+    let mut heard = false;
+    while let Some((message, protocol_version)) =
+        poll_handshake_message(stream, buffer, pending, &shared.session)?
+    {
+        heard = true;
+        channel.heard(protocol_version);
+        match message {
+            ReceiverMessageV1::Progress(progress) if progress.file_hash == channel.file_hash => {
+                control.report_bytes(Some(progress.bytes_received));
+            }
+            ReceiverMessageV1::TransferComplete(complete)
+                if complete.file_hash == channel.file_hash =>
+            {
+                info!("Receiver reported the transfer complete on the control channel");
+                shared.complete.store(true, Ordering::SeqCst);
+                break;
+            }
+            ReceiverMessageV1::Error(error) if error.code == TIME_LIMIT_CODE => {
+                control.abort_by_peer(error.message);
+                break;
+            }
+            ReceiverMessageV1::Cancel(cancel) => {
+                control.cancel_by_peer(cancel.reason);
+                break;
+            }
+            message => debug!("Ignoring {:?} on the control channel", message),
+        }
+    }
+    channel.push_rate_limit(stream, control)?;
+    Ok(heard)
+}
+
+/// Reads the next message of the receiver on the handshake connection, past its choice of
+/// protocol version, if it has arrived, without waiting for it otherwise, with the protocol
+/// version it was framed with. Heartbeats are read as no message.
+///
+/// `pending` holds the bytes read past the previous message and is left with those read past
+/// this one, so a receipt sent right behind it is kept for [read_receipt].
+pub(crate) fn poll_handshake_message(
+    stream: &mut NoiseStream<MaybeTlsStream>,
+    buffer: &mut [u8],
+    pending: &mut Vec<u8>,
+    session: &SessionV1,
+) -> Result<Option<(ReceiverMessageV1, u8)>, SendFileError> {
+    // Closed connections are reported when waiting for the receipt
+    if pending.is_empty() && !stream.has_pending_data()? {
+        return Ok(None);
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(OFFER_RESPONSE_TIMEOUT_SECS)))?;
+    buffer[..pending.len()].copy_from_slice(pending);
+    let mut filled_len = pending.len();
+    loop {
+        let result = read_next_payload::<ReceiverMessageV1, _>(stream, buffer, filled_len)?;
+        result.check_session(Some(&session.session_id), result.protocol_version)?;
+        filled_len = match result.next_payload_index {
+            Some(next_idx) => {
+                buffer.copy_within(next_idx..result.total_bytes_read, 0);
+                result.total_bytes_read - next_idx
+            }
+            None => 0,
+        };
+        let message = match result.message {
+            ReceiverMessageV1::ProtocolVersion(choice) => {
+                accept_protocol_version(choice)?;
+                continue;
+            }
+            // Only keep the connection alive, the receiver isn't reading it
+            ReceiverMessageV1::Ping(_) | ReceiverMessageV1::Pong(_) => None,
+            message => Some((message, result.protocol_version)),
+        };
+        pending.clear();
+        pending.extend_from_slice(&buffer[..filled_len]);
+        return Ok(message);
+    }
+}
+
+/// State shared by the data connections of a transfer.
+pub(crate) struct SharedTransfer {
+    /// Session data connections open with a hello for, see
+    /// [ConnHelloV1](crate::transport::ConnHelloV1).
+    session: SessionV1,
+    /// Set once a connection opened with a valid hello: the receiver supports them, so
+    /// connections without one are refused from then on.
+    hello_required: AtomicBool,
+    /// Set once a connection completed the transfer.
+    complete: AtomicBool,
+    /// Set once the receiver accepted the file on a connection that is also the handshake
+    /// connection.
+    accepted: AtomicBool,
+    /// Blocks that could not be read with their read error.
+    unreadable_blocks: Arc<Mutex<BTreeMap<u32, String>>>,
+    /// Receiver of a TLS handshake connection, whose certificate data connections must present.
+    tls: Option<TlsPeer>,
+    /// Receiver of a Noise channel, whose key data connections must hold.
+    noise: Option<NoisePeer>,
+    /// Set once the receiver acknowledged the handshake with [Capabilities::CANCEL], so data
+    /// connections tell it when the transfer is cancelled, see [crate::stream::cancel].
+    cancel_notice: AtomicBool,
+    /// Set once the receiver acknowledged the handshake with [Capabilities::RETRY_AFTER], so
+    /// requests that may succeed later are answered with `RetryAfter`, see
+    /// [crate::stream::retry_after].
+    retry_hints: AtomicBool,
+    /// Set once the receiver acknowledged the handshake with [Capabilities::GOODBYE], so data
+    /// connections are served until its `Goodbye`, even past its `TransferComplete`.
+    goodbye: AtomicBool,
+}
+
+impl SharedTransfer {
+    pub(crate) fn new(session: SessionV1) -> Self {
+        Self {
+            session,
+            hello_required: AtomicBool::new(false),
+            complete: AtomicBool::new(false),
+            accepted: AtomicBool::new(false),
+            unreadable_blocks: Arc::new(Mutex::new(BTreeMap::new())),
+            tls: None,
+            noise: None,
+            cancel_notice: AtomicBool::new(false),
+            retry_hints: AtomicBool::new(false),
+            goodbye: AtomicBool::new(false),
+        }
+    }
+
+    /// Checks the first message of a data connection.
+    ///
+    /// # Returns
+    ///
+    /// Whether the message was the connection's hello, consumed by the check, or an error if
+    /// the connection belongs to another session or lacks a hello the receiver is known to send.
+    pub(crate) fn admit(&self, message: &ReceiverMessageV1) -> Result<bool, SendFileError> {
+        match message {
+            ReceiverMessageV1::ConnHello(hello) if verify_conn_hello(&self.session, hello) => {
+                self.hello_required.store(true, Ordering::SeqCst);
+                Ok(true)
+            }
+            ReceiverMessageV1::ConnHello(_) => Err(SendFileError::ConnectionFailed(String::from(
+                "Connection hello is for another session",
+            ))),
+            _ if self.hello_required.load(Ordering::SeqCst) => Err(
+                SendFileError::ConnectionFailed(String::from("Connection did not say hello")),
+            ),
+            // Receivers without hellos start right away with their requests
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Serves the data connection `stream` accepted from the receiver, see [serve_connection].
+///
+/// # Returns
+///
+/// Whether the receiver reported the transfer complete on the connection, or an error if the
+/// connection failed.
+fn handle_connection(
+    stream: TcpStream,
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    shared: &SharedTransfer,
+    control: &TransferControl,
+) -> Result<bool, SendFileError> {
+    control.register(&stream);
+    let peer = stream.peer_addr()?;
+    let sending = || ErrorContext::new("sending").with_peer(peer);
+    // Datagrams are plaintext, encrypted transfers keep blocks on their connections
+    let udp = match options.udp_overhead {
+        Some(overhead) if shared.tls.is_none() && shared.noise.is_none() => {
+            UdpSender::bind(peer.ip(), overhead)
+                .inspect_err(|e| warn!("Sending blocks over TCP only, UDP unavailable: {}", e))
+                .ok()
+        }
+        _ => None,
+    };
+    let stream = match &shared.tls {
+        Some(tls) => {
+            // A receiver that never completes the TLS handshake frees its slot
+            stream.set_read_timeout(Some(options.handshake_timeout))?;
+            tls.accept(stream)
+                .inspect_err(|e| {
+                    warn!("Refusing connection: {}", e);
+                })
+                .context(sending)?
+        }
+        None => MaybeTlsStream::Plain(stream),
+    };
+    let mut stream = NoiseStream::new(stream);
+    if let Some(noise) = &shared.noise {
+        stream.set_read_timeout(Some(options.handshake_timeout))?;
+        noise
+            .accept(&mut stream, Some(&shared.session.session_id))
+            .inspect_err(|e| {
+                warn!("Refusing connection: {}", e);
+            })
+            .context(sending)?;
+    }
+    // Blocked writes wake up regularly to notice cancellation and stalled receivers
+    stream
+        .get_ref()
+        .tcp()
+        .set_write_timeout(Some(WRITE_POLL_INTERVAL))?;
+    let result = match options.heartbeat {
+        Some(heartbeat) => serve_connection(
+            &mut Heartbeating::new(stream, heartbeat, Side::Sender),
+            file_metadata,
+            source,
+            options,
+            shared,
+            control,
+            udp.as_ref(),
+        ),
+        None => serve_connection(
+            &mut stream,
+            file_metadata,
+            source,
+            options,
+            shared,
+            control,
+            udp.as_ref(),
+        ),
+    };
+    result.map(|pending| pending.is_some()).context(sending)
+}
+
+/// Answers the requests of the receiver on a data connection until it reports the transfer
+/// complete, or until its `Goodbye` if it acknowledged [Capabilities::GOODBYE]. Blocks requested
+/// as UDP datagrams are sent with `udp`, see [crate::stream::udp].
+///
+/// # Returns
+///
+/// Bytes read from `stream` past the receiver's `TransferComplete`, or past its `Goodbye` if it
+/// said one, `None` if it said goodbye without reporting the transfer complete, or an error if
+/// the connection failed before.
+fn serve_connection<S: Connection>(
+    stream: &mut S,
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    shared: &SharedTransfer,
+    control: &TransferControl,
+    udp: Option<&UdpSender>,
+) -> Result<Option<Vec<u8>>, SendFileError> {
+    control.trace().in_span("connection", || {
+        serve_requests(stream, file_metadata, source, options, shared, control, udp)
+    })
+}
+
+/// Answers the requests of the receiver as [serve_connection] does.
+fn serve_requests<S: Connection>(
+    stream: &mut S,
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    shared: &SharedTransfer,
+    control: &TransferControl,
+    udp: Option<&UdpSender>,
+) -> Result<Option<Vec<u8>>, SendFileError> {
+    let SendOptions {
+        block_size,
+        should_compress,
+        write_timeout,
+        read_retries,
+        ..
+    } = *options;
+    // Until its first request, so a connection that never sends one frees its slot
+    stream.set_read_timeout(Some(options.handshake_timeout))?;
+    let mut awaiting_first_request = true;
+    let mut buffer = AlignedBuffer::zeroed(MAX_MESSAGE_SIZE);
+    let block_len = |seq: u32| match source.bundle() {
+        Some(bundle) => bundle.block_len(seq),
+        None => {
+            let offset = seq as u64 * block_size as u64;
+            file_metadata
+                .size()
+                .saturating_sub(offset)
+                .min(block_size as u64)
         }
     };
+    let total_blocks = file_metadata.size().div_ceil(block_size as u64);
+    let mut filled_len = 0;
 
     let mut handler = ConnectionHandler {
-        file: file_handler,
-        expected_hash: *expected_hash,
+        source,
+        expected_hash: file_metadata.hash(),
         block_size,
         compression_enabled: None,
-        write_buffer: vec![0u8; MAX_MESSAGE_SIZE],
+        write_buffer: AlignedBuffer::zeroed(MAX_MESSAGE_SIZE),
         compressed_buffer: Vec::with_capacity(block_size as usize),
+        compressor: BlockCompressor::default(),
+        checksum: ChecksumAlgorithm::Crc32,
+        read_retries,
+        retry_hints: shared.retry_hints.load(Ordering::SeqCst),
+        unreadable_blocks: shared.unreadable_blocks.clone(),
+        timings: control.timings().clone(),
+        entropy_threshold: options.compress_entropy_threshold,
+        protocol_version: CURRENT_PROTOCOL_VERSION,
+        session_id: Some(shared.session.session_id),
+        blocks: BlockSpans::new(),
     };
+    // Range the receiver asked to be streamed, its acknowledgements refer to it
+    let mut push: Option<PushRangeV1> = None;
+    // Set once the receiver reported the transfer complete and is about to say goodbye
+    let mut completed = false;
 
     loop {
-        if transfer_complete.load(Ordering::Relaxed) {
+        // Receivers saying goodbye end every connection themselves
+        if shared.complete.load(Ordering::Relaxed) && !shared.goodbye.load(Ordering::Relaxed) {
             info!("Transfer already marked complete, closing connection");
-            return Ok(());
+            return Ok(Some(Vec::new()));
         }
-        match read_next_payload::<ReceiverMessageV1, _>(&mut stream, &mut buffer, filled_len) {
+        control.check_bottleneck();
+        match read_next_payload::<ReceiverMessageV1, _>(stream, &mut buffer, filled_len) {
             Ok(result) => {
-                let message = result.message;
+                // Frames of another transfer's session are refused
+                result
+                    .check_session(handler.session_id.as_ref(), result.protocol_version)
+                    .inspect_err(|e| warn!("Refusing connection: {}", e))?;
 
                 // Handle buffer management for next iteration
                 if let Some(next_idx) = result.next_payload_index {
@@ -166,28 +1659,224 @@ fn handle_connection(
                     filled_len = 0;
                 }
 
-                match message {
-                    ReceiverMessageV1::Request(req) => {
-                        handler.handle_data_request(&req, &mut stream, should_compress)?;
+                // Batched messages are handled one after the other, see [unbatch]
+                for message in unbatch(result.message)? {
+                    // Only sent on a data connection when it is also the handshake connection, see
+                    // [crate::stream::preconnected]
+                    match message {
+                        ReceiverMessageV1::ProtocolVersion(choice) => {
+                            accept_protocol_version(choice)?;
+                            continue;
+                        }
+                        ReceiverMessageV1::OfferResponse(_)
+                        | ReceiverMessageV1::HandshakeAck(_)
+                        | ReceiverMessageV1::HandshakeReject(_) => {
+                            handshake_answer(&message, file_metadata, options).transpose()?;
+                            shared.accepted.store(true, Ordering::SeqCst);
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    // Answers are framed as the receiver frames its requests
+                    handler.protocol_version = result.protocol_version;
+                    // Receivers ping right after their hello, see [crate::stream::heartbeat]
+                    match message {
+                        ReceiverMessageV1::Ping(ping) => {
+                            stream.enable_heartbeat(handler.protocol_version, handler.session_id);
+                            handler.handle_ping(&ping, stream)?;
+                            continue;
+                        }
+                        ReceiverMessageV1::Pong(_) => continue,
+                        _ => {}
                     }
-                    ReceiverMessageV1::Progress(prog) => {
-                        handler.handle_progress(&prog)?;
+
+                    let mut is_hello = false;
+                    if awaiting_first_request {
+                        stream.set_read_timeout(None)?;
+                        awaiting_first_request = false;
+                        is_hello = shared.admit(&message).inspect_err(|e| {
+                            warn!("Refusing connection: {}", e);
+                        })?;
                     }
-                    ReceiverMessageV1::TransferComplete(complete) => {
-                        return handler.handle_transfer_complete(&complete);
+                    if is_hello {
+                        debug!("Connection joined the session");
+                        continue;
                     }
-                    ReceiverMessageV1::Error(err) => {
-                        handler.handle_error(&err);
-                        return Err(SendFileError::ConnectionFailed(format!(
-                            "Receiver error {}: {}",
-                            err.code, err.message
-                        )));
+
+                    // A paused sender stops answering until it is resumed
+                    if let Err(e) = control.checkpoint() {
+                        send_abort(stream, options, control, shared, &handler);
+                        return Err(e);
                     }
-                    ReceiverMessageV1::VerifyBlock(verify) => {
-                        handler.handle_verify_block(&verify, &mut stream)?;
+
+                    let mut writer = ChunkedWriter::new(&mut *stream, control, write_timeout);
+                    // Served no faster than the rate limit of the transfer
+                    let sent = |bytes| {
+                        control.add_bytes(bytes);
+                        control.throttle(bytes);
+                    };
+                    let result = match message {
+                        // Blocks of a directory are requested by file, see [crate::stream::bundle]
+                        ReceiverMessageV1::Request(_)
+                        | ReceiverMessageV1::SparseRequest(_)
+                        | ReceiverMessageV1::UdpRequest(_)
+                        | ReceiverMessageV1::HaveBlocks(_)
+                        | ReceiverMessageV1::PushRange(_)
+                        | ReceiverMessageV1::RequestRange(_)
+                            if source.bundle().is_some() =>
+                        {
+                            return Err(SendFileError::InvalidRequest(String::from(
+                                "Receiver requested a block of a directory without naming its file, \
+                                 it can't receive directories",
+                            )));
+                        }
+                        ReceiverMessageV1::FileRequest(req) => handler
+                            .handle_file_request(&req, &mut writer, should_compress)
+                            .map(|()| {
+                                let seq = source
+                                    .bundle()
+                                    .and_then(|bundle| bundle.bundle_seq(req.file_index, req.seq));
+                                let bytes = seq.map_or(0, block_len);
+                                control.add_file_bytes(req.file_index, bytes);
+                                sent(bytes)
+                            }),
+                        ReceiverMessageV1::Request(req) => {
+                            if let Some(udp) = udp {
+                                udp.note_tcp_request(req.seq);
+                            }
+                            handler
+                                .handle_data_request(&req, &mut writer, should_compress)
+                                .map(|()| sent(block_len(req.seq)))
+                        }
+                        ReceiverMessageV1::SparseRequest(req) => handler
+                            .handle_sparse_request(&req, &mut writer, should_compress)
+                            .map(|()| sent(block_len(req.seq))),
+                        // Datagrams are throttled as they are sent
+                        ReceiverMessageV1::UdpRequest(req) => handler
+                            .handle_udp_request(&req, udp, &mut writer, should_compress, |bytes| {
+                                control.throttle(bytes)
+                            })
+                            .map(|()| control.add_bytes(block_len(req.seq))),
+                        // A paused sender stops pushing blocks until it is resumed
+                        ReceiverMessageV1::HaveBlocks(have) => handler.handle_have_blocks(
+                            &have,
+                            total_blocks,
+                            &mut writer,
+                            should_compress,
+                            |seq| {
+                                sent(block_len(seq));
+                                control.checkpoint()
+                            },
+                        ),
+                        ReceiverMessageV1::RequestRange(range) => handler.handle_request_range(
+                            &range,
+                            total_blocks,
+                            &mut writer,
+                            should_compress,
+                            |seq| {
+                                sent(block_len(seq));
+                                control.checkpoint()
+                            },
+                        ),
+                        ReceiverMessageV1::PushRange(range) => {
+                            push = Some(range);
+                            handler.handle_push_range(
+                                &range,
+                                total_blocks,
+                                &mut writer,
+                                should_compress,
+                                |seq| {
+                                    sent(block_len(seq));
+                                    control.checkpoint()
+                                },
+                            )
+                        }
+                        ReceiverMessageV1::PushAck(ack) => {
+                            let Some(range) = push else {
+                                return Err(SendFileError::UnexpectedMessage {
+                                    received: format!("{:?}", ack),
+                                    expected: String::from("PushRange"),
+                                });
+                            };
+                            handler.handle_push_ack(
+                                &ack,
+                                &range,
+                                &mut writer,
+                                should_compress,
+                                |seq| {
+                                    sent(block_len(seq));
+                                    control.checkpoint()
+                                },
+                            )
+                        }
+                        ReceiverMessageV1::Progress(prog) => handler.handle_progress(&prog),
+                        ReceiverMessageV1::TransferComplete(complete) => {
+                            handler.handle_transfer_complete(&complete)?;
+                            if !shared.goodbye.load(Ordering::SeqCst) {
+                                return Ok(Some(buffer[..filled_len].to_vec()));
+                            }
+                            completed = true;
+                            Ok(())
+                        }
+                        ReceiverMessageV1::Goodbye(goodbye) => {
+                            handler.handle_goodbye(&goodbye, &mut writer)?;
+                            return Ok(completed.then(|| buffer[..filled_len].to_vec()));
+                        }
+                        ReceiverMessageV1::Error(err) if err.code == TIME_LIMIT_CODE => {
+                            control.abort_by_peer(err.message);
+                            return Err(SendFileError::Cancelled);
+                        }
+                        ReceiverMessageV1::Cancel(cancel) => {
+                            control.cancel_by_peer(cancel.reason);
+                            return Err(control.cancellation());
+                        }
+                        ReceiverMessageV1::Error(err) => {
+                            handler.handle_error(&err);
+                            return Err(SendFileError::ConnectionFailed(format!(
+                                "Receiver error {}: {}",
+                                err.code, err.message
+                            )));
+                        }
+                        ReceiverMessageV1::VerifyBlock(verify) => {
+                            handler.handle_verify_block(&verify, &mut writer)
+                        }
+                        ReceiverMessageV1::BlockHashesRequest(req) => {
+                            handler.handle_block_hashes_request(&req, &mut writer)
+                        }
+                        ReceiverMessageV1::Algorithms(algorithms) => {
+                            handler.handle_algorithms(&algorithms)
+                        }
+                        ReceiverMessageV1::Receipt(_)
+                        | ReceiverMessageV1::NoReceipt(_)
+                        | ReceiverMessageV1::OfferResponse(_)
+                        | ReceiverMessageV1::HandshakeAck(_)
+                        | ReceiverMessageV1::HandshakeReject(_)
+                        | ReceiverMessageV1::ProbeAck(_)
+                        | ReceiverMessageV1::ConnHello(_)
+                        | ReceiverMessageV1::ProtocolVersion(_)
+                        | ReceiverMessageV1::NoiseHandshake(_)
+                        | ReceiverMessageV1::PairingReply(_)
+                        | ReceiverMessageV1::Introduction(_)
+                        | ReceiverMessageV1::Ping(_)
+                        | ReceiverMessageV1::Pong(_)
+                        | ReceiverMessageV1::Batch(_) => {
+                            return Err(SendFileError::UnexpectedMessage {
+                                received: format!("{:?}", message),
+                                expected: String::from("Request"),
+                            });
+                        }
+                    };
+                    // Writes abort with an I/O error once the transfer is cancelled
+                    match result {
+                        Err(_) if control.is_cancelled() => return Err(control.cancellation()),
+                        result => result?,
                     }
                 }
             }
+            Err(_) if control.is_cancelled() => {
+                send_abort(stream, options, control, shared, &handler);
+                return Err(control.cancellation());
+            }
             Err(e) => {
                 warn!("Connection error: {}", e);
                 return Err(SendFileError::Stream(e));
@@ -196,13 +1885,118 @@ fn handle_connection(
     }
 }
 
+/// Returns the error of a handshake connection the receiver said goodbye on before reporting the
+/// transfer complete, which it only does on connections it opened for a range.
+fn goodbye_before_completion() -> SendFileError {
+    SendFileError::UnexpectedMessage {
+        received: String::from("Goodbye"),
+        expected: String::from("TransferComplete"),
+    }
+}
+
+/// Returns the messages of `message` if it is a [BatchV1](crate::transport::BatchV1), `message`
+/// alone otherwise.
+///
+/// # Returns
+///
+/// The messages in the order they are handled, or [SendFileError::InvalidRequest] for an empty
+/// batch or a batch of more than [MAX_BATCH_MESSAGES] messages.
+fn unbatch(message: ReceiverMessageV1) -> Result<Vec<ReceiverMessageV1>, SendFileError> {
+    let ReceiverMessageV1::Batch(batch) = message else {
+        return Ok(vec![message]);
+    };
+    if batch.messages.is_empty() || batch.messages.len() > MAX_BATCH_MESSAGES {
+        return Err(SendFileError::InvalidRequest(format!(
+            "Receiver batched {} messages, batches hold 1 to {}",
+            batch.messages.len(),
+            MAX_BATCH_MESSAGES
+        )));
+    }
+    Ok(batch
+        .messages
+        .into_iter()
+        .map(ReceiverMessageV1::from)
+        .collect())
+}
+
+/// Asks the receiver of a connection past the connection limit to open it again in
+/// [BUSY_RETRY_AFTER] if it supports [Capabilities::RETRY_AFTER], before dropping it. Encrypted
+/// connections are dropped without a word, as the hint would have to follow their handshake.
+fn refuse_busy(mut stream: TcpStream, shared: &SharedTransfer) {
+    if !shared.retry_hints.load(Ordering::SeqCst) || shared.tls.is_some() || shared.noise.is_some()
+    {
+        return;
+    }
+    let msg = SenderMessageV1::RetryAfter(retry_hint(
+        String::from("Sender is at its connection limit"),
+        BUSY_RETRY_AFTER,
+    ));
+    let mut buffer = vec![0u8; 256];
+    let result = msg
+        .to_bytes(&mut buffer)
+        .map_err(SendFileError::from)
+        .and_then(|payload| {
+            stream.write_all(&attach_headers_for(
+                CURRENT_PROTOCOL_VERSION,
+                Some(&shared.session.session_id),
+                payload,
+            ))?;
+            Ok(stream.flush()?)
+        });
+    if let Err(e) = result {
+        debug!("Failed to ask the receiver to connect again later: {}", e);
+    }
+}
+
+/// Tells the receiver on `stream` why the transfer is aborted: the time limit of
+/// [SendOptions::max_duration] ran out, see [crate::stream::time_limit], or the transfer was
+/// cancelled on this side and the receiver supports [Capabilities::CANCEL], see
+/// [crate::stream::cancel]. Only called between messages, so the abort isn't written in the
+/// middle of an answer.
+fn send_abort<S: Write, B: BlockSource>(
+    stream: &mut S,
+    options: &SendOptions,
+    control: &TransferControl,
+    shared: &SharedTransfer,
+    handler: &ConnectionHandler<B>,
+) {
+    let msg = if control.is_expired() {
+        SenderMessageV1::Error(SenderErrorV1 {
+            code: TIME_LIMIT_CODE,
+            message: abort_reason("sender", options.max_duration),
+        })
+    } else if control.is_cancelled_here() && shared.cancel_notice.load(Ordering::SeqCst) {
+        SenderMessageV1::Cancel(CancelV1 {
+            file_hash: handler.expected_hash,
+            reason: cancel_reason("sender"),
+        })
+    } else {
+        return;
+    };
+    let mut buffer = vec![0u8; 256];
+    let result = msg
+        .to_bytes(&mut buffer)
+        .map_err(SendFileError::from)
+        .and_then(|payload| {
+            stream.write_all(&attach_headers_for(
+                handler.protocol_version,
+                handler.session_id.as_ref(),
+                payload,
+            ))?;
+            Ok(stream.flush()?)
+        });
+    if let Err(e) = result {
+        debug!("Failed to tell the receiver the transfer is aborted: {}", e);
+    }
+}
+
 /// Handler for a single connection from a receiver.
 ///
 /// Manages the state and logic for processing messages from a receiver,
 /// including handling data requests, progress updates, and verification requests.
-pub struct ConnectionHandler {
-    /// Handle to the file being transferred.
-    pub file: File,
+pub struct ConnectionHandler<S: BlockSource = File> {
+    /// Source of the file being transferred.
+    pub source: S,
     /// Expected BLAKE3 hash of the file, used for validation.
     pub expected_hash: [u8; 32],
     /// Size of each data block.
@@ -211,16 +2005,67 @@ pub struct ConnectionHandler {
     /// `None` indicates that the decision hasn't been made yet (probing).
     pub compression_enabled: Option<bool>,
     /// Buffer for writing outgoing messages.
-    pub write_buffer: Vec<u8>,
+    pub write_buffer: AlignedBuffer,
     /// Buffer for compressing data blocks.
     pub compressed_buffer: Vec<u8>,
+    /// Compressor of data blocks, gzip unless the receiver picked another codec with
+    /// `Algorithms`, see [Self::handle_algorithms].
+    pub compressor: BlockCompressor,
+    /// Algorithm data blocks are checksummed with, CRC-32 unless the receiver picked another.
+    pub checksum: ChecksumAlgorithm,
+    /// Number of times a failed block read is retried before the block is reported unreadable.
+    pub read_retries: u32,
+    /// Whether blocks whose read failed in a way that may pass are answered with `RetryAfter`
+    /// rather than reported unreadable, see [crate::stream::retry_after].
+    pub retry_hints: bool,
+    /// Blocks that could not be read with their read error, shared by every connection so they
+    /// are not retried again.
+    pub unreadable_blocks: Arc<Mutex<BTreeMap<u32, String>>>,
+    /// Time spent reading, compressing and sending blocks, shared by every connection.
+    pub timings: Arc<StageTimings>,
+    /// Entropy in bits per byte above which a block is sent without trying to compress it.
+    pub entropy_threshold: f64,
+    /// Protocol version answers are framed with.
+    pub protocol_version: u8,
+    /// Session id answers are tagged with, see [crate::transport::SESSION_FRAMING_PROTOCOL_VERSION].
+    pub session_id: Option<[u8; SESSION_ID_SIZE]>,
+    /// Spans the answered blocks are traced in, see [crate::telemetry].
+    pub blocks: BlockSpans,
+}
+
+#[cfg(test)]
+impl<S: BlockSource> ConnectionHandler<S> {
+    /// Creates a handler serving blocks of `block_size` of `source`, whose hash is
+    /// `expected_hash`, with defaults tests override the fields they exercise of: no session,
+    /// gzip and CRC-32, no read retries, and every block tried compressed.
+    pub(crate) fn for_test(source: S, expected_hash: [u8; 32], block_size: u32) -> Self {
+        Self {
+            source,
+            expected_hash,
+            block_size,
+            compression_enabled: None,
+            write_buffer: AlignedBuffer::zeroed(MAX_MESSAGE_SIZE),
+            compressed_buffer: Vec::new(),
+            compressor: BlockCompressor::default(),
+            checksum: ChecksumAlgorithm::Crc32,
+            read_retries: 0,
+            retry_hints: false,
+            unreadable_blocks: Default::default(),
+            timings: Default::default(),
+            entropy_threshold: 8.0,
+            protocol_version: CURRENT_PROTOCOL_VERSION,
+            session_id: None,
+            blocks: BlockSpans::default(),
+        }
+    }
 }
 
-impl ConnectionHandler {
+impl<S: BlockSource> ConnectionHandler<S> {
     /// Handles a request for a data block.
     ///
     /// Reads the requested block from the file, optionally compresses it,
-    /// calculates the checksum, and sends the data back to the requester.
+    /// calculates the checksum, and sends the data back to the requester. A block that can't be
+    /// read even after retrying is answered with a `BlockUnreadable` message instead.
     ///
     /// # Arguments
     ///
@@ -237,6 +2082,309 @@ impl ConnectionHandler {
         writer: &mut W,
         should_compress: bool,
     ) -> Result<(), SendFileError> {
+        self.answer_request(req, writer, should_compress, false)
+    }
+
+    /// Handles a request for a data block as [Self::handle_data_request] does, but answers with
+    /// a `ZeroBlock` message instead of the data if the block only holds zeros, see
+    /// [Capabilities::ZERO_BLOCKS].
+    pub fn handle_sparse_request<W: Write>(
+        &mut self,
+        req: &RequestV1,
+        writer: &mut W,
+        should_compress: bool,
+    ) -> Result<(), SendFileError> {
+        self.answer_request(req, writer, should_compress, true)
+    }
+
+    /// Handles the list of blocks of a range a resuming receiver already holds, see
+    /// [Capabilities::BLOCK_BITMAP]. Answers every other block of the range in order, as
+    /// [Self::handle_data_request] (or [Self::handle_sparse_request]) would.
+    ///
+    /// # Arguments
+    ///
+    /// * `have` - The blocks the receiver holds.
+    /// * `total_blocks` - Number of blocks of the file.
+    /// * `writer` - The writer to send the blocks to.
+    /// * `should_compress` - Whether blocks may be compressed.
+    /// * `sent` - Called with the sequence number of each block once it is sent, an error stops
+    ///   the answer.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once every missing block is sent, `Err` if the message is malformed, for another
+    /// file, or a block couldn't be sent.
+    pub fn handle_have_blocks<W: Write>(
+        &mut self,
+        have: &HaveBlocksV1,
+        total_blocks: u64,
+        writer: &mut W,
+        should_compress: bool,
+        mut sent: impl FnMut(u32) -> Result<(), SendFileError>,
+    ) -> Result<(), SendFileError> {
+        self.check_file_hash(&have.file_hash, "block list")?;
+        let missing = have
+            .missing()
+            .filter(|_| have.start_seq as u64 + have.count as u64 <= total_blocks)
+            .ok_or_else(|| {
+                SendFileError::InvalidRequest(format!(
+                    "Malformed list of the blocks {}..{} held by the receiver",
+                    have.start_seq,
+                    have.start_seq as u64 + have.count as u64
+                ))
+            })?;
+        info!(
+            "Receiver holds {} of the blocks {}..{}",
+            have.count - missing.iter().map(|range| range.len() as u32).sum::<u32>(),
+            have.start_seq,
+            have.start_seq + have.count
+        );
+
+        for seq in missing.into_iter().flatten() {
+            let request = RequestV1 {
+                file_hash: have.file_hash,
+                seq,
+            };
+            self.answer_request(&request, writer, should_compress, have.sparse)?;
+            sent(seq)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a request for a run of consecutive blocks, see [Capabilities::RANGE_REQUEST].
+    /// Answers every block of the run in order, as [Self::handle_data_request] (or
+    /// [Self::handle_sparse_request]) would answer each of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The blocks requested.
+    /// * `total_blocks` - Number of blocks of the file.
+    /// * `writer` - The writer to send the blocks to.
+    /// * `should_compress` - Whether blocks may be compressed.
+    /// * `sent` - Called with the sequence number of each block once it is sent, an error stops
+    ///   the answer.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once every block is sent, `Err` if the request is malformed, for another file,
+    /// or a block couldn't be sent.
+    pub fn handle_request_range<W: Write>(
+        &mut self,
+        range: &RequestRangeV1,
+        total_blocks: u64,
+        writer: &mut W,
+        should_compress: bool,
+        mut sent: impl FnMut(u32) -> Result<(), SendFileError>,
+    ) -> Result<(), SendFileError> {
+        self.check_file_hash(&range.file_hash, "range request")?;
+        if range.start_seq >= range.end_seq
+            || range.end_seq - range.start_seq > MAX_REQUEST_RANGE_BLOCKS
+            || range.end_seq as u64 > total_blocks
+        {
+            return Err(SendFileError::InvalidRequest(format!(
+                "Malformed request for the blocks {}..{}",
+                range.start_seq, range.end_seq
+            )));
+        }
+        debug!("Sending the blocks {}..{}", range.start_seq, range.end_seq);
+        for seq in range.start_seq..range.end_seq {
+            let request = RequestV1 {
+                file_hash: range.file_hash,
+                seq,
+            };
+            self.answer_request(&request, writer, should_compress, range.sparse)?;
+            sent(seq)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a request for every block of a range, see [Capabilities::PUSH_STREAM]. Streams
+    /// the first two windows of the range, as [Self::handle_data_request] (or
+    /// [Self::handle_sparse_request]) would answer each block, the others follow the receiver's
+    /// acknowledgements, see [Self::handle_push_ack].
+    ///
+    /// # Arguments
+    ///
+    /// * `push` - The range to stream.
+    /// * `total_blocks` - Number of blocks of the file.
+    /// * `writer` - The writer to send the blocks to.
+    /// * `should_compress` - Whether blocks may be compressed.
+    /// * `sent` - Called with the sequence number of each block once it is sent, an error stops
+    ///   the answer.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once both windows are sent, `Err` if the request is malformed, for another file,
+    /// or a block couldn't be sent.
+    pub fn handle_push_range<W: Write>(
+        &mut self,
+        push: &PushRangeV1,
+        total_blocks: u64,
+        writer: &mut W,
+        should_compress: bool,
+        mut sent: impl FnMut(u32) -> Result<(), SendFileError>,
+    ) -> Result<(), SendFileError> {
+        self.check_file_hash(&push.file_hash, "streaming request")?;
+        if push.window == 0
+            || push.window > MAX_PUSH_WINDOW
+            || push.start_seq as u64 + push.count as u64 > total_blocks
+        {
+            return Err(SendFileError::InvalidRequest(format!(
+                "Malformed request to stream the blocks {}..{} in windows of {}",
+                push.start_seq,
+                push.start_seq as u64 + push.count as u64,
+                push.window
+            )));
+        }
+        info!(
+            "Streaming the blocks {}..{} in {} windows of {}",
+            push.start_seq,
+            push.start_seq + push.count,
+            push.windows(),
+            push.window
+        );
+        for seq in push.window_blocks(0).chain(push.window_blocks(1)) {
+            self.push_block(push, seq, writer, should_compress)?;
+            sent(seq)?;
+        }
+        Ok(())
+    }
+
+    /// Handles the receiver's acknowledgement of a window of the range `push` is streaming, see
+    /// [Self::handle_push_range]. Sends the blocks it names again, then the window two past the
+    /// acknowledged one.
+    ///
+    /// # Arguments
+    ///
+    /// * `ack` - The acknowledgement.
+    /// * `push` - The range being streamed.
+    /// * `writer` - The writer to send the blocks to.
+    /// * `should_compress` - Whether blocks may be compressed.
+    /// * `sent` - Called with the sequence number of each block once it is sent, an error stops
+    ///   the answer.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once the blocks are sent, `Err` if the acknowledgement names blocks out of the
+    /// range or too many of them, is for another file, or a block couldn't be sent.
+    pub fn handle_push_ack<W: Write>(
+        &mut self,
+        ack: &PushAckV1,
+        push: &PushRangeV1,
+        writer: &mut W,
+        should_compress: bool,
+        mut sent: impl FnMut(u32) -> Result<(), SendFileError>,
+    ) -> Result<(), SendFileError> {
+        self.check_file_hash(&ack.file_hash, "acknowledgement")?;
+        let range = push.start_seq..push.start_seq + push.count;
+        if ack.missing.len() > push.count as usize
+            || !ack.missing.iter().all(|seq| range.contains(seq))
+        {
+            return Err(SendFileError::InvalidRequest(format!(
+                "Receiver asked for {} blocks of the window {} again, not all of {}..{}",
+                ack.missing.len(),
+                ack.window,
+                range.start,
+                range.end
+            )));
+        }
+        if !ack.missing.is_empty() {
+            warn!(
+                "Receiver asked for {} blocks again after window {}",
+                ack.missing.len(),
+                ack.window
+            );
+        }
+        let next = push.window_blocks(ack.window.saturating_add(2));
+        for seq in ack.missing.iter().copied().chain(next) {
+            self.push_block(push, seq, writer, should_compress)?;
+            sent(seq)?;
+        }
+        Ok(())
+    }
+
+    /// Sends block `seq` of the range `push` is streaming.
+    fn push_block<W: Write>(
+        &mut self,
+        push: &PushRangeV1,
+        seq: u32,
+        writer: &mut W,
+        should_compress: bool,
+    ) -> Result<(), SendFileError> {
+        let request = RequestV1 {
+            file_hash: push.file_hash,
+            seq,
+        };
+        self.answer_request(&request, writer, should_compress, push.sparse)
+    }
+
+    /// Checks that the receiver's `what` names the file being transferred, `file_hash`.
+    fn check_file_hash(&self, file_hash: &[u8; 32], what: &str) -> Result<(), SendFileError> {
+        if *file_hash != self.expected_hash {
+            warn!("Received {} for wrong file hash: {:?}", what, file_hash);
+            return Err(SendFileError::BlockHashMismatch {
+                expected: self.expected_hash,
+                received: file_hash.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Handles a request for a block of a file of a directory sent as one transfer, see
+    /// [crate::stream::bundle]. Answers as [Self::handle_data_request] does, with a `FileData`
+    /// message.
+    pub fn handle_file_request<W: Write>(
+        &mut self,
+        req: &FileRequestV1,
+        writer: &mut W,
+        should_compress: bool,
+    ) -> Result<(), SendFileError> {
+        let seq = self
+            .source
+            .bundle()
+            .and_then(|bundle| bundle.bundle_seq(req.file_index, req.seq));
+        let Some(seq) = seq else {
+            return Err(SendFileError::InvalidRequest(format!(
+                "No block {} in file {} of the directory",
+                req.seq, req.file_index
+            )));
+        };
+        let request = RequestV1 {
+            file_hash: req.file_hash,
+            seq,
+        };
+        self.answer_request(&request, writer, should_compress, false)
+    }
+
+    /// Reads block `req.seq` and answers with it, or with a `ZeroBlock` if it only holds zeros
+    /// and `zero_blocks` is set. Blocks of a directory are answered with a `FileData` message.
+    fn answer_request<W: Write>(
+        &mut self,
+        req: &RequestV1,
+        writer: &mut W,
+        should_compress: bool,
+        zero_blocks: bool,
+    ) -> Result<(), SendFileError> {
+        self.blocks.begin();
+        let len = self
+            .write_answer(req, writer, should_compress, zero_blocks)
+            .context(|| ErrorContext::new("sending").with_seq(req.seq))?;
+        self.blocks.done(len);
+        Ok(())
+    }
+
+    /// Answers block `req.seq` as [Self::answer_request] does.
+    ///
+    /// # Returns
+    ///
+    /// The length of the block, 0 if it could not be read.
+    fn write_answer<W: Write>(
+        &mut self,
+        req: &RequestV1,
+        writer: &mut W,
+        should_compress: bool,
+        zero_blocks: bool,
+    ) -> Result<u64, SendFileError> {
         let RequestV1 { seq, file_hash } = req;
 
         if file_hash != &self.expected_hash {
@@ -248,30 +2396,46 @@ impl ConnectionHandler {
         }
         info!("Received request for seq {}", seq);
 
-        match read_file_block(&mut self.file, *seq, self.block_size) {
+        let read = self
+            .timings
+            .time(Stage::DiskRead, || self.read_block_with_retries(*seq));
+        match read {
+            Ok(data) if zero_blocks && !data.is_empty() && data.iter().all(|&byte| byte == 0) => {
+                debug!("Block {} only holds zeros", seq);
+                let msg = SenderMessageV1::ZeroBlock(ZeroBlockV1 { seq: *seq });
+                let payload = msg.to_bytes(&mut self.write_buffer)?;
+                writer.write_all(&attach_headers_for(
+                    self.protocol_version,
+                    self.session_id.as_ref(),
+                    payload,
+                ))?;
+                writer.flush()?;
+                Ok(data.len() as u64)
+            }
             Ok(data) => {
+                let len = data.len() as u64;
                 let compressed_flag: bool;
                 let final_data: &[u8];
 
                 // Determine if we should attempt compression
                 let attempt_compression = should_compress
+                    && self.compressor.codec() != CompressionCodec::None
                     && match self.compression_enabled {
                         Some(true) => true,
                         Some(false) => false,
                         None => true, // Probe on first request
                     };
+                // Encrypted or already compressed blocks wouldn't shrink
+                let attempt_compression = attempt_compression && !self.looks_incompressible(&data);
 
                 if attempt_compression {
-                    let mut compression_success = false;
-                    self.compressed_buffer.clear();
-                    {
-                        let mut encoder =
-                            GzEncoder::new(&mut self.compressed_buffer, Compression::default());
-
-                        if encoder.write_all(&data).is_ok() && encoder.finish().is_ok() {
-                            compression_success = true;
-                        }
-                    }
+                    let started_at = Instant::now();
+                    let compression_success = self
+                        .compressor
+                        .compress(&data, &mut self.compressed_buffer)
+                        .is_ok();
+                    self.timings
+                        .record(Stage::Compression, started_at.elapsed());
 
                     if compression_success {
                         let is_smaller = self.compressed_buffer.len() < data.len();
@@ -302,19 +2466,34 @@ impl ConnectionHandler {
                     compressed_flag = false;
                 }
 
-                let checksum_val = checksum(CrcAlgorithm::Crc32IsoHdlc, final_data);
+                let checksum_val = block_checksum_with(self.checksum, final_data);
 
-                let msg = SenderMessageV1::Data(DataV1 {
+                let data = DataV1 {
                     seq: *seq,
-                    checksum: checksum_val as u32,
+                    checksum: checksum_val,
                     file_hash: &self.expected_hash,
                     compressed: compressed_flag,
                     data: final_data,
-                });
+                };
+                let msg = match self.source.bundle().and_then(|bundle| bundle.locate(*seq)) {
+                    Some((file_index, file_seq)) => SenderMessageV1::FileData(FileDataV1 {
+                        file_index,
+                        data: DataV1 {
+                            seq: file_seq,
+                            ..data
+                        },
+                    }),
+                    None => SenderMessageV1::Data(data),
+                };
 
                 match msg.to_bytes(&mut self.write_buffer) {
                     Ok(payload) => {
-                        let packet = crate::transport::attach_headers(payload);
+                        let packet = attach_headers_for(
+                            self.protocol_version,
+                            self.session_id.as_ref(),
+                            payload,
+                        );
+                        let started_at = Instant::now();
                         if let Err(e) = writer.write_all(&packet) {
                             error!("Failed to write data to stream: {}", e);
                             return Err(SendFileError::ConnectionFailed(format!(
@@ -329,7 +2508,8 @@ impl ConnectionHandler {
                                 e
                             )));
                         }
-                        Ok(())
+                        self.timings.record(Stage::Network, started_at.elapsed());
+                        Ok(len)
                     }
                     Err(e) => {
                         error!("Serialization error: {}", e);
@@ -337,22 +2517,158 @@ impl ConnectionHandler {
                     }
                 }
             }
+            Err(e) if self.retry_hints && is_transient(&e) => {
+                let retry_after = self.read_retry_after();
+                info!(
+                    "Asking the receiver to request block {} again in {:?}",
+                    seq, retry_after
+                );
+                let msg = SenderMessageV1::RetryAfter(retry_hint(
+                    format!("Failed to read block {}: {}", seq, e),
+                    retry_after,
+                ));
+                let payload = msg.to_bytes(&mut self.write_buffer)?;
+                writer.write_all(&attach_headers_for(
+                    self.protocol_version,
+                    self.session_id.as_ref(),
+                    payload,
+                ))?;
+                writer.flush()?;
+                Ok(0)
+            }
             Err(e) => {
-                error!("Failed to read file block: {}", e);
-                let error_msg = SenderMessageV1::Error(SenderErrorV1 {
-                    code: 500,
-                    message: format!("Read error: {}", e),
+                // The receiver decides whether to skip the block or abort
+                let msg = SenderMessageV1::BlockUnreadable(BlockUnreadableV1 {
+                    seq: *seq,
+                    reason: e.to_string(),
                 });
-                // Best effort to send error
-                if let Ok(payload) = error_msg.to_bytes(&mut self.write_buffer) {
-                    let packet = crate::transport::attach_headers(payload);
-                    let _ = writer.write_all(&packet);
+                let payload = msg.to_bytes(&mut self.write_buffer)?;
+                writer.write_all(&attach_headers_for(
+                    self.protocol_version,
+                    self.session_id.as_ref(),
+                    payload,
+                ))?;
+                writer.flush()?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Handles a request for a block to be sent as UDP datagrams, see [crate::stream::udp].
+    ///
+    /// Answers the request as [Self::handle_data_request] would, but encodes the answer into
+    /// datagrams, announces them on `writer` and sends them with `udp`, calling `throttle` with
+    /// the length of each datagram before it is sent. Without `udp`, no datagrams are announced
+    /// and the receiver requests the block over TCP instead.
+    pub fn handle_udp_request<W: Write>(
+        &mut self,
+        req: &UdpRequestV1,
+        udp: Option<&UdpSender>,
+        writer: &mut W,
+        should_compress: bool,
+        throttle: impl FnMut(u64),
+    ) -> Result<(), SendFileError> {
+        let Some(udp) = udp else {
+            let msg = SenderMessageV1::UdpBlock(UdpBlockV1 {
+                seq: req.seq,
+                length: 0,
+                datagrams: 0,
+            });
+            let payload = msg.to_bytes(&mut self.write_buffer)?;
+            writer.write_all(&attach_headers_for(
+                self.protocol_version,
+                self.session_id.as_ref(),
+                payload,
+            ))?;
+            writer.flush()?;
+            return Ok(());
+        };
+
+        let mut frame = Vec::new();
+        let request = RequestV1 {
+            file_hash: req.file_hash,
+            seq: req.seq,
+        };
+        self.handle_data_request(&request, &mut frame, should_compress)?;
+        let block = udp.encode(&frame, req);
+
+        let msg = SenderMessageV1::UdpBlock(block.announcement());
+        let payload = msg.to_bytes(&mut self.write_buffer)?;
+        writer.write_all(&attach_headers_for(
+            self.protocol_version,
+            self.session_id.as_ref(),
+            payload,
+        ))?;
+        writer.flush()?;
+        let started_at = Instant::now();
+        udp.send(&block, req.port, throttle)?;
+        self.timings.record(Stage::Network, started_at.elapsed());
+        Ok(())
+    }
+
+    /// Returns whether the sampled entropy of `data` is above the
+    /// [entropy threshold](Self::entropy_threshold), making compressing it a waste of CPU.
+    fn looks_incompressible(&self, data: &[u8]) -> bool {
+        let entropy = sampled_entropy(data);
+        if entropy > self.entropy_threshold {
+            debug!(
+                "Not compressing block, entropy {:.2} bits per byte is above {:.2}",
+                entropy, self.entropy_threshold
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Reads block `seq`, retrying failed reads up to [read_retries](Self::read_retries) times
+    /// with a growing delay. Blocks that already failed on any connection are not read again.
+    fn read_block_with_retries(&self, seq: u32) -> std::io::Result<Vec<u8>> {
+        if let Some(reason) = self.lock_unreadable().get(&seq) {
+            return Err(std::io::Error::other(reason.clone()));
+        }
+
+        let mut delay = Duration::from_millis(INITIAL_READ_RETRY_DELAY_MS);
+        let mut attempt = 0;
+        loop {
+            match self.source.read_block(seq, self.block_size) {
+                Ok(data) => return Ok(data),
+                Err(e) if attempt < self.read_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Failed to read block {} (attempt {}/{}): {}",
+                        seq, attempt, self.read_retries, e
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) if self.retry_hints && is_transient(&e) => {
+                    // The receiver asks again later, by when the read may pass
+                    warn!("Failed to read block {} for now: {}", seq, e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    error!("Giving up on block {}: {}", seq, e);
+                    self.lock_unreadable().insert(seq, e.to_string());
+                    return Err(e);
                 }
-                Err(SendFileError::Io(e))
             }
         }
     }
 
+    /// Returns the time after which a block whose reads failed for now is worth reading again:
+    /// the delay the next retry of [Self::read_block_with_retries] would have waited.
+    fn read_retry_after(&self) -> Duration {
+        Duration::from_millis(INITIAL_READ_RETRY_DELAY_MS)
+            .saturating_mul(2u32.saturating_pow(self.read_retries))
+            .min(MAX_RETRY_AFTER)
+    }
+
+    fn lock_unreadable(&self) -> std::sync::MutexGuard<'_, BTreeMap<u32, String>> {
+        self.unreadable_blocks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
     /// Handles a progress update message.
     ///
     /// Logs the progress of the file transfer.
@@ -380,7 +2696,53 @@ impl ConnectionHandler {
                 received: file_hash.to_vec(),
             });
         }
-        info!("Progress: {} bytes", bytes_received);
+        info!("Progress: {}", Size(*bytes_received));
+        Ok(())
+    }
+
+    /// Switches the blocks of this connection to the compression codec and checksum the receiver
+    /// picked, see [AlgorithmsV1].
+    ///
+    /// # Returns
+    ///
+    /// [SendFileError::InvalidRequest] if this build doesn't support one of them, which it never
+    /// advertised.
+    pub fn handle_algorithms(&mut self, algorithms: &AlgorithmsV1) -> Result<(), SendFileError> {
+        let AlgorithmsV1 {
+            compression,
+            checksum,
+        } = *algorithms;
+        let local = Capabilities::local();
+        if !local.contains(compression.capability()) || !local.contains(checksum.capability()) {
+            return Err(SendFileError::InvalidRequest(format!(
+                "Receiver picked {} compression and {} checksums, which were not offered",
+                compression, checksum
+            )));
+        }
+        debug!(
+            "Receiver picked {} compression and {} checksums",
+            compression, checksum
+        );
+        self.compressor = BlockCompressor::new(compression);
+        self.checksum = checksum;
+        Ok(())
+    }
+
+    /// Answers a heartbeat of the receiver with a `Pong`, see [crate::stream::heartbeat].
+    pub fn handle_ping<W: Write>(
+        &mut self,
+        ping: &PingV1,
+        writer: &mut W,
+    ) -> Result<(), SendFileError> {
+        debug!("Heartbeat {} from the receiver", ping.seq);
+        let msg = SenderMessageV1::Pong(PongV1 { seq: ping.seq });
+        let payload = msg.to_bytes(&mut self.write_buffer)?;
+        writer.write_all(&attach_headers_for(
+            self.protocol_version,
+            self.session_id.as_ref(),
+            payload,
+        ))?;
+        writer.flush()?;
         Ok(())
     }
 
@@ -415,6 +2777,41 @@ impl ConnectionHandler {
         Ok(())
     }
 
+    /// Handles the goodbye of a receiver done with the connection, see [Capabilities::GOODBYE].
+    ///
+    /// # Arguments
+    ///
+    /// * `goodbye` - The goodbye message.
+    /// * `writer` - The writer to answer with a goodbye of this side.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once answered, `Err` if the goodbye is for another file or the answer failed.
+    pub fn handle_goodbye<W: Write>(
+        &mut self,
+        goodbye: &GoodbyeV1,
+        writer: &mut W,
+    ) -> Result<(), SendFileError> {
+        if goodbye.file_hash != self.expected_hash {
+            return Err(SendFileError::BlockHashMismatch {
+                expected: self.expected_hash,
+                received: goodbye.file_hash.to_vec(),
+            });
+        }
+        debug!("Receiver said goodbye, closing connection");
+        let msg = SenderMessageV1::Goodbye(GoodbyeV1 {
+            file_hash: self.expected_hash,
+        });
+        let payload = msg.to_bytes(&mut self.write_buffer)?;
+        writer.write_all(&attach_headers_for(
+            self.protocol_version,
+            self.session_id.as_ref(),
+            payload,
+        ))?;
+        writer.flush()?;
+        Ok(())
+    }
+
     /// Handles an error message from the receiver.
     ///
     /// Logs the error code and message.
@@ -463,9 +2860,12 @@ impl ConnectionHandler {
         }
         info!("Received verify request for seq {}", seq);
 
-        match read_file_block(&mut self.file, *seq, self.block_size) {
+        let read = self
+            .timings
+            .time(Stage::DiskRead, || self.read_block_with_retries(*seq));
+        match read {
             Ok(data) => {
-                let computed_checksum = checksum(CrcAlgorithm::Crc32IsoHdlc, &data) as u32;
+                let computed_checksum = block_checksum_with(self.checksum, &data);
                 let valid = computed_checksum == *receiver_checksum;
 
                 info!(
@@ -481,7 +2881,11 @@ impl ConnectionHandler {
 
                 match msg.to_bytes(&mut self.write_buffer) {
                     Ok(payload) => {
-                        let packet = crate::transport::attach_headers(payload);
+                        let packet = attach_headers_for(
+                            self.protocol_version,
+                            self.session_id.as_ref(),
+                            payload,
+                        );
                         if let Err(e) = writer.write_all(&packet) {
                             error!("Failed to write verify response to stream: {}", e);
                             return Err(SendFileError::ConnectionFailed(format!(
@@ -505,18 +2909,90 @@ impl ConnectionHandler {
                 }
             }
             Err(e) => {
-                error!("Failed to read file block for verify: {}", e);
+                // The receiver then requests the block and learns it is unreadable
+                warn!("Failed to read file block for verify: {}", e);
                 let msg = SenderMessageV1::VerifyResponse(VerifyResponseV1 {
                     file_hash: self.expected_hash,
                     seq: *seq,
                     valid: false,
                 });
-                if let Ok(payload) = msg.to_bytes(&mut self.write_buffer) {
-                    let packet = crate::transport::attach_headers(payload);
-                    let _ = writer.write_all(&packet);
-                }
-                Err(SendFileError::Io(e))
+                let payload = msg.to_bytes(&mut self.write_buffer)?;
+                writer.write_all(&attach_headers_for(
+                    self.protocol_version,
+                    self.session_id.as_ref(),
+                    payload,
+                ))?;
+                writer.flush()?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles a request for the hashes of a run of blocks.
+    ///
+    /// Reads each requested block, hashes it with BLAKE3 and sends the hashes back. The run stops
+    /// early at the end of the file and is capped at [MAX_BLOCK_HASHES_PER_MESSAGE] blocks.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The block hashes request message.
+    /// * `writer` - The writer to send the response to.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if successful, `Err` if the request was invalid (wrong file hash) or an error occurred.
+    pub fn handle_block_hashes_request<W: Write>(
+        &mut self,
+        req: &BlockHashesRequestV1,
+        writer: &mut W,
+    ) -> Result<(), SendFileError> {
+        let BlockHashesRequestV1 {
+            file_hash,
+            start_seq,
+            count,
+        } = req;
+
+        if file_hash != &self.expected_hash {
+            warn!(
+                "Received block hashes request for wrong file hash: {:?}",
+                file_hash
+            );
+            return Err(SendFileError::BlockHashMismatch {
+                expected: self.expected_hash,
+                received: file_hash.to_vec(),
+            });
+        }
+        info!(
+            "Received block hashes request for seq {}..{}",
+            start_seq,
+            start_seq.saturating_add(*count)
+        );
+
+        let count = (*count).min(MAX_BLOCK_HASHES_PER_MESSAGE);
+        let mut hashes = Vec::with_capacity(count as usize);
+        for seq in *start_seq..start_seq.saturating_add(count) {
+            let data = self
+                .source
+                .read_block(seq, self.block_size)
+                .context(|| ErrorContext::new("hashing").with_seq(seq))?;
+            if data.is_empty() {
+                break;
             }
+            hashes.push(*blake3::hash(&data).as_bytes());
         }
+
+        let msg = SenderMessageV1::BlockHashes(BlockHashesV1 {
+            file_hash: self.expected_hash,
+            start_seq: *start_seq,
+            hashes,
+        });
+        let payload = msg.to_bytes(&mut self.write_buffer)?;
+        writer.write_all(&attach_headers_for(
+            self.protocol_version,
+            self.session_id.as_ref(),
+            payload,
+        ))?;
+        writer.flush()?;
+        Ok(())
     }
 }
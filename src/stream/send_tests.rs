@@ -1,9 +1,21 @@
-use crate::stream::send::ConnectionHandler;
-use crate::transport::{ProgressV1, RequestV1, SenderMessageV1, TransferCompleteV1};
+use crate::authentication::{conn_hello, new_session};
+use crate::capabilities::{ChecksumAlgorithm, CompressionCodec};
+use crate::stream::checksum::block_checksum_with;
+use crate::stream::inflate::BlockInflater;
+use crate::noise::NoiseStream;
+use crate::stream::send::{poll_handshake_message, ConnectionHandler, SharedTransfer};
+use crate::stream::source::BlockSource;
+use crate::tls::MaybeTlsStream;
+use crate::transport::{
+    attach_headers_for, AlgorithmsV1, BlockHashesRequestV1, FrameHeader, GoodbyeV1,
+    OfferResponseV1, ProgressV1, ReceiptV1, ReceiverMessageV1, RequestV1, SenderMessageV1,
+    TransferCompleteV1, CURRENT_PROTOCOL_VERSION, FRAME_HEADER_SIZE,
+};
 use blake3::Hasher;
 use std::fs::File;
 use std::io::{Cursor, Write};
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 
 fn create_temp_file(content: &[u8]) -> (File, PathBuf) {
     let mut dir = std::env::temp_dir();
@@ -28,19 +40,13 @@ fn calculate_hash(data: &[u8]) -> [u8; 32] {
     hasher.finalize().into()
 }
 
-// Helper to strip headers and deserialize
+// Helper to strip the frame header and deserialize
 fn parse_message(bytes: &[u8]) -> SenderMessageV1<'_> {
-    let delimiter = b"\r\n\r\n";
-    if let Some(start) = bytes
-        .windows(delimiter.len())
-        .position(|window| window == delimiter)
-    {
-        let payload = &bytes[start + delimiter.len()..];
-        SenderMessageV1::from_bytes(payload).expect("Failed to deserialize payload")
-    } else {
-        // Fallback or error
-        SenderMessageV1::from_bytes(bytes).expect("Failed to deserialize raw bytes")
-    }
+    let header = FrameHeader::decode(bytes[..FRAME_HEADER_SIZE].try_into().unwrap())
+        .expect("Invalid frame header");
+    let payload = &bytes[FRAME_HEADER_SIZE..];
+    assert_eq!(header.payload_length as usize, payload.len());
+    SenderMessageV1::from_bytes(payload).expect("Failed to deserialize payload")
 }
 
 #[test]
@@ -49,14 +55,7 @@ fn test_handle_data_request_compression_probe_positive() {
     let hash = calculate_hash(&data);
     let (file, path) = create_temp_file(&data);
 
-    let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
-        compression_enabled: None,
-        write_buffer: vec![0u8; 2048],
-        compressed_buffer: vec![0u8; 2048],
-    };
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
 
     let req = RequestV1 {
         file_hash: hash,
@@ -90,6 +89,64 @@ fn test_handle_data_request_compression_probe_positive() {
     let _ = std::fs::remove_file(path);
 }
 
+#[test]
+fn test_handle_data_request_with_negotiated_algorithms() {
+    let data = b"negotiated codec, ".repeat(64);
+    let hash = calculate_hash(&data);
+    let (file, path) = create_temp_file(&data);
+
+    let mut handler = ConnectionHandler::for_test(file, hash, data.len() as u32);
+    let algorithms = AlgorithmsV1 {
+        compression: CompressionCodec::Zstd,
+        checksum: ChecksumAlgorithm::Crc32c,
+    };
+    handler.handle_algorithms(&algorithms).unwrap();
+
+    let req = RequestV1 {
+        file_hash: hash,
+        seq: 0,
+    };
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, true)
+        .unwrap();
+
+    let written = cursor.into_inner();
+    let SenderMessageV1::Data(d) = parse_message(&written) else {
+        panic!("Expected Data message");
+    };
+    assert!(d.compressed);
+    assert_eq!(
+        d.checksum,
+        block_checksum_with(ChecksumAlgorithm::Crc32c, d.data)
+    );
+    let mut output = vec![0u8; data.len()];
+    let len = BlockInflater::with_codec(CompressionCodec::Zstd)
+        .inflate(d.data, &mut output)
+        .unwrap();
+    assert_eq!(&output[..len], &data[..]);
+
+    // Without compression, blocks are sent as they are
+    handler
+        .handle_algorithms(&AlgorithmsV1 {
+            compression: CompressionCodec::None,
+            ..algorithms
+        })
+        .unwrap();
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, true)
+        .unwrap();
+    let written = cursor.into_inner();
+    let SenderMessageV1::Data(d) = parse_message(&written) else {
+        panic!("Expected Data message");
+    };
+    assert!(!d.compressed);
+    assert_eq!(d.data, &data[..]);
+
+    let _ = std::fs::remove_file(path);
+}
+
 #[test]
 fn test_handle_data_request_compression_probe_negative() {
     // Generate random data (incompressible)
@@ -104,14 +161,7 @@ fn test_handle_data_request_compression_probe_negative() {
     let hash = calculate_hash(&data);
     let (file, path) = create_temp_file(&data);
 
-    let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
-        compression_enabled: None,
-        write_buffer: vec![0u8; 2048],
-        compressed_buffer: vec![0u8; 2048],
-    };
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
 
     let req = RequestV1 {
         file_hash: hash,
@@ -142,6 +192,46 @@ fn test_handle_data_request_compression_probe_negative() {
     let _ = std::fs::remove_file(path);
 }
 
+#[test]
+fn test_handle_data_request_skips_high_entropy_blocks() {
+    let mut data = Vec::with_capacity(1024);
+    let mut state: u64 = 0xCAFEBABE;
+    for _ in 0..1024 {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+        data.push((state >> 33) as u8);
+    }
+
+    let hash = calculate_hash(&data);
+    let (file, path) = create_temp_file(&data);
+
+    let mut handler = ConnectionHandler {
+        entropy_threshold: 7.0,
+        ..ConnectionHandler::for_test(file, hash, 1024)
+    };
+
+    let req = RequestV1 {
+        file_hash: hash,
+        seq: 0,
+    };
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, true)
+        .unwrap();
+
+    // Not even tried, so the next block still probes compression
+    assert_eq!(handler.compression_enabled, None);
+    let written = cursor.into_inner();
+    match parse_message(&written) {
+        SenderMessageV1::Data(d) => {
+            assert!(!d.compressed, "Data should not be compressed");
+            assert_eq!(d.data, data.as_slice());
+        }
+        _ => panic!("Expected Data message"),
+    }
+
+    let _ = std::fs::remove_file(path);
+}
+
 #[test]
 fn test_handle_data_request_honors_compression_disabled() {
     let data = vec![0u8; 1024]; // Compressible
@@ -149,12 +239,8 @@ fn test_handle_data_request_honors_compression_disabled() {
     let (file, path) = create_temp_file(&data);
 
     let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
         compression_enabled: Some(false), // Explicitly disabled
-        write_buffer: vec![0u8; 2048],
-        compressed_buffer: vec![0u8; 2048],
+        ..ConnectionHandler::for_test(file, hash, 1024)
     };
 
     let req = RequestV1 {
@@ -163,7 +249,7 @@ fn test_handle_data_request_honors_compression_disabled() {
     };
     let mut cursor = Cursor::new(Vec::new());
 
-    let _ = handler
+    handler
         .handle_data_request(&req, &mut cursor, true)
         .expect("handle_data_request failed");
 
@@ -187,14 +273,7 @@ fn test_handle_data_request_mismatched_hash() {
     let hash = calculate_hash(data);
     let (file, path) = create_temp_file(data);
 
-    let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
-        compression_enabled: None,
-        write_buffer: vec![0u8; 2048],
-        compressed_buffer: vec![0u8; 2048],
-    };
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
 
     let wrong_hash = [0u8; 32];
     let req = RequestV1 {
@@ -217,14 +296,7 @@ fn test_handle_data_request_eof_handling() {
     let hash = calculate_hash(&data);
     let (file, path) = create_temp_file(&data);
 
-    let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
-        compression_enabled: None,
-        write_buffer: vec![0u8; 2048],
-        compressed_buffer: vec![0u8; 2048],
-    };
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
 
     // Request seq 1 (offset 1024), which is beyond EOF (100 bytes)
     let req = RequestV1 {
@@ -253,20 +325,177 @@ fn test_handle_data_request_eof_handling() {
     let _ = std::fs::remove_file(path);
 }
 
+#[test]
+fn test_handle_sparse_request_names_zero_blocks() {
+    let mut data = vec![0u8; 2048];
+    data[1500] = 7;
+    let hash = calculate_hash(&data);
+    let (file, path) = create_temp_file(&data);
+
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
+
+    let mut answer = |seq| {
+        let req = RequestV1 {
+            file_hash: hash,
+            seq,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        handler
+            .handle_sparse_request(&req, &mut cursor, false)
+            .expect("handle_sparse_request failed");
+        cursor.into_inner()
+    };
+
+    let written = answer(0);
+    assert!(matches!(
+        parse_message(&written),
+        SenderMessageV1::ZeroBlock(zero) if zero.seq == 0
+    ));
+    let written = answer(1);
+    match parse_message(&written) {
+        SenderMessageV1::Data(d) => assert_eq!(d.data, &data[1024..]),
+        _ => panic!("Expected Data message"),
+    }
+
+    let _ = std::fs::remove_file(path);
+}
+
+/// Source whose reads fail until `failures` reads have been attempted.
+struct FlakySource {
+    failures: u32,
+    attempts: std::sync::atomic::AtomicU32,
+}
+
+impl BlockSource for FlakySource {
+    fn read_block(&self, _seq: u32, _block_size: u32) -> std::io::Result<Vec<u8>> {
+        let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+        if attempt < self.failures {
+            Err(std::io::Error::other("Input/output error"))
+        } else {
+            Ok(vec![1u8; 16])
+        }
+    }
+}
+
+#[test]
+fn test_handle_data_request_retries_and_reports_unreadable() {
+    let hash = [0x42; 32];
+    let source = FlakySource {
+        failures: 2,
+        attempts: Default::default(),
+    };
+    let mut handler = ConnectionHandler {
+        read_retries: 2,
+        ..ConnectionHandler::for_test(source, hash, 16)
+    };
+    let req = RequestV1 {
+        file_hash: hash,
+        seq: 0,
+    };
+
+    // Two failures are absorbed by the retries
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, false)
+        .unwrap();
+    assert!(matches!(
+        parse_message(&cursor.into_inner()),
+        SenderMessageV1::Data(_)
+    ));
+
+    // One more failure than retries marks the block unreadable, without failing the connection
+    handler.source = FlakySource {
+        failures: 3,
+        attempts: Default::default(),
+    };
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, false)
+        .unwrap();
+    match parse_message(&cursor.into_inner()) {
+        SenderMessageV1::BlockUnreadable(unreadable) => assert_eq!(unreadable.seq, 0),
+        message => panic!("Expected BlockUnreadable, got {:?}", message),
+    }
+    assert!(handler.unreadable_blocks.lock().unwrap().contains_key(&0));
+
+    // Known bad blocks are not read again
+    handler.source = FlakySource {
+        failures: 0,
+        attempts: Default::default(),
+    };
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, false)
+        .unwrap();
+    assert!(matches!(
+        parse_message(&cursor.into_inner()),
+        SenderMessageV1::BlockUnreadable(_)
+    ));
+    assert_eq!(handler.source.attempts.load(Ordering::SeqCst), 0);
+}
+
+/// Source whose reads time out a number of times before passing.
+struct BusySource {
+    failures: u32,
+    attempts: std::sync::atomic::AtomicU32,
+}
+
+impl BlockSource for BusySource {
+    fn read_block(&self, _seq: u32, _block_size: u32) -> std::io::Result<Vec<u8>> {
+        let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+        if attempt < self.failures {
+            Err(std::io::Error::from(std::io::ErrorKind::TimedOut))
+        } else {
+            Ok(vec![1u8; 16])
+        }
+    }
+}
+
+#[test]
+fn test_handle_data_request_asks_to_retry_transient_failures() {
+    let hash = [0x42; 32];
+    let source = BusySource {
+        failures: 2,
+        attempts: Default::default(),
+    };
+    let mut handler = ConnectionHandler {
+        read_retries: 1,
+        retry_hints: true,
+        ..ConnectionHandler::for_test(source, hash, 16)
+    };
+    let req = RequestV1 {
+        file_hash: hash,
+        seq: 0,
+    };
+
+    // The block isn't given up on, the receiver is told when to ask again
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, false)
+        .unwrap();
+    match parse_message(&cursor.into_inner()) {
+        SenderMessageV1::RetryAfter(hint) => assert_eq!(hint.retry_after_ms, 200),
+        message => panic!("Expected RetryAfter, got {:?}", message),
+    }
+    assert!(handler.unreadable_blocks.lock().unwrap().is_empty());
+
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_data_request(&req, &mut cursor, false)
+        .unwrap();
+    assert!(matches!(
+        parse_message(&cursor.into_inner()),
+        SenderMessageV1::Data(_)
+    ));
+}
+
 #[test]
 fn test_handle_progress_valid_hash() {
     let data = b"test";
     let hash = calculate_hash(data);
     let (file, path) = create_temp_file(data);
 
-    let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
-        compression_enabled: None,
-        write_buffer: vec![],
-        compressed_buffer: vec![],
-    };
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
 
     let prog = ProgressV1 {
         file_hash: hash,
@@ -283,14 +512,7 @@ fn test_handle_progress_invalid_hash() {
     let hash = calculate_hash(data);
     let (file, path) = create_temp_file(data);
 
-    let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
-        compression_enabled: None,
-        write_buffer: vec![],
-        compressed_buffer: vec![],
-    };
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
 
     let wrong_hash = [1u8; 32];
     let prog = ProgressV1 {
@@ -308,17 +530,139 @@ fn test_handle_transfer_complete_success() {
     let hash = calculate_hash(data);
     let (file, path) = create_temp_file(data);
 
-    let mut handler = ConnectionHandler {
-        file,
-        expected_hash: hash,
-        block_size: 1024,
-        compression_enabled: None,
-        write_buffer: vec![],
-        compressed_buffer: vec![],
-    };
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
 
     let complete = TransferCompleteV1 { file_hash: hash };
     assert!(handler.handle_transfer_complete(&complete).is_ok());
 
     let _ = std::fs::remove_file(path);
 }
+
+#[test]
+fn test_handle_goodbye_answers_with_a_goodbye() {
+    let hash = [0x42; 32];
+    let source = BusySource {
+        failures: 0,
+        attempts: Default::default(),
+    };
+    let mut handler = ConnectionHandler::for_test(source, hash, 16);
+
+    let mut cursor = Cursor::new(Vec::new());
+    handler
+        .handle_goodbye(&GoodbyeV1 { file_hash: hash }, &mut cursor)
+        .unwrap();
+    assert_eq!(
+        parse_message(&cursor.into_inner()),
+        SenderMessageV1::Goodbye(GoodbyeV1 { file_hash: hash })
+    );
+
+    // A goodbye for another file is not answered
+    let mut cursor = Cursor::new(Vec::new());
+    assert!(handler
+        .handle_goodbye(&GoodbyeV1 { file_hash: [0; 32] }, &mut cursor)
+        .is_err());
+    assert!(cursor.into_inner().is_empty());
+}
+
+#[test]
+fn test_handle_block_hashes_request() {
+    // Two full blocks and a partial one
+    let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+    let hash = calculate_hash(&data);
+    let (file, path) = create_temp_file(&data);
+
+    let mut handler = ConnectionHandler::for_test(file, hash, 1024);
+
+    let req = BlockHashesRequestV1 {
+        file_hash: hash,
+        start_seq: 1,
+        count: 10,
+    };
+    let mut output = Cursor::new(Vec::new());
+    handler
+        .handle_block_hashes_request(&req, &mut output)
+        .expect("Failed to handle block hashes request");
+
+    let bytes = output.into_inner();
+    match parse_message(&bytes) {
+        SenderMessageV1::BlockHashes(response) => {
+            assert_eq!(response.start_seq, 1);
+            assert_eq!(
+                response.hashes,
+                vec![
+                    *blake3::hash(&data[1024..2048]).as_bytes(),
+                    *blake3::hash(&data[2048..]).as_bytes(),
+                ]
+            );
+        }
+        _ => panic!("Expected BlockHashes message"),
+    }
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_shared_transfer_admits_connections_of_its_session() {
+    let session = new_session().unwrap();
+    let shared = SharedTransfer::new(session);
+    let request = ReceiverMessageV1::TransferComplete(TransferCompleteV1 { file_hash: [0; 32] });
+
+    // Until a hello is seen the receiver may not support them
+    assert!(!shared.admit(&request).unwrap());
+
+    let other = conn_hello(&new_session().unwrap());
+    assert!(shared.admit(&ReceiverMessageV1::ConnHello(other)).is_err());
+
+    let hello = conn_hello(&session);
+    assert!(shared.admit(&ReceiverMessageV1::ConnHello(hello)).unwrap());
+    assert!(shared.admit(&request).is_err());
+}
+
+#[test]
+fn test_polled_handshake_messages_keep_bytes_read_past_them() {
+    let session = new_session().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut receiver = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (sender, _) = listener.accept().unwrap();
+
+    // The receipt is written right behind the offer response, so both arrive in one read
+    let mut frames = Vec::new();
+    for message in [
+        ReceiverMessageV1::OfferResponse(OfferResponseV1 {
+            file_hash: [7; 32],
+            accepted: true,
+            reason: String::new(),
+        }),
+        ReceiverMessageV1::Receipt(ReceiptV1 {
+            file_hash: [7; 32],
+            bytes: 1024,
+            timestamp: 1_760_000_000,
+            receiver_key: [8; 32],
+            signature: vec![9; 64],
+        }),
+    ] {
+        let mut buffer = [0u8; 512];
+        let payload = message.to_bytes(&mut buffer).unwrap();
+        frames.extend_from_slice(&attach_headers_for(
+            CURRENT_PROTOCOL_VERSION,
+            Some(&session.session_id),
+            payload,
+        ));
+    }
+    receiver.write_all(&frames).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let mut stream = NoiseStream::new(MaybeTlsStream::Plain(sender));
+    let mut buffer = vec![0u8; 4096];
+    let mut pending = Vec::new();
+    let first = poll_handshake_message(&mut stream, &mut buffer, &mut pending, &session).unwrap();
+    assert!(matches!(
+        first,
+        Some((ReceiverMessageV1::OfferResponse(_), _))
+    ));
+    assert!(!pending.is_empty());
+
+    let second = poll_handshake_message(&mut stream, &mut buffer, &mut pending, &session).unwrap();
+    assert!(matches!(second, Some((ReceiverMessageV1::Receipt(_), _))));
+    assert!(pending.is_empty());
+}
@@ -0,0 +1,202 @@
+//! Descriptors of the sessions a receiver has in progress, so a receiver started again after it
+//! stopped or was restarted picks them up where they were left, see
+//! [ReceiveOptions::sessions](crate::stream::options::ReceiveOptions::sessions).
+//!
+//! A descriptor is recorded in the `sessions` [store](crate::state) once a single file offer is
+//! accepted, keyed by the hash of the file, and removed once the file is received. It records
+//! who sent the file and where it is written; the blocks already received stay in the resume
+//! state next to the partial file (see [crate::file::resume]). When the same sender offers the
+//! same file again, the receiver writes it to the recorded path and resumes from that state,
+//! even if it was restarted with another output directory.
+//!
+//! Descriptors whose partial file lost its resume state, or older than [MAX_SESSION_AGE], are
+//! dropped as they are looked up.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file::resume::resume_state_path,
+    history::to_hex,
+    state::{self, JsonFileStore, StateError, StateStore},
+};
+
+/// Name of the store of session descriptors.
+pub const SESSIONS_STORE_NAME: &str = "sessions";
+
+/// Age past which a session is not resumed anymore, and its descriptor is dropped.
+pub const MAX_SESSION_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A single file transfer a receiver accepted and has not completed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionDescriptor {
+    /// Time the offer was accepted, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// IP address of the sender.
+    pub peer: IpAddr,
+    /// Hex encoded public key of the sender, `None` if it did not authenticate.
+    pub sender_key: Option<String>,
+    /// Name of the file offered.
+    pub file_name: String,
+    /// Hex encoded BLAKE3 hash of the file.
+    pub file_hash: String,
+    /// Size of the file in bytes.
+    pub bytes: u64,
+    /// Absolute path the file is saved to.
+    pub output_path: PathBuf,
+    /// Absolute path the file is written to until it is complete, next to its resume state. It
+    /// differs from [Self::output_path] when received files are scanned.
+    pub write_path: PathBuf,
+}
+
+impl SessionDescriptor {
+    /// Returns whether the file of this session is offered again by the same sender: by its key
+    /// if both authenticated, by its IP address otherwise.
+    pub fn is_sent_by(&self, peer: IpAddr, sender_key: Option<&[u8; 32]>) -> bool {
+        match (&self.sender_key, sender_key) {
+            (Some(recorded), Some(key)) => *recorded == to_hex(key),
+            (None, None) => self.peer.to_canonical() == peer.to_canonical(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether the session can still be resumed `now`, in seconds since the Unix epoch.
+    fn is_live(&self, now: u64) -> bool {
+        now.saturating_sub(self.started_at) <= MAX_SESSION_AGE.as_secs()
+            && resume_state_path(&self.write_path).exists()
+    }
+}
+
+/// Returns the default location of the store of session descriptors, if a data directory is
+/// known.
+pub fn default_sessions_path() -> Option<PathBuf> {
+    state::default_store_path(SESSIONS_STORE_NAME)
+}
+
+/// Records `descriptor` in the store at `path`, creating it if needed. It replaces the
+/// descriptor of the same file, if any.
+pub fn record_session(path: &Path, descriptor: &SessionDescriptor) -> Result<(), StateError> {
+    record_session_in(&JsonFileStore::open(path)?, descriptor)
+}
+
+/// Records `descriptor` in `store`.
+pub fn record_session_in(
+    store: &dyn StateStore,
+    descriptor: &SessionDescriptor,
+) -> Result<(), StateError> {
+    store.put_as(&descriptor.file_hash, descriptor)
+}
+
+/// Looks up the session of the file hashing to `file_hash` in the store at `path`, `None` if
+/// there is none it can be resumed from.
+pub fn find_session(
+    path: &Path,
+    file_hash: &[u8; 32],
+) -> Result<Option<SessionDescriptor>, StateError> {
+    find_session_in(&JsonFileStore::open(path)?, file_hash, now())
+}
+
+/// Looks up the session of the file hashing to `file_hash` in `store` at `now`, in seconds
+/// since the Unix epoch, dropping its descriptor if it can't be resumed anymore.
+pub fn find_session_in(
+    store: &dyn StateStore,
+    file_hash: &[u8; 32],
+    now: u64,
+) -> Result<Option<SessionDescriptor>, StateError> {
+    let key = to_hex(file_hash);
+    let Some(descriptor) = store.get_as::<SessionDescriptor>(&key)? else {
+        return Ok(None);
+    };
+    if !descriptor.is_live(now) {
+        store.remove(&key)?;
+        return Ok(None);
+    }
+    Ok(Some(descriptor))
+}
+
+/// Removes the session of the file hashing to `file_hash` from the store at `path`, returning
+/// whether there was one.
+pub fn forget_session(path: &Path, file_hash: &[u8; 32]) -> Result<bool, StateError> {
+    JsonFileStore::open(path)?.remove(&to_hex(file_hash))
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::state::MemoryStore;
+
+    fn descriptor(dir: &Path, sender_key: Option<&[u8; 32]>) -> SessionDescriptor {
+        SessionDescriptor {
+            started_at: 1_700_000_000,
+            peer: "192.0.2.7".parse().unwrap(),
+            sender_key: sender_key.map(|key| to_hex(key)),
+            file_name: String::from("image.iso"),
+            file_hash: to_hex(&[0xAB; 32]),
+            bytes: 1 << 30,
+            output_path: dir.join("image.iso"),
+            write_path: dir.join("image.iso"),
+        }
+    }
+
+    #[test]
+    fn test_sessions_are_matched_to_their_sender() {
+        let dir = Path::new("/nonexistent");
+        let anonymous = descriptor(dir, None);
+        assert!(anonymous.is_sent_by("192.0.2.7".parse().unwrap(), None));
+        assert!(anonymous.is_sent_by("::ffff:192.0.2.7".parse().unwrap(), None));
+        assert!(!anonymous.is_sent_by("192.0.2.8".parse().unwrap(), None));
+        assert!(!anonymous.is_sent_by("192.0.2.7".parse().unwrap(), Some(&[1; 32])));
+
+        // An authenticated sender may come back from another address, but not as another key
+        let authenticated = descriptor(dir, Some(&[1; 32]));
+        assert!(authenticated.is_sent_by("192.0.2.8".parse().unwrap(), Some(&[1; 32])));
+        assert!(!authenticated.is_sent_by("192.0.2.7".parse().unwrap(), Some(&[2; 32])));
+        assert!(!authenticated.is_sent_by("192.0.2.7".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn test_sessions_without_resume_state_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("sendfile_sessions_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let store = MemoryStore::new();
+        let descriptor = descriptor(&dir, None);
+        let now = descriptor.started_at + 60;
+        record_session_in(&store, &descriptor).unwrap();
+        assert_eq!(find_session_in(&store, &[0xCD; 32], now).unwrap(), None);
+
+        fs::write(resume_state_path(&descriptor.write_path), b"{}").unwrap();
+        assert_eq!(
+            find_session_in(&store, &[0xAB; 32], now).unwrap(),
+            Some(descriptor.clone())
+        );
+        // Too old to resume
+        let expired = descriptor.started_at + MAX_SESSION_AGE.as_secs() + 1;
+        assert_eq!(find_session_in(&store, &[0xAB; 32], expired).unwrap(), None);
+        assert!(store.records().unwrap().is_empty());
+
+        // The partial file was removed along with its resume state
+        record_session_in(&store, &descriptor).unwrap();
+        fs::remove_file(resume_state_path(&descriptor.write_path)).unwrap();
+        assert_eq!(find_session_in(&store, &[0xAB; 32], now).unwrap(), None);
+        assert!(store.records().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
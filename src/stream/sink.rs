@@ -0,0 +1,161 @@
+//! Destinations the receiver writes incoming blocks to.
+
+use std::{fs::File, io, sync::Mutex};
+
+use log::debug;
+
+use crate::{
+    file::utils::{evict_file_range, read_file_block_at, write_file_block_at},
+    stream::throttle::RateLimiter,
+};
+
+/// Destination of the blocks of a received file.
+///
+/// Blocks arrive out of order from several connections at once, so implementations must accept
+/// concurrent calls for distinct blocks.
+pub trait BlockSink: Send + Sync {
+    /// Writes block `seq`, which starts at byte `seq * block_size` of the file.
+    fn write_block(&self, seq: u32, block_size: u32, data: &[u8]) -> io::Result<()>;
+
+    /// Reads back block `seq`, used to verify existing content when resuming. Returns fewer than
+    /// `block_size` bytes (possibly none) past the end of the written data.
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>>;
+}
+
+/// Writes blocks to a file with positioned I/O through a single handle.
+pub struct FileSink {
+    file: File,
+    /// Serializes writes, used on network filesystems where concurrent writes perform poorly.
+    write_lock: Option<Mutex<()>>,
+    /// Whether written blocks are flushed to disk and dropped from the page cache.
+    drop_cache: bool,
+    /// Limits the rate blocks are written at, `None` writes them as fast as they arrive.
+    write_limit: Option<RateLimiter>,
+}
+
+impl FileSink {
+    /// Creates a sink writing to `file`. With `serialize_writes`, only one block is written at a
+    /// time. With `drop_cache`, each block is written out to disk and dropped from the page cache
+    /// before the write returns.
+    pub fn new(file: File, serialize_writes: bool, drop_cache: bool) -> Self {
+        Self {
+            file,
+            write_lock: serialize_writes.then(|| Mutex::new(())),
+            drop_cache,
+            write_limit: None,
+        }
+    }
+
+    /// Limits writes to `bytes_per_second`, blocking each write until the rate allows it. `None`
+    /// leaves writes unlimited.
+    pub fn with_write_limit(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.write_limit = bytes_per_second.map(RateLimiter::new);
+        self
+    }
+}
+
+impl BlockSink for FileSink {
+    fn write_block(&self, seq: u32, block_size: u32, data: &[u8]) -> io::Result<()> {
+        if let Some(limiter) = &self.write_limit {
+            limiter.acquire(data.len() as u64);
+        }
+        let _guard = self
+            .write_lock
+            .as_ref()
+            .map(|lock| lock.lock().unwrap_or_else(|e| e.into_inner()));
+        write_file_block_at(&self.file, seq, block_size, data)?;
+
+        if self.drop_cache {
+            let offset = seq as u64 * block_size as u64;
+            if let Err(e) = evict_file_range(&self.file, offset, data.len() as u64) {
+                debug!("Failed to drop block {} from the page cache: {}", seq, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        read_file_block_at(&self.file, seq, block_size)
+    }
+}
+
+/// Collects blocks into an in-memory buffer.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    buffer: Mutex<Vec<u8>>,
+}
+
+impl MemorySink {
+    /// Creates a sink writing into `buffer`, whose existing content is discarded. The buffer's
+    /// allocation is reused.
+    pub fn new(mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
+        Self {
+            buffer: Mutex::new(buffer),
+        }
+    }
+
+    /// Returns the received content.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buffer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl BlockSink for MemorySink {
+    fn write_block(&self, seq: u32, block_size: u32, data: &[u8]) -> io::Result<()> {
+        let start = seq as usize * block_size as usize;
+        let end = start + data.len();
+
+        let mut buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        if buffer.len() < end {
+            buffer.resize(end, 0);
+        }
+        buffer[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        let buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        let start = (seq as usize * block_size as usize).min(buffer.len());
+        let end = (start + block_size as usize).min(buffer.len());
+        Ok(buffer[start..end].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_sink_out_of_order_blocks() {
+        let sink = MemorySink::new(b"stale".to_vec());
+        sink.write_block(1, 4, b"5678").unwrap();
+        sink.write_block(2, 4, b"9").unwrap();
+        sink.write_block(0, 4, b"1234").unwrap();
+
+        assert_eq!(sink.read_block(1, 4).unwrap(), b"5678");
+        assert_eq!(sink.read_block(2, 4).unwrap(), b"9");
+        assert!(sink.read_block(5, 4).unwrap().is_empty());
+        assert_eq!(sink.into_inner(), b"123456789");
+    }
+
+    #[test]
+    fn test_file_sink_positioned_writes() {
+        let path = std::env::temp_dir().join(format!("sendfile_sink_{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let sink = FileSink::new(file, true, true);
+        sink.write_block(1, 4, b"5678").unwrap();
+        sink.write_block(0, 4, b"1234").unwrap();
+        assert_eq!(sink.read_block(1, 4).unwrap(), b"5678");
+        assert_eq!(std::fs::read(&path).unwrap(), b"12345678");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
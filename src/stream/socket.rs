@@ -0,0 +1,114 @@
+//! Kernel tuning of data connections.
+//!
+//! Data connections disable Nagle's algorithm by default, so the small requests of the receiver
+//! go out immediately. On fast local networks, larger kernel buffers keep more of each block in
+//! flight and coalescing small segments saves per-packet overhead, see
+//! [SendProfile::Lan10g](crate::stream::profile::SendProfile::Lan10g).
+
+use std::{io, net::TcpStream};
+
+use log::{debug, warn};
+use socket2::SockRef;
+
+/// Socket options applied to every data connection of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketTuning {
+    /// Size of the kernel send and receive buffers. `None` leaves them to the kernel, which
+    /// grows them as needed on most systems.
+    pub buffer_size: Option<usize>,
+    /// Whether segments are sent without waiting to coalesce them (`TCP_NODELAY`).
+    pub nodelay: bool,
+}
+
+impl SocketTuning {
+    /// Applies these options to `stream`.
+    ///
+    /// # Returns
+    ///
+    /// An error if `TCP_NODELAY` can't be set. Buffer sizes the kernel refuses or would cap are
+    /// left to the kernel with a warning, the connection is still usable.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.buffer_size {
+            set_buffer_sizes(stream, size);
+        }
+        Ok(())
+    }
+}
+
+impl Default for SocketTuning {
+    /// Kernel-sized buffers, segments sent immediately.
+    fn default() -> Self {
+        Self {
+            buffer_size: None,
+            nodelay: true,
+        }
+    }
+}
+
+/// Sets the send and receive buffers of `stream` to `size` bytes.
+fn set_buffer_sizes(stream: &TcpStream, size: usize) {
+    // A fixed buffer turns off the kernel's autotuning, which may grow it further than a capped
+    // fixed size would
+    if let Some(limit) = kernel_buffer_limit().filter(|&limit| limit < size) {
+        debug!(
+            "Leaving socket buffers to the kernel, which caps them at {} bytes (net.core.rmem_max \
+             and net.core.wmem_max)",
+            limit
+        );
+        return;
+    }
+    let socket = SockRef::from(stream);
+    if let Err(e) = socket.set_send_buffer_size(size) {
+        warn!("Failed to set the socket send buffer size: {}", e);
+    }
+    if let Err(e) = socket.set_recv_buffer_size(size) {
+        warn!("Failed to set the socket receive buffer size: {}", e);
+    }
+}
+
+/// Returns the largest socket buffer an unprivileged process can set, if the kernel tells.
+#[cfg(target_os = "linux")]
+fn kernel_buffer_limit() -> Option<usize> {
+    let read = |name: &str| {
+        std::fs::read_to_string(format!("/proc/sys/net/core/{}", name))
+            .ok()?
+            .trim()
+            .parse::<usize>()
+            .ok()
+    };
+    Some(read("rmem_max")?.min(read("wmem_max")?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_buffer_limit() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_apply_socket_tuning() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        SocketTuning::default().apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        let tuning = SocketTuning {
+            buffer_size: Some(64 * 1024),
+            nodelay: false,
+        };
+        tuning.apply(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        // Linux doubles the size set for its bookkeeping
+        if kernel_buffer_limit().is_none_or(|limit| limit >= 64 * 1024) {
+            let socket = SockRef::from(&stream);
+            assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+            assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        }
+    }
+}
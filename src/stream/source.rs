@@ -0,0 +1,159 @@
+//! Origins the sender reads outgoing blocks from.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    sync::Mutex,
+};
+
+use log::debug;
+
+use crate::{
+    file::{
+        device::device_size,
+        utils::{advise_file, read_file_block_at, FileAdvice},
+    },
+    stream::bundle::Bundle,
+};
+
+/// Number of blocks after the one just read the kernel is asked to prefetch.
+const READAHEAD_BLOCKS: u64 = 4;
+/// Size from which a [FileSource] drops blocks from the page cache once they are read, so huge
+/// transfers don't evict everything else.
+pub const DROP_BEHIND_MIN_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Origin of the blocks of a sent file.
+///
+/// Every data connection reads from the same source concurrently, so implementations must
+/// accept concurrent calls.
+pub trait BlockSource: Send + Sync {
+    /// Reads block `seq`, which starts at byte `seq * block_size` of the file. Returns fewer than
+    /// `block_size` bytes (possibly none) at the end of the file.
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>>;
+
+    /// Returns the files the blocks belong to when the source is a directory, see
+    /// [crate::stream::bundle]. Block `seq` is then block `seq` of the bundle.
+    fn bundle(&self) -> Option<&Bundle> {
+        None
+    }
+}
+
+/// Files are read with positioned reads, so a single handle serves every connection.
+impl BlockSource for File {
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        read_file_block_at(self, seq, block_size)
+    }
+}
+
+/// A file on disk, read like a [File] while hinting the kernel at the access pattern.
+///
+/// Each connection serves its range of blocks in order, so once a block is read the following
+/// ones are prefetched. Files of at least [DROP_BEHIND_MIN_SIZE] also have every block read
+/// dropped from the page cache.
+pub struct FileSource {
+    file: File,
+    drop_behind: bool,
+}
+
+impl FileSource {
+    /// Wraps `file`, announcing it will be read sequentially.
+    pub fn new(file: File) -> io::Result<Self> {
+        let drop_behind = device_size(&file)? >= DROP_BEHIND_MIN_SIZE;
+        if let Err(e) = advise_file(&file, 0, 0, FileAdvice::Sequential) {
+            debug!("Failed to advise sequential reads: {}", e);
+        }
+        Ok(Self { file, drop_behind })
+    }
+}
+
+impl BlockSource for FileSource {
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        let data = read_file_block_at(&self.file, seq, block_size)?;
+
+        // Hints only, a failure just loses the optimization
+        let block_size = block_size as u64;
+        let offset = seq as u64 * block_size;
+        let _ = advise_file(
+            &self.file,
+            offset + block_size,
+            READAHEAD_BLOCKS * block_size,
+            FileAdvice::WillNeed,
+        );
+        if self.drop_behind && !data.is_empty() {
+            let _ = advise_file(&self.file, offset, data.len() as u64, FileAdvice::DontNeed);
+        }
+        Ok(data)
+    }
+}
+
+impl<T: BlockSource + ?Sized> BlockSource for &T {
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        (**self).read_block(seq, block_size)
+    }
+
+    fn bundle(&self) -> Option<&Bundle> {
+        (**self).bundle()
+    }
+}
+
+/// Reads blocks from any seekable reader, e.g. a database snapshot or an archive member.
+///
+/// Reads are serialized, since seeking and reading a shared reader is not atomic.
+pub struct ReaderSource<R> {
+    reader: Mutex<R>,
+    len: u64,
+}
+
+impl<R: Read + Seek + Send> ReaderSource<R> {
+    /// Creates a source serving the first `len` bytes of `reader`, counted from its start.
+    pub fn new(reader: R, len: u64) -> Self {
+        Self {
+            reader: Mutex::new(reader),
+            len,
+        }
+    }
+}
+
+impl<R: Read + Seek + Send> BlockSource for ReaderSource<R> {
+    fn read_block(&self, seq: u32, block_size: u32) -> io::Result<Vec<u8>> {
+        let offset = seq as u64 * block_size as u64;
+        let size = self.len.saturating_sub(offset).min(block_size as u64);
+
+        let mut reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut buffer = Vec::with_capacity(size as usize);
+        (&mut *reader).take(size).read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_reader_source_respects_length() {
+        // Only the first 10 bytes belong to the transferred content
+        let source = ReaderSource::new(Cursor::new(b"0123456789trailing".to_vec()), 10);
+
+        assert_eq!(source.read_block(1, 4).unwrap(), b"4567");
+        assert_eq!(source.read_block(2, 4).unwrap(), b"89");
+        assert!(source.read_block(3, 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_source_reads_blocks() {
+        let path = std::env::temp_dir().join(format!("sendfile-source-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let source = FileSource::new(File::open(&path).unwrap()).unwrap();
+        assert!(!source.drop_behind);
+        assert_eq!(source.read_block(0, 4).unwrap(), b"0123");
+        assert_eq!(source.read_block(2, 4).unwrap(), b"89");
+        assert!(source.read_block(5, 4).unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
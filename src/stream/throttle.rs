@@ -0,0 +1,91 @@
+//! Rate limiting of the receiver's disk writes.
+//!
+//! With `--disk-limit-rate`, every block written to the output file first reserves its share of
+//! the rate from a [RateLimiter] shared by all connections, and waits for it. Blocks still arrive
+//! as fast as the network allows, so the limit holds independently of the network speed, and
+//! connections simply stop requesting blocks while they wait to write the last one.
+
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Spaces out writes so that, over time, no more than a fixed number of bytes per second go
+/// through it.
+///
+/// Time not used is not saved up, so writing after an idle period doesn't burst past the rate.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    /// Time from which the next write may start.
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter letting `bytes_per_second` bytes through each second.
+    ///
+    /// # Panics
+    ///
+    /// If `bytes_per_second` is 0.
+    pub fn new(bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "rate limit must be positive");
+        Self {
+            bytes_per_second,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Returns the rate in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Reserves `bytes` of the rate and blocks until they may be written.
+    ///
+    /// # Returns
+    ///
+    /// How long the caller was blocked.
+    pub fn acquire(&self, bytes: u64) -> Duration {
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        let now = Instant::now();
+        let start = {
+            let mut next_slot = self.next_slot.lock().unwrap_or_else(|e| e.into_inner());
+            let start = (*next_slot).max(now);
+            *next_slot = start + cost;
+            start
+        };
+
+        let wait = start.saturating_duration_since(now);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_spaces_writes() {
+        let limiter = RateLimiter::new(10_000);
+        let started = Instant::now();
+
+        // The first write goes through at once, each following one waits for the previous one
+        assert_eq!(limiter.acquire(500), Duration::ZERO);
+        limiter.acquire(500);
+        limiter.acquire(500);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        // Shared between threads, the total rate still holds
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| limiter.acquire(250));
+            }
+        });
+        assert!(started.elapsed() >= Duration::from_millis(75));
+    }
+}
@@ -0,0 +1,116 @@
+//! Time limits of transfers (`--max-duration`), for scheduled jobs that must bound their runtime.
+//!
+//! A transfer still running once [SendOptions::max_duration] or [ReceiveOptions::max_duration]
+//! has passed is aborted like a cancelled one: blocks already written are kept, and the receiver
+//! records them for a later transfer to resume from. Before closing its connections, the side
+//! whose limit ran out tells its peer with an error message of code [TIME_LIMIT_CODE], and both
+//! sides fail with [SendFileError::TimeLimitExceeded].
+//!
+//! [SendOptions::max_duration]: crate::stream::options::SendOptions::max_duration
+//! [ReceiveOptions::max_duration]: crate::stream::options::ReceiveOptions::max_duration
+
+use std::{
+    sync::{Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::warn;
+
+use crate::{
+    stream::{error::SendFileError, handle::TransferControl},
+    units::Elapsed,
+};
+
+/// Code of the error a peer aborting the transfer at its time limit sends.
+pub const TIME_LIMIT_CODE: u16 = 408;
+
+/// Runs `transfer`, aborting it through `control` once `limit` has passed.
+///
+/// # Returns
+///
+/// The result of `transfer`, or [SendFileError::TimeLimitExceeded] if it failed after the limit
+/// of either side ran out.
+pub(crate) fn with_time_limit<T>(
+    limit: Option<Duration>,
+    control: &TransferControl,
+    transfer: impl FnOnce() -> Result<T, SendFileError>,
+) -> Result<T, SendFileError> {
+    let finished = Mutex::new(false);
+    let wakeup = Condvar::new();
+    let result = thread::scope(|scope| {
+        if let Some(limit) = limit {
+            let (finished, wakeup) = (&finished, &wakeup);
+            scope.spawn(move || {
+                let finished = finished.lock().unwrap_or_else(|e| e.into_inner());
+                let (finished, _) = wakeup
+                    .wait_timeout_while(finished, limit, |finished| !*finished)
+                    .unwrap_or_else(|e| e.into_inner());
+                if !*finished {
+                    warn!("Time limit of {} exceeded, aborting", Elapsed(limit));
+                    control.expire();
+                }
+            });
+        }
+        let result = transfer();
+        *finished.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wakeup.notify_all();
+        result
+    });
+
+    if result.is_err() {
+        if control.is_expired() {
+            return Err(SendFileError::TimeLimitExceeded(format!(
+                "time limit of {} exceeded",
+                Elapsed(limit.unwrap_or_default())
+            )));
+        }
+        if let Some(reason) = control.peer_abort() {
+            return Err(SendFileError::TimeLimitExceeded(reason));
+        }
+    }
+    result
+}
+
+/// Returns the reason a `role` ("sender" or "receiver") whose time limit `limit` ran out gives its
+/// peer.
+pub(crate) fn abort_reason(role: &str, limit: Option<Duration>) -> String {
+    format!(
+        "{}'s time limit of {} exceeded",
+        role,
+        Elapsed(limit.unwrap_or_default())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfers_past_their_limit_are_aborted() {
+        let control = TransferControl::new();
+        let result = with_time_limit(Some(Duration::from_millis(50)), &control, || {
+            while control.checkpoint().is_ok() {
+                thread::sleep(Duration::from_millis(5));
+            }
+            Err::<(), _>(SendFileError::Cancelled)
+        });
+        assert!(matches!(result, Err(SendFileError::TimeLimitExceeded(_))));
+        assert!(control.is_cancelled());
+
+        // Finishing in time stops the watchdog right away
+        let control = TransferControl::new();
+        let started = std::time::Instant::now();
+        assert!(with_time_limit(Some(Duration::from_secs(60)), &control, || Ok(())).is_ok());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!control.is_cancelled());
+
+        let control = TransferControl::new();
+        control.abort_by_peer(abort_reason("sender", Some(Duration::from_secs(90))));
+        let result = with_time_limit::<()>(None, &control, || Err(SendFileError::Cancelled));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Transfer aborted: sender's time limit of 1m 30s exceeded"
+        );
+    }
+}
@@ -0,0 +1,529 @@
+//! UDP data plane with forward error correction, for high-bandwidth, high-latency links.
+//!
+//! When the sender offers [UDP_FEC](crate::capabilities::Capabilities::UDP_FEC) (`send --udp`),
+//! the receiver asks for each block with a `UdpRequest` on its data connection instead of a
+//! `Request`, naming a UDP port it listens on. The sender announces the block on the data
+//! connection with a `UdpBlock`, then sends the frame it would have written to the connection as
+//! RaptorQ (RFC 6330) encoded datagrams to that port, with
+//! [repair datagrams](crate::stream::options::SendOptions::udp_overhead) on top. Any large enough
+//! share of the datagrams rebuilds the frame, so lost packets rarely cost a round trip. A block
+//! that can't be rebuilt is requested again with a `Request` over TCP.
+//!
+//! Datagrams are paced rather than sent in a burst per block. Each data connection starts at
+//! [INITIAL_UDP_RATE], speeds up while its blocks are rebuilt and halves its rate when one isn't,
+//! which the sender learns from the receiver asking for it again over TCP. Failed blocks also
+//! double the repair datagrams, which go back down to the configured share once blocks are rebuilt
+//! again. Datagrams count against the rate limit of the transfer (`--limit-rate`) as they are
+//! sent.
+//!
+//! Datagrams are neither encrypted nor authenticated, they only carry the random tag of the
+//! receiver's request. The receiver only takes datagrams from the sender's address, and connects
+//! its socket to the port of the first datagram carrying its tag. A forged datagram can at worst
+//! spoil a block, which then fails its checksum and is downloaded again. Encrypted transfers never
+//! use UDP.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    transport::{UdpBlockV1, UdpRequestV1},
+    units::Rate,
+};
+
+/// Bytes of encoded data per datagram, so datagrams fit the 1280 byte minimum MTU of IPv6 with
+/// their headers.
+pub const SYMBOL_SIZE: u16 = 1200;
+
+/// Default repair datagrams sent on top of the datagrams of a block, in percent of them.
+pub const DEFAULT_UDP_OVERHEAD: u32 = 10;
+
+/// Most repair datagrams sent on top of the datagrams of a block while blocks fail to be rebuilt,
+/// in percent of them.
+pub const MAX_UDP_OVERHEAD: u32 = 100;
+
+/// Rate the datagrams of a data connection are paced at before any block was rebuilt, 100 Mbit/s.
+pub const INITIAL_UDP_RATE: u64 = 12_500_000;
+
+/// Lowest rate datagrams are paced at, however many blocks fail to be rebuilt.
+const MIN_UDP_RATE: u64 = 125_000;
+
+/// Highest rate datagrams are paced at, 10 Gbit/s.
+const MAX_UDP_RATE: u64 = 1_250_000_000;
+
+/// Blocks rebuilt in a row after which the repair datagrams are lowered back towards the
+/// configured share.
+const OVERHEAD_DECAY_BLOCKS: u32 = 16;
+
+/// How far ahead of its pace a sender may get before it sleeps, so it doesn't sleep for every
+/// datagram.
+const PACING_SLACK: Duration = Duration::from_millis(1);
+
+/// Receive buffer requested for the UDP socket of a data connection, so a burst of datagrams
+/// isn't dropped while the receiver writes the previous block. The kernel may grant less.
+const UDP_RECV_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Time the receiver waits for the next datagram of a block before giving up on it.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Bytes preceding the encoded data of a datagram: the request's tag and the block's sequence
+/// number, then the RaptorQ payload id.
+const DATAGRAM_HEADER_SIZE: usize = 8 + 4 + 4;
+
+/// Frame of a block encoded into datagrams, ready to be announced and sent.
+pub struct EncodedBlock {
+    seq: u32,
+    length: u32,
+    datagrams: Vec<Vec<u8>>,
+}
+
+impl EncodedBlock {
+    /// Encodes `frame`, the answer to the request for block `seq` tagged with `tag`, adding
+    /// `overhead_percent` repair datagrams.
+    pub fn encode(frame: &[u8], seq: u32, tag: u64, overhead_percent: u32) -> Self {
+        let encoder = Encoder::new(frame, transmission_info(frame.len() as u32));
+        let source_symbols = frame.len().div_ceil(SYMBOL_SIZE as usize) as u32;
+        let repair = source_symbols
+            .saturating_mul(overhead_percent)
+            .div_ceil(100);
+        let datagrams = encoder
+            .get_encoded_packets(repair)
+            .iter()
+            .map(|packet| {
+                let mut datagram = Vec::with_capacity(DATAGRAM_HEADER_SIZE + SYMBOL_SIZE as usize);
+                datagram.extend_from_slice(&tag.to_be_bytes());
+                datagram.extend_from_slice(&seq.to_be_bytes());
+                datagram.extend_from_slice(&packet.serialize());
+                datagram
+            })
+            .collect();
+        Self {
+            seq,
+            length: frame.len() as u32,
+            datagrams,
+        }
+    }
+
+    /// Returns the announcement of the block, sent on the data connection before its datagrams.
+    pub fn announcement(&self) -> UdpBlockV1 {
+        UdpBlockV1 {
+            seq: self.seq,
+            length: self.length,
+            datagrams: self.datagrams.len() as u32,
+        }
+    }
+
+    /// Sends every datagram of the block from `socket` to `to`, calling `pace` with the length
+    /// of each before it is sent.
+    pub fn send(
+        &self,
+        socket: &UdpSocket,
+        to: SocketAddr,
+        mut pace: impl FnMut(usize),
+    ) -> io::Result<()> {
+        for datagram in &self.datagrams {
+            pace(datagram.len());
+            socket.send_to(datagram, to)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a sender learned of how its datagrams get through.
+#[derive(Debug)]
+struct LinkFeedback {
+    /// Bytes per second datagrams are paced at.
+    rate: u64,
+    /// Repair datagrams sent on top of each block, in percent.
+    overhead_percent: u32,
+    /// Blocks rebuilt in a row since the last one that wasn't, or since the overhead was lowered.
+    rebuilt_in_a_row: u32,
+    /// Block whose datagrams were sent last, until the receiver asks for another one.
+    in_flight: Option<u32>,
+    /// Time from which the next datagram may be sent.
+    next_slot: Instant,
+}
+
+/// UDP socket of a sender's data connection, sending blocks to the receiver at the other end.
+pub struct UdpSender {
+    socket: UdpSocket,
+    receiver: IpAddr,
+    /// Repair datagrams configured, in percent, the least sent on top of a block.
+    min_overhead_percent: u32,
+    feedback: Mutex<LinkFeedback>,
+}
+
+impl UdpSender {
+    /// Binds a socket on an ephemeral port to send datagrams to `receiver` from, with at least
+    /// `overhead_percent` repair datagrams per block.
+    pub fn bind(receiver: IpAddr, overhead_percent: u32) -> io::Result<Self> {
+        let socket = UdpSocket::bind(unspecified_address(receiver))?;
+        Ok(Self {
+            socket,
+            receiver,
+            min_overhead_percent: overhead_percent,
+            feedback: Mutex::new(LinkFeedback {
+                rate: INITIAL_UDP_RATE,
+                overhead_percent,
+                rebuilt_in_a_row: 0,
+                in_flight: None,
+                next_slot: Instant::now(),
+            }),
+        })
+    }
+
+    /// Encodes `frame`, the answer to `request`, into datagrams.
+    ///
+    /// A receiver asking for another block rebuilt the one sent before, the link then gets
+    /// faster and less repair datagrams are sent.
+    pub fn encode(&self, frame: &[u8], request: &UdpRequestV1) -> EncodedBlock {
+        let overhead_percent = {
+            let mut feedback = self.lock_feedback();
+            if feedback.in_flight.is_some_and(|seq| seq != request.seq) {
+                feedback.rate = (feedback.rate + feedback.rate / 8).min(MAX_UDP_RATE);
+                feedback.rebuilt_in_a_row += 1;
+                if feedback.rebuilt_in_a_row >= OVERHEAD_DECAY_BLOCKS {
+                    feedback.rebuilt_in_a_row = 0;
+                    let excess = feedback.overhead_percent - self.min_overhead_percent;
+                    feedback.overhead_percent -= excess.div_ceil(4);
+                }
+            }
+            feedback.in_flight = Some(request.seq);
+            feedback.overhead_percent
+        };
+        EncodedBlock::encode(frame, request.seq, request.tag, overhead_percent)
+    }
+
+    /// Notes that the receiver asked for block `seq` over TCP. If its datagrams were the last
+    /// sent, too many were lost to rebuild it: the rate is halved and the repair datagrams
+    /// doubled.
+    pub fn note_tcp_request(&self, seq: u32) {
+        let mut feedback = self.lock_feedback();
+        if feedback.in_flight != Some(seq) {
+            return;
+        }
+        feedback.in_flight = None;
+        feedback.rebuilt_in_a_row = 0;
+        feedback.rate = (feedback.rate / 2).max(MIN_UDP_RATE);
+        feedback.overhead_percent = (feedback.overhead_percent * 2)
+            .max(feedback.overhead_percent + 5)
+            .min(MAX_UDP_OVERHEAD.max(self.min_overhead_percent));
+        debug!(
+            "Block {} was lost over UDP, pacing at {} with {}% repair datagrams",
+            seq,
+            Rate(feedback.rate as f64),
+            feedback.overhead_percent
+        );
+    }
+
+    /// Sends the datagrams of `block` to `port` of the receiver, paced at the rate of the link,
+    /// calling `throttle` with the length of each before it is sent.
+    pub fn send(
+        &self,
+        block: &EncodedBlock,
+        port: u16,
+        mut throttle: impl FnMut(u64),
+    ) -> io::Result<()> {
+        let (rate, mut next_slot) = {
+            let feedback = self.lock_feedback();
+            (feedback.rate, feedback.next_slot)
+        };
+        let result = block.send(&self.socket, SocketAddr::new(self.receiver, port), |len| {
+            let now = Instant::now();
+            let start = next_slot.max(now);
+            next_slot = start + Duration::from_secs_f64(len as f64 / rate as f64);
+            let ahead = start.saturating_duration_since(now);
+            if ahead > PACING_SLACK {
+                thread::sleep(ahead);
+            }
+            throttle(len as u64);
+        });
+        self.lock_feedback().next_slot = next_slot;
+        result
+    }
+
+    fn lock_feedback(&self) -> std::sync::MutexGuard<'_, LinkFeedback> {
+        self.feedback.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// UDP socket of a receiver's data connection, rebuilding the blocks sent to it.
+pub struct UdpReceiver {
+    socket: UdpSocket,
+    /// Address of the sender, the only one datagrams are taken from.
+    sender: IpAddr,
+    /// Whether the socket is connected to the sender's UDP socket, once a datagram came from it.
+    connected: bool,
+    tag: u64,
+    buffer: Vec<u8>,
+    datagrams_received: u64,
+}
+
+impl UdpReceiver {
+    /// Binds a socket on an ephemeral port of the address family of `sender`, and picks the
+    /// random tag its requests carry.
+    pub fn bind(sender: IpAddr) -> io::Result<Self> {
+        let address = unspecified_address(sender);
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        if let Err(e) = socket.set_recv_buffer_size(UDP_RECV_BUFFER_SIZE) {
+            debug!("Failed to grow the UDP receive buffer: {}", e);
+        }
+        socket.bind(&address.into())?;
+        let socket = UdpSocket::from(socket);
+        socket.set_read_timeout(Some(UDP_IDLE_TIMEOUT))?;
+
+        let mut tag = [0u8; 8];
+        getrandom::fill(&mut tag).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Self {
+            socket,
+            sender,
+            connected: false,
+            tag: u64::from_be_bytes(tag),
+            // One byte spare, so longer datagrams aren't mistaken for truncated ones
+            buffer: vec![0u8; DATAGRAM_HEADER_SIZE + SYMBOL_SIZE as usize + 1],
+            datagrams_received: 0,
+        })
+    }
+
+    /// Returns the port the sender sends datagrams to.
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    /// Returns the tag requests carry, and with them the datagrams answering them.
+    pub fn tag(&self) -> u64 {
+        self.tag
+    }
+
+    /// Returns the number of datagrams received for any block so far.
+    pub fn datagrams_received(&self) -> u64 {
+        self.datagrams_received
+    }
+
+    /// Receives the datagrams of the block `announced` and rebuilds its frame, at most
+    /// `max_length` bytes long.
+    ///
+    /// # Returns
+    ///
+    /// The frame, or `None` if too many datagrams were lost to rebuild it.
+    pub fn receive(
+        &mut self,
+        announced: &UdpBlockV1,
+        max_length: usize,
+    ) -> io::Result<Option<Vec<u8>>> {
+        if announced.datagrams == 0 {
+            return Ok(None);
+        }
+        if announced.length == 0 || announced.length as usize > max_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Announced block of {} bytes", announced.length),
+            ));
+        }
+        let info = transmission_info(announced.length);
+        let mut decoder = Decoder::new(info);
+        let mut received = 0;
+        while received < announced.datagrams {
+            let (len, from) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            if from.ip().to_canonical() != self.sender.to_canonical() {
+                debug!("Ignoring a datagram from {}, not the sender", from);
+                continue;
+            }
+            let Some(packet) = self.parse(&self.buffer[..len], announced.seq, &info) else {
+                // Stray, or late for an earlier block
+                continue;
+            };
+            if !self.connected {
+                // Datagrams from any other port of the sender are dropped from now on
+                self.socket.connect(from)?;
+                self.connected = true;
+            }
+            received += 1;
+            self.datagrams_received += 1;
+            if let Some(frame) = decoder.decode(packet) {
+                return Ok(Some(frame));
+            }
+        }
+        debug!(
+            "Rebuilding block {} failed with {} of {} datagrams",
+            announced.seq, received, announced.datagrams
+        );
+        Ok(None)
+    }
+
+    /// Returns the encoded data of `datagram` if it belongs to block `seq` of this receiver.
+    fn parse(
+        &self,
+        datagram: &[u8],
+        seq: u32,
+        info: &ObjectTransmissionInformation,
+    ) -> Option<EncodingPacket> {
+        if datagram.len() != DATAGRAM_HEADER_SIZE + info.symbol_size() as usize
+            || datagram[..8] != self.tag.to_be_bytes()
+            || datagram[8..12] != seq.to_be_bytes()
+        {
+            return None;
+        }
+        let packet = EncodingPacket::deserialize(&datagram[12..]);
+        (packet.payload_id().source_block_number() < info.source_blocks()).then_some(packet)
+    }
+}
+
+/// Returns the unspecified address of the family of `peer`, with an ephemeral port.
+fn unspecified_address(peer: IpAddr) -> SocketAddr {
+    match peer {
+        IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        IpAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+/// Returns how a frame of `length` bytes is split into symbols, derived the same way by both
+/// peers so it is never taken from the wire.
+fn transmission_info(length: u32) -> ObjectTransmissionInformation {
+    ObjectTransmissionInformation::with_defaults(length as u64, SYMBOL_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender_socket() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap()
+    }
+
+    #[test]
+    fn test_blocks_are_rebuilt_despite_lost_datagrams() {
+        let frame: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut receiver = UdpReceiver::bind(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let sender = UdpSender::bind(Ipv4Addr::LOCALHOST.into(), 20).unwrap();
+        let request = UdpRequestV1 {
+            file_hash: [0; 32],
+            seq: 3,
+            port: receiver.port().unwrap(),
+            tag: receiver.tag(),
+        };
+
+        let mut block = sender.encode(&frame, &request);
+        let announced = block.announcement();
+        // Every tenth datagram is lost
+        let mut index = 0;
+        block.datagrams.retain(|_| {
+            index += 1;
+            index % 10 != 0
+        });
+        sender.send(&block, request.port, |_| ()).unwrap();
+
+        assert_eq!(
+            receiver.receive(&announced, frame.len()).unwrap(),
+            Some(frame)
+        );
+    }
+
+    #[test]
+    fn test_blocks_missing_too_many_datagrams_are_given_up() {
+        let frame = vec![0x5A; 50_000];
+        let mut receiver = UdpReceiver::bind(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let to = SocketAddr::from((Ipv4Addr::LOCALHOST, receiver.port().unwrap()));
+        let socket = sender_socket();
+
+        // Datagrams of another receiver's request are ignored
+        EncodedBlock::encode(&frame, 0, receiver.tag() ^ 1, 10)
+            .send(&socket, to, |_| ())
+            .unwrap();
+        let mut block = EncodedBlock::encode(&frame, 0, receiver.tag(), 10);
+        let announced = block.announcement();
+        block.datagrams.truncate(block.datagrams.len() / 2);
+        block.send(&socket, to, |_| ()).unwrap();
+
+        assert_eq!(receiver.receive(&announced, frame.len()).unwrap(), None);
+        assert_eq!(
+            receiver.datagrams_received(),
+            u64::from(announced.datagrams / 2)
+        );
+        assert!(receiver.receive(&announced, 100).is_err());
+    }
+
+    #[test]
+    fn test_lost_blocks_slow_down_the_link() {
+        let frame = vec![0x5A; 50_000];
+        let sender = UdpSender::bind(Ipv4Addr::LOCALHOST.into(), 10).unwrap();
+        let request = |seq| UdpRequestV1 {
+            file_hash: [0; 32],
+            seq,
+            port: 9,
+            tag: 1,
+        };
+        let state = |sender: &UdpSender| {
+            let feedback = sender.lock_feedback();
+            (feedback.rate, feedback.overhead_percent)
+        };
+
+        let block = sender.encode(&frame, &request(0));
+        let datagrams = block.datagrams.len();
+        // Requests for blocks that weren't sent over UDP say nothing of the link
+        sender.note_tcp_request(1);
+        assert_eq!(state(&sender), (INITIAL_UDP_RATE, 10));
+
+        sender.note_tcp_request(0);
+        assert_eq!(state(&sender), (INITIAL_UDP_RATE / 2, 20));
+        assert!(sender.encode(&frame, &request(0)).datagrams.len() > datagrams);
+
+        // Rebuilt blocks speed it up again, and lower the overhead back
+        for seq in 1..=OVERHEAD_DECAY_BLOCKS {
+            sender.encode(&frame, &request(seq));
+        }
+        let (rate, overhead) = state(&sender);
+        assert!(rate > INITIAL_UDP_RATE);
+        assert_eq!(overhead, 17);
+    }
+
+    #[test]
+    fn test_datagrams_are_paced() {
+        let frame = vec![0x5A; 20 * SYMBOL_SIZE as usize];
+        let receiver = UdpReceiver::bind(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let sender = UdpSender::bind(Ipv4Addr::LOCALHOST.into(), 0).unwrap();
+        sender.lock_feedback().rate = 100 * SYMBOL_SIZE as u64;
+        let request = UdpRequestV1 {
+            file_hash: [0; 32],
+            seq: 0,
+            port: receiver.port().unwrap(),
+            tag: receiver.tag(),
+        };
+
+        let block = sender.encode(&frame, &request);
+        let mut throttled = 0;
+        let started = Instant::now();
+        sender
+            .send(&block, request.port, |len| throttled += len)
+            .unwrap();
+        // 20 datagrams at 100 per second
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(
+            throttled,
+            block.datagrams.iter().map(|d| d.len() as u64).sum::<u64>()
+        );
+    }
+}
@@ -1,53 +1,708 @@
 use crate::{
-    file::FileMetadata,
-    stream::error::SendFileError,
-    transport::{self, HandshakeV1, SenderMessageV1},
+    authentication::sign_authentication,
+    capabilities::{Capabilities, SOFTWARE_VERSION},
+    connection::read_next_payload,
+    file::{content_type::SNIFF_LEN, metadata::check_metadata, FileMetadata},
+    history::to_hex,
+    identity::Identity,
+    noise::{self, NoiseError, NoiseHandshake, NoiseKey, NoisePeer, NoiseStream},
+    pairing,
+    peers::PeerRegistry,
+    stream::{
+        bundle::BundleError, clock::unix_millis, error::SendFileError,
+        keepalive::configure_keepalive, options::SendOptions, preconnected::Connection,
+        source::BlockSource,
+    },
+    tls::MaybeTlsStream,
+    transport::{
+        self, ClockV1, FileHeaderV1, HandshakeAckV1, HandshakeV1, MetadataV1, ProtocolVersionV1,
+        ProtocolVersionsV1, ReceiverMessageV1, SenderMessageV1, SessionV1,
+        MULTI_FILE_PROTOCOL_VERSION, SESSION_FRAMING_PROTOCOL_VERSION, SESSION_ID_SIZE,
+        SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    units::{Count, Size},
 };
-use log::{debug, info};
-use std::{io::Write, net::TcpStream, path::Path};
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// Time allowed to each address of the receiver to accept the connection.
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+/// Time allowed to the receiver to answer the offer once it chose the protocol version.
+pub(crate) const OFFER_RESPONSE_TIMEOUT_SECS: u64 = 15;
+
+/// The receiver's answer to the handshake, see [await_handshake_answer].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeAnswer {
+    /// The receiver accepted the file with the parameters of its acknowledgement, which match
+    /// the offer.
+    Acknowledged(HandshakeAckV1),
+    /// The receiver accepted the file with an `OfferResponse`, predating acknowledgements.
+    Accepted,
+    /// The receiver did not answer the offer, as receivers predating offer responses do.
+    Unanswered,
+}
+
+/// Binds a listener on the first address `address` resolves to that can be bound, queueing up to
+/// `backlog` connections before they are accepted.
+pub fn bind_listener(address: impl ToSocketAddrs, backlog: u32) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for address in address.to_socket_addrs()? {
+        match bind_listener_on(address, backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+fn bind_listener_on(address: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // As std does, so a restarted transfer can listen while old connections linger in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    Ok(socket.into())
+}
 
-/// Initializes a file handshake with the specified address and file path,
-/// sending the necessary metadata to the receiver.
+/// Initializes a file handshake with the specified address, sending the file's metadata to
+/// the receiver, see [handshake_frames]. With [SendOptions::noise], the Noise channel is set up
+/// between the handshake and the frames following it.
+///
+/// Transfers then wait for the receiver's answer to the offer, see [await_handshake_answer]:
+/// probes and dry runs, with `probing` set, read the answer of their own.
+///
+/// Returns the handshake connection, secured with [SendOptions::tls] if set, which stays open
+/// so the receiver can return its receipt once the transfer is verified, and the answer.
 pub fn initialize_handshake(
     transport_buffer: &mut [u8],
     address: (&str, u16),
-    file_path: &Path,
-    block_size: u32,
-    concurrency: u16,
-) -> Result<[u8; 32], SendFileError> {
-    debug!("Calculating file metadata for {:?}", file_path);
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    probing: Capabilities,
+    session: Option<&SessionV1>,
+) -> Result<(NoiseStream<MaybeTlsStream>, HandshakeAnswer), SendFileError> {
+    let identity = load_identity(options);
+    let (handshake_frame, trailing_frames) = handshake_frames(
+        transport_buffer,
+        file_metadata,
+        source,
+        options,
+        probing,
+        session,
+        identity.as_ref(),
+    )?;
 
-    let file_metadata = FileMetadata::from_file(file_path)?;
+    let hosts =
+        std::iter::once(address.0).chain(options.alternate_hosts.iter().map(String::as_str));
+    let (tcp, host) = connect_first(hosts, address.1)?;
+    tcp.set_nodelay(true)?;
+    configure_keepalive(&tcp, options.keepalive.as_ref());
+    let stream = match &options.tls {
+        Some(tls) => tls.connect(tcp, host)?,
+        None => MaybeTlsStream::Plain(tcp),
+    };
+    let mut stream = NoiseStream::new(stream);
+    if let Some(code) = &options.pairing_code {
+        let key = NoiseKey::new(identity.as_ref())?;
+        stream.set_read_timeout(Some(options.handshake_timeout))?;
+        pairing::pair_with_receiver(&mut stream, code.expose(), &key)?;
+        stream.set_read_timeout(None)?;
+        // The code vouches for the receiver, whatever its key
+        info!("Paired with the receiver, the handshake is encrypted");
+    }
+
+    info!(
+        "Connected to server, Initiating: {:?}",
+        file_metadata.name()
+    );
+    stream.write_all(&handshake_frame)?;
+    if options.noise && stream.peer().is_none() {
+        let key = NoiseKey::new(identity.as_ref())?;
+        stream.set_read_timeout(Some(options.handshake_timeout))?;
+        let handshake = NoiseHandshake::initiator(&key, &handshake_frame)?;
+        noise::initiate(&mut stream, handshake)?;
+        stream.set_read_timeout(None)?;
+        if let Some(peer) = stream.peer() {
+            check_noise_receiver(options, host, peer)?;
+        }
+    }
+    stream.write_all(&trailing_frames)?;
+    stream.flush()?; // Ensure the message is sent immediately
+
+    if probing != Capabilities::empty() {
+        return Ok((stream, HandshakeAnswer::Unanswered));
+    }
+    let answer = await_handshake_answer(
+        &mut stream,
+        transport_buffer,
+        file_metadata,
+        options,
+        session.map(|session| &session.session_id),
+    )?;
+    Ok((stream, answer))
+}
+
+/// Loads the identity at [SendOptions::identity_path], `None` if there is none or it can't be
+/// loaded, in which case the file is sent unauthenticated.
+pub fn load_identity(options: &SendOptions) -> Option<Identity> {
+    options.identity_path.as_deref().and_then(|path| {
+        Identity::load_or_generate(path)
+            .map_err(|e| warn!("Sending unauthenticated, identity unavailable: {}", e))
+            .ok()
+    })
+}
+
+/// Checks the receiver's Noise key against the key of the trusted peer reachable at `host`, if
+/// there is one, before anything about the file but its handshake goes out.
+fn check_noise_receiver(
+    options: &SendOptions,
+    host: &str,
+    peer: &NoisePeer,
+) -> Result<(), SendFileError> {
+    let key = to_hex(peer.remote_key());
+    let trusted = options
+        .peers_path
+        .as_deref()
+        .map(PeerRegistry::load)
+        .transpose()
+        .unwrap_or_else(|e| {
+            warn!("Failed to read trusted peers: {}", e);
+            None
+        });
+    match trusted
+        .as_ref()
+        .and_then(|peers| peers.find_by_address(host))
+    {
+        Some(trusted) => match trusted.key() {
+            Ok(identity_key) if peer.check_identity(&identity_key).is_ok() => {
+                info!("Noise channel up with trusted peer {:?}", trusted.name);
+                Ok(())
+            }
+            _ => Err(NoiseError::UntrustedKey {
+                key,
+                peer: trusted.name.clone(),
+            }
+            .into()),
+        },
+        None => {
+            warn!(
+                "Noise channel up with receiver key {}, which is not a trusted peer",
+                key
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Builds the frames opening a transfer: the handshake with the file's metadata, then the
+/// messages following it.
+///
+/// With an `identity`, the handshake is followed by an authentication signed with it. The first
+/// [SNIFF_LEN] bytes of `source` follow, so the receiver can check the type of the file.
+///
+/// `probing` tells the receiver no transfer follows: [Capabilities::DRY_RUN] for dry runs,
+/// [Capabilities::PROBE] for probes of the path, empty for transfers.
+///
+/// `session` is handed to the receiver next, for it to open data connections with, see
+/// [conn_hello](crate::authentication::conn_hello). The metadata of [SendOptions::metadata]
+/// comes next, and the time on this side's clock last, see [crate::stream::clock].
+///
+/// Returns the handshake frame and the frames following it, to be written to the receiver at
+/// once unless a Noise channel is set up in between.
+pub fn handshake_frames(
+    transport_buffer: &mut [u8],
+    file_metadata: &FileMetadata,
+    source: &dyn BlockSource,
+    options: &SendOptions,
+    probing: Capabilities,
+    session: Option<&SessionV1>,
+    identity: Option<&Identity>,
+) -> Result<(Vec<u8>, Vec<u8>), SendFileError> {
     info!("File name: {}", file_metadata.name());
-    info!("File size: {} bytes", file_metadata.size());
+    info!(
+        "File size: {} ({} bytes)",
+        Size(file_metadata.size()),
+        Count(file_metadata.size())
+    );
     info!("File BLAKE3 hash: {:x?}", file_metadata.hash());
+    check_metadata(&options.metadata)?;
+
+    let local = options.local_capabilities();
+    let capabilities = match identity {
+        Some(_) => local,
+        None => local.without(Capabilities::AUTHENTICATION),
+    };
+    let capabilities = capabilities.without(Capabilities::DRY_RUN | Capabilities::PROBE) | probing;
+    // Receivers wait for the session of senders advertising hellos
+    let capabilities = match session {
+        Some(_) => capabilities,
+        None => capabilities.without(Capabilities::CONN_HELLO),
+    };
 
     let handshake_message = SenderMessageV1::Handshake(HandshakeV1 {
         file_name: file_metadata.name(),
         file_hash: &file_metadata.hash(),
         total_size: file_metadata.size(),
-        concurrency,
-        block_size,
+        concurrency: options.concurrency,
+        block_size: options.block_size,
+        software_version: SOFTWARE_VERSION,
+        capabilities,
     });
 
+    // Framed as protocol version 1 until the receiver picks a version, so receivers predating
+    // the negotiation still read it
     let payload_bytes = handshake_message.to_bytes(transport_buffer)?;
-    let handshake_message = transport::attach_headers(payload_bytes);
+    let handshake_frame = transport::attach_text_headers(payload_bytes).into_vec();
+    let mut trailing_frames = Vec::new();
+
+    // Receivers that don't support authentication leave it unread
+    if let Some(identity) = identity {
+        info!("Authenticating with key {}", to_hex(&identity.public_key()));
+        let authentication = SenderMessageV1::Authentication(sign_authentication(
+            identity,
+            file_metadata.hash(),
+            session,
+        ));
+        let payload_bytes = authentication.to_bytes(transport_buffer)?;
+        trailing_frames.extend_from_slice(&transport::attach_text_headers(payload_bytes));
+    }
+
+    // Read errors surface again once the block is requested, the receiver then sees no header
+    let header = source.read_block(0, SNIFF_LEN as u32).unwrap_or_else(|e| {
+        warn!("Failed to read the start of the file: {}", e);
+        Vec::new()
+    });
+    let file_header = SenderMessageV1::FileHeader(FileHeaderV1 {
+        file_hash: file_metadata.hash(),
+        bytes: header,
+    });
+    let payload_bytes = file_header.to_bytes(transport_buffer)?;
+    trailing_frames.extend_from_slice(&transport::attach_text_headers(payload_bytes));
+
+    // Receivers that don't support connection hellos leave it unread
+    if let Some(session) = session {
+        let payload_bytes = SenderMessageV1::Session(*session).to_bytes(transport_buffer)?;
+        trailing_frames.extend_from_slice(&transport::attach_text_headers(payload_bytes));
+    }
+
+    // Receivers that don't negotiate the version leave it unread. Frames are only tagged with a
+    // session there is, directories can only be sent from the version listing their files on,
+    // and only directories are offered with that version
+    let versions = SenderMessageV1::ProtocolVersions(ProtocolVersionsV1 {
+        versions: SUPPORTED_PROTOCOL_VERSIONS
+            .iter()
+            .copied()
+            .filter(|&version| session.is_some() || version < SESSION_FRAMING_PROTOCOL_VERSION)
+            .filter(|&version| match source.bundle() {
+                Some(_) => version >= MULTI_FILE_PROTOCOL_VERSION,
+                None => version != MULTI_FILE_PROTOCOL_VERSION,
+            })
+            .collect(),
+    });
+    let payload_bytes = versions.to_bytes(transport_buffer)?;
+    trailing_frames.extend_from_slice(&transport::attach_text_headers(payload_bytes));
+
+    // Read once the receiver chose the version listing the files
+    if let Some(bundle) = source.bundle() {
+        let file_list = SenderMessageV1::FileList(bundle.file_list());
+        let payload_bytes = file_list
+            .to_bytes(transport_buffer)
+            .map_err(|_| BundleError::ListTooLarge)?;
+        trailing_frames.extend_from_slice(&transport::attach_text_headers(payload_bytes));
+    }
+
+    // Only advertised with metadata, which older receivers then leave unread
+    if capabilities.contains(Capabilities::METADATA) {
+        let metadata = SenderMessageV1::Metadata(MetadataV1 {
+            entries: options.metadata.clone(),
+        });
+        let payload_bytes = metadata.to_bytes(transport_buffer)?;
+        trailing_frames.extend_from_slice(&transport::attach_text_headers(payload_bytes));
+    }
+
+    // Last, so receivers that don't check clocks leave it unread
+    if capabilities.contains(Capabilities::CLOCK) {
+        let clock = SenderMessageV1::Clock(ClockV1 {
+            unix_millis: unix_millis(),
+        });
+        let payload_bytes = clock.to_bytes(transport_buffer)?;
+        trailing_frames.extend_from_slice(&transport::attach_text_headers(payload_bytes));
+    }
 
     debug!(
         "Serialized handshake message: {} bytes",
-        handshake_message.len()
+        handshake_frame.len() + trailing_frames.len()
     );
 
-    info!(
-        "Connecting to reciever at {}",
-        format!("{}:{}", address.0, address.1)
-    );
-    let mut stream = TcpStream::connect(address)?;
-    stream.set_nodelay(true)?;
+    Ok((handshake_frame, trailing_frames))
+}
 
-    info!("Connected to server, Initiating: {:?}", file_path);
-    stream.write_all(&handshake_message)?;
-    stream.flush()?; // Ensure the message is sent immediately
+/// Reads the receiver's first answer on the handshake connection, past the protocol version it
+/// sends first when it negotiates one. Bytes read past the answer are dropped, and frames tagged
+/// with a session other than `session_id` are refused.
+///
+/// # Returns
+///
+/// The answer, and the protocol version of the transfer: the one the receiver chose, or the one
+/// it framed its answer with if it doesn't negotiate.
+pub fn read_handshake_answer<S: Read>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> Result<(ReceiverMessageV1, u8), SendFileError> {
+    let mut filled_len = 0;
+    let mut chosen = None;
+    loop {
+        let result = read_next_payload::<ReceiverMessageV1, _>(stream, buffer, filled_len)?;
+        result.check_session(session_id, result.protocol_version)?;
+        let ReceiverMessageV1::ProtocolVersion(choice) = result.message else {
+            return Ok((result.message, chosen.unwrap_or(result.protocol_version)));
+        };
+        chosen = Some(accept_protocol_version(choice)?);
+        filled_len = match result.next_payload_index {
+            Some(next_idx) => {
+                buffer.copy_within(next_idx..result.total_bytes_read, 0);
+                result.total_bytes_read - next_idx
+            }
+            None => 0,
+        };
+    }
+}
+
+/// Waits on the handshake connection for the receiver's answer to the offer of
+/// `file_metadata`, past the protocol version it chooses first, and checks it against the offer.
+/// Frames tagged with a session other than `session_id` are refused.
+///
+/// Receivers are given [SendOptions::handshake_timeout] to choose the version, then
+/// [OFFER_RESPONSE_TIMEOUT_SECS] to decide on the file. Receivers predating offer responses stay
+/// silent, or go straight to their requests, which are handed back to `stream` to be read
+/// again, like anything read past the answer.
+///
+/// # Returns
+///
+/// The answer, [SendFileError::OfferRejected] or [SendFileError::TypeRejected] if the receiver
+/// refused the file, or [SendFileError::InvalidRequest] if it acknowledged other parameters than
+/// those offered.
+pub(crate) fn await_handshake_answer<S: Connection>(
+    stream: &mut NoiseStream<S>,
+    buffer: &mut [u8],
+    file_metadata: &FileMetadata,
+    options: &SendOptions,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> Result<HandshakeAnswer, SendFileError> {
+    stream.set_read_timeout(Some(options.handshake_timeout))?;
+    let answer = read_handshake_answer_of(stream, buffer, file_metadata, options, session_id);
+    stream.set_read_timeout(None)?;
+    answer
+}
+
+/// Reads the receiver's answer for [await_handshake_answer].
+fn read_handshake_answer_of<S: Connection>(
+    stream: &mut NoiseStream<S>,
+    buffer: &mut [u8],
+    file_metadata: &FileMetadata,
+    options: &SendOptions,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+) -> Result<HandshakeAnswer, SendFileError> {
+    let mut filled_len = 0;
+    let mut chosen = false;
+    loop {
+        let result = match read_next_payload::<ReceiverMessageV1, _>(stream, buffer, filled_len) {
+            Ok(result) => result,
+            Err(e) => {
+                let e = SendFileError::from(e);
+                if chosen || filled_len > 0 || !e.is_timeout() {
+                    return Err(e);
+                }
+                warn!("No answer to the offer, the receiver may predate offer responses");
+                return Ok(HandshakeAnswer::Unanswered);
+            }
+        };
+        result.check_session(session_id, result.protocol_version)?;
+        let end = result.total_bytes_read;
+        let next = result.next_payload_index.unwrap_or(end);
+        match result.message {
+            ReceiverMessageV1::ProtocolVersion(choice) => {
+                accept_protocol_version(choice)?;
+                chosen = true;
+                stream.set_read_timeout(Some(Duration::from_secs(OFFER_RESPONSE_TIMEOUT_SECS)))?;
+            }
+            // Only keep the connection alive while the receiver decides
+            ReceiverMessageV1::Ping(_) | ReceiverMessageV1::Pong(_) if chosen => {}
+            message => match handshake_answer(&message, file_metadata, options) {
+                Some(answer) => {
+                    stream.unread(&buffer[next..end]);
+                    return answer;
+                }
+                None if !chosen => {
+                    debug!(
+                        "Receiver went on without answering the offer: {:?}",
+                        message
+                    );
+                    stream.unread(&buffer[..end]);
+                    return Ok(HandshakeAnswer::Unanswered);
+                }
+                None => {
+                    return Err(SendFileError::UnexpectedMessage {
+                        received: format!("{:?}", message),
+                        expected: String::from("HandshakeAck"),
+                    });
+                }
+            },
+        }
+        buffer.copy_within(next..end, 0);
+        filled_len = end - next;
+    }
+}
+
+/// Returns the answer to the offer of `file_metadata` that `message` is, checked against the
+/// offer, or `None` if it is no answer, see [await_handshake_answer].
+pub(crate) fn handshake_answer(
+    message: &ReceiverMessageV1,
+    file_metadata: &FileMetadata,
+    options: &SendOptions,
+) -> Option<Result<HandshakeAnswer, SendFileError>> {
+    let answer = match message {
+        ReceiverMessageV1::HandshakeAck(ack) => check_handshake_ack(ack, file_metadata, options)
+            .map(|()| HandshakeAnswer::Acknowledged(*ack)),
+        ReceiverMessageV1::HandshakeReject(reject) => {
+            info!("Receiver refused the handshake: {}", reject.reason);
+            Err(SendFileError::handshake_rejected(reject.clone()))
+        }
+        ReceiverMessageV1::OfferResponse(response) if response.accepted => {
+            info!("Receiver accepted the file");
+            Ok(HandshakeAnswer::Accepted)
+        }
+        ReceiverMessageV1::OfferResponse(response) => {
+            Err(SendFileError::rejected(response.reason.clone()))
+        }
+        _ => return None,
+    };
+    Some(answer)
+}
+
+/// Checks that the receiver acknowledged the offer of `file_metadata` with the parameters it
+/// was offered with, and features this sender supports.
+fn check_handshake_ack(
+    ack: &HandshakeAckV1,
+    file_metadata: &FileMetadata,
+    options: &SendOptions,
+) -> Result<(), SendFileError> {
+    let capabilities = ack.capabilities | ack.compression.capability() | ack.checksum.capability();
+    let mismatch = if ack.file_hash != file_metadata.hash() {
+        String::from("another file")
+    } else if ack.block_size != options.block_size {
+        format!(
+            "block size {} rather than {}",
+            ack.block_size, options.block_size
+        )
+    } else if ack.concurrency > options.concurrency {
+        format!(
+            "{} connections, more than {}",
+            ack.concurrency, options.concurrency
+        )
+    } else if !options.local_capabilities().contains(capabilities) {
+        format!(
+            "capabilities this sender lacks ({})",
+            capabilities.without(options.local_capabilities())
+        )
+    } else {
+        info!(
+            "Receiver acknowledged the handshake: compression={}, checksum={}, concurrency={}, capabilities: {}",
+            ack.compression, ack.checksum, ack.concurrency, ack.capabilities
+        );
+        return Ok(());
+    };
+    Err(SendFileError::InvalidRequest(format!(
+        "Receiver acknowledged {}",
+        mismatch
+    )))
+}
+
+/// Checks that the protocol version the receiver chose is one this build speaks.
+pub fn accept_protocol_version(choice: ProtocolVersionV1) -> Result<u8, SendFileError> {
+    if !SUPPORTED_PROTOCOL_VERSIONS.contains(&choice.version) {
+        return Err(SendFileError::NoCommonProtocolVersion(vec![choice.version]));
+    }
+    info!("Receiver chose protocol version {}", choice.version);
+    Ok(choice.version)
+}
+
+/// Connects to the first of `hosts` that accepts a connection on `port`, trying each address a
+/// host resolves to in turn.
+///
+/// Returns the connection and the host it was made to, or the error of the last attempt if none
+/// could be reached.
+fn connect_first<'a>(
+    hosts: impl Iterator<Item = &'a str>,
+    port: u16,
+) -> Result<(TcpStream, &'a str), SendFileError> {
+    let mut last_error = None;
+    for host in hosts {
+        info!("Connecting to reciever at {}:{}", host, port);
+        let addrs = match (host, port).to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("Failed to resolve {}: {}", host, e);
+                last_error = Some(e);
+                continue;
+            }
+        };
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT_SECS)) {
+                Ok(stream) => return Ok((stream, host)),
+                Err(e) => {
+                    warn!("Failed to connect to {}: {}", addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No receiver address"))
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        capabilities::{ChecksumAlgorithm, CompressionCodec},
+        transport::{HandshakeRejectV1, RejectReasonV1, RequestV1},
+    };
+
+    #[test]
+    fn test_connect_first_falls_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Nothing listens on 127.0.0.2, the connection is refused
+        let (stream, host) = connect_first(["127.0.0.2", "127.0.0.1"].into_iter(), port).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert_eq!(host, "127.0.0.1");
+
+        assert!(connect_first(["127.0.0.2"].into_iter(), port).is_err());
+        assert!(connect_first(std::iter::empty(), port).is_err());
+    }
+
+    #[test]
+    fn test_bind_listener_with_backlog() {
+        let listener = bind_listener(("localhost", 0), 1).unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(address.ip().is_loopback());
+
+        let stream = TcpStream::connect(address).unwrap();
+        let (accepted, peer) = listener.accept().unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        drop(accepted);
+
+        assert!(bind_listener(Vec::<SocketAddr>::new().as_slice(), 1).is_err());
+    }
+
+    #[test]
+    fn test_await_handshake_answer() {
+        let file_metadata = FileMetadata::new(String::from("file.bin"), 1024, [7; 32]);
+        let options = SendOptions {
+            handshake_timeout: Duration::from_millis(200),
+            ..SendOptions::default()
+        };
+        let ack = HandshakeAckV1 {
+            file_hash: file_metadata.hash(),
+            capabilities: Capabilities::RECEIPT,
+            compression: CompressionCodec::Gzip,
+            checksum: ChecksumAlgorithm::Crc32,
+            block_size: options.block_size,
+            concurrency: 1,
+        };
+        let frame = |message: ReceiverMessageV1| {
+            let mut buffer = [0u8; 256];
+            transport::attach_headers(message.to_bytes(&mut buffer).unwrap()).into_vec()
+        };
+        let version = frame(ReceiverMessageV1::ProtocolVersion(ProtocolVersionV1 {
+            version: transport::BINARY_FRAMING_PROTOCOL_VERSION,
+        }));
+        let request = frame(ReceiverMessageV1::Request(RequestV1 {
+            file_hash: file_metadata.hash(),
+            seq: 0,
+        }));
+        let await_answer = |answer: Vec<u8>| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut receiver = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            receiver.write_all(&answer).unwrap();
+            let mut stream = NoiseStream::new(listener.accept().unwrap().0);
+            let mut buffer = [0u8; 1024];
+            let answer =
+                await_handshake_answer(&mut stream, &mut buffer, &file_metadata, &options, None);
+            // Bytes past the answer are left to read
+            stream
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            let mut rest = vec![0u8; request.len()];
+            let rest = match stream.read_exact(&mut rest) {
+                Ok(()) => rest,
+                Err(_) => Vec::new(),
+            };
+            (answer, rest)
+        };
+
+        let acknowledged = [
+            version.as_slice(),
+            &frame(ReceiverMessageV1::HandshakeAck(ack)),
+            &request,
+        ]
+        .concat();
+        let (answer, rest) = await_answer(acknowledged);
+        assert_eq!(answer.unwrap(), HandshakeAnswer::Acknowledged(ack));
+        assert_eq!(rest, request);
+
+        // Receivers predating offer responses are left to request blocks
+        let (answer, rest) = await_answer(request.clone());
+        assert_eq!(answer.unwrap(), HandshakeAnswer::Unanswered);
+        assert_eq!(rest, request);
+        let (answer, _) = await_answer(Vec::new());
+        assert_eq!(answer.unwrap(), HandshakeAnswer::Unanswered);
+
+        let rejected = [
+            version.as_slice(),
+            &frame(ReceiverMessageV1::HandshakeReject(HandshakeRejectV1 {
+                file_hash: file_metadata.hash(),
+                reason: RejectReasonV1::DiskFull,
+                message: String::from("Not enough space"),
+            })),
+        ]
+        .concat();
+        let (answer, _) = await_answer(rejected);
+        assert!(
+            matches!(answer, Err(SendFileError::OfferRejected(message)) if message == "Not enough space")
+        );
 
-    Ok(file_metadata.hash())
+        let other_block_size = HandshakeAckV1 {
+            block_size: options.block_size * 2,
+            ..ack
+        };
+        let mismatched = [
+            version.as_slice(),
+            &frame(ReceiverMessageV1::HandshakeAck(other_block_size)),
+        ]
+        .concat();
+        let (answer, _) = await_answer(mismatched);
+        assert!(matches!(answer, Err(SendFileError::InvalidRequest(_))));
+    }
 }
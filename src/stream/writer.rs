@@ -0,0 +1,138 @@
+//! Writes that give up on a stalled or cancelled transfer.
+//!
+//! A data frame can be several megabytes. Written with a single blocking `write_all`, a receiver
+//! that stops reading pins the sending thread until the kernel gives up on the connection, and a
+//! cancellation is only noticed once the frame is out. [ChunkedWriter] writes in small chunks
+//! instead, checking for cancellation between them and failing once no byte could be written for
+//! the stall timeout.
+
+use std::{
+    io::{self, ErrorKind, Write},
+    time::{Duration, Instant},
+};
+
+use crate::stream::handle::TransferControl;
+
+/// Largest number of bytes handed to the socket in one write.
+pub const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Write timeout to set on the socket, bounding how long a blocked write goes without checking
+/// for cancellation.
+pub const WRITE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default time a receiver may go without accepting any data before the connection is dropped.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// [Write] adapter splitting writes into [WRITE_CHUNK_SIZE] chunks and aborting on cancellation
+/// or stall.
+///
+/// The wrapped socket should have a write timeout (see [WRITE_POLL_INTERVAL]), otherwise a
+/// blocked write is never interrupted.
+pub struct ChunkedWriter<'a, W: Write> {
+    inner: W,
+    control: &'a TransferControl,
+    stall_timeout: Duration,
+}
+
+impl<'a, W: Write> ChunkedWriter<'a, W> {
+    /// Wraps `inner`, failing writes once `control` is cancelled or nothing could be written for
+    /// `stall_timeout`.
+    pub fn new(inner: W, control: &'a TransferControl, stall_timeout: Duration) -> Self {
+        Self {
+            inner,
+            control,
+            stall_timeout,
+        }
+    }
+}
+
+impl<W: Write> ChunkedWriter<'_, W> {
+    /// Runs `operation` on the wrapped writer until it neither times out nor is interrupted,
+    /// failing once the transfer is cancelled or the operation stalled for the stall timeout.
+    fn retry<T>(&mut self, mut operation: impl FnMut(&mut W) -> io::Result<T>) -> io::Result<T> {
+        let started = Instant::now();
+        loop {
+            if self.control.is_cancelled() {
+                return Err(io::Error::other("Transfer cancelled"));
+            }
+            match operation(&mut self.inner) {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if started.elapsed() >= self.stall_timeout {
+                        return Err(io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!("Receiver accepted no data for {:?}", self.stall_timeout),
+                        ));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk = &buf[..buf.len().min(WRITE_CHUNK_SIZE)];
+        self.retry(|inner| inner.write(chunk))
+    }
+
+    /// Retried like writes, for writers holding back bytes until they are flushed, such as
+    /// [NoiseStream](crate::noise::NoiseStream).
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(W::flush)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts `capacity` bytes, then behaves like a socket whose write timeout expires.
+    struct StallingWriter {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for StallingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.capacity - self.written.len());
+            if len == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_chunked_writes_and_stall() {
+        let control = TransferControl::new();
+        let data = vec![7u8; 3 * WRITE_CHUNK_SIZE + 10];
+
+        let mut inner = StallingWriter {
+            written: Vec::new(),
+            capacity: data.len(),
+        };
+        let mut writer = ChunkedWriter::new(&mut inner, &control, Duration::from_secs(1));
+        assert_eq!(writer.write(&data).unwrap(), WRITE_CHUNK_SIZE);
+        writer.write_all(&data[WRITE_CHUNK_SIZE..]).unwrap();
+        assert_eq!(inner.written, data);
+
+        let mut inner = StallingWriter {
+            written: Vec::new(),
+            capacity: 100,
+        };
+        let mut writer = ChunkedWriter::new(&mut inner, &control, Duration::from_millis(50));
+        let error = writer.write_all(&data).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        control.cancel();
+        let mut writer = ChunkedWriter::new(Vec::new(), &control, Duration::from_secs(1));
+        assert!(writer.write_all(&data).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! OpenTelemetry traces of transfers, to analyze their latencies and failures with the
+//! observability stack of a fleet.
+//!
+//! Every transfer is traced as a `send` or `receive` span, with child spans for its `handshake`,
+//! each data `connection`, and each run of up to [BLOCKS_PER_SPAN] blocks transferred over a
+//! connection (`blocks`, or `verify` for a batch of resumed blocks checked with the sender).
+//! Spans of a failed step carry the error as their status.
+//!
+//! Spans are exported over OTLP/HTTP once [start_otlp_export] installed an exporter, with
+//! `--otlp`. Without the `otel` cargo feature every span is a no-op.
+
+use std::{fmt::Display, time::SystemTime};
+
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    global,
+    trace::{Span as _, Status, TraceContextExt, Tracer as _},
+    Context, ContextGuard, KeyValue,
+};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+/// Number of blocks of a connection traced in a single `blocks` span.
+pub const BLOCKS_PER_SPAN: u32 = 64;
+
+/// Name of the tracer, and of the service spans are exported for.
+#[cfg(feature = "otel")]
+const TRACER_NAME: &str = "sendfile";
+
+/// A span, ended when dropped.
+#[derive(Debug, Default)]
+pub struct TraceSpan {
+    #[cfg(feature = "otel")]
+    context: Context,
+}
+
+/// Where new spans are attached: under a span of the transfer, or at the root of a new trace
+/// for `TraceContext::default()`.
+#[derive(Debug, Clone, Default)]
+pub struct TraceContext {
+    #[cfg(feature = "otel")]
+    context: Context,
+}
+
+/// Makes a span the parent of the spans started on this thread with
+/// [TraceContext::current] until dropped, see [TraceContext::in_span].
+struct Attached {
+    #[cfg(feature = "otel")]
+    _guard: ContextGuard,
+}
+
+impl TraceSpan {
+    /// Starts the span of a transfer, at the root of a new trace.
+    pub fn root(name: &'static str) -> Self {
+        TraceContext::default().child(name)
+    }
+
+    /// Returns a span that traces nothing, for transfers not traced yet.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Returns where the children of this span are attached.
+    pub fn context(&self) -> TraceContext {
+        TraceContext {
+            #[cfg(feature = "otel")]
+            context: self.context.clone(),
+        }
+    }
+
+    /// Sets the attribute `key` of the span to `value`.
+    pub fn set_str(&self, key: &'static str, value: &str) {
+        #[cfg(feature = "otel")]
+        self.context
+            .span()
+            .set_attribute(KeyValue::new(key, value.to_string()));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// Sets the attribute `key` of the span to `value`.
+    pub fn set_int(&self, key: &'static str, value: u64) {
+        #[cfg(feature = "otel")]
+        self.context
+            .span()
+            .set_attribute(KeyValue::new(key, value as i64));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// Marks the span failed with the error of `result`, if it is one.
+    pub fn record<T, E: Display>(&self, result: &Result<T, E>) {
+        #[cfg(feature = "otel")]
+        if let Err(e) = result {
+            self.context.span().set_status(Status::error(e.to_string()));
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = result;
+    }
+
+    fn attach(&self) -> Attached {
+        Attached {
+            #[cfg(feature = "otel")]
+            _guard: self.context.clone().attach(),
+        }
+    }
+}
+
+impl Drop for TraceSpan {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        self.context.span().end();
+    }
+}
+
+impl TraceContext {
+    /// Returns where spans are attached on this thread: under the span [Self::in_span] runs,
+    /// at the root of a new trace outside of one.
+    pub fn current() -> Self {
+        Self {
+            #[cfg(feature = "otel")]
+            context: Context::current(),
+        }
+    }
+
+    /// Starts a span named `name` here.
+    pub fn child(&self, name: &'static str) -> TraceSpan {
+        #[cfg(feature = "otel")]
+        {
+            let span = global::tracer(TRACER_NAME).start_with_context(name, &self.context);
+            TraceSpan {
+                context: self.context.with_span(span),
+            }
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = name;
+            TraceSpan::none()
+        }
+    }
+
+    /// Runs `run` in a span named `name` started here, marked failed if `run` fails. Spans
+    /// started with [Self::current] meanwhile on this thread are its children.
+    pub fn in_span<T, E: Display>(
+        &self,
+        name: &'static str,
+        run: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let span = self.child(name);
+        let result = {
+            let _attached = span.attach();
+            run()
+        };
+        span.record(&result);
+        result
+    }
+}
+
+/// Traces the blocks transferred over a connection in `blocks` spans of up to
+/// [BLOCKS_PER_SPAN] blocks, each from the start of its first block to the end of its last.
+/// Spans of blocks not done yet are ended when dropped.
+#[derive(Debug)]
+pub struct BlockSpans {
+    parent: TraceContext,
+    /// When the first block of the current span started, `None` if no block did.
+    started: Option<SystemTime>,
+    blocks: u32,
+    bytes: u64,
+}
+
+impl BlockSpans {
+    /// Traces blocks under the span [TraceContext::in_span] runs on this thread.
+    pub fn new() -> Self {
+        Self {
+            parent: TraceContext::current(),
+            started: None,
+            blocks: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Notes that a block starts being transferred.
+    pub fn begin(&mut self) {
+        self.started.get_or_insert_with(SystemTime::now);
+    }
+
+    /// Notes that the block last begun was transferred, with `bytes` of data.
+    pub fn done(&mut self, bytes: u64) {
+        self.blocks += 1;
+        self.bytes += bytes;
+        if self.blocks >= BLOCKS_PER_SPAN {
+            self.flush();
+        }
+    }
+
+    /// Ends the span of the blocks done so far, if there are any.
+    fn flush(&mut self) {
+        let started = self.started.take();
+        let blocks = std::mem::take(&mut self.blocks);
+        let bytes = std::mem::take(&mut self.bytes);
+        #[cfg(feature = "otel")]
+        if let Some(started) = started
+            && blocks > 0
+        {
+            let tracer = global::tracer(TRACER_NAME);
+            tracer
+                .span_builder("blocks")
+                .with_start_time(started)
+                .with_attributes([
+                    KeyValue::new("blocks", blocks as i64),
+                    KeyValue::new("bytes", bytes as i64),
+                ])
+                .start_with_context(&tracer, &self.parent.context)
+                .end();
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = (started, blocks, bytes, &self.parent);
+    }
+}
+
+impl Default for BlockSpans {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for BlockSpans {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Exporter installed by [start_otlp_export], which sends the spans still buffered when
+/// dropped.
+#[cfg(feature = "otel")]
+pub struct OtlpExport {
+    provider: SdkTracerProvider,
+}
+
+/// Exports the spans of this process over OTLP/HTTP, in batches from a background thread, to
+/// the collector named by the standard `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) variable, `http://localhost:4318` by default.
+///
+/// # Returns
+///
+/// The exporter, to drop before the process exits so no span is lost, or an error if the
+/// endpoint is invalid.
+#[cfg(feature = "otel")]
+pub fn start_otlp_export() -> Result<OtlpExport, opentelemetry_otlp::ExporterBuildError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(TRACER_NAME).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(OtlpExport { provider })
+}
+
+#[cfg(feature = "otel")]
+impl Drop for OtlpExport {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            log::warn!("Failed to export traces: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spans_pass_results_through() {
+        let transfer = TraceSpan::root("send");
+        let result: Result<u32, String> = transfer.context().in_span("connection", || {
+            let mut blocks = BlockSpans::new();
+            for _ in 0..BLOCKS_PER_SPAN + 1 {
+                blocks.begin();
+                blocks.done(4096);
+            }
+            // The first span ended with its last block, the second one is still open
+            assert_eq!(blocks.blocks, 1);
+            assert_eq!(blocks.bytes, 4096);
+            assert!(blocks.started.is_some());
+            Ok(7)
+        });
+        assert_eq!(result, Ok(7));
+
+        let failed: Result<(), String> =
+            TraceContext::current().in_span("handshake", || Err(String::from("refused")));
+        transfer.record(&failed);
+        assert_eq!(failed, Err(String::from("refused")));
+    }
+}
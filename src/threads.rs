@@ -0,0 +1,156 @@
+//! Sizing, naming and CPU pinning of worker threads.
+//!
+//! Every thread spawned by the crate is named `sendfile-<role>-<index>` (e.g. `sendfile-recv-3`,
+//! `sendfile-hash-1`) so transfers can be told apart in `top -H`, debuggers and profilers.
+
+use std::thread;
+
+use log::warn;
+
+/// Configuration for the CPU-bound workers (hashing, compression and decompression).
+#[derive(Debug, Clone, Default)]
+pub struct WorkerOptions {
+    /// Number of hashing worker threads. `None` uses the available parallelism.
+    pub threads: Option<usize>,
+    /// CPUs the workers are pinned to, assigned round-robin by worker index.
+    /// Empty leaves scheduling to the OS.
+    pub cpus: Vec<usize>,
+}
+
+impl WorkerOptions {
+    /// Returns the number of hashing worker threads to use (at least 1).
+    pub fn thread_count(&self) -> usize {
+        self.threads
+            .unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1)
+    }
+
+    /// Pins the calling thread to the CPU assigned to worker `index`, if pinning is configured.
+    ///
+    /// Failure to pin is logged and otherwise ignored, the worker keeps running unpinned.
+    pub fn pin_current_thread(&self, index: usize) {
+        if self.cpus.is_empty() {
+            return;
+        }
+
+        let cpu = self.cpus[index % self.cpus.len()];
+        if let Err(e) = pin_current_thread_to(cpu) {
+            warn!(
+                "Failed to pin thread {:?} to CPU {}: {}",
+                thread::current().name(),
+                cpu,
+                e
+            );
+        }
+    }
+}
+
+/// Number of CPUs a CPU list may refer to, `CPU_SETSIZE` on Linux. Higher indices can't be pinned
+/// to and are rejected when parsing.
+#[cfg(target_os = "linux")]
+const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const MAX_CPUS: usize = 1024;
+
+/// Returns the name of a crate thread for the given role and index, e.g. `sendfile-recv-3`.
+pub fn thread_name(role: &str, index: usize) -> String {
+    format!("sendfile-{role}-{index}")
+}
+
+/// Parses a CPU list such as `0-3,6,8-9` into individual CPU indices.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+
+    for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |value: &str| {
+            let cpu = value
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| format!("invalid CPU index {value:?}: {e}"))?;
+            if cpu >= MAX_CPUS {
+                return Err(format!(
+                    "CPU index {cpu} is out of range (max {})",
+                    MAX_CPUS - 1
+                ));
+            }
+            Ok(cpu)
+        };
+
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("invalid CPU range {part:?}"));
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(parse(part)?),
+        }
+    }
+
+    if cpus.is_empty() {
+        return Err(String::from("CPU list is empty"));
+    }
+
+    Ok(cpus)
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread_to(cpu: usize) -> Result<(), std::io::Error> {
+    if cpu >= MAX_CPUS {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("CPU index {cpu} is out of range"),
+        ));
+    }
+
+    // SAFETY: `cpu_set_t` is plain data, zero-initialised is an empty set, and `cpu` was bounds
+    // checked against CPU_SETSIZE above.
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread_to(_cpu: usize) -> Result<(), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU pinning is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,6").unwrap(), vec![0, 1, 2, 3, 6]);
+        assert_eq!(parse_cpu_list(" 2 , 4-5 ").unwrap(), vec![2, 4, 5]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("0-18446744073709551615").is_err());
+        assert!(parse_cpu_list(&MAX_CPUS.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_thread_count_is_at_least_one() {
+        let options = WorkerOptions {
+            threads: Some(0),
+            cpus: vec![],
+        };
+        assert_eq!(options.thread_count(), 1);
+    }
+}
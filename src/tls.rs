@@ -0,0 +1,715 @@
+//! Optional TLS layer of the handshake and data connections (`--tls`).
+//!
+//! Both peers hold a certificate signed by a certificate authority they trust, loaded with
+//! [TlsConfig::load]. The sender connects to the receiver as a TLS client, checking the
+//! receiver's certificate against the authority and the host name it connected to, and the
+//! receiver requires a client certificate signed by the same authority.
+//!
+//! Data connections don't go through the authority again: each side pins the exact certificate
+//! its peer presented on the handshake connection (see [TlsPeer]), so a data connection can only
+//! be opened by the host that started the transfer, even among hosts of the same authority.
+//!
+//! TLS wraps a connection from its first byte, so it can't be negotiated in the handshake: both
+//! peers must be started with `--tls`. Once it is on, peers advertise
+//! [Capabilities::ENCRYPTION](crate::capabilities::Capabilities::ENCRYPTION), for strict mode to
+//! require it.
+
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{
+        pem::{self, PemObject},
+        CertificateDer, PrivateKeyDer, ServerName, UnixTime,
+    },
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        WebPkiClientVerifier,
+    },
+    ClientConfig, ClientConnection, ConnectionCommon, DigitallySignedStruct, DistinguishedName,
+    RootCertStore, ServerConfig, ServerConnection, SignatureScheme, StreamOwned,
+};
+use thiserror::Error;
+
+use crate::{secrets::Secret, stream::preconnected::Connection};
+
+/// Name of the directory holding the default certificate, key and authority, inside the
+/// sendfile config directory.
+const TLS_DIR_NAME: &str = "tls";
+
+/// Errors that can occur while loading TLS credentials or securing a connection.
+#[derive(Error, Debug)]
+pub enum TlsError {
+    /// A PEM file could not be read or parsed.
+    #[error("Failed to load {path:?}: {source}")]
+    Pem { path: PathBuf, source: pem::Error },
+    /// A certificate file holds no certificate.
+    #[error("No certificate in {0:?}")]
+    NoCertificates(PathBuf),
+    /// The credentials were refused, or the TLS handshake with the peer failed.
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+    /// The certificate authority could not be used to verify peers.
+    #[error("Invalid certificate authority: {0}")]
+    Verifier(#[from] rustls::server::VerifierBuilderError),
+    /// The TLS handshake failed, or the connection failed during it.
+    #[error("TLS handshake failed: {0}")]
+    Io(#[from] io::Error),
+    /// The host the sender connects to is not a valid name to check the certificate against.
+    #[error("Invalid server name {0:?}")]
+    InvalidServerName(String),
+    /// The peer of a handshake connection presented no certificate to pin.
+    #[error("Peer presented no certificate")]
+    MissingPeerCertificate,
+}
+
+/// Certificate, private key and certificate authority a peer secures its connections with.
+///
+/// The rustls configurations of handshake connections are built once, so their caches of TLS
+/// sessions are shared by all connections.
+pub struct TlsConfig {
+    provider: Arc<CryptoProvider>,
+    /// Certificate chain presented to the peer, starting with this host's certificate.
+    certificates: Vec<CertificateDer<'static>>,
+    key: Secret<PrivateKeyDer<'static>>,
+    /// Authorities peer certificates of handshake connections must be signed by.
+    roots: Arc<RootCertStore>,
+    /// Configuration of the handshake connections this peer opens.
+    client: Arc<ClientConfig>,
+    /// Configuration of the handshake connections the peer opens.
+    server: Arc<ServerConfig>,
+}
+
+impl Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Leaves out the private key
+        f.debug_struct("TlsConfig")
+            .field("certificates", &self.certificates.len())
+            .field("roots", &self.roots.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsConfig {
+    /// Loads the PEM encoded certificate chain at `cert_path`, its private key at `key_path`
+    /// and the certificate authorities peers must be signed by at `ca_path`.
+    pub fn load(cert_path: &Path, key_path: &Path, ca_path: &Path) -> Result<Self, TlsError> {
+        let pem_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| TlsError::Pem { path, source }
+        };
+
+        let certificates = read_certificates(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(pem_error(key_path))?;
+        let mut roots = RootCertStore::empty();
+        for certificate in read_certificates(ca_path)? {
+            roots.add(certificate)?;
+        }
+        Self::new(certificates, key, roots)
+    }
+
+    /// Builds a configuration from credentials already in memory.
+    pub fn new(
+        certificates: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        roots: RootCertStore,
+    ) -> Result<Self, TlsError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let roots = Arc::new(roots);
+        // Refuses a key that doesn't match the certificate now rather than on every connection
+        let client = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots.clone())
+            .with_client_auth_cert(certificates.clone(), key.clone_key())?;
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build()?;
+        let server = server_config(&provider, verifier, &certificates, &key)?;
+        Ok(Self {
+            provider,
+            certificates,
+            key: Secret::new(key),
+            roots,
+            client: Arc::new(client),
+            server,
+        })
+    }
+
+    /// Secures the handshake connection to the receiver at `host`, whose certificate must be
+    /// signed by the authority and issued for `host`.
+    pub fn connect(&self, tcp: TcpStream, host: &str) -> Result<MaybeTlsStream, TlsError> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|_| TlsError::InvalidServerName(host.to_string()))?;
+        connect_with(&self.client, tcp, name)
+    }
+
+    /// Secures the handshake connection of a sender, which must present a certificate signed by
+    /// the authority.
+    pub fn accept(&self, tcp: TcpStream) -> Result<MaybeTlsStream, TlsError> {
+        accept_with(&self.server, tcp)
+    }
+}
+
+/// Peer of a secured handshake connection, whose data connections must present the same
+/// certificate.
+///
+/// It is kept for the whole session, so data connections share the rustls configurations built
+/// when it is pinned: once the first data connection is secured, the next ones, and the ones
+/// opened again after a connection is lost, resume its TLS session rather than running a full
+/// handshake.
+#[derive(Debug, Clone)]
+pub struct TlsPeer {
+    /// Configuration of the data connections this peer opens.
+    client: Arc<ClientConfig>,
+    /// Configuration of the data connections the peer opens.
+    server: Arc<ServerConfig>,
+}
+
+impl TlsPeer {
+    /// Pins the certificate the peer presented on `handshake`.
+    pub fn pin(config: Arc<TlsConfig>, handshake: &MaybeTlsStream) -> Result<Self, TlsError> {
+        let certificate = handshake
+            .peer_certificate()
+            .ok_or(TlsError::MissingPeerCertificate)?;
+        let verifier = Arc::new(PinnedCertificate {
+            certificate: certificate.clone(),
+            provider: config.provider.clone(),
+        });
+        let client = ClientConfig::builder_with_provider(config.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_client_auth_cert(config.certificates.clone(), config.key.expose().clone_key())?;
+        let server = server_config(
+            &config.provider,
+            verifier,
+            &config.certificates,
+            config.key.expose(),
+        )?;
+        Ok(Self {
+            client: Arc::new(client),
+            server,
+        })
+    }
+
+    /// Secures a data connection opened to the peer.
+    pub fn connect(&self, tcp: TcpStream) -> Result<MaybeTlsStream, TlsError> {
+        // The pinned certificate is checked instead of the name
+        let name = ServerName::IpAddress(tcp.peer_addr()?.ip().into());
+        connect_with(&self.client, tcp, name)
+    }
+
+    /// Secures a data connection the peer opened.
+    pub fn accept(&self, tcp: TcpStream) -> Result<MaybeTlsStream, TlsError> {
+        accept_with(&self.server, tcp)
+    }
+}
+
+/// Builds the configuration of connections the peer opens, whose certificates are checked by
+/// `verifier`.
+fn server_config(
+    provider: &Arc<CryptoProvider>,
+    verifier: Arc<dyn ClientCertVerifier>,
+    certificates: &[CertificateDer<'static>],
+    key: &PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let config = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certificates.to_vec(), key.clone_key())?;
+    Ok(Arc::new(config))
+}
+
+fn connect_with(
+    config: &Arc<ClientConfig>,
+    tcp: TcpStream,
+    name: ServerName<'static>,
+) -> Result<MaybeTlsStream, TlsError> {
+    let connection = ClientConnection::new(config.clone(), name)?;
+    let mut stream = StreamOwned::new(connection, tcp);
+    complete_handshake(&mut stream.conn, &stream.sock)?;
+    Ok(MaybeTlsStream::Client(Box::new(stream)))
+}
+
+fn accept_with(config: &Arc<ServerConfig>, tcp: TcpStream) -> Result<MaybeTlsStream, TlsError> {
+    let connection = ServerConnection::new(config.clone())?;
+    let mut stream = StreamOwned::new(connection, tcp);
+    complete_handshake(&mut stream.conn, &stream.sock)?;
+    Ok(MaybeTlsStream::Server(Box::new(stream)))
+}
+
+/// Accepts a single certificate, whoever signed it.
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertificate {
+    fn check(&self, end_entity: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(())
+        } else {
+            Err(rustls::Error::General(String::from(
+                "Certificate differs from the one of the handshake connection",
+            )))
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for PinnedCertificate {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        ServerCertVerifier::verify_tls12_signature(self, message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        ServerCertVerifier::verify_tls13_signature(self, message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        ServerCertVerifier::supported_verify_schemes(self)
+    }
+}
+
+/// Connection to a peer, secured with TLS or not.
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    /// Connection this peer opened.
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+    /// Connection the peer opened.
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl MaybeTlsStream {
+    /// Returns the underlying TCP connection, to tune it or shut it down.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Plain(tcp) => tcp,
+            Self::Client(stream) => &stream.sock,
+            Self::Server(stream) => &stream.sock,
+        }
+    }
+
+    /// Returns the certificate the peer presented, `None` for plain connections.
+    pub fn peer_certificate(&self) -> Option<&CertificateDer<'static>> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Client(stream) => stream.conn.peer_certificates()?.first(),
+            Self::Server(stream) => stream.conn.peer_certificates()?.first(),
+        }
+    }
+
+    /// Returns whether data from the peer can be read, without waiting for it. A closed
+    /// connection has none.
+    pub fn has_pending_data(&mut self) -> io::Result<bool> {
+        match self {
+            Self::Plain(tcp) => {
+                tcp.set_nonblocking(true)?;
+                let peeked = tcp.peek(&mut [0u8; 1]);
+                tcp.set_nonblocking(false)?;
+                match peeked {
+                    Ok(len) => Ok(len > 0),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                    Err(e) => Err(e),
+                }
+            }
+            Self::Client(stream) => has_plaintext(&mut stream.conn, &stream.sock),
+            Self::Server(stream) => has_plaintext(&mut stream.conn, &stream.sock),
+        }
+    }
+}
+
+impl Read for MaybeTlsStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(tcp) => tcp.read(buffer),
+            Self::Client(stream) => stream.read(buffer),
+            Self::Server(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for MaybeTlsStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(tcp) => tcp.write(buffer),
+            Self::Client(stream) => stream.write(buffer),
+            Self::Server(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(tcp) => tcp.flush(),
+            Self::Client(stream) => stream.flush(),
+            Self::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Connection for MaybeTlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+}
+
+impl Drop for MaybeTlsStream {
+    /// Tells the peer the connection ends here rather than being cut, so its last read reports
+    /// the end of the stream.
+    fn drop(&mut self) {
+        match self {
+            Self::Plain(_) => {}
+            Self::Client(stream) => close(&mut stream.conn, &stream.sock),
+            Self::Server(stream) => close(&mut stream.conn, &stream.sock),
+        }
+    }
+}
+
+/// Returns the default path of the TLS file `name` (`cert.pem`, `key.pem` or `ca.pem`),
+/// `<config dir>/sendfile/tls/<name>`.
+pub fn default_tls_path(name: &str) -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sendfile").join(TLS_DIR_NAME).join(name))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem_error = |source| TlsError::Pem {
+        path: path.to_path_buf(),
+        source,
+    };
+    let certificates = CertificateDer::pem_file_iter(path)
+        .map_err(pem_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error)?;
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certificates)
+}
+
+/// Runs the TLS handshake to completion, so a peer that is refused fails here rather than on
+/// its first message.
+fn complete_handshake<D>(
+    connection: &mut ConnectionCommon<D>,
+    mut tcp: &TcpStream,
+) -> Result<(), TlsError> {
+    while connection.is_handshaking() {
+        connection.complete_io(&mut tcp)?;
+    }
+    Ok(())
+}
+
+/// Reads the records that arrived on `tcp` without waiting for more, and returns whether they
+/// hold data for the application.
+fn has_plaintext<D>(connection: &mut ConnectionCommon<D>, mut tcp: &TcpStream) -> io::Result<bool> {
+    tcp.set_nonblocking(true)?;
+    let received = loop {
+        match connection.read_tls(&mut tcp) {
+            Ok(0) => break Ok(()),
+            Ok(_) => {
+                if let Err(e) = connection.process_new_packets() {
+                    break Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    tcp.set_nonblocking(false)?;
+    received?;
+    let state = connection
+        .process_new_packets()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(state.plaintext_bytes_to_read() > 0)
+}
+
+fn close<D>(connection: &mut ConnectionCommon<D>, mut tcp: &TcpStream) {
+    connection.send_close_notify();
+    while connection.wants_write() {
+        if connection.write_tls(&mut tcp).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::TcpListener, thread};
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, Issuer, KeyPair};
+    use rustls::{pki_types::PrivatePkcs8KeyDer, HandshakeKind};
+
+    use super::*;
+
+    fn authority() -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+    }
+
+    /// Issues a certificate for localhost signed by `issuer`, trusting `authority`.
+    fn config(issuer: &Issuer<'_, KeyPair>, authority: &CertificateDer<'static>) -> Arc<TlsConfig> {
+        let key = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec![String::from("localhost")])
+            .unwrap()
+            .signed_by(&key, issuer)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(authority.clone()).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key.serialize_der()).into();
+        Arc::new(TlsConfig::new(vec![certificate.der().clone()], key, roots).unwrap())
+    }
+
+    /// Connects `client` to `server` over loopback, returning both ends once secured.
+    fn secure_pair(
+        server: impl FnOnce(TcpStream) -> Result<MaybeTlsStream, TlsError> + Send,
+        client: impl FnOnce(TcpStream) -> Result<MaybeTlsStream, TlsError>,
+    ) -> (
+        Result<MaybeTlsStream, TlsError>,
+        Result<MaybeTlsStream, TlsError>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            let accepted = scope.spawn(move || server(listener.accept().unwrap().0));
+            let connected = client(TcpStream::connect(address).unwrap());
+            (accepted.join().unwrap(), connected)
+        })
+    }
+
+    #[test]
+    fn test_handshake_and_pinned_data_connections() {
+        let authority = authority();
+        let sender = config(&authority, authority.der());
+        let receiver = config(&authority, authority.der());
+
+        let (accepted, connected) = secure_pair(
+            |tcp| receiver.accept(tcp),
+            |tcp| sender.connect(tcp, "localhost"),
+        );
+        let (mut receiver_end, mut sender_end) = (accepted.unwrap(), connected.unwrap());
+        assert!(!receiver_end.has_pending_data().unwrap());
+        sender_end.write_all(b"handshake").unwrap();
+        let mut buffer = [0u8; 9];
+        receiver_end.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"handshake");
+
+        // Data connections go the other way, each side checking the certificate it saw
+        let sender_peer = TlsPeer::pin(sender.clone(), &sender_end).unwrap();
+        let receiver_peer = TlsPeer::pin(receiver.clone(), &receiver_end).unwrap();
+        let (accepted, connected) = secure_pair(
+            |tcp| sender_peer.accept(tcp),
+            |tcp| receiver_peer.connect(tcp),
+        );
+        let (mut sender_data, mut receiver_data) = (accepted.unwrap(), connected.unwrap());
+        receiver_data.write_all(b"request").unwrap();
+        receiver_data.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(sender_data.has_pending_data().unwrap());
+        let mut buffer = [0u8; 7];
+        sender_data.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"request");
+
+        // Closing ends the peer's stream cleanly
+        drop(receiver_data);
+        assert_eq!(sender_data.read(&mut buffer).unwrap(), 0);
+    }
+
+    /// Returns whether the TLS session of `stream` was resumed rather than negotiated anew.
+    fn is_resumed(stream: &MaybeTlsStream) -> bool {
+        let kind = match stream {
+            MaybeTlsStream::Plain(_) => None,
+            MaybeTlsStream::Client(stream) => stream.conn.handshake_kind(),
+            MaybeTlsStream::Server(stream) => stream.conn.handshake_kind(),
+        };
+        kind == Some(HandshakeKind::Resumed)
+    }
+
+    #[test]
+    fn test_data_connections_resume_the_tls_session() {
+        let authority = authority();
+        let sender = config(&authority, authority.der());
+        let receiver = config(&authority, authority.der());
+        let (accepted, connected) = secure_pair(
+            |tcp| receiver.accept(tcp),
+            |tcp| sender.connect(tcp, "localhost"),
+        );
+        let (mut receiver_end, mut sender_end) = (accepted.unwrap(), connected.unwrap());
+        receiver_end.write_all(b"answer").unwrap();
+        let mut buffer = [0u8; 6];
+        sender_end.read_exact(&mut buffer).unwrap();
+        let sender_peer = TlsPeer::pin(sender.clone(), &sender_end).unwrap();
+        let receiver_peer = TlsPeer::pin(receiver.clone(), &receiver_end).unwrap();
+
+        let mut resumed = Vec::new();
+        for _ in 0..3 {
+            let (accepted, connected) = secure_pair(
+                |tcp| sender_peer.accept(tcp),
+                |tcp| receiver_peer.connect(tcp),
+            );
+            let (mut sender_data, mut receiver_data) = (accepted.unwrap(), connected.unwrap());
+            // The session tickets come with the first data the receiver reads
+            sender_data.write_all(b"block").unwrap();
+            let mut buffer = [0u8; 5];
+            receiver_data.read_exact(&mut buffer).unwrap();
+            assert_eq!(is_resumed(&sender_data), is_resumed(&receiver_data));
+            resumed.push(is_resumed(&receiver_data));
+        }
+        assert_eq!(resumed, [false, true, true]);
+
+        // A handshake connection opened again resumes too, and still pins the peer
+        let (accepted, connected) = secure_pair(
+            |tcp| receiver.accept(tcp),
+            |tcp| sender.connect(tcp, "localhost"),
+        );
+        let (receiver_end, _sender_end) = (accepted.unwrap(), connected.unwrap());
+        assert!(is_resumed(&receiver_end));
+        TlsPeer::pin(receiver.clone(), &receiver_end).unwrap();
+    }
+
+    #[test]
+    fn test_untrusted_peers_are_refused() {
+        let authority = authority();
+        let receiver = config(&authority, authority.der());
+        let other_authority = self::authority();
+        let stranger = config(&other_authority, authority.der());
+
+        let (accepted, connected) = secure_pair(
+            |tcp| receiver.accept(tcp),
+            |tcp| stranger.connect(tcp, "localhost"),
+        );
+        assert!(accepted.is_err());
+        drop(connected);
+
+        // The certificate must be issued for the host connected to
+        let sender = config(&authority, authority.der());
+        let (_, connected) = secure_pair(
+            |tcp| receiver.accept(tcp),
+            |tcp| sender.connect(tcp, "127.0.0.1"),
+        );
+        assert!(connected.is_err());
+
+        // Another host of the same authority can't open data connections
+        let (accepted, connected) = secure_pair(
+            |tcp| receiver.accept(tcp),
+            |tcp| sender.connect(tcp, "localhost"),
+        );
+        let sender_peer = TlsPeer::pin(sender.clone(), &connected.unwrap()).unwrap();
+        let impostor = config(&authority, authority.der());
+        let impostor_peer = TlsPeer::pin(impostor, &accepted.unwrap()).unwrap();
+        let (accepted, _) = secure_pair(
+            |tcp| sender_peer.accept(tcp),
+            |tcp| impostor_peer.connect(tcp),
+        );
+        assert!(accepted.is_err());
+    }
+
+    #[test]
+    fn test_load_pem_files() {
+        let dir = std::env::temp_dir().join(format!("sendfile_tls_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let authority = authority();
+        let key = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec![String::from("localhost")])
+            .unwrap()
+            .signed_by(&key, &authority)
+            .unwrap();
+        fs::write(dir.join("cert.pem"), certificate.pem()).unwrap();
+        fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+        fs::write(dir.join("ca.pem"), authority.pem()).unwrap();
+
+        TlsConfig::load(
+            &dir.join("cert.pem"),
+            &dir.join("key.pem"),
+            &dir.join("ca.pem"),
+        )
+        .unwrap();
+        assert!(matches!(
+            TlsConfig::load(
+                &dir.join("key.pem"),
+                &dir.join("key.pem"),
+                &dir.join("ca.pem")
+            ),
+            Err(TlsError::NoCertificates(_))
+        ));
+        assert!(matches!(
+            TlsConfig::load(
+                &dir.join("cert.pem"),
+                &dir.join("missing.pem"),
+                &dir.join("ca.pem")
+            ),
+            Err(TlsError::Pem { .. })
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
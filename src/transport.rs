@@ -1,15 +1,70 @@
 //! Transport layer for the custom file transfer protocol.
 
+use std::{collections::BTreeMap, ops::Range};
+
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-/// The current version of the file transfer protocol.
-pub const CURRENT_PROTOCOL_VERSION: u8 = 1;
+use crate::{
+    capabilities::{Capabilities, ChecksumAlgorithm, CompressionCodec},
+    stream::checksum::block_checksum,
+};
+
+pub mod relay;
+#[cfg(unix)]
+pub mod uds;
+pub mod ws;
+
+/// The current version of the file transfer protocol, whose messages are framed with a binary
+/// [FrameHeader] tagged with the id of their session.
+pub const CURRENT_PROTOCOL_VERSION: u8 = HANDSHAKE_ACK_PROTOCOL_VERSION;
+/// The protocol version framing messages with text headers (`Ver: `, `Len: `), still read by
+/// [read_next_payload](crate::connection::read_next_payload).
+pub const TEXT_FRAMING_PROTOCOL_VERSION: u8 = 1;
+/// The protocol version framing messages with a binary [FrameHeader], without a session id.
+pub const BINARY_FRAMING_PROTOCOL_VERSION: u8 = 2;
+/// The protocol version tagging every frame with the id of the [SessionV1] it belongs to, see
+/// [FRAME_FLAG_SESSION]. Only negotiated along with a session.
+pub const SESSION_FRAMING_PROTOCOL_VERSION: u8 = 3;
+/// The protocol version sending a directory as one transfer, its files listed in a [FileListV1]
+/// and their blocks interleaved on the same data connections, see [crate::stream::bundle].
+/// Framed as [SESSION_FRAMING_PROTOCOL_VERSION], and only offered by senders of a directory, so
+/// receivers choosing a later version still know a [FileListV1] follows.
+pub const MULTI_FILE_PROTOCOL_VERSION: u8 = 4;
+/// The protocol version answering every offer explicitly: with a [HandshakeAckV1] carrying the
+/// parameters the receiver settled on, or a [HandshakeRejectV1] with the reason, instead of an
+/// [OfferResponseV1]. Framed as [SESSION_FRAMING_PROTOCOL_VERSION], and offered by senders of
+/// files and directories alike.
+pub const HANDSHAKE_ACK_PROTOCOL_VERSION: u8 = 5;
+/// Protocol versions this build speaks, oldest first. Peers negotiating the version pick the
+/// highest both support, see [choose_protocol_version].
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u8] = &[
+    TEXT_FRAMING_PROTOCOL_VERSION,
+    BINARY_FRAMING_PROTOCOL_VERSION,
+    SESSION_FRAMING_PROTOCOL_VERSION,
+    MULTI_FILE_PROTOCOL_VERSION,
+    HANDSHAKE_ACK_PROTOCOL_VERSION,
+];
 /// The maximum size of a file block (4 MB).
 pub const MAX_BLOCK_SIZE: u32 = 4 * 1024 * 1024; // 4 MB
 /// The maximum size of a message, including overhead for headers and metadata.
 pub const MAX_MESSAGE_SIZE: usize = MAX_BLOCK_SIZE as usize + 128; // Max block size plus some overhead for headers and metadata
 
+/// The maximum number of block hashes carried by a single [BlockHashesV1] message.
+pub const MAX_BLOCK_HASHES_PER_MESSAGE: u32 = 4096;
+
+/// The maximum number of messages coalesced in a single [BatchV1].
+pub const MAX_BATCH_MESSAGES: usize = 64;
+
+/// The maximum number of runs of blocks listed by a single [HaveBlocksV1].
+pub const MAX_HAVE_BLOCKS_RUNS: usize = 4096;
+
+/// The maximum number of blocks of a window of a [PushRangeV1].
+pub const MAX_PUSH_WINDOW: u32 = 1024;
+
+/// The maximum number of blocks requested by a single [RequestRangeV1].
+pub const MAX_REQUEST_RANGE_BLOCKS: u32 = 1024;
+
 /// The string prefix for the version header.
 pub const VERSION_HEADER_PREFIX_STR: &str = "Ver: ";
 /// The string prefix for the length header.
@@ -46,6 +101,120 @@ pub const LENGTH_HEADER_SIZE: usize = LENGTH_HEADER_PREFIX.len() + 10 + MESSAGE_
 pub const MAX_HEADER_SIZE: usize =
     VERSION_HEADER_SIZE + LENGTH_HEADER_SIZE + MESSAGE_DELIMITER.len() + 64;
 
+/// Bytes every binary frame starts with. Never the start of a text header, which tells the two
+/// framings apart.
+pub const FRAME_MAGIC: [u8; 4] = [0xF5, b'S', b'F', b'P'];
+/// The size of a binary [FrameHeader] on the wire.
+pub const FRAME_HEADER_SIZE: usize = 14;
+/// Flag of a [FrameHeader] followed by the [SESSION_ID_SIZE] bytes of the id of the session the
+/// message belongs to, set on every frame of [SESSION_FRAMING_PROTOCOL_VERSION] and never before.
+pub const FRAME_FLAG_SESSION: u8 = 0x01;
+/// Flags of a [FrameHeader] understood by this version.
+pub const KNOWN_FRAME_FLAGS: u8 = FRAME_FLAG_SESSION;
+/// The size of a session id, see [SessionV1].
+pub const SESSION_ID_SIZE: usize = 16;
+
+/// Fixed-size header framing every message from protocol version 2 on.
+///
+/// Laid out as, with integers in big-endian order:
+/// - Magic: 4 bytes, [FRAME_MAGIC]
+/// - Version: 1 byte
+/// - Flags: 1 byte, [FRAME_FLAG_SESSION], frames with flags outside [KNOWN_FRAME_FLAGS] are
+///   rejected
+/// - Payload length: 4 bytes, not counting the session id
+/// - Header checksum: 4 bytes, CRC-32 of the 10 bytes before it
+///
+/// With [FRAME_FLAG_SESSION], the session id follows the header, then the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Protocol version the payload is encoded with.
+    pub version: u8,
+    /// [FRAME_FLAG_SESSION] or none.
+    pub flags: u8,
+    /// Length of the payload following the header.
+    pub payload_length: u32,
+}
+
+/// Errors decoding a [FrameHeader].
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FrameHeaderError {
+    /// The header does not start with [FRAME_MAGIC].
+    #[error("Frame does not start with the frame magic")]
+    BadMagic,
+    /// The header checksum does not match its content.
+    #[error("Header checksum mismatch: expected {expected:#010x}, found {found:#010x}")]
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// The header has flags this version does not know.
+    #[error("Unknown frame flags {0:#04x}")]
+    UnknownFlags(u8),
+}
+
+impl FrameHeader {
+    /// Creates the header of a [BINARY_FRAMING_PROTOCOL_VERSION] payload of `payload_length`
+    /// bytes.
+    pub fn new(payload_length: u32) -> Self {
+        Self {
+            version: BINARY_FRAMING_PROTOCOL_VERSION,
+            flags: 0,
+            payload_length,
+        }
+    }
+
+    /// Creates the header of a [SESSION_FRAMING_PROTOCOL_VERSION] payload of `payload_length`
+    /// bytes, to be followed by its session id.
+    pub fn with_session(payload_length: u32) -> Self {
+        Self {
+            version: SESSION_FRAMING_PROTOCOL_VERSION,
+            flags: FRAME_FLAG_SESSION,
+            payload_length,
+        }
+    }
+
+    /// Returns whether the header is followed by a session id.
+    pub fn has_session(&self) -> bool {
+        self.flags & FRAME_FLAG_SESSION != 0
+    }
+
+    /// Encodes the header as it is sent on the wire.
+    pub fn encode(&self) -> [u8; FRAME_HEADER_SIZE] {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header[..4].copy_from_slice(&FRAME_MAGIC);
+        header[4] = self.version;
+        header[5] = self.flags;
+        header[6..10].copy_from_slice(&self.payload_length.to_be_bytes());
+        let checksum = block_checksum(&header[..10]);
+        header[10..].copy_from_slice(&checksum.to_be_bytes());
+        header
+    }
+
+    /// Decodes a header read from the wire.
+    ///
+    /// # Returns
+    ///
+    /// The header, or a [FrameHeaderError] if it is not a binary frame header, is corrupted or
+    /// uses flags this version doesn't know. The version is not checked.
+    pub fn decode(header: &[u8; FRAME_HEADER_SIZE]) -> Result<Self, FrameHeaderError> {
+        if header[..4] != FRAME_MAGIC {
+            return Err(FrameHeaderError::BadMagic);
+        }
+        let expected = block_checksum(&header[..10]);
+        let found = u32::from_be_bytes(header[10..].try_into().unwrap());
+        if found != expected {
+            return Err(FrameHeaderError::ChecksumMismatch { expected, found });
+        }
+        let flags = header[5];
+        if flags & !KNOWN_FRAME_FLAGS != 0 {
+            return Err(FrameHeaderError::UnknownFlags(flags));
+        }
+
+        Ok(Self {
+            version: header[4],
+            flags,
+            payload_length: u32::from_be_bytes(header[6..10].try_into().unwrap()),
+        })
+    }
+}
+
 /// Errors that can occur in the transport layer.
 #[derive(Error, Debug)]
 pub enum TransportError {
@@ -84,6 +253,48 @@ pub struct HandshakeV1<'a> {
 
     /// Size of each data block in bytes, used for splitting the file into chunks and for progress tracking.
     pub block_size: u32,
+
+    /// Version of the sender's software (e.g. "0.1.0"), used in diagnostics on the receiver side.
+    pub software_version: &'a str,
+
+    /// Optional features supported by the sender, used for feature negotiation.
+    pub capabilities: Capabilities,
+}
+
+/// Software version reported for senders whose handshake predates
+/// [HandshakeV1::software_version].
+pub const UNKNOWN_SOFTWARE_VERSION: &str = "unknown";
+
+/// Handshake of senders predating [HandshakeV1::software_version] and
+/// [HandshakeV1::capabilities], which end the message before them.
+#[derive(Debug, Deserialize)]
+struct LegacyHandshakeV1<'a> {
+    file_hash: &'a [u8],
+    total_size: u64,
+    concurrency: u16,
+    file_name: &'a str,
+    block_size: u32,
+}
+
+/// First message of a legacy sender, the handshake being the first variant of
+/// [SenderMessageV1].
+#[derive(Debug, Deserialize)]
+enum LegacySenderMessageV1<'a> {
+    Handshake(#[serde(borrow)] LegacyHandshakeV1<'a>),
+}
+
+impl<'a> From<LegacyHandshakeV1<'a>> for HandshakeV1<'a> {
+    fn from(legacy: LegacyHandshakeV1<'a>) -> Self {
+        HandshakeV1 {
+            file_hash: legacy.file_hash,
+            total_size: legacy.total_size,
+            concurrency: legacy.concurrency,
+            file_name: legacy.file_name,
+            block_size: legacy.block_size,
+            software_version: UNKNOWN_SOFTWARE_VERSION,
+            capabilities: Capabilities::empty(),
+        }
+    }
 }
 
 /// Data chunk message sent by the sender.
@@ -91,11 +302,12 @@ pub struct HandshakeV1<'a> {
 pub struct DataV1<'a> {
     /// Sequence number of the chunk being sent, used for tracking which chunks have been sent and received.
     pub seq: u32,
-    /// Checksum of the chunk data, used for integrity verification on the receiver side.
+    /// Checksum of the chunk data, used for integrity verification on the receiver side. Computed
+    /// with the negotiated [ChecksumAlgorithm], see [AlgorithmsV1].
     pub checksum: u32,
     /// BLAKE3 hash of the file this data belongs to.
     pub file_hash: &'a [u8],
-    /// Whether the data is compressed using gzip.
+    /// Whether the data is compressed, with the negotiated [CompressionCodec].
     pub compressed: bool,
     /// Actual chunk data being sent, with length specified in the Len header of the message.
     pub data: &'a [u8],
@@ -110,6 +322,271 @@ pub struct SenderErrorV1 {
     pub message: String,
 }
 
+/// BLAKE3 hashes of a run of blocks, sent by the sender in response to a
+/// [BlockHashesRequestV1].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHashesV1 {
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Sequence number of the first block in `hashes`.
+    pub start_seq: u32,
+    /// BLAKE3 hash of each block, in order. May be shorter than requested at the end of the file.
+    pub hashes: Vec<[u8; 32]>,
+}
+
+/// Signature by the sender's identity over the offered file, sent on the handshake connection
+/// right after the handshake when the sender advertises authentication.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthenticationV1 {
+    /// BLAKE3 hash of the offered file.
+    pub file_hash: [u8; 32],
+    /// Time of signing, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Ed25519 public key identifying the sender.
+    pub sender_key: [u8; 32],
+    /// Ed25519 signature by `sender_key` over the other fields.
+    pub signature: Vec<u8>,
+}
+
+/// Answer to a data request for a block the sender could not read, even after retrying.
+///
+/// The connection stays open, so the receiver can go on with other blocks or abort. Receivers
+/// predating this message fail the connection, as they did on the generic read error it
+/// replaces.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockUnreadableV1 {
+    /// Sequence number of the requested block.
+    pub seq: u32,
+    /// Description of the read error.
+    pub reason: String,
+}
+
+/// First bytes of the offered file, sent on the handshake connection after the handshake (and
+/// the authentication, if any) so the receiver can check the file type before accepting it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHeaderV1 {
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Up to [SNIFF_LEN](crate::file::content_type::SNIFF_LEN) bytes from the start of the file.
+    pub bytes: Vec<u8>,
+}
+
+/// Session of a transfer, sent on the handshake connection after the file header. Receivers open
+/// every data connection of the transfer with a [ConnHelloV1] derived from it, and from
+/// [SESSION_FRAMING_PROTOCOL_VERSION] on both peers tag every later frame with its id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionV1 {
+    /// Random identifier of the transfer.
+    pub session_id: [u8; SESSION_ID_SIZE],
+    /// Random secret only given out on the handshake connection, proving data connections come
+    /// from the receiver the file was offered to.
+    pub token: [u8; 32],
+}
+
+/// Protocol versions supported by the sender, sent on the handshake connection after the session
+/// when the sender advertises
+/// [Capabilities::VERSION_NEGOTIATION](crate::capabilities::Capabilities::VERSION_NEGOTIATION).
+/// The receiver answers with the [ProtocolVersionV1] it picked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolVersionsV1 {
+    /// Supported versions, oldest first.
+    pub versions: Vec<u8>,
+}
+
+/// Message of the Noise handshake setting up an encrypted channel, see
+/// [Capabilities::NOISE](crate::capabilities::Capabilities::NOISE). Sent by both peers, on the
+/// handshake connection right after the handshake, and first thing on every data connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoiseHandshakeV1 {
+    /// The Noise handshake message, see [crate::noise].
+    pub payload: Vec<u8>,
+}
+
+/// Blinded key of a sender pairing with the receiver's one-time code, sent first thing on the
+/// handshake connection, before the handshake, see [crate::pairing].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingV1 {
+    /// The sender's SPAKE2 element.
+    pub element: [u8; 32],
+}
+
+/// Proof that the sender derived the receiver's session key, and so used its code. Answers the
+/// [PairingReplyV1].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingConfirmV1 {
+    /// Sender's confirmation of the session key.
+    pub confirmation: [u8; 32],
+}
+
+/// Key/value metadata of the offered file, sent on the handshake connection after the protocol
+/// versions when the sender advertises
+/// [Capabilities::METADATA](crate::capabilities::Capabilities::METADATA).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataV1 {
+    /// Entries in key order, see [crate::file::metadata].
+    pub entries: BTreeMap<String, String>,
+}
+
+/// Announcement of a block sent as UDP datagrams in answer to a [UdpRequestV1], sent on the
+/// data connection before the datagrams, see [crate::stream::udp].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdpBlockV1 {
+    /// Sequence number of the block.
+    pub seq: u32,
+    /// Length of the frame the datagrams encode, the answer a [RequestV1] would have had.
+    pub length: u32,
+    /// Number of datagrams sent, repair datagrams included. None if the sender can't send any,
+    /// the block is then requested over TCP.
+    pub datagrams: u32,
+}
+
+/// Answer to a `SparseRequest` for a block that only holds zeros, sent instead of its data when
+/// both peers support [Capabilities::ZERO_BLOCKS](crate::capabilities::Capabilities::ZERO_BLOCKS).
+/// The receiver writes the zeros itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ZeroBlockV1 {
+    /// Sequence number of the block.
+    pub seq: u32,
+}
+
+/// Heartbeat sent by either peer on a connection it has been waiting on for a while, answered
+/// with a [PongV1], see [crate::stream::heartbeat].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PingV1 {
+    /// Sequence number of the heartbeat.
+    pub seq: u32,
+}
+
+/// Answer to a [PingV1].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PongV1 {
+    /// Sequence number of the answered heartbeat.
+    pub seq: u32,
+}
+
+/// Timed message of a probe, sent on the handshake connection once the receiver is ready, see
+/// [Capabilities::PROBE](crate::capabilities::Capabilities::PROBE).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeV1<'a> {
+    /// Sequence number of the probe.
+    pub seq: u32,
+    /// Whether the receiver acknowledges the probe with a [ProbeAckV1].
+    pub echo: bool,
+    /// Filler bytes, counted by the receiver to measure throughput.
+    pub payload: &'a [u8],
+}
+
+/// A file of a directory sent as one transfer, see [FileListV1].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntryV1 {
+    /// Path of the file relative to the directory, its components separated by `/`.
+    pub path: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// BLAKE3 hash of the file.
+    pub hash: [u8; 32],
+}
+
+/// Files of a directory sent as one transfer, sent on the handshake connection after the
+/// protocol versions by senders offering [MULTI_FILE_PROTOCOL_VERSION], read when the receiver
+/// chose it or a later version. The handshake offers the directory, its file hash being the
+/// hash of this list, see [Bundle::hash](crate::stream::bundle::Bundle::hash).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileListV1 {
+    /// Files in the order their blocks are numbered in.
+    pub files: Vec<FileEntryV1>,
+}
+
+/// Block of a file of a directory, answering a [FileRequestV1].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDataV1<'a> {
+    /// Index of the file in the [FileListV1].
+    pub file_index: u32,
+    /// The block, its sequence number counted from the start of the file and its file hash
+    /// being the hash of the directory.
+    #[serde(borrow)]
+    pub data: DataV1<'a>,
+}
+
+/// Directive of the sender on the control channel to stop the transfer, see
+/// [crate::stream::control]. The receiver keeps the blocks received so far and fails the
+/// transfer with the reason.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AbortV1 {
+    /// BLAKE3 hash of the file being transferred.
+    pub file_hash: [u8; 32],
+    /// Why the transfer is aborted, for people to read.
+    pub reason: String,
+}
+
+/// Directive of the sender on the control channel to request blocks no faster than a rate, see
+/// [crate::stream::control].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleV1 {
+    /// BLAKE3 hash of the file being transferred.
+    pub file_hash: [u8; 32],
+    /// Rate in bytes per second, `None` lifts the limit.
+    pub bytes_per_second: Option<u64>,
+}
+
+/// Notice that a peer cancelled the transfer (e.g. with Ctrl-C), sent to peers advertising
+/// [Capabilities::CANCEL](crate::capabilities::Capabilities::CANCEL) on its connections before
+/// closing them, see [crate::stream::cancel]. The peer stops at once instead of retrying, keeping
+/// the blocks received so far.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CancelV1 {
+    /// BLAKE3 hash of the file being transferred.
+    pub file_hash: [u8; 32],
+    /// Why the transfer is cancelled, for people to read.
+    pub reason: String,
+}
+
+/// Time on the sender's clock, sent on the handshake connection after every other message
+/// following the handshake when the sender advertises
+/// [Capabilities::CLOCK](crate::capabilities::Capabilities::CLOCK), so the receiver can measure
+/// the skew between their clocks, see [crate::stream::clock].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClockV1 {
+    /// Milliseconds since the Unix epoch when the handshake was written.
+    pub unix_millis: u64,
+}
+
+/// Error response of a sender that expects to serve the request later, sent instead of a
+/// [SenderErrorV1] to receivers advertising
+/// [Capabilities::RETRY_AFTER](crate::capabilities::Capabilities::RETRY_AFTER): the receiver
+/// asks again once `retry_after_ms` has passed instead of backing off blindly, see
+/// [crate::stream::retry_after].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryAfterV1 {
+    /// Error code, [UNAVAILABLE_CODE](crate::stream::retry_after::UNAVAILABLE_CODE) for now.
+    pub code: u16,
+    /// Error message.
+    pub message: String,
+    /// Milliseconds after which the request may succeed.
+    pub retry_after_ms: u64,
+}
+
+/// Identity of a peer paired by `sendfile pair`, sent by both peers through the Noise channel
+/// keyed by the pairing, the sender first, see [crate::pairing]. Only peers running
+/// `sendfile pair` send it, so no capability announces it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntroductionV1 {
+    /// Ed25519 public key of the peer's identity, the X25519 form of which is the channel's key.
+    pub public_key: [u8; 32],
+    /// Alias the peer suggests it is stored under, e.g. its host name.
+    pub name: String,
+}
+
+/// End of a data connection, sent by the receiver once it has nothing more to request on it and
+/// answered by the sender, when both advertise
+/// [Capabilities::GOODBYE](crate::capabilities::Capabilities::GOODBYE). Both then close the
+/// connection, which the sender no longer waits on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GoodbyeV1 {
+    /// BLAKE3 hash of the file being transferred.
+    pub file_hash: [u8; 32],
+}
+
 /// Messages sent from the Sender (the one sending the file) to the Receiver.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SenderMessageV1<'a> {
@@ -124,6 +601,78 @@ pub enum SenderMessageV1<'a> {
 
     /// A response to a VerifyBlock request, indicating if the block checksum matches.
     VerifyResponse(VerifyResponseV1),
+
+    /// A response to a BlockHashesRequest with the hashes of the requested blocks.
+    BlockHashes(BlockHashesV1),
+
+    /// Proof of the sender's identity, sent on the handshake connection after the handshake.
+    Authentication(AuthenticationV1),
+
+    /// The requested block could not be read from the sender's disk.
+    BlockUnreadable(BlockUnreadableV1),
+
+    /// First bytes of the offered file, sent on the handshake connection.
+    FileHeader(FileHeaderV1),
+
+    /// A timed message measuring the path to the receiver.
+    Probe(#[serde(borrow)] ProbeV1<'a>),
+
+    /// Session of the transfer, sent on the handshake connection.
+    Session(SessionV1),
+
+    /// Protocol versions the sender supports, sent on the handshake connection.
+    ProtocolVersions(ProtocolVersionsV1),
+
+    /// A step of the Noise handshake setting up an encrypted channel.
+    NoiseHandshake(NoiseHandshakeV1),
+
+    /// Opening of a pairing with the receiver's one-time code.
+    Pairing(PairingV1),
+
+    /// Confirmation that the sender holds the pairing's session key.
+    PairingConfirm(PairingConfirmV1),
+
+    /// Key/value metadata of the offered file, sent on the handshake connection.
+    Metadata(MetadataV1),
+
+    /// A block about to be sent as UDP datagrams.
+    UdpBlock(UdpBlockV1),
+
+    /// The requested block only holds zeros.
+    ZeroBlock(ZeroBlockV1),
+
+    /// A heartbeat on an idle connection.
+    Ping(PingV1),
+
+    /// Answer to a heartbeat of the receiver.
+    Pong(PongV1),
+
+    /// Files of the offered directory, sent on the handshake connection.
+    FileList(FileListV1),
+
+    /// A block of a file of the offered directory.
+    FileData(#[serde(borrow)] FileDataV1<'a>),
+
+    /// Directive to stop the transfer, sent on the control channel.
+    Abort(AbortV1),
+
+    /// Directive to limit the rate blocks are requested at, sent on the control channel.
+    Throttle(ThrottleV1),
+
+    /// The sender cancelled the transfer and is closing its connections.
+    Cancel(CancelV1),
+
+    /// Time on the sender's clock, sent on the handshake connection.
+    Clock(ClockV1),
+
+    /// The sender can't serve the request now, and asks to be retried later.
+    RetryAfter(RetryAfterV1),
+
+    /// Identity of a peer running `sendfile pair HOST CODE`.
+    Introduction(IntroductionV1),
+
+    /// The sender is done with the data connection, answering the receiver's goodbye.
+    Goodbye(GoodbyeV1),
 }
 
 impl<'a> SenderMessageV1<'a> {
@@ -137,6 +686,25 @@ impl<'a> SenderMessageV1<'a> {
     pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, TransportError> {
         Ok(postcard::from_bytes(bytes)?)
     }
+
+    /// Deserializes the first message of a sender, which opens the handshake connection.
+    ///
+    /// Handshakes of legacy senders, ending before [HandshakeV1::software_version], are read with
+    /// [UNKNOWN_SOFTWARE_VERSION] and no capabilities, so the receiver reports what they lack
+    /// instead of failing to parse them.
+    pub fn from_first_bytes(bytes: &'a [u8]) -> Result<Self, postcard::Error> {
+        match postcard::from_bytes(bytes) {
+            Err(postcard::Error::DeserializeUnexpectedEnd) => {
+                match postcard::from_bytes::<LegacySenderMessageV1>(bytes) {
+                    Ok(LegacySenderMessageV1::Handshake(legacy)) => {
+                        Ok(SenderMessageV1::Handshake(legacy.into()))
+                    }
+                    Err(_) => Err(postcard::Error::DeserializeUnexpectedEnd),
+                }
+            }
+            result => result,
+        }
+    }
 }
 
 /// Request message sent by the receiver to request a data chunk.
@@ -150,11 +718,13 @@ pub struct RequestV1 {
     pub seq: u32,
 }
 
-/// Progress update message sent by the receiver.
+/// Progress update message sent by the receiver, regularly on the control channel, see
+/// [crate::stream::control].
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ProgressV1 {
     /// BLAKE3 hash of the file being tracked.
     pub file_hash: [u8; 32],
+    /// Bytes of the file received (or reused) so far.
     pub bytes_received: u64,
 }
 
@@ -196,6 +766,399 @@ pub struct VerifyResponseV1 {
     pub valid: bool,
 }
 
+/// Request for the BLAKE3 hashes of a run of blocks, sent by the receiver to look blocks up in
+/// its local block store before downloading them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHashesRequestV1 {
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Sequence number of the first block.
+    pub start_seq: u32,
+    /// Number of blocks, at most [MAX_BLOCK_HASHES_PER_MESSAGE].
+    pub count: u32,
+}
+
+/// Signed receipt sent by the receiver on the handshake connection once the file has been
+/// written and its hash verified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceiptV1 {
+    /// BLAKE3 hash of the file that was delivered.
+    pub file_hash: [u8; 32],
+    /// Size of the delivered file in bytes.
+    pub bytes: u64,
+    /// Time of delivery, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Ed25519 public key identifying the receiver.
+    pub receiver_key: [u8; 32],
+    /// Ed25519 signature by `receiver_key` over the other fields.
+    pub signature: Vec<u8>,
+}
+
+/// Answer of a receiver that acknowledged
+/// [Capabilities::RECEIPT](crate::capabilities::Capabilities::RECEIPT) but will not sign a
+/// receipt for the verified file, sent on the handshake connection in place of the [ReceiptV1].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoReceiptV1 {
+    /// BLAKE3 hash of the file that was delivered.
+    pub file_hash: [u8; 32],
+    /// Why no receipt is signed, e.g. receipts are disabled or the identity is unavailable.
+    pub reason: String,
+}
+
+/// Answer of the receiver to the offered file, sent on the handshake connection before any data
+/// connection is opened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfferResponseV1 {
+    /// BLAKE3 hash of the offered file.
+    pub file_hash: [u8; 32],
+    /// Whether the receiver will download the file.
+    pub accepted: bool,
+    /// Why the file was rejected, empty if it was accepted.
+    pub reason: String,
+}
+
+/// Acknowledgement of a [ProbeV1] asking for one, also sent with sequence number 0 once the
+/// receiver is ready for probes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeAckV1 {
+    /// Sequence number of the acknowledged probe.
+    pub seq: u32,
+    /// Payload bytes received since the receiver became ready, this probe included.
+    pub bytes_received: u64,
+}
+
+/// First message of every data connection, naming the session the connection belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnHelloV1 {
+    /// Identifier of the session, from its [SessionV1].
+    pub session_id: [u8; 16],
+    /// BLAKE3 MAC of the session id keyed with the session token, see
+    /// [conn_hello](crate::authentication::conn_hello).
+    pub auth: [u8; 32],
+}
+
+/// Protocol version picked by the receiver among the [ProtocolVersionsV1] of the sender, sent on
+/// the handshake connection before any other answer. Every later message of the transfer, on
+/// any connection, is framed with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolVersionV1 {
+    /// The chosen version.
+    pub version: u8,
+}
+
+/// Answer of the receiver to a [PairingV1], with its own blinded key and the proof that it
+/// derived the session key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingReplyV1 {
+    /// The receiver's SPAKE2 element.
+    pub element: [u8; 32],
+    /// Receiver's confirmation of the session key.
+    pub confirmation: [u8; 32],
+}
+
+/// Request for a block to be sent as UDP datagrams, sent on a data connection when the sender
+/// advertises [Capabilities::UDP_FEC](crate::capabilities::Capabilities::UDP_FEC). The sender
+/// answers with a [UdpBlockV1].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdpRequestV1 {
+    /// BLAKE3 hash of the file being requested.
+    pub file_hash: [u8; 32],
+    /// Sequence number of the requested block.
+    pub seq: u32,
+    /// UDP port of the receiver the datagrams are sent to, at the address of the connection.
+    pub port: u16,
+    /// Random tag of the receiver's socket, carried by the datagrams.
+    pub tag: u64,
+}
+
+/// Request for a block of a file of a directory, sent instead of a [RequestV1] from
+/// [MULTI_FILE_PROTOCOL_VERSION] on. The sender answers with a [FileDataV1].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRequestV1 {
+    /// BLAKE3 hash of the offered directory, see [FileListV1].
+    pub file_hash: [u8; 32],
+    /// Index of the file in the [FileListV1].
+    pub file_index: u32,
+    /// Sequence number of the block, counted from the start of the file.
+    pub seq: u32,
+}
+
+/// The blocks of a range a resuming receiver already holds, sent to senders advertising
+/// [Capabilities::BLOCK_BITMAP](crate::capabilities::Capabilities::BLOCK_BITMAP) instead of
+/// verifying them one by one. The sender answers every other block of the range, in order, as
+/// if each had been requested.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HaveBlocksV1 {
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Sequence number of the first block of the range.
+    pub start_seq: u32,
+    /// Number of blocks of the range.
+    pub count: u32,
+    /// Lengths of alternating runs of held and missing blocks, starting with held blocks (so
+    /// a range starting with a missing block starts with an empty run), at most
+    /// [MAX_HAVE_BLOCKS_RUNS]. Blocks past the last run are missing.
+    pub runs: Vec<u32>,
+    /// Whether missing blocks only holding zeros are answered with a `ZeroBlock`, as for a
+    /// [ReceiverMessageV1::SparseRequest].
+    pub sparse: bool,
+}
+
+impl HaveBlocksV1 {
+    /// Lists the blocks of `range` for which `held` is true.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_hash` - BLAKE3 hash of the file.
+    /// * `range` - Sequence numbers of the blocks.
+    /// * `held` - Whether the receiver holds a block.
+    /// * `sparse` - Whether missing blocks may be answered with a `ZeroBlock`.
+    ///
+    /// # Returns
+    ///
+    /// The message, covering a prefix of `range` if listing all of it would take more than
+    /// [MAX_HAVE_BLOCKS_RUNS] runs. Its `count` tells how many blocks it covers.
+    pub fn new(
+        file_hash: [u8; 32],
+        range: Range<u32>,
+        held: impl Fn(u32) -> bool,
+        sparse: bool,
+    ) -> Self {
+        let mut runs = Vec::new();
+        let mut run_held = true;
+        let mut run_len = 0;
+        let mut end = range.start;
+        while end < range.end {
+            if held(end) != run_held {
+                if runs.len() + 2 > MAX_HAVE_BLOCKS_RUNS {
+                    break;
+                }
+                runs.push(run_len);
+                run_held = !run_held;
+                run_len = 0;
+            }
+            run_len += 1;
+            end += 1;
+        }
+        if run_held && run_len > 0 {
+            runs.push(run_len);
+        }
+        Self {
+            file_hash,
+            start_seq: range.start,
+            count: end - range.start,
+            runs,
+            sparse,
+        }
+    }
+
+    /// Returns the ranges of sequence numbers of the blocks the receiver is missing, or `None`
+    /// if the message is malformed: too many runs, or runs longer than the range.
+    pub fn missing(&self) -> Option<Vec<Range<u32>>> {
+        if self.runs.len() > MAX_HAVE_BLOCKS_RUNS {
+            return None;
+        }
+        let end = self.start_seq.checked_add(self.count)?;
+        let mut missing = Vec::new();
+        let mut seq = self.start_seq;
+        for (index, &run) in self.runs.iter().enumerate() {
+            let run_end = seq.checked_add(run).filter(|&run_end| run_end <= end)?;
+            if index % 2 == 1 && run > 0 {
+                missing.push(seq..run_end);
+            }
+            seq = run_end;
+        }
+        if seq < end {
+            missing.push(seq..end);
+        }
+        Some(missing)
+    }
+}
+
+/// Request of a receiver for every block of a range, sent to senders advertising
+/// [Capabilities::PUSH_STREAM](crate::capabilities::Capabilities::PUSH_STREAM) instead of a
+/// request per block. The sender streams the blocks in order, as if each had been requested,
+/// a window of `window` blocks at a time, and keeps two windows ahead of the receiver's
+/// [PushAckV1]s: the first two right away, the next one after each acknowledgement, following
+/// the blocks the acknowledgement asks for again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushRangeV1 {
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Sequence number of the first block of the range.
+    pub start_seq: u32,
+    /// Number of blocks of the range.
+    pub count: u32,
+    /// Number of blocks of a window, at most [MAX_PUSH_WINDOW].
+    pub window: u32,
+    /// Whether blocks only holding zeros are answered with a `ZeroBlock`, as for a
+    /// [ReceiverMessageV1::SparseRequest].
+    pub sparse: bool,
+}
+
+impl PushRangeV1 {
+    /// Returns the sequence numbers of the blocks of window `index`, empty past the range.
+    pub fn window_blocks(&self, index: u32) -> Range<u32> {
+        let end = self.start_seq.saturating_add(self.count);
+        let start = index
+            .checked_mul(self.window)
+            .and_then(|offset| self.start_seq.checked_add(offset))
+            .map_or(end, |start| start.min(end));
+        start..start.saturating_add(self.window).min(end)
+    }
+
+    /// Returns the number of windows of the range.
+    pub fn windows(&self) -> u32 {
+        self.count.div_ceil(self.window.max(1))
+    }
+}
+
+/// Acknowledgement of a window of a [PushRangeV1] by the receiver, once it received the
+/// window and the blocks the previous acknowledgement asked for again. Blocks that arrived
+/// damaged are listed, and sent again by the sender before its next window.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushAckV1 {
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Index of the window acknowledged, counted from 0, past the last window once the
+    /// receiver only waits for blocks sent again.
+    pub window: u32,
+    /// Sequence numbers of the blocks to send again, at most two windows of them.
+    pub missing: Vec<u32>,
+}
+
+/// Request of a receiver for the blocks `start_seq..end_seq`, sent to senders advertising
+/// [Capabilities::RANGE_REQUEST](crate::capabilities::Capabilities::RANGE_REQUEST) instead of a
+/// request per block. The sender answers every block in order, as if each had been requested,
+/// without waiting for the receiver in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestRangeV1 {
+    /// BLAKE3 hash of the file.
+    pub file_hash: [u8; 32],
+    /// Sequence number of the first block requested.
+    pub start_seq: u32,
+    /// Sequence number past the last block requested, at most [MAX_REQUEST_RANGE_BLOCKS] after
+    /// `start_seq`.
+    pub end_seq: u32,
+    /// Whether blocks only holding zeros are answered with a `ZeroBlock`, as for a
+    /// [ReceiverMessageV1::SparseRequest].
+    pub sparse: bool,
+}
+
+/// Compression codec and block checksum the receiver picked among those both peers support,
+/// sent on every data connection right after its [ConnHelloV1] unless they are gzip and CRC-32,
+/// which peers use without negotiating. Applies to every later block and verification of the
+/// connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlgorithmsV1 {
+    /// Codec compressed blocks are encoded with, [CompressionCodec::None] for none at all.
+    pub compression: CompressionCodec,
+    /// Algorithm blocks are checksummed with.
+    pub checksum: ChecksumAlgorithm,
+}
+
+/// Acknowledgement of the handshake, the receiver's answer to an accepted offer from
+/// [HANDSHAKE_ACK_PROTOCOL_VERSION] on, with the parameters of the transfer it settled on. Sent
+/// on the handshake connection before any data connection is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandshakeAckV1 {
+    /// BLAKE3 hash of the offered file.
+    pub file_hash: [u8; 32],
+    /// Optional features of the transfer, those both peers advertised that the receiver uses.
+    pub capabilities: Capabilities,
+    /// Codec blocks are compressed with, confirmed on each data connection by an
+    /// [AlgorithmsV1] unless it is gzip.
+    pub compression: CompressionCodec,
+    /// Algorithm blocks are checksummed with.
+    pub checksum: ChecksumAlgorithm,
+    /// Size of the blocks requested, the sender's.
+    pub block_size: u32,
+    /// Number of data connections the receiver opens, at most the sender's concurrency.
+    pub concurrency: u16,
+}
+
+/// Refusal of the handshake, the receiver's answer to a rejected offer from
+/// [HANDSHAKE_ACK_PROTOCOL_VERSION] on. The receiver closes the connection after it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandshakeRejectV1 {
+    /// BLAKE3 hash of the offered file.
+    pub file_hash: [u8; 32],
+    /// Why the offer is refused.
+    pub reason: RejectReasonV1,
+    /// Details of the refusal, for people to read.
+    pub message: String,
+}
+
+/// Why a receiver refused an offer, see [HandshakeRejectV1].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReasonV1 {
+    /// Turned down by the receiving application or its user.
+    Declined,
+    /// The receiver's policy refuses files of this type.
+    FileType,
+    /// The block size is beyond what the receiver accepts.
+    BlockSize,
+    /// The destination hasn't enough free space for the file.
+    DiskFull,
+    /// The receiver is busy with another transfer.
+    Busy,
+    /// The receiver can't receive this offer where it was asked to, e.g. a directory in memory.
+    Unsupported,
+}
+
+impl std::fmt::Display for RejectReasonV1 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Declined => "declined",
+            Self::FileType => "file type refused",
+            Self::BlockSize => "block size refused",
+            Self::DiskFull => "not enough disk space",
+            Self::Busy => "receiver busy",
+            Self::Unsupported => "unsupported offer",
+        })
+    }
+}
+
+/// Control messages of the receiver coalesced in a single frame, sent to senders advertising
+/// [Capabilities::BATCHING](crate::capabilities::Capabilities::BATCHING). The sender handles
+/// them in order, as if each had come in its own frame, and answers each of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchV1 {
+    /// The messages, at most [MAX_BATCH_MESSAGES]. The length prefix of the sequence is the
+    /// count of messages.
+    pub messages: Vec<BatchedMessageV1>,
+}
+
+/// A message of the receiver that can be part of a [BatchV1]. Batches don't nest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchedMessageV1 {
+    /// See [ReceiverMessageV1::Request].
+    Request(RequestV1),
+    /// See [ReceiverMessageV1::Progress].
+    Progress(ProgressV1),
+    /// See [ReceiverMessageV1::VerifyBlock].
+    VerifyBlock(VerifyBlockV1),
+    /// See [ReceiverMessageV1::BlockHashesRequest].
+    BlockHashesRequest(BlockHashesRequestV1),
+    /// See [ReceiverMessageV1::SparseRequest].
+    SparseRequest(RequestV1),
+    /// See [ReceiverMessageV1::FileRequest].
+    FileRequest(FileRequestV1),
+}
+
+impl From<BatchedMessageV1> for ReceiverMessageV1 {
+    fn from(message: BatchedMessageV1) -> Self {
+        match message {
+            BatchedMessageV1::Request(request) => Self::Request(request),
+            BatchedMessageV1::Progress(progress) => Self::Progress(progress),
+            BatchedMessageV1::VerifyBlock(verify) => Self::VerifyBlock(verify),
+            BatchedMessageV1::BlockHashesRequest(request) => Self::BlockHashesRequest(request),
+            BatchedMessageV1::SparseRequest(request) => Self::SparseRequest(request),
+            BatchedMessageV1::FileRequest(request) => Self::FileRequest(request),
+        }
+    }
+}
+
 /// Messages sent from the Receiver (the one receiving the file) to the Sender.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ReceiverMessageV1 {
@@ -215,6 +1178,81 @@ pub enum ReceiverMessageV1 {
 
     /// A request to verify an existing block during resume.
     VerifyBlock(VerifyBlockV1),
+
+    /// Signed proof of delivery, sent once the whole file has been verified.
+    Receipt(ReceiptV1),
+
+    /// A request for the hashes of a run of blocks.
+    BlockHashesRequest(BlockHashesRequestV1),
+
+    /// Whether the offered file is accepted.
+    OfferResponse(OfferResponseV1),
+
+    /// Acknowledgement of a probe.
+    ProbeAck(ProbeAckV1),
+
+    /// Opening message of a data connection.
+    ConnHello(ConnHelloV1),
+
+    /// Protocol version chosen for the transfer.
+    ProtocolVersion(ProtocolVersionV1),
+
+    /// A step of the Noise handshake setting up an encrypted channel.
+    NoiseHandshake(NoiseHandshakeV1),
+
+    /// Answer to a pairing with this receiver's one-time code.
+    PairingReply(PairingReplyV1),
+
+    /// A request for a block to be sent as UDP datagrams.
+    UdpRequest(UdpRequestV1),
+
+    /// A request for a block, answered with a `ZeroBlock` if it only holds zeros.
+    SparseRequest(RequestV1),
+
+    /// A heartbeat on an idle connection.
+    Ping(PingV1),
+
+    /// Answer to a heartbeat of the sender.
+    Pong(PongV1),
+
+    /// A request for a block of a file of the offered directory.
+    FileRequest(FileRequestV1),
+
+    /// Several control messages coalesced in a single frame.
+    Batch(BatchV1),
+
+    /// Compression codec and block checksum picked for the transfer.
+    Algorithms(AlgorithmsV1),
+
+    /// Acceptance of the offer, with the parameters of the transfer.
+    HandshakeAck(HandshakeAckV1),
+
+    /// Refusal of the offer, with the reason.
+    HandshakeReject(HandshakeRejectV1),
+
+    /// The blocks of a range the receiver already holds, asking for all the others.
+    HaveBlocks(HaveBlocksV1),
+
+    /// Request for every block of a range, streamed by the sender.
+    PushRange(PushRangeV1),
+
+    /// Acknowledgement of a window of streamed blocks, naming those to send again.
+    PushAck(PushAckV1),
+
+    /// Request for a run of consecutive blocks, answered one after the other.
+    RequestRange(RequestRangeV1),
+
+    /// The receiver cancelled the transfer and is closing its connections.
+    Cancel(CancelV1),
+
+    /// Identity of a peer running `sendfile pair`, answering the sender's.
+    Introduction(IntroductionV1),
+
+    /// The receiver has nothing more to request on the data connection.
+    Goodbye(GoodbyeV1),
+
+    /// The receiver verified the file but signs no receipt for it.
+    NoReceipt(NoReceiptV1),
 }
 
 impl ReceiverMessageV1 {
@@ -230,9 +1268,9 @@ impl ReceiverMessageV1 {
     }
 }
 
-/// Attaches the protocol headers (Version and Length) to the payload.
+/// Attaches the binary [FrameHeader] of [BINARY_FRAMING_PROTOCOL_VERSION] to the payload.
 ///
-/// This function constructs a new byte buffer containing the headers followed by the payload.
+/// This function constructs a new byte buffer containing the header followed by the payload.
 ///
 /// # Arguments
 ///
@@ -240,14 +1278,32 @@ impl ReceiverMessageV1 {
 ///
 /// # Returns
 ///
-/// A `Box<[u8]>` containing the full message with headers.
+/// A `Box<[u8]>` containing the full message with its header.
 pub fn attach_headers(payload: &[u8]) -> Box<[u8]> {
+    let length = u32::try_from(payload.len()).expect("payload larger than 4 GiB");
+    let mut message = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    message.extend_from_slice(&FrameHeader::new(length).encode());
+    message.extend_from_slice(payload);
+    message.into_boxed_slice()
+}
+
+/// Attaches the text headers of [TEXT_FRAMING_PROTOCOL_VERSION] (Version and Length) to the
+/// payload, as peers running protocol version 1 frame their messages.
+///
+/// # Arguments
+///
+/// * `payload` - The serialized message payload.
+///
+/// # Returns
+///
+/// A `Box<[u8]>` containing the full message with headers.
+pub fn attach_text_headers(payload: &[u8]) -> Box<[u8]> {
     let mut message = Vec::with_capacity(64 + payload.len());
     message.extend_from_slice(
         format!(
             //Ver: [PROTOCOL_VERSION]\r\n
             "{VERSION_HEADER_PREFIX_STR}{}{MESSAGE_DELIMITER_STR}",
-            CURRENT_PROTOCOL_VERSION
+            TEXT_FRAMING_PROTOCOL_VERSION
         )
         .as_bytes(),
     );
@@ -267,6 +1323,54 @@ pub fn attach_headers(payload: &[u8]) -> Box<[u8]> {
     message.into_boxed_slice()
 }
 
+/// Attaches the binary [FrameHeader] of [SESSION_FRAMING_PROTOCOL_VERSION] to the payload,
+/// tagged with `session_id`.
+///
+/// # Arguments
+///
+/// * `session_id` - Id of the session the message belongs to.
+/// * `payload` - The serialized message payload.
+///
+/// # Returns
+///
+/// A `Box<[u8]>` containing the full message with its header and session id.
+pub fn attach_session_headers(session_id: &[u8; SESSION_ID_SIZE], payload: &[u8]) -> Box<[u8]> {
+    let length = u32::try_from(payload.len()).expect("payload larger than 4 GiB");
+    let mut message = Vec::with_capacity(FRAME_HEADER_SIZE + SESSION_ID_SIZE + payload.len());
+    message.extend_from_slice(&FrameHeader::with_session(length).encode());
+    message.extend_from_slice(session_id);
+    message.extend_from_slice(payload);
+    message.into_boxed_slice()
+}
+
+/// Attaches the headers of protocol `version` to the payload: text headers up to
+/// [TEXT_FRAMING_PROTOCOL_VERSION], the binary [FrameHeader] after, tagged with `session_id`
+/// from [SESSION_FRAMING_PROTOCOL_VERSION] on. Without a session id, those versions frame the
+/// payload as [BINARY_FRAMING_PROTOCOL_VERSION] does.
+pub fn attach_headers_for(
+    version: u8,
+    session_id: Option<&[u8; SESSION_ID_SIZE]>,
+    payload: &[u8],
+) -> Box<[u8]> {
+    match session_id {
+        _ if version <= TEXT_FRAMING_PROTOCOL_VERSION => attach_text_headers(payload),
+        Some(session_id) if version >= SESSION_FRAMING_PROTOCOL_VERSION => {
+            attach_session_headers(session_id, payload)
+        }
+        _ => attach_headers(payload),
+    }
+}
+
+/// Returns the highest of the `offered` protocol versions that is also in
+/// [SUPPORTED_PROTOCOL_VERSIONS], or `None` if there is none.
+pub fn choose_protocol_version(offered: &[u8]) -> Option<u8> {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .rev()
+        .copied()
+        .find(|version| offered.contains(version))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -279,15 +1383,65 @@ mod tests {
             concurrency: 8,
             file_name: "test_file.txt",
             block_size: MAX_BLOCK_SIZE,
+            software_version: "0.1.0",
+            capabilities: Capabilities::local(),
         });
 
         let mut buffer = [0u8; 1024]; // Large enough buffer for serialization
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = SenderMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
 
+    #[test]
+    fn test_legacy_handshake_is_read_without_capabilities() {
+        #[derive(Serialize)]
+        enum BaselineSenderMessage<'a> {
+            Handshake {
+                file_hash: &'a [u8],
+                total_size: u64,
+                concurrency: u16,
+                file_name: &'a str,
+                block_size: u32,
+            },
+        }
+        let baseline = BaselineSenderMessage::Handshake {
+            file_hash: &[0xAA; 32],
+            total_size: 1024,
+            concurrency: 4,
+            file_name: "old.bin",
+            block_size: MAX_BLOCK_SIZE,
+        };
+        let mut payload = [0u8; 1024];
+        let payload = postcard::to_slice(&baseline, &mut payload).expect("Failed to serialize");
+        let frame = attach_text_headers(payload);
+
+        let mut buffer = vec![0u8; 1024];
+        let result = crate::connection::read_next_payload_with(
+            &mut &frame[..],
+            &mut buffer,
+            0,
+            SenderMessageV1::from_first_bytes,
+        )
+        .expect("Failed to read the baseline handshake");
+
+        assert_eq!(result.protocol_version, TEXT_FRAMING_PROTOCOL_VERSION);
+        assert_eq!(
+            result.message,
+            SenderMessageV1::Handshake(HandshakeV1 {
+                file_hash: &[0xAA; 32],
+                total_size: 1024,
+                concurrency: 4,
+                file_name: "old.bin",
+                block_size: MAX_BLOCK_SIZE,
+                software_version: UNKNOWN_SOFTWARE_VERSION,
+                capabilities: Capabilities::empty(),
+            })
+        );
+        assert!(SenderMessageV1::from_bytes(payload).is_err());
+    }
+
     #[test]
     fn test_transfer_complete_serde() {
         let msg = ReceiverMessageV1::TransferComplete(TransferCompleteV1 {
@@ -295,7 +1449,7 @@ mod tests {
         });
         let mut buffer = [0u8; 1024];
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = ReceiverMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
@@ -313,7 +1467,7 @@ mod tests {
 
         let mut buffer = [0u8; 1024]; // Large enough buffer for serialization
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = SenderMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
@@ -327,7 +1481,7 @@ mod tests {
 
         let mut buffer = [0u8; 1024]; // Large enough buffer for serialization
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = SenderMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
@@ -340,7 +1494,7 @@ mod tests {
         });
         let mut buffer = [0u8; 1024]; // Large enough buffer for serialization
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = ReceiverMessageV1::from_bytes(&serialized).expect("Failed to deserialize ");
+        let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize ");
 
         assert_eq!(msg, decoded);
     }
@@ -354,7 +1508,7 @@ mod tests {
         let mut buffer = [0u8; 1024]; // Large enough buffer for
                                       // serialization
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = ReceiverMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
@@ -372,7 +1526,7 @@ mod tests {
 
         let mut buffer = vec![0u8; (MAX_BLOCK_SIZE + 512) as usize]; // Large enough buffer for serialization
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = SenderMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
@@ -386,11 +1540,71 @@ mod tests {
         });
         let mut buffer = [0u8; 1024];
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = ReceiverMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
 
+    #[test]
+    fn test_batch_serde() {
+        let verify = VerifyBlockV1 {
+            file_hash: [0xCC; 32],
+            seq: 123,
+            checksum: 0xDEADBEEF,
+        };
+        let msg = ReceiverMessageV1::Batch(BatchV1 {
+            messages: vec![BatchedMessageV1::VerifyBlock(verify.clone()); MAX_BATCH_MESSAGES],
+        });
+        let mut buffer = [0u8; 8192];
+        let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
+        let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize");
+        assert_eq!(msg, decoded);
+
+        let ReceiverMessageV1::Batch(batch) = decoded else {
+            panic!("Expected a batch");
+        };
+        assert_eq!(
+            ReceiverMessageV1::from(batch.messages[0].clone()),
+            ReceiverMessageV1::VerifyBlock(verify)
+        );
+    }
+
+    #[test]
+    fn test_algorithms_serde() {
+        let msg = ReceiverMessageV1::Algorithms(AlgorithmsV1 {
+            compression: CompressionCodec::Zstd,
+            checksum: ChecksumAlgorithm::Crc32c,
+        });
+        let mut buffer = [0u8; 16];
+        let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
+        let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize");
+        assert_eq!(msg, decoded);
+    }
+
+    #[test]
+    fn test_handshake_answers_serde() {
+        let mut buffer = [0u8; 128];
+        for msg in [
+            ReceiverMessageV1::HandshakeAck(HandshakeAckV1 {
+                file_hash: [0xAB; 32],
+                capabilities: Capabilities::RECEIPT | Capabilities::CONN_HELLO,
+                compression: CompressionCodec::Zstd,
+                checksum: ChecksumAlgorithm::Crc32c,
+                block_size: 1024 * 1024,
+                concurrency: 8,
+            }),
+            ReceiverMessageV1::HandshakeReject(HandshakeRejectV1 {
+                file_hash: [0xAB; 32],
+                reason: RejectReasonV1::DiskFull,
+                message: String::from("Needs 2 GiB, 1 GiB free"),
+            }),
+        ] {
+            let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
+            let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize");
+            assert_eq!(msg, decoded);
+        }
+    }
+
     #[test]
     fn test_verify_response_serde() {
         let msg = SenderMessageV1::VerifyResponse(VerifyResponseV1 {
@@ -400,8 +1614,172 @@ mod tests {
         });
         let mut buffer = [0u8; 1024];
         let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
-        let decoded = SenderMessageV1::from_bytes(&serialized).expect("Failed to deserialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
+
+        assert_eq!(msg, decoded);
+    }
+
+    #[test]
+    fn test_block_hashes_serde() {
+        let msg = SenderMessageV1::BlockHashes(BlockHashesV1 {
+            file_hash: [0xCC; 32],
+            start_seq: 8,
+            hashes: vec![[0x11; 32], [0x22; 32]],
+        });
+        let mut buffer = [0u8; 1024];
+        let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
+
+        assert_eq!(msg, decoded);
+    }
+
+    #[test]
+    fn test_offer_response_serde() {
+        let msg = ReceiverMessageV1::OfferResponse(OfferResponseV1 {
+            file_hash: [0xDD; 32],
+            accepted: false,
+            reason: String::from("Quota exceeded"),
+        });
+        let mut buffer = [0u8; 1024];
+        let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
+        let decoded = ReceiverMessageV1::from_bytes(serialized).expect("Failed to deserialize");
+
+        assert_eq!(msg, decoded);
+    }
+
+    #[test]
+    fn test_authentication_serde() {
+        let msg = SenderMessageV1::Authentication(AuthenticationV1 {
+            file_hash: [0xEE; 32],
+            timestamp: 1_700_000_000,
+            sender_key: [0x11; 32],
+            signature: vec![0x22; 64],
+        });
+        let mut buffer = [0u8; 1024];
+        let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
+
+        assert_eq!(msg, decoded);
+    }
+
+    #[test]
+    fn test_block_unreadable_serde() {
+        let msg = SenderMessageV1::BlockUnreadable(BlockUnreadableV1 {
+            seq: 17,
+            reason: String::from("Input/output error (os error 5)"),
+        });
+        let mut buffer = [0u8; 256];
+        let serialized = msg.to_bytes(&mut buffer).expect("Failed to serialize");
+        let decoded = SenderMessageV1::from_bytes(serialized).expect("Failed to deserialize");
 
         assert_eq!(msg, decoded);
     }
+
+    #[test]
+    fn test_frame_header_round_trip() {
+        let header = FrameHeader::new(MAX_MESSAGE_SIZE as u32);
+        let encoded = header.encode();
+        assert_eq!(encoded[..4], FRAME_MAGIC);
+        assert_eq!(FrameHeader::decode(&encoded), Ok(header));
+
+        let mut corrupted = encoded;
+        corrupted[7] ^= 0x01;
+        assert!(matches!(
+            FrameHeader::decode(&corrupted),
+            Err(FrameHeaderError::ChecksumMismatch { .. })
+        ));
+
+        let mut text = [0u8; FRAME_HEADER_SIZE];
+        text.copy_from_slice(&attach_text_headers(b"payload")[..FRAME_HEADER_SIZE]);
+        assert_eq!(FrameHeader::decode(&text), Err(FrameHeaderError::BadMagic));
+
+        let flagged = FrameHeader {
+            flags: 0x80,
+            ..header
+        };
+        assert_eq!(
+            FrameHeader::decode(&flagged.encode()),
+            Err(FrameHeaderError::UnknownFlags(0x80))
+        );
+    }
+
+    #[test]
+    fn test_choose_protocol_version() {
+        assert_eq!(
+            choose_protocol_version(&[1, 2, 3, 5]),
+            Some(CURRENT_PROTOCOL_VERSION)
+        );
+        // Senders of a directory only offer the versions sending several files, older ones only
+        // the version listing them
+        assert_eq!(
+            choose_protocol_version(&[MULTI_FILE_PROTOCOL_VERSION, HANDSHAKE_ACK_PROTOCOL_VERSION]),
+            Some(HANDSHAKE_ACK_PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            choose_protocol_version(&[MULTI_FILE_PROTOCOL_VERSION]),
+            Some(MULTI_FILE_PROTOCOL_VERSION)
+        );
+        // Versions of newer peers are skipped, older ones still interoperate
+        assert_eq!(
+            choose_protocol_version(&[1, 2, 9]),
+            Some(BINARY_FRAMING_PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            choose_protocol_version(&[1]),
+            Some(TEXT_FRAMING_PROTOCOL_VERSION)
+        );
+        assert_eq!(choose_protocol_version(&[9]), None);
+        assert_eq!(choose_protocol_version(&[]), None);
+    }
+
+    #[test]
+    fn test_push_range_windows() {
+        let push = PushRangeV1 {
+            file_hash: [0xCC; 32],
+            start_seq: 10,
+            count: 10,
+            window: 4,
+            sparse: false,
+        };
+        assert_eq!(push.windows(), 3);
+        assert_eq!(push.window_blocks(0), 10..14);
+        assert_eq!(push.window_blocks(2), 18..20);
+        assert!(push.window_blocks(3).is_empty());
+        assert!(push.window_blocks(u32::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_have_blocks_lists_missing_runs() {
+        let held = |seq: u32| !(12..15).contains(&seq) && seq != 18;
+        let have = HaveBlocksV1::new([0xCC; 32], 10..20, held, true);
+        assert_eq!(have.count, 10);
+        assert_eq!(have.runs, vec![2, 3, 3, 1, 1]);
+        assert_eq!(have.missing(), Some(vec![12..15, 18..19]));
+
+        let have = HaveBlocksV1::new([0xCC; 32], 0..4, |seq| seq == 3, false);
+        assert_eq!(have.runs, vec![0, 3, 1]);
+        let missing: Vec<u32> = have.missing().unwrap().into_iter().flatten().collect();
+        assert_eq!(missing, vec![0, 1, 2]);
+        let have = HaveBlocksV1::new([0xCC; 32], 4..8, |seq| seq == 4, false);
+        assert_eq!(have.runs, vec![1]);
+        let missing: Vec<u32> = have.missing().unwrap().into_iter().flatten().collect();
+        assert_eq!(missing, vec![5, 6, 7]);
+
+        // Alternating blocks take a run each, the range is cut short before the limit
+        let have = HaveBlocksV1::new([0xCC; 32], 0..10_000, |seq| seq % 2 == 0, false);
+        assert_eq!(have.runs.len(), MAX_HAVE_BLOCKS_RUNS - 1);
+        assert_eq!(have.count as usize, MAX_HAVE_BLOCKS_RUNS);
+        let missing = have.missing().unwrap();
+        assert_eq!(missing.len(), MAX_HAVE_BLOCKS_RUNS / 2);
+        assert!(missing
+            .iter()
+            .all(|range| range.len() == 1 && range.start % 2 == 1));
+
+        let mut malformed = HaveBlocksV1::new([0xCC; 32], u32::MAX - 1..u32::MAX, |_| true, false);
+        assert_eq!(malformed.missing(), Some(vec![]));
+        malformed.runs = vec![2];
+        assert_eq!(malformed.missing(), None);
+        malformed.count = 2;
+        assert_eq!(malformed.missing(), None);
+    }
 }
@@ -0,0 +1,430 @@
+//! Rendezvous relay, for transfers between peers that are both behind NAT (`--relay`).
+//!
+//! Neither peer can reach the other, so both connect out to a relay run with `sendfile relay`
+//! and name the same rendezvous code. The relay pairs the connection of the sender with the one
+//! of the receiver waiting under that code, then pipes bytes between them both ways until either
+//! side closes. The transfer runs over that pair as a
+//! [pre-connected](crate::stream::preconnected) transfer, with the same protocol messages as over
+//! TCP.
+//!
+//! A peer opens its connection with a request line, `SENDFILE-RELAY/1 send CODE` or
+//! `SENDFILE-RELAY/1 receive CODE`, and the relay answers `OK` once the other peer arrived, or
+//! `ERR REASON`. The relay sees every message of the transfer: the file hash is still verified
+//! end to end, and receipts are still signed by the receiver, but the content is not encrypted.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use thiserror::Error;
+
+use crate::{threads::thread_name, units::Size};
+
+/// Port a relay listens on by default.
+pub const DEFAULT_RELAY_PORT: u16 = 7880;
+
+/// Time a peer waits at the relay for the other peer by default.
+pub const DEFAULT_RELAY_WAIT: Duration = Duration::from_secs(600);
+
+/// Protocol name and version opening every request line.
+const PROTOCOL: &str = "SENDFILE-RELAY/1";
+
+/// Longest request or answer line.
+const MAX_LINE_LEN: usize = 256;
+
+/// Longest rendezvous code.
+const MAX_CODE_LEN: usize = 64;
+
+/// Time a peer has to send its request line once connected.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval the relay closes the connections of peers that waited too long at.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Peers waiting at a relay at most, further requests are refused.
+const MAX_WAITING: usize = 1024;
+
+/// Side of the transfer a peer connecting to the relay is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayRole {
+    /// The peer sending the file.
+    Sender,
+    /// The peer receiving the file.
+    Receiver,
+}
+
+impl RelayRole {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Sender => "send",
+            Self::Receiver => "receive",
+        }
+    }
+}
+
+impl fmt::Display for RelayRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Errors that can occur while connecting through a relay.
+#[derive(Error, Debug)]
+pub enum RelayError {
+    /// The connection to the relay failed or was closed.
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    /// The relay refused the request.
+    #[error("Relay refused the connection: {0}")]
+    Refused(String),
+    /// The relay answered something that is not a relay answer.
+    #[error("Invalid answer from the relay: {0:?}")]
+    InvalidAnswer(String),
+    /// A peer sent something that is not a relay request.
+    #[error("Invalid relay request {0:?}")]
+    InvalidRequest(String),
+    /// The rendezvous code is empty, too long or contains whitespace.
+    #[error("Invalid rendezvous code {0:?}, use 1 to 64 characters without spaces")]
+    InvalidCode(String),
+}
+
+/// Connects to the relay at `relay` (`HOST` or `HOST:PORT`) as `role` and waits for the other
+/// peer to arrive under `code`.
+///
+/// # Returns
+///
+/// The connection to the relay, piped to the other peer, or a [RelayError] if the relay can't be
+/// reached, refuses the request or closes the connection before the other peer arrived.
+pub fn connect(relay: &str, role: RelayRole, code: &str) -> Result<TcpStream, RelayError> {
+    check_code(code)?;
+    let mut stream = TcpStream::connect(relay_address(relay))?;
+    stream.set_nodelay(true)?;
+    stream.write_all(format!("{} {} {}\n", PROTOCOL, role, code).as_bytes())?;
+    stream.flush()?;
+    info!("Waiting at relay {} for the other peer", relay);
+
+    let answer = read_line(&mut stream)?
+        .ok_or_else(|| RelayError::Refused(String::from("closed before the other peer arrived")))?;
+    if answer == "OK" {
+        return Ok(stream);
+    }
+    match answer.strip_prefix("ERR ") {
+        Some(reason) => Err(RelayError::Refused(reason.to_string())),
+        None => Err(RelayError::InvalidAnswer(answer)),
+    }
+}
+
+/// Returns `relay` with [DEFAULT_RELAY_PORT] appended if it has no port.
+fn relay_address(relay: &str) -> String {
+    if relay.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv6()) {
+        format!("[{}]:{}", relay, DEFAULT_RELAY_PORT)
+    } else if relay.parse::<SocketAddr>().is_ok()
+        || relay
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.contains(':') && port.parse::<u16>().is_ok())
+    {
+        relay.to_string()
+    } else {
+        format!("{}:{}", relay, DEFAULT_RELAY_PORT)
+    }
+}
+
+fn check_code(code: &str) -> Result<(), RelayError> {
+    if code.is_empty() || code.len() > MAX_CODE_LEN || code.chars().any(char::is_whitespace) {
+        return Err(RelayError::InvalidCode(code.to_string()));
+    }
+    Ok(())
+}
+
+/// Reads a line from `stream` a byte at a time, so nothing past it is consumed.
+///
+/// # Returns
+///
+/// The line without its end, or `None` if the stream ended first.
+fn read_line(stream: &mut TcpStream) -> Result<Option<String>, RelayError> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'\n' => break,
+            byte if line.len() < MAX_LINE_LEN => line.push(byte),
+            _ => {
+                return Err(RelayError::InvalidAnswer(
+                    String::from_utf8_lossy(&line).into_owned(),
+                ));
+            }
+        }
+    }
+    let line = String::from_utf8_lossy(&line);
+    Ok(Some(line.trim_end_matches('\r').to_string()))
+}
+
+/// Peer waiting at the relay for the other peer of its rendezvous code.
+struct Waiting {
+    role: RelayRole,
+    stream: TcpStream,
+    since: Instant,
+}
+
+/// Relay pairing the peers connecting to it by rendezvous code.
+pub struct Relay {
+    waiting: Mutex<HashMap<String, Waiting>>,
+    /// Time a peer waits for the other peer before its connection is closed.
+    wait_timeout: Duration,
+}
+
+impl Relay {
+    /// Creates a relay closing the connection of peers still alone after `wait_timeout`.
+    pub fn new(wait_timeout: Duration) -> Self {
+        Self {
+            waiting: Mutex::new(HashMap::new()),
+            wait_timeout,
+        }
+    }
+
+    /// Serves the peers connecting to `listener`, each on a thread of its own, until accepting
+    /// fails.
+    pub fn serve(self: Arc<Self>, listener: &TcpListener) -> io::Result<()> {
+        let relay = self.clone();
+        thread::Builder::new()
+            .name(thread_name("relay-prune", 0))
+            .spawn(move || loop {
+                thread::sleep(PRUNE_INTERVAL);
+                relay.prune(&mut relay.lock_waiting());
+            })?;
+        let mut index = 0;
+        loop {
+            let (stream, peer) = listener.accept()?;
+            let relay = self.clone();
+            let spawn_result = thread::Builder::new()
+                .name(thread_name("relay", index))
+                .spawn(move || {
+                    if let Err(e) = relay.handle(stream, index) {
+                        warn!("Dropping relay connection from {}: {}", peer, e);
+                    }
+                });
+            if let Err(e) = spawn_result {
+                warn!("Failed to spawn relay thread: {}", e);
+            }
+            index += 1;
+        }
+    }
+
+    /// Reads the request of a peer, then pairs it with the peer waiting under its code, or
+    /// leaves it waiting for one.
+    fn handle(&self, mut stream: TcpStream, index: usize) -> Result<(), RelayError> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let request = read_line(&mut stream)?.unwrap_or_default();
+        let (role, code) = match parse_request(&request) {
+            Ok(request) => request,
+            Err(reason) => {
+                let _ = writeln!(stream, "ERR {}", reason);
+                return Err(RelayError::InvalidRequest(request));
+            }
+        };
+        stream.set_read_timeout(None)?;
+
+        let mut other = {
+            let mut waiting = self.lock_waiting();
+            self.prune(&mut waiting);
+            match waiting.remove(&code) {
+                Some(other) if other.role != role => other,
+                Some(other) => {
+                    waiting.insert(code, other);
+                    writeln!(stream, "ERR A {} already waits under this code", role)?;
+                    return Ok(());
+                }
+                None if waiting.len() >= MAX_WAITING => {
+                    writeln!(stream, "ERR Too many peers waiting, try again later")?;
+                    return Ok(());
+                }
+                None => {
+                    debug!("A {} waits under code {:?}", role, code);
+                    let since = Instant::now();
+                    waiting.insert(
+                        code,
+                        Waiting {
+                            role,
+                            stream,
+                            since,
+                        },
+                    );
+                    return Ok(());
+                }
+            }
+        };
+
+        info!("Relaying a transfer under code {:?}", code);
+        stream.write_all(b"OK\n")?;
+        other.stream.write_all(b"OK\n")?;
+        let relayed = pipe(stream, other.stream, index)?;
+        info!("Relayed {} under code {:?}", Size(relayed), code);
+        Ok(())
+    }
+
+    fn lock_waiting(&self) -> MutexGuard<'_, HashMap<String, Waiting>> {
+        self.waiting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Closes the connections of peers that waited too long or left.
+    fn prune(&self, waiting: &mut HashMap<String, Waiting>) {
+        waiting.retain(|code, peer| {
+            let keep = peer.since.elapsed() < self.wait_timeout && !is_closed(&peer.stream);
+            if !keep {
+                debug!("No peer joined the {} under code {:?}", peer.role, code);
+            }
+            keep
+        });
+    }
+}
+
+/// Parses the request line of a peer into its role and rendezvous code, or the reason it is
+/// invalid.
+fn parse_request(request: &str) -> Result<(RelayRole, String), String> {
+    let mut parts = request.split(' ');
+    if parts.next() != Some(PROTOCOL) {
+        return Err(format!("Expected {} requests", PROTOCOL));
+    }
+    let role = match parts.next() {
+        Some("send") => RelayRole::Sender,
+        Some("receive") => RelayRole::Receiver,
+        _ => return Err(String::from("Unknown role, expected send or receive")),
+    };
+    let code = parts.next().unwrap_or_default();
+    if parts.next().is_some() || check_code(code).is_err() {
+        return Err(String::from("Invalid rendezvous code"));
+    }
+    Ok((role, code.to_string()))
+}
+
+/// Returns whether the peer waiting on `stream` closed its connection.
+fn is_closed(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let closed = match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    };
+    closed || stream.set_nonblocking(false).is_err()
+}
+
+/// Copies bytes between `a` and `b`, paired by the `index`th relay connection, both ways until
+/// both directions ended.
+///
+/// # Returns
+///
+/// The number of bytes copied.
+fn pipe(a: TcpStream, b: TcpStream, index: usize) -> io::Result<u64> {
+    let (a_reader, b_reader) = (a.try_clone()?, b.try_clone()?);
+    let forward = thread::Builder::new()
+        .name(thread_name("relay-pipe", index))
+        .spawn(move || copy_until_closed(a_reader, b))?;
+    let backward = copy_until_closed(b_reader, a);
+    let forward = forward.join().unwrap_or(0);
+    Ok(forward + backward)
+}
+
+/// Copies `from` to `to` until `from` ends or fails, then ends `to` too.
+fn copy_until_closed(mut from: TcpStream, mut to: TcpStream) -> u64 {
+    let copied = io::copy(&mut from, &mut to).unwrap_or_else(|e| {
+        debug!("Relayed connection ended: {}", e);
+        0
+    });
+    let _ = to.shutdown(Shutdown::Write);
+    let _ = from.shutdown(Shutdown::Read);
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::stream::{
+        options::{ReceiveOptions, SendOptions},
+        receive::receive_over,
+        send::send_over,
+    };
+
+    #[test]
+    fn test_relay_address_and_requests() {
+        assert_eq!(relay_address("relay.example.com"), "relay.example.com:7880");
+        assert_eq!(
+            relay_address("relay.example.com:443"),
+            "relay.example.com:443"
+        );
+        assert_eq!(relay_address("::1"), "[::1]:7880");
+        assert_eq!(relay_address("[::1]:9000"), "[::1]:9000");
+
+        assert_eq!(
+            parse_request("SENDFILE-RELAY/1 send 7-orbit-velvet"),
+            Ok((RelayRole::Sender, String::from("7-orbit-velvet")))
+        );
+        assert!(parse_request("SENDFILE-RELAY/1 send").is_err());
+        assert!(parse_request("SENDFILE-RELAY/1 listen code").is_err());
+        assert!(parse_request("GET / HTTP/1.1").is_err());
+        assert!(matches!(
+            connect("127.0.0.1:1", RelayRole::Sender, "two words"),
+            Err(RelayError::InvalidCode(_))
+        ));
+    }
+
+    #[test]
+    fn test_transfer_through_relay() {
+        let dir = std::env::temp_dir().join(format!("sendfile_relay_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = listener.local_addr().unwrap().to_string();
+        let server = Arc::new(Relay::new(DEFAULT_RELAY_WAIT));
+        thread::spawn(move || server.serve(&listener));
+
+        let send_options = SendOptions {
+            block_size: 64 * 1024,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        thread::scope(|scope| {
+            let receiver = scope.spawn(|| {
+                let stream = connect(&relay, RelayRole::Receiver, "1-test-code").unwrap();
+                receive_over(stream, &output, &receive_options)
+            });
+            // Only one receiver may wait under a code
+            thread::sleep(Duration::from_millis(100));
+            assert!(matches!(
+                connect(&relay, RelayRole::Receiver, "1-test-code"),
+                Err(RelayError::Refused(_))
+            ));
+            let stream = connect(&relay, RelayRole::Sender, "1-test-code").unwrap();
+            send_over(stream, &source, &send_options).unwrap();
+            receiver.join().unwrap().unwrap();
+        });
+        assert_eq!(fs::read(&output).unwrap(), data);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
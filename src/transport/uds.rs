@@ -0,0 +1,129 @@
+//! Unix domain socket transport, for transfers between processes of the same host (`--uds`).
+//!
+//! The receiver listens on a socket file instead of its TCP ports, e.g. in a volume shared by two
+//! containers, and the sender connects to it. The transfer then runs over that connection as a
+//! [pre-connected](crate::stream::preconnected) transfer, bypassing the TCP stack.
+
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
+use crate::file::utils::try_lock_file;
+
+/// Socket file a receiver listens on, removed once it is dropped.
+pub struct UdsListener {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Locked while listening, so a socket whose receiver is gone can be told apart from one in
+    /// use without connecting to it.
+    lock: File,
+    lock_path: PathBuf,
+}
+
+impl UdsListener {
+    /// Listens on a socket file at `path`. A socket left at `path` by a receiver that is gone
+    /// is replaced, one a receiver still listens on fails with [io::ErrorKind::AddrInUse].
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        let lock = File::create(&lock_path)?;
+        if !try_lock_file(&lock, false)? {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Another receiver listens on {:?}", path),
+            ));
+        }
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            debug!("Replacing stale socket {:?}", path);
+            fs::remove_file(path)?;
+        }
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+            lock,
+            lock_path,
+        })
+    }
+
+    /// Waits for the connection of a sender.
+    pub fn accept(&self) -> io::Result<UnixStream> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(stream)
+    }
+}
+
+impl Drop for UdsListener {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove socket {:?}: {}", self.path, e);
+        }
+        let _ = fs::remove_file(&self.lock_path);
+        let _ = self.lock.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::stream::{
+        options::{ReceiveOptions, SendOptions},
+        receive::receive_over,
+        send::send_over,
+    };
+
+    #[test]
+    fn test_transfer_over_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("sendfile_uds_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("sendfile.sock");
+        let source = dir.join("source.bin");
+        let output = dir.join("output.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data).unwrap();
+
+        // Left behind by a receiver that was killed
+        drop(UnixListener::bind(&socket).unwrap());
+        let listener = UdsListener::bind(&socket).unwrap();
+        assert_eq!(
+            UdsListener::bind(&socket).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+
+        let send_options = SendOptions {
+            block_size: 64 * 1024,
+            history_path: None,
+            identity_path: None,
+            peers_path: None,
+            ..SendOptions::default()
+        };
+        let receive_options = ReceiveOptions {
+            identity_path: None,
+            peers_path: None,
+            ..ReceiveOptions::default()
+        };
+        thread::scope(|scope| {
+            let receiver =
+                scope.spawn(|| receive_over(listener.accept()?, &output, &receive_options));
+            let stream = UnixStream::connect(&socket).unwrap();
+            send_over(stream, &source, &send_options).unwrap();
+            receiver.join().unwrap().unwrap();
+        });
+        drop(listener);
+
+        assert_eq!(fs::read(&output).unwrap(), data);
+        assert!(!socket.exists());
+        assert!(!dir.join("sendfile.sock.lock").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,166 @@
+//! Formatting of sizes, counts and durations shown to people.
+//!
+//! Every report, log line and status listing formats numbers through this module, so output looks
+//! the same everywhere and does not depend on the locale: sizes use the [UnitSystem] picked with
+//! `--units`, counts are grouped by thousands with `,`, and durations read like `1m 05s`.
+
+use std::{
+    fmt::{self, Display},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+
+/// Whether [UnitSystem::global] is SI rather than IEC.
+static GLOBAL_SI: AtomicBool = AtomicBool::new(false);
+
+/// Prefixes used to format sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum UnitSystem {
+    /// Decimal prefixes: 1 kB = 1000 bytes.
+    Si,
+    /// Binary prefixes: 1 KiB = 1024 bytes.
+    #[default]
+    Iec,
+}
+
+impl UnitSystem {
+    /// Returns the system sizes are formatted with by [Size], IEC unless changed with
+    /// [UnitSystem::set_global].
+    pub fn global() -> Self {
+        if GLOBAL_SI.load(Ordering::Relaxed) {
+            Self::Si
+        } else {
+            Self::Iec
+        }
+    }
+
+    /// Makes this the system sizes are formatted with for the rest of the process.
+    pub fn set_global(self) {
+        GLOBAL_SI.store(self == Self::Si, Ordering::Relaxed);
+    }
+
+    fn base(self) -> f64 {
+        match self {
+            Self::Si => 1000.0,
+            Self::Iec => 1024.0,
+        }
+    }
+
+    fn prefixes(self) -> &'static [&'static str] {
+        match self {
+            Self::Si => &["kB", "MB", "GB", "TB", "PB", "EB"],
+            Self::Iec => &["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"],
+        }
+    }
+}
+
+/// Size in bytes, displayed with one decimal in the largest fitting unit of
+/// [UnitSystem::global], e.g. `1.5 GiB`. Sizes under one kilobyte are shown exactly, e.g. `512 B`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size(pub u64);
+
+impl Size {
+    /// Formats the size in `system`.
+    pub fn format(self, system: UnitSystem) -> String {
+        let base = system.base();
+        if (self.0 as f64) < base {
+            return format!("{} B", self.0);
+        }
+
+        let mut value = self.0 as f64;
+        let mut prefix = system.prefixes()[0];
+        for candidate in system.prefixes() {
+            value /= base;
+            prefix = candidate;
+            if value < base {
+                break;
+            }
+        }
+        format!("{:.1} {}", value, prefix)
+    }
+}
+
+impl Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&self.format(UnitSystem::global()))
+    }
+}
+
+/// Transfer rate in bytes per second, displayed like a [Size] per second, e.g. `112.4 MiB/s`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate(pub f64);
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = Size(self.0.max(0.0).round() as u64);
+        f.pad(&format!("{}/s", size.format(UnitSystem::global())))
+    }
+}
+
+/// Number displayed with its digits grouped by thousands, e.g. `1,048,576`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Count(pub u64);
+
+impl Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.0.to_string();
+        let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+        for (index, digit) in digits.chars().enumerate() {
+            if index > 0 && (digits.len() - index).is_multiple_of(3) {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        f.pad(&grouped)
+    }
+}
+
+/// Duration displayed in its two largest units, e.g. `2.4s`, `3m 05s` or `1h 02m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(pub Duration);
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let formatted = if secs < 60 {
+            format!("{:.1}s", self.0.as_secs_f64())
+        } else if secs < 3600 {
+            format!("{}m {:02}s", secs / 60, secs % 60)
+        } else {
+            format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+        };
+        f.pad(&formatted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(Size(512).format(UnitSystem::Iec), "512 B");
+        assert_eq!(Size(1536).format(UnitSystem::Iec), "1.5 KiB");
+        assert_eq!(Size(1536).format(UnitSystem::Si), "1.5 kB");
+        assert_eq!(Size(1000).format(UnitSystem::Iec), "1000 B");
+        assert_eq!(Size(6_000_000).format(UnitSystem::Iec), "5.7 MiB");
+        assert_eq!(Size(6_000_000).format(UnitSystem::Si), "6.0 MB");
+        assert_eq!(Size(16 << 30).format(UnitSystem::Iec), "16.0 GiB");
+        assert_eq!(Size(u64::MAX).format(UnitSystem::Iec), "16.0 EiB");
+    }
+
+    #[test]
+    fn test_counts_and_durations() {
+        assert_eq!(Count(0).to_string(), "0");
+        assert_eq!(Count(999).to_string(), "999");
+        assert_eq!(Count(1000).to_string(), "1,000");
+        assert_eq!(Count(6_000_000).to_string(), "6,000,000");
+        assert_eq!(format!("{:>8}", Count(12345)), "  12,345");
+
+        assert_eq!(Elapsed(Duration::from_millis(2400)).to_string(), "2.4s");
+        assert_eq!(Elapsed(Duration::from_secs(185)).to_string(), "3m 05s");
+        assert_eq!(Elapsed(Duration::from_secs(3720)).to_string(), "1h 02m");
+    }
+}